@@ -0,0 +1,98 @@
+//! Allocations per request when serializing invoke payloads with and without the buffer pool.
+//!
+//! Run with `cargo bench --bench payload_buffers`.
+
+use axum::http::{HeaderMap, Method};
+use lambda_web_gateway::buffer_pool::BufferPool;
+use lambda_web_gateway::config::{PathParams, PayloadBufferConfig, PayloadMode};
+use lambda_web_gateway::request::{self, EventRequest};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+/// Counts allocations and reallocations, and the bytes they request.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const REQUESTS: usize = 200;
+
+/// Serializes `REQUESTS` events after a warm-up request, returning allocations, allocated bytes
+/// and microseconds per request.
+fn measure(event: &serde_json::Value, serialize: impl Fn(&serde_json::Value) -> String) -> (f64, f64, f64) {
+    std::hint::black_box(serialize(event));
+    let (allocations, bytes) = (
+        ALLOCATIONS.load(Ordering::Relaxed),
+        ALLOCATED_BYTES.load(Ordering::Relaxed),
+    );
+    let started = Instant::now();
+    for _ in 0..REQUESTS {
+        std::hint::black_box(serialize(event));
+    }
+    let elapsed = started.elapsed();
+    let per_request = |total: usize| total as f64 / REQUESTS as f64;
+    (
+        per_request(ALLOCATIONS.load(Ordering::Relaxed) - allocations),
+        per_request(ALLOCATED_BYTES.load(Ordering::Relaxed) - bytes),
+        elapsed.as_secs_f64() * 1e6 / REQUESTS as f64,
+    )
+}
+
+fn main() {
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", "application/octet-stream".parse().unwrap());
+    headers.insert("user-agent", "bench".parse().unwrap());
+    let params = PathParams::new();
+    let request = EventRequest {
+        method: &Method::POST,
+        path: "/upload",
+        raw_path: "/upload",
+        raw_query_string: "",
+        pattern: "/upload",
+        host: None,
+        path_params: &params,
+        source_ip: None,
+        request_id: "bench",
+        multi_value: false,
+    };
+    let pool = BufferPool::new(&PayloadBufferConfig::default());
+
+    println!(
+        "{:<8} {:<8} {:>12} {:>16} {:>10}",
+        "payload", "buffers", "allocs/req", "bytes/req", "us/req"
+    );
+    for (name, size) in [("8KB", 8 * 1024), ("1MB", 1024 * 1024)] {
+        let (body, is_base64_encoded) = request::encode_body(&headers, &vec![7; size]);
+        let event = request::build_event(PayloadMode::Alb, &request, &headers, &body, is_base64_encoded);
+        let fresh = measure(&event, |event| event.to_string());
+        let pooled = measure(&event, |event| pool.serialize("/upload", event));
+        for (buffers, (allocations, bytes, micros)) in [("fresh", fresh), ("pooled", pooled)] {
+            println!(
+                "{:<8} {:<8} {:>12.1} {:>16.0} {:>10.1}",
+                name, buffers, allocations, bytes, micros
+            );
+        }
+    }
+}
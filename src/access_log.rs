@@ -0,0 +1,366 @@
+use crate::config::{AccessLogConfig, FsyncPolicy, Rotation, RouteRule};
+use crate::error::ErrorPhase;
+use axum::body::{Body, Bytes};
+use http_body::Frame;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// One line of the access log.
+#[derive(Clone, Debug, Serialize)]
+pub struct AccessRecord {
+    /// Milliseconds since the Unix epoch when the response was produced.
+    pub timestamp_ms: u128,
+    /// Address of the connected client, IPv4 clients of dual-stack listeners included as IPv4.
+    /// IPv6 addresses are written without brackets, as they have no port.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<IpAddr>,
+    pub method: String,
+    pub path: String,
+    pub target: String,
+    /// Routing rule that selected the target.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<RouteRule>,
+    pub status: u16,
+    /// Time until the response was sent; for streamed responses, until the stream ended.
+    pub duration_ms: f64,
+    /// Time spent waiting on the function, summed over retries; absent if it was never invoked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream_ms: Option<f64>,
+    /// Time added by the gateway itself until the response head: `duration_ms` minus
+    /// `upstream_ms` for buffered responses.
+    pub overhead_ms: f64,
+    /// Size of the request body.
+    pub request_bytes: u64,
+    /// Size of the response body sent, which falls short of the whole body when the client left.
+    pub response_bytes: u64,
+    /// The response body was streamed from the function as it arrived.
+    pub streamed: bool,
+    /// The client went away before a streamed response ended.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub client_disconnected: bool,
+    /// Function that served the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<String>,
+    /// ID the gateway gave the request, as sent to the function and returned in the `request_id`
+    /// header.
+    pub request_id: String,
+    /// Request ID Lambda assigned to the invoke that served the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lambda_request_id: Option<String>,
+    /// Pipeline phase that failed, for errors produced by the gateway.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phase: Option<ErrorPhase>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<&'static str>,
+    /// Fingerprint of the configuration in effect when the request finished.
+    pub config_rev: String,
+}
+
+/// Writes access records to a rotating file from a dedicated thread, and with `events` as log
+/// events of the `access` target.
+///
+/// Records are handed over through a bounded queue, so the request path never blocks on disk. A
+/// full queue or a failed write drops the line and counts it in `dropped_lines`.
+#[derive(Clone)]
+pub struct AccessLog {
+    tx: Option<SyncSender<String>>,
+    events: bool,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AccessLog {
+    pub fn new(config: &AccessLogConfig) -> Self {
+        let dropped = Arc::new(AtomicU64::new(0));
+        let Some(path) = config.path.clone() else {
+            return Self {
+                events: config.events,
+                ..Self::disabled()
+            };
+        };
+        let (tx, rx) = mpsc::sync_channel(config.queue_capacity.max(1));
+        let writer = RotatingWriter {
+            path,
+            rotation: config.rotation.clone(),
+            max_bytes: config.max_bytes,
+            keep: config.keep,
+            file: None,
+            written: 0,
+            period: current_period(&config.rotation),
+            dropped: dropped.clone(),
+        };
+        let fsync = config.fsync.clone();
+        let fsync_interval = Duration::from_secs(config.fsync_interval_secs.max(1));
+        std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || writer.run(rx, fsync, fsync_interval))
+            .expect("failed to spawn access log writer");
+        Self {
+            tx: Some(tx),
+            events: config.events,
+            dropped,
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self {
+            tx: None,
+            events: false,
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn log(&self, record: &AccessRecord) {
+        if self.events {
+            log_event(record);
+        }
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        let line = serde_json::to_string(record).unwrap_or_default();
+        if tx.try_send(line).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn dropped_lines(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Logs `record` as an event of the `access` target, which the `json` log format writes with
+/// every field at the top level. The subscriber adds its own timestamp, and the target pattern is
+/// logged as `pattern`, as `target` names the log target.
+fn log_event(record: &AccessRecord) {
+    tracing::info!(
+        target: "access",
+        client_ip = record.client_ip.map(tracing::field::display),
+        method = record.method,
+        path = record.path,
+        pattern = record.target,
+        rule = record.rule.map(RouteRule::as_str),
+        status = record.status,
+        duration_ms = record.duration_ms,
+        upstream_ms = record.upstream_ms,
+        overhead_ms = record.overhead_ms,
+        request_bytes = record.request_bytes,
+        response_bytes = record.response_bytes,
+        streamed = record.streamed,
+        client_disconnected = record.client_disconnected,
+        upstream = record.upstream.as_deref(),
+        request_id = record.request_id,
+        lambda_request_id = record.lambda_request_id.as_deref(),
+        phase = record.phase.map(|phase| phase.as_str()),
+        error_code = record.error_code,
+        config_rev = record.config_rev,
+        "Request served"
+    );
+}
+
+/// A response body that logs the access record of its request once it has been sent, or dropped
+/// unfinished when the client went away. The record gets the number of bytes sent, and for
+/// streamed responses the time until the stream ended.
+pub struct LoggedBody {
+    inner: Body,
+    record: Option<AccessRecord>,
+    log: AccessLog,
+    started_at: Instant,
+    sent: u64,
+    finished: bool,
+}
+
+impl LoggedBody {
+    pub fn new(inner: Body, record: AccessRecord, log: AccessLog, started_at: Instant) -> Self {
+        Self {
+            inner,
+            record: Some(record),
+            log,
+            started_at,
+            sent: 0,
+            finished: false,
+        }
+    }
+}
+
+impl http_body::Body for LoggedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) => {
+                self.sent += frame.data_ref().map_or(0, |data| data.len() as u64);
+                self.finished = self.inner.is_end_stream();
+            }
+            Poll::Ready(None) => self.finished = true,
+            _ => {}
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        let Some(mut record) = self.record.take() else {
+            return;
+        };
+        record.response_bytes = self.sent;
+        if record.streamed {
+            record.duration_ms = self.started_at.elapsed().as_secs_f64() * 1000.0;
+            record.client_disconnected = !self.finished;
+        }
+        self.log.log(&record);
+    }
+}
+
+pub fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+/// Index of the rotation period `now` falls in, for time-based rotation.
+fn current_period(rotation: &Rotation) -> u64 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    match rotation {
+        Rotation::Size => 0,
+        Rotation::Hourly => secs / 3600,
+        Rotation::Daily => secs / 86400,
+    }
+}
+
+struct RotatingWriter {
+    path: PathBuf,
+    rotation: Rotation,
+    max_bytes: u64,
+    keep: usize,
+    file: Option<File>,
+    written: u64,
+    period: u64,
+    dropped: Arc<AtomicU64>,
+}
+
+impl RotatingWriter {
+    fn run(mut self, rx: Receiver<String>, fsync: FsyncPolicy, fsync_interval: Duration) {
+        let mut last_sync = Instant::now();
+        loop {
+            match rx.recv_timeout(fsync_interval) {
+                Ok(line) => {
+                    self.write_line(&line);
+                    if fsync == FsyncPolicy::Always {
+                        self.sync();
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            if fsync == FsyncPolicy::Interval && last_sync.elapsed() >= fsync_interval {
+                self.sync();
+                last_sync = Instant::now();
+            }
+        }
+        self.sync();
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if self.should_rotate(line.len() as u64 + 1) {
+            self.rotate();
+        }
+        if self.file.is_none() {
+            self.file = match OpenOptions::new().create(true).append(true).open(&self.path) {
+                Ok(file) => {
+                    self.written = file.metadata().map(|m| m.len()).unwrap_or(0);
+                    Some(file)
+                }
+                Err(e) => {
+                    self.drop_line(&format!("failed to open {}: {}", self.path.display(), e));
+                    return;
+                }
+            };
+        }
+        let file = self.file.as_mut().unwrap();
+        match writeln!(file, "{}", line) {
+            Ok(()) => self.written += line.len() as u64 + 1,
+            Err(e) => {
+                self.drop_line(&format!("failed to write {}: {}", self.path.display(), e));
+                // Reopen on the next line in case the file was removed or the disk recovered
+                self.file = None;
+            }
+        }
+    }
+
+    fn should_rotate(&self, incoming: u64) -> bool {
+        match self.rotation {
+            Rotation::Size => self.written > 0 && self.written + incoming > self.max_bytes,
+            Rotation::Hourly | Rotation::Daily => current_period(&self.rotation) != self.period,
+        }
+    }
+
+    /// Shifts `access.log` to `access.log.1`, `access.log.1` to `access.log.2` and so on, removing
+    /// files beyond `keep`. On failure the current file keeps growing.
+    fn rotate(&mut self) {
+        self.period = current_period(&self.rotation);
+        self.sync();
+        self.file = None;
+        if self.keep == 0 {
+            if let Err(e) = fs::remove_file(&self.path) {
+                tracing::warn!("Failed to rotate access log {}: {}", self.path.display(), e);
+            }
+            return;
+        }
+        let _ = fs::remove_file(rotated_path(&self.path, self.keep));
+        for i in (1..self.keep).rev() {
+            let from = rotated_path(&self.path, i);
+            if from.exists() {
+                let _ = fs::rename(&from, rotated_path(&self.path, i + 1));
+            }
+        }
+        if let Err(e) = fs::rename(&self.path, rotated_path(&self.path, 1)) {
+            tracing::warn!("Failed to rotate access log {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn sync(&mut self) {
+        if let Some(file) = &self.file {
+            let _ = file.sync_data();
+        }
+    }
+
+    fn drop_line(&self, reason: &str) {
+        if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+            tracing::warn!("Dropping access log lines: {}", reason);
+        }
+    }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    include!("access_log_tests.rs");
+}
@@ -0,0 +1,124 @@
+use super::*;
+
+fn record(path: &str) -> AccessRecord {
+    AccessRecord {
+        timestamp_ms: now_ms(),
+        client_ip: None,
+        method: "GET".to_string(),
+        path: path.to_string(),
+        target: "/*rest".to_string(),
+        rule: Some(RouteRule::Wildcard),
+        status: 200,
+        duration_ms: 1.5,
+        upstream_ms: None,
+        overhead_ms: 1.5,
+        request_bytes: 0,
+        response_bytes: 2,
+        streamed: false,
+        client_disconnected: false,
+        upstream: None,
+        request_id: "0190a6b2-7c3e-7d4f-8a1b-2c3d4e5f6a7b".to_string(),
+        lambda_request_id: None,
+        phase: None,
+        error_code: None,
+        config_rev: "0123456789abcdef".to_string(),
+    }
+}
+
+fn config(path: PathBuf) -> AccessLogConfig {
+    AccessLogConfig {
+        path: Some(path),
+        max_bytes: 400,
+        keep: 2,
+        fsync: FsyncPolicy::Always,
+        ..Default::default()
+    }
+}
+
+/// Waits for the writer thread to catch up.
+fn settle() {
+    std::thread::sleep(Duration::from_millis(200));
+}
+
+#[test]
+fn test_writes_json_lines() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("access.log");
+    let log = AccessLog::new(&config(path.clone()));
+
+    log.log(&record("/a"));
+    log.log(&record("/b"));
+    settle();
+
+    let contents = fs::read_to_string(&path).unwrap();
+    let lines: Vec<serde_json::Value> = contents.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["path"], "/a");
+    assert_eq!(lines[0]["rule"], "wildcard");
+    assert_eq!(lines[1]["status"], 200);
+    assert_eq!(log.dropped_lines(), 0);
+}
+
+#[test]
+fn test_size_rotation_keeps_n_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("access.log");
+    let log = AccessLog::new(&config(path.clone()));
+
+    for i in 0..20 {
+        log.log(&record(&format!("/{}", i)));
+    }
+    settle();
+
+    assert!(path.exists());
+    assert!(rotated_path(&path, 1).exists());
+    assert!(rotated_path(&path, 2).exists());
+    assert!(!rotated_path(&path, 3).exists());
+    for file in [path.clone(), rotated_path(&path, 1), rotated_path(&path, 2)] {
+        assert!(fs::metadata(&file).unwrap().len() <= 400);
+    }
+    // The newest record is in the live file
+    assert!(fs::read_to_string(&path).unwrap().contains(r#""path":"/19""#));
+    assert_eq!(log.dropped_lines(), 0);
+}
+
+#[test]
+fn test_rotation_failure_keeps_logging() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("access.log");
+    // A non-empty directory where the rotated file should go makes every rename fail
+    fs::create_dir(rotated_path(&path, 1)).unwrap();
+    fs::write(rotated_path(&path, 1).join("blocker"), "").unwrap();
+    let log = AccessLog::new(&AccessLogConfig {
+        keep: 1,
+        ..config(path.clone())
+    });
+
+    for i in 0..20 {
+        log.log(&record(&format!("/{}", i)));
+    }
+    settle();
+
+    assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 20);
+    assert_eq!(log.dropped_lines(), 0);
+}
+
+#[test]
+fn test_unwritable_path_counts_dropped_lines() {
+    let dir = tempfile::tempdir().unwrap();
+    let log = AccessLog::new(&config(dir.path().join("missing").join("access.log")));
+
+    for _ in 0..3 {
+        log.log(&record("/"));
+    }
+    settle();
+
+    assert_eq!(log.dropped_lines(), 3);
+}
+
+#[test]
+fn test_disabled_log_is_noop() {
+    let log = AccessLog::new(&AccessLogConfig::default());
+    log.log(&record("/"));
+    assert_eq!(log.dropped_lines(), 0);
+}
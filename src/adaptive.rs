@@ -0,0 +1,185 @@
+use crate::config::AdaptiveConcurrency;
+use serde::Serialize;
+use std::collections::VecDeque;
+
+/// Adjustments kept for `/status`.
+pub const RECENT_ADJUSTMENTS: usize = 10;
+
+/// Share of the gap to a slower window's p95 that the baseline moves by while the limit is at
+/// `min_limit`. A function that became slower for good is probed again from its new latency instead
+/// of being held there.
+const BASELINE_DRIFT: f64 = 0.05;
+
+/// Why the limit changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdjustReason {
+    /// A window kept its p95 latency near the baseline while using the limit, so one more slot is
+    /// tried.
+    Probe,
+    /// A window's p95 latency went past `tolerance` times the baseline.
+    Latency,
+    /// Lambda throttled an invoke.
+    Throttle,
+}
+
+/// A change of the limit, as shown on `/status`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Adjustment {
+    pub at_ms: u64,
+    pub from: usize,
+    pub to: usize,
+    pub reason: AdjustReason,
+    /// p95 latency of the window that caused it; unset for throttles.
+    pub p95_ms: Option<f64>,
+}
+
+/// State of an adaptive limit, as shown on `/status`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AdaptiveStatus {
+    pub limit: usize,
+    pub baseline_ms: Option<f64>,
+    /// Most recent last.
+    pub recent_adjustments: Vec<Adjustment>,
+}
+
+/// One finished invoke.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    /// Time spent waiting on Lambda.
+    pub latency_ms: f64,
+    pub throttled: bool,
+    /// Invokes of the target in flight when this one took its slot, itself included.
+    pub in_flight: usize,
+}
+
+/// Additive increase, multiplicative decrease of a concurrency limit from the invokes it lets
+/// through, in windows of `window` samples.
+///
+/// The baseline is the lowest p95 latency of any window, moving up only while the limit is at
+/// `min_limit` and latency stays high regardless. A window whose p95 stays within
+/// `tolerance` times the baseline raises the limit by one, provided it had at least half the limit
+/// in flight at some point; an idle target says nothing about whether it could take more. A
+/// slower window multiplies the limit by `backoff`, and so does the first throttle of a window,
+/// right away. Further throttles of the same window are taken as part of the same burst. The limit
+/// stays within `min_limit` and `max_limit`.
+///
+/// The controller does no I/O and keeps no clock, so it can be driven by synthetic latencies.
+#[derive(Clone, Debug)]
+pub struct AimdController {
+    config: AdaptiveConcurrency,
+    limit: usize,
+    baseline_ms: Option<f64>,
+    latencies: Vec<f64>,
+    seen: usize,
+    peak_in_flight: usize,
+    throttled: bool,
+    adjustments: VecDeque<Adjustment>,
+}
+
+impl AimdController {
+    pub fn new(config: &AdaptiveConcurrency) -> Self {
+        Self {
+            config: config.clone(),
+            limit: config
+                .initial_limit
+                .clamp(config.min_limit, config.max_limit.max(config.min_limit)),
+            baseline_ms: None,
+            latencies: Vec::new(),
+            seen: 0,
+            peak_in_flight: 0,
+            throttled: false,
+            adjustments: VecDeque::new(),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Folds in a sample taken at `now_ms`, returning the new limit when it changed.
+    pub fn observe(&mut self, sample: Sample, now_ms: u64) -> Option<usize> {
+        let before = self.limit;
+        self.seen += 1;
+        self.peak_in_flight = self.peak_in_flight.max(sample.in_flight);
+        if sample.throttled {
+            if !self.throttled {
+                self.throttled = true;
+                self.cut(AdjustReason::Throttle, None, now_ms);
+            }
+        } else {
+            self.latencies.push(sample.latency_ms);
+        }
+        if self.seen >= self.config.window.max(1) {
+            self.close_window(now_ms);
+        }
+        (self.limit != before).then_some(self.limit)
+    }
+
+    pub fn status(&self) -> AdaptiveStatus {
+        AdaptiveStatus {
+            limit: self.limit,
+            baseline_ms: self.baseline_ms,
+            recent_adjustments: self.adjustments.iter().cloned().collect(),
+        }
+    }
+
+    fn close_window(&mut self, now_ms: u64) {
+        let mut latencies = std::mem::take(&mut self.latencies);
+        let peak_in_flight = std::mem::take(&mut self.peak_in_flight);
+        self.seen = 0;
+        // A throttled window was already answered with a cut
+        if std::mem::take(&mut self.throttled) || latencies.is_empty() {
+            return;
+        }
+        let p95 = percentile(&mut latencies, 0.95);
+        let baseline = *self.baseline_ms.get_or_insert(p95);
+        if p95 > baseline * self.config.tolerance {
+            // Above `min_limit` a cut is the answer; at it, nothing is left to cut
+            if self.limit == self.config.min_limit {
+                self.baseline_ms = Some(baseline + BASELINE_DRIFT * (p95 - baseline));
+            }
+            self.cut(AdjustReason::Latency, Some(p95), now_ms);
+        } else {
+            self.baseline_ms = Some(baseline.min(p95));
+            if peak_in_flight * 2 >= self.limit {
+                self.set(self.limit + 1, AdjustReason::Probe, Some(p95), now_ms);
+            }
+        }
+    }
+
+    fn cut(&mut self, reason: AdjustReason, p95_ms: Option<f64>, now_ms: u64) {
+        let to = (self.limit as f64 * self.config.backoff) as usize;
+        self.set(to, reason, p95_ms, now_ms);
+    }
+
+    fn set(&mut self, to: usize, reason: AdjustReason, p95_ms: Option<f64>, now_ms: u64) {
+        let to = to.clamp(self.config.min_limit, self.config.max_limit.max(self.config.min_limit));
+        if to == self.limit {
+            return;
+        }
+        if self.adjustments.len() == RECENT_ADJUSTMENTS {
+            self.adjustments.pop_front();
+        }
+        self.adjustments.push_back(Adjustment {
+            at_ms: now_ms,
+            from: self.limit,
+            to,
+            reason,
+            p95_ms,
+        });
+        self.limit = to;
+    }
+}
+
+/// The `q` quantile of `values`, by the nearest-rank method. `values` must not be empty.
+fn percentile(values: &mut [f64], q: f64) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let rank = (q * values.len() as f64).ceil() as usize;
+    values[rank.clamp(1, values.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    include!("adaptive_tests.rs");
+}
@@ -0,0 +1,137 @@
+use super::*;
+
+fn settings(min_limit: usize, max_limit: usize, initial_limit: usize) -> AdaptiveConcurrency {
+    AdaptiveConcurrency {
+        min_limit,
+        max_limit,
+        initial_limit,
+        ..Default::default()
+    }
+}
+
+/// Runs `windows` windows of traffic that keeps every slot busy. An invoke made with `n` in flight
+/// takes `latency(n)` and is throttled when `throttled(n)`. Returns the limit after each window.
+fn simulate(
+    controller: &mut AimdController,
+    windows: usize,
+    latency: impl Fn(usize) -> f64,
+    throttled: impl Fn(usize) -> bool,
+) -> Vec<usize> {
+    let mut limits = Vec::new();
+    let mut now_ms = 0;
+    for _ in 0..windows {
+        for _ in 0..controller.config.window {
+            let in_flight = controller.limit();
+            let sample = Sample {
+                latency_ms: latency(in_flight),
+                throttled: throttled(in_flight),
+                in_flight,
+            };
+            controller.observe(sample, now_ms);
+            now_ms += 10;
+        }
+        limits.push(controller.limit());
+    }
+    limits
+}
+
+/// A function serving 20 invokes at once in 100 ms; more queue up and take proportionally longer.
+fn queueing(in_flight: usize) -> f64 {
+    100.0 * (in_flight as f64 / 20.0).max(1.0)
+}
+
+#[test]
+fn test_converges_around_capacity_under_queueing_latency() {
+    let mut controller = AimdController::new(&settings(1, 100, 10));
+    let limits = simulate(&mut controller, 200, queueing, |_| false);
+
+    // Latency passes 1.5 times the 100 ms baseline above 30 in flight; each cut goes to 3/4 of that
+    let settled = &limits[50..];
+    assert!(settled.iter().all(|limit| (23..=31).contains(limit)), "{:?}", settled);
+    assert!(settled.contains(&23) && settled.contains(&30));
+    let status = controller.status();
+    assert_eq!(status.baseline_ms, Some(100.0));
+    let reasons: Vec<_> = status.recent_adjustments.iter().map(|a| a.reason).collect();
+    assert!(reasons.contains(&AdjustReason::Probe) && reasons.contains(&AdjustReason::Latency));
+    assert!(status.recent_adjustments.len() <= RECENT_ADJUSTMENTS);
+}
+
+#[test]
+fn test_converges_below_throttling_concurrency() {
+    let mut controller = AimdController::new(&settings(1, 100, 10));
+    // Reserved concurrency of 15
+    let limits = simulate(&mut controller, 100, |_| 50.0, |in_flight| in_flight > 15);
+
+    let settled = &limits[20..];
+    assert!(settled.iter().all(|limit| (12..=16).contains(limit)), "{:?}", settled);
+    let throttle = controller
+        .status()
+        .recent_adjustments
+        .into_iter()
+        .find(|a| a.reason == AdjustReason::Throttle)
+        .unwrap();
+    assert_eq!((throttle.from, throttle.to, throttle.p95_ms), (16, 12, None));
+}
+
+#[test]
+fn test_burst_of_throttles_cuts_once() {
+    let mut controller = AimdController::new(&settings(1, 100, 16));
+    let throttle = Sample {
+        latency_ms: 5.0,
+        throttled: true,
+        in_flight: 16,
+    };
+    assert_eq!(controller.observe(throttle, 0), Some(12));
+    for now_ms in 1..5 {
+        assert_eq!(controller.observe(throttle, now_ms), None);
+    }
+    assert_eq!(controller.limit(), 12);
+    assert_eq!(controller.status().recent_adjustments.len(), 1);
+}
+
+#[test]
+fn test_stays_within_bounds() {
+    let mut controller = AimdController::new(&settings(2, 25, 10));
+    let limits = simulate(&mut controller, 40, |_| 50.0, |_| false);
+    assert_eq!(limits.last(), Some(&25));
+    assert!(limits.iter().all(|limit| *limit <= 25));
+
+    let limits = simulate(&mut controller, 20, |_| 50.0, |_| true);
+    assert_eq!(limits.last(), Some(&2));
+    assert!(limits.iter().all(|limit| *limit >= 2));
+}
+
+#[test]
+fn test_idle_target_does_not_probe() {
+    let mut controller = AimdController::new(&settings(1, 100, 10));
+    let light = Sample {
+        latency_ms: 50.0,
+        throttled: false,
+        in_flight: 2,
+    };
+    for now_ms in 0..500 {
+        assert_eq!(controller.observe(light, now_ms), None);
+    }
+    assert_eq!(controller.limit(), 10);
+}
+
+#[test]
+fn test_recovers_from_a_lasting_slowdown() {
+    let mut controller = AimdController::new(&settings(1, 8, 4));
+    simulate(&mut controller, 10, |_| 100.0, |_| false);
+    assert_eq!(controller.limit(), 8);
+
+    // The function is now twice as slow whatever the load: cutting does not help, so the baseline
+    // moves up while at the minimum and probing starts again
+    let limits = simulate(&mut controller, 60, |_| 200.0, |_| false);
+    assert!(limits.contains(&1), "{:?}", limits);
+    assert_eq!(limits.last(), Some(&8));
+    assert!(controller.status().baseline_ms.unwrap() >= 200.0 / 1.5);
+}
+
+#[test]
+fn test_percentile_nearest_rank() {
+    let mut values: Vec<f64> = (1..=20).rev().map(f64::from).collect();
+    assert_eq!(percentile(&mut values, 0.95), 19.0);
+    assert_eq!(percentile(&mut [7.0], 0.95), 7.0);
+}
@@ -0,0 +1,109 @@
+use crate::config::{AuthBanConfig, RetryAfterConfig};
+use crate::error::{ErrorPhase, GatewayError};
+use crate::retry_after::RetryAfterHints;
+use crate::state_store::StateStore;
+use crate::telemetry::Telemetry;
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Client addresses banned after failing API key authentication `max_failures` times within
+/// `window_secs`, answered 403 for `ban_secs`. Failures and bans are kept in the state store with
+/// those expiries, so every replica enforces a ban and a restart does not lift it. Bans end at a
+/// time of the gateway's [`crate::clock::Clock`], which replicas correcting their skew agree on.
+#[derive(Clone)]
+pub struct AuthBans {
+    store: Arc<dyn StateStore>,
+    telemetry: Telemetry,
+}
+
+fn failures_key(ip: IpAddr) -> String {
+    format!("auth_failures:{}", ip)
+}
+
+fn ban_key(ip: IpAddr) -> String {
+    format!("auth_ban:{}", ip)
+}
+
+fn epoch_ms(at: DateTime<Utc>) -> u64 {
+    u64::try_from(at.timestamp_millis()).unwrap_or_default()
+}
+
+impl AuthBans {
+    pub fn new(store: Arc<dyn StateStore>, telemetry: Telemetry) -> Self {
+        Self { store, telemetry }
+    }
+
+    /// Rejects `ip` while it is banned at `now`, with a `Retry-After` of when the ban ends. An
+    /// address whose ban cannot be read is let through.
+    pub async fn check(
+        &self,
+        ip: IpAddr,
+        retry_after: &RetryAfterConfig,
+        now: DateTime<Utc>,
+    ) -> Result<(), GatewayError> {
+        let until_ms = match self.store.get(&ban_key(ip)).await {
+            Ok(value) => value.and_then(|value| std::str::from_utf8(&value).ok()?.parse::<u64>().ok()),
+            Err(e) => {
+                tracing::warn!(client_ip = %ip, "Letting the address through, its ban cannot be read: {}", e);
+                None
+            }
+        };
+        let left_ms = until_ms.map_or(0, |until_ms| until_ms.saturating_sub(epoch_ms(now)));
+        if left_ms == 0 {
+            return Ok(());
+        }
+        self.telemetry.increment("auth_ban_rejections_total", vec![]);
+        Err(GatewayError::new(
+            ErrorPhase::Auth,
+            StatusCode::FORBIDDEN,
+            "client_banned",
+            "Too many failed authentication attempts from this address",
+        )
+        .with_retry_after(RetryAfterHints::ban(Some(Duration::from_millis(left_ms))).resolve(1, retry_after)))
+    }
+
+    /// Counts a failed authentication of `ip`, banning it once it reaches `max_failures`. Only the
+    /// first request to get there stores the ban, so failures racing on other replicas do not
+    /// extend it.
+    pub async fn record_failure(&self, config: &AuthBanConfig, ip: IpAddr, now: DateTime<Utc>) {
+        let window = Duration::from_secs(config.window_secs);
+        let failures = match self.store.increment(&failures_key(ip), 1, Some(window)).await {
+            Ok(failures) => failures,
+            Err(e) => {
+                tracing::warn!(client_ip = %ip, "Failed authentication not counted: {}", e);
+                return;
+            }
+        };
+        if failures < i64::from(config.max_failures) {
+            return;
+        }
+        let until_ms = epoch_ms(now) + config.ban_secs * 1000;
+        let ban = self
+            .store
+            .compare_and_swap(
+                &ban_key(ip),
+                None,
+                until_ms.to_string().into_bytes(),
+                Some(Duration::from_secs(config.ban_secs)),
+            )
+            .await;
+        match ban {
+            Ok(true) => {
+                let ban_secs = config.ban_secs;
+                tracing::warn!(client_ip = %ip, failures, ban_secs, "Banned after failed authentication");
+                self.telemetry.increment("auth_bans_total", vec![]);
+            }
+            // Banned already
+            Ok(false) => {}
+            Err(e) => tracing::warn!(client_ip = %ip, "Address not banned, the ban cannot be stored: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("auth_ban_tests.rs");
+}
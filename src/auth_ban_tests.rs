@@ -0,0 +1,117 @@
+use super::*;
+use crate::mock::UnreachableStore;
+use crate::state_store::{FallbackStore, MemoryStore};
+
+fn bans_on(store: Arc<dyn StateStore>) -> AuthBans {
+    AuthBans::new(store, Telemetry::new(&Default::default()))
+}
+
+/// Bounds letting the whole ban through as `Retry-After`.
+fn retry_after() -> RetryAfterConfig {
+    RetryAfterConfig {
+        max_secs: 3600,
+        ..Default::default()
+    }
+}
+
+fn settings() -> AuthBanConfig {
+    AuthBanConfig {
+        max_failures: 3,
+        window_secs: 60,
+        ban_secs: 600,
+        ..Default::default()
+    }
+}
+
+/// Ban list behaviour that must hold whatever store it is kept in. Bans made through one
+/// [`AuthBans`] hold for another on the same store, as for replicas or across a restart.
+async fn ban_contract(store: Arc<dyn StateStore>, ip: IpAddr) {
+    let (first, second) = (bans_on(store.clone()), bans_on(store));
+    for _ in 0..2 {
+        first.record_failure(&settings(), ip, Utc::now()).await;
+    }
+    assert!(second.check(ip, &retry_after(), Utc::now()).await.is_ok());
+
+    first.record_failure(&settings(), ip, Utc::now()).await;
+    let err = second.check(ip, &retry_after(), Utc::now()).await.unwrap_err();
+    assert_eq!(err.status, StatusCode::FORBIDDEN);
+    assert_eq!(err.code, "client_banned");
+    assert!(err.retry_after_secs.is_some_and(|secs| secs > 590 && secs <= 600));
+
+    // Later failures do not push the end of the ban further out
+    second.record_failure(&settings(), ip, Utc::now()).await;
+    assert!(first.check(ip, &retry_after(), Utc::now()).await.unwrap_err().retry_after_secs <= err.retry_after_secs);
+}
+
+#[tokio::test]
+async fn test_ban_contract_on_memory_store() {
+    ban_contract(Arc::new(MemoryStore::default()), "192.0.2.1".parse().unwrap()).await;
+}
+
+#[tokio::test]
+async fn test_ban_contract_while_store_is_down() {
+    let fallback = FallbackStore::new(Arc::new(UnreachableStore), Arc::new(MemoryStore::default()), 50);
+    ban_contract(Arc::new(fallback), "192.0.2.1".parse().unwrap()).await;
+}
+
+#[tokio::test]
+async fn test_ban_is_timed_by_the_given_clock() {
+    let bans = bans_on(Arc::new(MemoryStore::default()));
+    let ip: IpAddr = "192.0.2.1".parse().unwrap();
+    // As a replica whose corrected clock runs a minute ahead of the local one
+    let banned_at = Utc::now() + chrono::Duration::minutes(1);
+    for _ in 0..3 {
+        bans.record_failure(&settings(), ip, banned_at).await;
+    }
+    let err = bans.check(ip, &retry_after(), banned_at).await.unwrap_err();
+    assert!(err.retry_after_secs.is_some_and(|secs| secs > 590 && secs <= 600));
+    let after_ban = banned_at + chrono::Duration::seconds(600);
+    assert!(bans.check(ip, &retry_after(), after_ban).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_retry_after_is_capped() {
+    let bans = bans_on(Arc::new(MemoryStore::default()));
+    let ip: IpAddr = "192.0.2.1".parse().unwrap();
+    for _ in 0..3 {
+        bans.record_failure(&settings(), ip, Utc::now()).await;
+    }
+    let capped = RetryAfterConfig {
+        max_secs: 60,
+        ..Default::default()
+    };
+    let err = bans.check(ip, &capped, Utc::now()).await.unwrap_err();
+    assert_eq!(err.retry_after_secs, Some(60));
+}
+
+#[tokio::test]
+async fn test_other_addresses_are_not_banned() {
+    let bans = bans_on(Arc::new(MemoryStore::default()));
+    let banned: IpAddr = "192.0.2.1".parse().unwrap();
+    for _ in 0..3 {
+        bans.record_failure(&settings(), banned, Utc::now()).await;
+    }
+    assert!(bans.check(banned, &retry_after(), Utc::now()).await.is_err());
+    assert!(bans.check("192.0.2.2".parse().unwrap(), &retry_after(), Utc::now()).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_unreadable_ban_lets_the_address_through() {
+    let bans = bans_on(Arc::new(UnreachableStore));
+    assert!(bans.check("192.0.2.1".parse().unwrap(), &retry_after(), Utc::now()).await.is_ok());
+}
+
+/// Runs the contract against the server in `REDIS_URL`, when set.
+#[cfg(feature = "redis-state")]
+#[tokio::test]
+async fn test_ban_contract_on_redis_store() {
+    let Ok(url) = std::env::var("REDIS_URL") else {
+        eprintln!("REDIS_URL is not set, skipping the Redis ban contract test");
+        return;
+    };
+    let store = crate::redis_store::RedisStore::new(&url).unwrap();
+    // A documentation address of its own per run, so runs against one server do not collide
+    let run = epoch_ms(Utc::now());
+    let ip = IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, (run >> 16) as u16, run as u16]);
+    ban_contract(Arc::new(store), ip).await;
+}
@@ -0,0 +1,90 @@
+use crate::config::BalanceStrategy;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Spreads a target's requests over its member functions and tracks requests in flight per
+/// function.
+#[derive(Clone, Default)]
+pub struct Balancer {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    in_flight: Mutex<HashMap<String, Arc<AtomicUsize>>>,
+    /// Round-robin position per member list.
+    cursors: Mutex<HashMap<Vec<String>, usize>>,
+    random: RandomState,
+    draws: AtomicUsize,
+}
+
+/// Counts one request in flight to a function until dropped.
+#[derive(Debug)]
+pub struct InFlightGuard {
+    counter: Arc<AtomicUsize>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Balancer {
+    /// Drops the round-robin position kept for a member list that is no longer configured.
+    pub fn forget(&self, members: &[String]) {
+        self.inner.cursors.lock().unwrap().remove(members);
+    }
+
+    pub fn in_flight(&self, function: &str) -> usize {
+        self.inner
+            .in_flight
+            .lock()
+            .unwrap()
+            .get(function)
+            .map_or(0, |counter| counter.load(Ordering::SeqCst))
+    }
+
+    /// Picks a member with `strategy`, skipping those `available` rejects, and counts the request
+    /// as in flight to it. Selection and counting happen under one lock, so parallel
+    /// `LeastInFlight` picks see each other. Returns `None` when no member is available.
+    pub fn acquire<'a>(
+        &self,
+        members: &'a [String],
+        strategy: BalanceStrategy,
+        available: impl Fn(&str) -> bool,
+    ) -> Option<(&'a str, InFlightGuard)> {
+        let mut in_flight = self.inner.in_flight.lock().unwrap();
+        let candidates: Vec<&'a String> = members.iter().filter(|member| available(member)).collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        let load = |member: &str| in_flight.get(member).map_or(0, |c| c.load(Ordering::SeqCst));
+        let chosen = match strategy {
+            BalanceStrategy::RoundRobin => {
+                let mut cursors = self.inner.cursors.lock().unwrap();
+                let cursor = cursors.entry(members.to_vec()).or_default();
+                let chosen = candidates[*cursor % candidates.len()];
+                *cursor = cursor.wrapping_add(1);
+                chosen
+            }
+            // Ties go to the earliest member, keeping the choice deterministic
+            BalanceStrategy::LeastInFlight => candidates.iter().copied().min_by_key(|m| load(m)).unwrap(),
+            BalanceStrategy::Random => {
+                let draw = self.inner.draws.fetch_add(1, Ordering::Relaxed);
+                candidates[self.inner.random.hash_one(draw) as usize % candidates.len()]
+            }
+        };
+        let counter = in_flight.entry(chosen.clone()).or_default().clone();
+        counter.fetch_add(1, Ordering::SeqCst);
+        Some((chosen.as_str(), InFlightGuard { counter }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("balancer_tests.rs");
+}
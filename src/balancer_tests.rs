@@ -0,0 +1,102 @@
+use super::*;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+fn members(n: usize) -> Vec<String> {
+    (0..n).map(|i| format!("fn-{}", i)).collect()
+}
+
+#[test]
+fn test_round_robin() {
+    let balancer = Balancer::default();
+    let members = members(3);
+    let picks: Vec<&str> = (0..6)
+        .map(|_| balancer.acquire(&members, BalanceStrategy::RoundRobin, |_| true).unwrap().0)
+        .collect();
+    assert_eq!(picks, ["fn-0", "fn-1", "fn-2", "fn-0", "fn-1", "fn-2"]);
+}
+
+#[test]
+fn test_excluded_members_are_skipped() {
+    let balancer = Balancer::default();
+    let members = members(3);
+    for strategy in [
+        BalanceStrategy::RoundRobin,
+        BalanceStrategy::LeastInFlight,
+        BalanceStrategy::Random,
+    ] {
+        for _ in 0..10 {
+            let (member, _) = balancer.acquire(&members, strategy, |m| m != "fn-1").unwrap();
+            assert_ne!(member, "fn-1");
+        }
+        assert!(balancer.acquire(&members, strategy, |_| false).is_none());
+    }
+}
+
+#[test]
+fn test_random_uses_every_member() {
+    let balancer = Balancer::default();
+    let members = members(3);
+    let mut counts = BTreeMap::new();
+    for _ in 0..300 {
+        let (member, _) = balancer.acquire(&members, BalanceStrategy::Random, |_| true).unwrap();
+        *counts.entry(member).or_insert(0) += 1;
+    }
+    assert_eq!(counts.len(), 3);
+    assert!(counts.values().all(|&count| count > 30));
+}
+
+#[test]
+fn test_guards_track_in_flight() {
+    let balancer = Balancer::default();
+    let members = members(2);
+    let (first, guard) = balancer.acquire(&members, BalanceStrategy::LeastInFlight, |_| true).unwrap();
+    assert_eq!(first, "fn-0");
+    assert_eq!(balancer.in_flight("fn-0"), 1);
+
+    // fn-0 is busy, so the next request goes to fn-1
+    let (second, _guard) = balancer.acquire(&members, BalanceStrategy::LeastInFlight, |_| true).unwrap();
+    assert_eq!(second, "fn-1");
+
+    drop(guard);
+    assert_eq!(balancer.in_flight("fn-0"), 0);
+    let (third, _) = balancer.acquire(&members, BalanceStrategy::LeastInFlight, |_| true).unwrap();
+    assert_eq!(third, "fn-0");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn test_least_in_flight_under_parallel_load() {
+    let balancer = Balancer::default();
+    let members = Arc::new(members(4));
+    let peak = Arc::new(Mutex::new(BTreeMap::<String, usize>::new()));
+
+    let tasks: Vec<_> = (0..64)
+        .map(|_| {
+            let (balancer, members, peak) = (balancer.clone(), members.clone(), peak.clone());
+            tokio::spawn(async move {
+                let (member, guard) = balancer
+                    .acquire(&members, BalanceStrategy::LeastInFlight, |_| true)
+                    .unwrap();
+                let member = member.to_string();
+                {
+                    let mut peak = peak.lock().unwrap();
+                    let seen = peak.entry(member.clone()).or_default();
+                    *seen = (*seen).max(balancer.in_flight(&member));
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                drop(guard);
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    // 64 overlapping requests over 4 members: least-in-flight keeps them evenly loaded
+    let peak = peak.lock().unwrap();
+    assert_eq!(peak.len(), 4);
+    assert!(peak.values().all(|&p| p <= 16), "{:?}", peak);
+    for member in members.iter() {
+        assert_eq!(balancer.in_flight(member), 0);
+    }
+}
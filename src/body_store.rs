@@ -0,0 +1,482 @@
+use crate::config::{BodyStorageConfig, FlightRecorderConfig};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use clap::Args;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+
+/// Start of every encrypted record. `0xFF` never occurs in UTF-8, so plain records, which are
+/// lines of text, never contain it.
+pub const MAGIC: [u8; 4] = [0xFF, b'L', b'W', b'G'];
+/// Version of the encrypted record layout.
+const VERSION: u8 = 1;
+const NONCE_BYTES: usize = 12;
+/// AES-GCM authentication tag appended to the ciphertext.
+const TAG_BYTES: usize = 16;
+const KEY_BYTES: usize = 32;
+/// Largest record accepted, so a damaged length never makes a reader allocate gigabytes.
+pub const MAX_RECORD_BYTES: usize = 16 * 1024 * 1024;
+
+/// How records are written to disk, shared by every feature that keeps requests in files.
+///
+/// [`PlainStorage`] writes each record as a line. [`EncryptedStorage`] writes each record as a
+/// self-contained frame that can be appended to a file, or streamed, on its own:
+///
+/// ```text
+/// magic (4) | version (1) | key ID length (1) | key ID | nonce (12) | ciphertext length (4, BE) | ciphertext
+/// ```
+///
+/// The ciphertext is the record sealed with AES-256-GCM, its tag included, with everything before
+/// it as associated data, so the key ID cannot be swapped. Both kinds may share a file, as when
+/// encryption is switched on for an existing one; [`RecordReader`] reads them all.
+pub trait BodyStorage: Send + Sync {
+    /// Appends the stored form of `record` to `out`.
+    fn encode(&self, record: &[u8], out: &mut Vec<u8>) -> io::Result<()>;
+}
+
+/// Records as they are, one per line. Records must not contain a newline.
+pub struct PlainStorage;
+
+impl BodyStorage for PlainStorage {
+    fn encode(&self, record: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        out.extend_from_slice(record);
+        out.push(b'\n');
+        Ok(())
+    }
+}
+
+/// Records encrypted with one key, each under a fresh random nonce.
+pub struct EncryptedStorage {
+    key_id: String,
+    cipher: Aes256Gcm,
+}
+
+impl EncryptedStorage {
+    pub fn new(key_id: &str, cipher: Aes256Gcm) -> Self {
+        Self {
+            key_id: key_id.to_string(),
+            cipher,
+        }
+    }
+}
+
+impl BodyStorage for EncryptedStorage {
+    fn encode(&self, record: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        if record.len() > MAX_RECORD_BYTES {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "record too large"));
+        }
+        let mut nonce = [0; NONCE_BYTES];
+        getrandom::getrandom(&mut nonce).map_err(|e| io::Error::other(e.to_string()))?;
+        let start = out.len();
+        out.extend_from_slice(&MAGIC);
+        out.push(VERSION);
+        out.push(self.key_id.len() as u8);
+        out.extend_from_slice(self.key_id.as_bytes());
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&((record.len() + TAG_BYTES) as u32).to_be_bytes());
+        let sealed = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: record,
+                    aad: &out[start..],
+                },
+            )
+            .map_err(|_| io::Error::other("encryption failed"))?;
+        out.extend_from_slice(&sealed);
+        Ok(())
+    }
+}
+
+/// The storage `config` selects: encrypted with `encrypt_with` when set, plain otherwise. Fails on
+/// keys that cannot be read.
+pub fn storage(config: &BodyStorageConfig) -> Result<Box<dyn BodyStorage>, String> {
+    let mut keys = keys(config)?;
+    match &config.encrypt_with {
+        Some(key_id) => match keys.remove(key_id) {
+            Some(cipher) => Ok(Box::new(EncryptedStorage::new(key_id, cipher))),
+            None => Err(format!("encrypt_with names unknown key {:?}", key_id)),
+        },
+        None => Ok(Box::new(PlainStorage)),
+    }
+}
+
+/// Every key of `config`, by ID, read from the config or the environment.
+pub fn keys(config: &BodyStorageConfig) -> Result<BTreeMap<String, Aes256Gcm>, String> {
+    keys_from(config, &|name| std::env::var(name).ok())
+}
+
+fn keys_from(
+    config: &BodyStorageConfig,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Result<BTreeMap<String, Aes256Gcm>, String> {
+    let mut keys = BTreeMap::new();
+    for (id, key) in &config.keys {
+        if id.is_empty() || id.len() > u8::MAX as usize {
+            return Err(format!("key ID {:?} must be 1 to 255 bytes long", id));
+        }
+        let encoded = match (&key.key, &key.env) {
+            (Some(key), None) => key.clone(),
+            (None, Some(name)) => env(name).ok_or_else(|| format!("key {}: {} is not set", id, name))?,
+            _ => return Err(format!("key {} needs exactly one of key and env", id)),
+        };
+        keys.insert(
+            id.clone(),
+            cipher(encoded.trim()).map_err(|e| format!("key {}: {}", id, e))?,
+        );
+    }
+    Ok(keys)
+}
+
+/// A cipher for a base64-encoded 32-byte key.
+pub fn cipher(encoded: &str) -> Result<Aes256Gcm, String> {
+    let key = BASE64.decode(encoded).map_err(|_| "not valid base64".to_string())?;
+    if key.len() != KEY_BYTES {
+        return Err(format!("must be {} bytes, not {}", KEY_BYTES, key.len()));
+    }
+    Ok(Aes256Gcm::new_from_slice(&key).expect("the key length was checked"))
+}
+
+/// Why a record was skipped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Damaged, truncated or tampered with.
+    Corrupt,
+    /// Encrypted with a key the reader was not given.
+    UnknownKey(String),
+}
+
+/// A record [`RecordReader`] could not read, at byte `offset` of its input.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SkippedRecord {
+    pub offset: usize,
+    pub reason: SkipReason,
+}
+
+impl fmt::Display for SkippedRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.reason {
+            SkipReason::Corrupt => write!(f, "corrupt record at byte {}", self.offset),
+            SkipReason::UnknownKey(id) => write!(f, "record at byte {} uses unknown key {:?}", self.offset, id),
+        }
+    }
+}
+
+/// Reads back the records of [`BodyStorage`], plain and encrypted alike, with any of its keys.
+pub struct RecordReader {
+    keys: BTreeMap<String, Aes256Gcm>,
+}
+
+impl RecordReader {
+    pub fn new(keys: BTreeMap<String, Aes256Gcm>) -> Self {
+        Self { keys }
+    }
+
+    /// The records in `data`, in order. Damaged records are skipped, up to the start of the next
+    /// encrypted record; a plain record that is not UTF-8 counts as damaged.
+    pub fn records<'a>(&'a self, data: &'a [u8]) -> Records<'a> {
+        Records {
+            reader: self,
+            data,
+            offset: 0,
+        }
+    }
+
+    /// Opens the encrypted record at the start of `data`, returning it and its length in bytes.
+    fn open(&self, data: &[u8]) -> Result<(Vec<u8>, usize), SkipReason> {
+        let id_len = *data.get(MAGIC.len() + 1).ok_or(SkipReason::Corrupt)? as usize;
+        let id_start = MAGIC.len() + 2;
+        let nonce_start = id_start + id_len;
+        let len_start = nonce_start + NONCE_BYTES;
+        let sealed_start = len_start + 4;
+        if data[MAGIC.len()] != VERSION || data.len() < sealed_start {
+            return Err(SkipReason::Corrupt);
+        }
+        let sealed_len = u32::from_be_bytes(data[len_start..sealed_start].try_into().unwrap()) as usize;
+        if !(TAG_BYTES..=MAX_RECORD_BYTES + TAG_BYTES).contains(&sealed_len) || data.len() < sealed_start + sealed_len {
+            return Err(SkipReason::Corrupt);
+        }
+        let key_id = std::str::from_utf8(&data[id_start..nonce_start]).map_err(|_| SkipReason::Corrupt)?;
+        let Some(cipher) = self.keys.get(key_id) else {
+            return Err(SkipReason::UnknownKey(key_id.to_string()));
+        };
+        let payload = Payload {
+            msg: &data[sealed_start..sealed_start + sealed_len],
+            aad: &data[..sealed_start],
+        };
+        let record = cipher
+            .decrypt(Nonce::from_slice(&data[nonce_start..len_start]), payload)
+            .map_err(|_| SkipReason::Corrupt)?;
+        Ok((record, sealed_start + sealed_len))
+    }
+}
+
+pub struct Records<'a> {
+    reader: &'a RecordReader,
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl Records<'_> {
+    /// Moves to the next encrypted record after the current offset, or to the end.
+    fn resync(&mut self) {
+        self.offset = find_magic(self.data, self.offset + 1).unwrap_or(self.data.len());
+    }
+}
+
+impl Iterator for Records<'_> {
+    type Item = Result<Vec<u8>, SkippedRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let rest = &self.data[self.offset..];
+            if rest.is_empty() {
+                return None;
+            }
+            let offset = self.offset;
+            let skipped = |reason| Some(Err(SkippedRecord { offset, reason }));
+            if rest[0] == MAGIC[0] && !rest.starts_with(&MAGIC) {
+                self.resync();
+                return skipped(SkipReason::Corrupt);
+            }
+            if !rest.starts_with(&MAGIC) {
+                // A plain record runs to the end of its line, or to an encrypted record written
+                // after a line that was cut short
+                let end = rest
+                    .iter()
+                    .position(|&b| b == b'\n' || b == MAGIC[0])
+                    .unwrap_or(rest.len());
+                let line = &rest[..end];
+                self.offset += end + usize::from(rest.get(end) == Some(&b'\n'));
+                if line.is_empty() {
+                    continue;
+                }
+                if std::str::from_utf8(line).is_err() {
+                    return skipped(SkipReason::Corrupt);
+                }
+                return Some(Ok(line.to_vec()));
+            }
+            return match self.reader.open(rest) {
+                Ok((record, len)) => {
+                    self.offset += len;
+                    Some(Ok(record))
+                }
+                Err(SkipReason::UnknownKey(id)) => {
+                    // The frame is intact as far as can be told, so it ends where it says
+                    let sealed_len_at = MAGIC.len() + 2 + id.len() + NONCE_BYTES;
+                    let sealed_len = u32::from_be_bytes(rest[sealed_len_at..sealed_len_at + 4].try_into().unwrap());
+                    self.offset += sealed_len_at + 4 + sealed_len as usize;
+                    skipped(SkipReason::UnknownKey(id))
+                }
+                Err(SkipReason::Corrupt) => {
+                    self.resync();
+                    skipped(SkipReason::Corrupt)
+                }
+            };
+        }
+    }
+}
+
+fn find_magic(data: &[u8], from: usize) -> Option<usize> {
+    data.get(from..)?
+        .windows(MAGIC.len())
+        .position(|window| window == MAGIC)
+        .map(|position| from + position)
+}
+
+/// Appends records to a file through [`BodyStorage`] from a dedicated thread, so the request path
+/// never blocks on disk or encryption. Past `max_bytes` the file is moved to `<path>.1`. A full
+/// queue or a failed write drops the record and counts it in `dropped_records`.
+#[derive(Clone)]
+pub struct RecordFile {
+    tx: Option<SyncSender<Vec<u8>>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl RecordFile {
+    /// The flight recorder's file, or a disabled one without `path` or with keys that cannot be
+    /// read, which validation reports.
+    pub fn flight_recorder(config: &FlightRecorderConfig, storage: &BodyStorageConfig) -> Self {
+        let Some(path) = config.path.clone() else {
+            return Self::disabled();
+        };
+        let storage = match self::storage(storage) {
+            Ok(storage) => storage,
+            Err(e) => {
+                tracing::error!("Not writing the flight recorder to {}: {}", path.display(), e);
+                return Self::disabled();
+            }
+        };
+        let dropped = Arc::new(AtomicU64::new(0));
+        let (tx, rx) = mpsc::sync_channel(config.queue_capacity.max(1));
+        let writer = RecordWriter {
+            path,
+            max_bytes: config.max_bytes,
+            storage,
+            file: None,
+            written: 0,
+            dropped: dropped.clone(),
+        };
+        std::thread::Builder::new()
+            .name("flight-recorder".to_string())
+            .spawn(move || writer.run(rx))
+            .expect("failed to spawn flight recorder writer");
+        Self { tx: Some(tx), dropped }
+    }
+
+    pub fn disabled() -> Self {
+        Self {
+            tx: None,
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn append(&self, record: Vec<u8>) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        if tx.try_send(record).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn dropped_records(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+struct RecordWriter {
+    path: PathBuf,
+    max_bytes: u64,
+    storage: Box<dyn BodyStorage>,
+    file: Option<File>,
+    written: u64,
+    dropped: Arc<AtomicU64>,
+}
+
+impl RecordWriter {
+    fn run(mut self, rx: Receiver<Vec<u8>>) {
+        let mut encoded = Vec::new();
+        for record in rx {
+            encoded.clear();
+            match self.storage.encode(&record, &mut encoded) {
+                Ok(()) => self.write(&encoded),
+                Err(e) => self.drop_record(&format!("failed to encode a record: {}", e)),
+            }
+        }
+    }
+
+    fn write(&mut self, encoded: &[u8]) {
+        if self.written > 0 && self.written + encoded.len() as u64 > self.max_bytes {
+            self.file = None;
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            if let Err(e) = fs::rename(&self.path, rotated) {
+                tracing::warn!("Failed to rotate {}: {}", self.path.display(), e);
+            }
+        }
+        if self.file.is_none() {
+            self.file = match OpenOptions::new().create(true).append(true).open(&self.path) {
+                Ok(file) => {
+                    self.written = file.metadata().map(|m| m.len()).unwrap_or(0);
+                    Some(file)
+                }
+                Err(e) => {
+                    self.drop_record(&format!("failed to open {}: {}", self.path.display(), e));
+                    return;
+                }
+            };
+        }
+        // One write per record, so a record is never interleaved with another
+        match self.file.as_mut().unwrap().write_all(encoded) {
+            Ok(()) => self.written += encoded.len() as u64,
+            Err(e) => {
+                self.drop_record(&format!("failed to write {}: {}", self.path.display(), e));
+                self.file = None;
+            }
+        }
+    }
+
+    fn drop_record(&self, reason: &str) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        tracing::warn!("Dropped a flight recorder record: {}", reason);
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct DecryptCaptureArgs {
+    /// File of records written by the gateway.
+    pub file: PathBuf,
+    /// Key to read records with, as `ID=BASE64`; may be repeated. Defaults to the keys of
+    /// `body_storage` in the config.
+    #[arg(long = "key")]
+    pub keys: Vec<String>,
+}
+
+/// Prints every record of a file, one per line, with the skipped ones reported on stderr.
+pub fn run_decrypt_capture(config: &BodyStorageConfig, args: DecryptCaptureArgs) -> ExitCode {
+    let keys = if args.keys.is_empty() {
+        keys(config)
+    } else {
+        args.keys
+            .iter()
+            .map(|arg| {
+                let (id, key) = arg
+                    .split_once('=')
+                    .ok_or_else(|| format!("--key {:?} is not ID=BASE64", arg))?;
+                Ok((id.to_string(), cipher(key).map_err(|e| format!("key {}: {}", id, e))?))
+            })
+            .collect()
+    };
+    let keys = match keys {
+        Ok(keys) => keys,
+        Err(e) => {
+            eprintln!("FAIL: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let data = match fs::read(&args.file) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("FAIL: Failed to read {}: {}", args.file.display(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let (mut read, mut skipped) = (0, 0);
+    let mut stdout = io::stdout().lock();
+    for record in RecordReader::new(keys).records(&data) {
+        match record {
+            Ok(record) => {
+                read += 1;
+                if stdout
+                    .write_all(&record)
+                    .and_then(|()| stdout.write_all(b"\n"))
+                    .is_err()
+                {
+                    return ExitCode::FAILURE;
+                }
+            }
+            Err(e) => {
+                skipped += 1;
+                eprintln!("skipped {}", e);
+            }
+        }
+    }
+    eprintln!("{} records, {} skipped", read, skipped);
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    include!("body_store_tests.rs");
+}
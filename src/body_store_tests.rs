@@ -0,0 +1,225 @@
+use super::*;
+use crate::config::StorageKey;
+use std::time::Duration;
+
+/// Base64 of 32 bytes of `byte`.
+fn key(byte: u8) -> String {
+    BASE64.encode([byte; KEY_BYTES])
+}
+
+fn encrypted(key_id: &str, byte: u8) -> EncryptedStorage {
+    EncryptedStorage::new(key_id, cipher(&key(byte)).unwrap())
+}
+
+fn reader(keys: &[(&str, u8)]) -> RecordReader {
+    RecordReader::new(keys.iter().map(|(id, byte)| (id.to_string(), cipher(&key(*byte)).unwrap())).collect())
+}
+
+fn encode(storage: &dyn BodyStorage, records: &[&[u8]]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for record in records {
+        storage.encode(record, &mut out).unwrap();
+    }
+    out
+}
+
+fn read(reader: &RecordReader, data: &[u8]) -> Vec<Result<Vec<u8>, SkippedRecord>> {
+    reader.records(data).collect()
+}
+
+fn ok(record: &[u8]) -> Result<Vec<u8>, SkippedRecord> {
+    Ok(record.to_vec())
+}
+
+fn corrupt(offset: usize) -> Result<Vec<u8>, SkippedRecord> {
+    Err(SkippedRecord {
+        offset,
+        reason: SkipReason::Corrupt,
+    })
+}
+
+#[test]
+fn test_plain_round_trip() {
+    let data = encode(&PlainStorage, &[br#"{"a":1}"#, b"", br#"{"b":2}"#]);
+    assert_eq!(data, b"{\"a\":1}\n\n{\"b\":2}\n");
+    // Empty lines are not records
+    assert_eq!(read(&reader(&[]), &data), [ok(br#"{"a":1}"#), ok(br#"{"b":2}"#)]);
+}
+
+#[test]
+fn test_encrypted_round_trip() {
+    let large = vec![b'x'; 100_000];
+    let records: [&[u8]; 4] = [br#"{"path":"/orders"}"#, b"", b"line\nbreaks \xff too", &large];
+    let data = encode(&encrypted("2026-10", 1), &records);
+    assert!(!data.windows(7).any(|w| w == b"/orders"));
+    let read = read(&reader(&[("2026-10", 1)]), &data);
+    assert_eq!(read, records.map(ok));
+
+    // The same record never encrypts the same way twice
+    let once = encode(&encrypted("2026-10", 1), &[b"same"]);
+    assert_ne!(once, encode(&encrypted("2026-10", 1), &[b"same"]));
+}
+
+#[test]
+fn test_encrypted_framing() {
+    let data = encode(&encrypted("k1", 1), &[b"hello"]);
+    assert_eq!(&data[..4], b"\xffLWG");
+    assert_eq!(data[4], 1, "version");
+    assert_eq!(data[5], 2, "key ID length");
+    assert_eq!(&data[6..8], b"k1");
+    let sealed_len = u32::from_be_bytes(data[20..24].try_into().unwrap()) as usize;
+    assert_eq!(sealed_len, 5 + TAG_BYTES);
+    assert_eq!(data.len(), 24 + sealed_len);
+
+    // Records stream: any prefix ending on a record boundary reads on its own
+    let data = encode(&encrypted("k1", 1), &[b"one", b"two"]);
+    let first_len = data.len() / 2;
+    assert_eq!(read(&reader(&[("k1", 1)]), &data[..first_len]), [ok(b"one")]);
+}
+
+#[test]
+fn test_key_rotation() {
+    let mut data = encode(&PlainStorage, &[b"plain"]);
+    data.extend(encode(&encrypted("old", 1), &[b"a", b"b"]));
+    let rotated_at = data.len();
+    data.extend(encode(&encrypted("new", 2), &[b"c"]));
+
+    let all = reader(&[("old", 1), ("new", 2)]);
+    assert_eq!(read(&all, &data), [ok(b"plain"), ok(b"a"), ok(b"b"), ok(b"c")]);
+
+    // Without the old key its records are skipped whole, and reading goes on
+    let read = read(&reader(&[("new", 2)]), &data);
+    assert_eq!(read.len(), 4);
+    assert_eq!(read[0], ok(b"plain"));
+    assert_eq!(
+        read[1],
+        Err(SkippedRecord {
+            offset: 6,
+            reason: SkipReason::UnknownKey("old".to_string())
+        })
+    );
+    assert!(matches!(&read[2], Err(SkippedRecord { reason: SkipReason::UnknownKey(_), .. })));
+    assert_eq!(read[3], ok(b"c"));
+    assert_eq!(rotated_at, 6 + 2 * (6 + 3 + NONCE_BYTES + 4 + 1 + TAG_BYTES));
+}
+
+#[test]
+fn test_corrupt_records_are_skipped() {
+    let storage = encrypted("k", 1);
+    let records = encode(&storage, &[b"first", b"secnd", b"third"]);
+    let record_len = records.len() / 3;
+    let reader = reader(&[("k", 1)]);
+
+    // A flipped bit in the ciphertext fails authentication
+    let mut data = records.clone();
+    data[record_len + record_len - 1] ^= 1;
+    assert_eq!(read(&reader, &data), [ok(b"first"), corrupt(record_len), ok(b"third")]);
+
+    // So does another key ID of the same length, even a known one
+    let mut data = records.clone();
+    data[record_len + 6] = b'j';
+    let both = self::reader(&[("k", 1), ("j", 1)]);
+    assert_eq!(read(&both, &data), [ok(b"first"), corrupt(record_len), ok(b"third")]);
+
+    // A damaged length or version
+    for at in [record_len + 4, record_len + 21] {
+        let mut data = records.clone();
+        data[at] = 0x7f;
+        assert_eq!(read(&reader, &data), [ok(b"first"), corrupt(record_len), ok(b"third")], "{}", at);
+    }
+
+    // A record cut short, as by a crash mid-write
+    let data = &records[..records.len() - 3];
+    assert_eq!(read(&reader, data), [ok(b"first"), ok(b"secnd"), corrupt(2 * record_len)]);
+    let mut data = records[..record_len + 10].to_vec();
+    data.extend(encode(&storage, &[b"after crash"]));
+    assert_eq!(read(&reader, &data), [ok(b"first"), corrupt(record_len), ok(b"after crash")]);
+
+    // Garbage that is not UTF-8 between records, or a damaged magic
+    let mut data = b"\x80\x81\n".to_vec();
+    data.extend(&records);
+    data[3 + record_len + 1] = b'X';
+    let expected = [corrupt(0), ok(b"first"), corrupt(3 + record_len), ok(b"third")];
+    assert_eq!(read(&reader, &data), expected);
+    assert_eq!(read(&reader, b"\xff"), [corrupt(0)]);
+    assert_eq!(read(&reader, b""), []);
+}
+
+#[test]
+fn test_keys_from_config() {
+    let config = |encrypt_with: Option<&str>, keys: &[(&str, StorageKey)]| BodyStorageConfig {
+        encrypt_with: encrypt_with.map(String::from),
+        keys: keys.iter().map(|(id, key)| (id.to_string(), key.clone())).collect(),
+    };
+    let inline = |byte| StorageKey {
+        key: Some(key(byte)),
+        env: None,
+    };
+    let from_env = |name: &str| StorageKey {
+        key: None,
+        env: Some(name.to_string()),
+    };
+    let env = |name: &str| (name == "CAPTURE_KEY").then(|| format!(" {}\n", key(2)));
+
+    let keys = keys_from(&config(None, &[("a", inline(1)), ("b", from_env("CAPTURE_KEY"))]), &env).unwrap();
+    assert_eq!(keys.keys().collect::<Vec<_>>(), ["a", "b"]);
+    let data = encode(&EncryptedStorage::new("b", keys["b"].clone()), &[b"secret"]);
+    assert_eq!(read(&self::reader(&[("b", 2)]), &data), [ok(b"secret")]);
+
+    let error = |config: BodyStorageConfig| keys_from(&config, &env).err().unwrap();
+    assert_eq!(error(config(None, &[("b", from_env("MISSING"))])), "key b: MISSING is not set");
+    assert_eq!(error(config(None, &[("", inline(1))])), "key ID \"\" must be 1 to 255 bytes long");
+    assert_eq!(error(config(None, &[("a", StorageKey::default())])), "key a needs exactly one of key and env");
+    let short = StorageKey {
+        key: Some(BASE64.encode([1; 16])),
+        env: None,
+    };
+    assert_eq!(error(config(None, &[("a", short)])), "key a: must be 32 bytes, not 16");
+    let invalid = StorageKey {
+        key: Some("not base64!".to_string()),
+        env: None,
+    };
+    assert_eq!(error(config(None, &[("a", invalid)])), "key a: not valid base64");
+
+    assert!(storage(&config(None, &[])).is_ok());
+    assert!(storage(&config(Some("a"), &[("a", inline(1))])).is_ok());
+    assert_eq!(
+        storage(&config(Some("b"), &[("a", inline(1))])).err().unwrap(),
+        "encrypt_with names unknown key \"b\""
+    );
+}
+
+#[test]
+fn test_record_file_rotates() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("failed.log");
+    let storage = BodyStorageConfig {
+        encrypt_with: Some("k".to_string()),
+        keys: BTreeMap::from([(
+            "k".to_string(),
+            StorageKey {
+                key: Some(key(1)),
+                env: None,
+            },
+        )]),
+    };
+    let config = FlightRecorderConfig {
+        path: Some(path.clone()),
+        max_bytes: 200,
+        ..Default::default()
+    };
+    let file = RecordFile::flight_recorder(&config, &storage);
+    for i in 0..10 {
+        file.append(format!(r#"{{"i":{}}}"#, i).into_bytes());
+    }
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(file.dropped_records(), 0);
+
+    let reader = reader(&[("k", 1)]);
+    let rotated = fs::read(dir.path().join("failed.log.1")).unwrap();
+    let current = fs::read(&path).unwrap();
+    assert!(rotated.len() <= 200 && current.len() <= 200);
+    let records: Vec<_> = reader.records(&rotated).chain(reader.records(&current)).map(Result::unwrap).collect();
+    assert_eq!(records.last().unwrap(), br#"{"i":9}"#);
+    assert!(records.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", records);
+}
@@ -0,0 +1,476 @@
+use crate::access_log::now_ms;
+use crate::config::CircuitBreakerConfig;
+use crate::state_store::StateStore;
+use crate::telemetry::Telemetry;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a half-open breaker waits for its probe to settle before a request on any replica may
+/// probe instead, e.g. when the replica probing was stopped midway.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// State of a target's circuit breaker.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Requests are invoked; failures are counted.
+    Closed,
+    /// Requests are answered 503 without invoking until the cooldown ends.
+    Open,
+    /// The cooldown has ended; one probe request is let through to decide.
+    HalfOpen,
+}
+
+impl BreakerState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half_open",
+        }
+    }
+}
+
+/// Circuit breaker of one target, as shown on `/status`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct BreakerStatus {
+    pub state: BreakerState,
+    /// Failures within the window, while closed.
+    pub recent_failures: usize,
+    /// Time left in the cooldown, while open.
+    pub retry_in_ms: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Breaker {
+    state: BreakerState,
+    /// When the breaker opened, while open.
+    opened_at: Option<Instant>,
+    /// Whether the probe of a half-open breaker is in flight.
+    probing: bool,
+    /// Times of the failures within the window, oldest first.
+    failures: VecDeque<Instant>,
+}
+
+impl Breaker {
+    fn new() -> Self {
+        Self {
+            state: BreakerState::Closed,
+            opened_at: None,
+            probing: false,
+            failures: VecDeque::new(),
+        }
+    }
+
+    /// Time left in the cooldown at `now`, if the breaker is open.
+    fn cooldown(&self, config: &CircuitBreakerConfig, now: Instant) -> Option<Duration> {
+        let opened_at = self.opened_at?;
+        let open_for = Duration::from_secs(config.open_secs);
+        Some(open_for.saturating_sub(now.saturating_duration_since(opened_at)))
+    }
+
+    fn forget_failures_before(&mut self, config: &CircuitBreakerConfig, now: Instant) {
+        let window = Duration::from_secs(config.window_secs);
+        while self
+            .failures
+            .front()
+            .is_some_and(|failure| now.saturating_duration_since(*failure) >= window)
+        {
+            self.failures.pop_front();
+        }
+    }
+}
+
+/// A breaker as kept in the state store. A closed breaker has no entry, or a closed one left by
+/// the probe that closed it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct StoredBreaker {
+    state: BreakerState,
+    /// When the state was entered, in milliseconds since the Unix epoch.
+    since_ms: u64,
+}
+
+impl StoredBreaker {
+    fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("a stored breaker serializes")
+    }
+
+    /// The breaker in `value`; a value that cannot be read counts as closed.
+    fn decode(value: Option<&[u8]>) -> Option<Self> {
+        value
+            .and_then(|value| serde_json::from_slice::<Self>(value).ok())
+            .filter(|stored| stored.state != BreakerState::Closed)
+    }
+}
+
+fn store_key(pattern: &str) -> String {
+    format!("circuit_breaker:{}", pattern)
+}
+
+/// Expiry of an open or half-open entry: a breaker nobody probes within [`PROBE_TIMEOUT`] of its
+/// cooldown ending is forgotten, i.e. closed.
+fn stored_ttl(config: &CircuitBreakerConfig) -> Duration {
+    Duration::from_secs(config.open_secs) + PROBE_TIMEOUT
+}
+
+/// Per-target circuit breakers. A breaker opens after `failure_threshold` failed invokes within
+/// `window_secs` and then fast-fails requests for `open_secs`. After that it lets a single probe
+/// through: a success closes it, a failure opens it again. Transitions are logged and counted in
+/// `circuit_breaker_transitions_total`.
+///
+/// Open and half-open breakers are kept in the state store, so that replicas share them and a
+/// restart does not close them. Transitions are compare-and-swaps on the stored entry, so only
+/// one request across the replicas probes a half-open breaker. Failures are counted by each
+/// replica, and a breaker is decided locally while the store cannot be reached.
+#[derive(Clone)]
+pub struct CircuitBreakers {
+    breakers: Arc<Mutex<HashMap<String, Breaker>>>,
+    store: Arc<dyn StateStore>,
+    /// A point in time both as an `Instant` and in wall-clock milliseconds, to convert between
+    /// them.
+    epoch: (Instant, u64),
+    telemetry: Telemetry,
+}
+
+/// The half-open entry a probe stored when it claimed the breaker, replaced when it settles.
+struct Claim {
+    claimed: Vec<u8>,
+    /// Put back when the probe is dropped unsettled: the breaker open, with its cooldown over.
+    release: Vec<u8>,
+    ttl: Duration,
+}
+
+/// Admission of a request by its target's breaker, to be settled with the invoke's outcome. A
+/// probe dropped unsettled, e.g. by a client going away, lets the next request probe instead.
+pub struct BreakerPermit {
+    breakers: CircuitBreakers,
+    pattern: String,
+    probe: bool,
+    /// Set for a probe claimed in the state store.
+    claim: Option<Claim>,
+    settled: bool,
+}
+
+impl BreakerPermit {
+    pub async fn settle(mut self, config: &CircuitBreakerConfig, failed: bool, now: Instant) {
+        self.settled = true;
+        let claim = self.claim.take();
+        self.breakers
+            .record(&self.pattern, config, self.probe, claim, failed, now)
+            .await;
+    }
+}
+
+impl Drop for BreakerPermit {
+    fn drop(&mut self) {
+        if !self.probe || self.settled {
+            return;
+        }
+        if let Some(breaker) = self.breakers.breakers.lock().unwrap().get_mut(&self.pattern) {
+            breaker.probing = false;
+        }
+        // Hands the probe back rather than leaving the other replicas waiting for PROBE_TIMEOUT
+        if let Some(claim) = self.claim.take() {
+            let release = self.breakers.store.compare_and_swap(
+                &store_key(&self.pattern),
+                Some(claim.claimed),
+                claim.release,
+                Some(claim.ttl),
+            );
+            if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                runtime.spawn(release);
+            }
+        }
+    }
+}
+
+impl CircuitBreakers {
+    pub fn new(telemetry: Telemetry, store: Arc<dyn StateStore>) -> Self {
+        Self {
+            breakers: Arc::default(),
+            store,
+            epoch: (Instant::now(), now_ms() as u64),
+            telemetry,
+        }
+    }
+
+    /// `at` in wall-clock milliseconds, the time kept in the state store.
+    fn wall_ms(&self, at: Instant) -> u64 {
+        let (instant, ms) = self.epoch;
+        match at.checked_duration_since(instant) {
+            Some(after) => ms + after.as_millis() as u64,
+            None => ms.saturating_sub(instant.duration_since(at).as_millis() as u64),
+        }
+    }
+
+    fn permit(&self, pattern: &str, probe: bool, claim: Option<Claim>) -> BreakerPermit {
+        BreakerPermit {
+            breakers: self.clone(),
+            pattern: pattern.to_string(),
+            probe,
+            claim,
+            settled: false,
+        }
+    }
+
+    /// Admits a request to `pattern` at `now`, or returns the time left in the cooldown of its open
+    /// breaker. While a half-open breaker's probe is in flight, other requests are rejected with
+    /// no time left.
+    pub async fn admit(
+        &self,
+        pattern: &str,
+        config: &CircuitBreakerConfig,
+        now: Instant,
+    ) -> Result<BreakerPermit, Duration> {
+        let key = store_key(pattern);
+        let value = match self.store.get(&key).await {
+            Ok(value) => value,
+            Err(e) => {
+                tracing::debug!(
+                    pattern,
+                    "Deciding the circuit breaker locally, its state cannot be read: {}",
+                    e
+                );
+                return self.admit_locally(pattern, config, now);
+            }
+        };
+        let Some(stored) = StoredBreaker::decode(value.as_deref()) else {
+            self.adopt(pattern, BreakerState::Closed, now);
+            return Ok(self.permit(pattern, false, None));
+        };
+        let at_ms = self.wall_ms(now);
+        let elapsed = Duration::from_millis(at_ms.saturating_sub(stored.since_ms));
+        let wait = match stored.state {
+            BreakerState::Open => Duration::from_secs(config.open_secs),
+            _ => PROBE_TIMEOUT,
+        };
+        if elapsed < wait {
+            self.adopt(pattern, stored.state, now.checked_sub(elapsed).unwrap_or(now));
+            return Err(match stored.state {
+                BreakerState::Open => wait - elapsed,
+                _ => Duration::ZERO,
+            });
+        }
+        // The cooldown is over, or the last probe never settled: whoever swaps first probes
+        let claim = Claim {
+            claimed: StoredBreaker {
+                state: BreakerState::HalfOpen,
+                since_ms: at_ms,
+            }
+            .encode(),
+            release: StoredBreaker {
+                state: BreakerState::Open,
+                since_ms: at_ms.saturating_sub(config.open_secs * 1000),
+            }
+            .encode(),
+            ttl: stored_ttl(config),
+        };
+        let swapped = self
+            .store
+            .compare_and_swap(&key, value, claim.claimed.clone(), Some(claim.ttl))
+            .await;
+        match swapped {
+            Ok(true) => {
+                let mut breakers = self.breakers.lock().unwrap();
+                let breaker = breakers.entry(pattern.to_string()).or_insert_with(Breaker::new);
+                if breaker.state != BreakerState::HalfOpen {
+                    self.transition(pattern, breaker, BreakerState::HalfOpen, now);
+                }
+                breaker.probing = true;
+                Ok(self.permit(pattern, true, Some(claim)))
+            }
+            Ok(false) => Err(Duration::ZERO),
+            Err(e) => {
+                tracing::debug!(
+                    pattern,
+                    "Deciding the circuit breaker locally, its state cannot be stored: {}",
+                    e
+                );
+                self.admit_locally(pattern, config, now)
+            }
+        }
+    }
+
+    /// Takes over the state another replica stored, or that this one stored before a restart.
+    /// `since` is when an open breaker opened.
+    fn adopt(&self, pattern: &str, state: BreakerState, since: Instant) {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(pattern.to_string()).or_insert_with(Breaker::new);
+        if breaker.state != state {
+            breaker.state = state;
+            breaker.probing = false;
+            breaker.failures.clear();
+        }
+        if state == BreakerState::Open {
+            breaker.opened_at = Some(since);
+        }
+    }
+
+    /// [`Self::admit`] without the state store.
+    fn admit_locally(
+        &self,
+        pattern: &str,
+        config: &CircuitBreakerConfig,
+        now: Instant,
+    ) -> Result<BreakerPermit, Duration> {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(pattern.to_string()).or_insert_with(Breaker::new);
+        if breaker.state == BreakerState::Open {
+            match breaker.cooldown(config, now) {
+                Some(left) if !left.is_zero() => return Err(left),
+                _ => self.transition(pattern, breaker, BreakerState::HalfOpen, now),
+            }
+        }
+        let probe = breaker.state == BreakerState::HalfOpen;
+        if probe {
+            if breaker.probing {
+                return Err(Duration::ZERO);
+            }
+            breaker.probing = true;
+        }
+        Ok(self.permit(pattern, probe, None))
+    }
+
+    async fn record(
+        &self,
+        pattern: &str,
+        config: &CircuitBreakerConfig,
+        probe: bool,
+        claim: Option<Claim>,
+        failed: bool,
+        now: Instant,
+    ) {
+        let key = store_key(pattern);
+        if probe {
+            let next = if failed {
+                BreakerState::Open
+            } else {
+                BreakerState::Closed
+            };
+            let mut settled_elsewhere = false;
+            if let Some(claim) = claim {
+                let stored = StoredBreaker {
+                    state: next,
+                    since_ms: self.wall_ms(now),
+                };
+                let ttl = match next {
+                    BreakerState::Open => stored_ttl(config),
+                    _ => Duration::from_secs(config.window_secs),
+                };
+                match self
+                    .store
+                    .compare_and_swap(&key, Some(claim.claimed), stored.encode(), Some(ttl))
+                    .await
+                {
+                    Ok(swapped) => settled_elsewhere = !swapped,
+                    Err(e) => tracing::warn!(pattern, "Circuit breaker settled on this replica only: {}", e),
+                }
+            }
+            let mut breakers = self.breakers.lock().unwrap();
+            let Some(breaker) = breakers.get_mut(pattern) else {
+                return;
+            };
+            breaker.probing = false;
+            // A probe that outlived PROBE_TIMEOUT was taken over; the new probe decides
+            if !settled_elsewhere {
+                self.transition(pattern, breaker, next, now);
+            }
+            return;
+        }
+        let opened = {
+            let mut breakers = self.breakers.lock().unwrap();
+            let Some(breaker) = breakers.get_mut(pattern) else {
+                return;
+            };
+            // Requests admitted before the breaker opened may still finish; they change nothing
+            if breaker.state != BreakerState::Closed || !failed {
+                return;
+            }
+            breaker.forget_failures_before(config, now);
+            breaker.failures.push_back(now);
+            let opened = breaker.failures.len() >= config.failure_threshold as usize;
+            if opened {
+                self.transition(pattern, breaker, BreakerState::Open, now);
+            }
+            opened
+        };
+        if opened {
+            self.store_open(pattern, config, now).await;
+        }
+    }
+
+    /// Stores the breaker this replica opened, unless another replica opened it first.
+    async fn store_open(&self, pattern: &str, config: &CircuitBreakerConfig, now: Instant) {
+        let key = store_key(pattern);
+        let open = StoredBreaker {
+            state: BreakerState::Open,
+            since_ms: self.wall_ms(now),
+        };
+        let stored = match self.store.get(&key).await {
+            Ok(value) if StoredBreaker::decode(value.as_deref()).is_some() => return,
+            Ok(value) => {
+                self.store
+                    .compare_and_swap(&key, value, open.encode(), Some(stored_ttl(config)))
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = stored {
+            tracing::warn!(pattern, "Circuit breaker opened on this replica only: {}", e);
+        }
+    }
+
+    fn transition(&self, pattern: &str, breaker: &mut Breaker, state: BreakerState, now: Instant) {
+        match state {
+            BreakerState::Open => {
+                tracing::warn!(pattern, "Circuit breaker opened");
+                breaker.opened_at = Some(now);
+            }
+            BreakerState::HalfOpen => tracing::info!(pattern, "Circuit breaker half-open, probing"),
+            BreakerState::Closed => tracing::info!(pattern, "Circuit breaker closed"),
+        }
+        if state != BreakerState::Open {
+            breaker.opened_at = None;
+        }
+        breaker.failures.clear();
+        breaker.state = state;
+        self.telemetry.increment(
+            "circuit_breaker_transitions_total",
+            vec![("target", pattern.to_string()), ("state", state.as_str().to_string())],
+        );
+    }
+
+    /// Every tracked target's breaker as of `now`, with the breaker settings of `configs`.
+    pub fn status<'a>(
+        &self,
+        configs: impl Fn(&str) -> Option<&'a CircuitBreakerConfig>,
+        now: Instant,
+    ) -> BTreeMap<String, BreakerStatus> {
+        let mut breakers = self.breakers.lock().unwrap();
+        breakers
+            .iter_mut()
+            .filter_map(|(pattern, breaker)| {
+                let config = configs(pattern)?;
+                breaker.forget_failures_before(config, now);
+                let status = BreakerStatus {
+                    state: breaker.state,
+                    recent_failures: breaker.failures.len(),
+                    retry_in_ms: breaker.cooldown(config, now).map(|left| left.as_millis() as u64),
+                };
+                Some((pattern.clone(), status))
+            })
+            .collect()
+    }
+
+    /// Forgets a target removed by a reload, or one that no longer has a breaker.
+    pub fn remove(&self, pattern: &str) {
+        self.breakers.lock().unwrap().remove(pattern);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("breaker_tests.rs");
+}
@@ -0,0 +1,235 @@
+use super::*;
+use crate::mock::UnreachableStore;
+use crate::state_store::{FallbackStore, MemoryStore};
+
+static CONFIG: CircuitBreakerConfig = CircuitBreakerConfig {
+    failure_threshold: 3,
+    window_secs: 10,
+    open_secs: 5,
+};
+
+fn breakers_on(store: Arc<dyn StateStore>) -> CircuitBreakers {
+    let telemetry = Telemetry::new(&Default::default());
+    CircuitBreakers::new(telemetry, store)
+}
+
+fn breakers() -> CircuitBreakers {
+    breakers_on(Arc::new(MemoryStore::default()))
+}
+
+fn config() -> CircuitBreakerConfig {
+    CONFIG.clone()
+}
+
+async fn fail_on(breakers: &CircuitBreakers, pattern: &str, at: Instant) {
+    let permit = breakers.admit(pattern, &config(), at).await.unwrap();
+    permit.settle(&config(), true, at).await;
+}
+
+async fn fail(breakers: &CircuitBreakers, at: Instant) {
+    fail_on(breakers, "/orders", at).await;
+}
+
+fn state_of(breakers: &CircuitBreakers, pattern: &str, now: Instant) -> BreakerState {
+    breakers.status(|_| Some(&CONFIG), now)[pattern].state
+}
+
+fn state(breakers: &CircuitBreakers, now: Instant) -> BreakerState {
+    state_of(breakers, "/orders", now)
+}
+
+#[tokio::test]
+async fn test_opens_after_threshold_within_window() {
+    let breakers = breakers();
+    let start = Instant::now();
+    fail(&breakers, start).await;
+    fail(&breakers, start + Duration::from_secs(5)).await;
+    // The first failure has left the window by the third
+    fail(&breakers, start + Duration::from_secs(11)).await;
+    assert_eq!(state(&breakers, start + Duration::from_secs(11)), BreakerState::Closed);
+    fail(&breakers, start + Duration::from_secs(12)).await;
+    assert_eq!(state(&breakers, start + Duration::from_secs(12)), BreakerState::Open);
+
+    let left = breakers.admit("/orders", &config(), start + Duration::from_secs(14)).await.err();
+    assert_eq!(left, Some(Duration::from_secs(3)));
+    let status = &breakers.status(|_| Some(&CONFIG), start + Duration::from_secs(14))["/orders"];
+    assert_eq!(status.retry_in_ms, Some(3000));
+}
+
+#[tokio::test]
+async fn test_half_open_lets_one_probe_through() {
+    let breakers = breakers();
+    let start = Instant::now();
+    for _ in 0..3 {
+        fail(&breakers, start).await;
+    }
+
+    let after_cooldown = start + Duration::from_secs(5);
+    let probe = breakers.admit("/orders", &config(), after_cooldown).await.unwrap();
+    assert_eq!(state(&breakers, after_cooldown), BreakerState::HalfOpen);
+    let rejected = breakers.admit("/orders", &config(), after_cooldown).await.err();
+    assert_eq!(rejected, Some(Duration::ZERO));
+
+    // A failed probe opens the breaker for another cooldown
+    probe.settle(&config(), true, after_cooldown).await;
+    assert_eq!(state(&breakers, after_cooldown), BreakerState::Open);
+    let during_cooldown = after_cooldown + Duration::from_secs(4);
+    assert!(breakers.admit("/orders", &config(), during_cooldown).await.is_err());
+
+    // A successful one closes it
+    let later = after_cooldown + Duration::from_secs(5);
+    let probe = breakers.admit("/orders", &config(), later).await.unwrap();
+    probe.settle(&config(), false, later).await;
+    assert_eq!(state(&breakers, later), BreakerState::Closed);
+    assert!(breakers.admit("/orders", &config(), later).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_dropped_probe_frees_the_half_open_slot() {
+    let breakers = breakers();
+    let start = Instant::now();
+    for _ in 0..3 {
+        fail(&breakers, start).await;
+    }
+
+    let after_cooldown = start + Duration::from_secs(5);
+    drop(breakers.admit("/orders", &config(), after_cooldown).await.unwrap());
+    assert!(breakers.admit("/orders", &config(), after_cooldown).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_late_results_do_not_count_while_open() {
+    let breakers = breakers();
+    let start = Instant::now();
+    let slow = breakers.admit("/orders", &config(), start).await.unwrap();
+    for _ in 0..3 {
+        fail(&breakers, start).await;
+    }
+    slow.settle(&config(), true, start).await;
+
+    let after_cooldown = start + Duration::from_secs(5);
+    let probe = breakers.admit("/orders", &config(), after_cooldown).await.unwrap();
+    probe.settle(&config(), false, after_cooldown).await;
+    assert_eq!(state(&breakers, after_cooldown), BreakerState::Closed);
+    let status = &breakers.status(|_| Some(&CONFIG), after_cooldown)["/orders"];
+    assert_eq!(status.recent_failures, 0);
+}
+
+#[tokio::test]
+async fn test_stale_probe_is_taken_over() {
+    let breakers = breakers();
+    let start = Instant::now();
+    for _ in 0..3 {
+        fail(&breakers, start).await;
+    }
+    let after_cooldown = start + Duration::from_secs(5);
+    let stuck = breakers.admit("/orders", &config(), after_cooldown).await.unwrap();
+
+    let stale = after_cooldown + PROBE_TIMEOUT;
+    let probe = breakers.admit("/orders", &config(), stale).await.unwrap();
+    // The stuck probe settling late changes nothing, the probe that took over decides
+    stuck.settle(&config(), false, stale).await;
+    assert!(breakers.admit("/orders", &config(), stale).await.is_err());
+    probe.settle(&config(), false, stale).await;
+    assert_eq!(state(&breakers, stale), BreakerState::Closed);
+}
+
+/// Breaker behaviour that must hold whatever store the state is kept in, or fails to be kept in.
+async fn breaker_contract(store: Arc<dyn StateStore>, pattern: &str) {
+    let breakers = breakers_on(store);
+    let start = Instant::now();
+    for _ in 0..3 {
+        fail_on(&breakers, pattern, start).await;
+    }
+    assert_eq!(state_of(&breakers, pattern, start), BreakerState::Open);
+    let during_cooldown = start + Duration::from_secs(2);
+    let left = breakers.admit(pattern, &config(), during_cooldown).await.err();
+    assert!(left.is_some_and(|left| left > Duration::from_secs(2) && left <= Duration::from_secs(3)));
+
+    let after_cooldown = start + Duration::from_secs(6);
+    let probe = breakers.admit(pattern, &config(), after_cooldown).await.unwrap();
+    assert!(breakers.admit(pattern, &config(), after_cooldown).await.is_err());
+    drop(probe);
+    // A remote store is handed the probe back in the background
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let probe = breakers.admit(pattern, &config(), after_cooldown).await.unwrap();
+    probe.settle(&config(), true, after_cooldown).await;
+    assert_eq!(state_of(&breakers, pattern, after_cooldown), BreakerState::Open);
+
+    let later = after_cooldown + Duration::from_secs(6);
+    let probe = breakers.admit(pattern, &config(), later).await.unwrap();
+    probe.settle(&config(), false, later).await;
+    assert_eq!(state_of(&breakers, pattern, later), BreakerState::Closed);
+    assert!(breakers.admit(pattern, &config(), later).await.is_ok());
+}
+
+/// Breakers sharing `store`, as replicas or a replica and its restart do, see the same state and
+/// let a single probe through between them.
+async fn shared_breaker_contract(store: Arc<dyn StateStore>, pattern: &str) {
+    let (first, second) = (breakers_on(store.clone()), breakers_on(store));
+    let start = Instant::now();
+    for _ in 0..3 {
+        fail_on(&first, pattern, start).await;
+    }
+    let during_cooldown = start + Duration::from_secs(2);
+    assert!(second.admit(pattern, &config(), during_cooldown).await.is_err());
+    assert_eq!(state_of(&second, pattern, during_cooldown), BreakerState::Open);
+
+    let after_cooldown = start + Duration::from_secs(6);
+    let (a, b) = futures::join!(
+        first.admit(pattern, &config(), after_cooldown),
+        second.admit(pattern, &config(), after_cooldown)
+    );
+    assert_eq!(a.is_ok() as usize + b.is_ok() as usize, 1);
+    let probe = a.or(b).unwrap();
+    probe.settle(&config(), false, after_cooldown).await;
+    assert!(first.admit(pattern, &config(), after_cooldown).await.is_ok());
+    assert!(second.admit(pattern, &config(), after_cooldown).await.is_ok());
+    assert_eq!(state_of(&second, pattern, after_cooldown), BreakerState::Closed);
+}
+
+#[tokio::test]
+async fn test_breaker_contract_on_memory_store() {
+    let store: Arc<dyn StateStore> = Arc::new(MemoryStore::default());
+    breaker_contract(store.clone(), "/orders").await;
+    shared_breaker_contract(store, "/payments").await;
+}
+
+#[tokio::test]
+async fn test_breaker_contract_while_store_is_down() {
+    breaker_contract(Arc::new(UnreachableStore), "/orders").await;
+    let fallback = FallbackStore::new(Arc::new(UnreachableStore), Arc::new(MemoryStore::default()), 50);
+    breaker_contract(Arc::new(fallback), "/orders").await;
+}
+
+#[tokio::test]
+async fn test_probe_race_has_one_winner() {
+    let store: Arc<dyn StateStore> = Arc::new(MemoryStore::default());
+    let replicas: Vec<_> = (0..8).map(|_| breakers_on(store.clone())).collect();
+    let start = Instant::now();
+    for _ in 0..3 {
+        fail(&replicas[0], start).await;
+    }
+    let after_cooldown = start + Duration::from_secs(6);
+    let admitted = futures::future::join_all(
+        replicas
+            .iter()
+            .map(|replica| replica.admit("/orders", &config(), after_cooldown)),
+    )
+    .await;
+    assert_eq!(admitted.iter().filter(|permit| permit.is_ok()).count(), 1);
+}
+
+/// Runs the contracts against the server in `REDIS_URL`, when set.
+#[cfg(feature = "redis-state")]
+#[tokio::test]
+async fn test_breaker_contract_on_redis_store() {
+    let Ok(url) = std::env::var("REDIS_URL") else {
+        eprintln!("REDIS_URL is not set, skipping the Redis breaker contract test");
+        return;
+    };
+    let store: Arc<dyn StateStore> = Arc::new(crate::redis_store::RedisStore::new(&url).unwrap());
+    let prefix = format!("/gateway-contract-{}", now_ms());
+    breaker_contract(store.clone(), &format!("{}/orders", prefix)).await;
+    shared_breaker_contract(store, &format!("{}/payments", prefix)).await;
+}
@@ -0,0 +1,90 @@
+use crate::config::PayloadBufferConfig;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Returned buffers between attempts to shrink one to its target's recent needs.
+const SHRINK_EVERY: u64 = 64;
+
+/// Reusable buffers for serializing invoke payloads. A payload is written into a pooled buffer
+/// already sized for its target, then copied out at its exact size, so steady traffic no longer
+/// grows a fresh buffer by doubling on every request. Each target's size is a high-water mark that
+/// decays by an eighth per request, so a single large payload is forgotten after a while.
+///
+/// Retained memory is capped by `max_retained_bytes`, and buffers over `max_buffer_bytes` are
+/// dropped rather than kept. Every [`SHRINK_EVERY`] returns, a buffer more than twice its target's
+/// high-water mark is shrunk to it.
+#[derive(Clone)]
+pub struct BufferPool {
+    config: PayloadBufferConfig,
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    free: Vec<Vec<u8>>,
+    retained_bytes: usize,
+    /// Decaying largest payload per target pattern.
+    high_water: HashMap<String, usize>,
+    returns: u64,
+}
+
+impl BufferPool {
+    pub fn new(config: &PayloadBufferConfig) -> Self {
+        Self {
+            config: config.clone(),
+            inner: Arc::default(),
+        }
+    }
+
+    /// Serializes the event of a request to `target`, the pattern of its target.
+    pub fn serialize(&self, target: &str, event: &serde_json::Value) -> String {
+        let mut buffer = self.take(target);
+        serde_json::to_writer(&mut buffer, event).expect("JSON values always serialize");
+        let payload = String::from_utf8(buffer.to_vec()).expect("serde_json writes UTF-8");
+        self.put(target, buffer);
+        payload
+    }
+
+    /// Capacity of the buffers currently kept for reuse.
+    pub fn retained_bytes(&self) -> usize {
+        self.inner.lock().unwrap().retained_bytes
+    }
+
+    fn take(&self, target: &str) -> Vec<u8> {
+        let mut inner = self.inner.lock().unwrap();
+        let wanted = inner.high_water.get(target).copied().unwrap_or_default();
+        let mut buffer = inner.free.pop().unwrap_or_default();
+        inner.retained_bytes -= buffer.capacity();
+        drop(inner);
+        buffer.reserve(wanted);
+        buffer
+    }
+
+    fn put(&self, target: &str, mut buffer: Vec<u8>) {
+        let len = buffer.len();
+        buffer.clear();
+        let mut inner = self.inner.lock().unwrap();
+        let high_water = match inner.high_water.get_mut(target) {
+            Some(high_water) => {
+                *high_water = len.max(*high_water - *high_water / 8);
+                *high_water
+            }
+            None => *inner.high_water.entry(target.to_string()).or_insert(len),
+        };
+        inner.returns += 1;
+        if inner.returns.is_multiple_of(SHRINK_EVERY) && buffer.capacity() > high_water * 2 {
+            buffer.shrink_to(high_water);
+        }
+        let capacity = buffer.capacity();
+        if capacity > self.config.max_buffer_bytes || inner.retained_bytes + capacity > self.config.max_retained_bytes {
+            return;
+        }
+        inner.retained_bytes += capacity;
+        inner.free.push(buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("buffer_pool_tests.rs");
+}
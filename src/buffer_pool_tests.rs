@@ -0,0 +1,81 @@
+use super::*;
+use serde_json::json;
+
+fn pool_with(max_retained_bytes: usize, max_buffer_bytes: usize) -> BufferPool {
+    BufferPool::new(&PayloadBufferConfig {
+        max_retained_bytes,
+        max_buffer_bytes,
+    })
+}
+
+fn event(body_bytes: usize) -> serde_json::Value {
+    json!({"path": "/", "body": "a".repeat(body_bytes)})
+}
+
+fn free_capacities(pool: &BufferPool) -> Vec<usize> {
+    pool.inner.lock().unwrap().free.iter().map(Vec::capacity).collect()
+}
+
+#[test]
+fn test_serializes_like_to_string() {
+    let pool = BufferPool::new(&PayloadBufferConfig::default());
+    let event = json!({"httpMethod": "GET", "headers": {"accept": "*/*"}, "body": "é"});
+    assert_eq!(pool.serialize("/a", &event), event.to_string());
+    assert_eq!(pool.serialize("/a", &event), event.to_string());
+}
+
+#[test]
+fn test_buffers_are_reused_at_the_high_water_mark() {
+    let pool = BufferPool::new(&PayloadBufferConfig::default());
+    let len = pool.serialize("/a", &event(10_000)).len();
+    assert_eq!(free_capacities(&pool).len(), 1);
+    assert_eq!(pool.retained_bytes(), free_capacities(&pool)[0]);
+
+    // The next buffer for the target is taken from the pool and fits without growing
+    let buffer = pool.take("/a");
+    assert!(buffer.capacity() >= len);
+    assert_eq!(pool.retained_bytes(), 0);
+    pool.put("/a", buffer);
+    assert_eq!(free_capacities(&pool).len(), 1);
+}
+
+#[test]
+fn test_high_water_mark_decays() {
+    let pool = BufferPool::new(&PayloadBufferConfig::default());
+    pool.serialize("/a", &event(8000));
+    let peak = pool.inner.lock().unwrap().high_water["/a"];
+    for _ in 0..20 {
+        pool.serialize("/a", &event(10));
+    }
+    let high_water = pool.inner.lock().unwrap().high_water["/a"];
+    assert!(high_water < peak / 10, "{} of {}", high_water, peak);
+    assert!(high_water >= event(10).to_string().len());
+}
+
+#[test]
+fn test_retained_memory_is_capped() {
+    // Buffers that would take the pool over its budget are freed
+    let pool = pool_with(4096, 1024 * 1024);
+    pool.serialize("/a", &event(10_000));
+    assert!(free_capacities(&pool).is_empty());
+    assert_eq!(pool.retained_bytes(), 0);
+
+    // So are buffers grown past the largest kept size
+    let pool = pool_with(1024 * 1024, 1024);
+    pool.serialize("/a", &event(2000));
+    assert!(free_capacities(&pool).is_empty());
+    pool.serialize("/b", &event(10));
+    assert_eq!(free_capacities(&pool).len(), 1);
+}
+
+#[test]
+fn test_oversized_buffers_are_shrunk() {
+    let pool = BufferPool::new(&PayloadBufferConfig::default());
+    pool.serialize("/a", &event(100_000));
+    let large = free_capacities(&pool)[0];
+    for _ in 0..SHRINK_EVERY {
+        pool.serialize("/a", &event(10));
+    }
+    let capacity = free_capacities(&pool)[0];
+    assert!(capacity < large / 2, "{} of {}", capacity, large);
+}
@@ -0,0 +1,156 @@
+use crate::config::CanonicalRequestConfig;
+use crate::hosts;
+use axum::http::{HeaderMap, Method, Uri};
+use percent_encoding::{percent_decode_str, percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+/// Bytes written as they are in canonical paths and query strings: the RFC 3986 unreserved
+/// characters. Everything else is percent-encoded with uppercase hex digits.
+const ENCODE: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+/// The one answer to "is this the same request": every feature that signs, caches, deduplicates or
+/// keys requests builds this from the request instead of normalizing on its own, so they never
+/// disagree.
+///
+/// Its [`Display`](fmt::Display) form is meant to be read by people and compared by machines, one
+/// field per line:
+///
+/// ```text
+/// POST
+/// /orders/42
+/// page=2&tag=a&tag=b
+/// content-type:application/json
+///
+/// content-type;x-tenant
+/// <hex SHA-256 of the body>
+/// ```
+///
+/// The header lines hold the participating headers the request carries; the line after the blank
+/// one lists every participating header, so requests built under different configs never collide.
+/// The format, and so [`CanonicalRequest::hash`], only changes deliberately between releases.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CanonicalRequest {
+    /// Uppercase method.
+    pub method: String,
+    /// See [`normalize_path`].
+    pub path: String,
+    /// See [`normalize_query`].
+    pub query: String,
+    /// Participating headers present on the request as `(name, value)`, sorted by name.
+    pub headers: Vec<(String, String)>,
+    /// Every participating header name, lowercase and sorted.
+    pub header_names: Vec<String>,
+    /// Hex SHA-256 of the body.
+    pub body_sha256: String,
+}
+
+impl CanonicalRequest {
+    pub fn new(config: &CanonicalRequestConfig, method: &Method, uri: &Uri, headers: &HeaderMap, body: &[u8]) -> Self {
+        let mut header_names: Vec<String> = config
+            .headers
+            .iter()
+            .map(|name| name.trim().to_ascii_lowercase())
+            .collect();
+        header_names.sort();
+        header_names.dedup();
+        Self {
+            method: method.as_str().to_ascii_uppercase(),
+            path: normalize_path(uri.path()),
+            query: normalize_query(uri.query().unwrap_or_default()),
+            headers: header_names
+                .iter()
+                .filter_map(|name| Some((name.clone(), header_value(name, uri, headers)?)))
+                .collect(),
+            header_names,
+            body_sha256: format!("{:x}", Sha256::digest(body)),
+        }
+    }
+
+    /// SHA-256 of the [`Display`](fmt::Display) form.
+    pub fn hash(&self) -> [u8; 32] {
+        Sha256::digest(self.to_string().as_bytes()).into()
+    }
+
+    /// [`CanonicalRequest::hash`] as 64 lowercase hex digits.
+    pub fn hash_hex(&self) -> String {
+        self.hash().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+impl fmt::Display for CanonicalRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.method)?;
+        writeln!(f, "{}", self.path)?;
+        writeln!(f, "{}", self.query)?;
+        for (name, value) in &self.headers {
+            writeln!(f, "{}:{}", name, value)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{}", self.header_names.join(";"))?;
+        write!(f, "{}", self.body_sha256)
+    }
+}
+
+/// A raw path with each segment percent-decoded and re-encoded, so `%7e`, `%7E` and `~` agree,
+/// and with `.` and `..` segments resolved. Empty segments are dropped, which collapses runs of
+/// slashes and removes a trailing slash: `/a//b/` is `/a/b`. An encoded `/` (`%2F`) stays part of
+/// its segment.
+pub fn normalize_path(raw_path: &str) -> String {
+    let mut segments: Vec<String> = Vec::new();
+    for segment in raw_path.split('/') {
+        let decoded: Vec<u8> = percent_decode_str(segment).collect();
+        match decoded.as_slice() {
+            b"" | b"." => {}
+            b".." => {
+                segments.pop();
+            }
+            _ => segments.push(percent_encode(&decoded, ENCODE).to_string()),
+        }
+    }
+    format!("/{}", segments.join("/"))
+}
+
+/// A raw query string as `key=value` pairs decoded like a form, with `+` as a space, re-encoded
+/// and sorted by key, then value. Duplicate keys keep every value, so `tag=b&tag=a` and
+/// `tag=a&tag=b` agree. A key without `=` is the same as one with an empty value.
+pub fn normalize_query(raw_query: &str) -> String {
+    let mut pairs: Vec<(String, String)> = url::form_urlencoded::parse(raw_query.as_bytes())
+        .map(|(key, value)| {
+            (
+                percent_encode(key.as_bytes(), ENCODE).to_string(),
+                percent_encode(value.as_bytes(), ENCODE).to_string(),
+            )
+        })
+        .collect();
+    pairs.sort();
+    pairs
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// The values of header `name`, each trimmed with inner whitespace collapsed to one space, joined
+/// with `,` in the order received. `host` is the host the request was sent to, lowercase.
+fn header_value(name: &str, uri: &Uri, headers: &HeaderMap) -> Option<String> {
+    if name == "host" {
+        return hosts::presented_host(uri, headers).map(|host| host.to_ascii_lowercase());
+    }
+    let values: Vec<String> = headers
+        .get_all(name)
+        .iter()
+        .map(|value| {
+            String::from_utf8_lossy(value.as_bytes())
+                .split_ascii_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect();
+    (!values.is_empty()).then(|| values.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    include!("canonical_tests.rs");
+}
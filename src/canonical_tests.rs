@@ -0,0 +1,154 @@
+use super::*;
+
+fn canonical(
+    headers: &[&str],
+    method: &str,
+    uri: &str,
+    request_headers: &[(&str, &str)],
+    body: &[u8],
+) -> CanonicalRequest {
+    let config = CanonicalRequestConfig {
+        headers: headers.iter().map(|name| name.to_string()).collect(),
+    };
+    let mut map = HeaderMap::new();
+    for (name, value) in request_headers {
+        map.append(axum::http::HeaderName::try_from(*name).unwrap(), value.parse().unwrap());
+    }
+    CanonicalRequest::new(&config, &method.parse().unwrap(), &uri.parse().unwrap(), &map, body)
+}
+
+#[test]
+fn test_normalize_path() {
+    for (raw, expected) in [
+        ("/", "/"),
+        ("", "/"),
+        ("/orders", "/orders"),
+        ("/orders/", "/orders"),
+        ("//orders///42//", "/orders/42"),
+        ("/a/./b/../c", "/a/c"),
+        ("/../../a", "/a"),
+        ("/a/%2E%2E/b", "/b"),
+        ("/%7euser/%7Ename/~x", "/~user/~name/~x"),
+        ("/caf%c3%a9", "/caf%C3%A9"),
+        ("/café", "/caf%C3%A9"),
+        ("/a%2fb/c", "/a%2Fb/c"),
+        ("/a%20b/a+b", "/a%20b/a%2Bb"),
+        ("/%ff", "/%FF"),
+    ] {
+        assert_eq!(normalize_path(raw), expected, "{}", raw);
+    }
+}
+
+#[test]
+fn test_normalize_query() {
+    for (raw, expected) in [
+        ("", ""),
+        ("b=2&a=1", "a=1&b=2"),
+        ("tag=b&tag=a&tag=b", "tag=a&tag=b&tag=b"),
+        ("a=1&&b=2&", "a=1&b=2"),
+        ("flag&x=", "flag=&x="),
+        ("q=a+b&r=a%20b", "q=a%20b&r=a%20b"),
+        ("k=%7e&K=1", "K=1&k=~"),
+        ("a%3Db=c%26d", "a%3Db=c%26d"),
+        ("name=caf%c3%a9", "name=caf%C3%A9"),
+    ] {
+        assert_eq!(normalize_query(raw), expected, "{}", raw);
+    }
+}
+
+#[test]
+fn test_query_order_and_trailing_slash_do_not_matter() {
+    let a = canonical(&[], "get", "/orders/?page=2&tag=b&tag=a", &[], b"");
+    let b = canonical(&[], "GET", "/orders?tag=a&page=2&tag=b", &[], b"");
+    assert_eq!(a, b);
+    assert_eq!(a.hash(), b.hash());
+
+    // Different values, keys or methods are different requests
+    for other in [
+        canonical(&[], "GET", "/orders?tag=a&page=3&tag=b", &[], b""),
+        canonical(&[], "GET", "/orders?tag=a&page=2", &[], b""),
+        canonical(&[], "HEAD", "/orders?tag=a&page=2&tag=b", &[], b""),
+        canonical(&[], "GET", "/orders/x?tag=a&page=2&tag=b", &[], b""),
+    ] {
+        assert_ne!(a.hash(), other.hash(), "{}", other);
+    }
+}
+
+#[test]
+fn test_selected_headers_only() {
+    let headers = ["Content-Type", "x-tenant", "content-type"];
+    let a = canonical(
+        &headers,
+        "POST",
+        "/orders",
+        &[("content-type", "application/json"), ("X-Tenant", "  acme   corp "), ("x-request-id", "1")],
+        b"{}",
+    );
+    let b = canonical(
+        &headers,
+        "POST",
+        "/orders",
+        &[("x-tenant", "acme corp"), ("Content-Type", "application/json"), ("x-request-id", "2")],
+        b"{}",
+    );
+    assert_eq!(a, b);
+    assert_eq!(a.header_names, ["content-type", "x-tenant"]);
+    assert_eq!(
+        a.headers,
+        [
+            ("content-type".to_string(), "application/json".to_string()),
+            ("x-tenant".to_string(), "acme corp".to_string())
+        ]
+    );
+
+    // Header values are case-sensitive, and repeated headers keep their order
+    let c = canonical(&headers, "POST", "/orders", &[("x-tenant", "Acme corp")], b"{}");
+    assert_ne!(a.hash(), c.hash());
+    let d = canonical(&["accept"], "GET", "/", &[("accept", "text/html"), ("accept", "*/*")], b"");
+    assert_eq!(d.headers[0].1, "text/html,*/*");
+}
+
+#[test]
+fn test_missing_header_differs_from_other_config() {
+    // A request without the header and a config without the header are told apart
+    let without_header = canonical(&["x-tenant"], "GET", "/", &[], b"");
+    let without_config = canonical(&[], "GET", "/", &[], b"");
+    assert!(without_header.headers.is_empty());
+    assert_ne!(without_header.hash(), without_config.hash());
+    let empty = canonical(&["x-tenant"], "GET", "/", &[("x-tenant", "")], b"");
+    assert_ne!(without_header.hash(), empty.hash());
+}
+
+#[test]
+fn test_host_from_authority_or_header() {
+    let from_header = canonical(&["host"], "GET", "/", &[("host", "API.example.com")], b"");
+    let from_authority = canonical(&["host"], "GET", "https://api.example.com/", &[], b"");
+    assert_eq!(from_header.headers, [("host".to_string(), "api.example.com".to_string())]);
+    assert_eq!(from_header.hash(), from_authority.hash());
+}
+
+#[test]
+fn test_body_digest() {
+    let empty = canonical(&[], "POST", "/", &[], b"");
+    assert_eq!(empty.body_sha256, "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    assert_ne!(empty.hash(), canonical(&[], "POST", "/", &[], b" ").hash());
+}
+
+#[test]
+fn test_canonical_form_is_stable() {
+    // Changing either value invalidates every signature, cache key and idempotency key in use:
+    // only update them for a deliberate format change, and call it out in the release notes
+    let request = canonical(
+        &["content-type", "host", "x-tenant"],
+        "post",
+        "/orders//42/?tag=b&page=2&tag=a",
+        &[("Host", "api.example.com"), ("content-type", "application/json"), ("x-other", "1")],
+        br#"{"qty":1}"#,
+    );
+    assert_eq!(
+        request.to_string(),
+        "POST\n/orders/42\npage=2&tag=a&tag=b\ncontent-type:application/json\nhost:api.example.com\n\n\
+         content-type;host;x-tenant\n92438ddd4266b3271fcebff491a7db7f0995332bade824c704f83596b7f36f74"
+    );
+    assert_eq!(request.hash_hex(), "7a69e63bc9bc8872ffb257df1e025b9a039ae06a4d834faf8b5a28ab4254190d");
+}
@@ -0,0 +1,240 @@
+use crate::canonical::CanonicalRequest;
+use crate::config::{self, Config, PayloadMode, UnmatchedRoute};
+use crate::expr::Attributes;
+use crate::telemetry::Telemetry;
+use crate::{invoke_target, raw_client_context, request, ApplicationState, FunctionError};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method};
+use clap::Args;
+use std::process::ExitCode;
+use std::time::Instant;
+
+/// Invokes a target exactly as the server would and prints the decoded response.
+#[derive(Args, Debug)]
+pub struct CheckArgs {
+    /// Target pattern from the config, e.g. `/orders/*rest`. Defaults to the target matching `--path`.
+    #[arg(long)]
+    pub target: Option<String>,
+    #[arg(long, default_value = "GET")]
+    pub method: Method,
+    /// Request path, optionally with a query string.
+    #[arg(long, default_value = "/")]
+    pub path: String,
+    /// Request header as `name: value`; may be repeated.
+    #[arg(long = "header", short = 'H')]
+    pub headers: Vec<String>,
+    /// Request body.
+    #[arg(long, default_value = "")]
+    pub data: String,
+    /// Fail unless the response has this status code.
+    #[arg(long)]
+    pub expect_status: Option<u16>,
+    /// Fail unless the response body contains this string.
+    #[arg(long)]
+    pub expect_body_contains: Option<String>,
+    /// Print the canonical form of the request and its hash instead of invoking.
+    #[arg(long)]
+    pub print_canonical: bool,
+}
+
+pub async fn run_check(config: Config, args: CheckArgs) -> ExitCode {
+    let (path, query) = match args.path.split_once('?') {
+        Some((path, query)) => (path.to_string(), query),
+        None => (args.path.clone(), ""),
+    };
+    let mut headers = match parse_headers(&args.headers) {
+        Ok(headers) => headers,
+        Err(e) => return fail(&e),
+    };
+    if args.print_canonical {
+        let uri = match args.path.parse() {
+            Ok(uri) => uri,
+            Err(e) => return fail(&format!("Invalid path {:?}: {}", args.path, e)),
+        };
+        let canonical = CanonicalRequest::new(
+            &config.canonical_request,
+            &args.method,
+            &uri,
+            &headers,
+            args.data.as_bytes(),
+        );
+        println!("{}", canonical);
+        println!();
+        println!("hash: {}", canonical.hash_hex());
+        return ExitCode::SUCCESS;
+    }
+
+    let (pattern, target) = match &args.target {
+        Some(pattern) => match config.targets.get(pattern) {
+            Some(target) => (pattern.clone(), target.clone()),
+            None => return fail(&format!("No target {} in config", pattern)),
+        },
+        None => match config.match_route_on_host(headers.get("host").and_then(|v| v.to_str().ok()), &path) {
+            Some((pattern, target, _)) => (pattern.to_string(), target.clone()),
+            None if config.unmatched == UnmatchedRoute::NotFound => {
+                return fail(&format!("No target serves {}", path));
+            }
+            None => Default::default(),
+        },
+    };
+
+    let path_params = config::path_pattern(&pattern)
+        .and_then(|pattern| config::match_pattern(pattern, &path))
+        .unwrap_or_default();
+    // Like the server, a target with `match` hands the request to the target its conditions or
+    // body select. There is no client address to match on.
+    let (pattern, target) = match &target.body_match {
+        Some(body_match) => {
+            let body = request::ParsedBody::new(args.data.clone().into());
+            let decoded = request::decode_path(&path);
+            let attributes = Attributes {
+                method: &args.method,
+                path: &decoded,
+                query,
+                headers: &headers,
+                client_ip: None,
+                key_id: None,
+                record: None,
+            };
+            let name = match request::match_conditions(body_match, &attributes) {
+                Some(name) => name,
+                None => request::match_body(body_match, &headers, &body).0,
+            };
+            match config.targets.get(name) {
+                Some(selected) => (name.to_string(), selected.clone()),
+                None => (pattern, target),
+            }
+        }
+        None => (pattern, target),
+    };
+
+    if !target.accepts_method(&args.method) {
+        return fail(&format!(
+            "Target {} answers {} with 405, it only accepts {}",
+            pattern,
+            args.method,
+            target.allow_header()
+        ));
+    }
+    let rewritten = request::rewrite_path(&target, &pattern, &path);
+    if rewritten.is_some() {
+        request::insert_original_path_header(&mut headers, &target, &path);
+    }
+    let (decoded, raw_path) = match rewritten {
+        Some(raw_path) => (request::decode_path(&raw_path), raw_path),
+        None => (path.clone(), path.clone()),
+    };
+
+    let telemetry = Telemetry::new(&config.telemetry);
+    let state = ApplicationState::new(config, telemetry).await;
+    let mut request_context = request::RequestContext::new(&headers);
+    let event_request = request::EventRequest {
+        method: &args.method,
+        path: &decoded,
+        raw_path: &raw_path,
+        raw_query_string: query,
+        pattern: &pattern,
+        host: target.host.as_deref(),
+        path_params: &path_params,
+        source_ip: None,
+        request_id: &request_context.request_id,
+        multi_value: target.multi_value,
+    };
+    let payload_mode = state.config().payload_mode(&target);
+    if payload_mode == PayloadMode::Raw {
+        match raw_client_context(&state.config(), &event_request, &headers) {
+            Ok(context) => request_context.client_context = Some(context),
+            Err(e) => return fail(&e.to_string()),
+        }
+    }
+    let payload = request::build_request_body(payload_mode, &event_request, &headers, args.data.as_bytes());
+    let started_at = Instant::now();
+    let resp = match invoke_target(&state, &target, payload, &request_context).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            return fail(&format!(
+                "Invoke of {} failed: {}",
+                state.config().function_name(&target),
+                e
+            ))
+        }
+    };
+    let status = resp.status();
+    let function_error = resp.extensions().get::<FunctionError>().cloned();
+    let resp_headers = resp.headers().clone();
+    let body = match axum::body::to_bytes(resp.into_body(), usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => return fail(&format!("Failed to read response body: {}", e)),
+    };
+    let latency = started_at.elapsed();
+
+    println!("function: {}", state.config().function_name(&target));
+    println!("status: {}", status);
+    println!("latency: {} ms", latency.as_millis());
+    if let Some(FunctionError(kind)) = &function_error {
+        println!("function_error: {}", kind);
+    }
+    for (name, value) in &resp_headers {
+        println!("{}: {}", name, String::from_utf8_lossy(value.as_bytes()));
+    }
+    println!();
+    println!("{}", String::from_utf8_lossy(&body));
+
+    let failures = check_expectations(&args, status.as_u16(), &body, function_error.as_ref());
+    for failure in &failures {
+        eprintln!("FAIL: {}", failure);
+    }
+    if failures.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn parse_headers(raw: &[String]) -> Result<HeaderMap, String> {
+    let mut headers = HeaderMap::new();
+    for header in raw {
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| format!("Invalid header {:?}, expected `name: value`", header))?;
+        let name = HeaderName::try_from(name.trim()).map_err(|e| format!("Invalid header name {:?}: {}", name, e))?;
+        let value =
+            HeaderValue::try_from(value.trim()).map_err(|e| format!("Invalid header value for {}: {}", name, e))?;
+        headers.append(name, value);
+    }
+    Ok(headers)
+}
+
+fn check_expectations(
+    args: &CheckArgs,
+    status: u16,
+    body: &[u8],
+    function_error: Option<&FunctionError>,
+) -> Vec<String> {
+    let mut failures = Vec::new();
+    if let Some(FunctionError(kind)) = function_error {
+        failures.push(format!("function returned an error ({})", kind));
+    }
+    match args.expect_status {
+        Some(expected) if expected != status => {
+            failures.push(format!("expected status {}, got {}", expected, status));
+        }
+        None if status >= 500 => failures.push(format!("status {}", status)),
+        _ => {}
+    }
+    if let Some(needle) = &args.expect_body_contains {
+        if !String::from_utf8_lossy(body).contains(needle.as_str()) {
+            failures.push(format!("body does not contain {:?}", needle));
+        }
+    }
+    failures
+}
+
+fn fail(message: &str) -> ExitCode {
+    eprintln!("FAIL: {}", message);
+    ExitCode::FAILURE
+}
+
+#[cfg(test)]
+mod tests {
+    include!("check_tests.rs");
+}
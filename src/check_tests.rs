@@ -0,0 +1,57 @@
+use super::*;
+use clap::Parser;
+
+#[derive(Parser)]
+struct TestCli {
+    #[command(flatten)]
+    check: CheckArgs,
+}
+
+fn args(argv: &[&str]) -> CheckArgs {
+    TestCli::parse_from(std::iter::once("check").chain(argv.iter().copied())).check
+}
+
+#[test]
+fn test_parse_args() {
+    let args = args(&[
+        "--target",
+        "/orders/*rest",
+        "--method",
+        "POST",
+        "--path",
+        "/orders/health?verbose=1",
+        "-H",
+        "content-type: application/json",
+        "--expect-status",
+        "200",
+    ]);
+    assert_eq!(args.target.as_deref(), Some("/orders/*rest"));
+    assert_eq!(args.method, Method::POST);
+    assert_eq!(args.expect_status, Some(200));
+    assert!(!args.print_canonical);
+    assert!(self::args(&["--print-canonical"]).print_canonical);
+    assert_eq!(parse_headers(&args.headers).unwrap()["content-type"], "application/json");
+}
+
+#[test]
+fn test_parse_headers_rejects_invalid() {
+    assert!(parse_headers(&["no-colon".to_string()]).is_err());
+    assert!(parse_headers(&["bad name: x".to_string()]).is_err());
+}
+
+#[test]
+fn test_check_expectations() {
+    let args = args(&["--expect-status", "200", "--expect-body-contains", "ok"]);
+    assert!(check_expectations(&args, 200, b"all ok", None).is_empty());
+    assert_eq!(check_expectations(&args, 404, b"all ok", None).len(), 1);
+    assert_eq!(check_expectations(&args, 200, b"nope", None).len(), 1);
+    assert_eq!(
+        check_expectations(&args, 200, b"all ok", Some(&FunctionError("Unhandled".to_string()))).len(),
+        1
+    );
+
+    // Without an explicit expectation, only server errors fail
+    let args = self::args(&[]);
+    assert!(check_expectations(&args, 404, b"", None).is_empty());
+    assert_eq!(check_expectations(&args, 502, b"", None).len(), 1);
+}
@@ -0,0 +1,171 @@
+use crate::access_log::now_ms;
+use crate::config::StateStoreConfig;
+use crate::state_store::{MemoryStore, SnapshotEntry};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Version of the checkpoint file format. Files of another version are ignored.
+pub const CHECKPOINT_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CheckpointFile {
+    version: u32,
+    written_at_ms: u128,
+    /// Entries of each feature's local state store, keyed by feature.
+    stores: BTreeMap<String, Vec<SnapshotEntry>>,
+}
+
+/// Persists local state such as quota and rate limit counters, so a restart does not reset them.
+/// State is written periodically and on shutdown, and restored into each store as its feature
+/// registers it. A missing, corrupt, stale or foreign checkpoint is skipped with a warning; it
+/// never prevents startup.
+#[derive(Clone)]
+pub struct Checkpointer {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    path: Option<PathBuf>,
+    interval: Duration,
+    stores: Mutex<BTreeMap<String, Arc<MemoryStore>>>,
+    /// Checkpointed entries not yet claimed by a registered store.
+    restored: Mutex<BTreeMap<String, Vec<SnapshotEntry>>>,
+}
+
+impl Checkpointer {
+    /// Loads the checkpoint at `checkpoint_path`, if configured.
+    pub fn new(config: &StateStoreConfig) -> Self {
+        let restored = match &config.checkpoint_path {
+            Some(path) => load(path, Duration::from_secs(config.checkpoint_max_age_secs)),
+            None => BTreeMap::new(),
+        };
+        Self {
+            inner: Arc::new(Inner {
+                path: config.checkpoint_path.clone(),
+                interval: Duration::from_secs(config.checkpoint_interval_secs.max(1)),
+                stores: Mutex::default(),
+                restored: Mutex::new(restored),
+            }),
+        }
+    }
+
+    /// Checkpoints `store` under `name` from now on, first restoring its checkpointed entries.
+    pub fn register(&self, name: &str, store: Arc<MemoryStore>) {
+        if let Some(entries) = self.inner.restored.lock().unwrap().remove(name) {
+            let restored = store.restore(entries);
+            tracing::info!(store = name, restored, "Restored state from checkpoint");
+        }
+        self.inner.stores.lock().unwrap().insert(name.to_string(), store);
+    }
+
+    /// Writes every registered store to the checkpoint file. The file is replaced atomically, so a
+    /// crash mid-write leaves the previous checkpoint intact.
+    pub fn write(&self) -> io::Result<()> {
+        let Some(path) = &self.inner.path else {
+            return Ok(());
+        };
+        let stores = self.inner.stores.lock().unwrap().clone();
+        if stores.is_empty() {
+            return Ok(());
+        }
+        let file = CheckpointFile {
+            version: CHECKPOINT_VERSION,
+            written_at_ms: now_ms(),
+            stores: stores
+                .iter()
+                .map(|(name, store)| (name.clone(), store.snapshot()))
+                .collect(),
+        };
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, serde_json::to_vec(&file)?)?;
+        fs::rename(&temp, path)
+    }
+
+    /// Writes a checkpoint every `checkpoint_interval_secs`.
+    pub fn spawn_periodic(&self) -> Option<tokio::task::JoinHandle<()>> {
+        self.inner.path.as_ref()?;
+        let checkpointer = self.clone();
+        Some(tokio::spawn(async move {
+            let mut tick = tokio::time::interval(checkpointer.inner.interval);
+            tick.tick().await;
+            loop {
+                tick.tick().await;
+                checkpointer.write_in_background().await;
+            }
+        }))
+    }
+
+    /// Writes a final checkpoint, giving up after `timeout`. False if it was given up.
+    pub async fn flush(&self, timeout: Duration) -> bool {
+        if self.inner.path.is_none() {
+            return true;
+        }
+        if tokio::time::timeout(timeout, self.write_in_background()).await.is_err() {
+            tracing::warn!("Final state checkpoint did not finish within {:?}", timeout);
+            return false;
+        }
+        true
+    }
+
+    /// Whether state is checkpointed, and so written by a final flush.
+    pub fn is_enabled(&self) -> bool {
+        self.inner.path.is_some()
+    }
+
+    async fn write_in_background(&self) {
+        let checkpointer = self.clone();
+        match tokio::task::spawn_blocking(move || checkpointer.write()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::warn!("Failed to write state checkpoint: {}", e),
+            Err(e) => tracing::warn!("State checkpoint task failed: {}", e),
+        }
+    }
+}
+
+fn load(path: &Path, max_age: Duration) -> BTreeMap<String, Vec<SnapshotEntry>> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return BTreeMap::new(),
+        Err(e) => {
+            tracing::warn!("Ignoring state checkpoint {}: {}", path.display(), e);
+            return BTreeMap::new();
+        }
+    };
+    let file: CheckpointFile = match serde_json::from_slice(&contents) {
+        Ok(file) => file,
+        Err(e) => {
+            tracing::warn!("Ignoring corrupt state checkpoint {}: {}", path.display(), e);
+            return BTreeMap::new();
+        }
+    };
+    if file.version != CHECKPOINT_VERSION {
+        tracing::warn!(
+            "Ignoring state checkpoint {} of version {}, expected {}",
+            path.display(),
+            file.version,
+            CHECKPOINT_VERSION
+        );
+        return BTreeMap::new();
+    }
+    let age_ms = now_ms().saturating_sub(file.written_at_ms);
+    if age_ms > max_age.as_millis() {
+        tracing::warn!(
+            "Ignoring state checkpoint {} written {}s ago, older than {}s",
+            path.display(),
+            age_ms / 1000,
+            max_age.as_secs()
+        );
+        return BTreeMap::new();
+    }
+    file.stores
+}
+
+#[cfg(test)]
+mod tests {
+    include!("checkpoint_tests.rs");
+}
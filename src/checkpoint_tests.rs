@@ -0,0 +1,145 @@
+use super::*;
+use crate::state_store::StateStore;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+fn config(path: &Path) -> StateStoreConfig {
+    StateStoreConfig {
+        checkpoint_path: Some(path.to_path_buf()),
+        ..Default::default()
+    }
+}
+
+fn write_file(path: &Path, version: u32, written_at_ms: u128, stores: BTreeMap<String, Vec<SnapshotEntry>>) {
+    let file = CheckpointFile {
+        version,
+        written_at_ms,
+        stores,
+    };
+    fs::write(path, serde_json::to_vec(&file).unwrap()).unwrap();
+}
+
+fn quota_entry() -> BTreeMap<String, Vec<SnapshotEntry>> {
+    let entry = SnapshotEntry {
+        key: "client-a".to_string(),
+        value: BASE64.encode(b"7"),
+        expires_at_ms: None,
+    };
+    BTreeMap::from([("quota".to_string(), vec![entry])])
+}
+
+#[tokio::test]
+async fn test_counter_continues_after_restart_mid_window() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("state.json");
+    let window = Some(Duration::from_secs(60));
+
+    let before = Checkpointer::new(&config(&path));
+    let store = Arc::new(MemoryStore::default());
+    before.register("rate_limit", store.clone());
+    assert_eq!(store.increment("client-a", 3, window).await, Ok(3));
+    before.flush(Duration::from_secs(5)).await;
+    assert!(path.exists());
+    assert!(!path.with_extension("tmp").exists());
+
+    let after = Checkpointer::new(&config(&path));
+    let restored = Arc::new(MemoryStore::default());
+    after.register("rate_limit", restored.clone());
+    assert_eq!(restored.increment("client-a", 1, window).await, Ok(4));
+    // The window keeps its original expiry instead of starting over
+    let expires_at_ms = restored.snapshot()[0].expires_at_ms.unwrap();
+    assert!(expires_at_ms <= now_ms() + 60_000);
+    assert!(expires_at_ms > now_ms() + 50_000);
+}
+
+#[tokio::test]
+async fn test_entries_restore_only_into_their_own_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("state.json");
+    write_file(&path, CHECKPOINT_VERSION, now_ms(), quota_entry());
+
+    let checkpointer = Checkpointer::new(&config(&path));
+    let other = Arc::new(MemoryStore::default());
+    checkpointer.register("rate_limit", other.clone());
+    assert_eq!(other.get("client-a").await, Ok(None));
+    let quota = Arc::new(MemoryStore::default());
+    checkpointer.register("quota", quota.clone());
+    assert_eq!(quota.get("client-a").await, Ok(Some(b"7".to_vec())));
+}
+
+#[tokio::test]
+async fn test_expired_entries_are_dropped() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("state.json");
+    let mut stores = quota_entry();
+    stores.get_mut("quota").unwrap().push(SnapshotEntry {
+        key: "client-b".to_string(),
+        value: BASE64.encode(b"2"),
+        expires_at_ms: Some(now_ms() - 1),
+    });
+    write_file(&path, CHECKPOINT_VERSION, now_ms(), stores);
+
+    let checkpointer = Checkpointer::new(&config(&path));
+    let quota = Arc::new(MemoryStore::default());
+    checkpointer.register("quota", quota.clone());
+    assert_eq!(quota.get("client-a").await, Ok(Some(b"7".to_vec())));
+    assert_eq!(quota.get("client-b").await, Ok(None));
+}
+
+#[tokio::test]
+async fn test_unusable_checkpoints_are_skipped() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("state.json");
+    let file = |version: u32, written_at_ms: u128| {
+        let file = CheckpointFile {
+            version,
+            written_at_ms,
+            stores: quota_entry(),
+        };
+        Some(serde_json::to_vec(&file).unwrap())
+    };
+    let cases = [
+        ("missing", None),
+        ("corrupt", Some(b"{\"version\": 1, \"stor".to_vec())),
+        ("other version", file(CHECKPOINT_VERSION + 1, now_ms())),
+        ("stale", file(CHECKPOINT_VERSION, now_ms() - 2 * 3600 * 1000)),
+    ];
+    for (case, contents) in cases {
+        let _ = fs::remove_file(&path);
+        if let Some(contents) = contents {
+            fs::write(&path, contents).unwrap();
+        }
+        let checkpointer = Checkpointer::new(&config(&path));
+        let quota = Arc::new(MemoryStore::default());
+        checkpointer.register("quota", quota.clone());
+        assert_eq!(quota.get("client-a").await, Ok(None), "{}", case);
+    }
+}
+
+#[tokio::test]
+async fn test_periodic_checkpoints() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("state.json");
+    let checkpointer = Checkpointer::new(&StateStoreConfig {
+        checkpoint_interval_secs: 1,
+        ..config(&path)
+    });
+    let store = Arc::new(MemoryStore::default());
+    checkpointer.register("quota", store.clone());
+    store.set("client-a", b"1".to_vec(), None).await.unwrap();
+
+    let task = checkpointer.spawn_periodic().unwrap();
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    task.abort();
+    let file: CheckpointFile = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    assert_eq!(file.version, CHECKPOINT_VERSION);
+    assert_eq!(file.stores["quota"], store.snapshot());
+}
+
+#[tokio::test]
+async fn test_without_path_nothing_is_written() {
+    let checkpointer = Checkpointer::new(&StateStoreConfig::default());
+    checkpointer.register("quota", Arc::new(MemoryStore::default()));
+    assert!(checkpointer.spawn_periodic().is_none());
+    checkpointer.write().unwrap();
+}
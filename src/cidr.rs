@@ -0,0 +1,72 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A network such as `10.0.0.0/8` or `2001:db8::/32`. A bare address is a network of one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Whether `ip` is in the network. IPv4 clients connecting over IPv6 as `::ffff:a.b.c.d`
+    /// match IPv4 networks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid network {:?}, expected e.g. 10.0.0.0/8", s);
+        let (address, prefix_len) = match s.trim().split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s.trim(), None),
+        };
+        let network: IpAddr = address.parse().map_err(|_| invalid())?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(invalid)?,
+            None => max_len,
+        };
+        // Written as IPv4-mapped, as `::ffff:10.0.0.0/104`, the network holds IPv4 clients,
+        // which `contains` sees as IPv4 whichever way they connected
+        if let (IpAddr::V6(_), IpAddr::V4(mapped)) = (network, network.to_canonical()) {
+            if prefix_len >= 96 {
+                return Ok(Cidr {
+                    network: IpAddr::V4(mapped),
+                    prefix_len: prefix_len - 96,
+                });
+            }
+        }
+        Ok(Cidr { network, prefix_len })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("cidr_tests.rs");
+}
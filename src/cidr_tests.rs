@@ -0,0 +1,54 @@
+use super::*;
+
+fn contains(cidr: &str, ip: &str) -> bool {
+    cidr.parse::<Cidr>().unwrap().contains(ip.parse().unwrap())
+}
+
+#[test]
+fn test_ipv4_networks() {
+    assert!(contains("10.0.0.0/8", "10.255.1.2"));
+    assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+    assert!(contains("192.168.1.0/24", "192.168.1.77"));
+    assert!(!contains("192.168.1.0/24", "192.168.2.1"));
+    // Host bits in the network address are ignored
+    assert!(contains("192.168.1.9/24", "192.168.1.200"));
+    assert!(contains("0.0.0.0/0", "203.0.113.5"));
+    assert!(contains("203.0.113.5", "203.0.113.5"));
+    assert!(!contains("203.0.113.5", "203.0.113.6"));
+}
+
+#[test]
+fn test_ipv6_networks() {
+    assert!(contains("2001:db8::/32", "2001:db8:1::1"));
+    assert!(!contains("2001:db8::/32", "2001:db9::1"));
+    assert!(contains("::/0", "fe80::1"));
+    assert!(contains("::1", "::1"));
+    assert!(!contains("2001:db8::/32", "10.0.0.1"));
+}
+
+#[test]
+fn test_ipv4_mapped_clients_match_ipv4_networks() {
+    assert!(contains("10.0.0.0/8", "::ffff:10.1.2.3"));
+    assert!(!contains("10.0.0.0/8", "::ffff:11.1.2.3"));
+}
+
+#[test]
+fn test_ipv4_mapped_networks_match_either_form() {
+    for client in ["10.1.2.3", "::ffff:10.1.2.3"] {
+        assert!(contains("::ffff:10.0.0.0/104", client), "{}", client);
+        assert!(contains("::ffff:10.1.2.3", client), "{}", client);
+        assert!(!contains("::ffff:11.0.0.0/104", client), "{}", client);
+    }
+    assert_eq!("::ffff:10.0.0.0/104".parse::<Cidr>().unwrap().to_string(), "10.0.0.0/8");
+    // Shorter prefixes reach past the mapped range and stay IPv6
+    assert!(!contains("::ffff:0.0.0.0/95", "10.1.2.3"));
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!("10.0.0.0/8".parse::<Cidr>().unwrap().to_string(), "10.0.0.0/8");
+    assert_eq!("::1".parse::<Cidr>().unwrap().to_string(), "::1/128");
+    for invalid in ["", "10.0.0.0/33", "::/129", "10.0.0/8", "10.0.0.0/", "example.com/8", "10.0.0.0/-1"] {
+        assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+    }
+}
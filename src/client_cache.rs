@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a failed construction is remembered before the next request tries again.
+pub const DEFAULT_FAILURE_TTL: Duration = Duration::from_secs(5);
+
+/// Clients built lazily on first use, one per key, such as per-region or per-assumed-role Lambda
+/// clients. Concurrent first requests for a key wait for a single construction instead of each
+/// building their own; a failed construction is cached for `failure_ttl`, so an outage of the
+/// credentials provider is not hammered by every request.
+pub struct ClientCache<K, V, E> {
+    cells: Mutex<HashMap<K, Cell<V, E>>>,
+    failure_ttl: Duration,
+}
+
+/// A key's construction state; its lock is held while the client is being built.
+type Cell<V, E> = Arc<tokio::sync::Mutex<Option<Entry<V, E>>>>;
+
+enum Entry<V, E> {
+    Ready(V),
+    Failed { error: E, until: Instant },
+}
+
+impl<K, V, E> ClientCache<K, V, E>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+    E: Clone,
+{
+    pub fn new(failure_ttl: Duration) -> Self {
+        Self {
+            cells: Mutex::new(HashMap::new()),
+            failure_ttl,
+        }
+    }
+
+    /// The client for `key`, built with `init` unless it already exists or its last construction
+    /// failed less than `failure_ttl` ago, in which case that error is returned.
+    pub async fn get_or_try_init<F, Fut>(&self, key: &K, init: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+    {
+        let cell = self.cells.lock().unwrap().entry(key.clone()).or_default().clone();
+        // Held across the construction, so callers arriving meanwhile wait for its outcome
+        let mut entry = cell.lock().await;
+        match &*entry {
+            Some(Entry::Ready(client)) => return Ok(client.clone()),
+            Some(Entry::Failed { error, until }) if Instant::now() < *until => return Err(error.clone()),
+            _ => {}
+        }
+        let result = init().await;
+        *entry = Some(match &result {
+            Ok(client) => Entry::Ready(client.clone()),
+            Err(error) => Entry::Failed {
+                error: error.clone(),
+                until: Instant::now() + self.failure_ttl,
+            },
+        });
+        result
+    }
+
+    /// Drops the client for `key`, e.g. once its credentials are known to be revoked.
+    pub fn invalidate(&self, key: &K) {
+        self.cells.lock().unwrap().remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("client_cache_tests.rs");
+}
@@ -0,0 +1,70 @@
+use super::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+async fn build(builds: &AtomicUsize, result: Result<&'static str, &'static str>) -> Result<&'static str, &'static str> {
+    builds.fetch_add(1, Ordering::SeqCst);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    result
+}
+
+#[tokio::test]
+async fn test_parallel_first_requests_build_once() {
+    let cache = Arc::new(ClientCache::new(DEFAULT_FAILURE_TTL));
+    let builds = Arc::new(AtomicUsize::new(0));
+
+    let requests = (0..100).map(|_| {
+        let (cache, builds) = (cache.clone(), builds.clone());
+        tokio::spawn(async move {
+            cache
+                .get_or_try_init(&"us-west-2", || build(&builds, Ok("client")))
+                .await
+        })
+    });
+    for result in futures::future::join_all(requests).await {
+        assert_eq!(result.unwrap(), Ok("client"));
+    }
+    assert_eq!(builds.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_keys_are_built_independently() {
+    let cache = ClientCache::new(DEFAULT_FAILURE_TTL);
+    let builds = AtomicUsize::new(0);
+
+    assert_eq!(cache.get_or_try_init(&"a", || build(&builds, Ok("a"))).await, Ok("a"));
+    assert_eq!(cache.get_or_try_init(&"b", || build(&builds, Ok("b"))).await, Ok("b"));
+    assert_eq!(cache.get_or_try_init(&"a", || build(&builds, Ok("other"))).await, Ok("a"));
+    assert_eq!(builds.load(Ordering::SeqCst), 2);
+
+    cache.invalidate(&"a");
+    assert_eq!(cache.get_or_try_init(&"a", || build(&builds, Ok("rebuilt"))).await, Ok("rebuilt"));
+}
+
+#[tokio::test]
+async fn test_failure_is_cached_until_it_expires() {
+    let cache = Arc::new(ClientCache::new(Duration::from_millis(200)));
+    let builds = Arc::new(AtomicUsize::new(0));
+
+    // Requests arriving during a failing construction share its error
+    let requests = (0..20).map(|_| {
+        let (cache, builds) = (cache.clone(), builds.clone());
+        tokio::spawn(async move {
+            cache
+                .get_or_try_init(&"role", || build(&builds, Err("sts unavailable")))
+                .await
+        })
+    });
+    for result in futures::future::join_all(requests).await {
+        assert_eq!(result.unwrap(), Err("sts unavailable"));
+    }
+    assert_eq!(
+        cache.get_or_try_init(&"role", || build(&builds, Ok("client"))).await,
+        Err("sts unavailable")
+    );
+    assert_eq!(builds.load(Ordering::SeqCst), 1);
+
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    assert_eq!(cache.get_or_try_init(&"role", || build(&builds, Ok("client"))).await, Ok("client"));
+    assert_eq!(cache.get_or_try_init(&"role", || build(&builds, Err("unused"))).await, Ok("client"));
+    assert_eq!(builds.load(Ordering::SeqCst), 2);
+}
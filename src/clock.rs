@@ -0,0 +1,296 @@
+use crate::config::ClockConfig;
+use crate::ewma::Ewma;
+use aws_sdk_lambda::config::interceptors::BeforeDeserializationInterceptorContextRef;
+use aws_sdk_lambda::config::{ConfigBag, Intercept, RuntimeComponents};
+use aws_sdk_lambda::error::BoxError;
+use aws_sdk_lambda::Client;
+use axum::http::header::{DATE, HOST};
+use axum::http::Request;
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use http_body_util::Empty;
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// `Date` headers are truncated to the second, so the server was on average half a second past
+/// the one it sent.
+const DATE_RESOLUTION_MS: i64 = 1000;
+
+/// A reference clock, read from the `Date` header of one of its responses.
+pub trait TimeSource: Send + Sync + 'static {
+    fn date(&self) -> BoxFuture<'static, Result<DateTime<Utc>, String>>;
+}
+
+/// State of the clock check, as shown on `/status`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ClockStatus {
+    /// Smoothed offset of the reference clock from the local one; positive when the local clock is
+    /// behind. Unset before the first measurement.
+    pub offset_ms: Option<i64>,
+    /// Offset of the latest measurement alone.
+    pub last_sample_ms: Option<i64>,
+    pub max_skew_ms: u64,
+    /// Whether the offset is within `max_skew_ms`; true before the first measurement.
+    pub within_tolerance: bool,
+    /// Whether the offset is applied to time-based checks.
+    pub correcting: bool,
+    pub checked_at: Option<DateTime<Utc>>,
+    /// Error of the latest measurement, cleared by the next successful one.
+    pub last_error: Option<String>,
+}
+
+/// The gateway's view of the time. Schedules and other time-based checks would otherwise trust the
+/// local clock, and an instance that drifted by minutes serves or refuses requests at the wrong
+/// times. The offset from a reference clock is smoothed, reported on `/status` and, with
+/// `correct`, added to the local clock.
+#[derive(Clone)]
+pub struct Clock {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    config: ClockConfig,
+    state: Mutex<State>,
+}
+
+struct State {
+    offset: Ewma,
+    last_sample_ms: Option<i64>,
+    checked_at: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+}
+
+impl Clock {
+    pub fn new(config: &ClockConfig) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                config: config.clone(),
+                state: Mutex::new(State {
+                    offset: Ewma::new(config.smoothing),
+                    last_sample_ms: None,
+                    checked_at: None,
+                    last_error: None,
+                }),
+            }),
+        }
+    }
+
+    /// The time for time-based checks.
+    pub fn now(&self) -> DateTime<Utc> {
+        self.corrected(Utc::now())
+    }
+
+    /// `local` moved by the smoothed offset when correcting, else as it is.
+    pub fn corrected(&self, local: DateTime<Utc>) -> DateTime<Utc> {
+        match self.offset_ms() {
+            Some(offset_ms) if self.inner.config.correct => local + chrono::Duration::milliseconds(offset_ms),
+            _ => local,
+        }
+    }
+
+    /// The smoothed offset, once measured.
+    pub fn offset_ms(&self) -> Option<i64> {
+        let state = self.inner.state.lock().unwrap();
+        state.offset.value().map(|offset| offset.round() as i64)
+    }
+
+    /// Folds in a measurement: a request sent at `sent` and answered at `received`, both local
+    /// time, by a server whose `Date` header read `date`. Returns the smoothed offset.
+    pub fn observe(&self, sent: DateTime<Utc>, received: DateTime<Utc>, date: DateTime<Utc>) -> i64 {
+        let sample_ms = sample_offset_ms(sent, received, date);
+        let offset_ms = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.offset.observe(sample_ms as f64);
+            state.last_sample_ms = Some(sample_ms);
+            state.checked_at = Some(received);
+            state.last_error = None;
+            state.offset.value().unwrap_or_default().round() as i64
+        };
+        let max_skew_ms = self.inner.config.max_skew_ms;
+        if offset_ms.unsigned_abs() > max_skew_ms {
+            tracing::warn!(
+                offset_ms,
+                max_skew_ms,
+                correcting = self.inner.config.correct,
+                "The gateway clock is off from the reference clock"
+            );
+        } else {
+            tracing::debug!(offset_ms, sample_ms, "Measured the clock offset");
+        }
+        offset_ms
+    }
+
+    /// Records a failed measurement, keeping the offset measured before.
+    pub fn record_error(&self, error: String, at: DateTime<Utc>) {
+        tracing::warn!(%error, "Could not measure the clock offset");
+        let mut state = self.inner.state.lock().unwrap();
+        state.checked_at = Some(at);
+        state.last_error = Some(error);
+    }
+
+    pub fn status(&self) -> ClockStatus {
+        let offset_ms = self.offset_ms();
+        let state = self.inner.state.lock().unwrap();
+        let max_skew_ms = self.inner.config.max_skew_ms;
+        ClockStatus {
+            offset_ms,
+            last_sample_ms: state.last_sample_ms,
+            max_skew_ms,
+            within_tolerance: offset_ms.map_or(true, |offset| offset.unsigned_abs() <= max_skew_ms),
+            correcting: self.inner.config.correct,
+            checked_at: state.checked_at,
+            last_error: state.last_error.clone(),
+        }
+    }
+
+    /// Measures the offset against `source` once, within `timeout_ms`.
+    pub async fn check(&self, source: &dyn TimeSource) {
+        let timeout = Duration::from_millis(self.inner.config.timeout_ms);
+        let sent = Utc::now();
+        match tokio::time::timeout(timeout, source.date()).await {
+            Ok(Ok(date)) => {
+                self.observe(sent, Utc::now(), date);
+            }
+            Ok(Err(e)) => self.record_error(e, Utc::now()),
+            Err(_) => self.record_error(format!("timed out after {:?}", timeout), Utc::now()),
+        }
+    }
+
+    /// Measures the offset now and then every `interval_secs`.
+    pub fn spawn_checker(&self, source: Arc<dyn TimeSource>) -> tokio::task::JoinHandle<()> {
+        let clock = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(clock.inner.config.interval_secs.max(1)));
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                clock.check(source.as_ref()).await;
+            }
+        })
+    }
+}
+
+/// Offset of the server's clock from the local one for a single exchange, taking the server to
+/// have read its clock halfway through the round trip.
+pub fn sample_offset_ms(sent: DateTime<Utc>, received: DateTime<Utc>, date: DateTime<Utc>) -> i64 {
+    let midpoint = sent + (received - sent) / 2;
+    (date - midpoint).num_milliseconds() + DATE_RESOLUTION_MS / 2
+}
+
+/// Parses an HTTP `Date` header, such as `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value.trim())
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+/// The source for `clock` settings: `source_url` when set, else the Lambda endpoint of `sdk_config`.
+pub fn source(config: &ClockConfig, sdk_config: &aws_config::SdkConfig) -> Arc<dyn TimeSource> {
+    match config.source_url.as_deref().and_then(|url| url::Url::parse(url).ok()) {
+        Some(url) => Arc::new(HttpDateSource { url }),
+        None => Arc::new(LambdaDateSource::new(sdk_config)),
+    }
+}
+
+/// Sends `HEAD` to an `http://` URL.
+pub struct HttpDateSource {
+    url: url::Url,
+}
+
+impl TimeSource for HttpDateSource {
+    fn date(&self) -> BoxFuture<'static, Result<DateTime<Utc>, String>> {
+        let url = self.url.clone();
+        Box::pin(async move {
+            let host = url.host_str().unwrap_or_default();
+            let port = url.port_or_known_default().unwrap_or(80);
+            let stream = tokio::net::TcpStream::connect((host, port))
+                .await
+                .map_err(|e| e.to_string())?;
+            let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+                .await
+                .map_err(|e| e.to_string())?;
+            tokio::spawn(connection);
+            let authority = match url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_string(),
+            };
+            let request = Request::head(url.path())
+                .header(HOST, authority)
+                .body(Empty::<axum::body::Bytes>::new())
+                .map_err(|e| e.to_string())?;
+            let response = sender.send_request(request).await.map_err(|e| e.to_string())?;
+            let value = response
+                .headers()
+                .get(DATE)
+                .and_then(|v| v.to_str().ok())
+                .ok_or_else(|| format!("{} sent no Date header", url))?;
+            parse_http_date(value).ok_or_else(|| format!("{} sent an invalid Date header {:?}", url, value))
+        })
+    }
+}
+
+/// Calls `GetAccountSettings` on the Lambda endpoint and reads the `Date` of whatever it answers,
+/// so a role without `lambda:GetAccountSettings` still gets a measurement from the error response.
+pub struct LambdaDateSource {
+    client: Client,
+    date: Arc<Mutex<Option<String>>>,
+}
+
+impl LambdaDateSource {
+    pub fn new(sdk_config: &aws_config::SdkConfig) -> Self {
+        let date = Arc::new(Mutex::new(None));
+        let config = aws_sdk_lambda::config::Builder::from(sdk_config)
+            .interceptor(DateRecorder(date.clone()))
+            .build();
+        Self {
+            client: Client::from_conf(config),
+            date,
+        }
+    }
+}
+
+impl TimeSource for LambdaDateSource {
+    fn date(&self) -> BoxFuture<'static, Result<DateTime<Utc>, String>> {
+        // Only the checker sends through this client, one call at a time
+        self.date.lock().unwrap().take();
+        let send = self.client.get_account_settings().send();
+        let date = self.date.clone();
+        Box::pin(async move {
+            let _ = send.await;
+            let value = date
+                .lock()
+                .unwrap()
+                .take()
+                .ok_or("the Lambda endpoint sent no Date header")?;
+            parse_http_date(&value)
+                .ok_or_else(|| format!("the Lambda endpoint sent an invalid Date header {:?}", value))
+        })
+    }
+}
+
+/// Keeps the `Date` header of every response the client receives.
+#[derive(Debug)]
+struct DateRecorder(Arc<Mutex<Option<String>>>);
+
+impl Intercept for DateRecorder {
+    fn name(&self) -> &'static str {
+        "DateRecorder"
+    }
+
+    fn read_after_transmit(
+        &self,
+        context: &BeforeDeserializationInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        _cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        *self.0.lock().unwrap() = context.response().headers().get("date").map(String::from);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("clock_tests.rs");
+}
@@ -0,0 +1,101 @@
+use super::*;
+use chrono::TimeZone;
+
+fn at(ms: i64) -> DateTime<Utc> {
+    Utc.timestamp_millis_opt(1_790_000_000_000 + ms).unwrap()
+}
+
+fn clock(correct: bool) -> Clock {
+    Clock::new(&ClockConfig {
+        smoothing: 0.5,
+        correct,
+        ..Default::default()
+    })
+}
+
+/// Answers with the time of a clock `offset_ms` ahead of the local one, truncated to the second.
+struct AheadBy(i64);
+
+impl TimeSource for AheadBy {
+    fn date(&self) -> BoxFuture<'static, Result<DateTime<Utc>, String>> {
+        let date = Utc::now() + chrono::Duration::milliseconds(self.0);
+        let truncated = Utc.timestamp_opt(date.timestamp(), 0).unwrap();
+        Box::pin(async move { Ok(truncated) })
+    }
+}
+
+struct Failing;
+
+impl TimeSource for Failing {
+    fn date(&self) -> BoxFuture<'static, Result<DateTime<Utc>, String>> {
+        Box::pin(async { Err("connection refused".to_string()) })
+    }
+}
+
+#[test]
+fn test_sample_offset_uses_round_trip_midpoint() {
+    // Sent at 0, answered at 400: the server read its clock around 200, and a Date of 240_000
+    // stands for anything up to a second later
+    assert_eq!(sample_offset_ms(at(0), at(400), at(240_000)), 240_000 - 200 + 500);
+    assert_eq!(sample_offset_ms(at(1000), at(1000), at(0)), -500);
+}
+
+#[test]
+fn test_offset_is_smoothed() {
+    let clock = clock(false);
+    assert_eq!(clock.offset_ms(), None);
+    assert_eq!(clock.observe(at(0), at(0), at(3500)), 4000);
+    assert_eq!(clock.observe(at(0), at(0), at(-500)), 2000);
+    assert_eq!(clock.observe(at(0), at(0), at(-500)), 1000);
+    let status = clock.status();
+    assert_eq!((status.offset_ms, status.last_sample_ms), (Some(1000), Some(0)));
+    assert_eq!(status.checked_at, Some(at(0)));
+}
+
+#[test]
+fn test_status_flags_skew_past_max() {
+    let clock = clock(false);
+    assert!(clock.status().within_tolerance);
+    clock.observe(at(0), at(0), at(-240_500));
+    let status = clock.status();
+    assert_eq!(status.offset_ms, Some(-240_000));
+    assert!(!status.within_tolerance);
+    assert_eq!(status.max_skew_ms, 2000);
+}
+
+#[test]
+fn test_correction_applied_only_when_enabled() {
+    for (correct, expected) in [(false, at(0)), (true, at(-240_000))] {
+        let clock = clock(correct);
+        assert_eq!(clock.corrected(at(0)), at(0));
+        clock.observe(at(0), at(0), at(-240_500));
+        assert_eq!(clock.corrected(at(0)), expected);
+        assert_eq!(clock.status().correcting, correct);
+    }
+}
+
+#[tokio::test]
+async fn test_check_measures_source_and_keeps_offset_on_error() {
+    let clock = clock(true);
+    clock.check(&AheadBy(240_000)).await;
+    let offset = clock.offset_ms().unwrap();
+    assert!((239_000..=241_000).contains(&offset), "{}", offset);
+    let skew = clock.now() - Utc::now();
+    assert!((skew.num_milliseconds() - offset).abs() < 100);
+
+    clock.check(&Failing).await;
+    let status = clock.status();
+    assert_eq!(status.offset_ms, Some(offset));
+    assert_eq!(status.last_error.as_deref(), Some("connection refused"));
+    clock.check(&AheadBy(240_000)).await;
+    assert_eq!(clock.status().last_error, None);
+}
+
+#[test]
+fn test_parse_http_date() {
+    assert_eq!(
+        parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+        Some(Utc.with_ymd_and_hms(1994, 11, 6, 8, 49, 37).unwrap())
+    );
+    assert_eq!(parse_http_date("yesterday"), None);
+}
@@ -0,0 +1,83 @@
+use crate::config::PayloadMode;
+use serde_json::Value;
+
+/// Fields of each payload mode's event that `compact_payload` leaves out when they are null, `{}`,
+/// `[]` or `""`, as paths from the event root. Only fields the mode's deserializers treat as
+/// optional are listed: `httpMethod`, `isBase64Encoded`, `requestContext.elb` and the 2.0
+/// `version`, `routeKey`, `rawPath` and `requestContext.http` are always kept.
+pub fn omittable(mode: PayloadMode) -> &'static [&'static [&'static str]] {
+    match mode {
+        PayloadMode::Alb => &[
+            &["headers"],
+            &["multiValueHeaders"],
+            &["queryStringParameters"],
+            &["multiValueQueryStringParameters"],
+            &["body"],
+            &["requestContext", "elb", "targetGroupArn"],
+        ],
+        PayloadMode::ApiGatewayV1 => &[
+            &["headers"],
+            &["multiValueHeaders"],
+            &["queryStringParameters"],
+            &["multiValueQueryStringParameters"],
+            &["pathParameters"],
+            &["stageVariables"],
+            &["body"],
+            &["requestContext", "accountId"],
+            &["requestContext", "apiId"],
+            &["requestContext", "resourceId"],
+            &["requestContext", "identity", "sourceIp"],
+            &["requestContext", "identity", "userAgent"],
+        ],
+        PayloadMode::ApiGatewayV2 | PayloadMode::FunctionUrl => &[
+            &["headers"],
+            &["rawQueryString"],
+            &["requestContext", "accountId"],
+            &["requestContext", "apiId"],
+            &["requestContext", "domainName"],
+            &["requestContext", "domainPrefix"],
+            &["requestContext", "http", "sourceIp"],
+            &["requestContext", "http", "userAgent"],
+        ],
+        PayloadMode::Raw => &[],
+    }
+}
+
+/// Removes the empty fields [`omittable`] lists for `mode` from `event`, returning how many bytes
+/// shorter its compact serialization got.
+pub fn compact(mode: PayloadMode, event: &mut Value) -> usize {
+    let mut saved = 0;
+    for path in omittable(mode) {
+        let Some((field, parents)) = path.split_last() else {
+            continue;
+        };
+        let Some(Value::Object(parent)) = parents.iter().try_fold(&mut *event, |value, key| value.get_mut(*key)) else {
+            continue;
+        };
+        if !parent.get(*field).is_some_and(is_empty) {
+            continue;
+        }
+        let value = parent.remove(*field).unwrap_or_default();
+        // `"field":value`, plus the comma separating it from a remaining field
+        saved += Value::from(*field).to_string().len() + 1 + value.to_string().len();
+        if !parent.is_empty() {
+            saved += 1;
+        }
+    }
+    saved
+}
+
+fn is_empty(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Object(map) => map.is_empty(),
+        Value::Array(values) => values.is_empty(),
+        Value::String(s) => s.is_empty(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("compact_tests.rs");
+}
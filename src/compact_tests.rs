@@ -0,0 +1,134 @@
+use super::*;
+use crate::config::PathParams;
+use crate::request::{build_event, EventRequest};
+use axum::http::{HeaderMap, HeaderValue, Method};
+
+fn event_request<'a>(raw_query_string: &'a str, params: &'a PathParams, multi_value: bool) -> EventRequest<'a> {
+    EventRequest {
+        method: &Method::GET,
+        path: "/items/1",
+        raw_path: "/items/1",
+        raw_query_string,
+        pattern: "/items/:id",
+        host: None,
+        path_params: params,
+        source_ip: None,
+        request_id: "req-1",
+        multi_value,
+    }
+}
+
+/// The event of a bare `GET /items/1` in `mode`, before and after compaction, checking that the
+/// reported savings match the serialized lengths.
+fn compacted(mode: PayloadMode, multi_value: bool) -> (Value, Value) {
+    let params = PathParams::new();
+    let request = event_request("", &params, multi_value);
+    let event = build_event(mode, &request, &HeaderMap::new(), "", false);
+    let mut compact_event = event.clone();
+    let saved = compact(mode, &mut compact_event);
+    assert_eq!(event.to_string().len() - compact_event.to_string().len(), saved, "{:?}", mode);
+    (event, compact_event)
+}
+
+fn assert_omitted(event: &Value, pointers: &[&str]) {
+    for pointer in pointers {
+        assert_eq!(event.pointer(pointer), None, "{} in {}", pointer, event);
+    }
+}
+
+#[test]
+fn test_compact_alb() {
+    let (event, compact_event) = compacted(PayloadMode::Alb, false);
+    assert_eq!(event["headers"], serde_json::json!({}));
+    assert_omitted(
+        &compact_event,
+        &["/headers", "/queryStringParameters", "/body", "/requestContext/elb/targetGroupArn"],
+    );
+    assert_eq!(compact_event["httpMethod"], "GET");
+    assert_eq!(compact_event["isBase64Encoded"], false);
+    assert_eq!(compact_event["requestContext"]["elb"], serde_json::json!({}));
+    let parsed: aws_lambda_events::alb::AlbTargetGroupRequest = serde_json::from_value(compact_event).unwrap();
+    assert_eq!(parsed.http_method, Method::GET);
+    assert_eq!(parsed.body, None);
+
+    let (_, compact_event) = compacted(PayloadMode::Alb, true);
+    assert_omitted(&compact_event, &["/multiValueHeaders", "/multiValueQueryStringParameters"]);
+    serde_json::from_value::<aws_lambda_events::alb::AlbTargetGroupRequest>(compact_event).unwrap();
+}
+
+#[test]
+fn test_compact_apigw_v1() {
+    let (event, compact_event) = compacted(PayloadMode::ApiGatewayV1, false);
+    assert_eq!(event["stageVariables"], Value::Null);
+    assert_omitted(
+        &compact_event,
+        &[
+            "/headers",
+            "/multiValueHeaders",
+            "/queryStringParameters",
+            "/multiValueQueryStringParameters",
+            "/pathParameters",
+            "/stageVariables",
+            "/body",
+            "/requestContext/accountId",
+            "/requestContext/identity/userAgent",
+        ],
+    );
+    assert_eq!(compact_event["requestContext"]["httpMethod"], "GET");
+    assert_eq!(compact_event["requestContext"]["requestId"], "req-1");
+    let parsed: aws_lambda_events::apigw::ApiGatewayProxyRequest = serde_json::from_value(compact_event).unwrap();
+    assert_eq!(parsed.path.as_deref(), Some("/items/1"));
+    assert_eq!(parsed.resource.as_deref(), Some("/items/{id}"));
+}
+
+#[test]
+fn test_compact_apigw_v2() {
+    let (event, compact_event) = compacted(PayloadMode::ApiGatewayV2, false);
+    assert_eq!(event["rawQueryString"], "");
+    assert_omitted(
+        &compact_event,
+        &["/headers", "/rawQueryString", "/requestContext/domainName", "/requestContext/http/userAgent"],
+    );
+    for field in ["version", "routeKey", "rawPath", "isBase64Encoded"] {
+        assert!(compact_event.get(field).is_some(), "{}", field);
+    }
+    assert_eq!(compact_event["requestContext"]["http"]["method"], "GET");
+    let parsed: aws_lambda_events::apigw::ApiGatewayV2httpRequest = serde_json::from_value(compact_event).unwrap();
+    assert_eq!(parsed.raw_path.as_deref(), Some("/items/1"));
+}
+
+#[test]
+fn test_compact_function_url() {
+    let (_, compact_event) = compacted(PayloadMode::FunctionUrl, false);
+    assert_omitted(&compact_event, &["/headers", "/rawQueryString", "/requestContext/http/sourceIp"]);
+    // Function URL events name the account even when anonymous
+    assert_eq!(compact_event["requestContext"]["accountId"], "anonymous");
+    let parsed: aws_lambda_events::lambda_function_urls::LambdaFunctionUrlRequest =
+        serde_json::from_value(compact_event).unwrap();
+    assert_eq!(parsed.request_context.http.method.as_deref(), Some("GET"));
+}
+
+#[test]
+fn test_compact_keeps_populated_fields() {
+    let params = PathParams::from([("id".to_string(), "1".to_string())]);
+    let request = event_request("a=1", &params, false);
+    let mut headers = HeaderMap::new();
+    headers.insert("host", HeaderValue::from_static("api.example.com"));
+    headers.insert("user-agent", HeaderValue::from_static("curl/8.0"));
+    for mode in [PayloadMode::Alb, PayloadMode::ApiGatewayV1, PayloadMode::ApiGatewayV2] {
+        let event = build_event(mode, &request, &headers, "hello", false);
+        let mut compact_event = event.clone();
+        compact(mode, &mut compact_event);
+        assert_eq!(compact_event["headers"], event["headers"], "{:?}", mode);
+        assert_eq!(compact_event["queryStringParameters"]["a"], "1", "{:?}", mode);
+        assert_eq!(compact_event["body"], "hello", "{:?}", mode);
+    }
+}
+
+#[test]
+fn test_compact_raw_is_untouched() {
+    assert!(omittable(PayloadMode::Raw).is_empty());
+    let mut payload = serde_json::json!({"a": null, "b": {}});
+    assert_eq!(compact(PayloadMode::Raw, &mut payload), 0);
+    assert_eq!(payload, serde_json::json!({"a": null, "b": {}}));
+}
@@ -0,0 +1,297 @@
+use crate::config::CompressionConfig;
+use crate::request::media_type;
+use crate::StreamedResponse;
+use axum::body::{Body, Bytes};
+use axum::http::header::{ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, VARY};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::Response;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use http_body::Frame;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Brotli quality, low enough to keep up with streamed responses.
+const BROTLI_QUALITY: u32 = 5;
+/// Brotli window, as the log2 of its size.
+const BROTLI_WINDOW: u32 = 22;
+
+/// A content coding the gateway compresses responses with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    /// The zlib format, which `deflate` names in HTTP.
+    Deflate,
+}
+
+impl Encoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+/// The encoding the `Accept-Encoding` of `headers` weighs highest, Brotli then gzip on equal
+/// weights. `*` weighs the encodings not listed. `None` when the header is missing or accepts none
+/// of them.
+pub fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+    let codings: Vec<(String, f32)> = headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|coding| {
+            let mut params = coding.split(';');
+            let name = params.next()?.trim().to_ascii_lowercase();
+            let q = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse().ok())?;
+            (!name.is_empty()).then_some((name, q))
+        })
+        .collect();
+    let quality = |name: &str| {
+        let listed = codings.iter().find(|(coding, _)| coding == name);
+        listed
+            .or_else(|| codings.iter().find(|(coding, _)| coding == "*"))
+            .map_or(0.0, |(_, q)| *q)
+    };
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate] {
+        let q = quality(encoding.as_str());
+        if q > best.map_or(0.0, |(_, best)| best) {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Whether the media type `pattern` of `compression.types` matches `media_type`.
+pub fn matches_type(pattern: &str, media_type: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(kind) => media_type
+            .split_once('/')
+            .is_some_and(|(media_kind, _)| media_kind.eq_ignore_ascii_case(kind)),
+        None => pattern.eq_ignore_ascii_case(media_type),
+    }
+}
+
+/// Whether a response could be compressed: it has a body of one of the configured types, is not
+/// encoded already, and is not marked `no-transform`.
+fn compressible(config: &CompressionConfig, status: StatusCode, headers: &HeaderMap) -> bool {
+    let bodiless = [
+        StatusCode::NO_CONTENT,
+        StatusCode::NOT_MODIFIED,
+        StatusCode::PARTIAL_CONTENT,
+    ];
+    if status.is_informational() || bodiless.contains(&status) || headers.contains_key(CONTENT_ENCODING) {
+        return false;
+    }
+    let no_transform = headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.to_ascii_lowercase().contains("no-transform"));
+    let content_type = headers.get(CONTENT_TYPE).and_then(|value| value.to_str().ok());
+    match content_type {
+        Some(content_type) if !no_transform => {
+            let media_type = media_type(content_type);
+            config.types.iter().any(|pattern| matches_type(pattern, &media_type))
+        }
+        _ => false,
+    }
+}
+
+/// Compresses `response` with `encoding` when `config` covers it, marking it `Vary:
+/// Accept-Encoding` whether or not this client accepts any. A buffered body is compressed whole
+/// and given its new `Content-Length`. A streamed body is compressed chunk by chunk, each flushed
+/// as it comes so that events are not held back, and sent without a `Content-Length`.
+pub async fn compress(response: Response, encoding: Option<Encoding>, config: &CompressionConfig) -> Response {
+    if !compressible(config, response.status(), response.headers()) {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    // Caches must not hand a compressed response to clients that did not ask for one
+    let varies = parts
+        .headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| name.trim() == "*" || name.trim().eq_ignore_ascii_case("accept-encoding"));
+    if !varies {
+        parts.headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+    }
+    let Some(encoding) = encoding else {
+        return Response::from_parts(parts, body);
+    };
+    let body = if parts.extensions.get::<StreamedResponse>().is_some() {
+        parts.headers.remove(CONTENT_LENGTH);
+        Body::new(CompressedBody::new(body, encoding))
+    } else {
+        // A buffered body is in memory, so reading it cannot fail
+        let data = axum::body::to_bytes(body, usize::MAX).await.unwrap_or_default();
+        if data.len() < config.min_bytes {
+            return Response::from_parts(parts, Body::from(data));
+        }
+        let mut encoder = Encoder::new(encoding);
+        let compressed = match encoder.push(&data).and_then(|head| Ok((head, encoder.finish()?))) {
+            Ok((head, tail)) => [head, tail].concat(),
+            Err(e) => {
+                tracing::warn!("Sending the response uncompressed, compression failed: {}", e);
+                return Response::from_parts(parts, Body::from(data));
+            }
+        };
+        parts
+            .headers
+            .insert(CONTENT_LENGTH, HeaderValue::from(compressed.len()));
+        Body::from(compressed)
+    };
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.as_str()));
+    // The compressed bytes differ, so a strong validator of the original no longer holds
+    if let Some(etag) = parts.headers.get(ETAG).and_then(|value| value.to_str().ok()) {
+        if !etag.starts_with("W/") {
+            if let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag)) {
+                parts.headers.insert(ETAG, weak);
+            }
+        }
+    }
+    Response::from_parts(parts, body)
+}
+
+/// A compressor whose output is taken as it is produced.
+enum Encoder {
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                4096,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+            Encoding::Deflate => Encoder::Deflate(ZlibEncoder::new(Vec::new(), Compression::default())),
+        }
+    }
+
+    /// Compresses `data` and flushes it, returning everything produced since the last call. The
+    /// output decodes to all the data pushed so far.
+    fn push(&mut self, data: &[u8]) -> io::Result<Bytes> {
+        if data.is_empty() {
+            return Ok(Bytes::new());
+        }
+        let output = match self {
+            Encoder::Brotli(writer) => {
+                writer.write_all(data)?;
+                writer.flush()?;
+                writer.get_mut()
+            }
+            Encoder::Gzip(writer) => {
+                writer.write_all(data)?;
+                writer.flush()?;
+                writer.get_mut()
+            }
+            Encoder::Deflate(writer) => {
+                writer.write_all(data)?;
+                writer.flush()?;
+                writer.get_mut()
+            }
+        };
+        Ok(Bytes::from(std::mem::take(output)))
+    }
+
+    /// Ends the compressed stream, returning its last bytes.
+    fn finish(self) -> io::Result<Bytes> {
+        let output = match self {
+            Encoder::Brotli(writer) => writer.into_inner(),
+            Encoder::Gzip(writer) => writer.finish()?,
+            Encoder::Deflate(writer) => writer.finish()?,
+        };
+        Ok(Bytes::from(output))
+    }
+}
+
+/// A streamed body compressed as it is sent. Trailers follow the end of the compressed stream.
+pub struct CompressedBody {
+    inner: Body,
+    /// Taken when the compressed stream has ended or failed.
+    encoder: Option<Encoder>,
+    /// Trailers of the inner body, held back until the compressed stream has ended.
+    trailers: Option<HeaderMap>,
+}
+
+impl CompressedBody {
+    pub fn new(inner: Body, encoding: Encoding) -> Self {
+        Self {
+            inner,
+            encoder: Some(Encoder::new(encoding)),
+            trailers: None,
+        }
+    }
+
+    fn finish(&mut self) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let Some(encoder) = self.encoder.take() else {
+            return Poll::Ready(None);
+        };
+        Poll::Ready(Some(encoder.finish().map(Frame::data).map_err(axum::Error::new)))
+    }
+}
+
+impl http_body::Body for CompressedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let this = &mut *self;
+        loop {
+            let Some(encoder) = this.encoder.as_mut() else {
+                return Poll::Ready(this.trailers.take().map(|trailers| Ok(Frame::trailers(trailers))));
+            };
+            match Pin::new(&mut this.inner).poll_frame(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Ok(frame))) => match frame.into_data() {
+                    Ok(data) => match encoder.push(&data) {
+                        // Nothing to send for an empty chunk
+                        Ok(compressed) if compressed.is_empty() => continue,
+                        Ok(compressed) => return Poll::Ready(Some(Ok(Frame::data(compressed)))),
+                        Err(e) => {
+                            this.encoder = None;
+                            return Poll::Ready(Some(Err(axum::Error::new(e))));
+                        }
+                    },
+                    // Trailers come last
+                    Err(frame) => {
+                        this.trailers = frame.into_trailers().ok();
+                        return this.finish();
+                    }
+                },
+                Poll::Ready(Some(Err(e))) => {
+                    this.encoder = None;
+                    return Poll::Ready(Some(Err(e)));
+                }
+                Poll::Ready(None) => return this.finish(),
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.encoder.is_none() && self.trailers.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("compression_tests.rs");
+}
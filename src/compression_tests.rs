@@ -0,0 +1,121 @@
+use super::*;
+use http_body_util::BodyExt;
+use std::io::Read;
+
+fn accepting(accept_encoding: &str) -> HeaderMap {
+    HeaderMap::from_iter([(ACCEPT_ENCODING, HeaderValue::from_str(accept_encoding).unwrap())])
+}
+
+fn enabled() -> CompressionConfig {
+    CompressionConfig {
+        enabled: true,
+        ..Default::default()
+    }
+}
+
+fn buffered(content_type: &str, body: &str) -> Response {
+    Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .header(CONTENT_LENGTH, body.len())
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn decode(encoding: Encoding, data: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::new();
+    match encoding {
+        Encoding::Brotli => brotli::Decompressor::new(data, 4096).read_to_end(&mut decoded),
+        Encoding::Gzip => flate2::read::GzDecoder::new(data).read_to_end(&mut decoded),
+        Encoding::Deflate => flate2::read::ZlibDecoder::new(data).read_to_end(&mut decoded),
+    }
+    .unwrap();
+    decoded
+}
+
+#[test]
+fn test_negotiate() {
+    assert_eq!(negotiate(&HeaderMap::new()), None);
+    assert_eq!(negotiate(&accepting("gzip, br")), Some(Encoding::Brotli));
+    assert_eq!(negotiate(&accepting("gzip, deflate")), Some(Encoding::Gzip));
+    assert_eq!(negotiate(&accepting("br;q=0.5, GZIP")), Some(Encoding::Gzip));
+    assert_eq!(negotiate(&accepting("deflate")), Some(Encoding::Deflate));
+    assert_eq!(negotiate(&accepting("*")), Some(Encoding::Brotli));
+    assert_eq!(negotiate(&accepting("br;q=0, *;q=0.1")), Some(Encoding::Gzip));
+    assert_eq!(negotiate(&accepting("identity")), None);
+    assert_eq!(negotiate(&accepting("gzip;q=0")), None);
+}
+
+#[test]
+fn test_matches_type() {
+    assert!(matches_type("application/json", "application/json"));
+    assert!(matches_type("text/*", "text/event-stream"));
+    assert!(!matches_type("text/*", "application/json"));
+    assert!(!matches_type("application/json", "application/problem+json"));
+}
+
+#[tokio::test]
+async fn test_buffered_response_is_compressed_whole() {
+    let body = "{\"items\": []} ".repeat(200);
+    for encoding in [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate] {
+        let response = compress(buffered("application/json", &body), Some(encoding), &enabled()).await;
+        assert_eq!(response.headers()[CONTENT_ENCODING], encoding.as_str());
+        assert_eq!(response.headers()[VARY], "accept-encoding");
+        let length: usize = response.headers()[CONTENT_LENGTH].to_str().unwrap().parse().unwrap();
+        let compressed = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(length, compressed.len());
+        assert!(length < body.len());
+        assert_eq!(decode(encoding, &compressed), body.as_bytes());
+    }
+}
+
+#[tokio::test]
+async fn test_responses_left_uncompressed() {
+    let body = "x".repeat(2048);
+    // Too small
+    let response = compress(buffered("text/plain", "small"), Some(Encoding::Gzip), &enabled()).await;
+    assert!(!response.headers().contains_key(CONTENT_ENCODING));
+    assert_eq!(response.headers()[VARY], "accept-encoding");
+    // A client accepting no encoding still gets the Vary
+    let response = compress(buffered("text/plain", &body), None, &enabled()).await;
+    assert!(!response.headers().contains_key(CONTENT_ENCODING));
+    assert_eq!(response.headers()[VARY], "accept-encoding");
+    // Not a configured type
+    let response = compress(buffered("image/png", &body), Some(Encoding::Gzip), &enabled()).await;
+    assert!(!response.headers().contains_key(CONTENT_ENCODING) && !response.headers().contains_key(VARY));
+    // Encoded by the function already
+    let mut response = buffered("text/plain", &body);
+    response.headers_mut().insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    let response = compress(response, Some(Encoding::Brotli), &enabled()).await;
+    assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+    assert_eq!(response.headers()[CONTENT_LENGTH], "2048");
+    let mut response = buffered("text/plain", &body);
+    response.headers_mut().insert(CACHE_CONTROL, HeaderValue::from_static("no-transform"));
+    let response = compress(response, Some(Encoding::Gzip), &enabled()).await;
+    assert!(!response.headers().contains_key(CONTENT_ENCODING));
+}
+
+#[tokio::test]
+async fn test_streamed_response_decodes_chunk_by_chunk() {
+    let chunks = ["event: a\n\n", "", "event: b\n\n", "event: c\n\n"];
+    for encoding in [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate] {
+        let stream = futures::stream::iter(chunks.map(|chunk| Ok::<_, io::Error>(Bytes::from(chunk))));
+        let mut response = Response::builder()
+            .header(CONTENT_TYPE, "text/event-stream")
+            .header(ETAG, "\"v1\"")
+            .body(Body::from_stream(stream))
+            .unwrap();
+        response.extensions_mut().insert(StreamedResponse);
+        let response = compress(response, Some(encoding), &enabled()).await;
+        assert_eq!(response.headers()[CONTENT_ENCODING], encoding.as_str());
+        assert_eq!(response.headers()[ETAG], "W/\"v1\"");
+
+        // The empty chunk sends nothing, and the end of the stream comes in a frame of its own
+        let mut body = response.into_body();
+        let mut frames = Vec::new();
+        while let Some(frame) = body.frame().await {
+            frames.push(frame.unwrap().into_data().unwrap());
+        }
+        assert_eq!(frames.len(), 4);
+        assert_eq!(decode(encoding, &frames.concat()), chunks.concat().as_bytes());
+    }
+}
@@ -1,81 +1,3427 @@
+use crate::expr::{Attributes, CompileError, Expr};
+use crate::routes::RouteRegistry;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use std::str::FromStr;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
 use std::fs;
-use std::path::Path;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock, RwLock};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Config {
+    /// Function of unmatched requests and of targets without their own `function`; required
+    /// unless neither exists.
+    #[serde(default)]
+    pub lambda_function_name: String,
+    #[serde(default = "default_lambda_invoke_mode")]
+    pub lambda_invoke_mode: LambdaInvokeMode,
+    /// Event shape functions are invoked with, unless their target sets its own.
+    #[serde(default)]
+    pub payload: PayloadMode,
+    #[serde(default)]
+    pub api_keys: HashSet<String>,
+    /// API keys whose callers may send already base64-encoded bodies with
+    /// `x-gateway-body-encoded: base64`.
+    #[serde(default)]
+    pub trusted_body_encoding_keys: HashSet<String>,
+    /// API keys accepted by the `/admin` endpoints. Empty disables them.
+    #[serde(default)]
+    pub admin_api_keys: HashSet<String>,
+    #[serde(default = "default_auth_mode")]
+    pub auth_mode: AuthMode,
+    /// Where the listener is bound without `listeners`: `host:port`, or `unix:/path/to.sock`.
+    #[serde(default = "default_addr")]
+    pub addr: BindAddr,
+    /// Named listeners, each with its own address and profile, serving the same targets. When
+    /// set, `addr` is not bound. Addresses are read at startup, profiles per request.
+    #[serde(default)]
+    pub listeners: BTreeMap<String, ListenerConfig>,
+    /// Certificate of the listeners serving HTTPS; see [`crate::tls`]. Needs the `tls` feature.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Host names requests must be sent to, answered with 421 otherwise; `*.example.com` allows
+    /// any subdomain. Empty allows every host.
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+    /// The Prometheus `/metrics` endpoint.
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    /// A limit on the invokes of all targets together, applied after each target's own.
+    #[serde(default)]
+    pub concurrency: GlobalConcurrency,
+    /// Shares of the queued requests each priority class is admitted with.
+    #[serde(default)]
+    pub priority_weights: PriorityWeights,
+    /// Format of the gateway's own log lines, read at startup.
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// The header correlating a request across the gateway, the function and the response.
+    #[serde(default)]
+    pub request_id: RequestIdConfig,
+    /// Trace context carried from the client through the gateway to the function.
+    #[serde(default)]
+    pub tracing: TracingConfig,
+    /// Export of the gateway's spans to an OpenTelemetry collector, read at startup.
+    #[serde(default)]
+    pub otel: OtelConfig,
+    /// Compression of responses for clients that accept it; see [`crate::compression`].
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    #[serde(default)]
+    pub access_log: AccessLogConfig,
+    #[serde(default)]
+    pub conflict_retry: ConflictRetryConfig,
+    #[serde(default)]
+    pub retry_after: RetryAfterConfig,
+    #[serde(default)]
+    pub event_retry: EventRetryConfig,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
+    pub log_dedup: LogDedupConfig,
+    /// Gateway behaviors enabled for some clients only, keyed by flag name; adjustable at runtime
+    /// through `/admin/flags`.
+    #[serde(default)]
+    pub feature_flags: BTreeMap<String, FeatureFlag>,
+    /// Connection to the shared store that features configured with `store: redis` keep state in.
+    #[serde(default)]
+    pub state_store: StateStoreConfig,
+    /// Disk space for the streams of targets with `resumable_downloads`.
+    #[serde(default)]
+    pub spool: SpoolConfig,
+    /// Windows and thresholds of the burn rate alerts for targets with an `slo`.
+    #[serde(default)]
+    pub error_budget: ErrorBudgetConfig,
+    /// Limits on the request head, applied by the listener.
+    #[serde(default)]
+    pub server: ServerConfig,
+    /// Startup checks against accidentally exposing functions without authentication.
+    #[serde(default)]
+    pub security: SecurityConfig,
+    /// Buffers reused to serialize invoke payloads. Read at startup.
+    #[serde(default)]
+    pub payload_buffers: PayloadBufferConfig,
+    /// Upper bound on the memory held by all in-memory state together; stores are evicted in
+    /// proportion to their usage once it is exceeded. Unset means no limit.
+    #[serde(default)]
+    pub state_memory_budget_bytes: Option<usize>,
+    /// How long streams of a target removed or changed by a reload may keep running before they are
+    /// aborted.
+    #[serde(default = "default_reload_drain_timeout_ms")]
+    pub reload_drain_timeout_ms: u64,
+    /// Where lifecycle events, such as reloads and shutdown phases, are delivered. Read at startup.
+    #[serde(default)]
+    pub lifecycle: LifecycleConfig,
+    /// What makes two requests the same request wherever the gateway needs to tell; see
+    /// [`crate::canonical`].
+    #[serde(default)]
+    pub canonical_request: CanonicalRequestConfig,
+    /// How request records written to disk are stored, and the keys encrypting them; see
+    /// [`crate::body_store`].
+    #[serde(default)]
+    pub body_storage: BodyStorageConfig,
+    /// Keeps the failed requests of the flight recorder in a file as well. Read at startup.
+    #[serde(default)]
+    pub flight_recorder: FlightRecorderConfig,
+    /// Checks of the gateway clock against a reference clock; see [`crate::clock`]. Read at startup.
+    #[serde(default)]
+    pub clock: ClockConfig,
+    /// Templates of error bodies for clients preferring HTML or plain text; see
+    /// [`crate::error_pages`].
+    #[serde(default)]
+    pub error_pages: ErrorPagesConfig,
+    /// Status of the answer to a request whose function failed, reporting a `FunctionError`.
+    #[serde(default = "default_function_error_status")]
+    pub function_error_status: u16,
+    /// Largest request body read, as sent by the client; larger bodies are answered with 413.
+    /// Targets can set their own.
+    #[serde(default = "default_max_request_body_bytes")]
+    pub max_request_body_bytes: usize,
+    /// Includes the `errorType` and `errorMessage` of a failed function in the error body, which
+    /// is opaque otherwise. Off by default, as they may reveal the function's internals.
+    #[serde(default)]
+    pub expose_function_errors: bool,
+    /// Ordered shutdown phases and their deadlines; see [`Config::shutdown_config`].
+    #[serde(default)]
+    pub shutdown: Option<ShutdownConfig>,
+    /// Superseded by `shutdown`: when that is unset, the deadline of both drain phases and the
+    /// final state checkpoint.
+    #[serde(default)]
+    pub shutdown_grace_secs: Option<u64>,
+    /// Adds debugging headers such as `x-gateway-upstream` to responses.
+    #[serde(default)]
+    pub debug_headers: bool,
+    /// Sends the gateway version, instance ID and request ID to functions as the invoke
+    /// `ClientContext`.
+    #[serde(default)]
+    pub client_context: bool,
+    /// Identifies this gateway instance in the `ClientContext`; defaults to the host name.
+    #[serde(default)]
+    pub instance_id: Option<String>,
+    /// Per-route settings keyed by path pattern, e.g. `/orders` or `/orders/*rest`.
+    #[serde(default)]
+    pub targets: BTreeMap<String, Target>,
+    /// A control plane that more targets are fetched from; see [`crate::config_source`]. Read at
+    /// startup.
+    #[serde(default)]
+    pub config_source: Option<ConfigSourceConfig>,
+    /// Signing of temporary URLs that targets with `accept_signed_urls` serve without an API key;
+    /// see [`crate::signed_url`].
+    #[serde(default)]
+    pub signed_urls: Option<SignedUrlConfig>,
+    /// Where targets' open and half-open circuit breakers are kept, so that replicas share them
+    /// and restarts keep them; see [`crate::breaker`]. Read at startup.
+    #[serde(default)]
+    pub circuit_breaker_store: StoreKind,
+    /// Where namespaces' rate limit buckets are kept, so that replicas share the limits and
+    /// restarts keep them; see [`crate::rate_limit`]. Read at startup.
+    #[serde(default)]
+    pub rate_limit_store: StoreKind,
+    /// Bans of client addresses that keep failing API key authentication; see
+    /// [`crate::auth_ban`].
+    #[serde(default)]
+    pub auth_bans: Option<AuthBanConfig>,
+    /// Pattern of the target serving the bare root path `/`. Without it, the root is served by a
+    /// target keyed `/`, or the top-level function; wildcard targets never match the root.
+    #[serde(default)]
+    pub root_target: Option<String>,
+    /// What serves a request that matches no target.
+    #[serde(default)]
+    pub unmatched: UnmatchedRoute,
+    /// Groups of targets owned by different teams, each mounted under its own path prefix.
+    #[serde(default)]
+    pub namespaces: BTreeMap<String, Namespace>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Namespace {
+    /// Path prefix the namespace's targets are mounted under, e.g. `/team-a`.
+    pub prefix: String,
+    /// File with the rest of the namespace's settings, relative to the main config file. Settings
+    /// given inline take precedence over the file.
+    pub file: Option<PathBuf>,
+    /// Default auth mode for the namespace's targets.
+    pub auth_mode: Option<AuthMode>,
+    /// Default API keys for the namespace's targets.
+    pub api_keys: Option<HashSet<String>>,
+    /// Requests per second the namespace's targets accept together; unset means no limit.
+    pub rate_limit: Option<RateLimit>,
+    /// Targets keyed by path pattern relative to `prefix`.
+    pub targets: BTreeMap<String, Target>,
+    /// Where the settings came from, for namespaces with a `file`.
+    #[serde(skip)]
+    pub source: Option<NamespaceSource>,
+}
+
+/// What a namespace's `file` is merged into, kept so that the file can be reloaded alone.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NamespaceSource {
+    /// The namespace as written in the main config.
+    pub inline: Box<Namespace>,
+    /// Directory `file` is relative to.
+    pub base_dir: PathBuf,
+}
+
+/// A token bucket: requests over it are rejected with 429 until it refills.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RateLimit {
+    /// Rate the bucket refills at.
+    pub requests_per_second: f64,
+    /// Requests accepted at once after a quiet period; unset allows one second's worth.
+    pub burst: Option<u32>,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            requests_per_second: 1.0,
+            burst: None,
+        }
+    }
+}
+
+impl RateLimit {
+    /// Size of the bucket, never below one request.
+    pub fn capacity(&self) -> f64 {
+        self.burst.map_or(self.requests_per_second, f64::from).max(1.0)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Target {
+    /// Function to invoke, or a list of identical functions to spread requests over; defaults to
+    /// `lambda_function_name`.
+    pub function: Option<FunctionRef>,
+    /// Alias or version of the function to invoke, such as `live` or `42`; unset invokes
+    /// `$LATEST`, or the qualifier in a function ARN.
+    pub qualifier: Option<String>,
+    /// Lets a request pick the alias or version to invoke with the [`QUALIFIER_HEADER`] header,
+    /// e.g. to smoke-test a new version. Without it the header is ignored.
+    ///
+    /// [`QUALIFIER_HEADER`]: crate::request::QUALIFIER_HEADER
+    pub qualifier_on_request: bool,
+    /// Lets a request with [`DRY_RUN_HEADER`] set to `true` have Lambda check the gateway's
+    /// permission to invoke the function, answered `204`, instead of invoking it.
+    ///
+    /// [`DRY_RUN_HEADER`]: crate::request::DRY_RUN_HEADER
+    pub dry_run_on_request: bool,
+    /// Serves only requests for this host: a host name, optionally with a port, or `*.` followed
+    /// by a domain for its subdomains. Such targets take precedence over targets without a host.
+    pub host: Option<String>,
+    /// Methods the target accepts, such as `[get, head]`; others are answered 405 with an `Allow`
+    /// header, without authenticating or invoking. Unset accepts any method.
+    pub methods: Option<Vec<String>>,
+    /// How requests are spread when `function` is a list.
+    pub strategy: BalanceStrategy,
+    /// Invoke mode; defaults to `lambda_invoke_mode`.
+    pub invoke: Option<LambdaInvokeMode>,
+    /// Lets a request pick the invoke mode with the [`INVOKE_MODE_HEADER`] header, e.g. to stream
+    /// a large export. Values the header does not parse as fall back to `invoke`.
+    ///
+    /// [`INVOKE_MODE_HEADER`]: crate::request::INVOKE_MODE_HEADER
+    pub allow_invoke_mode_override: bool,
+    /// Bytes of padding sent ahead of a streamed `text/html` body so buffering proxies and
+    /// browsers start rendering right away. Zero disables the padding.
+    pub initial_flush_padding: usize,
+    /// Aborts a stream once chunks have waited longer than this for the client, continuously for
+    /// `max_client_lag_duration_ms`, or once a client has taken no chunk for both added together.
+    /// Unset disables the abort; lag is still measured and logged.
+    pub max_client_lag_ms: Option<u64>,
+    pub max_client_lag_duration_ms: u64,
+    /// Budget for the function's answer, answered with 504 when exceeded: the whole response of a
+    /// buffered invoke, conflict retries included, or the first event of a stream. Does not limit
+    /// how long the stream runs.
+    #[serde(alias = "timeout_ms")]
+    pub invoke_timeout_ms: Option<u64>,
+    /// Retries invokes that failed in a transient way; see [`InvokeRetryConfig`].
+    pub retries: Option<InvokeRetryConfig>,
+    /// Fast-fails requests with 503 while the function keeps failing; see [`CircuitBreakerConfig`].
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Streaming only: budget for receiving the complete response prelude once the first event
+    /// has arrived, answered with 504 when exceeded.
+    pub prelude_timeout_ms: Option<u64>,
+    /// Streaming only: cap on the whole response, measured from dispatching the invoke. A stream
+    /// still running at the cap is aborted however steadily the function is sending.
+    pub max_stream_duration_ms: Option<u64>,
+    /// Streaming only: aborts a stream once the function has sent nothing for this long after the
+    /// prelude. Unset lets a stream idle until `max_stream_duration_ms` or the invoke ends.
+    pub idle_timeout_ms: Option<u64>,
+    /// Format of the gateway's own error bodies, instead of negotiating it from `Accept`.
+    pub error_format: Option<ErrorFormat>,
+    /// Response header globs to remove, on top of the built-in strip list.
+    pub strip_response_headers: Vec<String>,
+    /// When non-empty, only response headers matching these globs are passed to the client.
+    pub allow_response_headers: Vec<String>,
+    /// Rewrites the `Set-Cookie` headers of responses, e.g. for a function unaware of the path
+    /// prefix it is served under.
+    pub cookie_rewrite: Option<CookieRewrite>,
+    /// Auth mode; defaults to the namespace's, then `auth_mode`.
+    pub auth: Option<AuthMode>,
+    /// API keys accepted in `ApiKey` mode; defaults to the namespace's, then `api_keys`.
+    pub api_keys: Option<HashSet<String>>,
+    /// Inflate gzip/deflate request bodies before forwarding them.
+    pub decompress_request: bool,
+    /// Upper bound on an inflated request body; larger bodies are rejected with 413.
+    pub max_decompressed_request_bytes: usize,
+    /// Overrides the top-level `max_request_body_bytes` for this target.
+    pub max_request_body_bytes: Option<usize>,
+    /// Rejects `application/json` request bodies that do not parse with 400, before invoking.
+    pub validate_json_body: bool,
+    /// With `validate_json_body`, also rejects bodies nesting arrays and objects deeper than this.
+    pub max_json_depth: Option<usize>,
+    /// Sends each path parameter captured by the pattern as an `x-path-param-<name>` header, for
+    /// ALB-mode functions whose payload has no `pathParameters`.
+    pub path_param_headers: bool,
+    /// Forwards the path without the literal segments the pattern starts with, so a function
+    /// mounted at `/svc/orders/*rest` sees `/svc/orders/list` as `/list`.
+    pub strip_prefix: bool,
+    /// Rewrites the forwarded path, after `strip_prefix`.
+    pub rewrite: Option<PathRewrite>,
+    /// Header carrying the path as the client sent it when `strip_prefix` or `rewrite` is set.
+    pub original_path_header: String,
+    /// Sends ALB events with every value of repeated headers and query parameters, in
+    /// `multiValueHeaders` and `multiValueQueryStringParameters`, as target groups with multi-value
+    /// headers enabled do.
+    pub multi_value: bool,
+    /// Leaves empty maps and null optional fields out of the event, keeping the fields the payload
+    /// mode requires. See [`crate::compact::omittable`].
+    pub compact_payload: bool,
+    /// Content type of responses that carry none: buffered responses of `payload: raw` targets,
+    /// `application/json` by default, and raw streams, `application/octet-stream` by default.
+    pub raw_content_type: Option<String>,
+    /// Hands the request to another target chosen by its JSON body.
+    #[serde(rename = "match")]
+    pub body_match: Option<BodyMatch>,
+    /// Fails the response with 502 when the function returns a header that is not valid HTTP,
+    /// instead of dropping just that header.
+    pub strict_upstream_headers: bool,
+    /// Streaming only: hashes the body sent to the client with SHA-256 and sends the digest as an
+    /// `x-content-sha256` trailer to clients that accept trailers, logging it otherwise.
+    pub body_digest_trailer: bool,
+    /// Streaming only: reads the body as newline-delimited JSON and forwards it record by record,
+    /// dropping records that do not parse or do not meet a filter; see [`NdjsonConfig`].
+    pub ndjson: Option<NdjsonConfig>,
+    /// Streaming only: reads the whole response stream and answers with a buffered response that
+    /// has a `Content-Length`, for clients that cannot handle chunked responses.
+    pub buffer_stream: bool,
+    /// Lets clients ask for `buffer_stream` per request with `x-gateway-buffer-stream: true`.
+    pub buffer_stream_on_request: bool,
+    /// Largest streamed body assembled by `buffer_stream`; larger responses fail with 502.
+    pub max_response_body_bytes: usize,
+    /// Event shape the function is invoked with and, for buffered responses, answers in; defaults
+    /// to `payload`.
+    pub payload: Option<PayloadMode>,
+    /// Streaming only: whether the function's stream starts with a response prelude.
+    pub stream_format: StreamFormat,
+    /// Streaming only: once a response is seen in the other `stream_format`, reads the target's
+    /// responses in that format until the process exits or the target is reloaded.
+    pub auto_correct_stream_format: bool,
+    /// Streaming only: spools successful streams to disk so an interrupted download can be resumed
+    /// with `Range` and the `x-download-token` the response carried, without invoking again.
+    pub resumable_downloads: bool,
+    /// Gateway settings passed to the function as `x-gateway-setting-<name>` request headers, so it
+    /// can read them at runtime instead of duplicating them.
+    pub forward_settings: Vec<ForwardedSetting>,
+    /// In `api_key` auth mode, also serves requests without a key whose URL was minted through
+    /// `/admin/signed-urls` and is still valid.
+    pub accept_signed_urls: bool,
+    /// Passes the result of gateway authentication to the function: in `requestContext.authorizer`
+    /// for API Gateway payloads, and as `x-gateway-auth-method` and, for API keys,
+    /// `x-gateway-auth-key-id` request headers for the others.
+    pub auth_context: bool,
+    /// Invokes of this target allowed to run at once; further requests queue. Unset means no limit.
+    #[serde(alias = "max_in_flight")]
+    pub max_concurrency: Option<usize>,
+    /// Requests allowed to wait for a `max_concurrency` slot; more are rejected with 429.
+    pub max_queue_depth: usize,
+    /// What happens to requests over the concurrency limit. Unset queues them when
+    /// `max_queue_depth` allows.
+    pub overflow: Option<Overflow>,
+    /// Longest wait in the queue for a slot before the request is rejected with 429. Unset waits
+    /// for as long as the request runs.
+    pub queue_timeout_ms: Option<u64>,
+    /// Adjusts the concurrency limit to the function's latency and throttles instead of a fixed
+    /// `max_concurrency`; requests over the limit queue as with `max_concurrency`.
+    pub adaptive_concurrency: Option<AdaptiveConcurrency>,
+    /// Class the target's requests queue in when a concurrency limit is reached.
+    pub priority: PriorityClass,
+    /// Classes for the requests meeting a condition, the first that holds winning over `priority`.
+    pub priority_rules: Vec<PriorityRule>,
+    pub health: TargetHealth,
+    /// Times of the week the target accepts requests; requests outside them get 503.
+    pub schedule: Option<Schedule>,
+    /// Percentage of requests expected to succeed, such as `99.5`. Enables error budget tracking,
+    /// with burn rate alerts as set by `error_budget`; responses with a 5xx status count as failed.
+    pub slo: Option<f64>,
+    /// Adds `x-gateway-slo-at-risk` to responses while the target's error budget is burning too
+    /// fast.
+    pub slo_warning_header: bool,
+    /// Mirrors the target's requests to a candidate function, whose responses are discarded.
+    pub shadow: Option<ShadowConfig>,
+    /// Namespace the target was mounted from, if any.
+    #[serde(skip_deserializing)]
+    pub namespace: Option<String>,
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Self {
+            function: None,
+            qualifier: None,
+            qualifier_on_request: false,
+            dry_run_on_request: false,
+            host: None,
+            methods: None,
+            strategy: BalanceStrategy::RoundRobin,
+            invoke: None,
+            allow_invoke_mode_override: false,
+            initial_flush_padding: 0,
+            max_client_lag_ms: None,
+            max_client_lag_duration_ms: 5000,
+            invoke_timeout_ms: None,
+            retries: None,
+            circuit_breaker: None,
+            prelude_timeout_ms: None,
+            max_stream_duration_ms: None,
+            idle_timeout_ms: None,
+            error_format: None,
+            strip_response_headers: Vec::new(),
+            cookie_rewrite: None,
+            allow_response_headers: Vec::new(),
+            auth: None,
+            api_keys: None,
+            decompress_request: false,
+            max_decompressed_request_bytes: 6 * 1024 * 1024,
+            max_request_body_bytes: None,
+            validate_json_body: false,
+            max_json_depth: None,
+            path_param_headers: false,
+            strip_prefix: false,
+            rewrite: None,
+            original_path_header: "x-forwarded-path".to_string(),
+            multi_value: false,
+            compact_payload: false,
+            raw_content_type: None,
+            body_match: None,
+            strict_upstream_headers: false,
+            body_digest_trailer: false,
+            ndjson: None,
+            buffer_stream: false,
+            buffer_stream_on_request: false,
+            max_response_body_bytes: 20 * 1024 * 1024,
+            payload: None,
+            stream_format: StreamFormat::Auto,
+            auto_correct_stream_format: false,
+            resumable_downloads: false,
+            forward_settings: Vec::new(),
+            accept_signed_urls: false,
+            auth_context: false,
+            max_concurrency: None,
+            max_queue_depth: 0,
+            overflow: None,
+            queue_timeout_ms: None,
+            adaptive_concurrency: None,
+            priority: PriorityClass::default(),
+            priority_rules: Vec::new(),
+            health: TargetHealth::default(),
+            schedule: None,
+            slo: None,
+            slo_warning_header: false,
+            shadow: None,
+            namespace: None,
+        }
+    }
+}
+
+/// Whether `qualifier` is a valid Lambda alias or version: up to 128 letters, digits, `$`, `-` or
+/// `_`.
+pub fn valid_qualifier(qualifier: &str) -> bool {
+    (1..=128).contains(&qualifier.len())
+        && qualifier
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'$' | b'-' | b'_'))
+}
+
+/// Methods a target's `methods` may list, in any case.
+pub const KNOWN_METHODS: [&str; 9] = [
+    "GET", "HEAD", "POST", "PUT", "DELETE", "PATCH", "OPTIONS", "CONNECT", "TRACE",
+];
+
+impl Target {
+    /// The class of a request: that of the first of `priority_rules` it meets, else `priority`.
+    pub fn priority_of(&self, request: &Attributes) -> PriorityClass {
+        self.priority_rules
+            .iter()
+            .find(|rule| rule.when.holds(request))
+            .map_or(self.priority, |rule| rule.priority)
+    }
+
+    /// Whether the target's `methods`, if any, include `method`.
+    pub fn accepts_method(&self, method: &axum::http::Method) -> bool {
+        match &self.methods {
+            Some(methods) => methods.iter().any(|m| m.eq_ignore_ascii_case(method.as_str())),
+            None => true,
+        }
+    }
+
+    /// `Allow` header value listing the target's `methods` in the order configured, e.g. `GET, HEAD`.
+    pub fn allow_header(&self) -> String {
+        let mut allowed: Vec<String> = Vec::new();
+        for method in self.methods.iter().flatten() {
+            let method = method.to_ascii_uppercase();
+            if !allowed.contains(&method) {
+                allowed.push(method);
+            }
+        }
+        allowed.join(", ")
+    }
+}
+
+/// Where `/metrics` is served; see [`crate::prometheus`].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    /// Serves `/metrics` on a listener of its own at this `host:port`, instead of the listeners
+    /// exposing the admin endpoints, so it can stay off the public address. Read at startup.
+    pub addr: Option<String>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            addr: None,
+        }
+    }
+}
+
+/// How requests are given the ID sent to the function and returned to the client.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct RequestIdConfig {
+    /// Header carrying the ID, on the request and on the response.
+    pub header: String,
+    /// Keeps an ID the client sent; otherwise every request gets a new one.
+    pub trust_incoming: bool,
+}
+
+impl Default for RequestIdConfig {
+    fn default() -> Self {
+        Self {
+            header: "x-request-id".to_string(),
+            trust_incoming: true,
+        }
+    }
+}
+
+/// Trace propagation; see [`crate::trace_context`].
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct TracingConfig {
+    /// Trace headers continued toward the function; unset leaves trace headers as they came.
+    pub propagation: Option<TracePropagation>,
+}
+
+/// Trace header format the gateway reads from clients and sends to functions.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TracePropagation {
+    /// AWS X-Ray's `X-Amzn-Trace-Id`, also set on the invoke so Lambda joins the trace.
+    Xray,
+    /// W3C Trace Context's `traceparent` and `tracestate`.
+    W3c,
+}
+
+/// OTLP export of spans; see [`crate::otel`]. Needs the `otel` feature.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct OtelConfig {
+    /// The collector, e.g. `http://localhost:4317` for gRPC or `http://localhost:4318` for HTTP.
+    /// Unset exports nothing.
+    pub endpoint: Option<String>,
+    pub protocol: OtlpProtocol,
+    /// `service.name` of the exported spans.
+    pub service_name: String,
+    /// Time each export may take before its spans are dropped.
+    pub timeout_ms: u64,
+}
+
+impl Default for OtelConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            protocol: OtlpProtocol::default(),
+            service_name: "lambda-web-gateway".to_string(),
+            timeout_ms: 10_000,
+        }
+    }
+}
+
+/// Compression of responses with Brotli, gzip or deflate, as the client's `Accept-Encoding`
+/// prefers. Responses that already carry a `Content-Encoding` are sent as they are.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    /// Buffered responses smaller than this are sent uncompressed. Streamed responses, whose size
+    /// is not known up front, are always compressed.
+    pub min_bytes: usize,
+    /// Content types compressed; `text/*` matches every subtype.
+    pub types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_bytes: 1024,
+            types: vec!["application/json".to_string(), "text/*".to_string()],
+        }
+    }
+}
+
+/// Transport of OTLP exports.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OtlpProtocol {
+    #[default]
+    Grpc,
+    /// Protobuf over HTTP, to the endpoint's `/v1/traces`.
+    HttpProtobuf,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// Maximum number of buffered metric events before new ones are dropped.
+    pub queue_capacity: usize,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self { queue_capacity: 4096 }
+    }
+}
+
+/// Bounds and tuning of an adaptive concurrency limit; see [`crate::adaptive::AimdController`].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct AdaptiveConcurrency {
+    pub min_limit: usize,
+    pub max_limit: usize,
+    /// Limit before any latency has been seen.
+    pub initial_limit: usize,
+    /// Finished invokes per decision.
+    pub window: usize,
+    /// How many times the baseline latency a window's p95 may reach before the limit is cut.
+    pub tolerance: f64,
+    /// Factor the limit is multiplied by when cut, between 0 and 1.
+    pub backoff: f64,
+}
+
+impl Default for AdaptiveConcurrency {
+    fn default() -> Self {
+        Self {
+            min_limit: 1,
+            max_limit: 100,
+            initial_limit: 10,
+            window: 20,
+            tolerance: 1.5,
+            backoff: 0.75,
+        }
+    }
+}
+
+/// One function, or several identical functions (e.g. sharded by region or account) that share a
+/// target's traffic.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum FunctionRef {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl FunctionRef {
+    pub fn members(&self) -> &[String] {
+        match self {
+            FunctionRef::One(name) => std::slice::from_ref(name),
+            FunctionRef::Many(names) => names,
+        }
+    }
+}
+
+impl From<&str> for FunctionRef {
+    fn from(name: &str) -> Self {
+        FunctionRef::One(name.to_string())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceStrategy {
+    #[default]
+    RoundRobin,
+    /// The member with the fewest requests in flight from this gateway.
+    LeastInFlight,
+    Random,
+}
+
+/// Event shape a target's function is invoked with, and the buffered response shape it answers in.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadMode {
+    /// ALB target group events.
+    #[default]
+    Alb,
+    /// API Gateway REST API proxy events, payload format version 1.0.
+    #[serde(rename = "apigw_v1")]
+    ApiGatewayV1,
+    /// API Gateway HTTP API events, payload format version 2.0.
+    #[serde(rename = "apigw_v2")]
+    ApiGatewayV2,
+    /// Lambda Function URL events: the 2.0 shape without routes, stages or path parameters.
+    FunctionUrl,
+    /// No event: the request body is the invoke payload, with the method, path, query and headers
+    /// in the `ClientContext`, and the response payload is the response body.
+    Raw,
+}
+
+/// What a target does with requests over its concurrency limit.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// Answer 429 at once, whatever `max_queue_depth` says.
+    Reject,
+    /// Wait for a slot in a queue of at most `max_queue_depth`, for at most `queue_timeout_ms`.
+    Queue,
+}
+
+/// Priority of a request waiting for a concurrency slot. Queued requests are admitted by weighted
+/// fair queuing across classes, and a full queue sheds the lowest class first.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum PriorityClass {
+    Critical,
+    #[default]
+    Normal,
+    Batch,
+}
+
+impl PriorityClass {
+    /// Every class, highest first.
+    pub const ALL: [PriorityClass; 3] = [PriorityClass::Critical, PriorityClass::Normal, PriorityClass::Batch];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PriorityClass::Critical => "critical",
+            PriorityClass::Normal => "normal",
+            PriorityClass::Batch => "batch",
+        }
+    }
+}
+
+/// Puts the requests meeting `when` in class `priority`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PriorityRule {
+    pub when: Condition,
+    pub priority: PriorityClass,
+}
+
+/// Relative shares of the slots freed while requests of several classes queue. Every class gets
+/// some, so none starves.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct PriorityWeights {
+    pub critical: u32,
+    pub normal: u32,
+    pub batch: u32,
+}
+
+impl PriorityWeights {
+    pub fn weight(&self, class: PriorityClass) -> u32 {
+        match class {
+            PriorityClass::Critical => self.critical,
+            PriorityClass::Normal => self.normal,
+            PriorityClass::Batch => self.batch,
+        }
+    }
+}
+
+impl Default for PriorityWeights {
+    fn default() -> Self {
+        Self {
+            critical: 8,
+            normal: 4,
+            batch: 1,
+        }
+    }
+}
+
+/// Limit on the invokes running at once across every target. Requests over it queue as they do
+/// for a target's `max_concurrency`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct GlobalConcurrency {
+    /// Unset means no limit.
+    pub max_concurrency: Option<usize>,
+    pub max_queue_depth: usize,
+    pub queue_timeout_ms: Option<u64>,
+}
+
+/// How a streaming function frames its response.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamFormat {
+    /// A stream starting with `{` is read as a prelude, anything else as a raw body.
+    #[default]
+    Auto,
+    /// A JSON prelude with the status and headers, then eight NUL bytes, then the body, as sent by
+    /// Function URL style handlers such as `lambda_http` streaming.
+    Prelude,
+    /// The body only, sent with status 200.
+    Raw,
+}
+
+/// A target setting a function may read from its request headers. Only settings listed here can be
+/// forwarded, so secrets such as API keys never reach functions this way.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardedSetting {
+    /// `invoke_timeout_ms`, when set.
+    TimeoutMs,
+    /// The namespace the target was mounted from, when any.
+    Namespace,
+    /// The target's path pattern.
+    TargetName,
+}
+
+impl ForwardedSetting {
+    pub fn header_name(&self) -> &'static str {
+        match self {
+            ForwardedSetting::TimeoutMs => "x-gateway-setting-timeout-ms",
+            ForwardedSetting::Namespace => "x-gateway-setting-namespace",
+            ForwardedSetting::TargetName => "x-gateway-setting-target-name",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct PayloadBufferConfig {
+    /// Total capacity of the idle buffers kept for reuse.
+    pub max_retained_bytes: usize,
+    /// Buffers grown past this by a large payload are freed instead of kept.
+    pub max_buffer_bytes: usize,
+}
+
+impl Default for PayloadBufferConfig {
+    fn default() -> Self {
+        Self {
+            max_retained_bytes: 32 * 1024 * 1024,
+            max_buffer_bytes: 8 * 1024 * 1024,
+        }
+    }
+}
+
+/// The steps of a graceful shutdown on SIGTERM or Ctrl-C, run one after another in `phases` order.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    pub phases: Vec<ShutdownPhase>,
+    /// How long `unready` keeps serving with `/readyz` failing, so load balancers stop sending.
+    pub unready_ms: u64,
+    /// How long `drain_buffered` waits for requests without a streamed response.
+    pub drain_buffered_ms: u64,
+    /// How long `drain_streams` waits for streamed responses.
+    pub drain_streams_ms: u64,
+    /// How long `flush_state` waits for the final state checkpoint.
+    pub flush_state_ms: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            phases: vec![
+                ShutdownPhase::Unready,
+                ShutdownPhase::RefuseNew,
+                ShutdownPhase::DrainBuffered,
+                ShutdownPhase::DrainStreams,
+                ShutdownPhase::FlushState,
+            ],
+            unready_ms: 5_000,
+            drain_buffered_ms: 20_000,
+            drain_streams_ms: 60_000,
+            flush_state_ms: 5_000,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownPhase {
+    /// Fails `/readyz`, then keeps serving for `unready_ms`.
+    Unready,
+    /// Stops accepting connections and answers new requests 503.
+    RefuseNew,
+    /// Waits for requests still waiting on their function or sending a buffered response, and
+    /// aborts them at the deadline.
+    DrainBuffered,
+    /// Waits for streamed responses, and aborts them at the deadline.
+    DrainStreams,
+    /// Writes the final state checkpoint.
+    FlushState,
+}
+
+impl ShutdownPhase {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ShutdownPhase::Unready => "unready",
+            ShutdownPhase::RefuseNew => "refuse_new",
+            ShutdownPhase::DrainBuffered => "drain_buffered",
+            ShutdownPhase::DrainStreams => "drain_streams",
+            ShutdownPhase::FlushState => "flush_state",
+        }
+    }
+}
+
+/// Collapsing of repeated identical error logs.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct LogDedupConfig {
+    pub enabled: bool,
+    /// Errors of the same target and error code within a window are logged once, followed by a
+    /// summary of how many were suppressed.
+    pub window_secs: u64,
+    /// Logs the first error of each window; otherwise only summaries are logged.
+    pub log_first: bool,
+    /// Distinct target and error code pairs tracked at once; further pairs share one entry.
+    pub max_keys: usize,
+}
+
+impl Default for LogDedupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window_secs: 60,
+            log_first: true,
+            max_keys: 1024,
+        }
+    }
+}
+
+/// Background health probing of every target.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct HealthConfig {
+    pub enabled: bool,
+    /// Default time between probes of a target.
+    pub interval_secs: u64,
+    /// A probe taking longer than this marks the target unhealthy.
+    pub timeout_ms: u64,
+    /// Probes running at the same time.
+    pub concurrency: usize,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 30,
+            timeout_ms: 5000,
+            concurrency: 4,
+        }
+    }
+}
+
+/// Changes made to each `Set-Cookie` header of a target's responses.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct CookieRewrite {
+    /// Public prefix the target is served under, such as `/app`, put in front of `Path` attributes.
+    pub path_prefix: Option<String>,
+    /// Replaces the `Domain` attribute of cookies that have one.
+    pub domain: Option<String>,
+    /// Removes the `Domain` attribute, so cookies only go back to the host that set them.
+    pub strip_domain: bool,
+    /// Adds the `Secure` attribute.
+    pub secure: bool,
+    /// Sets the `SameSite` attribute. `none` also adds `Secure`, which browsers require with it.
+    pub same_site: Option<SameSite>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl SameSite {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+/// How one target is probed.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct TargetHealth {
+    pub mode: HealthMode,
+    /// Path of the synthetic `GET` sent by `invoke` probes.
+    pub path: String,
+    /// Overrides `health.interval_secs`.
+    pub interval_secs: Option<u64>,
+    pub disabled: bool,
+}
+
+impl Default for TargetHealth {
+    fn default() -> Self {
+        Self {
+            mode: HealthMode::GetFunction,
+            path: "/".to_string(),
+            interval_secs: None,
+            disabled: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthMode {
+    /// `GetFunction`: the function exists, is active and the gateway can see it. Never runs code.
+    #[default]
+    GetFunction,
+    /// Sends a synthetic request through the normal payload builder and invoke path; healthy
+    /// when the function answers without a function error or 5xx status.
+    Invoke,
+}
+
+/// Where a stateful feature keeps its state.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreKind {
+    /// In this process; each replica counts on its own.
+    #[default]
+    Memory,
+    /// In the Redis server of `state_store`, shared by all replicas. Needs the `redis-state` feature.
+    Redis,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct StateStoreConfig {
+    /// e.g. `redis://:password@redis.internal:6379/0`.
+    pub redis_url: Option<String>,
+    /// Operations slower than this use local state instead.
+    pub timeout_ms: u64,
+    /// File local state is checkpointed to, and restored from on startup. Unset keeps local state
+    /// in memory only.
+    pub checkpoint_path: Option<PathBuf>,
+    /// Time between checkpoints, the most state a crash can lose.
+    pub checkpoint_interval_secs: u64,
+    /// Checkpoints older than this are ignored on startup.
+    pub checkpoint_max_age_secs: u64,
+}
+
+impl Default for StateStoreConfig {
+    fn default() -> Self {
+        Self {
+            redis_url: None,
+            timeout_ms: 200,
+            checkpoint_path: None,
+            checkpoint_interval_secs: 30,
+            checkpoint_max_age_secs: 3600,
+        }
+    }
+}
+
+/// A gateway behavior that can be switched on for some clients only. A request may use it when it
+/// is enabled, the request is in both allow lists and `when` holds; an empty list allows everyone.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct FeatureFlag {
+    pub enabled: bool,
+    /// IDs of the API keys allowed: the first 16 hex digits of the key's SHA-256.
+    pub allowed_key_ids: Vec<String>,
+    /// Client networks allowed, e.g. `10.0.0.0/8` or `2001:db8::/32`.
+    pub allowed_cidrs: Vec<String>,
+    /// Condition the request must meet as well.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub when: Option<Condition>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct SpoolConfig {
+    /// Directory spool files are written to; defaults to `lambda-web-gateway-spool` in the system
+    /// temp directory. Spool files left in it by an earlier run are removed on startup.
+    pub dir: Option<PathBuf>,
+    /// Streams growing past this are no longer spooled, and cannot be resumed.
+    pub max_file_bytes: u64,
+    /// Disk space for all spool files together; the oldest completed downloads are evicted to make
+    /// room for new ones.
+    pub max_total_bytes: u64,
+    /// How long a completed download can be resumed.
+    pub ttl_secs: u64,
+}
+
+impl Default for SpoolConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            max_file_bytes: 100 * 1024 * 1024,
+            max_total_bytes: 1024 * 1024 * 1024,
+            ttl_secs: 600,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Buffer for a request head; larger heads are answered with 431 before routing. Over HTTP/2
+    /// it bounds the header list instead. At least 8192. Read at startup.
+    pub max_header_bytes: usize,
+    /// Longest single header value, answered with 431 naming the header when exceeded.
+    pub max_header_value_bytes: usize,
+    /// What to do with header values continued on the next line (obs-fold). Read at startup.
+    pub obs_fold: ObsFold,
+    /// Lets listeners on IPv6 addresses such as `[::]:8000` accept IPv4 clients too, which are
+    /// then seen as their IPv4 address. Without it they accept IPv6 clients only, on every
+    /// platform. Read at startup.
+    pub dual_stack: bool,
+    /// Permissions of the socket files of listeners on `unix:` addresses, in octal. Read at
+    /// startup.
+    pub socket_mode: FileMode,
+    /// Serves HTTP/2 without TLS (h2c) to clients starting with its preface, as well as HTTP/1.1.
+    /// Read at startup.
+    pub http2: bool,
+    /// Streams an HTTP/2 connection may have open at once. Read at startup.
+    pub max_concurrent_streams: u32,
+    /// Idle time before TCP keep-alive probes are sent on client connections; 0 leaves keep-alive
+    /// off. Read at startup.
+    pub keepalive_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            max_header_bytes: 64 * 1024,
+            max_header_value_bytes: 16 * 1024,
+            obs_fold: ObsFold::Reject,
+            dual_stack: false,
+            socket_mode: FileMode(0o660),
+            http2: false,
+            max_concurrent_streams: 200,
+            keepalive_secs: 0,
+        }
+    }
+}
+
+/// Unix file permissions, written in octal as `0660`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileMode(pub u32);
+
+impl FromStr for FileMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid file mode {:?}, expected octal as 0660", s);
+        let digits = s.strip_prefix("0o").unwrap_or(s);
+        match u32::from_str_radix(digits, 8) {
+            Ok(mode) if mode <= 0o777 && !digits.is_empty() => Ok(FileMode(mode)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Serialize for FileMode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:04o}", self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for FileMode {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // A plain 0660 is read as its text, so the leading zero does not matter
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct SecurityConfig {
+    /// Fails validation when `addr` is not a loopback address, no `allowed_hosts` are set, and a
+    /// target, or the top-level function serving unmatched paths, uses open auth.
+    pub require_auth_on_public_bind: bool,
+    /// Accepts open auth on a public bind for intentionally public endpoints.
+    pub acknowledge_open: bool,
+}
+
+/// Handling of obsolete line folding in request headers, deprecated by RFC 9112.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ObsFold {
+    /// Answer 400 with error code `obs_fold_rejected`.
+    Reject,
+    /// Replace each fold with a single space, as RFC 9112 allows, and serve the request.
+    Normalize,
+}
+
+/// Multiwindow burn rate alerting: an alert fires when both the short and the long window burn
+/// the error budget at least at its rate. The short window makes alerts stop soon after a problem
+/// is fixed, the long one keeps brief blips from firing them.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ErrorBudgetConfig {
+    pub short_window_secs: u64,
+    pub long_window_secs: u64,
+    /// Burn rate of the `fast_burn` alert; 14.4 spends 2% of a 30-day budget in an hour.
+    pub fast_burn_rate: f64,
+    /// Burn rate of the `slow_burn` alert; 6 spends 5% of a 30-day budget in six hours.
+    pub slow_burn_rate: f64,
+    /// Requests the long window needs before alerts are evaluated, so a handful of failures on a
+    /// quiet target does not fire them.
+    pub min_requests: u64,
+}
+
+impl Default for ErrorBudgetConfig {
+    fn default() -> Self {
+        Self {
+            short_window_secs: 300,
+            long_window_secs: 3600,
+            fast_burn_rate: 14.4,
+            slow_burn_rate: 6.0,
+            min_requests: 20,
+        }
+    }
+}
+
+/// Weekly windows that open or close a target, e.g. business hours or a maintenance window.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Schedule {
+    /// `allow` serves requests only inside the windows, `deny` rejects requests inside them.
+    pub action: ScheduleAction,
+    /// Time zone of the windows that do not set their own; defaults to UTC.
+    #[serde(default = "default_timezone")]
+    pub timezone: chrono_tz::Tz,
+    pub windows: Vec<ScheduleWindow>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleAction {
+    Allow,
+    Deny,
+}
+
+/// A window opening on each of `days` at `start` and closing at `end`, local time. A window whose
+/// `end` is not after its `start` closes on the next day.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ScheduleWindow {
+    /// Days the window opens on, e.g. `[mon, tue]`.
+    pub days: Vec<chrono::Weekday>,
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+    /// Overrides the schedule's `timezone`.
+    #[serde(default)]
+    pub timezone: Option<chrono_tz::Tz>,
+}
+
+/// A local time written `HH:MM`, from `00:00` to `24:00`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimeOfDay {
+    pub minutes: u32,
+}
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid time of day {:?}, expected HH:MM", s);
+        let (hours, minutes) = s.split_once(':').ok_or_else(invalid)?;
+        let (hours, minutes): (u32, u32) = (
+            hours.parse().map_err(|_| invalid())?,
+            minutes.parse().map_err(|_| invalid())?,
+        );
+        if minutes >= 60 || hours * 60 + minutes > 24 * 60 {
+            return Err(invalid());
+        }
+        Ok(TimeOfDay {
+            minutes: hours * 60 + minutes,
+        })
+    }
+}
+
+impl Serialize for TimeOfDay {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:02}:{:02}", self.minutes / 60, self.minutes % 60))
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeOfDay {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+fn default_timezone() -> chrono_tz::Tz {
+    chrono_tz::UTC
+}
+
+/// Retries for invokes rejected while the function is being updated, e.g. during
+/// `UpdateFunctionCode`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ConflictRetryConfig {
+    /// Retries after the first attempt; zero fails right away.
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each one after.
+    pub backoff_ms: u64,
+    /// `Retry-After` sent with the 503 once retries are exhausted.
+    pub retry_after_secs: u64,
+}
+
+impl Default for ConflictRetryConfig {
+    fn default() -> Self {
+        Self {
+            retries: 2,
+            backoff_ms: 600,
+            retry_after_secs: 2,
+        }
+    }
+}
+
+/// Mirroring of a target's requests to a candidate function. The copy is invoked buffered, in the
+/// background, so it never delays the response sent to the client.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ShadowConfig {
+    /// The candidate function.
+    pub function: String,
+    pub qualifier: Option<String>,
+    /// Percentage of the target's requests mirrored.
+    pub sample_percent: f64,
+    /// Compares the candidate's responses with the ones sent to clients; see
+    /// [`crate::response_diff`]. Only buffered responses are compared.
+    pub compare: bool,
+    /// Time the comparison waits for the candidate before giving up on it.
+    pub timeout_ms: u64,
+    /// Response headers that must match, besides the status.
+    pub headers: Vec<String>,
+    pub body: BodyComparison,
+    /// JSON pointers of body fields left out of `json` comparisons, such as `/generated_at`. A
+    /// `*` segment stands for any key or array index, as in `/items/*/id`.
+    pub ignore_pointers: Vec<String>,
+}
+
+impl Default for ShadowConfig {
+    fn default() -> Self {
+        Self {
+            function: String::new(),
+            qualifier: None,
+            sample_percent: 100.0,
+            compare: false,
+            timeout_ms: 5000,
+            headers: vec!["content-type".to_string()],
+            body: BodyComparison::default(),
+            ignore_pointers: Vec::new(),
+        }
+    }
+}
+
+/// How shadow comparisons match response bodies.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BodyComparison {
+    /// Byte for byte.
+    Exact,
+    /// As JSON values, so key order and spacing do not count; bodies that are not JSON are
+    /// compared byte for byte.
+    #[default]
+    Json,
+}
+
+/// Retries of a target's invokes that Lambda throttled or failed on its side, with jittered
+/// exponential backoff. Conflicts are retried by `conflict_retry` beforehand, within each attempt.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct InvokeRetryConfig {
+    /// Attempts in all, the first one included.
+    pub max_attempts: u32,
+    /// Cap of the delay before the first retry, doubled for each one after. The delay itself is
+    /// drawn at random below the cap, so replicas do not retry in lockstep.
+    pub base_delay_ms: u64,
+    /// Upper bound of the doubled cap.
+    pub max_delay_ms: u64,
+    pub retry_on: Vec<RetryOn>,
+    /// Also retries requests whose method is not idempotent, such as `POST` and `PATCH`. A
+    /// function may then run more than once for one request.
+    pub retry_non_idempotent: bool,
+}
+
+impl Default for InvokeRetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay_ms: 50,
+            max_delay_ms: 1000,
+            retry_on: vec![RetryOn::Throttled, RetryOn::ServiceError],
+            retry_non_idempotent: false,
+        }
+    }
+}
+
+/// Invoke failures [`InvokeRetryConfig`] can retry.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// `TooManyRequestsException`.
+    Throttled,
+    /// `ServiceException`.
+    ServiceError,
+}
+
+impl RetryOn {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RetryOn::Throttled => "throttled",
+            RetryOn::ServiceError => "service_error",
+        }
+    }
+}
+
+/// A target's circuit breaker; see [`crate::breaker`]. Invokes that fail, are throttled or time
+/// out count as failures, as do functions reporting a `FunctionError`. Responses the function
+/// answers with a 5xx status do not.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    /// Failures within `window_secs` that open the breaker.
+    pub failure_threshold: u32,
+    pub window_secs: u64,
+    /// How long an open breaker answers 503 before letting a probe request through.
+    pub open_secs: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 10,
+            window_secs: 30,
+            open_secs: 15,
+        }
+    }
+}
+
+/// Record processing of a target streaming newline-delimited JSON; see [`crate::ndjson`]. Records
+/// are forwarded one per line, blank lines are skipped.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct NdjsonConfig {
+    /// What happens to a record that is not valid JSON or is longer than `max_record_bytes`.
+    pub on_invalid: OnInvalidRecord,
+    /// Condition a record must meet to be forwarded. Besides the request, it reads the record's
+    /// fields with `record("a.b")`, e.g. `record("tenant") == key_id`.
+    pub filter: Option<Condition>,
+    /// Longest record buffered while waiting for its end of line.
+    pub max_record_bytes: usize,
+}
+
+impl Default for NdjsonConfig {
+    fn default() -> Self {
+        Self {
+            on_invalid: OnInvalidRecord::Drop,
+            filter: None,
+            max_record_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// Handling of streamed NDJSON records that are invalid.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnInvalidRecord {
+    /// Skip the record and keep streaming.
+    Drop,
+    /// End the stream with an error at the record.
+    Abort,
+}
+
+/// Key and limits of gateway-signed URLs.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct SignedUrlConfig {
+    /// Secret the URLs are signed with, using HMAC-SHA256; at least 32 bytes. Changing it
+    /// invalidates every URL minted before.
+    pub signing_key: String,
+    /// Lifetime of a URL minted without `ttl_secs`.
+    pub default_ttl_secs: u64,
+    /// Longest lifetime a URL can be minted with; URLs expiring later are rejected.
+    pub max_ttl_secs: u64,
+    /// Where the uses of URLs minted with `max_uses` are counted. Read at startup.
+    pub store: StoreKind,
+}
+
+impl Default for SignedUrlConfig {
+    fn default() -> Self {
+        Self {
+            signing_key: String::new(),
+            default_ttl_secs: 3600,
+            max_ttl_secs: 86400,
+            store: StoreKind::Memory,
+        }
+    }
+}
+
+/// Thresholds of the ban list of client addresses failing API key authentication.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct AuthBanConfig {
+    /// Failed attempts within `window_secs` that get an address banned.
+    pub max_failures: u32,
+    pub window_secs: u64,
+    /// How long a banned address is answered 403 before it may try again.
+    pub ban_secs: u64,
+    /// Where failures and bans are kept. Read at startup.
+    pub store: StoreKind,
+}
+
+impl Default for AuthBanConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: 20,
+            window_secs: 60,
+            ban_secs: 600,
+            store: StoreKind::Memory,
+        }
+    }
+}
+
+/// Bounds of the `Retry-After` header sent with 429 and 503 answers.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct RetryAfterConfig {
+    /// Sent for throttled invokes when Lambda gives no hint of its own.
+    pub default_secs: u64,
+    /// Upper bound on any `Retry-After`, whatever its source.
+    pub max_secs: u64,
+}
+
+impl Default for RetryAfterConfig {
+    fn default() -> Self {
+        Self {
+            default_secs: 1,
+            max_secs: 60,
+        }
+    }
+}
+
+/// Queue for asynchronous (`Event`) invokes rejected with a throttle or while the function is
+/// updating, retried in the background after the client has been answered.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct EventRetryConfig {
+    /// Invokes held at once; further ones go straight to the dead-letter file. Zero disables the
+    /// queue.
+    pub capacity: usize,
+    /// Retries after the first attempt before an invoke is dead-lettered.
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each one after up to `max_backoff_ms`.
+    pub backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Retries running at once.
+    pub concurrency: usize,
+    /// JSON-lines file receiving invokes that could not be delivered; unset only logs them.
+    pub dead_letter_path: Option<PathBuf>,
+}
+
+impl Default for EventRetryConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1000,
+            retries: 5,
+            backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            concurrency: 4,
+            dead_letter_path: None,
+        }
+    }
+}
+
+/// Delivery of lifecycle events besides the structured log.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct LifecycleConfig {
+    /// Events kept for `/admin/events`.
+    pub recent_events: usize,
+    pub webhook: Option<WebhookConfig>,
+}
+
+impl Default for LifecycleConfig {
+    fn default() -> Self {
+        Self {
+            recent_events: 100,
+            webhook: None,
+        }
+    }
+}
+
+/// Request headers that take part in canonical requests, besides the method, path, query and body.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct CanonicalRequestConfig {
+    /// Header names, matched ignoring case. `host` stands for the HTTP/2 `:authority` as well.
+    pub headers: Vec<String>,
+}
+
+impl Default for CanonicalRequestConfig {
+    fn default() -> Self {
+        Self {
+            headers: vec!["content-type".to_string()],
+        }
+    }
+}
+
+/// Storage of the records features write to disk. Without `encrypt_with` records are written as
+/// they are; with it they are encrypted with that key, while every key in `keys` stays available
+/// for reading older records.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct BodyStorageConfig {
+    /// ID of the key in `keys` new records are encrypted with.
+    pub encrypt_with: Option<String>,
+    /// AES-256 keys by ID.
+    pub keys: BTreeMap<String, StorageKey>,
+}
+
+/// A 32-byte key, base64-encoded, given inline or in an environment variable, e.g. one filled from
+/// SSM Parameter Store by the task definition.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageKey {
+    pub key: Option<String>,
+    pub env: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct FlightRecorderConfig {
+    /// File the failed requests are appended to, one record each; unset keeps them in memory only.
+    pub path: Option<PathBuf>,
+    /// File size past which the file is moved to `<path>.1`, replacing the previous one.
+    pub max_bytes: u64,
+    /// Records buffered for the writer before new ones are dropped.
+    pub queue_capacity: usize,
+}
+
+impl Default for FlightRecorderConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            max_bytes: 64 * 1024 * 1024,
+            queue_capacity: 1024,
+        }
+    }
+}
+
+/// Where a listener is bound: a TCP `host:port`, with IPv6 addresses in brackets, or a Unix
+/// domain socket written `unix:/path/to.sock`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BindAddr {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl BindAddr {
+    /// Whether only clients on this host can connect: a loopback address or a Unix socket.
+    pub fn is_local(&self) -> bool {
+        match self {
+            BindAddr::Tcp(addr) => is_loopback_addr(addr),
+            BindAddr::Unix(_) => true,
+        }
+    }
+}
+
+impl FromStr for BindAddr {
+    type Err = String;
+
+    /// Anything without the `unix:` prefix is taken as a TCP address, checked by validation.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err(format!("invalid address {:?}, expected unix:/path/to.sock", s)),
+            Some(path) => Ok(BindAddr::Unix(PathBuf::from(path))),
+            None => Ok(BindAddr::Tcp(s.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for BindAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BindAddr::Tcp(addr) => f.write_str(addr),
+            BindAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Serialize for BindAddr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for BindAddr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Name of the listener bound to `addr` when no `listeners` are configured.
+pub const DEFAULT_LISTENER: &str = "default";
+/// Name of the listener bound to `tls.addr` when no `listeners` are configured.
+pub const TLS_LISTENER: &str = "tls";
+
+/// Certificate and private key served over TLS. The files are read at startup, and again on
+/// SIGHUP or when they change; their paths are read at startup only.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first, as Let's Encrypt's `fullchain.pem`.
+    pub cert: PathBuf,
+    /// PEM private key: PKCS#8, PKCS#1 or SEC1.
+    pub key: PathBuf,
+    /// Without `listeners`, binds an HTTPS listener here, next to the plain one on `addr`, and
+    /// with the same profile. With `listeners`, set `tls: true` on listeners instead.
+    pub addr: Option<String>,
+    /// How often the files are checked for changes; 0 reloads on SIGHUP only.
+    pub watch_interval_secs: u64,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert: PathBuf::new(),
+            key: PathBuf::new(),
+            addr: None,
+            watch_interval_secs: 60,
+        }
+    }
+}
+
+/// What a listener exposes. The listener bound to `addr` when no `listeners` are configured
+/// exposes everything, as [`ListenerConfig::unrestricted`].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ListenerConfig {
+    pub addr: BindAddr,
+    /// Auth mode of targets without their own `auth`, instead of the top-level `auth_mode`.
+    pub auth_mode: Option<AuthMode>,
+    /// Serves `/admin/*` and `/support-bundle`.
+    pub admin: bool,
+    /// Adds the debugging headers of `debug_headers` and the `debug_headers` flag.
+    pub debug_headers: bool,
+    /// Applies the targets' `max_concurrency` and `adaptive_concurrency` limits.
+    pub concurrency_limits: bool,
+    /// Keys of the targets served; empty serves every target and unmatched requests.
+    pub targets: Vec<String>,
+    /// Serves HTTPS with the `tls` certificate instead of plain HTTP.
+    pub tls: bool,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        Self {
+            addr: BindAddr::Tcp(String::new()),
+            auth_mode: None,
+            admin: false,
+            debug_headers: false,
+            concurrency_limits: true,
+            targets: Vec::new(),
+            tls: false,
+        }
+    }
+}
+
+impl ListenerConfig {
+    /// The profile of the listener bound to `addr`.
+    pub fn unrestricted() -> &'static ListenerConfig {
+        static UNRESTRICTED: OnceLock<ListenerConfig> = OnceLock::new();
+        UNRESTRICTED.get_or_init(|| ListenerConfig {
+            admin: true,
+            debug_headers: true,
+            ..Default::default()
+        })
+    }
+
+    /// Whether the target keyed `key` is served.
+    pub fn serves(&self, key: &str) -> bool {
+        self.targets.is_empty() || self.targets.iter().any(|target| target == key)
+    }
+}
+
+/// Format of an error body the gateway produces itself.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorFormat {
+    /// The standard JSON error body.
+    Json,
+    /// A page rendered from `error_pages.html_template`.
+    Html,
+    /// Text rendered from `error_pages.text_template`.
+    Text,
+}
+
+/// Templates of `html` and `text` error bodies, read when the config is loaded. Unset templates
+/// use the built-in ones.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ErrorPagesConfig {
+    /// HTML template file, relative to the main config file.
+    pub html_template: Option<PathBuf>,
+    /// Plain text template file, relative to the main config file.
+    pub text_template: Option<PathBuf>,
+    /// Template files larger than this fail loading.
+    pub max_template_bytes: u64,
+    /// Contents of `html_template`, once loaded.
+    #[serde(skip)]
+    pub html: Option<String>,
+    /// Contents of `text_template`, once loaded.
+    #[serde(skip)]
+    pub text: Option<String>,
+}
+
+impl Default for ErrorPagesConfig {
+    fn default() -> Self {
+        Self {
+            html_template: None,
+            text_template: None,
+            max_template_bytes: 64 * 1024,
+            html: None,
+            text: None,
+        }
+    }
+}
+
+/// Measures the offset of the gateway clock from the `Date` header of a reference server, at
+/// startup and every `interval_secs`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ClockConfig {
+    pub enabled: bool,
+    /// `http://` URL answering with a `Date` header; unset uses the Lambda endpoint.
+    pub source_url: Option<String>,
+    pub interval_secs: u64,
+    /// Budget for each measurement.
+    pub timeout_ms: u64,
+    /// Offset past which a warning is logged and `/status` reports the clock as skewed.
+    pub max_skew_ms: u64,
+    /// Weight of each new measurement in the smoothed offset, between 0 and 1.
+    pub smoothing: f64,
+    /// Applies the smoothed offset to the time-based checks, such as schedules.
+    pub correct: bool,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            source_url: None,
+            interval_secs: 300,
+            timeout_ms: 5000,
+            max_skew_ms: 2000,
+            smoothing: 0.3,
+            correct: false,
+        }
+    }
+}
+
+/// POSTs each lifecycle event as JSON to `url`, an `http://` URL, off the path of whatever
+/// published it.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Events waiting for delivery; further ones are dropped and counted.
+    pub queue_capacity: usize,
+    /// Retries after the first attempt, for connection errors and 5xx answers.
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each one after.
+    pub backoff_ms: u64,
+    /// Budget for each attempt.
+    pub timeout_ms: u64,
+}
+
+/// Where `config_source` fetches its targets document from, and how often.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ConfigSourceConfig {
+    #[serde(flatten)]
+    pub kind: ConfigSourceKind,
+    #[serde(default = "default_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+    /// Longest wait between fetches while they fail; the wait doubles from `poll_interval_secs`
+    /// with each failure.
+    #[serde(default = "default_max_backoff_secs")]
+    pub max_backoff_secs: u64,
+    /// Budget for each fetch.
+    #[serde(default = "default_fetch_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ConfigSourceKind {
+    /// `GET` an `http://` URL; needs the `http-config-source` feature.
+    Http(HttpSourceConfig),
+    /// Read an item of a DynamoDB table; needs the `dynamodb-config-source` feature.
+    Dynamodb(DynamoDbSourceConfig),
+}
+
+impl ConfigSourceKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConfigSourceKind::Http(_) => "http",
+            ConfigSourceKind::Dynamodb(_) => "dynamodb",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct HttpSourceConfig {
+    pub url: String,
+    /// Sends the `ETag` of the last document as `If-None-Match`, so an unchanged document is
+    /// answered `304` without a body.
+    pub etag_caching: bool,
+}
+
+impl Default for HttpSourceConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            etag_caching: true,
+        }
+    }
+}
+
+/// The item whose partition key `key_attribute` is `key` holds the document as a string in
+/// `document_attribute`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct DynamoDbSourceConfig {
+    pub table: String,
+    pub key: String,
+    pub key_attribute: String,
+    pub document_attribute: String,
+}
+
+impl Default for DynamoDbSourceConfig {
+    fn default() -> Self {
+        Self {
+            table: String::new(),
+            key: String::new(),
+            key_attribute: "id".to_string(),
+            document_attribute: "document".to_string(),
+        }
+    }
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            queue_capacity: 1000,
+            retries: 3,
+            backoff_ms: 500,
+            timeout_ms: 5000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct AccessLogConfig {
+    /// File to write access records to; unset disables the access log.
+    pub path: Option<PathBuf>,
+    pub rotation: Rotation,
+    /// File size that triggers a rotation with `rotation: Size`.
+    pub max_bytes: u64,
+    /// Number of rotated files to keep.
+    pub keep: usize,
+    pub fsync: FsyncPolicy,
+    pub fsync_interval_secs: u64,
+    /// Lines buffered for the writer before new ones are dropped.
+    pub queue_capacity: usize,
+    /// Also log every record as an event of the `access` log target, in the gateway's `log_format`.
+    pub events: bool,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            rotation: Rotation::Size,
+            max_bytes: 100 * 1024 * 1024,
+            keep: 5,
+            fsync: FsyncPolicy::Interval,
+            fsync_interval_secs: 5,
+            queue_capacity: 8192,
+            events: false,
+        }
+    }
+}
+
+/// Format of the lines the gateway logs to stdout.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of the event at the top level.
+    Json,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Rotation {
+    Size,
+    Hourly,
+    Daily,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Leave flushing to the OS.
+    Never,
+    /// Sync after every line.
+    Always,
+    /// Sync every `fsync_interval_secs`.
+    Interval,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            lambda_function_name: String::new(),
+            lambda_invoke_mode: default_lambda_invoke_mode(),
+            payload: PayloadMode::Alb,
+            api_keys: HashSet::new(),
+            trusted_body_encoding_keys: HashSet::new(),
+            admin_api_keys: HashSet::new(),
+            auth_mode: default_auth_mode(),
+            addr: default_addr(),
+            listeners: BTreeMap::new(),
+            tls: None,
+            allowed_hosts: Vec::new(),
+            telemetry: TelemetryConfig::default(),
+            metrics: MetricsConfig::default(),
+            concurrency: GlobalConcurrency::default(),
+            priority_weights: PriorityWeights::default(),
+            log_format: LogFormat::default(),
+            request_id: RequestIdConfig::default(),
+            tracing: TracingConfig::default(),
+            otel: OtelConfig::default(),
+            compression: CompressionConfig::default(),
+            access_log: AccessLogConfig::default(),
+            conflict_retry: ConflictRetryConfig::default(),
+            retry_after: RetryAfterConfig::default(),
+            event_retry: EventRetryConfig::default(),
+            health: HealthConfig::default(),
+            log_dedup: LogDedupConfig::default(),
+            feature_flags: BTreeMap::new(),
+            state_store: StateStoreConfig::default(),
+            spool: SpoolConfig::default(),
+            error_budget: ErrorBudgetConfig::default(),
+            server: ServerConfig::default(),
+            security: SecurityConfig::default(),
+            payload_buffers: PayloadBufferConfig::default(),
+            state_memory_budget_bytes: None,
+            reload_drain_timeout_ms: default_reload_drain_timeout_ms(),
+            lifecycle: LifecycleConfig::default(),
+            canonical_request: CanonicalRequestConfig::default(),
+            body_storage: BodyStorageConfig::default(),
+            flight_recorder: FlightRecorderConfig::default(),
+            clock: ClockConfig::default(),
+            error_pages: ErrorPagesConfig::default(),
+            function_error_status: default_function_error_status(),
+            max_request_body_bytes: default_max_request_body_bytes(),
+            expose_function_errors: false,
+            shutdown: None,
+            shutdown_grace_secs: None,
+            debug_headers: false,
+            client_context: false,
+            instance_id: None,
+            targets: BTreeMap::new(),
+            config_source: None,
+            signed_urls: None,
+            circuit_breaker_store: StoreKind::Memory,
+            rate_limit_store: StoreKind::Memory,
+            auth_bans: None,
+            root_target: None,
+            unmatched: UnmatchedRoute::default(),
+            namespaces: BTreeMap::new(),
+        }
+    }
+}
+
+/// The configuration in effect, replaced as a whole on reload. Readers take a snapshot and keep
+/// using it for the rest of their work. The fingerprint is computed once per installed config.
+#[derive(Clone, Debug)]
+pub struct SharedConfig(Arc<RwLock<(Arc<Config>, Arc<str>)>>);
+
+impl SharedConfig {
+    pub fn new(config: Config) -> Self {
+        let fingerprint = config.fingerprint().into();
+        Self(Arc::new(RwLock::new((Arc::new(config), fingerprint))))
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        self.0.read().unwrap().0.clone()
+    }
+
+    /// [`Config::fingerprint`] of the current configuration.
+    pub fn fingerprint(&self) -> Arc<str> {
+        self.0.read().unwrap().1.clone()
+    }
+
+    /// Installs `config`, returning the configuration it replaces.
+    pub fn replace(&self, config: Config) -> Arc<Config> {
+        let fingerprint = config.fingerprint().into();
+        std::mem::replace(&mut *self.0.write().unwrap(), (Arc::new(config), fingerprint)).0
+    }
+}
+
+/// Config built into the binary at compile time, used when no other source gives one.
+pub const EMBEDDED_CONFIG: &str = include_str!("default_config.yaml");
+
+/// Where the configuration document came from; see [`Config::load_document`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigSource {
+    /// The `CONFIG_YAML` environment variable.
+    Inline,
+    /// The config file.
+    File,
+    /// `TARGETS` on top of the built-in config.
+    Targets,
+    /// The built-in config alone.
+    Embedded,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Self {
+        Self::try_load(path).unwrap_or_else(|e| panic!("Invalid config: {}", e))
+    }
+
+    /// Loads and validates the configuration, as on startup, without panicking on invalid files.
+    pub fn try_load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let config = Self::load_unvalidated(path);
+        config.validate()?;
+        for warning in config.lint() {
+            tracing::warn!("Config warning: {}", warning);
+        }
+        Ok(config)
+    }
+
+    /// Loads the configuration and environment overrides without validating, for reporting on
+    /// configs that may be invalid. See [`Config::load_document`] for where it comes from.
+    pub fn load_unvalidated<P: AsRef<Path>>(path: P) -> Self {
+        let (mut config, source) = Self::load_document(path.as_ref(), &|name| std::env::var(name).ok());
+        config.apply_env_overrides();
+        if source == ConfigSource::Embedded && config.auth_mode == AuthMode::Open {
+            tracing::warn!(
+                "No config file, CONFIG_YAML or TARGETS found: serving every path from {} WITHOUT \
+                 AUTHENTICATION. Set AUTH_MODE=apikey and API_KEYS to require an API key.",
+                config.lambda_function_name
+            );
+        }
+        config
+    }
+
+    /// The config built into the binary: no targets, buffered invokes and open auth.
+    pub fn embedded() -> Self {
+        serde_yaml::from_str(EMBEDDED_CONFIG).expect("the embedded config is valid")
+    }
+
+    /// Reads the configuration document, before the environment overrides. The first of these
+    /// that is present is used:
+    ///
+    /// 1. `CONFIG_YAML`, a whole config document in an environment variable;
+    /// 2. the config file at `path`;
+    /// 3. the config built into the binary.
+    ///
+    /// `TARGETS`, a YAML or JSON map of targets, is then added to the document's targets, replacing
+    /// any with the same pattern. A document that cannot be read is logged and the built-in config
+    /// used instead.
+    fn load_document(path: &Path, env: &dyn Fn(&str) -> Option<String>) -> (Self, ConfigSource) {
+        let document = match env("CONFIG_YAML") {
+            Some(yaml) => Self::from_yaml(&yaml, Path::new("."))
+                .map(|config| (config, ConfigSource::Inline))
+                .map_err(|e| format!("CONFIG_YAML: {}", e)),
+            None if !path.exists() => Ok((Self::embedded(), ConfigSource::Embedded)),
+            None => Self::load_from_file(path)
+                .map(|config| (config, ConfigSource::File))
+                .map_err(|e| format!("{}: {}", path.display(), e)),
+        };
+        let (mut config, mut source) = document.unwrap_or_else(|e| {
+            tracing::warn!("Failed to load config from {}. Using default values.", e);
+            (Self::embedded(), ConfigSource::Embedded)
+        });
+        if let Some(targets) = env("TARGETS") {
+            match serde_yaml::from_str::<BTreeMap<String, Target>>(&targets) {
+                Ok(targets) => {
+                    config.targets.extend(targets);
+                    if source == ConfigSource::Embedded {
+                        source = ConfigSource::Targets;
+                    }
+                }
+                Err(e) => tracing::warn!("Ignoring TARGETS, which is not a map of targets: {}", e),
+            }
+        }
+        (config, source)
+    }
+
+    /// Checks the settings that cannot be expressed through types, reporting every problem found.
+    pub fn validate(&self) -> Result<(), String> {
+        let errors = self.validation_errors();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+
+    /// The problems [`Config::validate`] reports, one per entry.
+    pub fn validation_errors(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if let Some(root_target) = &self.root_target {
+            if !self.targets.contains_key(root_target) {
+                errors.push(format!(
+                    "root_target {} is not a configured target, so the root path is unreachable",
+                    root_target
+                ));
+            } else if root_target != "/" && self.targets.contains_key("/") {
+                errors.push(format!(
+                    "root_target {} conflicts with the target keyed /; configure only one of them",
+                    root_target
+                ));
+            }
+        }
+
+        let mut prefixes: Vec<(&str, &str)> = Vec::new();
+        for (name, namespace) in &self.namespaces {
+            let prefix = namespace.prefix.as_str();
+            if !prefix.starts_with('/') || prefix.len() < 2 || prefix.ends_with('/') || prefix.contains('*') {
+                errors.push(format!(
+                    "namespace {}: prefix {:?} must start with '/' and have no trailing '/' or wildcard",
+                    name, prefix
+                ));
+                continue;
+            }
+            for (other, other_prefix) in &prefixes {
+                if paths_overlap(prefix, other_prefix) {
+                    errors.push(format!(
+                        "namespace {}: prefix {} collides with prefix {} of namespace {}",
+                        name, prefix, other_prefix, other
+                    ));
+                }
+            }
+            prefixes.push((name, prefix));
+            if let Some(rate_limit) = &namespace.rate_limit {
+                if !(rate_limit.requests_per_second > 0.0 && rate_limit.requests_per_second.is_finite()) {
+                    errors.push(format!(
+                        "namespace {}: rate_limit.requests_per_second must be above 0",
+                        name
+                    ));
+                }
+                if rate_limit.burst == Some(0) {
+                    errors.push(format!("namespace {}: rate_limit.burst must be at least 1", name));
+                }
+            }
+
+            for pattern in namespace.targets.keys() {
+                let mounted = mount_pattern(prefix, pattern);
+                match self.targets.get(&mounted).and_then(|t| t.namespace.as_deref()) {
+                    Some(owner) if owner == name => {}
+                    Some(owner) => errors.push(format!(
+                        "namespace {}: target {} collides with namespace {}",
+                        name, mounted, owner
+                    )),
+                    None => errors.push(format!(
+                        "namespace {}: target {} collides with a top-level target",
+                        name, mounted
+                    )),
+                }
+            }
+        }
+
+        for (pattern, target) in &self.targets {
+            if target.function.as_ref().is_some_and(|f| f.members().is_empty()) {
+                errors.push(format!("target {}: function list is empty", pattern));
+            }
+            if target.max_concurrency == Some(0) {
+                errors.push(format!("target {}: max_concurrency must be at least 1", pattern));
+            }
+            if target.overflow == Some(Overflow::Queue) && target.max_queue_depth == 0 {
+                errors.push(format!(
+                    "target {}: overflow: queue needs a max_queue_depth of at least 1",
+                    pattern
+                ));
+            }
+            if target.queue_timeout_ms == Some(0) {
+                errors.push(format!("target {}: queue_timeout_ms must be at least 1", pattern));
+            }
+            if target.max_request_body_bytes == Some(0) {
+                errors.push(format!("target {}: max_request_body_bytes must be at least 1", pattern));
+            }
+            for rule in &target.priority_rules {
+                if let Some(e) = rule.when.error() {
+                    errors.push(format!(
+                        "target {}: priority condition {:?}: {}",
+                        pattern,
+                        rule.when.as_str(),
+                        e
+                    ));
+                }
+            }
+            if let Some(adaptive) = &target.adaptive_concurrency {
+                if target.max_concurrency.is_some() {
+                    errors.push(format!(
+                        "target {}: set either max_concurrency or adaptive_concurrency, not both",
+                        pattern
+                    ));
+                }
+                if adaptive.min_limit == 0 || adaptive.min_limit > adaptive.max_limit {
+                    errors.push(format!(
+                        "target {}: adaptive_concurrency needs 1 <= min_limit <= max_limit",
+                        pattern
+                    ));
+                }
+                if adaptive.window == 0 {
+                    errors.push(format!(
+                        "target {}: adaptive_concurrency.window must be at least 1",
+                        pattern
+                    ));
+                }
+                if !(adaptive.tolerance >= 1.0 && adaptive.tolerance.is_finite()) {
+                    errors.push(format!(
+                        "target {}: adaptive_concurrency.tolerance must be at least 1",
+                        pattern
+                    ));
+                }
+                if !(adaptive.backoff > 0.0 && adaptive.backoff < 1.0) {
+                    errors.push(format!(
+                        "target {}: adaptive_concurrency.backoff must be between 0 and 1",
+                        pattern
+                    ));
+                }
+            }
+            if target.retries.as_ref().is_some_and(|retries| retries.max_attempts == 0) {
+                errors.push(format!("target {}: retries.max_attempts must be at least 1", pattern));
+            }
+            if target.accept_signed_urls && self.signed_urls.is_none() {
+                errors.push(format!(
+                    "target {}: accept_signed_urls needs a signed_urls section",
+                    pattern
+                ));
+            }
+            if let Some(ndjson) = &target.ndjson {
+                if ndjson.max_record_bytes == 0 {
+                    errors.push(format!(
+                        "target {}: ndjson.max_record_bytes must be at least 1",
+                        pattern
+                    ));
+                }
+                if let Some(e) = ndjson.filter.as_ref().and_then(|filter| filter.error()) {
+                    errors.push(format!("target {}: ndjson.filter: {}", pattern, e));
+                }
+            }
+            if let Some(breaker) = &target.circuit_breaker {
+                if breaker.failure_threshold == 0 || breaker.window_secs == 0 || breaker.open_secs == 0 {
+                    errors.push(format!(
+                        "target {}: circuit_breaker failure_threshold, window_secs and open_secs must be at least 1",
+                        pattern
+                    ));
+                }
+            }
+            if let Some(shadow) = &target.shadow {
+                if shadow.function.is_empty() {
+                    errors.push(format!("target {}: shadow.function must be set", pattern));
+                }
+                if !(0.0..=100.0).contains(&shadow.sample_percent) {
+                    errors.push(format!(
+                        "target {}: shadow.sample_percent must be between 0 and 100",
+                        pattern
+                    ));
+                }
+                if let Some(qualifier) = shadow.qualifier.as_deref().filter(|q| !valid_qualifier(q)) {
+                    errors.push(format!(
+                        "target {}: shadow.qualifier {:?} is not a valid alias or version",
+                        pattern, qualifier
+                    ));
+                }
+                if shadow.timeout_ms == 0 {
+                    errors.push(format!("target {}: shadow.timeout_ms must be at least 1", pattern));
+                }
+                for name in &shadow.headers {
+                    if axum::http::HeaderName::try_from(name.as_str()).is_err() {
+                        errors.push(format!(
+                            "target {}: shadow header {:?} is not a valid header name",
+                            pattern, name
+                        ));
+                    }
+                }
+                for pointer in shadow
+                    .ignore_pointers
+                    .iter()
+                    .filter(|pointer| !pointer.starts_with('/'))
+                {
+                    errors.push(format!(
+                        "target {}: shadow ignore pointer {:?} must start with /",
+                        pattern, pointer
+                    ));
+                }
+            }
+            if target.slo.is_some_and(|slo| !(slo > 0.0 && slo < 100.0)) {
+                errors.push(format!(
+                    "target {}: slo must be a percentage between 0 and 100",
+                    pattern
+                ));
+            }
+            if let Some(rewrite) = &target.cookie_rewrite {
+                let prefix = rewrite.path_prefix.as_deref().unwrap_or("/");
+                if !prefix.starts_with('/') || prefix.contains([';', ',']) || prefix.contains(char::is_whitespace) {
+                    errors.push(format!(
+                        "target {}: cookie_rewrite.path_prefix {:?} must start with '/' and hold no ';', ',' or spaces",
+                        pattern, prefix
+                    ));
+                }
+                match &rewrite.domain {
+                    Some(_) if rewrite.strip_domain => errors.push(format!(
+                        "target {}: cookie_rewrite sets both domain and strip_domain; use only one",
+                        pattern
+                    )),
+                    Some(domain)
+                        if domain.is_empty() || domain.contains([';', ',']) || domain.contains(char::is_whitespace) =>
+                    {
+                        errors.push(format!(
+                            "target {}: cookie_rewrite.domain {:?} is not a domain",
+                            pattern, domain
+                        ))
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(host) = &target.host {
+                if let Err(e) = crate::hosts::validate_pattern(host) {
+                    errors.push(format!("target {}: host {}", pattern, e));
+                }
+            }
+            match target.qualifier.as_deref() {
+                Some("") => errors.push(format!("target {}: qualifier must not be empty", pattern)),
+                Some(qualifier) if !valid_qualifier(qualifier) => errors.push(format!(
+                    "target {}: qualifier {:?} is not a valid alias or version",
+                    pattern, qualifier
+                )),
+                _ => {}
+            }
+            if let Some(methods) = &target.methods {
+                if methods.is_empty() {
+                    errors.push(format!("target {}: methods must list at least one method", pattern));
+                }
+                for method in methods {
+                    if !KNOWN_METHODS.iter().any(|known| known.eq_ignore_ascii_case(method)) {
+                        errors.push(format!("target {}: unknown method {:?} in methods", pattern, method));
+                    }
+                }
+            }
+            if target.strip_prefix && literal_prefix_segments(pattern) == 0 {
+                errors.push(format!(
+                    "target {}: strip_prefix needs a pattern starting with a literal segment",
+                    pattern
+                ));
+            }
+            if let Some(rewrite) = &target.rewrite {
+                if rewrite.from.as_str().is_empty() {
+                    errors.push(format!("target {}: rewrite.from is required", pattern));
+                } else if rewrite.is_regex() {
+                    if let Err(e) = rewrite.from.compiled() {
+                        errors.push(format!(
+                            "target {}: rewrite.from {:?}: {}",
+                            pattern,
+                            rewrite.from.as_str(),
+                            e
+                        ));
+                    }
+                }
+            }
+            if (target.strip_prefix || target.rewrite.is_some())
+                && axum::http::HeaderName::try_from(target.original_path_header.as_str()).is_err()
+            {
+                errors.push(format!(
+                    "target {}: original_path_header {:?} is not a valid header name",
+                    pattern, target.original_path_header
+                ));
+            }
+            if let Some(body_match) = &target.body_match {
+                errors.extend(self.check_body_match(pattern, body_match));
+            }
+            if let Some(schedule) = &target.schedule {
+                if schedule.windows.is_empty() {
+                    errors.push(format!("target {}: schedule has no windows", pattern));
+                }
+                for window in &schedule.windows {
+                    if window.days.is_empty() || window.start == window.end {
+                        errors.push(format!(
+                            "target {}: schedule windows need days and different start and end times",
+                            pattern
+                        ));
+                    }
+                }
+            }
+        }
+        if self.unmatched == UnmatchedRoute::NotFound && self.targets.is_empty() {
+            errors.push("unmatched is not_found but no targets are configured, so every request is 404".to_string());
+        }
+        if let Some(shutdown) = &self.shutdown {
+            let mut seen = HashSet::new();
+            for phase in &shutdown.phases {
+                if !seen.insert(phase) {
+                    errors.push(format!("shutdown: phase {} is listed more than once", phase.as_str()));
+                }
+            }
+        }
+        if self.server.max_header_bytes < 8192 {
+            errors.push("server: max_header_bytes must be at least 8192".to_string());
+        }
+        if self.server.max_concurrent_streams == 0 {
+            errors.push("server: max_concurrent_streams must be at least 1".to_string());
+        }
+        let budget = &self.error_budget;
+        if budget.short_window_secs == 0 || budget.short_window_secs >= budget.long_window_secs {
+            errors.push("error_budget: short_window_secs must be at least 1 and below long_window_secs".to_string());
+        }
+        if !(budget.slow_burn_rate > 0.0 && budget.slow_burn_rate <= budget.fast_burn_rate) {
+            errors.push("error_budget: slow_burn_rate must be positive and at most fast_burn_rate".to_string());
+        }
+        if let Some(webhook) = &self.lifecycle.webhook {
+            let url = url::Url::parse(&webhook.url);
+            if !url.is_ok_and(|url| url.scheme() == "http" && url.host().is_some()) {
+                errors.push(format!(
+                    "lifecycle.webhook: url {:?} must be an absolute http:// URL",
+                    webhook.url
+                ));
+            }
+            if webhook.queue_capacity == 0 {
+                errors.push("lifecycle.webhook: queue_capacity must be at least 1".to_string());
+            }
+        }
+        if let Some(signed_urls) = &self.signed_urls {
+            if signed_urls.signing_key.len() < 32 {
+                errors.push("signed_urls: signing_key must be at least 32 bytes".to_string());
+            }
+            if signed_urls.default_ttl_secs == 0 || signed_urls.default_ttl_secs > signed_urls.max_ttl_secs {
+                errors.push("signed_urls: default_ttl_secs must be between 1 and max_ttl_secs".to_string());
+            }
+        }
+        if let Some(bans) = &self.auth_bans {
+            if bans.max_failures == 0 || bans.window_secs == 0 || bans.ban_secs == 0 {
+                errors.push("auth_bans: max_failures, window_secs and ban_secs must be above 0".to_string());
+            }
+        }
+        if let Some(source) = &self.config_source {
+            match &source.kind {
+                ConfigSourceKind::Http(http) => {
+                    let url = url::Url::parse(&http.url);
+                    if !url.is_ok_and(|url| url.scheme() == "http" && url.host().is_some()) {
+                        errors.push(format!(
+                            "config_source: url {:?} must be an absolute http:// URL",
+                            http.url
+                        ));
+                    }
+                }
+                ConfigSourceKind::Dynamodb(dynamodb) => {
+                    if dynamodb.table.is_empty() || dynamodb.key.is_empty() {
+                        errors.push("config_source: table and key are required".to_string());
+                    }
+                }
+            }
+            if source.poll_interval_secs == 0 || source.max_backoff_secs < source.poll_interval_secs {
+                errors.push(
+                    "config_source: poll_interval_secs must be at least 1 and at most max_backoff_secs".to_string(),
+                );
+            }
+        }
+        if let Err(e) = crate::body_store::storage(&self.body_storage) {
+            errors.push(format!("body_storage: {}", e));
+        }
+        if let Some(source_url) = &self.clock.source_url {
+            let url = url::Url::parse(source_url);
+            if !url.is_ok_and(|url| url.scheme() == "http" && url.host().is_some()) {
+                errors.push(format!(
+                    "clock: source_url {:?} must be an absolute http:// URL",
+                    source_url
+                ));
+            }
+        }
+        if !(400..600).contains(&self.function_error_status) {
+            errors.push("function_error_status must be a 4xx or 5xx status".to_string());
+        }
+        if self.max_request_body_bytes == 0 {
+            errors.push("max_request_body_bytes must be at least 1".to_string());
+        }
+        if self.clock.interval_secs == 0 {
+            errors.push("clock: interval_secs must be at least 1".to_string());
+        }
+        if !(self.clock.smoothing > 0.0 && self.clock.smoothing <= 1.0) {
+            errors.push("clock: smoothing must be above 0 and at most 1".to_string());
+        }
+        let mut bound = HashSet::new();
+        if self.listeners.is_empty() {
+            if let Err(e) = check_bind_addr(&self.addr) {
+                errors.push(e);
+            }
+        }
+        for (name, listener) in &self.listeners {
+            if let Err(e) = check_bind_addr(&listener.addr) {
+                errors.push(format!("listener {}: {}", name, e));
+            } else if !bound.insert(&listener.addr) {
+                errors.push(format!(
+                    "listener {}: addr {} is bound by another listener",
+                    name, listener.addr
+                ));
+            }
+            for key in listener.targets.iter().filter(|key| !self.targets.contains_key(*key)) {
+                errors.push(format!("listener {}: {} is not a configured target", name, key));
+            }
+            if listener.tls && self.tls.is_none() {
+                errors.push(format!(
+                    "listener {}: tls needs the tls section with cert and key",
+                    name
+                ));
+            }
+        }
+        if let Some(tls) = &self.tls {
+            match (&tls.addr, self.listeners.is_empty()) {
+                (Some(_), false) => errors
+                    .push("tls: addr is for use without listeners; set tls: true on listeners instead".to_string()),
+                (Some(addr), true) if !is_host_port(addr) => {
+                    errors.push(format!("tls: addr {:?} must be host:port, with IPv6 in brackets", addr))
+                }
+                (Some(addr), true) if self.addr == BindAddr::Tcp(addr.clone()) => {
+                    errors.push(format!("tls: addr {} is bound by the plain listener", addr))
+                }
+                (None, true) => errors.push("tls: addr must be set to serve HTTPS without listeners".to_string()),
+                _ => {}
+            }
+            #[cfg(feature = "tls")]
+            if let Err(e) = crate::tls::load(tls) {
+                errors.push(format!("tls: {}", e));
+            }
+            #[cfg(not(feature = "tls"))]
+            errors.push("tls: needs a build with the tls feature".to_string());
+        }
+        if let Some(addr) = self.metrics.addr.as_deref().filter(|_| self.metrics.enabled) {
+            if !is_host_port(addr) {
+                errors.push(format!(
+                    "metrics: addr {:?} must be host:port, with IPv6 in brackets",
+                    addr
+                ));
+            } else if self
+                .listener_addrs()
+                .iter()
+                .any(|(_, bound)| *bound == BindAddr::Tcp(addr.to_string()))
+            {
+                errors.push(format!("metrics: addr {} is bound by a listener", addr));
+            }
+        }
+        if self.concurrency.max_concurrency == Some(0) {
+            errors.push("concurrency: max_concurrency must be at least 1".to_string());
+        }
+        if self.concurrency.queue_timeout_ms == Some(0) {
+            errors.push("concurrency: queue_timeout_ms must be at least 1".to_string());
+        }
+        for class in PriorityClass::ALL {
+            if self.priority_weights.weight(class) == 0 {
+                errors.push(format!(
+                    "priority_weights: {} must be at least 1, or its requests could wait forever",
+                    class.as_str()
+                ));
+            }
+        }
+        if let Some(endpoint) = &self.otel.endpoint {
+            let scheme = url::Url::parse(endpoint).map(|url| url.scheme().to_string());
+            if !matches!(scheme.as_deref(), Ok("http" | "https")) {
+                errors.push(format!(
+                    "otel: endpoint {:?} must be an http:// or https:// URL",
+                    endpoint
+                ));
+            }
+        }
+        if self.otel.service_name.is_empty() {
+            errors.push("otel: service_name must not be empty".to_string());
+        }
+        if self.otel.timeout_ms == 0 {
+            errors.push("otel: timeout_ms must be at least 1".to_string());
+        }
+        for content_type in &self.compression.types {
+            let (kind, subtype) = content_type.split_once('/').unwrap_or_default();
+            if kind.is_empty() || kind == "*" || subtype.is_empty() || subtype.contains('/') {
+                errors.push(format!(
+                    "compression: type {:?} must be type/subtype or type/*",
+                    content_type
+                ));
+            }
+        }
+        if axum::http::HeaderName::try_from(self.request_id.header.as_str()).is_err() {
+            errors.push(format!(
+                "request_id: {:?} is not a valid header name",
+                self.request_id.header
+            ));
+        }
+        if self.flight_recorder.queue_capacity == 0 {
+            errors.push("flight_recorder: queue_capacity must be at least 1".to_string());
+        }
+        for name in &self.canonical_request.headers {
+            if axum::http::HeaderName::try_from(name.as_str()).is_err() {
+                errors.push(format!("canonical_request: {:?} is not a valid header name", name));
+            }
+        }
+        errors.extend(
+            self.allowed_hosts
+                .iter()
+                .filter_map(|pattern| crate::hosts::validate_pattern(pattern).err())
+                .map(|e| format!("allowed_hosts: {}", e)),
+        );
+        errors.extend(
+            self.feature_flags
+                .iter()
+                .filter_map(|(name, flag)| crate::flags::validate(name, flag).err()),
+        );
+        errors.extend(RouteRegistry::new(self).errors().map(|e| e.message.clone()));
+        if let Err(e) = self.check_public_bind() {
+            errors.push(e);
+        }
+        errors
+    }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct Config {
-    pub lambda_function_name: String,
-    #[serde(default = "default_lambda_invoke_mode")]
-    pub lambda_invoke_mode: LambdaInvokeMode,
-    #[serde(default)]
-    pub api_keys: HashSet<String>,
-    #[serde(default = "default_auth_mode")]
-    pub auth_mode: AuthMode,
-    #[serde(default = "default_addr")]
-    pub addr: String,
-}
+    /// Settings that are valid but probably not what was meant, such as routes shadowed by
+    /// built-in ones. Logged at startup; they do not stop the gateway.
+    pub fn lint(&self) -> Vec<String> {
+        let mut warnings: Vec<String> = RouteRegistry::new(self).warnings().map(|w| w.message.clone()).collect();
+        if self.shutdown.is_some() && self.shutdown_grace_secs.is_some() {
+            warnings.push("shutdown_grace_secs is ignored because shutdown is set".to_string());
+        }
+        warnings
+    }
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            lambda_function_name: String::new(),
-            lambda_invoke_mode: default_lambda_invoke_mode(),
-            api_keys: HashSet::new(),
-            auth_mode: default_auth_mode(),
-            addr: default_addr(),
+    /// Checks the `match` of the target at `pattern` against the targets it names.
+    fn check_body_match(&self, pattern: &str, body_match: &BodyMatch) -> Vec<String> {
+        let mut errors = Vec::new();
+        if body_match.fallback.is_empty() {
+            errors.push(format!("target {}: match.fallback is required", pattern));
+        }
+        let conditions = body_match.conditions.iter().map(|rule| &rule.target);
+        let targets = conditions.chain(body_match.body_json.iter().map(|rule| &rule.target));
+        for name in targets.chain([&body_match.fallback]).filter(|name| !name.is_empty()) {
+            match self.targets.get(name) {
+                None => errors.push(format!("target {}: match names unknown target {}", pattern, name)),
+                Some(target) if target.body_match.is_some() => errors.push(format!(
+                    "target {}: match names target {}, which has a match of its own",
+                    pattern, name
+                )),
+                Some(_) => {}
+            }
+        }
+        for rule in &body_match.conditions {
+            if let Some(e) = rule.when.error() {
+                errors.push(format!(
+                    "target {}: match condition {:?}: {}",
+                    pattern,
+                    rule.when.as_str(),
+                    e
+                ));
+            }
+        }
+        for rule in &body_match.body_json {
+            if !rule.pointer.is_empty() && !rule.pointer.starts_with('/') {
+                errors.push(format!(
+                    "target {}: match pointer {:?} must be empty or start with '/'",
+                    pattern, rule.pointer
+                ));
+            }
+            if rule.equals.is_some() == rule.regex.is_some() {
+                errors.push(format!(
+                    "target {}: match rule for {} needs exactly one of equals and regex",
+                    pattern, rule.pointer
+                ));
+            }
+            if let Some(regex) = &rule.regex {
+                if let Err(e) = regex.compiled() {
+                    errors.push(format!("target {}: match regex {:?}: {}", pattern, regex.as_str(), e));
+                }
+            }
         }
+        errors
     }
-}
 
-impl Config {
-    pub fn load<P: AsRef<Path>>(path: P) -> Self {
-        let mut config = Self::load_from_file(path).unwrap_or_else(|e| {
-            tracing::warn!("Failed to load config from file: {}. Using default values.", e);
-            Config::default()
-        });
-        config.apply_env_overrides();
+    /// Enforces `security.require_auth_on_public_bind`, naming every target that would serve
+    /// unauthenticated requests on a public address. Checked after namespaces are mounted, so their
+    /// default auth modes are taken into account.
+    fn check_public_bind(&self) -> Result<(), String> {
+        let security = &self.security;
+        if !security.require_auth_on_public_bind || security.acknowledge_open || !self.allowed_hosts.is_empty() {
+            return Ok(());
+        }
+        let listeners: Vec<(BindAddr, &ListenerConfig)> = if self.listeners.is_empty() {
+            let tls = self.tls.as_ref().and_then(|tls| tls.addr.clone()).map(BindAddr::Tcp);
+            std::iter::once(self.addr.clone())
+                .chain(tls)
+                .map(|addr| (addr, ListenerConfig::unrestricted()))
+                .collect()
+        } else {
+            self.listeners
+                .values()
+                .map(|listener| (listener.addr.clone(), listener))
+                .collect()
+        };
+        let mut errors = Vec::new();
+        for (addr, listener) in listeners.into_iter().filter(|(addr, _)| !addr.is_local()) {
+            // Paths no target matches are served by the top-level function with the default auth
+            let mut open: Vec<&str> = Vec::new();
+            let default_auth = listener.auth_mode.as_ref().unwrap_or(&self.auth_mode);
+            if self.unmatched == UnmatchedRoute::Fallback
+                && listener.targets.is_empty()
+                && *default_auth == AuthMode::Open
+            {
+                open.push("the top-level function");
+            }
+            open.extend(
+                self.targets
+                    .iter()
+                    .filter(|(key, target)| {
+                        listener.serves(key) && self.auth_mode_on(target, listener) == AuthMode::Open
+                    })
+                    .map(|(pattern, _)| pattern.as_str()),
+            );
+            if !open.is_empty() {
+                errors.push(format!(
+                    "security: {} is not a loopback address and no allowed_hosts are set, but {} allow \
+                     unauthenticated requests; use auth_mode ApiKey, set allowed_hosts, or set \
+                     security.acknowledge_open for an intentionally public endpoint",
+                    addr,
+                    open.join(", ")
+                ));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+
+    /// Fills inline namespace settings from their `file`s.
+    fn load_namespace_files(&mut self, base_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        for (name, namespace) in self.namespaces.iter_mut() {
+            if namespace.file.is_some() {
+                namespace.source = Some(NamespaceSource {
+                    inline: Box::new(namespace.clone()),
+                    base_dir: base_dir.to_path_buf(),
+                });
+                merge_namespace_file(name, namespace, base_dir)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The configuration with the `file` of namespace `name` read again and merged into its
+    /// settings from the main config as loaded. The files of other namespaces are not read, so
+    /// one team's broken or missing file cannot hold up another's reload.
+    pub fn reload_namespace(&self, name: &str) -> Result<Config, String> {
+        let namespace = self
+            .namespaces
+            .get(name)
+            .ok_or_else(|| format!("namespace {}: not configured", name))?;
+        let Some(source) = &namespace.source else {
+            return Err(format!("namespace {}: has no file to reload", name));
+        };
+        let mut reloaded = (*source.inline).clone();
+        merge_namespace_file(name, &mut reloaded, &source.base_dir)?;
+        reloaded.source = Some(source.clone());
+
+        let mut config = self.clone();
         config
+            .targets
+            .retain(|_, target| target.namespace.as_deref() != Some(name));
+        config.namespaces.insert(name.to_string(), reloaded);
+        config.mount_namespaces();
+        // Only the reloaded namespace changed, and the running config passed these checks
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reads the `error_pages` templates, refusing files over `max_template_bytes`.
+    fn load_error_templates(&mut self, base_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let pages = &mut self.error_pages;
+        let max = pages.max_template_bytes;
+        let read = |file: &Option<PathBuf>| -> Result<Option<String>, String> {
+            let Some(file) = file else {
+                return Ok(None);
+            };
+            let path = base_dir.join(file);
+            let failed = |e: std::io::Error| format!("error_pages: failed to read {}: {}", file.display(), e);
+            let len = fs::metadata(&path).map_err(failed)?.len();
+            if len > max {
+                return Err(format!(
+                    "error_pages: {} is {} bytes, over max_template_bytes of {}",
+                    file.display(),
+                    len,
+                    max
+                ));
+            }
+            fs::read_to_string(&path).map(Some).map_err(failed)
+        };
+        pages.html = read(&pages.html_template)?;
+        pages.text = read(&pages.text_template)?;
+        Ok(())
+    }
+
+    /// Adds each namespace's targets to `targets` under the namespace prefix, applying the
+    /// namespace defaults. Collisions keep the existing target and are reported by `validate`.
+    pub(crate) fn mount_namespaces(&mut self) {
+        for (name, namespace) in &self.namespaces {
+            for (pattern, target) in &namespace.targets {
+                let mut target = target.clone();
+                target.namespace = Some(name.clone());
+                target.auth = target.auth.or_else(|| namespace.auth_mode.clone());
+                target.api_keys = target.api_keys.or_else(|| namespace.api_keys.clone());
+                self.targets
+                    .entry(mount_pattern(&namespace.prefix, pattern))
+                    .or_insert(target);
+            }
+        }
     }
 
     fn apply_env_overrides(&mut self) {
-        if let Ok(val) = std::env::var("LAMBDA_FUNCTION_NAME") {
+        self.apply_env_overrides_from(&|name| std::env::var(name).ok());
+    }
+
+    /// Applies the environment variables that override single settings. `ADDR` wins over `PORT`,
+    /// which listens on every interface.
+    fn apply_env_overrides_from(&mut self, env: &dyn Fn(&str) -> Option<String>) {
+        if let Some(val) = env("LAMBDA_FUNCTION_NAME") {
             self.lambda_function_name = val;
         }
-        if self.lambda_function_name.is_empty() {
-            panic!("No lambda_function_name provided. Please set it in the config file or LAMBDA_FUNCTION_NAME environment variable.");
+        if self.lambda_function_name.is_empty() && self.needs_top_level_function() {
+            panic!("No lambda_function_name provided. Please set it in the config file or LAMBDA_FUNCTION_NAME environment variable, or give every target a function and set unmatched: not_found.");
         }
-        if let Ok(val) = std::env::var("LAMBDA_INVOKE_MODE") {
+        if let Some(val) = env("LAMBDA_INVOKE_MODE") {
             if let Ok(mode) = val.parse() {
                 self.lambda_invoke_mode = mode;
             }
         }
-        if let Ok(val) = std::env::var("API_KEYS") {
+        if let Some(val) = env("API_KEYS") {
             self.api_keys = val.split(',').filter(|s| !s.is_empty()).map(String::from).collect();
         }
-        if let Ok(val) = std::env::var("AUTH_MODE") {
+        if let Some(val) = env("AUTH_MODE") {
             if let Ok(mode) = val.parse() {
                 self.auth_mode = mode;
             }
         }
-        if let Ok(val) = std::env::var("ADDR") {
-            self.addr = val;
+        if let Some(val) = env("PORT") {
+            self.addr = BindAddr::Tcp(format!("0.0.0.0:{}", val));
+        }
+        if let Some(val) = env("ADDR") {
+            if let Ok(addr) = val.parse() {
+                self.addr = addr;
+            }
+        }
+        if let Some(val) = env("INSTANCE_ID") {
+            self.instance_id = Some(val);
+        }
+    }
+
+    /// Finds the target serving `path`. Returns the matched pattern alongside the target.
+    pub fn match_target(&self, path: &str) -> Option<(&str, &Target)> {
+        self.match_route(path).map(|(pattern, target, _)| (pattern, target))
+    }
+
+    /// Finds the target serving `path` for a request without a host, among targets without a `host`.
+    pub fn match_route(&self, path: &str) -> Option<(&str, &Target, RouteRule)> {
+        self.match_route_on_host(None, path)
+    }
+
+    /// Finds the target serving `path` on `host` and the rule that selected it. Targets whose `host`
+    /// matches are tried first, the most specific host first, then targets without a `host`. Among
+    /// targets of one host, the root path is served by `root_target` or a target whose pattern is
+    /// `/`; any other path by an exact pattern first, then the pattern with the most literal
+    /// segments, preferring `:param` patterns over wildcards.
+    pub fn match_route_on_host(&self, host: Option<&str>, path: &str) -> Option<(&str, &Target, RouteRule)> {
+        self.match_route_on_listener(ListenerConfig::unrestricted(), host, path)
+    }
+
+    /// [`Config::match_route_on_host`] among the targets `listener` serves.
+    pub fn match_route_on_listener(
+        &self,
+        listener: &ListenerConfig,
+        host: Option<&str>,
+        path: &str,
+    ) -> Option<(&str, &Target, RouteRule)> {
+        let mut hosts: Vec<&str> = self
+            .targets
+            .iter()
+            .filter(|(key, _)| listener.serves(key))
+            .filter_map(|(_, target)| target.host.as_deref())
+            .filter(|pattern| host.is_some_and(|host| crate::hosts::matches(pattern, host)))
+            .collect();
+        hosts.sort_by_key(|pattern| std::cmp::Reverse((crate::hosts::specificity(pattern), *pattern)));
+        hosts.dedup();
+        hosts
+            .into_iter()
+            .map(Some)
+            .chain([None])
+            .find_map(|host| self.match_route_among(listener, host, path))
+    }
+
+    /// [`Config::match_route_on_listener`] among the targets whose `host` is exactly `host`.
+    fn match_route_among(
+        &self,
+        listener: &ListenerConfig,
+        host: Option<&str>,
+        path: &str,
+    ) -> Option<(&str, &Target, RouteRule)> {
+        let targets = || {
+            self.targets
+                .iter()
+                .filter(|(key, _)| listener.serves(key))
+                .filter(move |(_, target)| target.host.as_deref() == host)
+                .filter_map(|(key, target)| Some((key.as_str(), path_pattern(key)?, target)))
+        };
+        if path == "/" {
+            let root_target = self
+                .root_target
+                .as_ref()
+                .filter(|key| listener.serves(key))
+                .and_then(|key| self.targets.get_key_value(key));
+            if let Some((key, target)) = root_target.filter(|(_, target)| target.host.as_deref() == host) {
+                return Some((key.as_str(), target, RouteRule::RootTarget));
+            }
+        }
+        let decoded = decode_segment(path);
+        if let Some((key, _, target)) = targets().find(|(_, pattern, _)| *pattern == decoded) {
+            return Some((key, target, RouteRule::Exact));
+        }
+        if path == "/" {
+            return None;
+        }
+        targets()
+            .filter(|(_, pattern, _)| pattern.contains("/:") || pattern.contains("/*"))
+            .filter(|(_, pattern, _)| match_pattern(pattern, path).is_some())
+            .max_by_key(|(_, pattern, _)| pattern_rank(pattern))
+            .map(|(key, pattern, target)| {
+                let rule = if pattern.contains("/*") {
+                    RouteRule::Wildcard
+                } else {
+                    RouteRule::Param
+                };
+                (key, target, rule)
+            })
+    }
+
+    /// Whether some request may invoke `lambda_function_name`: unmatched requests fall back to it, or
+    /// a target names no function of its own.
+    pub fn needs_top_level_function(&self) -> bool {
+        self.unmatched == UnmatchedRoute::Fallback || self.targets.values().any(|target| target.function.is_none())
+    }
+
+    /// Function name a target invokes; the first member when `function` is a list.
+    pub fn function_name<'a>(&'a self, target: &'a Target) -> &'a str {
+        self.function_members(target)
+            .first()
+            .map_or(&self.lambda_function_name, String::as_str)
+    }
+
+    /// Functions a target spreads its requests over.
+    pub fn function_members<'a>(&'a self, target: &'a Target) -> &'a [String] {
+        match &target.function {
+            Some(function) => function.members(),
+            None => std::slice::from_ref(&self.lambda_function_name),
+        }
+    }
+
+    /// Auth mode a target uses.
+    pub fn auth_mode(&self, target: &Target) -> AuthMode {
+        self.auth_mode_on(target, ListenerConfig::unrestricted())
+    }
+
+    /// Auth mode a target uses on `listener`, whose default replaces the top-level one.
+    pub fn auth_mode_on(&self, target: &Target, listener: &ListenerConfig) -> AuthMode {
+        target
+            .auth
+            .clone()
+            .or_else(|| listener.auth_mode.clone())
+            .unwrap_or_else(|| self.auth_mode.clone())
+    }
+
+    /// The profile of the listener named `name`; unrestricted for the listener bound to `addr`. A
+    /// listener a reload removed keeps serving until restart with the defaults, without admin.
+    pub fn listener(&self, name: Option<&str>) -> &ListenerConfig {
+        static REMOVED: OnceLock<ListenerConfig> = OnceLock::new();
+        if self.listeners.is_empty() {
+            return ListenerConfig::unrestricted();
+        }
+        name.and_then(|name| self.listeners.get(name))
+            .unwrap_or_else(|| REMOVED.get_or_init(ListenerConfig::default))
+    }
+
+    /// Name and address of each listener to bind: the configured ones, else `default` on `addr`.
+    pub fn listener_addrs(&self) -> Vec<(String, BindAddr)> {
+        if self.listeners.is_empty() {
+            let mut addrs = vec![(DEFAULT_LISTENER.to_string(), self.addr.clone())];
+            if let Some(addr) = self.tls.as_ref().and_then(|tls| tls.addr.clone()) {
+                addrs.push((TLS_LISTENER.to_string(), BindAddr::Tcp(addr)));
+            }
+            return addrs;
+        }
+        self.listeners
+            .iter()
+            .map(|(name, listener)| (name.clone(), listener.addr.clone()))
+            .collect()
+    }
+
+    /// Whether the listener `name` serves HTTPS.
+    pub fn listener_tls(&self, name: &str) -> bool {
+        if self.listeners.is_empty() {
+            return name == TLS_LISTENER && self.tls.as_ref().is_some_and(|tls| tls.addr.is_some());
+        }
+        self.listeners.get(name).is_some_and(|listener| listener.tls)
+    }
+
+    /// Largest request body `target` accepts.
+    pub fn request_body_limit(&self, target: &Target) -> usize {
+        target.max_request_body_bytes.unwrap_or(self.max_request_body_bytes)
+    }
+
+    /// Largest request body read for a `target` with `match` whose conditions did not pick a
+    /// target: the largest limit of it and of the targets its `body_json` rules and `fallback` name.
+    pub fn body_match_limit(&self, target: &Target, body_match: &BodyMatch) -> usize {
+        let names = body_match.body_json.iter().map(|rule| &rule.target);
+        names
+            .chain([&body_match.fallback])
+            .filter_map(|name| self.targets.get(name))
+            .map(|target| self.request_body_limit(target))
+            .fold(self.request_body_limit(target), usize::max)
+    }
+
+    /// API keys a target accepts.
+    pub fn api_keys<'a>(&'a self, target: &'a Target) -> &'a HashSet<String> {
+        target.api_keys.as_ref().unwrap_or(&self.api_keys)
+    }
+
+    /// Event shape a target's function is invoked with.
+    pub fn payload_mode(&self, target: &Target) -> PayloadMode {
+        target.payload.unwrap_or(self.payload)
+    }
+
+    /// Shutdown phases in effect: `shutdown`, or else the defaults with `shutdown_grace_secs` as the
+    /// deadline of the drain phases and the final checkpoint, as before phases existed.
+    pub fn shutdown_config(&self) -> ShutdownConfig {
+        if let Some(shutdown) = &self.shutdown {
+            return shutdown.clone();
+        }
+        let mut shutdown = ShutdownConfig::default();
+        if let Some(grace_secs) = self.shutdown_grace_secs {
+            let grace_ms = grace_secs * 1000;
+            shutdown.drain_buffered_ms = grace_ms;
+            shutdown.drain_streams_ms = grace_ms;
+            shutdown.flush_state_ms = grace_ms;
         }
+        shutdown
+    }
+
+    /// Invoke mode a target uses.
+    pub fn invoke_mode(&self, target: &Target) -> LambdaInvokeMode {
+        target.invoke.clone().unwrap_or_else(|| self.lambda_invoke_mode.clone())
+    }
+
+    /// A short hash of the effective config that replicas running the same settings agree on: the
+    /// first 16 hex digits of the SHA-256 of its JSON form. Object keys are serialized in sorted
+    /// order, so neither the order of keys in the file nor the order targets were loaded in
+    /// changes it. Secrets are redacted first, as in the support bundle; rotating a key alone
+    /// leaves the fingerprint unchanged.
+    pub fn fingerprint(&self) -> String {
+        // `serde_json` objects are ordered maps unless its `preserve_order` feature is enabled
+        let canonical = crate::support::redacted_config(self).to_string();
+        let mut fingerprint = format!("{:x}", Sha256::digest(canonical.as_bytes()));
+        fingerprint.truncate(16);
+        fingerprint
     }
 
     pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
-        let contents = fs::read_to_string(path)?;
-        let config: Config = serde_yaml::from_str(&contents)?;
+        let contents = fs::read_to_string(&path)?;
+        Self::from_yaml(&contents, path.as_ref().parent().unwrap_or(Path::new(".")))
+    }
+
+    /// Parses a config document, reading namespace files relative to `base_dir`.
+    fn from_yaml(contents: &str, base_dir: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut config: Config = serde_yaml::from_str(contents)?;
+        config.load_namespace_files(base_dir)?;
+        config.load_error_templates(base_dir)?;
+        config.mount_namespaces();
         Ok(config)
     }
 }
 
+/// Whether a listen address is an IP address and port, as `0.0.0.0:8000` or `[::]:8000`, or a
+/// host name and port. An IPv6 address without brackets, as `::1:8000`, is neither.
+fn is_host_port(addr: &str) -> bool {
+    addr.parse::<SocketAddr>().is_ok()
+        || addr
+            .rsplit_once(':')
+            .is_some_and(|(host, port)| !host.is_empty() && !host.contains(':') && port.parse::<u16>().is_ok())
+}
+
+/// Checks a listener's address: a TCP one must be host:port, and Unix sockets need a Unix platform.
+fn check_bind_addr(addr: &BindAddr) -> Result<(), String> {
+    match addr {
+        BindAddr::Tcp(host_port) if !is_host_port(host_port) => Err(format!(
+            "addr {:?} must be host:port, with IPv6 in brackets, or unix:/path/to.sock",
+            host_port
+        )),
+        #[cfg(not(unix))]
+        BindAddr::Unix(_) => Err(format!("addr {} needs a platform with Unix sockets", addr)),
+        _ => Ok(()),
+    }
+}
+
+/// Whether a listen address only accepts connections from this host.
+fn is_loopback_addr(addr: &str) -> bool {
+    match addr.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip().to_canonical().is_loopback(),
+        Err(_) => addr.rsplit_once(':').is_some_and(|(host, _)| host == "localhost"),
+    }
+}
+
+/// Fills the settings of `namespace` that it leaves unset from its `file`, read relative to
+/// `base_dir`.
+fn merge_namespace_file(name: &str, namespace: &mut Namespace, base_dir: &Path) -> Result<(), String> {
+    let Some(file) = &namespace.file else {
+        return Ok(());
+    };
+    let contents = fs::read_to_string(base_dir.join(file))
+        .map_err(|e| format!("namespace {}: failed to read {}: {}", name, file.display(), e))?;
+    let from_file: Namespace = serde_yaml::from_str(&contents)
+        .map_err(|e| format!("namespace {}: failed to parse {}: {}", name, file.display(), e))?;
+    if namespace.prefix.is_empty() {
+        namespace.prefix = from_file.prefix;
+    }
+    namespace.auth_mode = namespace.auth_mode.take().or(from_file.auth_mode);
+    namespace.api_keys = namespace.api_keys.take().or(from_file.api_keys);
+    namespace.rate_limit = namespace.rate_limit.take().or(from_file.rate_limit);
+    for (pattern, target) in from_file.targets {
+        namespace.targets.entry(pattern).or_insert(target);
+    }
+    Ok(())
+}
+
+/// Key of a namespace target once its path pattern is mounted under the namespace prefix. A label
+/// ahead of the pattern stays in front.
+pub fn mount_pattern(prefix: &str, pattern: &str) -> String {
+    let (label, path) = match pattern.find('/') {
+        Some(i) if i > 0 => pattern.split_at(i),
+        _ => ("", pattern),
+    };
+    match path {
+        "/" => format!("{}{}", label, prefix),
+        _ => format!("{}{}{}", label, prefix, path),
+    }
+}
+
+/// Number of literal segments a target key's path pattern starts with, before any `:param` or
+/// `*rest` segment: 2 for `/svc/orders/*rest`, 0 for `/` and `/*rest`.
+pub fn literal_prefix_segments(key: &str) -> usize {
+    let Some(pattern) = path_pattern(key) else {
+        return 0;
+    };
+    pattern
+        .split('/')
+        .skip(1)
+        .take_while(|segment| !segment.is_empty() && !segment.starts_with([':', '*']))
+        .count()
+}
+
+/// Path pattern of a target key: the key from its first `/` on, so that targets for different
+/// hosts can share a pattern under keys such as `foo/*rest` and `bar/*rest`. Keys without a `/`
+/// have none and are never matched by path.
+pub fn path_pattern(key: &str) -> Option<&str> {
+    key.find('/').map(|i| &key[i..])
+}
+
+/// Named captures of a matched route, percent-decoded.
+pub type PathParams = BTreeMap<String, String>;
+
+/// Matches `path` against a pattern made of literal, `:name` and trailing `*name` segments,
+/// returning the captures. A `:name` capture is one non-empty segment; a `*name` capture is the
+/// rest of the path, which may span several segments. Segments are percent-decoded one at a time,
+/// so an encoded `/` stays inside its capture.
+pub fn match_pattern(pattern: &str, path: &str) -> Option<PathParams> {
+    let mut params = PathParams::new();
+    let mut rest = Some(path.strip_prefix('/')?);
+    for part in pattern.strip_prefix('/')?.split('/') {
+        if let Some(name) = part.strip_prefix('*') {
+            params.insert(name.to_string(), decode_segment(rest?));
+            return Some(params);
+        }
+        let (segment, next) = match rest?.split_once('/') {
+            Some((segment, next)) => (segment, Some(next)),
+            None => (rest?, None),
+        };
+        rest = next;
+        if let Some(name) = part.strip_prefix(':') {
+            if segment.is_empty() {
+                return None;
+            }
+            params.insert(name.to_string(), decode_segment(segment));
+        } else if decode_segment(segment) != part {
+            return None;
+        }
+    }
+    rest.is_none().then_some(params)
+}
+
+fn decode_segment(segment: &str) -> String {
+    percent_encoding::percent_decode_str(segment)
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+/// Orders patterns matching the same path: those without a wildcard first, then by number of
+/// literal segments, then by length.
+pub(crate) fn pattern_rank(pattern: &str) -> (bool, usize, usize) {
+    let literals = pattern
+        .split('/')
+        .filter(|part| !part.starts_with(':') && !part.starts_with('*'))
+        .count();
+    (!pattern.contains("/*"), literals, pattern.len())
+}
+
+/// Whether one path prefix equals or contains the other at a segment boundary.
+fn paths_overlap(a: &str, b: &str) -> bool {
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    long.strip_prefix(short)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
 #[cfg(test)]
 mod tests {
     include!("config_tests.rs");
 }
 
+fn default_function_error_status() -> u16 {
+    502
+}
+
+fn default_max_request_body_bytes() -> usize {
+    6 * 1024 * 1024
+}
+
+fn default_poll_interval_secs() -> u64 {
+    30
+}
+
+fn default_max_backoff_secs() -> u64 {
+    600
+}
+
+fn default_fetch_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_reload_drain_timeout_ms() -> u64 {
+    60_000
+}
+
 fn default_auth_mode() -> AuthMode {
     AuthMode::Open
 }
@@ -84,34 +3430,228 @@ fn default_lambda_invoke_mode() -> LambdaInvokeMode {
     LambdaInvokeMode::Buffered
 }
 
-fn default_addr() -> String {
-    "0.0.0.0:8000".to_string()
+fn default_addr() -> BindAddr {
+    BindAddr::Tcp("0.0.0.0:8000".to_string())
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
-pub enum AuthMode {
-    Open,
-    ApiKey,
+/// Routing of a target's requests by conditions on the request and by their JSON body. The first
+/// of `conditions` that holds, else the first rule of `body_json` that matches, picks the target
+/// serving the request; requests nothing matches, including bodies that are not JSON or larger
+/// than `max_body_bytes`, go to `fallback`. The chosen target is used as if the path had matched
+/// it, with its own function, auth and settings.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct BodyMatch {
+    pub conditions: Vec<ConditionRule>,
+    pub body_json: Vec<BodyJsonRule>,
+    /// Target serving requests that match no rule; required.
+    pub fallback: String,
+    /// Larger bodies are not parsed and go to `fallback`.
+    pub max_body_bytes: usize,
 }
 
-impl Default for AuthMode {
+impl Default for BodyMatch {
     fn default() -> Self {
-        AuthMode::Open
+        Self {
+            conditions: Vec::new(),
+            body_json: Vec::new(),
+            fallback: String::new(),
+            max_body_bytes: 64 * 1024,
+        }
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
-pub enum LambdaInvokeMode {
-    Buffered,
-    ResponseStream,
+/// Picks `target` for requests meeting `when`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ConditionRule {
+    pub when: Condition,
+    pub target: String,
 }
 
-impl Default for LambdaInvokeMode {
-    fn default() -> Self {
-        LambdaInvokeMode::Buffered
+/// Matches the value at a JSON pointer, such as `/type`, either exactly or, for strings, by a
+/// regex.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct BodyJsonRule {
+    pub pointer: String,
+    #[serde(default)]
+    pub equals: Option<serde_json::Value>,
+    #[serde(default)]
+    pub regex: Option<LazyRegex>,
+    /// Target serving the requests this rule matches.
+    pub target: String,
+}
+
+/// Rewrite of a forwarded path. A `from` starting with `^` is a regex whose first match is
+/// replaced by `to`, which may refer to captures as `$1` or `$name`; any other `from` is a literal
+/// prefix replaced by `to`. Paths `from` does not match are forwarded unchanged.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PathRewrite {
+    pub from: LazyRegex,
+    pub to: String,
+}
+
+impl PathRewrite {
+    pub fn is_regex(&self) -> bool {
+        self.from.as_str().starts_with('^')
+    }
+}
+
+/// A regex kept as written in the config and compiled on first use.
+#[derive(Clone, Debug, Default)]
+pub struct LazyRegex {
+    source: String,
+    compiled: Arc<OnceLock<Result<Regex, regex::Error>>>,
+}
+
+impl LazyRegex {
+    pub fn new(source: &str) -> Self {
+        Self {
+            source: source.to_string(),
+            compiled: Arc::default(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    pub fn compiled(&self) -> Result<&Regex, &regex::Error> {
+        self.compiled.get_or_init(|| Regex::new(&self.source)).as_ref()
+    }
+}
+
+impl PartialEq for LazyRegex {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Serialize for LazyRegex {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for LazyRegex {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(|source| LazyRegex::new(&source))
+    }
+}
+
+/// A condition in the language of [`Expr`], compiled when the config is read. Validation reports
+/// conditions that do not compile, which never hold.
+#[derive(Clone, Debug)]
+pub struct Condition {
+    source: String,
+    compiled: Result<Arc<Expr>, CompileError>,
+}
+
+impl Condition {
+    pub fn new(source: &str) -> Self {
+        Self {
+            source: source.to_string(),
+            compiled: Expr::compile(source).map(Arc::new),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    pub fn error(&self) -> Option<&CompileError> {
+        self.compiled.as_ref().err()
+    }
+
+    pub fn holds(&self, attributes: &Attributes) -> bool {
+        self.compiled.as_ref().is_ok_and(|expr| expr.eval(attributes))
+    }
+}
+
+impl PartialEq for Condition {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for Condition {}
+
+impl Serialize for Condition {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Condition {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(|source| Condition::new(&source))
+    }
+}
+
+/// Handling of requests whose path matches no target.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnmatchedRoute {
+    /// The top-level `lambda_function_name` serves them.
+    #[default]
+    Fallback,
+    /// They are answered 404 without invoking anything.
+    NotFound,
+}
+
+/// Which routing rule selected the target for a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteRule {
+    /// The path equals a target's pattern.
+    Exact,
+    /// The root path, served by `root_target`.
+    RootTarget,
+    /// The most specific pattern with `:param` segments.
+    Param,
+    /// The most specific matching wildcard pattern.
+    Wildcard,
+    /// No target matched; the top-level function serves the request.
+    Fallback,
+    /// One of the `conditions` of the matched target's `match` picked the target.
+    Condition,
+    /// A `body_json` rule of the matched target's `match` picked the target.
+    BodyJson,
+    /// The matched target has a `match` but no rule matched the body, so its `fallback` serves.
+    BodyFallback,
+}
+
+impl RouteRule {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RouteRule::Exact => "exact",
+            RouteRule::RootTarget => "root_target",
+            RouteRule::Param => "param",
+            RouteRule::Wildcard => "wildcard",
+            RouteRule::Fallback => "fallback",
+            RouteRule::Condition => "condition",
+            RouteRule::BodyJson => "body_json",
+            RouteRule::BodyFallback => "body_fallback",
+        }
     }
 }
 
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum AuthMode {
+    #[default]
+    Open,
+    ApiKey,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum LambdaInvokeMode {
+    #[default]
+    Buffered,
+    ResponseStream,
+    /// Asynchronous invoke: the client is answered `202 Accepted` once Lambda has queued the event,
+    /// without waiting for the function.
+    Event,
+}
+
 impl FromStr for AuthMode {
     type Err = String;
 
@@ -128,9 +3668,11 @@ impl FromStr for LambdaInvokeMode {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_lowercase().as_str() {
+        // `response_stream` and `response-stream` read as `ResponseStream`
+        match s.to_lowercase().replace(['_', '-'], "").as_str() {
             "buffered" => Ok(LambdaInvokeMode::Buffered),
             "responsestream" => Ok(LambdaInvokeMode::ResponseStream),
+            "event" => Ok(LambdaInvokeMode::Event),
             _ => Err(format!("Invalid LambdaInvokeMode: {}", s)),
         }
     }
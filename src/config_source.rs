@@ -0,0 +1,385 @@
+use crate::config::{Config, ConfigSourceConfig, ConfigSourceKind, Target};
+use crate::telemetry::Telemetry;
+use crate::ApplicationState;
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// What a fetch found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fetched {
+    /// The document has not changed since the last fetch.
+    Unchanged,
+    Document(String),
+}
+
+/// Where the targets document is read from.
+pub trait ConfigFetcher: Send + Sync + 'static {
+    fn fetch(&self) -> BoxFuture<'static, Result<Fetched, String>>;
+}
+
+/// State of the config source, as shown on `/status`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ConfigSourceStatus {
+    /// Time of the last successful fetch, changed or not.
+    pub fetched_at: Option<DateTime<Utc>>,
+    /// Seconds since the last successful fetch, or since startup before the first.
+    pub staleness_secs: u64,
+    /// Patterns of the targets the source adds, from the last good document.
+    pub targets: Vec<String>,
+    pub consecutive_failures: u32,
+    /// Error of the latest fetch, cleared by the next successful one.
+    pub last_error: Option<String>,
+}
+
+/// The targets fetched from `config_source` and the config file settings they are added to. The
+/// document is a YAML or JSON map of targets, like `TARGETS`, validated with the file's settings
+/// before it is applied. The file stays the base: a new document replaces the targets of the one
+/// before, never the file's, and a reload of the file keeps the last good document's targets.
+#[derive(Clone)]
+pub struct ConfigSourceState {
+    inner: Arc<Mutex<State>>,
+}
+
+struct State {
+    base: Config,
+    /// The last document that validated, and its targets.
+    document: Option<(String, BTreeMap<String, Target>)>,
+    fetched_at: Option<DateTime<Utc>>,
+    last_success: Instant,
+    consecutive_failures: u32,
+    last_error: Option<String>,
+    /// Staleness last reported to the gauge, which only takes deltas.
+    reported_staleness_secs: i64,
+}
+
+impl ConfigSourceState {
+    pub fn new(base: &Config) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(State {
+                base: base.clone(),
+                document: None,
+                fetched_at: None,
+                last_success: Instant::now(),
+                consecutive_failures: 0,
+                last_error: None,
+                reported_staleness_secs: 0,
+            })),
+        }
+    }
+
+    /// Makes `config`, freshly loaded from the file, the base and returns it with the last good
+    /// document's targets. Targets that no longer validate with it are dropped, and the next
+    /// fetch tries the document again.
+    pub fn rebase(&self, config: Config) -> Config {
+        let mut state = self.inner.lock().unwrap();
+        state.base = config.clone();
+        let Some((_, targets)) = &state.document else {
+            return config;
+        };
+        match compose(&config, targets.clone()) {
+            Ok(composed) => composed,
+            Err(e) => {
+                tracing::warn!(
+                    "Dropping the fetched targets, they are invalid with the reloaded config: {}",
+                    e
+                );
+                state.document = None;
+                config
+            }
+        }
+    }
+
+    /// Validates `document` against the base config, returning the config to switch to, or
+    /// `None` when it is the document already applied.
+    pub fn apply(&self, document: String) -> Result<Option<Config>, String> {
+        let mut state = self.inner.lock().unwrap();
+        if state.document.as_ref().is_some_and(|(applied, _)| *applied == document) {
+            return Ok(None);
+        }
+        let targets: BTreeMap<String, Target> =
+            serde_yaml::from_str(&document).map_err(|e| format!("not a map of targets: {}", e))?;
+        let config = compose(&state.base, targets.clone())?;
+        state.document = Some((document, targets));
+        Ok(Some(config))
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.inner.lock().unwrap();
+        state.fetched_at = Some(Utc::now());
+        state.last_success = Instant::now();
+        state.consecutive_failures = 0;
+        state.last_error = None;
+    }
+
+    /// Records a failed fetch, returning how many have failed in a row.
+    pub fn record_failure(&self, error: String) -> u32 {
+        let mut state = self.inner.lock().unwrap();
+        state.consecutive_failures += 1;
+        state.last_error = Some(error);
+        state.consecutive_failures
+    }
+
+    /// Sets `config_source_staleness_seconds` to the time since the last successful fetch.
+    pub fn report_staleness(&self, telemetry: &Telemetry) {
+        let mut state = self.inner.lock().unwrap();
+        let staleness = state.last_success.elapsed().as_secs() as i64;
+        let delta = staleness - state.reported_staleness_secs;
+        state.reported_staleness_secs = staleness;
+        if delta != 0 {
+            telemetry.gauge("config_source_staleness_seconds", vec![], delta);
+        }
+    }
+
+    pub fn status(&self) -> ConfigSourceStatus {
+        let state = self.inner.lock().unwrap();
+        ConfigSourceStatus {
+            fetched_at: state.fetched_at,
+            staleness_secs: state.last_success.elapsed().as_secs(),
+            targets: state
+                .document
+                .iter()
+                .flat_map(|(_, targets)| targets.keys().cloned())
+                .collect(),
+            consecutive_failures: state.consecutive_failures,
+            last_error: state.last_error.clone(),
+        }
+    }
+}
+
+/// `base` with `targets` added, replacing any with the same pattern, if it validates.
+pub fn compose(base: &Config, targets: BTreeMap<String, Target>) -> Result<Config, String> {
+    let mut config = base.clone();
+    config.targets.extend(targets);
+    config.validate()?;
+    for warning in config.lint() {
+        tracing::warn!("Config source warning: {}", warning);
+    }
+    Ok(config)
+}
+
+/// Wait before the next fetch: `poll_interval_secs`, doubled for each failure in a row, up to
+/// `max_backoff_secs`.
+pub fn next_delay(config: &ConfigSourceConfig, failures: u32) -> Duration {
+    let factor = 1u64.checked_shl(failures).unwrap_or(u64::MAX);
+    let secs = config
+        .poll_interval_secs
+        .saturating_mul(factor)
+        .min(config.max_backoff_secs);
+    Duration::from_secs(secs)
+}
+
+/// Fetches once within `timeout_ms`, switching to the new targets when the document changed and
+/// validates. Returns the wait before the next fetch.
+pub async fn poll(state: &ApplicationState, fetcher: &dyn ConfigFetcher, config: &ConfigSourceConfig) -> Duration {
+    let source = &state.config_source;
+    let timeout = Duration::from_millis(config.timeout_ms);
+    let result = match tokio::time::timeout(timeout, fetcher.fetch()).await {
+        Ok(Ok(Fetched::Unchanged)) => Ok(None),
+        Ok(Ok(Fetched::Document(document))) => source.apply(document),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(format!("timed out after {:?}", timeout)),
+    };
+    let delay = match result {
+        Ok(changed) => {
+            source.record_success();
+            if let Some(new_config) = changed {
+                tracing::info!(
+                    source = config.kind.as_str(),
+                    "Applying the targets fetched from the config source"
+                );
+                state.reload(new_config);
+            }
+            Duration::from_secs(config.poll_interval_secs)
+        }
+        Err(error) => {
+            let failures = source.record_failure(error.clone());
+            state.telemetry.increment(
+                "config_source_failures_total",
+                vec![("source", config.kind.as_str().to_string())],
+            );
+            let delay = next_delay(config, failures);
+            tracing::warn!(%error, failures, ?delay, "Keeping the last good targets, the config source failed");
+            delay
+        }
+    };
+    source.report_staleness(&state.telemetry);
+    delay
+}
+
+/// Fetches from `config` now and then after each [`poll`]'s delay.
+pub fn spawn(state: ApplicationState, config: ConfigSourceConfig) {
+    tokio::spawn(async move {
+        let fetcher = match fetcher(&config.kind).await {
+            Ok(fetcher) => fetcher,
+            Err(e) => {
+                tracing::error!(
+                    "The config source is unavailable, serving the config file's targets only: {}",
+                    e
+                );
+                return;
+            }
+        };
+        loop {
+            let delay = poll(&state, fetcher.as_ref(), &config).await;
+            tokio::time::sleep(delay).await;
+        }
+    });
+}
+
+/// The fetcher for `kind`, if the gateway was built with its feature.
+pub async fn fetcher(kind: &ConfigSourceKind) -> Result<Arc<dyn ConfigFetcher>, String> {
+    match kind {
+        #[cfg(feature = "http-config-source")]
+        ConfigSourceKind::Http(http) => {
+            let url = url::Url::parse(&http.url).map_err(|e| e.to_string())?;
+            Ok(Arc::new(HttpFetcher::new(url, http.etag_caching)))
+        }
+        #[cfg(feature = "dynamodb-config-source")]
+        ConfigSourceKind::Dynamodb(dynamodb) => {
+            let sdk_config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+            Ok(Arc::new(DynamoDbFetcher::new(&sdk_config, dynamodb)))
+        }
+        #[allow(unreachable_patterns)]
+        kind => Err(format!(
+            "type {} needs the {}-config-source feature",
+            kind.as_str(),
+            kind.as_str()
+        )),
+    }
+}
+
+/// Sends `GET` to an `http://` URL, which answers the document with `200`. With ETag caching, the
+/// last `ETag` is sent as `If-None-Match` and a `304` means the document is unchanged.
+#[cfg(feature = "http-config-source")]
+pub struct HttpFetcher {
+    url: url::Url,
+    etag_caching: bool,
+    etag: Arc<Mutex<Option<String>>>,
+}
+
+#[cfg(feature = "http-config-source")]
+impl HttpFetcher {
+    pub fn new(url: url::Url, etag_caching: bool) -> Self {
+        Self {
+            url,
+            etag_caching,
+            etag: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+#[cfg(feature = "http-config-source")]
+impl ConfigFetcher for HttpFetcher {
+    fn fetch(&self) -> BoxFuture<'static, Result<Fetched, String>> {
+        use axum::http::header::{ETAG, HOST, IF_NONE_MATCH};
+        use axum::http::{Request, StatusCode};
+        use http_body_util::{BodyExt, Empty};
+        use hyper_util::rt::TokioIo;
+
+        let url = self.url.clone();
+        let etag = self.etag.clone();
+        let etag_caching = self.etag_caching;
+        Box::pin(async move {
+            let host = url.host_str().unwrap_or_default();
+            let port = url.port_or_known_default().unwrap_or(80);
+            let stream = tokio::net::TcpStream::connect((host, port))
+                .await
+                .map_err(|e| e.to_string())?;
+            let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+                .await
+                .map_err(|e| e.to_string())?;
+            tokio::spawn(connection);
+            let authority = match url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_string(),
+            };
+            let path = match url.query() {
+                Some(query) => format!("{}?{}", url.path(), query),
+                None => url.path().to_string(),
+            };
+            let mut request = Request::get(path).header(HOST, authority);
+            let cached = etag.lock().unwrap().clone().filter(|_| etag_caching);
+            if let Some(cached) = &cached {
+                request = request.header(IF_NONE_MATCH, cached);
+            }
+            let request = request
+                .body(Empty::<axum::body::Bytes>::new())
+                .map_err(|e| e.to_string())?;
+            let response = sender.send_request(request).await.map_err(|e| e.to_string())?;
+            match response.status() {
+                StatusCode::NOT_MODIFIED if cached.is_some() => return Ok(Fetched::Unchanged),
+                StatusCode::OK => {}
+                status => return Err(format!("{} answered {}", url, status)),
+            }
+            let new_etag = response
+                .headers()
+                .get(ETAG)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string);
+            let body = response.into_body().collect().await.map_err(|e| e.to_string())?;
+            let document = String::from_utf8(body.to_bytes().to_vec())
+                .map_err(|_| format!("{} sent a document that is not UTF-8", url))?;
+            *etag.lock().unwrap() = new_etag;
+            Ok(Fetched::Document(document))
+        })
+    }
+}
+
+/// Reads the document attribute of one DynamoDB item with a consistent read.
+#[cfg(feature = "dynamodb-config-source")]
+pub struct DynamoDbFetcher {
+    client: aws_sdk_dynamodb::Client,
+    config: crate::config::DynamoDbSourceConfig,
+}
+
+#[cfg(feature = "dynamodb-config-source")]
+impl DynamoDbFetcher {
+    pub fn new(sdk_config: &aws_config::SdkConfig, config: &crate::config::DynamoDbSourceConfig) -> Self {
+        Self {
+            client: aws_sdk_dynamodb::Client::new(sdk_config),
+            config: config.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "dynamodb-config-source")]
+impl ConfigFetcher for DynamoDbFetcher {
+    fn fetch(&self) -> BoxFuture<'static, Result<Fetched, String>> {
+        use aws_sdk_dynamodb::error::DisplayErrorContext;
+        use aws_sdk_dynamodb::types::AttributeValue;
+
+        let send = self
+            .client
+            .get_item()
+            .table_name(&self.config.table)
+            .key(&self.config.key_attribute, AttributeValue::S(self.config.key.clone()))
+            .consistent_read(true)
+            .send();
+        let config = self.config.clone();
+        Box::pin(async move {
+            let output = send.await.map_err(|e| DisplayErrorContext(e).to_string())?;
+            let item = output.item().ok_or_else(|| {
+                format!(
+                    "{} has no item with {} {:?}",
+                    config.table, config.key_attribute, config.key
+                )
+            })?;
+            match item.get(&config.document_attribute) {
+                Some(AttributeValue::S(document)) => Ok(Fetched::Document(document.clone())),
+                _ => Err(format!(
+                    "the item of {} has no string attribute {}",
+                    config.table, config.document_attribute
+                )),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("config_source_tests.rs");
+}
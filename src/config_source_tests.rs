@@ -0,0 +1,180 @@
+use super::*;
+use crate::config::HttpSourceConfig;
+use crate::mock::{test_state_with, MockInvoker};
+
+fn source_config(url: String) -> ConfigSourceConfig {
+    ConfigSourceConfig {
+        kind: ConfigSourceKind::Http(HttpSourceConfig {
+            url,
+            etag_caching: true,
+        }),
+        poll_interval_secs: 10,
+        max_backoff_secs: 60,
+        timeout_ms: 1000,
+    }
+}
+
+fn file_config() -> Config {
+    Config {
+        targets: BTreeMap::from([("/file".to_string(), Target::default())]),
+        ..Default::default()
+    }
+}
+
+/// A control plane serving whatever document is set, with an `ETag` of its revision. Counts the
+/// requests answered `304`.
+#[cfg(feature = "http-config-source")]
+async fn control_plane() -> (String, Arc<Mutex<(u32, String)>>, Arc<std::sync::atomic::AtomicUsize>) {
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::response::IntoResponse;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let document = Arc::new(Mutex::new((0, String::new())));
+    let not_modified = Arc::new(AtomicUsize::new(0));
+    let app = axum::Router::new().route(
+        "/targets",
+        axum::routing::get({
+            let (document, not_modified) = (document.clone(), not_modified.clone());
+            move |headers: HeaderMap| async move {
+                let (rev, body) = document.lock().unwrap().clone();
+                let etag = format!("\"{}\"", rev);
+                if headers.get("if-none-match").is_some_and(|v| *v == *etag) {
+                    not_modified.fetch_add(1, Ordering::SeqCst);
+                    return StatusCode::NOT_MODIFIED.into_response();
+                }
+                ([("etag", etag)], body).into_response()
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/targets?env=test", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    (url, document, not_modified)
+}
+
+#[cfg(feature = "http-config-source")]
+fn publish(document: &Mutex<(u32, String)>, body: &str) {
+    let mut document = document.lock().unwrap();
+    document.0 += 1;
+    document.1 = body.to_string();
+}
+
+#[test]
+fn test_next_delay_backs_off_to_max() {
+    let config = source_config(String::new());
+    let delays: Vec<u64> = (0..5).map(|failures| next_delay(&config, failures).as_secs()).collect();
+    assert_eq!(delays, [10, 20, 40, 60, 60]);
+    assert_eq!(next_delay(&config, 100).as_secs(), 60);
+}
+
+#[test]
+fn test_apply_validates_against_base() {
+    let source = ConfigSourceState::new(&file_config());
+    let config = source.apply(r#"{"/orders": {"function": "orders"}}"#.to_string()).unwrap().unwrap();
+    assert_eq!(config.targets.keys().collect::<Vec<_>>(), ["/file", "/orders"]);
+    // The same document again changes nothing
+    assert_eq!(source.apply(r#"{"/orders": {"function": "orders"}}"#.to_string()).unwrap(), None);
+
+    let e = source.apply("/orders: [1, 2]".to_string()).unwrap_err();
+    assert!(e.starts_with("not a map of targets"), "{}", e);
+    let e = source
+        .apply("/orders:\n  circuit_breaker:\n    failure_threshold: 0\n".to_string())
+        .unwrap_err();
+    assert!(e.contains("target /orders"), "{}", e);
+    assert_eq!(source.status().targets, ["/orders"]);
+}
+
+#[test]
+fn test_rebase_keeps_fetched_targets() {
+    let source = ConfigSourceState::new(&file_config());
+    source.apply("/orders: {}".to_string()).unwrap();
+    let reloaded = Config {
+        targets: BTreeMap::from([("/reloaded".to_string(), Target::default())]),
+        ..Default::default()
+    };
+    let config = source.rebase(reloaded);
+    assert_eq!(config.targets.keys().collect::<Vec<_>>(), ["/orders", "/reloaded"]);
+    // A later document builds on the reloaded file, not the first one
+    let config = source.apply("/items: {}".to_string()).unwrap().unwrap();
+    assert_eq!(config.targets.keys().collect::<Vec<_>>(), ["/items", "/reloaded"]);
+}
+
+#[cfg(feature = "http-config-source")]
+#[tokio::test]
+async fn test_http_fetcher_caches_etag() {
+    use std::sync::atomic::Ordering;
+
+    let (url, document, not_modified) = control_plane().await;
+    publish(&document, "/orders: {}");
+    let fetcher = HttpFetcher::new(url::Url::parse(&url).unwrap(), true);
+    assert_eq!(fetcher.fetch().await, Ok(Fetched::Document("/orders: {}".to_string())));
+    assert_eq!(fetcher.fetch().await, Ok(Fetched::Unchanged));
+    assert_eq!(not_modified.load(Ordering::SeqCst), 1);
+    publish(&document, "/items: {}");
+    assert_eq!(fetcher.fetch().await, Ok(Fetched::Document("/items: {}".to_string())));
+
+    // Without caching, every fetch gets the whole document
+    let fetcher = HttpFetcher::new(url::Url::parse(&url).unwrap(), false);
+    for _ in 0..2 {
+        assert_eq!(fetcher.fetch().await, Ok(Fetched::Document("/items: {}".to_string())));
+    }
+    assert_eq!(not_modified.load(Ordering::SeqCst), 1);
+
+    let fetcher = HttpFetcher::new(url::Url::parse(&url.replace("/targets", "/missing")).unwrap(), true);
+    assert!(fetcher.fetch().await.unwrap_err().ends_with("answered 404 Not Found"));
+}
+
+#[cfg(feature = "http-config-source")]
+#[tokio::test]
+async fn test_poll_hot_swaps_and_keeps_last_good() {
+    let (url, document, _) = control_plane().await;
+    let config = source_config(url.clone());
+    let fetcher = HttpFetcher::new(url::Url::parse(&url).unwrap(), true);
+    let state = test_state_with(file_config(), MockInvoker::new(vec![]));
+
+    publish(&document, r#"{"/orders": {"function": "orders"}}"#);
+    assert_eq!(poll(&state, &fetcher, &config).await, Duration::from_secs(10));
+    let rev = state.config_rev();
+    assert_eq!(state.config().targets.keys().collect::<Vec<_>>(), ["/file", "/orders"]);
+    assert_eq!(state.config().targets["/orders"].function, Some("orders".into()));
+    // Unchanged documents keep the running config
+    assert_eq!(poll(&state, &fetcher, &config).await, Duration::from_secs(10));
+    assert_eq!(state.config_rev(), rev);
+
+    // Invalid documents keep the last good targets while fetching backs off
+    publish(&document, "/items:\n  circuit_breaker:\n    window_secs: 0\n");
+    assert_eq!(poll(&state, &fetcher, &config).await, Duration::from_secs(20));
+    assert_eq!(poll(&state, &fetcher, &config).await, Duration::from_secs(40));
+    assert_eq!(state.config_rev(), rev);
+    let status = state.config_source.status();
+    assert_eq!(status.consecutive_failures, 2);
+    assert!(status.last_error.unwrap().contains("target /items"));
+    assert_eq!(status.targets, ["/orders"]);
+
+    publish(&document, "/items: {}");
+    assert_eq!(poll(&state, &fetcher, &config).await, Duration::from_secs(10));
+    assert_eq!(state.config().targets.keys().collect::<Vec<_>>(), ["/file", "/items"]);
+    let status = state.config_source.status();
+    assert_eq!((status.consecutive_failures, status.last_error), (0, None));
+    assert!(status.fetched_at.is_some());
+}
+
+#[cfg(feature = "http-config-source")]
+#[tokio::test]
+async fn test_poll_unreachable_source_counts_failures() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/targets", listener.local_addr().unwrap());
+    drop(listener);
+    let config = source_config(url.clone());
+    let fetcher = HttpFetcher::new(url::Url::parse(&url).unwrap(), true);
+    let state = test_state_with(file_config(), MockInvoker::new(vec![]));
+
+    assert_eq!(poll(&state, &fetcher, &config).await, Duration::from_secs(20));
+    assert_eq!(state.config().targets.keys().collect::<Vec<_>>(), ["/file"]);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let registry = state.telemetry.snapshot();
+    assert_eq!(
+        registry.counters.get(&("config_source_failures_total", vec![("source", "http".to_string())])),
+        Some(&1)
+    );
+}
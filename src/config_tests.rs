@@ -19,6 +19,9 @@ fn test_lambda_invoke_mode_from_str() {
     assert_eq!("responsestream".parse::<LambdaInvokeMode>().unwrap(), LambdaInvokeMode::ResponseStream);
     assert_eq!("BUFFERED".parse::<LambdaInvokeMode>().unwrap(), LambdaInvokeMode::Buffered);
     assert_eq!("RESPONSESTREAM".parse::<LambdaInvokeMode>().unwrap(), LambdaInvokeMode::ResponseStream);
+    assert_eq!("response_stream".parse::<LambdaInvokeMode>().unwrap(), LambdaInvokeMode::ResponseStream);
+    assert_eq!("event".parse::<LambdaInvokeMode>().unwrap(), LambdaInvokeMode::Event);
+    assert_eq!("Event".parse::<LambdaInvokeMode>().unwrap(), LambdaInvokeMode::Event);
     assert!("invalid".parse::<LambdaInvokeMode>().is_err());
 }
 
@@ -29,7 +32,7 @@ fn test_config_default() {
     assert_eq!(config.lambda_invoke_mode, LambdaInvokeMode::Buffered);
     assert!(config.api_keys.is_empty());
     assert_eq!(config.auth_mode, AuthMode::Open);
-    assert_eq!(config.addr, "0.0.0.0:8000");
+    assert_eq!(config.addr.to_string(), "0.0.0.0:8000");
 }
 
 #[test]
@@ -54,7 +57,7 @@ fn test_config_apply_env_overrides() {
     assert_eq!(config.lambda_invoke_mode, LambdaInvokeMode::ResponseStream);
     assert_eq!(config.api_keys, vec!["key1", "key2"].into_iter().map(String::from).collect::<HashSet<String>>());
     assert_eq!(config.auth_mode, AuthMode::ApiKey);
-    assert_eq!(config.addr, "127.0.0.1:3000");
+    assert_eq!(config.addr.to_string(), "127.0.0.1:3000");
 
     // Clean up environment variables
     env::remove_var("LAMBDA_FUNCTION_NAME");
@@ -85,7 +88,7 @@ addr: 127.0.0.1:3000
     assert_eq!(config.lambda_invoke_mode, LambdaInvokeMode::ResponseStream);
     assert_eq!(config.api_keys, vec!["key1", "key2"].into_iter().map(String::from).collect::<HashSet<String>>());
     assert_eq!(config.auth_mode, AuthMode::ApiKey);
-    assert_eq!(config.addr, "127.0.0.1:3000");
+    assert_eq!(config.addr.to_string(), "127.0.0.1:3000");
 }
 
 #[test]
@@ -113,7 +116,7 @@ addr: 0.0.0.0:8000
     assert_eq!(config.lambda_invoke_mode, LambdaInvokeMode::ResponseStream);
     assert_eq!(config.api_keys, vec!["file-key"].into_iter().map(String::from).collect::<HashSet<String>>());
     assert_eq!(config.auth_mode, AuthMode::ApiKey);
-    assert_eq!(config.addr, "0.0.0.0:8000");
+    assert_eq!(config.addr.to_string(), "0.0.0.0:8000");
 
     // Clean up environment variables
     env::remove_var("LAMBDA_FUNCTION_NAME");
@@ -144,7 +147,7 @@ fn test_config_load_invalid_file() {
     assert_eq!(config.auth_mode, AuthMode::ApiKey);
     assert_eq!(config.lambda_invoke_mode, LambdaInvokeMode::ResponseStream);
     assert!(config.api_keys.is_empty());
-    assert_eq!(config.addr, "0.0.0.0:8000");
+    assert_eq!(config.addr.to_string(), "0.0.0.0:8000");
 
     // Clean up environment variables
     env::remove_var("LAMBDA_FUNCTION_NAME");
@@ -169,7 +172,7 @@ fn test_config_load_invalid_yaml() {
     assert_eq!(config.auth_mode, AuthMode::ApiKey);
     assert_eq!(config.lambda_invoke_mode, LambdaInvokeMode::ResponseStream);
     assert!(config.api_keys.is_empty());
-    assert_eq!(config.addr, "0.0.0.0:8000");
+    assert_eq!(config.addr.to_string(), "0.0.0.0:8000");
 
     // Clean up environment variables
     env::remove_var("LAMBDA_FUNCTION_NAME");
@@ -189,3 +192,1490 @@ fn test_config_load_empty_api_keys() {
     env::remove_var("API_KEYS");
     env::remove_var("LAMBDA_FUNCTION_NAME"); // Add this line
 }
+
+#[test]
+fn test_match_target() {
+    let mut config = Config::default();
+    for pattern in ["/orders", "/orders/*rest", "/orders/archive/*rest", "/*rest"] {
+        config.targets.insert(pattern.to_string(), Target::default());
+    }
+
+    assert_eq!(config.match_target("/orders").unwrap().0, "/orders");
+    assert_eq!(config.match_target("/orders/1").unwrap().0, "/orders/*rest");
+    assert_eq!(config.match_target("/orders/archive/1").unwrap().0, "/orders/archive/*rest");
+    assert_eq!(config.match_target("/ordersx").unwrap().0, "/*rest");
+    assert!(config.match_target("/").is_none());
+
+    config.targets.remove("/*rest");
+    assert!(config.match_target("/other").is_none());
+}
+
+#[test]
+fn test_match_route_params() {
+    let mut config = Config::default();
+    for pattern in ["/users/me", "/users/:id", "/users/:id/posts/:post", "/users/*rest"] {
+        config.targets.insert(pattern.to_string(), Target::default());
+    }
+
+    assert_eq!(config.match_route("/users/me").unwrap().2, RouteRule::Exact);
+    let (pattern, _, rule) = config.match_route("/users/42").unwrap();
+    assert_eq!((pattern, rule), ("/users/:id", RouteRule::Param));
+    assert_eq!(config.match_route("/users/42/posts/7").unwrap().0, "/users/:id/posts/:post");
+    assert_eq!(config.match_route("/users/42/likes").unwrap().0, "/users/*rest");
+    // A parameter never captures an empty segment
+    assert_eq!(config.match_route("/users/").unwrap().0, "/users/*rest");
+}
+
+#[test]
+fn test_match_pattern_captures() {
+    let params = match_pattern("/users/:id/posts/:post", "/users/42/posts/7").unwrap();
+    assert_eq!(params["id"], "42");
+    assert_eq!(params["post"], "7");
+
+    // Captures are decoded per segment, so an encoded slash stays in its parameter
+    let params = match_pattern("/users/:id", "/users/a%2Fb%20c").unwrap();
+    assert_eq!(params["id"], "a/b c");
+    assert!(match_pattern("/users/:id", "/users/a/b").is_none());
+
+    // A wildcard captures the remaining path as a single parameter
+    let params = match_pattern("/files/:bucket/*key", "/files/b1/docs/2024/report%231.pdf").unwrap();
+    assert_eq!(params["bucket"], "b1");
+    assert_eq!(params["key"], "docs/2024/report#1.pdf");
+    assert!(match_pattern("/files/:bucket/*key", "/files/b1").is_none());
+
+    assert_eq!(match_pattern("/caf%C3%A9/:x", "/caf%C3%A9/1"), None);
+    assert_eq!(match_pattern("/café/:x", "/caf%C3%A9/1").unwrap()["x"], "1");
+}
+
+#[test]
+fn test_target_defaults_to_top_level_function() {
+    let config_content = r#"
+lambda_function_name: default-function
+targets:
+  /orders/*rest:
+    function: orders-function
+    invoke: ResponseStream
+    initial_flush_padding: 2048
+  /*rest: {}
+"#;
+    let config: Config = serde_yaml::from_str(config_content).unwrap();
+
+    let (_, orders) = config.match_target("/orders/1").unwrap();
+    assert_eq!(config.function_name(orders), "orders-function");
+    assert_eq!(config.invoke_mode(orders), LambdaInvokeMode::ResponseStream);
+    assert_eq!(orders.initial_flush_padding, 2048);
+
+    let (_, fallback) = config.match_target("/other").unwrap();
+    assert_eq!(config.function_name(fallback), "default-function");
+    assert_eq!(config.invoke_mode(fallback), LambdaInvokeMode::Buffered);
+}
+
+fn write_file(dir: &std::path::Path, name: &str, contents: &str) -> std::path::PathBuf {
+    let path = dir.join(name);
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn test_namespaces_mounted_under_prefix() {
+    let dir = tempfile::tempdir().unwrap();
+    write_file(
+        dir.path(),
+        "team-a.yaml",
+        r#"
+auth_mode: ApiKey
+api_keys: [team-a-key]
+targets:
+  /orders/*rest:
+    function: team-a-orders
+  /:
+    function: team-a-home
+"#,
+    );
+    let path = write_file(
+        dir.path(),
+        "config.yaml",
+        r#"
+lambda_function_name: default-function
+auth_mode: Open
+namespaces:
+  team-a:
+    prefix: /team-a
+    file: team-a.yaml
+  team-b:
+    prefix: /team-b
+    targets:
+      /*rest:
+        function: team-b-function
+        auth: Open
+"#,
+    );
+
+    let config = Config::load_from_file(&path).unwrap();
+    assert!(config.validate().is_ok());
+
+    let (pattern, orders) = config.match_target("/team-a/orders/1").unwrap();
+    assert_eq!(pattern, "/team-a/orders/*rest");
+    assert_eq!(config.function_name(orders), "team-a-orders");
+    assert_eq!(orders.namespace.as_deref(), Some("team-a"));
+    assert_eq!(config.auth_mode(orders), AuthMode::ApiKey);
+    assert!(config.api_keys(orders).contains("team-a-key"));
+
+    let (_, home) = config.match_target("/team-a").unwrap();
+    assert_eq!(config.function_name(home), "team-a-home");
+
+    let (_, team_b) = config.match_target("/team-b/anything").unwrap();
+    assert_eq!(config.function_name(team_b), "team-b-function");
+    assert_eq!(config.auth_mode(team_b), AuthMode::Open);
+    assert!(config.api_keys(team_b).is_empty());
+}
+
+#[test]
+fn test_namespace_inline_settings_override_file() {
+    let dir = tempfile::tempdir().unwrap();
+    write_file(dir.path(), "team.yaml", "prefix: /from-file\nauth_mode: ApiKey\n");
+    let path = write_file(
+        dir.path(),
+        "config.yaml",
+        "lambda_function_name: f\nnamespaces:\n  team:\n    file: team.yaml\n    auth_mode: Open\n",
+    );
+
+    let config = Config::load_from_file(&path).unwrap();
+    let namespace = &config.namespaces["team"];
+    assert_eq!(namespace.prefix, "/from-file");
+    assert_eq!(namespace.auth_mode, Some(AuthMode::Open));
+}
+
+#[test]
+fn test_namespace_missing_file_is_an_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_file(
+        dir.path(),
+        "config.yaml",
+        "lambda_function_name: f\nnamespaces:\n  team:\n    prefix: /team\n    file: missing.yaml\n",
+    );
+    let err = Config::load_from_file(&path).unwrap_err().to_string();
+    assert!(err.contains("namespace team"), "{}", err);
+}
+
+#[test]
+fn test_namespace_reload_reads_only_its_own_file() {
+    let dir = tempfile::tempdir().unwrap();
+    write_file(dir.path(), "team-a.yaml", "api_keys: [a-old]\ntargets:\n  /*rest:\n    function: a-v1\n");
+    write_file(dir.path(), "team-b.yaml", "api_keys: [b-key]\ntargets:\n  /*rest:\n    function: b-v1\n");
+    let path = write_file(
+        dir.path(),
+        "config.yaml",
+        r#"
+lambda_function_name: default-function
+auth_mode: ApiKey
+namespaces:
+  team-a:
+    prefix: /team-a
+    file: team-a.yaml
+    rate_limit:
+      requests_per_second: 5
+  team-b:
+    prefix: /team-b
+    file: team-b.yaml
+"#,
+    );
+    let config = Config::load_from_file(&path).unwrap();
+    assert!(config.validate().is_ok());
+
+    // Team B's keys file is gone, which a reload of team A must not notice
+    fs::remove_file(dir.path().join("team-b.yaml")).unwrap();
+    write_file(
+        dir.path(),
+        "team-a.yaml",
+        "api_keys: [a-new]\ntargets:\n  /*rest:\n    function: a-v2\n  /extra:\n    function: a-extra\n",
+    );
+    let reloaded = config.reload_namespace("team-a").unwrap();
+
+    let (_, team_a) = reloaded.match_target("/team-a/orders").unwrap();
+    assert_eq!(reloaded.function_name(team_a), "a-v2");
+    assert!(reloaded.api_keys(team_a).contains("a-new") && !reloaded.api_keys(team_a).contains("a-old"));
+    assert!(reloaded.targets.contains_key("/team-a/extra"));
+    // Inline settings of the main config still win
+    assert_eq!(reloaded.namespaces["team-a"].rate_limit.as_ref().unwrap().requests_per_second, 5.0);
+    let (_, team_b) = reloaded.match_target("/team-b/orders").unwrap();
+    assert_eq!(reloaded.function_name(team_b), "b-v1");
+    assert!(reloaded.api_keys(team_b).contains("b-key"));
+
+    // Targets the file no longer lists are unmounted
+    write_file(dir.path(), "team-a.yaml", "targets:\n  /*rest:\n    function: a-v3\n");
+    let reloaded = reloaded.reload_namespace("team-a").unwrap();
+    assert!(!reloaded.targets.contains_key("/team-a/extra"));
+
+    // The namespace whose file is gone fails alone
+    let err = reloaded.reload_namespace("team-b").unwrap_err();
+    assert!(err.contains("namespace team-b: failed to read"), "{}", err);
+    let err = reloaded.reload_namespace("team-c").unwrap_err();
+    assert!(err.contains("namespace team-c: not configured"), "{}", err);
+}
+
+#[test]
+fn test_namespace_reload_is_validated() {
+    let dir = tempfile::tempdir().unwrap();
+    write_file(dir.path(), "team-a.yaml", "targets:\n  /orders:\n    function: a\n");
+    let path = write_file(
+        dir.path(),
+        "config.yaml",
+        r#"
+lambda_function_name: default-function
+namespaces:
+  team-a:
+    file: team-a.yaml
+    prefix: /team-a
+  team-b:
+    prefix: /team-b
+"#,
+    );
+    let config = Config::load_from_file(&path).unwrap();
+    assert!(config.validate().is_ok());
+    assert!(config.reload_namespace("team-b").unwrap_err().contains("no file to reload"));
+
+    write_file(
+        dir.path(),
+        "team-a.yaml",
+        "rate_limit:\n  requests_per_second: 0\n  burst: 0\ntargets:\n  /orders:\n    function: a\n",
+    );
+    let err = config.reload_namespace("team-a").unwrap_err();
+    assert!(err.contains("namespace team-a: rate_limit.requests_per_second must be above 0"), "{}", err);
+    assert!(err.contains("namespace team-a: rate_limit.burst must be at least 1"), "{}", err);
+}
+
+#[test]
+fn test_namespace_collisions() {
+    let config_content = r#"
+lambda_function_name: f
+targets:
+  /team-a/orders: {}
+namespaces:
+  team-a:
+    prefix: /team-a
+    targets:
+      /orders: {}
+  team-a-sub:
+    prefix: /team-a/sub
+  bad:
+    prefix: team-c/
+"#;
+    let mut temp_file = NamedTempFile::new().unwrap();
+    write!(temp_file, "{}", config_content).unwrap();
+
+    let config = Config::load_from_file(temp_file.path()).unwrap();
+    let err = config.validate().unwrap_err();
+
+    assert!(err.contains("target /team-a/orders collides with a top-level target"), "{}", err);
+    assert!(err.contains("prefix /team-a/sub collides with prefix /team-a"), "{}", err);
+    assert!(err.contains("namespace bad"), "{}", err);
+}
+
+#[test]
+fn test_namespace_prefixes_sharing_text_do_not_collide() {
+    let mut config = Config::default();
+    for (name, prefix) in [("a", "/team"), ("b", "/teams")] {
+        config.namespaces.insert(
+            name.to_string(),
+            Namespace {
+                prefix: prefix.to_string(),
+                ..Default::default()
+            },
+        );
+    }
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn test_match_route_root_rules() {
+    let mut config = Config::default();
+    config.targets.insert("/*rest".to_string(), Target::default());
+    assert_eq!(config.match_route("/x").unwrap().2, RouteRule::Wildcard);
+    assert!(config.match_route("/").is_none());
+
+    config.targets.insert("/".to_string(), Target::default());
+    assert_eq!(config.match_route("/").unwrap().0, "/");
+    assert_eq!(config.match_route("/").unwrap().2, RouteRule::Exact);
+    assert_eq!(config.match_route("/x").unwrap().0, "/*rest");
+
+    config.targets.remove("/");
+    config.targets.insert("/home".to_string(), Target::default());
+    config.root_target = Some("/home".to_string());
+    let (pattern, _, rule) = config.match_route("/").unwrap();
+    assert_eq!((pattern, rule), ("/home", RouteRule::RootTarget));
+    assert_eq!(config.match_route("/home").unwrap().2, RouteRule::Exact);
+}
+
+#[test]
+fn test_validate_allowed_hosts() {
+    let mut config = Config {
+        allowed_hosts: vec!["api.example.com:8443".to_string(), "*.internal.example.com".to_string()],
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+
+    config.allowed_hosts.push("api.*.com".to_string());
+    let err = config.validate().unwrap_err();
+    assert!(err.contains("allowed_hosts: \"api.*.com\""), "{}", err);
+}
+
+#[test]
+fn test_validate_root_target() {
+    let mut config = Config {
+        root_target: Some("/home".to_string()),
+        ..Default::default()
+    };
+    let err = config.validate().unwrap_err();
+    assert!(err.contains("root path is unreachable"), "{}", err);
+
+    config.targets.insert("/home".to_string(), Target::default());
+    assert!(config.validate().is_ok());
+
+    config.targets.insert("/".to_string(), Target::default());
+    let err = config.validate().unwrap_err();
+    assert!(err.contains("conflicts with the target keyed /"), "{}", err);
+}
+
+#[test]
+fn test_function_list_and_strategy() {
+    let config: Config = serde_yaml::from_str(
+        r#"
+lambda_function_name: default-function
+targets:
+  /single:
+    function: one
+  /sharded/*rest:
+    function: [shard-a, shard-b]
+    strategy: least_in_flight
+  /empty:
+    function: []
+"#,
+    )
+    .unwrap();
+
+    let single = &config.targets["/single"];
+    assert_eq!(config.function_members(single), ["one"]);
+    assert_eq!(single.strategy, BalanceStrategy::RoundRobin);
+
+    let sharded = &config.targets["/sharded/*rest"];
+    assert_eq!(config.function_members(sharded), ["shard-a", "shard-b"]);
+    assert_eq!(config.function_name(sharded), "shard-a");
+    assert_eq!(sharded.strategy, BalanceStrategy::LeastInFlight);
+
+    assert_eq!(config.function_members(&Target::default()), ["default-function"]);
+    assert!(config.validate().unwrap_err().contains("target /empty: function list is empty"));
+}
+
+#[test]
+fn test_fingerprint_ignores_key_order_and_secrets() {
+    let config: Config = serde_yaml::from_str(
+        r#"
+lambda_function_name: default-function
+api_keys: [key-a]
+targets:
+  /orders/*rest:
+    function: orders-function
+    invoke_timeout_ms: 3000
+  /users/:id:
+    function: users-function
+"#,
+    )
+    .unwrap();
+    let reordered: Config = serde_yaml::from_str(
+        r#"
+targets:
+  /users/:id:
+    function: users-function
+  /orders/*rest:
+    invoke_timeout_ms: 3000
+    function: orders-function
+api_keys: [key-b, key-c]
+lambda_function_name: default-function
+"#,
+    )
+    .unwrap();
+
+    assert_eq!(config.fingerprint(), reordered.fingerprint());
+    assert_eq!(config.fingerprint().len(), 16);
+    assert!(config.fingerprint().bytes().all(|b| b.is_ascii_hexdigit()));
+}
+
+#[test]
+fn test_fingerprint_changes_with_any_setting() {
+    let config: Config = serde_yaml::from_str("lambda_function_name: default-function").unwrap();
+    let mut changed = config.clone();
+    changed.debug_headers = true;
+    assert_ne!(config.fingerprint(), changed.fingerprint());
+
+    let mut changed = config.clone();
+    changed.targets.insert("/orders".to_string(), Target::default());
+    assert_ne!(config.fingerprint(), changed.fingerprint());
+    let mut changed_target = changed.clone();
+    changed_target.targets.get_mut("/orders").unwrap().initial_flush_padding = 1;
+    assert_ne!(changed.fingerprint(), changed_target.fingerprint());
+}
+
+#[test]
+fn test_validate_slo_and_error_budget() {
+    let mut config = Config::default();
+    config.targets.insert(
+        "/api".to_string(),
+        Target {
+            slo: Some(100.0),
+            ..Default::default()
+        },
+    );
+    config.error_budget.short_window_secs = 3600;
+    let errors = config.validate().unwrap_err();
+    assert!(errors.contains("target /api: slo must be a percentage"), "{}", errors);
+    assert!(errors.contains("short_window_secs must be at least 1 and below long_window_secs"), "{}", errors);
+
+    config.targets.get_mut("/api").unwrap().slo = Some(99.9);
+    config.error_budget = ErrorBudgetConfig::default();
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn test_validate_adaptive_concurrency() {
+    let mut config = Config::default();
+    config.targets.insert(
+        "/api".to_string(),
+        Target {
+            max_concurrency: Some(10),
+            adaptive_concurrency: Some(AdaptiveConcurrency {
+                min_limit: 20,
+                max_limit: 10,
+                backoff: 1.0,
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+    );
+    let errors = config.validate().unwrap_err();
+    assert!(errors.contains("set either max_concurrency or adaptive_concurrency"), "{}", errors);
+    assert!(errors.contains("needs 1 <= min_limit <= max_limit"), "{}", errors);
+    assert!(errors.contains("backoff must be between 0 and 1"), "{}", errors);
+
+    let target = config.targets.get_mut("/api").unwrap();
+    target.max_concurrency = None;
+    target.adaptive_concurrency = Some(AdaptiveConcurrency::default());
+    assert!(config.validate().is_ok());
+}
+
+fn env_of(vars: &'static [(&'static str, &'static str)]) -> impl Fn(&str) -> Option<String> {
+    move |name| vars.iter().find(|(key, _)| *key == name).map(|(_, value)| value.to_string())
+}
+
+#[test]
+fn test_embedded_config_is_zero_config() {
+    let config = Config::embedded();
+    assert_eq!(config.lambda_function_name, "");
+    assert_eq!(config.lambda_invoke_mode, LambdaInvokeMode::Buffered);
+    assert_eq!(config.auth_mode, AuthMode::Open);
+    assert_eq!(config.addr.to_string(), "0.0.0.0:8000");
+    assert!(config.targets.is_empty());
+
+    let (mut config, source) = Config::load_document(Path::new("non_existent_file.yaml"), &env_of(&[]));
+    assert_eq!(source, ConfigSource::Embedded);
+    config.apply_env_overrides_from(&env_of(&[("LAMBDA_FUNCTION_NAME", "my-function"), ("PORT", "9000")]));
+    assert_eq!(config.addr.to_string(), "0.0.0.0:9000");
+    // Every path falls back to the top-level function
+    assert_eq!(config.match_target("/any/path"), None);
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+#[should_panic(expected = "No lambda_function_name provided")]
+fn test_zero_config_without_function_name_is_fatal() {
+    let (mut config, _) = Config::load_document(Path::new("non_existent_file.yaml"), &env_of(&[]));
+    config.apply_env_overrides_from(&env_of(&[("PORT", "9000")]));
+}
+
+#[test]
+fn test_addr_wins_over_port() {
+    let mut config = Config::default();
+    config.apply_env_overrides_from(&env_of(&[
+        ("LAMBDA_FUNCTION_NAME", "my-function"),
+        ("PORT", "9000"),
+        ("ADDR", "127.0.0.1:3000"),
+    ]));
+    assert_eq!(config.addr.to_string(), "127.0.0.1:3000");
+}
+
+#[test]
+fn test_config_source_precedence() {
+    let mut file = NamedTempFile::new().unwrap();
+    write!(file, "lambda_function_name: file-function\ntargets:\n  /file: {{}}\n  /both: {{}}\n").unwrap();
+    let missing = Path::new("non_existent_file.yaml");
+    let patterns = |config: &Config| config.targets.keys().cloned().collect::<Vec<_>>();
+
+    // CONFIG_YAML wins over the file
+    let inline = env_of(&[("CONFIG_YAML", "lambda_function_name: inline-function\nauth_mode: ApiKey")]);
+    let (config, source) = Config::load_document(file.path(), &inline);
+    assert_eq!(source, ConfigSource::Inline);
+    assert_eq!(config.lambda_function_name, "inline-function");
+    assert_eq!(config.auth_mode, AuthMode::ApiKey);
+
+    let (config, source) = Config::load_document(file.path(), &env_of(&[]));
+    assert_eq!(source, ConfigSource::File);
+    assert_eq!(config.lambda_function_name, "file-function");
+
+    // TARGETS adds to the document's targets, replacing those with the same pattern
+    let targets = env_of(&[("TARGETS", r#"{"/both": {"function": "env-function"}, "/env": {}}"#)]);
+    let (config, source) = Config::load_document(file.path(), &targets);
+    assert_eq!(source, ConfigSource::File);
+    assert_eq!(patterns(&config), ["/both", "/env", "/file"]);
+    assert_eq!(config.function_name(&config.targets["/both"]), "env-function");
+
+    // Without a document, TARGETS goes on top of the built-in config
+    let (config, source) = Config::load_document(missing, &targets);
+    assert_eq!(source, ConfigSource::Targets);
+    assert_eq!(patterns(&config), ["/both", "/env"]);
+    assert_eq!(config.auth_mode, AuthMode::Open);
+
+    // A document that cannot be read falls back to the built-in config
+    let broken = env_of(&[("CONFIG_YAML", "invalid: yaml: content")]);
+    assert_eq!(Config::load_document(file.path(), &broken).1, ConfigSource::Embedded);
+    let broken_targets = env_of(&[("TARGETS", "[/a, /b]")]);
+    let (config, source) = Config::load_document(missing, &broken_targets);
+    assert_eq!(source, ConfigSource::Embedded);
+    assert!(config.targets.is_empty());
+}
+
+#[test]
+fn test_synthesized_config_is_validated() {
+    let targets = env_of(&[("TARGETS", "/api:\n  slo: 120\n")]);
+    let (mut config, _) = Config::load_document(Path::new("non_existent_file.yaml"), &targets);
+    config.apply_env_overrides_from(&env_of(&[("LAMBDA_FUNCTION_NAME", "my-function")]));
+    assert!(config.validate().unwrap_err().contains("target /api: slo must be a percentage"));
+}
+
+#[test]
+fn test_payload_mode_names() {
+    let target: Target = serde_yaml::from_str("payload: apigw_v2").unwrap();
+    assert_eq!(target.payload, Some(PayloadMode::ApiGatewayV2));
+    let target: Target = serde_yaml::from_str("payload: apigw_v1").unwrap();
+    assert_eq!(target.payload, Some(PayloadMode::ApiGatewayV1));
+    let url_target: Target = serde_yaml::from_str("payload: function_url").unwrap();
+    assert_eq!(url_target.payload, Some(PayloadMode::FunctionUrl));
+    assert_eq!(Target::default().payload, None);
+
+    let mut config: Config = serde_yaml::from_str("lambda_function_name: f\npayload: apigw_v1").unwrap();
+    assert_eq!(config.payload_mode(&Target::default()), PayloadMode::ApiGatewayV1);
+    assert_eq!(config.payload_mode(&target), PayloadMode::ApiGatewayV1);
+    config.payload = PayloadMode::Alb;
+    assert_eq!(config.payload_mode(&Target::default()), PayloadMode::Alb);
+}
+
+/// A config requiring auth on public binds, with an open namespace and an API key protected target.
+const PUBLIC_BIND_CONFIG: &str = r#"
+lambda_function_name: f
+auth_mode: ApiKey
+api_keys: [key]
+security:
+  require_auth_on_public_bind: true
+namespaces:
+  docs:
+    prefix: /docs
+    auth_mode: Open
+    targets:
+      /*rest: {}
+targets:
+  /orders: {}
+  /public:
+    auth: Open
+"#;
+
+#[test]
+fn test_require_auth_on_public_bind() {
+    let config = Config::from_yaml(PUBLIC_BIND_CONFIG, Path::new(".")).unwrap();
+    let with = |addr: &str, edit: fn(&mut Config)| {
+        let mut config = Config {
+            addr: addr.parse().unwrap(),
+            ..config.clone()
+        };
+        edit(&mut config);
+        config.validate()
+    };
+
+    // Namespace defaults and target overrides are both considered
+    let error = with("0.0.0.0:8000", |_| {}).unwrap_err();
+    assert!(error.contains("security: 0.0.0.0:8000 is not a loopback address"), "{}", error);
+    assert!(error.contains("/docs/*rest, /public allow unauthenticated requests"), "{}", error);
+    assert!(!error.contains("/orders"), "{}", error);
+    assert!(!error.contains("top-level function"), "{}", error);
+    let error = with("[::]:8000", |c| c.auth_mode = AuthMode::Open).unwrap_err();
+    assert!(error.contains("the top-level function, /docs/*rest, /orders, /public"), "{}", error);
+    let error = with("[::]:8000", |c| {
+        c.auth_mode = AuthMode::Open;
+        c.unmatched = UnmatchedRoute::NotFound;
+    })
+    .unwrap_err();
+    assert!(!error.contains("top-level function"), "{}", error);
+
+    for loopback in ["127.0.0.1:8000", "[::1]:8000", "localhost:8000"] {
+        assert_eq!(with(loopback, |_| {}), Ok(()), "{}", loopback);
+    }
+    assert_eq!(with("0.0.0.0:8000", |c| c.security.acknowledge_open = true), Ok(()));
+    assert_eq!(
+        with("0.0.0.0:8000", |c| c.allowed_hosts = vec!["api.example.com".to_string()]),
+        Ok(())
+    );
+    assert_eq!(with("0.0.0.0:8000", |c| c.security.require_auth_on_public_bind = false), Ok(()));
+    assert_eq!(
+        with("0.0.0.0:8000", |c| {
+            c.targets.values_mut().for_each(|t| t.auth = Some(AuthMode::ApiKey));
+        }),
+        Ok(())
+    );
+}
+
+#[test]
+fn test_validate_cookie_rewrite() {
+    let mut config = Config::default();
+    let rewrite = |yaml: &str| serde_yaml::from_str::<CookieRewrite>(yaml).unwrap();
+    config.targets.insert(
+        "/app/*rest".to_string(),
+        Target {
+            cookie_rewrite: Some(rewrite("{path_prefix: app, domain: example.com, strip_domain: true}")),
+            ..Default::default()
+        },
+    );
+    let errors = config.validate().unwrap_err();
+    assert!(errors.contains("cookie_rewrite.path_prefix \"app\" must start with '/'"), "{}", errors);
+    assert!(errors.contains("sets both domain and strip_domain"), "{}", errors);
+
+    let valid = rewrite("{path_prefix: /app, domain: example.com, secure: true, same_site: none}");
+    assert_eq!(valid.same_site, Some(SameSite::None));
+    config.targets.get_mut("/app/*rest").unwrap().cookie_rewrite = Some(valid);
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn test_validate_path_rewrite() {
+    let yaml = r#"
+lambda_function_name: f
+targets:
+  /:id:
+    strip_prefix: true
+  /v1/*rest:
+    rewrite: { from: "^/v1/(", to: "/" }
+  /v2/*rest:
+    rewrite: { from: "", to: "/" }
+    original_path_header: "bad header"
+"#;
+    let errors = Config::from_yaml(yaml, Path::new(".")).unwrap().validate().unwrap_err();
+    assert!(errors.contains("target /:id: strip_prefix needs a pattern"), "{}", errors);
+    assert!(errors.contains("target /v1/*rest: rewrite.from \"^/v1/(\""), "{}", errors);
+    assert!(errors.contains("target /v2/*rest: rewrite.from is required"), "{}", errors);
+    assert!(errors.contains("target /v2/*rest: original_path_header"), "{}", errors);
+
+    let yaml = "targets:\n  /svc/*rest: { strip_prefix: true, rewrite: { from: /a, to: /b } }\n";
+    let config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    let target = &config.targets["/svc/*rest"];
+    assert!(!target.rewrite.as_ref().unwrap().is_regex());
+    assert_eq!(target.original_path_header, "x-forwarded-path");
+    assert_eq!(literal_prefix_segments("/svc/*rest"), 1);
+    assert_eq!(literal_prefix_segments("shop/a/b/:id"), 2);
+}
+
+#[test]
+fn test_canonical_request_config() {
+    let config: Config = serde_yaml::from_str("lambda_function_name: f\n").unwrap();
+    assert_eq!(config.canonical_request.headers, ["content-type"]);
+    let yaml = "lambda_function_name: f\ncanonical_request:\n  headers: [host, \"bad header\"]\n";
+    let config: Config = serde_yaml::from_str(yaml).unwrap();
+    let errors = config.validate().unwrap_err();
+    assert!(errors.contains("canonical_request: \"bad header\" is not a valid header name"), "{}", errors);
+}
+
+#[test]
+fn test_target_methods() {
+    let yaml = r#"
+lambda_function_name: f
+targets:
+  /a: { methods: [get, Head, get] }
+  /b: { methods: [GET, fetch] }
+  /c: { methods: [] }
+"#;
+    let config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    let errors = config.validate().unwrap_err();
+    assert!(errors.contains("target /b: unknown method \"fetch\" in methods"), "{}", errors);
+    assert!(errors.contains("target /c: methods must list at least one method"), "{}", errors);
+    assert!(!errors.contains("target /a"), "{}", errors);
+
+    let target = &config.targets["/a"];
+    assert!(target.accepts_method(&axum::http::Method::GET));
+    assert!(target.accepts_method(&axum::http::Method::HEAD));
+    assert!(!target.accepts_method(&axum::http::Method::POST));
+    assert_eq!(target.allow_header(), "GET, HEAD");
+    assert!(Target::default().accepts_method(&axum::http::Method::DELETE));
+}
+
+#[test]
+fn test_target_qualifier() {
+    let yaml = r#"
+lambda_function_name: f
+targets:
+  /live: { qualifier: live, qualifier_on_request: true }
+  /version: { qualifier: "42" }
+  /empty: { qualifier: "" }
+  /bad: { qualifier: "live:canary" }
+"#;
+    let config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    let errors = config.validate().unwrap_err();
+    assert!(errors.contains("target /empty: qualifier must not be empty"), "{}", errors);
+    assert!(errors.contains("target /bad: qualifier \"live:canary\" is not a valid alias or version"), "{}", errors);
+    assert!(!errors.contains("target /live") && !errors.contains("target /version"), "{}", errors);
+    assert_eq!(config.targets["/live"].qualifier.as_deref(), Some("live"));
+    assert!(config.targets["/live"].qualifier_on_request);
+
+    assert!(valid_qualifier("$LATEST"));
+    assert!(valid_qualifier("canary-2_b"));
+    assert!(!valid_qualifier(""));
+    assert!(!valid_qualifier(&"a".repeat(129)));
+    assert!(!valid_qualifier("live canary"));
+}
+
+#[test]
+fn test_body_storage_validation() {
+    let yaml = r#"
+lambda_function_name: f
+body_storage:
+  encrypt_with: "2026-10"
+  keys:
+    "2026-04": { key: "c2hvcnQ=" }
+flight_recorder: { path: /tmp/failed.log, queue_capacity: 0 }
+"#;
+    let errors = Config::from_yaml(yaml, Path::new(".")).unwrap().validate().unwrap_err();
+    assert!(errors.contains("body_storage: key 2026-04: must be 32 bytes, not 5"), "{}", errors);
+    assert!(errors.contains("flight_recorder: queue_capacity must be at least 1"), "{}", errors);
+
+    let yaml = r#"
+lambda_function_name: f
+body_storage:
+  encrypt_with: "2026-10"
+  keys:
+    "2026-10": { key: "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=" }
+"#;
+    assert_eq!(Config::from_yaml(yaml, Path::new(".")).unwrap().validate(), Ok(()));
+    let unknown = yaml.replace("encrypt_with: \"2026-10\"", "encrypt_with: \"2027-01\"");
+    let errors = Config::from_yaml(&unknown, Path::new(".")).unwrap().validate().unwrap_err();
+    assert!(errors.contains("body_storage: encrypt_with names unknown key \"2027-01\""), "{}", errors);
+    assert!(Config::from_yaml("body_storage: { keys: { a: { key: x, file: y } } }", Path::new(".")).is_err());
+}
+
+#[test]
+fn test_shutdown_config() {
+    let yaml = "lambda_function_name: f\nshutdown:\n  phases: [refuse_new, drain_streams]\n  drain_streams_ms: 1000\n";
+    let config: Config = serde_yaml::from_str(yaml).unwrap();
+    let shutdown = config.shutdown_config();
+    assert_eq!(shutdown.phases, [ShutdownPhase::RefuseNew, ShutdownPhase::DrainStreams]);
+    assert_eq!(shutdown.drain_streams_ms, 1000);
+    assert_eq!(shutdown.drain_buffered_ms, 20_000);
+
+    // Without phases, the old grace period bounds draining and the final checkpoint
+    let config: Config = serde_yaml::from_str("lambda_function_name: f\nshutdown_grace_secs: 10\n").unwrap();
+    let shutdown = config.shutdown_config();
+    assert_eq!(shutdown.phases, ShutdownConfig::default().phases);
+    assert_eq!(shutdown.unready_ms, 5_000);
+    assert_eq!(
+        (shutdown.drain_buffered_ms, shutdown.drain_streams_ms, shutdown.flush_state_ms),
+        (10_000, 10_000, 10_000)
+    );
+
+    let config = Config {
+        shutdown: Some(ShutdownConfig {
+            phases: vec![ShutdownPhase::Unready, ShutdownPhase::Unready],
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let errors = config.validate().unwrap_err();
+    assert!(errors.contains("shutdown: phase unready is listed more than once"), "{}", errors);
+}
+
+#[test]
+fn test_unmatched_not_found_needs_no_top_level_function() {
+    let yaml = r#"
+unmatched: not_found
+targets:
+  /api/*rest: { function: api-function }
+  /admin/*rest: { function: admin-function }
+"#;
+    let mut config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    config.apply_env_overrides_from(&env_of(&[]));
+    assert_eq!(config.lambda_function_name, "");
+    assert!(config.validate().is_ok());
+
+    // A target without a function still invokes the top-level one
+    config.targets.insert("/legacy".to_string(), Target::default());
+    assert!(config.needs_top_level_function());
+
+    let config = Config {
+        unmatched: UnmatchedRoute::NotFound,
+        ..Default::default()
+    };
+    let errors = config.validate().unwrap_err();
+    assert!(errors.contains("no targets are configured"), "{}", errors);
+}
+
+#[test]
+fn test_body_match_validation() {
+    let yaml = r#"
+lambda_function_name: default-function
+targets:
+  /events:
+    match:
+      body_json:
+        - { pointer: /type, equals: order.created, target: orders }
+        - { pointer: /type, regex: "^refund\\.", target: refunds }
+      fallback: events
+  orders: { function: orders-function }
+  refunds: { function: refunds-function }
+  events: { function: events-function }
+"#;
+    let config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    assert!(config.validate().is_ok());
+    let body_match = config.targets["/events"].body_match.as_ref().unwrap();
+    assert_eq!(body_match.body_json[0].equals, Some(serde_json::json!("order.created")));
+    assert_eq!(body_match.body_json[1].regex.as_ref().unwrap().as_str(), "^refund\\.");
+    assert_eq!(body_match.max_body_bytes, 64 * 1024);
+
+    let yaml = r#"
+lambda_function_name: default-function
+targets:
+  /events:
+    match:
+      body_json:
+        - { pointer: type, equals: a, regex: b, target: missing }
+        - { pointer: /type, regex: "(", target: chained }
+  chained:
+    match: { fallback: events }
+  events: {}
+"#;
+    let errors = Config::from_yaml(yaml, Path::new(".")).unwrap().validate().unwrap_err();
+    for expected in [
+        "target /events: match.fallback is required",
+        "target /events: match names unknown target missing",
+        "target /events: match names target chained, which has a match of its own",
+        "target /events: match pointer \"type\" must be empty or start with '/'",
+        "target /events: match rule for type needs exactly one of equals and regex",
+        "target /events: match regex \"(\"",
+    ] {
+        assert!(errors.contains(expected), "{} not in {}", expected, errors);
+    }
+}
+
+#[test]
+fn test_match_condition_validation() {
+    let yaml = r#"
+lambda_function_name: default-function
+targets:
+  /events:
+    match:
+      conditions:
+        - { when: 'header("x-tier") == "gold" && method != "DELETE"', target: priority }
+        - { when: 'starts_with(path, "/events") &&', target: events }
+        - { when: 'len(method) > "3"', target: missing }
+      fallback: events
+  priority: { function: priority-function }
+  events: { function: events-function }
+"#;
+    let config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    let body_match = config.targets["/events"].body_match.as_ref().unwrap();
+    assert_eq!(body_match.conditions[0].when.as_str(), r#"header("x-tier") == "gold" && method != "DELETE""#);
+    assert!(body_match.conditions[0].when.error().is_none());
+    let errors = config.validate().unwrap_err();
+    for expected in [
+        r#"target /events: match condition "starts_with(path, \"/events\") &&": column 32: expected a value"#,
+        r#"target /events: match condition "len(method) > \"3\"": column 15: cannot compare an integer with a string"#,
+        "target /events: match names unknown target missing",
+    ] {
+        assert!(errors.contains(expected), "{} not in {}", expected, errors);
+    }
+}
+
+#[test]
+fn test_match_route_on_host() {
+    let yaml = r#"
+lambda_function_name: default-function
+targets:
+  /*rest: { function: default-function }
+  /health: { function: health-function }
+  foo/*rest: { host: api.foo.com, function: foo-function }
+  bar/*rest: { host: "*.bar.com", function: bar-function }
+  bar-api/*rest: { host: "*.api.bar.com", function: bar-api-function }
+  bar-admin/admin: { host: "*.bar.com", function: bar-admin-function }
+"#;
+    let config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    assert!(config.validate().is_ok());
+    let route = |host: Option<&str>, path: &str| config.match_route_on_host(host, path).map(|(key, _, _)| key);
+
+    assert_eq!(route(Some("api.foo.com"), "/orders"), Some("foo/*rest"));
+    assert_eq!(route(Some("API.foo.com:8000"), "/orders"), Some("foo/*rest"));
+    // Host-specific targets win even over a more specific path for any host
+    assert_eq!(route(Some("api.foo.com"), "/health"), Some("foo/*rest"));
+    assert_eq!(route(Some("www.bar.com"), "/admin"), Some("bar-admin/admin"));
+    // Of overlapping wildcards the longer domain wins, then the shorter one by path
+    assert_eq!(route(Some("v1.api.bar.com"), "/admin"), Some("bar-api/*rest"));
+    assert_eq!(route(Some("www.bar.com"), "/orders"), Some("bar/*rest"));
+    // Without a matching host, or any host, only targets without one are tried
+    assert_eq!(route(Some("other.com"), "/health"), Some("/health"));
+    assert_eq!(route(None, "/orders"), Some("/*rest"));
+    assert_eq!(config.match_route("/health").unwrap().0, "/health");
+
+    let (_, _, rule) = config.match_route_on_host(Some("api.foo.com"), "/x/y").unwrap();
+    assert_eq!(rule, RouteRule::Wildcard);
+    assert_eq!(path_pattern("foo/*rest"), Some("/*rest"));
+    assert_eq!(path_pattern("orders"), None);
+    assert_eq!(mount_pattern("/team-a", "foo/*rest"), "foo/team-a/*rest");
+    assert_eq!(mount_pattern("/team-a", "foo/"), "foo/team-a");
+
+    let config = Config {
+        targets: BTreeMap::from([(
+            "foo/*rest".to_string(),
+            Target {
+                host: Some("api.*.com".to_string()),
+                ..Default::default()
+            },
+        )]),
+        ..Default::default()
+    };
+    let errors = config.validate().unwrap_err();
+    assert!(errors.contains("target foo/*rest: host \"api.*.com\" must be a host name"), "{}", errors);
+}
+
+#[test]
+fn test_lifecycle_webhook_validation() {
+    let yaml = r#"
+lambda_function_name: default-function
+lifecycle:
+  webhook:
+    url: "http://events.internal:8080/gateway"
+"#;
+    let config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    assert!(config.validate().is_ok());
+    let webhook = config.lifecycle.webhook.as_ref().unwrap();
+    assert_eq!((webhook.queue_capacity, webhook.retries), (1000, 3));
+    assert_eq!(config.lifecycle.recent_events, 100);
+
+    for url in ["https://events.internal/gateway", "/gateway", ""] {
+        let mut config = config.clone();
+        config.lifecycle.webhook.as_mut().unwrap().url = url.to_string();
+        let errors = config.validate().unwrap_err();
+        assert!(errors.contains("must be an absolute http:// URL"), "{}: {}", url, errors);
+    }
+}
+
+#[test]
+fn test_clock_validation() {
+    let yaml = r#"
+lambda_function_name: f
+clock: { source_url: "https://time.example.com", interval_secs: 0, smoothing: 0 }
+"#;
+    let errors = Config::from_yaml(yaml, Path::new(".")).unwrap().validate().unwrap_err();
+    let url_error = "clock: source_url \"https://time.example.com\" must be an absolute http:// URL";
+    assert!(errors.contains(url_error), "{}", errors);
+    assert!(errors.contains("clock: interval_secs must be at least 1"), "{}", errors);
+    assert!(errors.contains("clock: smoothing must be above 0 and at most 1"), "{}", errors);
+
+    let yaml = "lambda_function_name: f\nclock: { source_url: \"http://169.254.169.254/\" }";
+    let config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    assert!(config.validate().is_ok());
+    assert!(config.clock.enabled && !config.clock.correct);
+}
+
+#[test]
+fn test_ndjson_validation() {
+    let yaml = r#"
+targets:
+  /events:
+    function: events
+    ndjson: { on_invalid: abort, filter: 'record("tenant") == key_id' }
+  /broken:
+    function: broken
+    ndjson: { filter: 'record(tenant) == key_id', max_record_bytes: 0 }
+"#;
+    let config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    let ndjson = config.targets["/events"].ndjson.as_ref().unwrap();
+    assert_eq!(ndjson.on_invalid, OnInvalidRecord::Abort);
+    assert_eq!(ndjson.max_record_bytes, 1024 * 1024);
+    let errors = config.validate().unwrap_err();
+    assert!(errors.contains("target /broken: ndjson.max_record_bytes must be at least 1"), "{}", errors);
+    assert!(errors.contains("target /broken: ndjson.filter: column 8"), "{}", errors);
+    assert!(!errors.contains("/events"), "{}", errors);
+}
+
+#[test]
+fn test_signed_urls_validation() {
+    let yaml = r#"
+lambda_function_name: f
+signed_urls: { signing_key: short, default_ttl_secs: 7200, max_ttl_secs: 3600 }
+targets:
+  /reports/*path: { function: reports, accept_signed_urls: true }
+"#;
+    let config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    let errors = config.validate().unwrap_err();
+    assert!(errors.contains("signed_urls: signing_key must be at least 32 bytes"), "{}", errors);
+    assert!(errors.contains("signed_urls: default_ttl_secs must be between 1"), "{}", errors);
+
+    let yaml = "lambda_function_name: f\ntargets:\n  /reports: { function: reports, accept_signed_urls: true }";
+    let errors = Config::from_yaml(yaml, Path::new(".")).unwrap().validate().unwrap_err();
+    assert!(errors.contains("target /reports: accept_signed_urls needs a signed_urls section"), "{}", errors);
+
+    let yaml = "lambda_function_name: f\nsigned_urls: { signing_key: 0123456789abcdef0123456789abcdef }";
+    let config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    assert!(config.validate().is_ok());
+    let signed_urls = config.signed_urls.unwrap();
+    assert_eq!((signed_urls.default_ttl_secs, signed_urls.max_ttl_secs), (3600, 86400));
+}
+
+#[test]
+fn test_auth_bans_and_breaker_store() {
+    let yaml = "lambda_function_name: f\ncircuit_breaker_store: redis\nauth_bans: { max_failures: 5 }";
+    let config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    assert!(config.validate().is_ok());
+    assert_eq!(config.circuit_breaker_store, StoreKind::Redis);
+    assert_eq!(config.rate_limit_store, StoreKind::Memory);
+    let bans = config.auth_bans.unwrap();
+    assert_eq!((bans.max_failures, bans.window_secs, bans.ban_secs), (5, 60, 600));
+    assert_eq!(bans.store, StoreKind::Memory);
+
+    let yaml = "lambda_function_name: f\nauth_bans: { ban_secs: 0 }";
+    let errors = Config::from_yaml(yaml, Path::new(".")).unwrap().validate().unwrap_err();
+    assert!(errors.contains("auth_bans: max_failures, window_secs and ban_secs must be above 0"), "{}", errors);
+}
+
+#[test]
+fn test_log_format() {
+    assert_eq!(Config::default().log_format, LogFormat::Text);
+    let config: Config = serde_yaml::from_str("log_format: json\naccess_log:\n  events: true").unwrap();
+    assert_eq!(config.log_format, LogFormat::Json);
+    assert!(config.access_log.events);
+    assert!(serde_yaml::from_str::<Config>("log_format: xml").is_err());
+}
+
+#[test]
+fn test_metrics_validation() {
+    let config = Config::from_yaml("lambda_function_name: f", Path::new(".")).unwrap();
+    assert_eq!(config.metrics, MetricsConfig { enabled: true, addr: None });
+
+    let yaml = "lambda_function_name: f\naddr: 0.0.0.0:8000\nmetrics: { addr: 0.0.0.0:8000 }";
+    let errors = Config::from_yaml(yaml, Path::new(".")).unwrap().validate().unwrap_err();
+    assert!(errors.contains("metrics: addr 0.0.0.0:8000 is bound by a listener"), "{}", errors);
+    let yaml = "lambda_function_name: f\nmetrics: { addr: localhost }";
+    let errors = Config::from_yaml(yaml, Path::new(".")).unwrap().validate().unwrap_err();
+    assert!(errors.contains("metrics: addr \"localhost\" must be host:port"), "{}", errors);
+    let yaml = "lambda_function_name: f\nmetrics: { addr: 127.0.0.1:9100 }";
+    assert!(Config::from_yaml(yaml, Path::new(".")).unwrap().validate().is_ok());
+}
+
+#[test]
+fn test_request_id_settings() {
+    let config = Config::from_yaml("lambda_function_name: f", Path::new(".")).unwrap();
+    assert_eq!(config.request_id.header, "x-request-id");
+    assert!(config.request_id.trust_incoming);
+
+    let yaml = "lambda_function_name: f\nrequest_id: { header: x-correlation-id, trust_incoming: false }";
+    let config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    assert_eq!(config.request_id.header, "x-correlation-id");
+    assert!(!config.request_id.trust_incoming);
+    assert!(config.validate().is_ok());
+    let yaml = "lambda_function_name: f\nrequest_id: { header: \"request id\" }";
+    let errors = Config::from_yaml(yaml, Path::new(".")).unwrap().validate().unwrap_err();
+    assert!(errors.contains("request_id: \"request id\" is not a valid header name"), "{}", errors);
+}
+
+#[test]
+fn test_tracing_propagation() {
+    assert_eq!(Config::default().tracing.propagation, None);
+    let config: Config = serde_yaml::from_str("tracing: { propagation: xray }").unwrap();
+    assert_eq!(config.tracing.propagation, Some(TracePropagation::Xray));
+    let config: Config = serde_yaml::from_str("tracing: { propagation: w3c }").unwrap();
+    assert_eq!(config.tracing.propagation, Some(TracePropagation::W3c));
+    assert!(serde_yaml::from_str::<Config>("tracing: { propagation: b3 }").is_err());
+}
+
+#[test]
+fn test_otel_settings() {
+    let config = Config::default();
+    assert_eq!(config.otel.endpoint, None);
+    assert_eq!((config.otel.protocol, config.otel.service_name.as_str()), (OtlpProtocol::Grpc, "lambda-web-gateway"));
+
+    let yaml = "{ lambda_function_name: f, otel: { endpoint: 'http://collector:4318', protocol: http_protobuf } }";
+    let config: Config = serde_yaml::from_str(yaml).unwrap();
+    assert_eq!(config.otel.protocol, OtlpProtocol::HttpProtobuf);
+    assert_eq!(config.validate(), Ok(()));
+
+    let yaml = "otel: { endpoint: 'collector:4317', service_name: '', timeout_ms: 0 }";
+    let err = serde_yaml::from_str::<Config>(yaml).unwrap().validate().unwrap_err();
+    assert!(err.contains("otel: endpoint \"collector:4317\" must be an http:// or https:// URL"), "{}", err);
+    assert!(err.contains("otel: service_name must not be empty"), "{}", err);
+    assert!(err.contains("otel: timeout_ms must be at least 1"), "{}", err);
+}
+
+#[test]
+fn test_compression_settings() {
+    let config = Config::default();
+    assert!(!config.compression.enabled);
+    assert_eq!(config.compression.min_bytes, 1024);
+    assert_eq!(config.compression.types, ["application/json", "text/*"]);
+
+    let yaml = "{ lambda_function_name: f, compression: { enabled: true, types: [text/*, application/xml] } }";
+    let config: Config = serde_yaml::from_str(yaml).unwrap();
+    assert_eq!(config.validate(), Ok(()));
+
+    let yaml = "compression: { types: [json, '*/*', text/] }";
+    let err = serde_yaml::from_str::<Config>(yaml).unwrap().validate().unwrap_err();
+    assert!(err.contains("compression: type \"json\" must be type/subtype or type/*"), "{}", err);
+    assert!(err.contains("compression: type \"*/*\" must be"), "{}", err);
+    assert!(err.contains("compression: type \"text/\" must be"), "{}", err);
+}
+
+#[test]
+fn test_shadow_settings() {
+    let yaml = r#"
+lambda_function_name: f
+targets:
+  /orders/*rest:
+    shadow:
+      function: orders-v2
+      qualifier: live
+      sample_percent: 10
+      compare: true
+      ignore_pointers: [/generated_at, /items/*/etag]
+"#;
+    let config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    assert_eq!(config.validate(), Ok(()));
+    let shadow = config.targets["/orders/*rest"].shadow.clone().unwrap();
+    assert_eq!((shadow.function.as_str(), shadow.qualifier.as_deref()), ("orders-v2", Some("live")));
+    assert_eq!((shadow.sample_percent, shadow.compare, shadow.timeout_ms), (10.0, true, 5000));
+    assert_eq!((shadow.headers, shadow.body), (vec!["content-type".to_string()], BodyComparison::Json));
+    assert_eq!(Target::default().shadow, None);
+
+    let yaml = r#"
+lambda_function_name: f
+targets:
+  /orders/*rest:
+    shadow:
+      qualifier: "bad qualifier"
+      sample_percent: 150
+      timeout_ms: 0
+      headers: ["bad header"]
+      ignore_pointers: [generated_at]
+      body: exact
+"#;
+    let err = Config::from_yaml(yaml, Path::new(".")).unwrap().validate().unwrap_err();
+    for expected in [
+        "target /orders/*rest: shadow.function must be set",
+        "shadow.sample_percent must be between 0 and 100",
+        "shadow.qualifier \"bad qualifier\" is not a valid alias or version",
+        "shadow.timeout_ms must be at least 1",
+        "shadow header \"bad header\" is not a valid header name",
+        "shadow ignore pointer \"generated_at\" must start with /",
+    ] {
+        assert!(err.contains(expected), "{}", err);
+    }
+}
+
+#[test]
+fn test_priority_classes() {
+    use axum::http::{HeaderMap, HeaderName, HeaderValue, Method};
+
+    let yaml = r#"
+lambda_function_name: f
+concurrency: { max_concurrency: 50, max_queue_depth: 100 }
+targets:
+  /reports/*rest:
+    priority: batch
+    priority_rules:
+      - { when: 'header("x-interactive") == "1"', priority: critical }
+"#;
+    let config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    assert_eq!(config.validate(), Ok(()));
+    assert_eq!(config.priority_weights, PriorityWeights::default());
+    let target = &config.targets["/reports/*rest"];
+    let priority_with = |headers: &HeaderMap| {
+        target.priority_of(&Attributes {
+            method: &Method::GET,
+            path: "/reports/1",
+            query: "",
+            headers,
+            client_ip: None,
+            key_id: None,
+            record: None,
+        })
+    };
+    assert_eq!(priority_with(&HeaderMap::new()), PriorityClass::Batch);
+    let interactive = HeaderMap::from_iter([(HeaderName::from_static("x-interactive"), HeaderValue::from_static("1"))]);
+    assert_eq!(priority_with(&interactive), PriorityClass::Critical);
+    assert_eq!(Target::default().priority, PriorityClass::Normal);
+
+    let yaml = r#"
+lambda_function_name: f
+concurrency: { max_concurrency: 0, queue_timeout_ms: 0 }
+priority_weights: { batch: 0 }
+targets:
+  /a:
+    priority_rules: [{ when: 'method ==', priority: batch }]
+"#;
+    let errors = Config::from_yaml(yaml, Path::new(".")).unwrap().validation_errors();
+    assert!(errors.contains(&"concurrency: max_concurrency must be at least 1".to_string()), "{:?}", errors);
+    assert!(errors.contains(&"concurrency: queue_timeout_ms must be at least 1".to_string()), "{:?}", errors);
+    assert!(errors.iter().any(|e| e.starts_with("priority_weights: batch must be at least 1")), "{:?}", errors);
+    assert!(errors.iter().any(|e| e.starts_with("target /a: priority condition \"method ==\"")), "{:?}", errors);
+}
+
+#[test]
+fn test_ipv6_listen_addresses() {
+    let with_addr = |addr: &str| {
+        let yaml = format!("lambda_function_name: f\naddr: \"{}\"\nserver: {{ dual_stack: true }}", addr);
+        Config::from_yaml(&yaml, Path::new(".")).unwrap()
+    };
+    for valid in ["[::]:8000", "[2001:db8::1]:443", "0.0.0.0:8000", "localhost:8000"] {
+        let config = with_addr(valid);
+        assert!(config.server.dual_stack);
+        assert_eq!(config.validate(), Ok(()), "{}", valid);
+    }
+    for invalid in ["::1:8000", ":::8000", "[::1]", "8000"] {
+        let errors = with_addr(invalid).validate().unwrap_err();
+        assert!(errors.contains("must be host:port, with IPv6 in brackets"), "{}: {}", invalid, errors);
+    }
+}
+
+#[test]
+fn test_server_settings() {
+    let yaml = r#"
+lambda_function_name: f
+server: { http2: true, max_header_bytes: 16384, max_concurrent_streams: 50, keepalive_secs: 30 }
+"#;
+    let config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    assert!(config.server.http2);
+    assert_eq!(
+        (config.server.max_header_bytes, config.server.max_concurrent_streams, config.server.keepalive_secs),
+        (16384, 50, 30)
+    );
+    assert_eq!(config.validate(), Ok(()));
+    let defaults = ServerConfig::default();
+    assert!(!defaults.http2 && defaults.keepalive_secs == 0);
+
+    let mut config = config;
+    config.server.max_header_bytes = 0;
+    config.server.max_concurrent_streams = 0;
+    let errors = config.validate().unwrap_err();
+    assert!(errors.contains("server: max_header_bytes must be at least 8192"), "{}", errors);
+    assert!(errors.contains("server: max_concurrent_streams must be at least 1"), "{}", errors);
+}
+
+#[test]
+fn test_config_source_parsing_and_validation() {
+    let yaml = r#"
+lambda_function_name: f
+config_source: { type: http, url: "http://control.internal/targets", poll_interval_secs: 15 }
+"#;
+    let config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    assert!(config.validate().is_ok());
+    let source = config.config_source.unwrap();
+    assert_eq!((source.poll_interval_secs, source.max_backoff_secs), (15, 600));
+    let ConfigSourceKind::Http(http) = source.kind else {
+        panic!("not an http source");
+    };
+    assert!(http.etag_caching);
+
+    let yaml = "lambda_function_name: f\nconfig_source: { type: dynamodb, table: routes, key: prod }";
+    let source = Config::from_yaml(yaml, Path::new(".")).unwrap().config_source.unwrap();
+    assert_eq!(
+        source.kind,
+        ConfigSourceKind::Dynamodb(DynamoDbSourceConfig {
+            table: "routes".to_string(),
+            key: "prod".to_string(),
+            ..Default::default()
+        })
+    );
+
+    let yaml = r#"
+lambda_function_name: f
+config_source: { type: http, url: "https://control.internal/targets", poll_interval_secs: 0 }
+"#;
+    let errors = Config::from_yaml(yaml, Path::new(".")).unwrap().validate().unwrap_err();
+    assert!(errors.contains("must be an absolute http:// URL"), "{}", errors);
+    assert!(errors.contains("config_source: poll_interval_secs must be at least 1"), "{}", errors);
+    let yaml = "lambda_function_name: f\nconfig_source: { type: dynamodb, table: routes }";
+    let errors = Config::from_yaml(yaml, Path::new(".")).unwrap().validate().unwrap_err();
+    assert!(errors.contains("config_source: table and key are required"), "{}", errors);
+}
+
+#[test]
+fn test_listener_validation() {
+    let yaml = r#"
+lambda_function_name: f
+targets:
+  /public: {}
+listeners:
+  external: { addr: "0.0.0.0:8080", targets: [/public, /missing] }
+  internal: { addr: "0.0.0.0:8080", admin: true }
+  metrics: { addr: "localhost" }
+"#;
+    let errors = Config::from_yaml(yaml, Path::new(".")).unwrap().validate().unwrap_err();
+    assert!(errors.contains("listener external: /missing is not a configured target"), "{}", errors);
+    assert!(errors.contains("listener internal: addr 0.0.0.0:8080 is bound by another listener"), "{}", errors);
+    assert!(errors.contains("listener metrics: addr \"localhost\" must be host:port"), "{}", errors);
+
+    let yaml = r#"
+lambda_function_name: f
+targets:
+  /public: { auth: ApiKey }
+  /internal: {}
+listeners:
+  external: { addr: "0.0.0.0:8080", targets: [/public] }
+  internal: { addr: "127.0.0.1:9090", admin: true }
+"#;
+    let config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    assert!(config.validate().is_ok());
+    let external = config.listener(Some("external"));
+    assert!(!external.admin && external.concurrency_limits && external.serves("/public"));
+    assert!(!external.serves("/internal"));
+    assert!(config.match_route_on_listener(external, None, "/internal").is_none());
+    assert!(config.listener(Some("internal")).admin);
+    // A listener no longer configured gets the defaults
+    assert!(!config.listener(Some("removed")).admin);
+    assert_eq!(
+        config.listener_addrs(),
+        [
+            ("external".to_string(), BindAddr::Tcp("0.0.0.0:8080".to_string())),
+            ("internal".to_string(), BindAddr::Tcp("127.0.0.1:9090".to_string()))
+        ]
+    );
+
+    // The public listener's default auth decides whether it serves anything unauthenticated
+    let mut open = config.clone();
+    open.security.require_auth_on_public_bind = true;
+    open.listeners.get_mut("external").unwrap().targets.clear();
+    let error = open.validate().unwrap_err();
+    assert!(error.contains("security: 0.0.0.0:8080 is not a loopback address"), "{}", error);
+    assert!(error.contains("the top-level function, /internal allow"), "{}", error);
+    open.listeners.get_mut("external").unwrap().auth_mode = Some(AuthMode::ApiKey);
+    assert!(open.validate().is_ok());
+}
+
+#[test]
+fn test_tls_listeners() {
+    let yaml = r#"
+lambda_function_name: f
+addr: "0.0.0.0:8080"
+tls: { cert: /etc/gateway/fullchain.pem, key: /etc/gateway/privkey.pem, addr: "0.0.0.0:8443" }
+"#;
+    let config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    let tls = config.tls.clone().unwrap();
+    assert_eq!((tls.cert.to_str(), tls.watch_interval_secs), (Some("/etc/gateway/fullchain.pem"), 60));
+    assert_eq!(
+        config.listener_addrs(),
+        [
+            (DEFAULT_LISTENER.to_string(), BindAddr::Tcp("0.0.0.0:8080".to_string())),
+            (TLS_LISTENER.to_string(), BindAddr::Tcp("0.0.0.0:8443".to_string()))
+        ]
+    );
+    assert!(config.listener_tls(TLS_LISTENER) && !config.listener_tls(DEFAULT_LISTENER));
+    // The files are read by validation, failing when they cannot be
+    let err = config.validate().unwrap_err();
+    assert!(err.contains("tls: "), "{}", err);
+
+    let yaml = r#"
+lambda_function_name: f
+listeners:
+  public: { addr: "0.0.0.0:8443", tls: true }
+  internal: { addr: "127.0.0.1:9090" }
+"#;
+    let mut config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    let err = config.validate().unwrap_err();
+    assert!(err.contains("listener public: tls needs the tls section with cert and key"), "{}", err);
+    config.tls = Some(TlsConfig {
+        addr: Some("0.0.0.0:8444".to_string()),
+        ..Default::default()
+    });
+    assert!(config.listener_tls("public") && !config.listener_tls("internal"));
+    let err = config.validate().unwrap_err();
+    assert!(err.contains("tls: addr is for use without listeners"), "{}", err);
+
+    let mut config = Config {
+        lambda_function_name: "f".to_string(),
+        tls: Some(TlsConfig::default()),
+        ..Default::default()
+    };
+    let err = config.validate().unwrap_err();
+    assert!(err.contains("tls: addr must be set to serve HTTPS without listeners"), "{}", err);
+    config.tls.as_mut().unwrap().addr = Some(config.addr.to_string());
+    let err = config.validate().unwrap_err();
+    assert!(err.contains("is bound by the plain listener"), "{}", err);
+}
+
+#[test]
+fn test_unix_socket_addresses() {
+    let yaml = r#"
+lambda_function_name: f
+auth_mode: Open
+security: { require_auth_on_public_bind: true }
+addr: unix:/run/gateway/gateway.sock
+server: { socket_mode: 0600 }
+"#;
+    let config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    assert_eq!(config.addr, BindAddr::Unix(PathBuf::from("/run/gateway/gateway.sock")));
+    assert_eq!(config.server.socket_mode, FileMode(0o600));
+    assert_eq!(Config::default().server.socket_mode, FileMode(0o660));
+    // Only clients on this host can connect, so open auth is not on a public bind
+    assert_eq!(config.validate(), Ok(()));
+    // As the support bundle shows them
+    let value = serde_json::to_value(&config).unwrap();
+    assert_eq!(value["addr"], "unix:/run/gateway/gateway.sock");
+    assert_eq!(value["server"]["socket_mode"], "0600");
+
+    assert_eq!("0.0.0.0:8000".parse(), Ok(BindAddr::Tcp("0.0.0.0:8000".to_string())));
+    assert!("unix:".parse::<BindAddr>().is_err());
+    assert_eq!("0o640".parse(), Ok(FileMode(0o640)));
+    for invalid in ["0800", "1777", "rw-rw----", ""] {
+        assert!(invalid.parse::<FileMode>().is_err(), "{}", invalid);
+    }
+    let yaml = "lambda_function_name: f\nserver: { socket_mode: 0999 }";
+    assert!(Config::from_yaml(yaml, Path::new(".")).is_err());
+
+    let yaml = r#"
+lambda_function_name: f
+listeners:
+  sidecar: { addr: "unix:/run/gateway.sock", admin: true }
+  local: { addr: "unix:/run/gateway.sock" }
+  public: { addr: "0.0.0.0:8080" }
+"#;
+    let config = Config::from_yaml(yaml, Path::new(".")).unwrap();
+    assert_eq!(config.listener_addrs()[2], ("sidecar".to_string(), BindAddr::Unix("/run/gateway.sock".into())));
+    let errors = config.validate().unwrap_err();
+    let duplicate = "listener sidecar: addr unix:/run/gateway.sock is bound by another listener";
+    assert!(errors.contains(duplicate), "{}", errors);
+}
+
+#[test]
+fn test_error_templates_loaded_within_size_limit() {
+    let dir = tempfile::tempdir().unwrap();
+    write_file(dir.path(), "error.html", "<h1>{{status}}</h1>");
+    write_file(dir.path(), "error.txt", &"x".repeat(100));
+    let path = write_file(
+        dir.path(),
+        "config.yaml",
+        "lambda_function_name: f\nerror_pages:\n  html_template: error.html\n  max_template_bytes: 64\n",
+    );
+    let config = Config::load_from_file(&path).unwrap();
+    assert_eq!(config.error_pages.html.as_deref(), Some("<h1>{{status}}</h1>"));
+    assert_eq!(config.error_pages.text, None);
+
+    let path = write_file(
+        dir.path(),
+        "config.yaml",
+        "lambda_function_name: f\nerror_pages:\n  text_template: error.txt\n  max_template_bytes: 64\n",
+    );
+    let err = Config::load_from_file(&path).unwrap_err().to_string();
+    assert!(err.contains("error_pages: error.txt is 100 bytes, over max_template_bytes of 64"), "{}", err);
+    let path = write_file(dir.path(), "config.yaml", "lambda_function_name: f\nerror_pages:\n  html_template: gone.html\n");
+    let err = Config::load_from_file(&path).unwrap_err().to_string();
+    assert!(err.contains("error_pages: failed to read gone.html"), "{}", err);
+}
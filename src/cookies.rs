@@ -0,0 +1,117 @@
+use crate::config::{CookieRewrite, SameSite};
+use axum::http::header::SET_COOKIE;
+use axum::http::{HeaderMap, HeaderValue};
+
+/// Applies `rewrite` to every `Set-Cookie` header, keeping their order. Values that are not valid
+/// UTF-8 are left alone.
+pub fn rewrite_set_cookie_headers(headers: &mut HeaderMap, rewrite: &CookieRewrite) {
+    let values: Vec<HeaderValue> = headers.get_all(SET_COOKIE).iter().cloned().collect();
+    if values.is_empty() {
+        return;
+    }
+    headers.remove(SET_COOKIE);
+    for value in values {
+        let rewritten = value
+            .to_str()
+            .ok()
+            .and_then(|cookie| HeaderValue::try_from(rewrite_cookie(cookie, rewrite)).ok());
+        headers.append(SET_COOKIE, rewritten.unwrap_or(value));
+    }
+}
+
+/// A `Set-Cookie` value split into the cookie itself and its attributes, in their original order
+/// and spelling.
+struct SetCookie<'a> {
+    /// `name=value`, which may itself contain `=`.
+    pair: &'a str,
+    attributes: Vec<(String, Option<String>)>,
+}
+
+impl<'a> SetCookie<'a> {
+    fn parse(value: &'a str) -> Self {
+        let mut parts = value.split(';');
+        let pair = parts.next().unwrap_or_default().trim();
+        let attributes = parts
+            .map(str::trim)
+            .filter(|attribute| !attribute.is_empty())
+            .map(|attribute| match attribute.split_once('=') {
+                Some((name, value)) => (name.trim().to_string(), Some(value.trim().to_string())),
+                None => (attribute.to_string(), None),
+            })
+            .collect();
+        Self { pair, attributes }
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| attribute.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_deref().unwrap_or_default())
+    }
+
+    /// Sets the value of the first attribute called `name`, appending it when there is none.
+    fn set(&mut self, name: &str, value: Option<&str>) {
+        let value = value.map(String::from);
+        match self
+            .attributes
+            .iter_mut()
+            .find(|(attribute, _)| attribute.eq_ignore_ascii_case(name))
+        {
+            Some(attribute) => attribute.1 = value,
+            None => self.attributes.push((name.to_string(), value)),
+        }
+    }
+
+    fn remove(&mut self, name: &str) {
+        self.attributes
+            .retain(|(attribute, _)| !attribute.eq_ignore_ascii_case(name));
+    }
+
+    fn serialize(&self) -> String {
+        let mut serialized = self.pair.to_string();
+        for (name, value) in &self.attributes {
+            serialized.push_str("; ");
+            serialized.push_str(name);
+            if let Some(value) = value {
+                serialized.push('=');
+                serialized.push_str(value);
+            }
+        }
+        serialized
+    }
+}
+
+/// Rewrites one `Set-Cookie` value. Attribute names are matched case-insensitively.
+pub fn rewrite_cookie(value: &str, rewrite: &CookieRewrite) -> String {
+    let mut cookie = SetCookie::parse(value);
+    if let Some(prefix) = &rewrite.path_prefix {
+        // A path not starting with `/` is ignored by browsers, who use the default path instead
+        if let Some(path) = cookie.get("path").filter(|path| path.starts_with('/')) {
+            let prefix = prefix.trim_end_matches('/');
+            let path = match path {
+                "/" if !prefix.is_empty() => prefix.to_string(),
+                path => format!("{}{}", prefix, path),
+            };
+            cookie.set("Path", Some(&path));
+        }
+    }
+    if rewrite.strip_domain {
+        cookie.remove("domain");
+    } else if let Some(domain) = &rewrite.domain {
+        if cookie.get("domain").is_some() {
+            cookie.set("Domain", Some(domain));
+        }
+    }
+    if let Some(same_site) = rewrite.same_site {
+        cookie.set("SameSite", Some(same_site.as_str()));
+    }
+    if rewrite.secure || rewrite.same_site == Some(SameSite::None) {
+        cookie.set("Secure", None);
+    }
+    cookie.serialize()
+}
+
+#[cfg(test)]
+mod tests {
+    include!("cookies_tests.rs");
+}
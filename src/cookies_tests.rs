@@ -0,0 +1,87 @@
+use super::*;
+
+fn rewrite_with(configure: impl FnOnce(&mut CookieRewrite)) -> CookieRewrite {
+    let mut rewrite = CookieRewrite::default();
+    configure(&mut rewrite);
+    rewrite
+}
+
+#[test]
+fn test_path_prefix() {
+    let rewrite = rewrite_with(|r| r.path_prefix = Some("/app/".to_string()));
+    let cases = [
+        ("sid=1; Path=/", "sid=1; Path=/app"),
+        ("sid=1; path=/account", "sid=1; path=/app/account"),
+        ("sid=1; PATH = /a/b ; HttpOnly", "sid=1; PATH=/app/a/b; HttpOnly"),
+        // No path, or one browsers ignore, keeps the default path of the public URL
+        ("sid=1; HttpOnly", "sid=1; HttpOnly"),
+        ("sid=1; Path=relative", "sid=1; Path=relative"),
+    ];
+    for (input, expected) in cases {
+        assert_eq!(rewrite_cookie(input, &rewrite), expected, "{}", input);
+    }
+}
+
+#[test]
+fn test_domain() {
+    let replace = rewrite_with(|r| r.domain = Some("example.com".to_string()));
+    let strip = rewrite_with(|r| r.strip_domain = true);
+    let cases = [
+        (&replace, "sid=1; Domain=internal.local; Path=/", "sid=1; Domain=example.com; Path=/"),
+        (&replace, "sid=1; domain=.internal.local", "sid=1; domain=example.com"),
+        // Host-only cookies stay host-only
+        (&replace, "sid=1; Path=/", "sid=1; Path=/"),
+        (&strip, "sid=1; Domain=internal.local; Path=/", "sid=1; Path=/"),
+        (&strip, "sid=1; DOMAIN=a; domain=b", "sid=1"),
+        (&strip, "sid=1", "sid=1"),
+    ];
+    for (rewrite, input, expected) in cases {
+        assert_eq!(rewrite_cookie(input, rewrite), expected, "{}", input);
+    }
+}
+
+#[test]
+fn test_secure_and_same_site() {
+    let secure = rewrite_with(|r| r.secure = true);
+    let lax = rewrite_with(|r| r.same_site = Some(SameSite::Lax));
+    let none = rewrite_with(|r| r.same_site = Some(SameSite::None));
+    let cases = [
+        (&secure, "sid=1", "sid=1; Secure"),
+        (&secure, "sid=1; secure", "sid=1; secure"),
+        (&lax, "sid=1; samesite=Strict; Path=/", "sid=1; samesite=Lax; Path=/"),
+        (&lax, "sid=1", "sid=1; SameSite=Lax"),
+        (&none, "sid=1; HttpOnly", "sid=1; HttpOnly; SameSite=None; Secure"),
+    ];
+    for (rewrite, input, expected) in cases {
+        assert_eq!(rewrite_cookie(input, rewrite), expected, "{}", input);
+    }
+}
+
+#[test]
+fn test_values_with_equals_signs_survive() {
+    let rewrite = rewrite_with(|r| {
+        r.path_prefix = Some("/app".to_string());
+        r.secure = true;
+    });
+    assert_eq!(
+        rewrite_cookie("token=a=b==; Path=/; Expires=Wed, 21 Oct 2026 07:28:00 GMT", &rewrite),
+        "token=a=b==; Path=/app; Expires=Wed, 21 Oct 2026 07:28:00 GMT; Secure"
+    );
+    assert_eq!(rewrite_cookie("=empty-name", &CookieRewrite::default()), "=empty-name");
+}
+
+#[test]
+fn test_rewrite_headers_keeps_order() {
+    let mut headers = HeaderMap::new();
+    headers.append(SET_COOKIE, HeaderValue::from_static("b=2; Path=/"));
+    headers.append(SET_COOKIE, HeaderValue::from_bytes(b"bin=\xff; Path=/").unwrap());
+    headers.append(SET_COOKIE, HeaderValue::from_static("a=1; Path=/x"));
+    headers.insert("content-type", HeaderValue::from_static("text/plain"));
+    let rewrite = rewrite_with(|r| r.path_prefix = Some("/app".to_string()));
+
+    rewrite_set_cookie_headers(&mut headers, &rewrite);
+
+    let values: Vec<&[u8]> = headers.get_all(SET_COOKIE).iter().map(|v| v.as_bytes()).collect();
+    assert_eq!(values, [&b"b=2; Path=/app"[..], b"bin=\xff; Path=/", b"a=1; Path=/app/x"]);
+    assert_eq!(headers["content-type"], "text/plain");
+}
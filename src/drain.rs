@@ -0,0 +1,249 @@
+use crate::config::{Config, Target};
+use axum::body::{Body, Bytes};
+use http_body::Frame;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Called with a retired target once its last request has finished, to free per-target state.
+pub type RetiredHook = Arc<dyn Fn(&str, &Target) + Send + Sync>;
+
+/// Counts requests in flight per target generation. A reload that removes or changes a target
+/// retires its generation: requests already running may finish until the drain deadline, after
+/// which their streams are aborted, and the retired state is freed once the last one is done.
+#[derive(Clone)]
+pub struct TargetTracker {
+    inner: Arc<Mutex<Generations>>,
+    on_retired: RetiredHook,
+}
+
+#[derive(Default)]
+struct Generations {
+    next_id: u64,
+    /// Current generation of each configured target pattern.
+    current: HashMap<String, u64>,
+    all: HashMap<u64, Generation>,
+}
+
+struct Generation {
+    pattern: String,
+    target: Target,
+    active: usize,
+    retired_at: Option<Instant>,
+    deadline: watch::Sender<Option<Instant>>,
+}
+
+/// A target generation still serving requests after a reload retired it, as shown on `/status`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DrainingTarget {
+    pub pattern: String,
+    pub generation: u64,
+    pub active_requests: usize,
+    /// Time left before remaining streams are aborted.
+    pub remaining_ms: u64,
+}
+
+impl Generations {
+    fn start(&mut self, pattern: &str, target: &Target) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.current.insert(pattern.to_string(), id);
+        self.all.insert(
+            id,
+            Generation {
+                pattern: pattern.to_string(),
+                target: target.clone(),
+                active: 0,
+                retired_at: None,
+                deadline: watch::channel(None).0,
+            },
+        );
+    }
+}
+
+impl TargetTracker {
+    pub fn new(config: &Config, on_retired: RetiredHook) -> Self {
+        let mut generations = Generations::default();
+        for (pattern, target) in &config.targets {
+            generations.start(pattern, target);
+        }
+        Self {
+            inner: Arc::new(Mutex::new(generations)),
+            on_retired,
+        }
+    }
+
+    /// Registers a request for the current generation of `pattern`. Returns `None` for paths
+    /// served by the top-level function, which have no target to drain.
+    pub fn enter(&self, pattern: &str) -> Option<ActiveRequest> {
+        let mut generations = self.inner.lock().unwrap();
+        let id = *generations.current.get(pattern)?;
+        let generation = generations.all.get_mut(&id)?;
+        generation.active += 1;
+        Some(ActiveRequest {
+            tracker: self.clone(),
+            generation: id,
+            drain: DrainSignal(generation.deadline.subscribe()),
+        })
+    }
+
+    /// Retires the generations of targets that `new` removes or changes and starts generations
+    /// for the targets it adds or changes.
+    pub fn reload(&self, new: &Config, drain_timeout: Duration) {
+        let mut retired = Vec::new();
+        {
+            let mut generations = self.inner.lock().unwrap();
+            let now = Instant::now();
+            let patterns: Vec<(String, u64)> = generations.current.iter().map(|(p, id)| (p.clone(), *id)).collect();
+            for (pattern, id) in patterns {
+                let unchanged = new.targets.get(&pattern) == generations.all.get(&id).map(|g| &g.target);
+                if unchanged {
+                    continue;
+                }
+                generations.current.remove(&pattern);
+                let generation = generations.all.get_mut(&id).expect("current generation exists");
+                generation.retired_at = Some(now);
+                generation.deadline.send_replace(Some(now + drain_timeout));
+                tracing::info!(
+                    pattern,
+                    generation = id,
+                    active_requests = generation.active,
+                    "Draining target retired by reload"
+                );
+                if generation.active == 0 {
+                    retired.extend(generations.all.remove(&id));
+                }
+            }
+            for (pattern, target) in &new.targets {
+                if !generations.current.contains_key(pattern) {
+                    generations.start(pattern, target);
+                }
+            }
+        }
+        for generation in retired {
+            (self.on_retired)(&generation.pattern, &generation.target);
+        }
+    }
+
+    /// Retired generations that still have requests in flight.
+    pub fn draining(&self) -> Vec<DrainingTarget> {
+        let generations = self.inner.lock().unwrap();
+        let mut draining: Vec<DrainingTarget> = generations
+            .all
+            .iter()
+            .filter(|(_, g)| g.retired_at.is_some())
+            .map(|(id, g)| DrainingTarget {
+                pattern: g.pattern.clone(),
+                generation: *id,
+                active_requests: g.active,
+                remaining_ms: g
+                    .deadline
+                    .borrow()
+                    .map_or(0, |d| d.saturating_duration_since(Instant::now()).as_millis() as u64),
+            })
+            .collect();
+        draining.sort_by_key(|d| d.generation);
+        draining
+    }
+
+    fn leave(&self, id: u64) {
+        let retired = {
+            let mut generations = self.inner.lock().unwrap();
+            let Some(generation) = generations.all.get_mut(&id) else {
+                return;
+            };
+            generation.active -= 1;
+            if generation.active > 0 || generation.retired_at.is_none() {
+                return;
+            }
+            generations.all.remove(&id).expect("generation exists")
+        };
+        tracing::info!(pattern = retired.pattern, generation = id, "Retired target drained");
+        (self.on_retired)(&retired.pattern, &retired.target);
+    }
+}
+
+/// A request counted against its target generation until dropped.
+pub struct ActiveRequest {
+    tracker: TargetTracker,
+    generation: u64,
+    pub drain: DrainSignal,
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.tracker.leave(self.generation);
+    }
+}
+
+/// A response body that keeps a guard, such as its request's [`ActiveRequest`], alive until the
+/// body has been sent or dropped.
+pub struct TrackedBody<G> {
+    inner: Body,
+    _guard: G,
+}
+
+impl<G> TrackedBody<G> {
+    pub fn new(inner: Body, guard: G) -> Self {
+        Self { inner, _guard: guard }
+    }
+}
+
+impl<G: Unpin> http_body::Body for TrackedBody<G> {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Resolves once the request's target generation has been retired and its drain deadline has
+/// passed.
+#[derive(Clone, Debug)]
+pub struct DrainSignal(watch::Receiver<Option<Instant>>);
+
+impl DrainSignal {
+    pub async fn expired(mut self) {
+        loop {
+            let deadline = *self.0.borrow_and_update();
+            if let Some(deadline) = deadline {
+                tokio::time::sleep_until(deadline.into()).await;
+                return;
+            }
+            if self.0.changed().await.is_err() {
+                // The generation is gone, so it can no longer be retired
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+/// Error ending a stream that outlived the drain deadline of its retired target.
+#[derive(Debug)]
+pub struct Drained;
+
+impl std::fmt::Display for Drained {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("stream aborted: its target was removed or changed by a reload")
+    }
+}
+
+impl std::error::Error for Drained {}
+
+#[cfg(test)]
+mod tests {
+    include!("drain_tests.rs");
+}
@@ -0,0 +1,83 @@
+use super::*;
+
+fn config_with(targets: &[(&str, &str)]) -> Config {
+    let mut config = Config::default();
+    for (pattern, function) in targets {
+        let target = Target {
+            function: Some((*function).into()),
+            ..Default::default()
+        };
+        config.targets.insert(pattern.to_string(), target);
+    }
+    config
+}
+
+fn tracker(config: &Config) -> (TargetTracker, Arc<Mutex<Vec<String>>>) {
+    let retired = Arc::new(Mutex::new(Vec::new()));
+    let hook_log = retired.clone();
+    let hook: RetiredHook = Arc::new(move |pattern: &str, _: &Target| {
+        hook_log.lock().unwrap().push(pattern.to_string());
+    });
+    (TargetTracker::new(config, hook), retired)
+}
+
+#[test]
+fn test_idle_target_is_freed_on_reload() {
+    let (tracker, retired) = tracker(&config_with(&[("/a", "fn-a"), ("/b", "fn-b")]));
+
+    tracker.reload(&config_with(&[("/b", "fn-b")]), Duration::from_secs(60));
+
+    assert_eq!(*retired.lock().unwrap(), vec!["/a"]);
+    assert!(tracker.draining().is_empty());
+    assert!(tracker.enter("/a").is_none());
+}
+
+#[test]
+fn test_changed_target_drains_until_last_request() {
+    let (tracker, retired) = tracker(&config_with(&[("/a", "fn-a")]));
+    let old = tracker.enter("/a").unwrap();
+
+    tracker.reload(&config_with(&[("/a", "fn-a2")]), Duration::from_secs(60));
+
+    let draining = tracker.draining();
+    assert_eq!(draining.len(), 1);
+    assert_eq!((draining[0].pattern.as_str(), draining[0].active_requests), ("/a", 1));
+    assert!(draining[0].remaining_ms > 59_000);
+
+    // New requests belong to the new generation and do not hold up the old one
+    let new = tracker.enter("/a").unwrap();
+    assert_ne!(new.generation, old.generation);
+    assert!(retired.lock().unwrap().is_empty());
+
+    drop(old);
+    assert_eq!(*retired.lock().unwrap(), vec!["/a"]);
+    assert!(tracker.draining().is_empty());
+}
+
+#[test]
+fn test_unchanged_target_keeps_its_generation() {
+    let config = config_with(&[("/a", "fn-a")]);
+    let (tracker, retired) = tracker(&config);
+    let before = tracker.enter("/a").unwrap();
+
+    tracker.reload(&config, Duration::from_secs(60));
+
+    assert_eq!(tracker.enter("/a").unwrap().generation, before.generation);
+    assert!(tracker.draining().is_empty());
+    assert!(retired.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_drain_signal_fires_at_deadline() {
+    let (tracker, _) = tracker(&config_with(&[("/a", "fn-a"), ("/b", "fn-b")]));
+    let retiring = tracker.enter("/a").unwrap();
+    let kept = tracker.enter("/b").unwrap();
+
+    tracker.reload(&config_with(&[("/b", "fn-b")]), Duration::from_millis(50));
+
+    let started = Instant::now();
+    retiring.drain.clone().expired().await;
+    assert!(started.elapsed() >= Duration::from_millis(40));
+    let kept_expired = tokio::time::timeout(Duration::from_millis(100), kept.drain.clone().expired()).await;
+    assert!(kept_expired.is_err());
+}
@@ -0,0 +1,143 @@
+use axum::http::header::RETRY_AFTER;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+/// Where in the request pipeline an error happened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorPhase {
+    /// Reading and decoding the client request.
+    Ingress,
+    /// Authenticating the client.
+    Auth,
+    /// Building the Lambda payload.
+    Build,
+    /// Calling Lambda.
+    Invoke,
+    /// Interpreting the function's response.
+    Upstream,
+    /// Sending the response to the client.
+    Egress,
+}
+
+impl ErrorPhase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorPhase::Ingress => "ingress",
+            ErrorPhase::Auth => "auth",
+            ErrorPhase::Build => "build",
+            ErrorPhase::Invoke => "invoke",
+            ErrorPhase::Upstream => "upstream",
+            ErrorPhase::Egress => "egress",
+        }
+    }
+}
+
+/// An error the gateway reports to the client itself, rendered as the standard JSON error body
+/// `{"error_code": "<code>", "phase": "<phase>", "message": "<message>"}`, plus any details.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GatewayError {
+    pub phase: ErrorPhase,
+    pub status: StatusCode,
+    /// Short machine-readable identifier, e.g. `payload_too_large`.
+    pub code: &'static str,
+    pub message: String,
+    /// Seconds sent in `Retry-After`, for errors the client should retry later.
+    pub retry_after_secs: Option<u64>,
+    /// Further fields of the JSON body, such as the `error_type` of a failed function.
+    pub details: Map<String, Value>,
+}
+
+/// Phase and code of a [`GatewayError`], attached to the extensions of the response it produced so
+/// metrics and the access log can report them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ErrorInfo {
+    pub phase: ErrorPhase,
+    pub code: &'static str,
+}
+
+impl ErrorInfo {
+    /// Class of an error calling Lambda or reading the function's answer, counted in
+    /// `invoke_errors_total`: `throttle`, `function_error`, `deserialize`, `timeout` or `other`.
+    /// `None` for errors of the other phases and for invokes an open circuit breaker refused.
+    pub fn invoke_error_class(&self) -> Option<&'static str> {
+        if !matches!(self.phase, ErrorPhase::Invoke | ErrorPhase::Upstream) || self.code == "circuit_open" {
+            return None;
+        }
+        Some(match self.code {
+            "function_throttled" => "throttle",
+            "function_error" => "function_error",
+            "invalid_upstream_response" | "invalid_upstream_header" => "deserialize",
+            "invoke_timeout" | "prelude_timeout" => "timeout",
+            _ => "other",
+        })
+    }
+}
+
+/// Message of a [`GatewayError`], attached to the extensions of the response it produced so the
+/// body can be rendered again in another format.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrorMessage(pub String);
+
+impl GatewayError {
+    pub fn new(phase: ErrorPhase, status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            phase,
+            status,
+            code,
+            message: message.into(),
+            retry_after_secs: None,
+            details: Map::new(),
+        }
+    }
+
+    pub fn with_retry_after(mut self, secs: u64) -> Self {
+        self.retry_after_secs = Some(secs);
+        self
+    }
+
+    pub fn with_detail(mut self, name: &str, value: impl Into<Value>) -> Self {
+        self.details.insert(name.to_string(), value.into());
+        self
+    }
+}
+
+impl std::fmt::Display for GatewayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({} in {}): {}",
+            self.status,
+            self.code,
+            self.phase.as_str(),
+            self.message
+        )
+    }
+}
+
+impl std::error::Error for GatewayError {}
+
+impl IntoResponse for GatewayError {
+    fn into_response(self) -> Response {
+        let mut body = json!({
+            "error_code": self.code,
+            "phase": self.phase,
+            "message": self.message,
+        });
+        if let Value::Object(fields) = &mut body {
+            fields.extend(self.details);
+        }
+        let mut resp = (self.status, axum::Json(body)).into_response();
+        if let Some(secs) = self.retry_after_secs {
+            resp.headers_mut().insert(RETRY_AFTER, secs.into());
+        }
+        resp.extensions_mut().insert(ErrorInfo {
+            phase: self.phase,
+            code: self.code,
+        });
+        resp.extensions_mut().insert(ErrorMessage(self.message));
+        resp
+    }
+}
@@ -0,0 +1,159 @@
+use crate::config::{ErrorFormat, ErrorPagesConfig};
+use axum::http::StatusCode;
+
+/// Page for `html` error bodies when `error_pages.html_template` is unset.
+pub const DEFAULT_HTML_TEMPLATE: &str = "<!DOCTYPE html>
+<html>
+<head><meta charset=\"utf-8\"><title>{{status}} {{reason}}</title></head>
+<body>
+<h1>{{status}} {{reason}}</h1>
+<p>{{message}}</p>
+<p><small>Error code: {{error_code}}. Request ID: {{request_id}}</small></p>
+</body>
+</html>
+";
+
+/// Text for `text` error bodies when `error_pages.text_template` is unset.
+pub const DEFAULT_TEXT_TEMPLATE: &str = "{{status}} {{reason}}: {{message}}
+error_code: {{error_code}}
+request_id: {{request_id}}
+";
+
+/// What an error page can show, each available to templates as `{{name}}`.
+#[derive(Clone, Debug)]
+pub struct ErrorPage<'a> {
+    pub status: StatusCode,
+    pub error_code: &'a str,
+    pub phase: &'a str,
+    pub message: &'a str,
+    /// The request's `x-request-id`, when known.
+    pub request_id: &'a str,
+}
+
+impl ErrorPage<'_> {
+    fn value(&self, name: &str) -> Option<String> {
+        Some(match name {
+            "status" => self.status.as_u16().to_string(),
+            "reason" => self.status.canonical_reason().unwrap_or_default().to_string(),
+            "error_code" => self.error_code.to_string(),
+            "phase" => self.phase.to_string(),
+            "message" => self.message.to_string(),
+            "request_id" => self.request_id.to_string(),
+            _ => return None,
+        })
+    }
+}
+
+/// The format an `Accept` header prefers among JSON, HTML and plain text. Each is weighed by the
+/// most specific range matching it, so `text/*;q=0.5, text/html` weighs HTML 1 and plain text
+/// 0.5. Equal weights go to JSON, then HTML, so `*/*` and a missing header get JSON.
+pub fn negotiate(accept: Option<&str>) -> ErrorFormat {
+    let Some(accept) = accept else {
+        return ErrorFormat::Json;
+    };
+    let ranges: Vec<(&str, f32)> = accept
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let media = params.next()?.trim();
+            let q = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse().ok())?;
+            (!media.is_empty()).then_some((media, q))
+        })
+        .collect();
+    let quality = |media: &str| {
+        let (kind, _) = media.split_once('/').unwrap_or_default();
+        ranges
+            .iter()
+            .filter_map(|(range, q)| {
+                let specificity = if range.eq_ignore_ascii_case(media) {
+                    3
+                } else if range.split_once('/') == Some((kind, "*")) {
+                    2
+                } else if *range == "*/*" {
+                    1
+                } else {
+                    return None;
+                };
+                Some((specificity, *q))
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map_or(0.0, |(_, q)| q)
+    };
+    let mut best = (ErrorFormat::Json, quality("application/json"));
+    for (format, media) in [(ErrorFormat::Html, "text/html"), (ErrorFormat::Text, "text/plain")] {
+        let q = quality(media);
+        if q > best.1 {
+            best = (format, q);
+        }
+    }
+    best.0
+}
+
+/// Renders `page` as `format`, returning the content type and body; `None` for JSON, which
+/// [`crate::error::GatewayError`] renders itself.
+pub fn render(format: ErrorFormat, config: &ErrorPagesConfig, page: &ErrorPage) -> Option<(&'static str, String)> {
+    match format {
+        ErrorFormat::Json => None,
+        ErrorFormat::Html => {
+            let template = config.html.as_deref().unwrap_or(DEFAULT_HTML_TEMPLATE);
+            let body = substitute(template, |name| page.value(name).map(|value| escape_html(&value)));
+            Some(("text/html; charset=utf-8", body))
+        }
+        ErrorFormat::Text => {
+            let template = config.text.as_deref().unwrap_or(DEFAULT_TEXT_TEMPLATE);
+            Some((
+                "text/plain; charset=utf-8",
+                substitute(template, |name| page.value(name)),
+            ))
+        }
+    }
+}
+
+/// Replaces each `{{name}}` in `template` in a single pass, so values are never substituted
+/// again. Unknown names are left as they are.
+pub fn substitute(template: &str, value: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let replaced = after
+            .find("}}")
+            .and_then(|end| Some((end, value(after[..end].trim())?)));
+        match replaced {
+            Some((end, value)) => {
+                out.push_str(&value);
+                rest = &after[end + 2..];
+            }
+            None => {
+                out.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Escapes text for HTML element content and quoted attribute values.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    include!("error_pages_tests.rs");
+}
@@ -0,0 +1,71 @@
+use super::*;
+
+fn page<'a>(message: &'a str, request_id: &'a str) -> ErrorPage<'a> {
+    ErrorPage {
+        status: StatusCode::SERVICE_UNAVAILABLE,
+        error_code: "target_closed",
+        phase: "ingress",
+        message,
+        request_id,
+    }
+}
+
+#[test]
+fn test_negotiate_precedence() {
+    let cases = [
+        (None, ErrorFormat::Json),
+        (Some("*/*"), ErrorFormat::Json),
+        (Some("application/json"), ErrorFormat::Json),
+        (Some("text/plain"), ErrorFormat::Text),
+        (Some("text/*"), ErrorFormat::Html),
+        (Some("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"), ErrorFormat::Html),
+        (Some("application/json;q=0.5, text/plain"), ErrorFormat::Text),
+        (Some("text/*;q=0.5, text/html;q=0.1, */*;q=0.2"), ErrorFormat::Text),
+        (Some("text/html;q=0, */*"), ErrorFormat::Json),
+        (Some("TEXT/HTML"), ErrorFormat::Html),
+        (Some("image/png"), ErrorFormat::Json),
+        (Some("text/html;q=bad, text/plain;q=0.3"), ErrorFormat::Text),
+    ];
+    for (accept, expected) in cases {
+        assert_eq!(negotiate(accept), expected, "{:?}", accept);
+    }
+}
+
+#[test]
+fn test_render_html_escapes_values() {
+    let config = ErrorPagesConfig::default();
+    let (content_type, body) = render(
+        ErrorFormat::Html,
+        &config,
+        &page("<script>alert('x')</script> & more", "id\"1"),
+    )
+    .unwrap();
+    assert_eq!(content_type, "text/html; charset=utf-8");
+    assert!(body.contains("<title>503 Service Unavailable</title>"), "{}", body);
+    assert!(body.contains("&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt; &amp; more"), "{}", body);
+    assert!(body.contains("Request ID: id&quot;1"), "{}", body);
+    assert!(!body.contains("<script>"), "{}", body);
+}
+
+#[test]
+fn test_render_custom_templates() {
+    let config = ErrorPagesConfig {
+        html: Some("<p>{{ status }} {{error_code}} {{unknown}}</p>".to_string()),
+        text: Some("{{status}} {{message}} ({{phase}}, {{request_id}})".to_string()),
+        ..Default::default()
+    };
+    // A value that looks like a placeholder is not substituted again
+    let page = page("see {{request_id}} <now>", "req-1");
+    let (_, html) = render(ErrorFormat::Html, &config, &page).unwrap();
+    assert_eq!(html, "<p>503 target_closed {{unknown}}</p>");
+    let (content_type, text) = render(ErrorFormat::Text, &config, &page).unwrap();
+    assert_eq!(content_type, "text/plain; charset=utf-8");
+    assert_eq!(text, "503 see {{request_id}} <now> (ingress, req-1)");
+    assert!(render(ErrorFormat::Json, &config, &page).is_none());
+}
+
+#[test]
+fn test_substitute_leaves_unclosed_braces() {
+    assert_eq!(substitute("a {{ b", |_| Some("x".to_string())), "a {{ b");
+    assert_eq!(substitute("{{a}}{{b}}", |name| Some(name.to_uppercase())), "AB");
+}
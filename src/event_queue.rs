@@ -0,0 +1,193 @@
+use crate::access_log::now_ms;
+use crate::config::EventRetryConfig;
+use crate::invoker::{InvokeError, InvokeRequest, Invoker};
+use crate::telemetry::Telemetry;
+use futures::StreamExt;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Asynchronous invokes waiting for another attempt after a throttle or conflict. The client has
+/// already been answered `202`, so an invoke leaves the queue either delivered or dead-lettered.
+#[derive(Clone)]
+pub struct EventQueue {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    config: EventRetryConfig,
+    invoker: Arc<dyn Invoker>,
+    telemetry: Telemetry,
+    pending: Mutex<VecDeque<Pending>>,
+    wake: Notify,
+}
+
+struct Pending {
+    request: InvokeRequest,
+    /// Attempts made so far, the first one included.
+    attempts: u32,
+    due: Instant,
+}
+
+/// A line of the dead-letter file.
+#[derive(Debug, Serialize)]
+struct DeadLetter<'a> {
+    timestamp_ms: u128,
+    function: &'a str,
+    reason: &'static str,
+    attempts: u32,
+    error: String,
+    payload: &'a str,
+}
+
+impl EventQueue {
+    pub fn new(config: &EventRetryConfig, invoker: Arc<dyn Invoker>, telemetry: Telemetry) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                config: config.clone(),
+                invoker,
+                telemetry,
+                pending: Mutex::new(VecDeque::new()),
+                wake: Notify::new(),
+            }),
+        }
+    }
+
+    /// Submits an asynchronous invoke. Throttles and conflicts are queued for a retry and reported
+    /// as accepted without a request ID; other errors are returned to the caller.
+    pub async fn submit(&self, request: InvokeRequest) -> Result<Option<String>, InvokeError> {
+        match self.inner.invoker.invoke_event(request.clone()).await {
+            Err(e) if is_retryable(&e) && self.inner.config.capacity > 0 => {
+                tracing::warn!(function = %request.function_name, "Queueing asynchronous invoke for a retry: {}", e);
+                self.requeue(request, 1, e);
+                Ok(None)
+            }
+            result => result,
+        }
+    }
+
+    /// Invokes waiting for a retry.
+    pub fn depth(&self) -> usize {
+        self.inner.pending.lock().unwrap().len()
+    }
+
+    /// Retries queued invokes as they come due, at most `concurrency` at a time.
+    pub fn spawn_drainer(&self) -> tokio::task::JoinHandle<()> {
+        let queue = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let due = queue.take_due(Instant::now());
+                futures::stream::iter(due)
+                    .for_each_concurrent(queue.inner.config.concurrency.max(1), |pending| queue.retry(pending))
+                    .await;
+                let wait = queue.next_due().map_or(Duration::from_secs(60), |due| {
+                    due.saturating_duration_since(Instant::now())
+                });
+                tokio::select! {
+                    _ = queue.inner.wake.notified() => {}
+                    _ = tokio::time::sleep(wait) => {}
+                }
+            }
+        })
+    }
+
+    async fn retry(&self, pending: Pending) {
+        let function = pending.request.function_name.clone();
+        match self.inner.invoker.invoke_event(pending.request.clone()).await {
+            Ok(_) => {
+                tracing::info!(function = %function, attempts = pending.attempts + 1, "Delivered queued asynchronous invoke");
+                self.inner
+                    .telemetry
+                    .increment("event_retries_total", vec![("outcome", "delivered".to_string())]);
+            }
+            Err(e) if is_retryable(&e) => {
+                self.inner
+                    .telemetry
+                    .increment("event_retries_total", vec![("outcome", "requeued".to_string())]);
+                self.requeue(pending.request, pending.attempts + 1, e);
+            }
+            Err(e) => self.dead_letter(&pending.request, "failed", pending.attempts + 1, &e),
+        }
+    }
+
+    /// Queues an invoke that failed its `attempts`th attempt, or dead-letters it when retries are
+    /// exhausted or the queue is full.
+    fn requeue(&self, request: InvokeRequest, attempts: u32, error: InvokeError) {
+        let config = &self.inner.config;
+        if attempts > config.retries {
+            return self.dead_letter(&request, "retries_exhausted", attempts, &error);
+        }
+        let backoff = config
+            .backoff_ms
+            .saturating_mul(1 << (attempts - 1).min(20))
+            .min(config.max_backoff_ms);
+        {
+            let mut pending = self.inner.pending.lock().unwrap();
+            if pending.len() < config.capacity {
+                pending.push_back(Pending {
+                    request,
+                    attempts,
+                    due: Instant::now() + Duration::from_millis(backoff),
+                });
+                self.inner.telemetry.gauge("event_retry_queue_depth", vec![], 1);
+                self.inner.wake.notify_one();
+                return;
+            }
+        }
+        self.dead_letter(&request, "queue_full", attempts, &error);
+    }
+
+    fn take_due(&self, now: Instant) -> Vec<Pending> {
+        let mut pending = self.inner.pending.lock().unwrap();
+        let (due, waiting): (VecDeque<_>, VecDeque<_>) = pending.drain(..).partition(|p| p.due <= now);
+        *pending = waiting;
+        self.inner
+            .telemetry
+            .gauge("event_retry_queue_depth", vec![], -(due.len() as i64));
+        due.into()
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.inner.pending.lock().unwrap().iter().map(|p| p.due).min()
+    }
+
+    fn dead_letter(&self, request: &InvokeRequest, reason: &'static str, attempts: u32, error: &InvokeError) {
+        tracing::error!(function = %request.function_name, reason, attempts, "Dropping asynchronous invoke: {}", error);
+        self.inner
+            .telemetry
+            .increment("event_dead_letters_total", vec![("reason", reason.to_string())]);
+        let Some(path) = &self.inner.config.dead_letter_path else {
+            return;
+        };
+        let line = DeadLetter {
+            timestamp_ms: now_ms(),
+            function: &request.function_name,
+            reason,
+            attempts,
+            error: error.to_string(),
+            payload: &request.payload,
+        };
+        let written = serde_json::to_string(&line)
+            .map_err(std::io::Error::from)
+            .and_then(|line| {
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                writeln!(file, "{}", line)
+            });
+        if let Err(e) = written {
+            tracing::error!("Failed to write dead letter to {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn is_retryable(e: &InvokeError) -> bool {
+    matches!(e, InvokeError::Throttled(..) | InvokeError::Conflict(_))
+}
+
+#[cfg(test)]
+mod tests {
+    include!("event_queue_tests.rs");
+}
@@ -0,0 +1,126 @@
+use super::*;
+use crate::mock::MockInvoker;
+
+fn throttled() -> Result<Option<String>, InvokeError> {
+    Err(InvokeError::Throttled("Rate exceeded".to_string(), None))
+}
+
+fn request(payload: &str) -> InvokeRequest {
+    InvokeRequest {
+        function_name: "webhook".to_string(),
+        qualifier: None,
+        payload: payload.to_string(),
+        client_context: None,
+        trace_header: None,
+    }
+}
+
+fn retry_config(dead_letter_path: Option<std::path::PathBuf>) -> EventRetryConfig {
+    EventRetryConfig {
+        capacity: 2,
+        retries: 3,
+        backoff_ms: 10,
+        max_backoff_ms: 40,
+        concurrency: 2,
+        dead_letter_path,
+    }
+}
+
+fn queue(config: &EventRetryConfig, invoker: Arc<MockInvoker>) -> EventQueue {
+    let telemetry = Telemetry::new(&Default::default());
+    EventQueue::new(config, invoker, telemetry)
+}
+
+async fn wait_for_calls(invoker: &MockInvoker, calls: usize) {
+    tokio::time::timeout(Duration::from_secs(2), async {
+        while invoker.calls() < calls {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .expect("queued invoke was not retried");
+}
+
+fn dead_letters(path: &std::path::Path) -> Vec<serde_json::Value> {
+    std::fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_throttled_invoke_is_retried_until_accepted() {
+    let invoker = MockInvoker::with_events(vec![throttled(), throttled(), throttled()]);
+    let queue = queue(&retry_config(None), invoker.clone());
+    queue.spawn_drainer();
+
+    // The caller is answered right away; the payload is delivered in the background
+    assert_eq!(queue.submit(request("payload")).await, Ok(None));
+    assert_eq!(queue.depth(), 1);
+
+    wait_for_calls(&invoker, 4).await;
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(queue.depth(), 0);
+    assert!(invoker.requests().iter().all(|r| r.payload == "payload"));
+    assert_eq!(invoker.calls(), 4);
+}
+
+#[tokio::test]
+async fn test_accepted_invoke_is_not_queued() {
+    let invoker = MockInvoker::with_events(vec![]);
+    let queue = queue(&retry_config(None), invoker.clone());
+
+    assert_eq!(queue.submit(request("payload")).await, Ok(Some("mock-request-id".to_string())));
+    assert_eq!(queue.depth(), 0);
+}
+
+#[tokio::test]
+async fn test_other_errors_are_returned() {
+    let error = InvokeError::Other("AccessDenied".to_string());
+    let invoker = MockInvoker::with_events(vec![Err(error.clone())]);
+    let queue = queue(&retry_config(None), invoker);
+
+    assert_eq!(queue.submit(request("payload")).await, Err(error));
+    assert_eq!(queue.depth(), 0);
+}
+
+#[tokio::test]
+async fn test_exhausted_retries_are_dead_lettered() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("dead-letters.jsonl");
+    let invoker = MockInvoker::with_events(vec![throttled(); 4]);
+    let queue = queue(&retry_config(Some(path.clone())), invoker.clone());
+    queue.spawn_drainer();
+
+    queue.submit(request("lost")).await.unwrap();
+    wait_for_calls(&invoker, 4).await;
+    tokio::time::sleep(Duration::from_millis(20)).await;
+
+    let letters = dead_letters(&path);
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0]["reason"], "retries_exhausted");
+    assert_eq!(letters[0]["attempts"], 4);
+    assert_eq!(letters[0]["function"], "webhook");
+    assert_eq!(letters[0]["payload"], "lost");
+    assert_eq!(queue.depth(), 0);
+}
+
+#[tokio::test]
+async fn test_full_queue_spills_to_dead_letters() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("dead-letters.jsonl");
+    let invoker = MockInvoker::with_events(vec![throttled(); 3]);
+    // No drainer, so the queue stays full
+    let queue = queue(&retry_config(Some(path.clone())), invoker);
+
+    for payload in ["a", "b", "c"] {
+        assert_eq!(queue.submit(request(payload)).await, Ok(None));
+    }
+
+    assert_eq!(queue.depth(), 2);
+    let letters = dead_letters(&path);
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0]["reason"], "queue_full");
+    assert_eq!(letters[0]["payload"], "c");
+}
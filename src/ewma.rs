@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+/// Weight of the newest sample in service time averages.
+pub const SERVICE_TIME_ALPHA: f64 = 0.2;
+
+/// Exponentially weighted moving average, used to track recent service times.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ewma {
+    alpha: f64,
+    value: Option<f64>,
+}
+
+impl Ewma {
+    /// `alpha` is the weight of each new sample, between 0 and 1.
+    pub fn new(alpha: f64) -> Self {
+        Self {
+            alpha: alpha.clamp(f64::MIN_POSITIVE, 1.0),
+            value: None,
+        }
+    }
+
+    /// Folds in a sample; the first sample is taken as is.
+    pub fn observe(&mut self, sample: f64) {
+        self.value = Some(match self.value {
+            Some(value) => value + self.alpha * (sample - value),
+            None => sample,
+        });
+    }
+
+    /// The average so far, or `None` before the first sample.
+    pub fn value(&self) -> Option<f64> {
+        self.value
+    }
+}
+
+/// Expected wait for a request joining `queued` waiters in front of `concurrency` busy slots, when
+/// each request holds its slot for `service_ms`. Waiters are served in rounds of `concurrency`.
+pub fn estimate_wait(queued: usize, concurrency: usize, service_ms: f64) -> Duration {
+    let rounds = (queued / concurrency.max(1) + 1) as f64;
+    Duration::from_secs_f64((rounds * service_ms.max(0.0) / 1000.0).min(u32::MAX as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    include!("ewma_tests.rs");
+}
@@ -0,0 +1,37 @@
+use super::*;
+
+#[test]
+fn test_first_sample_is_taken_as_is() {
+    let mut ewma = Ewma::new(0.2);
+    assert_eq!(ewma.value(), None);
+    ewma.observe(100.0);
+    assert_eq!(ewma.value(), Some(100.0));
+}
+
+#[test]
+fn test_moves_towards_new_samples_by_alpha() {
+    let mut ewma = Ewma::new(0.5);
+    ewma.observe(100.0);
+    ewma.observe(200.0);
+    assert_eq!(ewma.value(), Some(150.0));
+    ewma.observe(150.0);
+    assert_eq!(ewma.value(), Some(150.0));
+}
+
+#[test]
+fn test_converges_after_a_level_shift() {
+    let mut ewma = Ewma::new(SERVICE_TIME_ALPHA);
+    ewma.observe(1000.0);
+    for _ in 0..50 {
+        ewma.observe(10.0);
+    }
+    assert!((ewma.value().unwrap() - 10.0).abs() < 1.0, "{:?}", ewma.value());
+}
+
+#[test]
+fn test_wait_grows_by_rounds_of_concurrency() {
+    assert_eq!(estimate_wait(0, 4, 200.0), Duration::from_millis(200));
+    assert_eq!(estimate_wait(3, 4, 200.0), Duration::from_millis(200));
+    assert_eq!(estimate_wait(4, 4, 200.0), Duration::from_millis(400));
+    assert_eq!(estimate_wait(9, 0, 100.0), Duration::from_millis(1000));
+}
@@ -0,0 +1,756 @@
+use crate::cidr::Cidr;
+use axum::http::{HeaderMap, HeaderName, Method};
+use regex::Regex;
+use std::borrow::Cow;
+use std::fmt;
+use std::net::IpAddr;
+
+/// Longest condition accepted, in bytes.
+const MAX_SOURCE_BYTES: usize = 4096;
+
+/// Deepest nesting of operators, calls and parentheses accepted, so that no condition can exhaust
+/// the stack of the parser or the evaluator.
+const MAX_DEPTH: usize = 64;
+
+/// Names a condition can read, besides the `header` and `query` functions.
+const VARIABLES: [&str; 4] = ["method", "path", "client_ip", "key_id"];
+
+const FUNCTIONS: [&str; 12] = [
+    "header",
+    "query",
+    "record",
+    "lower",
+    "upper",
+    "len",
+    "starts_with",
+    "ends_with",
+    "contains",
+    "matches",
+    "in_cidr",
+    "one_of",
+];
+
+/// What a condition can read about a request.
+#[derive(Clone, Copy, Debug)]
+pub struct Attributes<'a> {
+    pub method: &'a Method,
+    /// Percent-decoded request path.
+    pub path: &'a str,
+    /// Query string as sent, still encoded.
+    pub query: &'a str,
+    pub headers: &'a HeaderMap,
+    pub client_ip: Option<IpAddr>,
+    /// ID of the API key the request authenticated with, as used by feature flags. Unknown where
+    /// conditions are evaluated before auth, as in `match`.
+    pub key_id: Option<&'a str>,
+    /// Streamed NDJSON record being filtered; unset everywhere else.
+    pub record: Option<&'a serde_json::Value>,
+}
+
+/// Why a condition did not compile, and where.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompileError {
+    /// Position in the source, in characters from 1.
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "column {}: {}", self.column, self.message)
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// A condition such as `header("x-tier") == "premium" && method == "POST"`, compiled once and
+/// evaluated per request.
+///
+/// The language has no loops, assignments or side effects. It has string, integer, boolean and
+/// `null` literals, the variables `method`, `path`, `client_ip` and `key_id`, the functions
+/// `header(name)`, `query(name)`, `record(field)`, `lower`, `upper`, `len`, `starts_with`,
+/// `ends_with`, `contains`, `matches(s, regex)`, `in_cidr(ip, network)` and
+/// `one_of(x, a, b, ...)`, comparisons, `!`, `&&` and `||`. Types are checked when compiling: a
+/// condition is a boolean, and values of different types are never compared. Missing headers,
+/// query parameters, record fields, client addresses and key IDs are `null`, which equals only
+/// `null` and fails every string function.
+#[derive(Debug)]
+pub struct Expr {
+    root: Node,
+}
+
+impl Expr {
+    pub fn compile(source: &str) -> Result<Expr, CompileError> {
+        if source.len() > MAX_SOURCE_BYTES {
+            return Err(CompileError {
+                column: 1,
+                message: format!("condition is longer than {} bytes", MAX_SOURCE_BYTES),
+            });
+        }
+        let mut parser = Parser {
+            source,
+            tokens: tokenize(source)?,
+            next: 0,
+            depth: 0,
+        };
+        let (root, ty) = parser.expression()?;
+        let token = parser.peek();
+        if token.kind != TokenKind::End {
+            return Err(parser.error_at(token.start, format!("unexpected {}", token.kind)));
+        }
+        if ty != Type::Bool {
+            return Err(parser.error_at(0, format!("a condition must be a boolean, found {}", ty)));
+        }
+        Ok(Expr { root })
+    }
+
+    pub fn eval(&self, attributes: &Attributes) -> bool {
+        matches!(self.root.eval(attributes), Value::Bool(true))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Type {
+    Bool,
+    Int,
+    Str,
+    Ip,
+    Null,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Type::Bool => "a boolean",
+            Type::Int => "an integer",
+            Type::Str => "a string",
+            Type::Ip => "an IP address",
+            Type::Null => "null",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value<'a> {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Str(Cow<'a, str>),
+    Ip(IpAddr),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StrPredicate {
+    StartsWith,
+    EndsWith,
+    Contains,
+}
+
+#[derive(Debug)]
+enum Node {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    Ip(IpAddr),
+    Method,
+    Path,
+    ClientIp,
+    KeyId,
+    Header(HeaderName),
+    Query(String),
+    /// Path of object keys or array indexes into the record.
+    Record(Vec<String>),
+    Lower(Box<Node>),
+    Upper(Box<Node>),
+    Len(Box<Node>),
+    StrPredicate(StrPredicate, Box<Node>, Box<Node>),
+    Matches(Box<Node>, Regex),
+    InCidr(Box<Node>, Cidr),
+    OneOf(Box<Node>, Vec<Node>),
+    Compare(CompareOp, Box<Node>, Box<Node>),
+    Not(Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+}
+
+impl Node {
+    fn eval<'a>(&'a self, attributes: &Attributes<'a>) -> Value<'a> {
+        match self {
+            Node::Null => Value::Null,
+            Node::Bool(b) => Value::Bool(*b),
+            Node::Int(i) => Value::Int(*i),
+            Node::Str(s) => Value::Str(Cow::Borrowed(s)),
+            Node::Ip(ip) => Value::Ip(*ip),
+            Node::Method => Value::Str(Cow::Borrowed(attributes.method.as_str())),
+            Node::Path => Value::Str(Cow::Borrowed(attributes.path)),
+            Node::ClientIp => attributes.client_ip.map_or(Value::Null, Value::Ip),
+            Node::KeyId => attributes
+                .key_id
+                .map_or(Value::Null, |id| Value::Str(Cow::Borrowed(id))),
+            Node::Header(name) => match attributes.headers.get(name).map(|value| value.to_str()) {
+                Some(Ok(value)) => Value::Str(Cow::Borrowed(value)),
+                _ => Value::Null,
+            },
+            Node::Query(name) => url::form_urlencoded::parse(attributes.query.as_bytes())
+                .find(|(key, _)| key == name)
+                .map_or(Value::Null, |(_, value)| Value::Str(value)),
+            Node::Record(path) => {
+                let field = attributes.record.and_then(|record| {
+                    path.iter().try_fold(record, |value, key| match value {
+                        serde_json::Value::Array(items) => key.parse().ok().and_then(|i: usize| items.get(i)),
+                        value => value.get(key),
+                    })
+                });
+                match field {
+                    Some(serde_json::Value::String(s)) => Value::Str(Cow::Borrowed(s)),
+                    // Numbers and booleans compare as their JSON text
+                    Some(value @ (serde_json::Value::Number(_) | serde_json::Value::Bool(_))) => {
+                        Value::Str(Cow::Owned(value.to_string()))
+                    }
+                    _ => Value::Null,
+                }
+            }
+            Node::Lower(s) => map_str(s.eval(attributes), |s| {
+                if s.chars().any(|c| c.to_lowercase().ne([c])) {
+                    Cow::Owned(s.to_lowercase())
+                } else {
+                    s
+                }
+            }),
+            Node::Upper(s) => map_str(s.eval(attributes), |s| {
+                if s.chars().any(|c| c.to_uppercase().ne([c])) {
+                    Cow::Owned(s.to_uppercase())
+                } else {
+                    s
+                }
+            }),
+            Node::Len(s) => match s.eval(attributes) {
+                Value::Str(s) => Value::Int(s.chars().count() as i64),
+                _ => Value::Int(0),
+            },
+            Node::StrPredicate(predicate, s, pattern) => {
+                let (Value::Str(s), Value::Str(pattern)) = (s.eval(attributes), pattern.eval(attributes)) else {
+                    return Value::Bool(false);
+                };
+                Value::Bool(match predicate {
+                    StrPredicate::StartsWith => s.starts_with(pattern.as_ref()),
+                    StrPredicate::EndsWith => s.ends_with(pattern.as_ref()),
+                    StrPredicate::Contains => s.contains(pattern.as_ref()),
+                })
+            }
+            Node::Matches(s, regex) => Value::Bool(match s.eval(attributes) {
+                Value::Str(s) => regex.is_match(&s),
+                _ => false,
+            }),
+            Node::InCidr(ip, cidr) => Value::Bool(match ip.eval(attributes) {
+                Value::Ip(ip) => cidr.contains(ip),
+                _ => false,
+            }),
+            Node::OneOf(value, candidates) => {
+                let value = value.eval(attributes);
+                Value::Bool(candidates.iter().any(|candidate| candidate.eval(attributes) == value))
+            }
+            Node::Compare(op, left, right) => {
+                Value::Bool(compare(*op, &left.eval(attributes), &right.eval(attributes)))
+            }
+            Node::Not(operand) => Value::Bool(operand.eval(attributes) != Value::Bool(true)),
+            Node::And(left, right) => {
+                Value::Bool(left.eval(attributes) == Value::Bool(true) && right.eval(attributes) == Value::Bool(true))
+            }
+            Node::Or(left, right) => {
+                Value::Bool(left.eval(attributes) == Value::Bool(true) || right.eval(attributes) == Value::Bool(true))
+            }
+        }
+    }
+}
+
+fn map_str<'a>(value: Value<'a>, f: impl FnOnce(Cow<'a, str>) -> Cow<'a, str>) -> Value<'a> {
+    match value {
+        Value::Str(s) => Value::Str(f(s)),
+        other => other,
+    }
+}
+
+/// Equality holds between equal values of one type and between two nulls. Ordering holds between
+/// integers and between strings only, so any comparison with a missing value but `!=` is false.
+fn compare(op: CompareOp, left: &Value, right: &Value) -> bool {
+    let ordering = match (left, right) {
+        (Value::Int(left), Value::Int(right)) => Some(left.cmp(right)),
+        (Value::Str(left), Value::Str(right)) => Some(left.cmp(right)),
+        _ => None,
+    };
+    match op {
+        CompareOp::Eq => left == right,
+        CompareOp::Ne => left != right,
+        CompareOp::Lt => ordering.is_some_and(|o| o.is_lt()),
+        CompareOp::Le => ordering.is_some_and(|o| o.is_le()),
+        CompareOp::Gt => ordering.is_some_and(|o| o.is_gt()),
+        CompareOp::Ge => ordering.is_some_and(|o| o.is_ge()),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TokenKind {
+    Str(String),
+    Int(i64),
+    Ident(String),
+    LParen,
+    RParen,
+    Comma,
+    Not,
+    And,
+    Or,
+    Compare(CompareOp),
+    End,
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenKind::Str(s) => write!(f, "string {:?}", s),
+            TokenKind::Int(i) => write!(f, "number {}", i),
+            TokenKind::Ident(name) => write!(f, "{:?}", name),
+            TokenKind::LParen => f.write_str("'('"),
+            TokenKind::RParen => f.write_str("')'"),
+            TokenKind::Comma => f.write_str("','"),
+            TokenKind::Not => f.write_str("'!'"),
+            TokenKind::And => f.write_str("'&&'"),
+            TokenKind::Or => f.write_str("'||'"),
+            TokenKind::Compare(op) => write!(f, "'{}'", op_str(*op)),
+            TokenKind::End => f.write_str("end of condition"),
+        }
+    }
+}
+
+fn op_str(op: CompareOp) -> &'static str {
+    match op {
+        CompareOp::Eq => "==",
+        CompareOp::Ne => "!=",
+        CompareOp::Lt => "<",
+        CompareOp::Le => "<=",
+        CompareOp::Gt => ">",
+        CompareOp::Ge => ">=",
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    kind: TokenKind,
+    /// Byte offset in the source.
+    start: usize,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, CompileError> {
+    let error = |start: usize, message: String| CompileError {
+        column: column(source, start),
+        message,
+    };
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let mut next_is = |expected: char| chars.next_if(|&(_, c)| c == expected).is_some();
+        let kind = match c {
+            c if c.is_whitespace() => continue,
+            '(' => TokenKind::LParen,
+            ')' => TokenKind::RParen,
+            ',' => TokenKind::Comma,
+            '&' if next_is('&') => TokenKind::And,
+            '|' if next_is('|') => TokenKind::Or,
+            '=' if next_is('=') => TokenKind::Compare(CompareOp::Eq),
+            '!' if next_is('=') => TokenKind::Compare(CompareOp::Ne),
+            '!' => TokenKind::Not,
+            '<' if next_is('=') => TokenKind::Compare(CompareOp::Le),
+            '<' => TokenKind::Compare(CompareOp::Lt),
+            '>' if next_is('=') => TokenKind::Compare(CompareOp::Ge),
+            '>' => TokenKind::Compare(CompareOp::Gt),
+            '"' | '\'' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, end)) if end == c => break,
+                        Some((at, '\\')) => match chars.next() {
+                            Some((_, escaped @ ('"' | '\'' | '\\'))) => value.push(escaped),
+                            Some((_, 'n')) => value.push('\n'),
+                            Some((_, 't')) => value.push('\t'),
+                            _ => return Err(error(at, "invalid escape, expected \\\", \\', \\\\, \\n or \\t".into())),
+                        },
+                        Some((_, other)) => value.push(other),
+                        None => return Err(error(start, "unterminated string".into())),
+                    }
+                }
+                TokenKind::Str(value)
+            }
+            '0'..='9' => {
+                let mut end = start + 1;
+                while let Some((at, _)) = chars.next_if(|(_, c)| c.is_ascii_digit()) {
+                    end = at + 1;
+                }
+                match source[start..end].parse() {
+                    Ok(value) => TokenKind::Int(value),
+                    Err(_) => return Err(error(start, "number is too large".into())),
+                }
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut end = start + 1;
+                while let Some((at, _)) = chars.next_if(|(_, c)| c.is_ascii_alphanumeric() || *c == '_') {
+                    end = at + 1;
+                }
+                TokenKind::Ident(source[start..end].to_string())
+            }
+            other => return Err(error(start, format!("unexpected character {:?}", other))),
+        };
+        tokens.push(Token { kind, start });
+    }
+    tokens.push(Token {
+        kind: TokenKind::End,
+        start: source.len(),
+    });
+    Ok(tokens)
+}
+
+/// Column of a byte offset, counted in characters from 1.
+fn column(source: &str, offset: usize) -> usize {
+    source[..offset].chars().count() + 1
+}
+
+/// Recursive descent parser that checks types as it goes. Precedence from loosest: `||`, `&&`,
+/// `!`, comparisons, which do not chain, then literals, names, calls and parentheses.
+struct Parser<'s> {
+    source: &'s str,
+    tokens: Vec<Token>,
+    next: usize,
+    depth: usize,
+}
+
+/// A node with the type of the value it evaluates to.
+type Typed = (Node, Type);
+
+type Parsed = Result<Typed, CompileError>;
+
+impl Parser<'_> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.next]
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.next].clone();
+        if token.kind != TokenKind::End {
+            self.next += 1;
+        }
+        token
+    }
+
+    fn error_at(&self, offset: usize, message: String) -> CompileError {
+        CompileError {
+            column: column(self.source, offset),
+            message,
+        }
+    }
+
+    fn expect(&mut self, kind: TokenKind) -> Result<Token, CompileError> {
+        let token = self.advance();
+        if token.kind == kind {
+            Ok(token)
+        } else {
+            Err(self.error_at(token.start, format!("expected {}, found {}", kind, token.kind)))
+        }
+    }
+
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T, CompileError>) -> Result<T, CompileError> {
+        if self.depth == MAX_DEPTH {
+            let start = self.peek().start;
+            return Err(self.error_at(start, format!("condition nests deeper than {} levels", MAX_DEPTH)));
+        }
+        self.depth += 1;
+        let parsed = parse(self);
+        self.depth -= 1;
+        parsed
+    }
+
+    fn expression(&mut self) -> Parsed {
+        self.nested(|parser| parser.or())
+    }
+
+    fn or(&mut self) -> Parsed {
+        let depth = self.depth;
+        let mut left = self.and()?;
+        while self.peek().kind == TokenKind::Or {
+            let start = self.advance().start;
+            // Each operator in a chain nests the tree one level deeper
+            let right = self.nested(|parser| parser.and());
+            self.depth += 1;
+            left = (
+                Node::Or(self.boolean(left, start)?, self.boolean(right?, start)?),
+                Type::Bool,
+            );
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Parsed {
+        let depth = self.depth;
+        let mut left = self.not()?;
+        while self.peek().kind == TokenKind::And {
+            let start = self.advance().start;
+            let right = self.nested(|parser| parser.not());
+            self.depth += 1;
+            left = (
+                Node::And(self.boolean(left, start)?, self.boolean(right?, start)?),
+                Type::Bool,
+            );
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Parsed {
+        if self.peek().kind != TokenKind::Not {
+            return self.comparison();
+        }
+        let start = self.advance().start;
+        let operand = self.nested(|parser| parser.not())?;
+        Ok((Node::Not(self.boolean(operand, start)?), Type::Bool))
+    }
+
+    /// Checks that an operand of `!`, `&&` or `||` is a boolean.
+    fn boolean(&self, (node, ty): Typed, operator_start: usize) -> Result<Box<Node>, CompileError> {
+        if ty != Type::Bool {
+            let operator = &self.source[operator_start..];
+            let operator = if operator.starts_with('!') { "!" } else { &operator[..2] };
+            return Err(self.error_at(
+                operator_start,
+                format!("operands of '{}' must be booleans, found {}", operator, ty),
+            ));
+        }
+        Ok(Box::new(node))
+    }
+
+    fn comparison(&mut self) -> Parsed {
+        let left_start = self.peek().start;
+        let left = self.primary()?;
+        let TokenKind::Compare(op) = self.peek().kind else {
+            return Ok(left);
+        };
+        let op_start = self.advance().start;
+        let right_start = self.peek().start;
+        let right = self.primary()?;
+        if let TokenKind::Compare(_) = self.peek().kind {
+            let start = self.peek().start;
+            return Err(self.error_at(start, "comparisons do not chain, use && between them".into()));
+        }
+        let (left, right) = self.comparable(left, left_start, right, right_start)?;
+        let ordered = !matches!(op, CompareOp::Eq | CompareOp::Ne);
+        if ordered && !matches!(left.1, Type::Int | Type::Str) {
+            return Err(self.error_at(
+                op_start,
+                format!("'{}' compares integers or strings, not {}", op_str(op), left.1),
+            ));
+        }
+        Ok((Node::Compare(op, Box::new(left.0), Box::new(right.0)), Type::Bool))
+    }
+
+    /// Checks that two values can be compared: values of one type, anything with `null`, and IP
+    /// addresses with string literals, which are parsed as addresses here.
+    fn comparable(
+        &self,
+        left: Typed,
+        left_start: usize,
+        right: Typed,
+        right_start: usize,
+    ) -> Result<(Typed, Typed), CompileError> {
+        match (left.1, right.1) {
+            (l, r) if l == r || l == Type::Null || r == Type::Null => {
+                let ty = if l == Type::Null { r } else { l };
+                Ok(((left.0, ty), (right.0, ty)))
+            }
+            (Type::Ip, Type::Str) => Ok((left, (self.ip_literal(right.0, right_start)?, Type::Ip))),
+            (Type::Str, Type::Ip) => Ok(((self.ip_literal(left.0, left_start)?, Type::Ip), right)),
+            (l, r) => Err(self.error_at(right_start, format!("cannot compare {} with {}", l, r))),
+        }
+    }
+
+    fn ip_literal(&self, node: Node, start: usize) -> Result<Node, CompileError> {
+        match node {
+            Node::Str(s) => match s.parse() {
+                Ok(ip) => Ok(Node::Ip(ip)),
+                Err(_) => Err(self.error_at(start, format!("{:?} is not an IP address", s))),
+            },
+            _ => Err(self.error_at(start, "IP addresses compare with string literals only".into())),
+        }
+    }
+
+    fn primary(&mut self) -> Parsed {
+        let token = self.advance();
+        match token.kind {
+            TokenKind::Str(s) => Ok((Node::Str(s), Type::Str)),
+            TokenKind::Int(i) => Ok((Node::Int(i), Type::Int)),
+            TokenKind::LParen => {
+                let inner = self.expression()?;
+                self.expect(TokenKind::RParen)?;
+                Ok(inner)
+            }
+            TokenKind::Ident(name) if self.peek().kind == TokenKind::LParen => self.call(&name, token.start),
+            TokenKind::Ident(name) => match name.as_str() {
+                "true" => Ok((Node::Bool(true), Type::Bool)),
+                "false" => Ok((Node::Bool(false), Type::Bool)),
+                "null" => Ok((Node::Null, Type::Null)),
+                "method" => Ok((Node::Method, Type::Str)),
+                "path" => Ok((Node::Path, Type::Str)),
+                "client_ip" => Ok((Node::ClientIp, Type::Ip)),
+                "key_id" => Ok((Node::KeyId, Type::Str)),
+                _ => Err(self.error_at(
+                    token.start,
+                    format!("unknown name {:?}, expected one of {}", name, VARIABLES.join(", ")),
+                )),
+            },
+            kind => Err(self.error_at(token.start, format!("expected a value, found {}", kind))),
+        }
+    }
+
+    fn call(&mut self, name: &str, start: usize) -> Parsed {
+        if !FUNCTIONS.contains(&name) {
+            return Err(self.error_at(
+                start,
+                format!("unknown function {:?}, expected one of {}", name, FUNCTIONS.join(", ")),
+            ));
+        }
+        self.expect(TokenKind::LParen)?;
+        let mut args = Vec::new();
+        if self.peek().kind != TokenKind::RParen {
+            loop {
+                let arg_start = self.peek().start;
+                let (node, ty) = self.expression()?;
+                args.push(Arg {
+                    node,
+                    ty,
+                    start: arg_start,
+                });
+                if self.peek().kind != TokenKind::Comma {
+                    break;
+                }
+                self.advance();
+            }
+        }
+        self.expect(TokenKind::RParen)?;
+
+        let mut args = args.into_iter();
+        let mut arg = |expected: Type| -> Result<Arg, CompileError> {
+            match args.next() {
+                Some(arg) if arg.ty == expected || arg.ty == Type::Null => Ok(arg),
+                Some(arg) => Err(self.error_at(
+                    arg.start,
+                    format!("{} expects {} here, found {}", name, expected, arg.ty),
+                )),
+                None => Err(self.error_at(start, format!("{} needs more arguments", name))),
+            }
+        };
+        let node = match name {
+            "header" => {
+                let (name, at) = arg(Type::Str)?.literal(self, "header names")?;
+                match HeaderName::from_bytes(name.to_ascii_lowercase().as_bytes()) {
+                    Ok(name) => (Node::Header(name), Type::Str),
+                    Err(_) => return Err(self.error_at(at, format!("{:?} is not a valid header name", name))),
+                }
+            }
+            "query" => (
+                Node::Query(arg(Type::Str)?.literal(self, "query parameter names")?.0),
+                Type::Str,
+            ),
+            "record" => {
+                let (field, at) = arg(Type::Str)?.literal(self, "record fields")?;
+                if field.split('.').any(str::is_empty) {
+                    return Err(self.error_at(at, format!("{:?} is not a field path such as \"a.b\"", field)));
+                }
+                (Node::Record(field.split('.').map(String::from).collect()), Type::Str)
+            }
+            "lower" => (Node::Lower(Box::new(arg(Type::Str)?.node)), Type::Str),
+            "upper" => (Node::Upper(Box::new(arg(Type::Str)?.node)), Type::Str),
+            "len" => (Node::Len(Box::new(arg(Type::Str)?.node)), Type::Int),
+            "starts_with" | "ends_with" | "contains" => {
+                let predicate = match name {
+                    "starts_with" => StrPredicate::StartsWith,
+                    "ends_with" => StrPredicate::EndsWith,
+                    _ => StrPredicate::Contains,
+                };
+                let s = arg(Type::Str)?.node;
+                let pattern = arg(Type::Str)?.node;
+                (
+                    Node::StrPredicate(predicate, Box::new(s), Box::new(pattern)),
+                    Type::Bool,
+                )
+            }
+            "matches" => {
+                let s = arg(Type::Str)?.node;
+                let (pattern, at) = arg(Type::Str)?.literal(self, "regexes")?;
+                match Regex::new(&pattern) {
+                    Ok(regex) => (Node::Matches(Box::new(s), regex), Type::Bool),
+                    Err(e) => return Err(self.error_at(at, format!("invalid regex: {}", e))),
+                }
+            }
+            "in_cidr" => {
+                let ip = arg(Type::Ip)?.node;
+                let (network, at) = arg(Type::Str)?.literal(self, "networks")?;
+                match network.parse::<Cidr>() {
+                    Ok(cidr) => (Node::InCidr(Box::new(ip), cidr), Type::Bool),
+                    Err(e) => return Err(self.error_at(at, e)),
+                }
+            }
+            "one_of" => {
+                let Some(first) = args.next() else {
+                    return Err(self.error_at(start, "one_of needs a value and at least one candidate".into()));
+                };
+                let value_start = first.start;
+                let mut value = (first.node, first.ty);
+                let mut candidates = Vec::new();
+                for candidate in args.by_ref() {
+                    let (v, c) =
+                        self.comparable(value, value_start, (candidate.node, candidate.ty), candidate.start)?;
+                    value = v;
+                    candidates.push(c.0);
+                }
+                if candidates.is_empty() {
+                    return Err(self.error_at(start, "one_of needs a value and at least one candidate".into()));
+                }
+                (Node::OneOf(Box::new(value.0), candidates), Type::Bool)
+            }
+            _ => unreachable!("every name in FUNCTIONS is handled"),
+        };
+        if let Some(extra) = args.next() {
+            return Err(self.error_at(extra.start, format!("too many arguments to {}", name)));
+        }
+        Ok(node)
+    }
+}
+
+struct Arg {
+    node: Node,
+    ty: Type,
+    start: usize,
+}
+
+impl Arg {
+    /// The value of a string literal argument, for arguments compiled ahead of evaluation.
+    fn literal(self, parser: &Parser, what: &str) -> Result<(String, usize), CompileError> {
+        match self.node {
+            Node::Str(s) => Ok((s, self.start)),
+            _ => Err(parser.error_at(self.start, format!("{} must be string literals", what))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("expr_tests.rs");
+}
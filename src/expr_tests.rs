@@ -0,0 +1,379 @@
+use super::*;
+
+struct Request {
+    method: Method,
+    path: &'static str,
+    query: &'static str,
+    headers: HeaderMap,
+    client_ip: Option<IpAddr>,
+    key_id: Option<&'static str>,
+    record: Option<serde_json::Value>,
+}
+
+impl Request {
+    fn attributes(&self) -> Attributes<'_> {
+        Attributes {
+            method: &self.method,
+            path: self.path,
+            query: self.query,
+            headers: &self.headers,
+            client_ip: self.client_ip,
+            key_id: self.key_id,
+            record: self.record.as_ref(),
+        }
+    }
+}
+
+fn request() -> Request {
+    let mut headers = HeaderMap::new();
+    headers.insert("x-tier", "premium".parse().unwrap());
+    headers.insert("user-agent", "Mozilla/5.0 (X11)".parse().unwrap());
+    headers.insert("x-binary", axum::http::HeaderValue::from_bytes(b"caf\xe9").unwrap());
+    Request {
+        method: Method::POST,
+        path: "/orders/42",
+        query: "page=2&tag=a&tag=b&q=caf%C3%A9+au+lait&empty=",
+        headers,
+        client_ip: Some("10.1.2.3".parse().unwrap()),
+        key_id: Some("85dbe15d75ef9308"),
+        record: None,
+    }
+}
+
+fn eval(source: &str) -> bool {
+    eval_on(source, &request())
+}
+
+fn eval_on(source: &str, request: &Request) -> bool {
+    let expr = Expr::compile(source).unwrap_or_else(|e| panic!("{}: {}", source, e));
+    expr.eval(&request.attributes())
+}
+
+fn error(source: &str) -> CompileError {
+    match Expr::compile(source) {
+        Ok(_) => panic!("{} compiled", source),
+        Err(e) => e,
+    }
+}
+
+#[test]
+fn test_example_condition() {
+    assert!(eval(r#"header("x-tier") == "premium" && method == "POST""#));
+    assert!(!eval(r#"header("x-tier") == "premium" && method == "GET""#));
+}
+
+#[test]
+fn test_literals_and_variables() {
+    for source in [
+        "true",
+        "!false",
+        "1 == 1",
+        "10 > 9",
+        r#""a" == 'a'"#,
+        r#""it's" == 'it\'s'"#,
+        r#""a\"b\\c\n\t" != "abc""#,
+        r#"path == "/orders/42""#,
+        r#"method == "POST""#,
+        r#"key_id == "85dbe15d75ef9308""#,
+        r#"client_ip == "10.1.2.3""#,
+        r#""10.1.2.3" == client_ip"#,
+        r#"client_ip != "::1""#,
+        "null == null",
+    ] {
+        assert!(eval(source), "{}", source);
+    }
+    assert!(!eval("false"));
+}
+
+#[test]
+fn test_precedence_and_grouping() {
+    // && binds tighter than ||, and ! tighter than both
+    assert!(eval("true || false && false"));
+    assert!(!eval("(true || false) && false"));
+    assert!(eval("!false && !false"));
+    assert!(!eval("!(true || false)"));
+    assert!(eval("!!true"));
+    assert!(eval("(1 < 2) == true"));
+    assert!(eval(r#"((((method == "POST"))))"#));
+}
+
+#[test]
+fn test_comparisons() {
+    for (source, expected) in [
+        ("1 < 2", true),
+        ("2 <= 2", true),
+        ("3 >= 4", false),
+        ("3 > 2", true),
+        (r#""abc" < "abd""#, true),
+        (r#""B" < "a""#, true),
+        ("1 != 2", true),
+        ("true == false", false),
+        (r#"header("x-missing") == null"#, true),
+        (r#"header("x-missing") != "x""#, true),
+        (r#"header("x-missing") == """#, false),
+        (r#"header("x-missing") < "zzz""#, false),
+        (r#"header("x-missing") >= """#, false),
+        (r#"null != header("x-tier")"#, true),
+        ("len(path) == 10", true),
+    ] {
+        assert_eq!(eval(source), expected, "{}", source);
+    }
+}
+
+#[test]
+fn test_headers() {
+    // Header names are case-insensitive, values are not
+    assert!(eval(r#"header("X-Tier") == "premium""#));
+    assert!(!eval(r#"header("x-tier") == "Premium""#));
+    assert!(eval(r#"starts_with(header("user-agent"), "Mozilla/")"#));
+    // Values that are not visible ASCII read as missing
+    assert!(eval(r#"header("x-binary") == null"#));
+}
+
+#[test]
+fn test_query() {
+    assert!(eval(r#"query("page") == "2""#));
+    // The first of repeated parameters, decoded like a form
+    assert!(eval(r#"query("tag") == "a""#));
+    assert!(eval(r#"query("q") == "café au lait""#));
+    assert!(eval(r#"query("empty") == """#));
+    assert!(eval(r#"query("missing") == null"#));
+    let no_query = Request { query: "", ..request() };
+    assert!(eval_on(r#"query("page") == null"#, &no_query));
+}
+
+#[test]
+fn test_string_functions() {
+    for source in [
+        r#"lower(header("user-agent")) == "mozilla/5.0 (x11)""#,
+        r#"upper(method) == "POST""#,
+        r#"lower("ÄÖ") == "äö""#,
+        r#"upper("straße") == "STRASSE""#,
+        r#"len("café") == 4"#,
+        r#"len(header("x-missing")) == 0"#,
+        r#"lower(header("x-missing")) == null"#,
+        r#"starts_with(path, "/orders/")"#,
+        r#"ends_with(path, "/42")"#,
+        r#"contains(header("user-agent"), "X11")"#,
+        r#"matches(path, "^/orders/[0-9]+$")"#,
+        r#"!matches(path, "^/users/")"#,
+        r#"in_cidr(client_ip, "10.0.0.0/8")"#,
+        r#"!in_cidr(client_ip, "192.168.0.0/16")"#,
+        r#"one_of(method, "GET", "POST")"#,
+        r#"!one_of(method, "GET", "HEAD")"#,
+        r#"one_of(client_ip, "10.0.0.1", "10.1.2.3")"#,
+        r#"one_of(len(path), 1, 10)"#,
+    ] {
+        assert!(eval(source), "{}", source);
+    }
+}
+
+#[test]
+fn test_record_fields() {
+    let with_record = Request {
+        record: Some(serde_json::json!({
+            "tenant": "85dbe15d75ef9308",
+            "level": "warn",
+            "user": {"id": 7, "admin": false, "tags": ["a", "b"]},
+            "note": null,
+        })),
+        ..request()
+    };
+    for source in [
+        r#"record("tenant") == key_id"#,
+        r#"record("level") != "info" && starts_with(record("level"), "w")"#,
+        r#"record("user.id") == "7""#,
+        r#"record("user.admin") == "false""#,
+        r#"record("user.tags.1") == "b""#,
+        r#"record("user") == null && record("note") == null && record("missing.deeper") == null"#,
+        r#"record("user.tags.2") == null && record("user.tags.x") == null"#,
+    ] {
+        assert!(eval_on(source, &with_record), "{}", source);
+    }
+    // Outside record filters there is no record
+    assert!(eval(r#"record("tenant") == null"#));
+    assert_eq!(error(r#"record("a..b")"#).column, 8);
+    assert_eq!(error("record(path)").message, "record fields must be string literals");
+}
+
+#[test]
+fn test_missing_values_fail_functions() {
+    let anonymous = Request {
+        client_ip: None,
+        key_id: None,
+        ..request()
+    };
+    for source in [
+        r#"starts_with(header("x-missing"), "")"#,
+        r#"contains("abc", header("x-missing"))"#,
+        r#"ends_with(header("x-missing"), header("x-missing"))"#,
+        r#"matches(header("x-missing"), ".*")"#,
+        r#"in_cidr(client_ip, "0.0.0.0/0")"#,
+        r#"key_id == "85dbe15d75ef9308""#,
+        r#"one_of(key_id, "a", "b")"#,
+    ] {
+        assert!(!eval_on(source, &anonymous), "{}", source);
+    }
+    assert!(eval_on("key_id == null && client_ip == null", &anonymous));
+    assert!(eval_on("one_of(key_id, \"a\", null)", &anonymous));
+}
+
+#[test]
+fn test_compile_errors_have_positions() {
+    for (source, column, message) in [
+        ("", 1, "expected a value, found end of condition"),
+        ("method ==", 10, "expected a value, found end of condition"),
+        (r#"method == "POST" &&"#, 20, "expected a value"),
+        ("method = 'GET'", 8, "unexpected character '='"),
+        ("true & false", 6, "unexpected character '&'"),
+        ("'unterminated", 1, "unterminated string"),
+        (r#""bad \x escape""#, 6, "invalid escape"),
+        ("99999999999999999999 > 1", 1, "number is too large"),
+        ("verb == 'GET'", 1, "unknown name \"verb\""),
+        ("lowercase(path) == 'x'", 1, "unknown function \"lowercase\""),
+        ("true true", 6, "unexpected \"true\""),
+        ("(true", 6, "expected ')', found end of condition"),
+        ("method", 1, "a condition must be a boolean, found a string"),
+        ("len(path)", 1, "a condition must be a boolean, found an integer"),
+        ("path && true", 6, "operands of '&&' must be booleans, found a string"),
+        ("true || 1", 6, "operands of '||' must be booleans, found an integer"),
+        ("!path", 1, "operands of '!' must be booleans, found a string"),
+        ("path == 1", 9, "cannot compare a string with an integer"),
+        ("true < false", 6, "'<' compares integers or strings, not a boolean"),
+        ("client_ip > '10.0.0.1'", 11, "'>' compares integers or strings"),
+        ("null < null", 6, "'<' compares integers or strings, not null"),
+        ("client_ip == 'localhost'", 14, "\"localhost\" is not an IP address"),
+        ("client_ip == path", 14, "IP addresses compare with string literals only"),
+        ("1 < 2 < 3", 7, "comparisons do not chain"),
+        ("header(path) == 'x'", 8, "header names must be string literals"),
+        ("header('bad name') == 'x'", 8, "\"bad name\" is not a valid header name"),
+        ("query(method) == 'x'", 7, "query parameter names must be string literals"),
+        ("header() == 'x'", 1, "header needs more arguments"),
+        ("header('a', 'b') == 'x'", 13, "too many arguments to header"),
+        ("starts_with(path, 1)", 19, "starts_with expects a string here, found an integer"),
+        ("len(1) == 1", 5, "len expects a string here, found an integer"),
+        ("matches(path, '(')", 15, "invalid regex"),
+        ("matches(path, method)", 15, "regexes must be string literals"),
+        ("in_cidr(path, '10.0.0.0/8')", 9, "in_cidr expects an IP address here, found a string"),
+        ("in_cidr(client_ip, '10.0.0.0/33')", 20, "invalid network"),
+        ("one_of(method)", 1, "one_of needs a value and at least one candidate"),
+        ("one_of(method, 'GET', 1)", 23, "cannot compare a string with an integer"),
+        ("path == 'é' && é", 16, "unexpected character 'é'"),
+    ] {
+        let e = error(source);
+        assert_eq!(e.column, column, "{}: {}", source, e);
+        assert!(e.message.contains(message), "{}: {}", source, e);
+    }
+    assert_eq!(error("true ==").to_string(), "column 8: expected a value, found end of condition");
+}
+
+#[test]
+fn test_limits() {
+    let long = format!("'{}' == ''", "a".repeat(MAX_SOURCE_BYTES));
+    assert!(error(&long).message.contains("longer than"));
+
+    let nested = |depth: usize| format!("{}true{}", "(".repeat(depth), ")".repeat(depth));
+    assert!(eval(&nested(MAX_DEPTH - 1)));
+    assert!(error(&nested(MAX_DEPTH + 1)).message.contains("nests deeper than"));
+    assert!(error(&format!("{}true", "!".repeat(MAX_DEPTH + 1)))
+        .message
+        .contains("nests deeper than"));
+    // Long chains of one operator nest the tree as deeply as parentheses
+    assert!(eval(&vec!["true"; MAX_DEPTH / 2].join(" && ")));
+    assert!(error(&vec!["true"; MAX_DEPTH * 2].join(" || ")).message.contains("nests deeper than"));
+    let calls = format!("{}path{} == ''", "lower(".repeat(MAX_DEPTH + 1), ")".repeat(MAX_DEPTH + 1));
+    assert!(error(&calls).message.contains("nests deeper than"));
+}
+
+#[test]
+fn test_evaluation_borrows_unchanged_values() {
+    let request = request();
+    let attributes = request.attributes();
+    let expr = |source: &str| Expr::compile(source).unwrap();
+    let header = expr("header('x-tier') == ''");
+    let Node::Compare(_, left, _) = &header.root else {
+        unreachable!()
+    };
+    assert!(matches!(left.eval(&attributes), Value::Str(Cow::Borrowed("premium"))));
+    let lower = expr("lower(path) == ''");
+    let Node::Compare(_, left, _) = &lower.root else {
+        unreachable!()
+    };
+    assert!(matches!(left.eval(&attributes), Value::Str(Cow::Borrowed("/orders/42"))));
+    let query = expr("query('page') == ''");
+    let Node::Compare(_, left, _) = &query.root else {
+        unreachable!()
+    };
+    assert!(matches!(left.eval(&attributes), Value::Str(Cow::Borrowed("2"))));
+}
+
+/// Deterministic xorshift generator, so a failing fuzz case reproduces.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
+
+/// Compiles `source`, checking that errors point into it and that whatever compiles evaluates.
+fn fuzz_one(source: &str, requests: &[Request]) {
+    match Expr::compile(source) {
+        Ok(expr) => {
+            for request in requests {
+                expr.eval(&request.attributes());
+            }
+        }
+        Err(e) => assert!(
+            (1..=source.chars().count() + 1).contains(&e.column),
+            "{:?}: {}",
+            source,
+            e
+        ),
+    }
+}
+
+#[test]
+fn test_fuzz_token_soup() {
+    const PIECES: [&str; 41] = [
+        "(", ")", ",", "!", "&&", "||", "==", "!=", "<", "<=", ">", ">=", "true", "false", "null", "method", "path",
+        "client_ip", "key_id", "header(", "query(", "record(", "lower(", "upper(", "len(", "starts_with(", "ends_with(",
+        "contains(", "matches(", "in_cidr(", "one_of(", "'x-tier'", "'premium'", "'10.0.0.0/8'", "'10.1.2.3'",
+        "'^/o'", "'('", "0", "42", "'", "é",
+    ];
+    let requests = [request(), Request {
+        client_ip: None,
+        key_id: None,
+        query: "",
+        headers: HeaderMap::new(),
+        ..request()
+    }];
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let mut compiled = 0;
+    for _ in 0..20_000 {
+        let len = 1 + rng.below(16);
+        let source: Vec<&str> = (0..len).map(|_| PIECES[rng.below(PIECES.len())]).collect();
+        let source = source.join(if rng.below(2) == 0 { " " } else { "" });
+        fuzz_one(&source, &requests);
+        compiled += usize::from(Expr::compile(&source).is_ok());
+    }
+    // The soup should reach the evaluator too, not just the error paths
+    assert!(compiled > 0);
+}
+
+#[test]
+fn test_fuzz_random_text() {
+    const CHARS: &[char] = &[
+        '(', ')', '"', '\'', '\\', '!', '&', '|', '=', '<', '>', ',', ' ', 'a', '_', '1', 'é', '\n', '\0',
+    ];
+    let requests = [request()];
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    for _ in 0..20_000 {
+        let len = rng.below(40);
+        let source: String = (0..len).map(|_| CHARS[rng.below(CHARS.len())]).collect();
+        fuzz_one(&source, &requests);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::config::PriorityClass;
+use std::collections::VecDeque;
+
+/// A bounded queue admitting entries of several priority classes by weighted fair queuing.
+///
+/// Each entry is stamped with a virtual finish time: the later of the queue's virtual time and the
+/// finish time of the previous entry of its class, plus the inverse of its class weight. [`pop`]
+/// takes the entry that finishes first, so while every class has entries waiting, a class of
+/// weight `w` is admitted `w` times for every `W` pops, `W` being the sum of the weights. A class
+/// that was idle starts from the current virtual time rather than from credit saved up. Within a
+/// class, entries leave in the order they arrived.
+///
+/// [`pop`]: FairQueue::pop
+pub struct FairQueue<T> {
+    capacity: usize,
+    /// Entries waiting per class, in the order of [`PriorityClass::ALL`].
+    classes: [VecDeque<Entry<T>>; 3],
+    /// Finish time of the last entry stamped per class.
+    last_finish: [f64; 3],
+    /// Finish time of the last entry popped.
+    virtual_time: f64,
+    next_id: u64,
+}
+
+struct Entry<T> {
+    id: u64,
+    start: f64,
+    finish: f64,
+    item: T,
+}
+
+/// An entry taken in by [`FairQueue::push`].
+#[derive(Debug)]
+pub struct Pushed<T> {
+    /// Identifies the entry to [`FairQueue::remove`].
+    pub id: u64,
+    /// The entry of a lower class dropped to make room, when the queue was full.
+    pub shed: Option<(PriorityClass, T)>,
+}
+
+fn index(class: PriorityClass) -> usize {
+    match class {
+        PriorityClass::Critical => 0,
+        PriorityClass::Normal => 1,
+        PriorityClass::Batch => 2,
+    }
+}
+
+impl<T> FairQueue<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            classes: Default::default(),
+            last_finish: [0.0; 3],
+            virtual_time: 0.0,
+            next_id: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.classes.iter().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.classes.iter().all(VecDeque::is_empty)
+    }
+
+    /// Entries waiting in `class`.
+    pub fn depth(&self, class: PriorityClass) -> usize {
+        self.classes[index(class)].len()
+    }
+
+    /// Queues `item` in `class`, whose weight is `weight` (at least 1). A full queue makes room by
+    /// shedding the newest entry of the lowest class below `class`, and hands `item` back when
+    /// there is none.
+    pub fn push(&mut self, class: PriorityClass, weight: u32, item: T) -> Result<Pushed<T>, T> {
+        let mut shed = None;
+        if self.len() >= self.capacity {
+            let lower = PriorityClass::ALL
+                .into_iter()
+                .rev()
+                .take_while(|lower| *lower > class)
+                .find(|lower| self.depth(*lower) > 0);
+            let Some(lower) = lower else {
+                return Err(item);
+            };
+            let queue = &mut self.classes[index(lower)];
+            let entry = queue.pop_back().expect("the class has entries");
+            // The class does not pay for an entry that was never admitted
+            self.last_finish[index(lower)] = queue.back().map_or(entry.start, |last| last.finish);
+            shed = Some((lower, entry.item));
+        }
+        let i = index(class);
+        let start = self.virtual_time.max(self.last_finish[i]);
+        let finish = start + 1.0 / f64::from(weight.max(1));
+        self.last_finish[i] = finish;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.classes[i].push_back(Entry {
+            id,
+            start,
+            finish,
+            item,
+        });
+        Ok(Pushed { id, shed })
+    }
+
+    /// Takes the entry with the earliest finish time, the higher class first on a tie.
+    pub fn pop(&mut self) -> Option<(PriorityClass, T)> {
+        let i = (0..self.classes.len())
+            .filter(|i| !self.classes[*i].is_empty())
+            .min_by(|a, b| self.classes[*a][0].finish.total_cmp(&self.classes[*b][0].finish))?;
+        let entry = self.classes[i].pop_front().expect("the class has entries");
+        self.virtual_time = entry.finish;
+        Some((PriorityClass::ALL[i], entry.item))
+    }
+
+    /// Takes out the entry `id`, if it is still waiting.
+    pub fn remove(&mut self, id: u64) -> Option<T> {
+        self.classes.iter_mut().find_map(|queue| {
+            let position = queue.iter().position(|entry| entry.id == id)?;
+            queue.remove(position).map(|entry| entry.item)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("fair_queue_tests.rs");
+}
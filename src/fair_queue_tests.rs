@@ -0,0 +1,152 @@
+use super::*;
+use crate::config::PriorityWeights;
+
+/// A xorshift generator, so the randomized tests replay the same traffic on every run.
+struct Seeded(u64);
+
+impl Seeded {
+    fn next(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+}
+
+fn push(queue: &mut FairQueue<u32>, class: PriorityClass, item: u32) -> Result<Pushed<u32>, u32> {
+    queue.push(class, PriorityWeights::default().weight(class), item)
+}
+
+/// Pops `n` entries, refilling each class as soon as it runs empty so that all stay backlogged,
+/// and counts the admissions per class.
+fn admissions(n: usize) -> [usize; 3] {
+    let mut queue = FairQueue::new(usize::MAX);
+    let mut counts = [0; 3];
+    for class in PriorityClass::ALL {
+        push(&mut queue, class, 0).unwrap();
+    }
+    for _ in 0..n {
+        let (class, _) = queue.pop().unwrap();
+        counts[index(class)] += 1;
+        push(&mut queue, class, 0).unwrap();
+    }
+    counts
+}
+
+#[test]
+fn test_backlogged_classes_share_by_weight() {
+    // Weights 8, 4 and 1 out of 13
+    assert_eq!(admissions(1300), [800, 400, 100]);
+}
+
+#[test]
+fn test_no_class_starves() {
+    let mut queue = FairQueue::new(usize::MAX);
+    for i in 0..100 {
+        push(&mut queue, PriorityClass::Critical, i).unwrap();
+    }
+    push(&mut queue, PriorityClass::Batch, 100).unwrap();
+    let position = std::iter::from_fn(|| queue.pop())
+        .position(|(class, _)| class == PriorityClass::Batch)
+        .unwrap();
+    // Admitted after at most one round of the heavier class
+    assert!(position <= 8, "batch admitted after {} critical requests", position);
+}
+
+#[test]
+fn test_fifo_within_a_class() {
+    let mut queue = FairQueue::new(10);
+    for i in 0..5 {
+        push(&mut queue, PriorityClass::Normal, i).unwrap();
+    }
+    let order: Vec<u32> = std::iter::from_fn(|| queue.pop()).map(|(_, item)| item).collect();
+    assert_eq!(order, [0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn test_idle_class_saves_no_credit() {
+    let mut queue = FairQueue::new(usize::MAX);
+    // Critical is served alone for a while, then batch arrives with a burst
+    for i in 0..50 {
+        push(&mut queue, PriorityClass::Critical, i).unwrap();
+        queue.pop().unwrap();
+    }
+    for i in 0..10 {
+        push(&mut queue, PriorityClass::Batch, i).unwrap();
+        push(&mut queue, PriorityClass::Critical, i).unwrap();
+    }
+    let first: Vec<PriorityClass> = std::iter::from_fn(|| queue.pop()).take(9).map(|(class, _)| class).collect();
+    let batch = first.iter().filter(|class| **class == PriorityClass::Batch).count();
+    assert_eq!(batch, 1, "{:?}", first);
+}
+
+#[test]
+fn test_full_queue_sheds_lowest_class_first() {
+    let mut queue = FairQueue::new(3);
+    push(&mut queue, PriorityClass::Normal, 1).unwrap();
+    push(&mut queue, PriorityClass::Batch, 2).unwrap();
+    push(&mut queue, PriorityClass::Batch, 3).unwrap();
+
+    // The newest batch entry makes room first, then the other, then normal ones
+    let pushed = push(&mut queue, PriorityClass::Critical, 4).unwrap();
+    assert_eq!(pushed.shed, Some((PriorityClass::Batch, 3)));
+    let pushed = push(&mut queue, PriorityClass::Normal, 5).unwrap();
+    assert_eq!(pushed.shed, Some((PriorityClass::Batch, 2)));
+    let pushed = push(&mut queue, PriorityClass::Critical, 6).unwrap();
+    assert_eq!(pushed.shed, Some((PriorityClass::Normal, 5)));
+    assert_eq!(queue.len(), 3);
+
+    // Nothing lower to shed: the newcomer is turned away
+    assert_eq!(push(&mut queue, PriorityClass::Normal, 7).unwrap_err(), 7);
+    assert_eq!(push(&mut queue, PriorityClass::Batch, 8).unwrap_err(), 8);
+    assert_eq!(queue.depth(PriorityClass::Critical), 2);
+    assert_eq!(queue.depth(PriorityClass::Normal), 1);
+}
+
+#[test]
+fn test_zero_capacity_rejects() {
+    let mut queue = FairQueue::new(0);
+    assert_eq!(push(&mut queue, PriorityClass::Critical, 1).unwrap_err(), 1);
+}
+
+#[test]
+fn test_remove() {
+    let mut queue = FairQueue::new(10);
+    let first = push(&mut queue, PriorityClass::Normal, 1).unwrap().id;
+    push(&mut queue, PriorityClass::Batch, 2).unwrap();
+    assert_eq!(queue.remove(first), Some(1));
+    assert_eq!(queue.remove(first), None);
+    assert_eq!(queue.pop(), Some((PriorityClass::Batch, 2)));
+    assert!(queue.is_empty());
+}
+
+/// Random arrivals and departures: the same seed replays the same admissions, and every entry
+/// pushed is popped, shed, rejected or still waiting at the end.
+fn simulate(seed: u64) -> Vec<(PriorityClass, u32)> {
+    let mut rng = Seeded(seed);
+    let mut queue = FairQueue::new(16);
+    let (mut admitted, mut arrivals, mut dropped) = (Vec::new(), 0, 0);
+    for item in 0..2000 {
+        if rng.next(3) > 0 {
+            arrivals += 1;
+            let class = PriorityClass::ALL[rng.next(3) as usize];
+            match push(&mut queue, class, item) {
+                Ok(pushed) => dropped += usize::from(pushed.shed.is_some()),
+                Err(_) => dropped += 1,
+            }
+        } else if let Some(entry) = queue.pop() {
+            admitted.push(entry);
+        }
+    }
+    assert_eq!(admitted.len() + dropped + queue.len(), arrivals);
+    admitted
+}
+
+#[test]
+fn test_seeded_simulation_is_deterministic_and_serves_every_class() {
+    let admitted = simulate(0x9e37_79b9_7f4a_7c15);
+    assert_eq!(admitted, simulate(0x9e37_79b9_7f4a_7c15));
+    for class in PriorityClass::ALL {
+        assert!(admitted.iter().any(|(admitted, _)| *admitted == class), "{:?} starved", class);
+    }
+}
@@ -0,0 +1,248 @@
+use crate::cidr::Cidr;
+use crate::config::{Condition, FeatureFlag};
+use crate::expr::Attributes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+
+/// Adds `x-gateway-upstream` and the other `debug_headers` to the response.
+pub const DEBUG_HEADERS: &str = "debug_headers";
+
+/// Every flag a feature checks. Config and admin changes naming other flags are rejected.
+pub const KNOWN_FLAGS: [&str; 1] = [DEBUG_HEADERS];
+
+/// Hex digits of a key ID.
+const KEY_ID_LEN: usize = 16;
+
+/// The ID flags refer to an API key by, so the key itself stays out of the config: the first 16 hex
+/// digits of its SHA-256.
+pub fn key_id(api_key: &str) -> String {
+    let mut id = format!("{:x}", Sha256::digest(api_key.as_bytes()));
+    id.truncate(KEY_ID_LEN);
+    id
+}
+
+/// What flags are evaluated against for one request, with the flags as they were when it started.
+#[derive(Clone, Debug, Default)]
+pub struct FlagContext {
+    flags: Arc<CompiledFlags>,
+    /// ID of the API key the request authenticated with.
+    pub key_id: Option<String>,
+    /// Address of the connected client.
+    pub client_ip: Option<IpAddr>,
+    /// Flags whose `when` the request does not meet, evaluated when the context is created.
+    unmet: Vec<String>,
+}
+
+/// Whether the request of `ctx` may use `flag`: the flag is enabled, the request is in both of its
+/// allow lists and meets its `when`. Unknown flags are never allowed.
+pub fn is_allowed(name: &str, ctx: &FlagContext) -> bool {
+    let Some(flag) = ctx.flags.get(name) else {
+        return false;
+    };
+    flag.enabled
+        && !ctx.unmet.iter().any(|unmet| unmet == name)
+        && flag
+            .key_ids
+            .as_ref()
+            .is_none_or(|ids| ctx.key_id.as_ref().is_some_and(|id| ids.contains(id)))
+        && flag
+            .cidrs
+            .as_ref()
+            .is_none_or(|cidrs| ctx.client_ip.is_some_and(|ip| cidrs.iter().any(|c| c.contains(ip))))
+}
+
+/// Checks the name and allow lists of a flag.
+pub fn validate(name: &str, flag: &FeatureFlag) -> Result<(), String> {
+    if !KNOWN_FLAGS.contains(&name) {
+        return Err(format!(
+            "feature_flags: unknown flag {:?}, expected one of {}",
+            name,
+            KNOWN_FLAGS.join(", ")
+        ));
+    }
+    for cidr in &flag.allowed_cidrs {
+        cidr.parse::<Cidr>()
+            .map_err(|e| format!("feature_flags: {}: {}", name, e))?;
+    }
+    if let Some(when) = &flag.when {
+        if let Some(e) = when.error() {
+            return Err(format!("feature_flags: {}: when {:?}: {}", name, when.as_str(), e));
+        }
+    }
+    for id in &flag.allowed_key_ids {
+        if id.len() != KEY_ID_LEN || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(format!(
+                "feature_flags: {}: key ID {:?} must be {} hex digits",
+                name, id, KEY_ID_LEN
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Changes made to a flag at runtime, layered over its value from the config file field by field.
+/// Overrides outlive config reloads until they are cleared.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlagOverride {
+    pub enabled: Option<bool>,
+    pub allowed_key_ids: Option<Vec<String>>,
+    pub allowed_cidrs: Option<Vec<String>>,
+    pub when: Option<Condition>,
+}
+
+impl FlagOverride {
+    fn apply(&self, flag: &FeatureFlag) -> FeatureFlag {
+        FeatureFlag {
+            enabled: self.enabled.unwrap_or(flag.enabled),
+            allowed_key_ids: self
+                .allowed_key_ids
+                .clone()
+                .unwrap_or_else(|| flag.allowed_key_ids.clone()),
+            allowed_cidrs: self.allowed_cidrs.clone().unwrap_or_else(|| flag.allowed_cidrs.clone()),
+            when: self.when.clone().or_else(|| flag.when.clone()),
+        }
+    }
+}
+
+/// A flag as it is in effect, and the override it comes from, if any.
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct FlagReport {
+    #[serde(flatten)]
+    pub effective: FeatureFlag,
+    #[serde(rename = "override")]
+    pub override_: Option<FlagOverride>,
+}
+
+/// The feature flags in effect: the config file's values with the runtime overrides on top.
+#[derive(Clone, Default)]
+pub struct FeatureFlags {
+    inner: Arc<RwLock<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    baseline: BTreeMap<String, FeatureFlag>,
+    overrides: BTreeMap<String, FlagOverride>,
+    compiled: Arc<CompiledFlags>,
+}
+
+type CompiledFlags = HashMap<String, CompiledFlag>;
+
+/// A flag ready for per-request checks; `None` allow lists allow everyone.
+#[derive(Debug)]
+struct CompiledFlag {
+    enabled: bool,
+    key_ids: Option<HashSet<String>>,
+    cidrs: Option<Vec<Cidr>>,
+    when: Option<Condition>,
+}
+
+impl FeatureFlags {
+    pub fn new(flags: &BTreeMap<String, FeatureFlag>) -> Self {
+        let feature_flags = Self::default();
+        feature_flags.reload(flags);
+        feature_flags
+    }
+
+    /// A context for a request, holding the flags as they are now. Their conditions are evaluated
+    /// here, against the request as it arrived.
+    pub fn context(&self, request: &Attributes) -> FlagContext {
+        let flags = self.inner.read().unwrap().compiled.clone();
+        let unmet = flags
+            .iter()
+            .filter(|(_, flag)| flag.when.as_ref().is_some_and(|when| !when.holds(request)))
+            .map(|(name, _)| name.clone())
+            .collect();
+        FlagContext {
+            flags,
+            key_id: request.key_id.map(String::from),
+            client_ip: request.client_ip,
+            unmet,
+        }
+    }
+
+    /// Takes the flags of a reloaded config file as the new baseline, keeping runtime overrides.
+    pub fn reload(&self, flags: &BTreeMap<String, FeatureFlag>) {
+        let mut inner = self.inner.write().unwrap();
+        inner.baseline = flags.clone();
+        inner.recompile();
+    }
+
+    /// Replaces the runtime override of `flag`, returning the flag now in effect.
+    pub fn set_override(&self, flag: &str, change: FlagOverride) -> Result<FeatureFlag, String> {
+        let mut inner = self.inner.write().unwrap();
+        let effective = change.apply(&inner.baseline_of(flag));
+        validate(flag, &effective)?;
+        inner.overrides.insert(flag.to_string(), change);
+        inner.recompile();
+        Ok(effective)
+    }
+
+    /// Drops the runtime override of `flag`, returning whether there was one.
+    pub fn clear_override(&self, flag: &str) -> bool {
+        let mut inner = self.inner.write().unwrap();
+        let cleared = inner.overrides.remove(flag).is_some();
+        inner.recompile();
+        cleared
+    }
+
+    /// Every known flag as it is in effect.
+    pub fn report(&self) -> BTreeMap<String, FlagReport> {
+        let inner = self.inner.read().unwrap();
+        KNOWN_FLAGS
+            .iter()
+            .map(|name| {
+                let report = FlagReport {
+                    effective: inner.effective(name),
+                    override_: inner.overrides.get(*name).cloned(),
+                };
+                (name.to_string(), report)
+            })
+            .collect()
+    }
+}
+
+impl Inner {
+    fn baseline_of(&self, flag: &str) -> FeatureFlag {
+        self.baseline.get(flag).cloned().unwrap_or_default()
+    }
+
+    fn effective(&self, flag: &str) -> FeatureFlag {
+        let baseline = self.baseline_of(flag);
+        match self.overrides.get(flag) {
+            Some(change) => change.apply(&baseline),
+            None => baseline,
+        }
+    }
+
+    fn recompile(&mut self) {
+        let names: HashSet<&String> = self.baseline.keys().chain(self.overrides.keys()).collect();
+        let compiled = names
+            .into_iter()
+            .map(|name| (name.clone(), compile(&self.effective(name))))
+            .collect();
+        self.compiled = Arc::new(compiled);
+    }
+}
+
+fn compile(flag: &FeatureFlag) -> CompiledFlag {
+    CompiledFlag {
+        enabled: flag.enabled,
+        key_ids: (!flag.allowed_key_ids.is_empty())
+            .then(|| flag.allowed_key_ids.iter().map(|id| id.to_ascii_lowercase()).collect()),
+        // Networks are validated with the config and with overrides; one that does not parse
+        // matches nothing, so the list still restricts
+        cidrs: (!flag.allowed_cidrs.is_empty())
+            .then(|| flag.allowed_cidrs.iter().filter_map(|cidr| cidr.parse().ok()).collect()),
+        when: flag.when.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("flags_tests.rs");
+}
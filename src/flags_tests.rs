@@ -0,0 +1,200 @@
+use super::*;
+use axum::http::{HeaderMap, HeaderName, Method};
+
+fn flag(enabled: bool, key_ids: &[&str], cidrs: &[&str]) -> FeatureFlag {
+    FeatureFlag {
+        enabled,
+        allowed_key_ids: key_ids.iter().map(|id| id.to_string()).collect(),
+        allowed_cidrs: cidrs.iter().map(|cidr| cidr.to_string()).collect(),
+        when: None,
+    }
+}
+
+fn flags(flag: FeatureFlag) -> FeatureFlags {
+    FeatureFlags::new(&BTreeMap::from([(DEBUG_HEADERS.to_string(), flag)]))
+}
+
+fn context(flags: &FeatureFlags, key: Option<&str>, ip: Option<&str>, headers: &HeaderMap) -> FlagContext {
+    let key_id = key.map(key_id);
+    flags.context(&Attributes {
+        method: &Method::GET,
+        path: "/",
+        query: "",
+        headers,
+        client_ip: ip.map(|ip| ip.parse().unwrap()),
+        key_id: key_id.as_deref(),
+        record: None,
+    })
+}
+
+fn allowed(flags: &FeatureFlags, key: Option<&str>, ip: Option<&str>) -> bool {
+    is_allowed(DEBUG_HEADERS, &context(flags, key, ip, &HeaderMap::new()))
+}
+
+fn tier(value: &str) -> HeaderMap {
+    HeaderMap::from_iter([(HeaderName::from_static("x-tier"), value.parse().unwrap())])
+}
+
+#[test]
+fn test_key_id() {
+    // printf %s secret-key | sha256sum | cut -c1-16
+    assert_eq!(key_id("secret-key"), "85dbe15d75ef9308");
+    assert_eq!(key_id(""), "e3b0c44298fc1c14");
+}
+
+#[test]
+fn test_enabled_without_allow_lists_allows_everyone() {
+    let flags = flags(flag(true, &[], &[]));
+    assert!(allowed(&flags, None, None));
+    assert!(allowed(&flags, Some("any"), Some("203.0.113.9")));
+    assert!(!allowed(&self::flags(flag(false, &[], &[])), Some("any"), Some("203.0.113.9")));
+}
+
+#[test]
+fn test_unknown_or_unset_flags_are_never_allowed() {
+    let flags = FeatureFlags::default();
+    assert!(!allowed(&flags, Some("any"), Some("10.0.0.1")));
+    let ctx = context(&self::flags(flag(true, &[], &[])), None, None, &HeaderMap::new());
+    assert!(!is_allowed("no_such_flag", &ctx));
+}
+
+#[test]
+fn test_key_id_allow_list() {
+    let flags = flags(flag(true, &[&key_id("ops-key").to_uppercase()], &[]));
+    assert!(allowed(&flags, Some("ops-key"), None));
+    assert!(!allowed(&flags, Some("other-key"), None));
+    // Requests without an authenticated key are not in any key list
+    assert!(!allowed(&flags, None, None));
+}
+
+#[test]
+fn test_cidr_allow_list() {
+    let flags = flags(flag(true, &[], &["10.0.0.0/8", "2001:db8::/32"]));
+    assert!(allowed(&flags, None, Some("10.20.30.40")));
+    assert!(allowed(&flags, None, Some("::ffff:10.1.1.1")));
+    assert!(allowed(&flags, None, Some("2001:db8::7")));
+    assert!(!allowed(&flags, None, Some("192.0.2.1")));
+    assert!(!allowed(&flags, None, None));
+}
+
+#[test]
+fn test_both_allow_lists_must_match() {
+    let flags = flags(flag(true, &[&key_id("ops-key")], &["10.0.0.0/8"]));
+    assert!(allowed(&flags, Some("ops-key"), Some("10.0.0.1")));
+    assert!(!allowed(&flags, Some("ops-key"), Some("192.0.2.1")));
+    assert!(!allowed(&flags, Some("other-key"), Some("10.0.0.1")));
+}
+
+#[test]
+fn test_overrides_layer_over_the_file_field_by_field() {
+    let flags = flags(flag(false, &[], &["10.0.0.0/8"]));
+    let change = FlagOverride {
+        enabled: Some(true),
+        ..Default::default()
+    };
+    assert_eq!(flags.set_override(DEBUG_HEADERS, change).unwrap(), flag(true, &[], &["10.0.0.0/8"]));
+    assert!(allowed(&flags, None, Some("10.0.0.1")));
+    assert!(!allowed(&flags, None, Some("192.0.2.1")));
+}
+
+#[test]
+fn test_overrides_survive_reloads() {
+    let flags = flags(flag(false, &[], &["10.0.0.0/8"]));
+    let change = FlagOverride {
+        enabled: Some(true),
+        ..Default::default()
+    };
+    flags.set_override(DEBUG_HEADERS, change.clone()).unwrap();
+
+    // The reloaded file is the new baseline, the override stays on top of it
+    flags.reload(&BTreeMap::from([(DEBUG_HEADERS.to_string(), flag(false, &[], &["192.0.2.0/24"]))]));
+    assert!(allowed(&flags, None, Some("192.0.2.1")));
+    assert!(!allowed(&flags, None, Some("10.0.0.1")));
+    // Even when the file no longer lists the flag
+    flags.reload(&BTreeMap::new());
+    assert!(allowed(&flags, None, Some("10.0.0.1")));
+    assert_eq!(flags.report()[DEBUG_HEADERS].override_, Some(change));
+
+    assert!(flags.clear_override(DEBUG_HEADERS));
+    assert!(!flags.clear_override(DEBUG_HEADERS));
+    assert!(!allowed(&flags, None, Some("10.0.0.1")));
+}
+
+#[test]
+fn test_invalid_overrides_are_rejected() {
+    let flags = flags(flag(true, &[], &["10.0.0.0/8"]));
+    let bad_network = FlagOverride {
+        allowed_cidrs: Some(vec!["10.0.0.0/99".to_string()]),
+        ..Default::default()
+    };
+    assert!(flags.set_override(DEBUG_HEADERS, bad_network).is_err());
+    let bad_key_id = FlagOverride {
+        allowed_key_ids: Some(vec!["ops-key".to_string()]),
+        ..Default::default()
+    };
+    assert!(flags.set_override(DEBUG_HEADERS, bad_key_id).is_err());
+    assert!(flags.set_override("no_such_flag", FlagOverride::default()).is_err());
+    // Nothing changed
+    assert!(allowed(&flags, None, Some("10.0.0.1")));
+    assert_eq!(flags.report()[DEBUG_HEADERS].override_, None);
+    assert!(serde_json::from_str::<FlagOverride>(r#"{"enable": true}"#).is_err());
+}
+
+#[test]
+fn test_requests_keep_the_flags_they_started_with() {
+    let flags = flags(flag(true, &[], &[]));
+    let ctx = context(&flags, None, None, &HeaderMap::new());
+    let change = FlagOverride {
+        enabled: Some(false),
+        ..Default::default()
+    };
+    flags.set_override(DEBUG_HEADERS, change).unwrap();
+    assert!(is_allowed(DEBUG_HEADERS, &ctx));
+    assert!(!allowed(&flags, None, None));
+}
+
+#[test]
+fn test_validate() {
+    assert_eq!(validate(DEBUG_HEADERS, &flag(true, &[&key_id("k")], &["10.0.0.0/8", "::1"])), Ok(()));
+    assert!(validate("debug_header", &FeatureFlag::default()).is_err());
+    assert!(validate(DEBUG_HEADERS, &flag(true, &["not-hex-digits!!"], &[])).is_err());
+    assert!(validate(DEBUG_HEADERS, &flag(true, &[], &["10.0.0.0"])).is_ok());
+    assert!(validate(DEBUG_HEADERS, &flag(true, &[], &["ten"])).is_err());
+    let broken = FeatureFlag {
+        when: Some(Condition::new(r#"header("x-tier") =="#)),
+        ..flag(true, &[], &[])
+    };
+    let error = validate(DEBUG_HEADERS, &broken).unwrap_err();
+    assert!(error.starts_with("feature_flags: debug_headers: when "), "{}", error);
+    assert!(error.ends_with("column 20: expected a value, found end of condition"), "{}", error);
+}
+
+#[test]
+fn test_when_must_hold_too() {
+    let flags = flags(FeatureFlag {
+        when: Some(Condition::new(r#"header("x-tier") == "gold" || key_id != null"#)),
+        ..flag(true, &[], &["10.0.0.0/8"])
+    });
+    let allowed = |key, ip, headers: HeaderMap| is_allowed(DEBUG_HEADERS, &context(&flags, key, ip, &headers));
+    assert!(allowed(None, Some("10.0.0.1"), tier("gold")));
+    assert!(allowed(Some("ops-key"), Some("10.0.0.1"), HeaderMap::new()));
+    assert!(!allowed(None, Some("10.0.0.1"), tier("silver")));
+    // Allow lists still apply
+    assert!(!allowed(None, Some("192.0.2.1"), tier("gold")));
+
+    // Overrides may replace the condition, and a condition that does not compile is rejected
+    let change = FlagOverride {
+        when: Some(Condition::new(r#"header("x-tier") == "silver""#)),
+        ..Default::default()
+    };
+    flags.set_override(DEBUG_HEADERS, change).unwrap();
+    assert!(allowed(None, Some("10.0.0.1"), tier("silver")));
+    assert!(!allowed(None, Some("10.0.0.1"), tier("gold")));
+    let broken = FlagOverride {
+        when: Some(Condition::new("len(")),
+        ..Default::default()
+    };
+    assert!(flags.set_override(DEBUG_HEADERS, broken).is_err());
+    let change: FlagOverride = serde_json::from_str(r#"{"when": "method == \"GET\""}"#).unwrap();
+    assert_eq!(change.when, Some(Condition::new(r#"method == "GET""#)));
+}
@@ -0,0 +1,72 @@
+use crate::config::Config;
+use crate::error::{ErrorPhase, GatewayError};
+use crate::invoker::BufferedOutput;
+use crate::request::MAX_INVOKE_PAYLOAD_BYTES;
+use axum::http::StatusCode;
+use serde::Deserialize;
+
+/// The error object a failed function returns instead of a response, e.g.
+/// `{"errorType": "TypeError", "errorMessage": "x is undefined", "stackTrace": [...]}`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct LambdaError {
+    pub error_type: String,
+    pub error_message: String,
+    /// Left as sent, since runtimes format it differently.
+    pub stack_trace: serde_json::Value,
+}
+
+impl LambdaError {
+    /// Reads the error object from `payload`. A payload that is not one, such as the output of a
+    /// crashed runtime, is taken as the message.
+    pub fn parse(payload: &[u8]) -> Self {
+        serde_json::from_slice(payload).unwrap_or_else(|_| LambdaError {
+            error_message: String::from_utf8_lossy(payload).trim().to_string(),
+            ..Default::default()
+        })
+    }
+}
+
+/// The error answering a request whose function failed with `error`: `function_error_status`,
+/// with an opaque message unless `expose_function_errors` is set.
+pub fn gateway_error(config: &Config, error: &LambdaError) -> GatewayError {
+    let status = StatusCode::from_u16(config.function_error_status).unwrap_or(StatusCode::BAD_GATEWAY);
+    if !config.expose_function_errors {
+        return GatewayError::new(ErrorPhase::Upstream, status, "function_error", "The function failed");
+    }
+    let message = match error.error_type.as_str() {
+        "" => format!("The function failed: {}", error.error_message),
+        error_type => format!("The function failed with {}: {}", error_type, error.error_message),
+    };
+    GatewayError::new(ErrorPhase::Upstream, status, "function_error", message)
+        .with_detail("error_type", error.error_type.as_str())
+        .with_detail("error_message", error.error_message.as_str())
+}
+
+/// `errorType` of the error Lambda returns in place of a response larger than an invoke carries.
+pub const RESPONSE_SIZE_TOO_LARGE: &str = "Function.ResponseSizeTooLarge";
+
+/// Whether a buffered invoke returned more than the invoke payload limit: Lambda's error for it, or
+/// a payload that large, as one reassembled from a truncated read would be.
+pub fn response_too_large(output: &BufferedOutput) -> bool {
+    output.payload.len() > MAX_INVOKE_PAYLOAD_BYTES
+        || output.function_error.is_some() && LambdaError::parse(&output.payload).error_type == RESPONSE_SIZE_TOO_LARGE
+}
+
+/// The error answering a request whose function response did not fit in the invoke payload.
+pub fn response_too_large_error() -> GatewayError {
+    GatewayError::new(
+        ErrorPhase::Upstream,
+        StatusCode::BAD_GATEWAY,
+        "response_payload_too_large",
+        format!(
+            "The function response exceeds the Lambda payload limit of {} bytes",
+            MAX_INVOKE_PAYLOAD_BYTES
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    include!("function_errors_tests.rs");
+}
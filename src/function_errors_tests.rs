@@ -0,0 +1,51 @@
+use super::*;
+
+#[test]
+fn test_parse_error_object() {
+    let payload = br#"{"errorType": "TypeError", "errorMessage": "x is undefined", "stackTrace": ["at handler"]}"#;
+    let error = LambdaError::parse(payload);
+    assert_eq!((error.error_type.as_str(), error.error_message.as_str()), ("TypeError", "x is undefined"));
+    assert_eq!(error.stack_trace, serde_json::json!(["at handler"]));
+
+    // Runtimes that crash report plain text
+    let error = LambdaError::parse(b"RequestId: 1 Error: Runtime exited with error: signal: killed\n");
+    assert_eq!(error.error_type, "");
+    assert_eq!(error.error_message, "RequestId: 1 Error: Runtime exited with error: signal: killed");
+}
+
+#[test]
+fn test_gateway_error_exposes_details_on_opt_in() {
+    let error = LambdaError::parse(br#"{"errorType": "TypeError", "errorMessage": "x is undefined"}"#);
+    let opaque = gateway_error(&Config::default(), &error);
+    assert_eq!((opaque.status, opaque.code), (StatusCode::BAD_GATEWAY, "function_error"));
+    assert_eq!(opaque.message, "The function failed");
+    assert!(opaque.details.is_empty());
+
+    let config = Config {
+        function_error_status: 500,
+        expose_function_errors: true,
+        ..Default::default()
+    };
+    let exposed = gateway_error(&config, &error);
+    assert_eq!(exposed.status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(exposed.message, "The function failed with TypeError: x is undefined");
+    assert_eq!(exposed.details["error_type"], "TypeError");
+    assert_eq!(exposed.details["error_message"], "x is undefined");
+}
+
+#[test]
+fn test_response_too_large() {
+    let output = |payload: Vec<u8>, function_error: Option<&str>| BufferedOutput {
+        payload: payload.into(),
+        function_error: function_error.map(String::from),
+        request_id: None,
+    };
+    let overflow = br#"{"errorType": "Function.ResponseSizeTooLarge", "errorMessage": "too large"}"#.to_vec();
+    assert!(response_too_large(&output(overflow.clone(), Some("Unhandled"))));
+    assert!(response_too_large(&output(vec![b'a'; MAX_INVOKE_PAYLOAD_BYTES + 1], None)));
+    assert!(!response_too_large(&output(vec![b'a'; MAX_INVOKE_PAYLOAD_BYTES], None)));
+    // Only Lambda's error says so, not a response that happens to look like it
+    assert!(!response_too_large(&output(overflow, None)));
+    let thrown = br#"{"errorType": "TypeError", "errorMessage": "x is undefined"}"#.to_vec();
+    assert!(!response_too_large(&output(thrown, Some("Unhandled"))));
+}
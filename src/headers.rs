@@ -0,0 +1,141 @@
+use crate::config::Target;
+use crate::error::{ErrorPhase, GatewayError};
+use axum::http::header::SET_COOKIE;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+
+/// Headers stripped from every response: Lambda's remapped copies of reserved headers.
+pub const DEFAULT_STRIP_RESPONSE_HEADERS: &[&str] = &["x-amzn-remapped-*"];
+
+/// Removes internal headers from a Lambda response before it reaches the client.
+///
+/// Headers matching the default strip list or the target's `strip_response_headers` are removed.
+/// When `allow_response_headers` is set, only matching headers survive. `Set-Cookie` is never
+/// removed by a glob; it is only stripped when named explicitly.
+pub fn sanitize_response_headers(headers: &mut HeaderMap, target: &Target) {
+    let names: Vec<_> = headers.keys().cloned().collect();
+    for name in names {
+        if should_strip(name.as_str(), target) {
+            headers.remove(&name);
+        }
+    }
+}
+
+fn should_strip(name: &str, target: &Target) -> bool {
+    if name == SET_COOKIE {
+        return target
+            .strip_response_headers
+            .iter()
+            .any(|pattern| pattern.eq_ignore_ascii_case(name));
+    }
+    if !target.allow_response_headers.is_empty()
+        && !target
+            .allow_response_headers
+            .iter()
+            .any(|pattern| glob_match(pattern, name))
+    {
+        return true;
+    }
+    DEFAULT_STRIP_RESPONSE_HEADERS
+        .iter()
+        .copied()
+        .chain(target.strip_response_headers.iter().map(String::as_str))
+        .any(|pattern| glob_match(pattern, name))
+}
+
+/// Case-insensitive match where `*` matches any run of characters.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let name = name.to_ascii_lowercase();
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<_> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Headers returned by a function, validated one by one. Entries that cannot be sent over HTTP,
+/// e.g. values containing a newline, are kept out of `valid` and only their names are remembered.
+#[derive(Debug, Default)]
+pub struct UpstreamHeaders {
+    pub valid: HeaderMap,
+    /// Names of the dropped headers, escaped for logging.
+    pub invalid: Vec<String>,
+}
+
+impl UpstreamHeaders {
+    pub fn append(&mut self, name: &str, value: &str) {
+        match (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+            (Ok(name), Ok(value)) => {
+                self.valid.append(name, value);
+            }
+            _ => {
+                let name = name.escape_debug().to_string();
+                tracing::warn!(header = %name, "Dropping invalid header from the function response (value redacted)");
+                self.invalid.push(name);
+            }
+        }
+    }
+
+    /// The headers to send to the client. With `strict`, any invalid header fails the response
+    /// instead of being dropped.
+    pub fn checked(self, strict: bool) -> Result<HeaderMap, GatewayError> {
+        if strict && !self.invalid.is_empty() {
+            return Err(GatewayError::new(
+                ErrorPhase::Upstream,
+                StatusCode::BAD_GATEWAY,
+                "invalid_upstream_header",
+                format!("Function returned invalid headers: {}", self.invalid.join(", ")),
+            ));
+        }
+        Ok(self.valid)
+    }
+}
+
+impl<'a> FromIterator<(&'a str, &'a str)> for UpstreamHeaders {
+    fn from_iter<I: IntoIterator<Item = (&'a str, &'a str)>>(iter: I) -> Self {
+        let mut headers = Self::default();
+        for (name, value) in iter {
+            headers.append(name, value);
+        }
+        headers
+    }
+}
+
+impl<'de> Deserialize<'de> for UpstreamHeaders {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Values {
+            One(String),
+            Many(Vec<String>),
+        }
+
+        let raw = BTreeMap::<String, Values>::deserialize(deserializer)?;
+        let mut headers = Self::default();
+        for (name, values) in &raw {
+            match values {
+                Values::One(value) => headers.append(name, value),
+                Values::Many(values) => values.iter().for_each(|value| headers.append(name, value)),
+            }
+        }
+        Ok(headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("headers_tests.rs");
+}
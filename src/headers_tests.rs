@@ -0,0 +1,139 @@
+use super::*;
+
+#[test]
+fn test_glob_match() {
+    let cases = [
+        ("x-amzn-remapped-*", "x-amzn-remapped-date", true),
+        ("x-amzn-remapped-*", "x-amzn-requestid", false),
+        ("X-Internal-*", "x-internal-debug", true),
+        ("server", "server", true),
+        ("server", "server-timing", false),
+        ("*-debug", "x-internal-debug", true),
+        ("x-*-debug", "x-internal-debug", true),
+        ("x-*-debug", "x-debug", false),
+        ("*", "anything", true),
+    ];
+    for (pattern, name, expected) in cases {
+        assert_eq!(glob_match(pattern, name), expected, "{} vs {}", pattern, name);
+    }
+}
+
+#[test]
+fn test_sanitize_response_headers() {
+    struct Case {
+        strip: &'static [&'static str],
+        allow: &'static [&'static str],
+        kept: &'static [&'static str],
+        removed: &'static [&'static str],
+    }
+    let cases = [
+        // Default strip list only
+        Case {
+            strip: &[],
+            allow: &[],
+            kept: &["content-type", "server", "set-cookie", "x-internal-debug"],
+            removed: &["x-amzn-remapped-date"],
+        },
+        // Configured globs on top of the defaults
+        Case {
+            strip: &["server", "x-internal-*"],
+            allow: &[],
+            kept: &["content-type", "set-cookie"],
+            removed: &["server", "x-internal-debug", "x-amzn-remapped-date"],
+        },
+        // A catch-all glob never removes Set-Cookie
+        Case {
+            strip: &["*"],
+            allow: &[],
+            kept: &["set-cookie"],
+            removed: &["content-type", "server"],
+        },
+        // ...unless it is named explicitly
+        Case {
+            strip: &["Set-Cookie"],
+            allow: &[],
+            kept: &["content-type"],
+            removed: &["set-cookie"],
+        },
+        // Allowlist mode
+        Case {
+            strip: &[],
+            allow: &["content-*"],
+            kept: &["content-type", "set-cookie"],
+            removed: &["server", "x-internal-debug", "x-amzn-remapped-date"],
+        },
+        // The default strip list still applies to allowlisted headers
+        Case {
+            strip: &[],
+            allow: &["x-amzn-*"],
+            kept: &["set-cookie"],
+            removed: &["content-type", "x-amzn-remapped-date"],
+        },
+    ];
+
+    for case in cases {
+        let target = Target {
+            strip_response_headers: case.strip.iter().map(|s| s.to_string()).collect(),
+            allow_response_headers: case.allow.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        };
+        let mut headers = HeaderMap::new();
+        for name in ["content-type", "server", "set-cookie", "x-internal-debug", "x-amzn-remapped-date"] {
+            headers.insert(name, "value".parse().unwrap());
+        }
+        headers.append("set-cookie", "b=2".parse().unwrap());
+
+        sanitize_response_headers(&mut headers, &target);
+
+        for name in case.kept {
+            assert!(headers.contains_key(*name), "{} should be kept for {:?}", name, case.strip);
+        }
+        for name in case.removed {
+            assert!(!headers.contains_key(*name), "{} should be removed for {:?}", name, case.strip);
+        }
+        if case.kept.contains(&"set-cookie") {
+            assert_eq!(headers.get_all("set-cookie").iter().count(), 2);
+        }
+    }
+}
+
+#[test]
+fn test_upstream_headers_validation() {
+    let headers: UpstreamHeaders = [
+        ("content-type", "text/plain"),
+        ("x-injected", "a\r\nSet-Cookie: evil=1"),
+        ("x-unicode", "café"),
+        ("", "empty name"),
+        ("bad name", "x"),
+    ]
+    .into_iter()
+    .collect();
+
+    assert_eq!(headers.valid.len(), 2);
+    assert_eq!(headers.valid["content-type"], "text/plain");
+    // Non-ASCII bytes are allowed in values, just not control characters
+    assert_eq!(headers.valid["x-unicode"].as_bytes(), "café".as_bytes());
+    assert_eq!(headers.invalid, vec!["x-injected", "", "bad name"]);
+    assert!(headers.valid.get("set-cookie").is_none());
+}
+
+#[test]
+fn test_upstream_headers_checked() {
+    let lenient: UpstreamHeaders = [("x-ok", "1"), ("x-bad", "a\nb")].into_iter().collect();
+    assert_eq!(lenient.checked(false).unwrap().len(), 1);
+
+    let strict: UpstreamHeaders = [("x-ok", "1"), ("x-bad", "a\nb")].into_iter().collect();
+    let err = strict.checked(true).unwrap_err();
+    assert_eq!(err.phase, ErrorPhase::Upstream);
+    assert_eq!(err.code, "invalid_upstream_header");
+    assert!(err.message.contains("x-bad"));
+}
+
+#[test]
+fn test_upstream_headers_deserialize_multi_value() {
+    let headers: UpstreamHeaders =
+        serde_json::from_str(r#"{"vary": ["accept", "origin"], "x-bad": "\u0000", "x-one": "1"}"#).unwrap();
+    assert_eq!(headers.valid.get_all("vary").iter().count(), 2);
+    assert_eq!(headers.valid["x-one"], "1");
+    assert_eq!(headers.invalid, vec!["x-bad"]);
+}
@@ -0,0 +1,174 @@
+use crate::config::{HealthMode, PathParams, PayloadMode, Target};
+use crate::lifecycle::{Lifecycle, LifecycleEvent};
+use crate::{invoke_target, raw_client_context, request, ApplicationState, FunctionError};
+use axum::http::{HeaderMap, HeaderValue, Method};
+use futures::stream::{self, StreamExt};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Outcome of the latest probe of a target.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ProbeResult {
+    pub healthy: bool,
+    pub mode: HealthMode,
+    pub checked_at_ms: u128,
+    pub latency_ms: f64,
+    pub error: Option<String>,
+}
+
+/// Latest probe result per target pattern, shared by the prober, the readiness report and
+/// anything else that wants to skip targets known to be down.
+#[derive(Clone, Default)]
+pub struct HealthRegistry {
+    results: Arc<Mutex<BTreeMap<String, ProbeResult>>>,
+    lifecycle: Lifecycle,
+}
+
+impl HealthRegistry {
+    /// A registry publishing the health transitions of targets to `lifecycle`.
+    pub fn new(lifecycle: Lifecycle) -> Self {
+        Self {
+            results: Arc::default(),
+            lifecycle,
+        }
+    }
+
+    pub fn record(&self, pattern: &str, result: ProbeResult) {
+        let previous = self.results.lock().unwrap().insert(pattern.to_string(), result.clone());
+        if previous.map(|p| p.healthy) != Some(result.healthy) {
+            let target = pattern.to_string();
+            if result.healthy {
+                tracing::info!(pattern, "Target is healthy");
+                self.lifecycle.publish(LifecycleEvent::TargetHealthy { target });
+            } else {
+                tracing::warn!(pattern, error = ?result.error, "Target is unhealthy");
+                let error = result.error;
+                self.lifecycle
+                    .publish(LifecycleEvent::TargetUnhealthy { target, error });
+            }
+        }
+    }
+
+    /// Whether the target passed its latest probe; `None` until it has been probed.
+    pub fn is_healthy(&self, pattern: &str) -> Option<bool> {
+        self.results.lock().unwrap().get(pattern).map(|r| r.healthy)
+    }
+
+    /// Forgets a target that is no longer configured.
+    pub fn remove(&self, pattern: &str) {
+        self.results.lock().unwrap().remove(pattern);
+    }
+
+    pub fn report(&self) -> BTreeMap<String, ProbeResult> {
+        self.results.lock().unwrap().clone()
+    }
+}
+
+/// Probes one target according to its `health` settings.
+pub(crate) async fn probe_target(state: &ApplicationState, target: &Target) -> ProbeResult {
+    let started_at = Instant::now();
+    let timeout = Duration::from_millis(state.config().health.timeout_ms);
+    let outcome = match tokio::time::timeout(timeout, run_probe(state, target)).await {
+        Ok(outcome) => outcome,
+        Err(_) => Err(format!("probe timed out after {:?}", timeout)),
+    };
+    ProbeResult {
+        healthy: outcome.is_ok(),
+        mode: target.health.mode,
+        checked_at_ms: crate::access_log::now_ms(),
+        latency_ms: started_at.elapsed().as_secs_f64() * 1000.0,
+        error: outcome.err(),
+    }
+}
+
+async fn run_probe(state: &ApplicationState, target: &Target) -> Result<(), String> {
+    match target.health.mode {
+        HealthMode::GetFunction => state
+            .invoker
+            .check_function(state.config().function_name(target), target.qualifier.as_deref())
+            .await
+            .map_err(|e| e.to_string()),
+        HealthMode::Invoke => {
+            let mut headers = HeaderMap::new();
+            headers.insert("user-agent", HeaderValue::from_static("lambda-web-gateway-health"));
+            let mut request_context = request::RequestContext::new(&headers);
+            let event_request = request::EventRequest {
+                method: &Method::GET,
+                path: &target.health.path,
+                raw_path: &target.health.path,
+                raw_query_string: "",
+                pattern: "",
+                host: None,
+                path_params: &PathParams::new(),
+                source_ip: None,
+                request_id: &request_context.request_id,
+                multi_value: target.multi_value,
+            };
+            let payload_mode = state.config().payload_mode(target);
+            if payload_mode == PayloadMode::Raw {
+                let context =
+                    raw_client_context(&state.config(), &event_request, &headers).map_err(|e| e.to_string())?;
+                request_context.client_context = Some(context);
+            }
+            let payload = request::build_request_body(payload_mode, &event_request, &headers, b"");
+            let resp = invoke_target(state, target, payload, &request_context)
+                .await
+                .map_err(|e| e.to_string())?;
+            if let Some(FunctionError(kind)) = resp.extensions().get::<FunctionError>() {
+                return Err(format!("function error: {}", kind));
+            }
+            if resp.status().is_server_error() {
+                return Err(format!("status {}", resp.status()));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Probes the given targets, at most `health.concurrency` at a time, and records the results.
+pub(crate) async fn probe_all(state: &ApplicationState, targets: Vec<(String, Target)>) {
+    stream::iter(targets)
+        .for_each_concurrent(
+            state.config().health.concurrency.max(1),
+            |(pattern, target)| async move {
+                let result = probe_target(state, &target).await;
+                state.health.record(&pattern, result);
+            },
+        )
+        .await;
+}
+
+/// Probes every target that is not disabled, each on its own interval.
+pub fn spawn_prober(state: ApplicationState) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut last_probed: HashMap<String, Instant> = HashMap::new();
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tick.tick().await;
+            let config = state.config();
+            let due: Vec<(String, Target)> = config
+                .targets
+                .iter()
+                .filter(|(_, target)| !target.health.disabled)
+                .filter(|(pattern, target)| {
+                    let interval = target.health.interval_secs.unwrap_or(config.health.interval_secs);
+                    last_probed
+                        .get(*pattern)
+                        .is_none_or(|at| at.elapsed() >= Duration::from_secs(interval))
+                })
+                .map(|(pattern, target)| (pattern.clone(), target.clone()))
+                .collect();
+            for (pattern, _) in &due {
+                last_probed.insert(pattern.clone(), Instant::now());
+            }
+            probe_all(&state, due).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    include!("health_tests.rs");
+}
@@ -0,0 +1,101 @@
+use super::*;
+use crate::config::{Config, TargetHealth};
+use crate::invoker::{BufferedOutput, InvokeError};
+use crate::mock::{ok_output, test_state_with, MockInvoker};
+use axum::body::Bytes;
+
+fn target(mode: HealthMode, function: &str) -> Target {
+    Target {
+        function: Some(function.into()),
+        health: TargetHealth {
+            mode,
+            path: "/internal/health".to_string(),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_get_function_probe() {
+    let invoker = MockInvoker::new(vec![]);
+    let state = test_state_with(Config::default(), invoker.clone());
+
+    let result = probe_target(&state, &target(HealthMode::GetFunction, "orders")).await;
+    assert!(result.healthy);
+    assert_eq!(result.mode, HealthMode::GetFunction);
+    // GetFunction probes never run the function
+    assert!(invoker.requests().is_empty());
+
+    invoker.set_function_error(InvokeError::Conflict("function state is Pending".to_string()));
+    let result = probe_target(&state, &target(HealthMode::GetFunction, "orders")).await;
+    assert!(!result.healthy);
+    assert!(result.error.unwrap().contains("Pending"));
+}
+
+#[tokio::test]
+async fn test_invoke_probe_uses_payload_builder() {
+    let invoker = MockInvoker::new(vec![ok_output("ok")]);
+    let state = test_state_with(Config::default(), invoker.clone());
+
+    let result = probe_target(&state, &target(HealthMode::Invoke, "orders")).await;
+    assert!(result.healthy);
+
+    let requests = invoker.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].function_name, "orders");
+    let event: serde_json::Value = serde_json::from_str(&requests[0].payload).unwrap();
+    assert_eq!(event["httpMethod"], "GET");
+    assert_eq!(event["path"], "/internal/health");
+    assert_eq!(event["headers"]["user-agent"], "lambda-web-gateway-health");
+}
+
+#[tokio::test]
+async fn test_invoke_probe_failures() {
+    let server_error = Ok(BufferedOutput {
+        payload: Bytes::from(r#"{"statusCode": 503, "body": ""}"#),
+        function_error: None,
+        request_id: None,
+    });
+    let function_error = Ok(BufferedOutput {
+        payload: Bytes::from(r#"{"statusCode": 200, "body": ""}"#),
+        function_error: Some("Unhandled".to_string()),
+        request_id: None,
+    });
+    let invoker = MockInvoker::new(vec![server_error, function_error]);
+    let state = test_state_with(Config::default(), invoker);
+    let target = target(HealthMode::Invoke, "orders");
+
+    assert!(probe_target(&state, &target).await.error.unwrap().contains("503"));
+    assert!(probe_target(&state, &target).await.error.unwrap().contains("Unhandled"));
+}
+
+#[tokio::test]
+async fn test_probe_timeout() {
+    let mut config = Config::default();
+    config.health.timeout_ms = 10;
+    let state = test_state_with(config, MockInvoker::with_delay(Duration::from_millis(200)));
+
+    let result = probe_target(&state, &target(HealthMode::GetFunction, "slow")).await;
+    assert!(!result.healthy);
+    assert!(result.error.unwrap().contains("timed out"));
+}
+
+#[tokio::test]
+async fn test_probe_all_bounded_concurrency() {
+    let mut config = Config::default();
+    config.health.concurrency = 3;
+    let invoker = MockInvoker::with_delay(Duration::from_millis(20));
+    let state = test_state_with(config, invoker.clone());
+    let targets: Vec<(String, Target)> = (0..10)
+        .map(|i| (format!("/t{}", i), target(HealthMode::GetFunction, "f")))
+        .collect();
+
+    probe_all(&state, targets).await;
+
+    assert_eq!(invoker.calls(), 10);
+    assert_eq!(invoker.max_in_flight(), 3);
+    assert_eq!(state.health.report().len(), 10);
+    assert_eq!(state.health.is_healthy("/t0"), Some(true));
+    assert_eq!(state.health.is_healthy("/missing"), None);
+}
@@ -0,0 +1,93 @@
+use axum::http::{HeaderMap, Uri};
+use sha2::{Digest, Sha256};
+
+/// Buckets rejected hosts are hashed into for metrics, so scanners cannot grow label cardinality.
+pub const HOST_BUCKETS: u8 = 64;
+
+/// The host a request was sent to: the HTTP/2 `:authority`, else the `Host` header.
+pub fn presented_host<'a>(uri: &'a Uri, headers: &'a HeaderMap) -> Option<&'a str> {
+    uri.authority()
+        .map(|authority| authority.as_str())
+        .or_else(|| headers.get("host").and_then(|v| v.to_str().ok()))
+}
+
+/// Whether `host` matches one of the `allowed` patterns, ignoring case. A pattern is a host name,
+/// optionally with a port, or `*.` followed by a domain to allow any of its subdomains. Patterns
+/// without a port match every port.
+pub fn is_allowed(allowed: &[String], host: &str) -> bool {
+    allowed.iter().any(|pattern| matches(pattern, host))
+}
+
+/// Whether `host` matches a single host pattern, as described for [`is_allowed`].
+pub fn matches(pattern: &str, host: &str) -> bool {
+    let (name, port) = split_port(host);
+    let (pattern_name, pattern_port) = split_port(pattern);
+    if pattern_port.is_some() && pattern_port != port {
+        return false;
+    }
+    match pattern_name.strip_prefix("*.") {
+        Some(domain) => {
+            let Some(subdomain_len) = name.len().checked_sub(domain.len()) else {
+                return false;
+            };
+            subdomain_len > 1
+                && name.as_bytes()[subdomain_len - 1] == b'.'
+                && name
+                    .get(subdomain_len..)
+                    .is_some_and(|d| d.eq_ignore_ascii_case(domain))
+        }
+        None => name.eq_ignore_ascii_case(pattern_name),
+    }
+}
+
+/// Orders host patterns matching the same host from least to most specific: an exact name beats
+/// any wildcard, a wildcard over a longer domain beats a shorter one, and a port beats none.
+pub fn specificity(pattern: &str) -> (bool, usize, bool) {
+    let (name, port) = split_port(pattern);
+    (!name.starts_with("*."), name.len(), port.is_some())
+}
+
+/// Checks the syntax of a host pattern.
+pub fn validate_pattern(pattern: &str) -> Result<(), String> {
+    let (name, port) = split_port(pattern);
+    let name = name.strip_prefix("*.").unwrap_or(name);
+    if name.is_empty() || name.contains('*') {
+        return Err(format!(
+            "{:?} must be a host name, optionally starting with *.",
+            pattern
+        ));
+    }
+    if port.is_some_and(|port| port.parse::<u16>().is_err()) {
+        return Err(format!("{:?} has an invalid port", pattern));
+    }
+    Ok(())
+}
+
+/// Metric label for a rejected host: one of [`HOST_BUCKETS`] stable hash buckets, or `none` for
+/// requests without a host.
+pub fn host_label(host: Option<&str>) -> String {
+    match host {
+        Some(host) => {
+            let digest = Sha256::digest(split_port(host).0.to_ascii_lowercase().as_bytes());
+            format!("unknown-{:02}", digest[0] % HOST_BUCKETS)
+        }
+        None => "none".to_string(),
+    }
+}
+
+/// Splits `host[:port]`, keeping the brackets of an IPv6 literal.
+fn split_port(host: &str) -> (&str, Option<&str>) {
+    let port_start = match host.rfind(']') {
+        Some(end) => host[end..].find(':').map(|i| end + i),
+        None => host.rfind(':'),
+    };
+    match port_start {
+        Some(i) => (&host[..i], Some(&host[i + 1..])),
+        None => (host, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("hosts_tests.rs");
+}
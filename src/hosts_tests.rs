@@ -0,0 +1,78 @@
+use super::*;
+
+fn allowed() -> Vec<String> {
+    vec![
+        "api.example.com".to_string(),
+        "*.internal.example.com".to_string(),
+        "admin.example.com:8443".to_string(),
+        "[::1]".to_string(),
+    ]
+}
+
+#[test]
+fn test_matching_ignores_case_and_port() {
+    let allowed = allowed();
+    for host in ["api.example.com", "API.Example.COM", "api.example.com:8080", "[::1]:3000", "[::1]"] {
+        assert!(is_allowed(&allowed, host), "{}", host);
+    }
+    for host in ["example.com", "api.example.com.evil.net", "xapi.example.com", "[::2]", ""] {
+        assert!(!is_allowed(&allowed, host), "{}", host);
+    }
+}
+
+#[test]
+fn test_wildcard_matches_subdomains_only() {
+    let allowed = allowed();
+    assert!(is_allowed(&allowed, "a.internal.example.com"));
+    assert!(is_allowed(&allowed, "a.b.Internal.example.com:80"));
+    assert!(!is_allowed(&allowed, "internal.example.com"));
+    assert!(!is_allowed(&allowed, "ainternal.example.com"));
+}
+
+#[test]
+fn test_pattern_with_port_requires_that_port() {
+    let allowed = allowed();
+    assert!(is_allowed(&allowed, "admin.example.com:8443"));
+    assert!(!is_allowed(&allowed, "admin.example.com"));
+    assert!(!is_allowed(&allowed, "admin.example.com:443"));
+}
+
+#[test]
+fn test_validate_pattern() {
+    for pattern in allowed() {
+        assert_eq!(validate_pattern(&pattern), Ok(()));
+    }
+    for pattern in ["", "*", "*.", "api.*.com", "api.example.com:http"] {
+        assert!(validate_pattern(pattern).is_err(), "{}", pattern);
+    }
+}
+
+#[test]
+fn test_host_label_is_bounded_and_stable() {
+    assert_eq!(host_label(Some("scanner.test")), host_label(Some("SCANNER.test:8080")));
+    assert_eq!(host_label(None), "none");
+    let labels: std::collections::HashSet<String> =
+        (0..1000).map(|i| host_label(Some(&format!("host-{}.test", i)))).collect();
+    assert!(labels.len() <= HOST_BUCKETS as usize);
+}
+
+#[test]
+fn test_presented_host_prefers_authority() {
+    let mut headers = HeaderMap::new();
+    headers.insert("host", "from-header.example.com".parse().unwrap());
+    let uri: Uri = "https://from-authority.example.com/path".parse().unwrap();
+    assert_eq!(presented_host(&uri, &headers), Some("from-authority.example.com"));
+    let uri: Uri = "/path".parse().unwrap();
+    assert_eq!(presented_host(&uri, &headers), Some("from-header.example.com"));
+    assert_eq!(presented_host(&uri, &HeaderMap::new()), None);
+}
+
+#[test]
+fn test_specificity_orders_overlapping_patterns() {
+    let mut patterns = vec!["*.foo.com", "api.foo.com", "*.api.foo.com", "api.foo.com:8443"];
+    patterns.sort_by_key(|pattern| std::cmp::Reverse(specificity(pattern)));
+    assert_eq!(patterns, ["api.foo.com:8443", "api.foo.com", "*.api.foo.com", "*.foo.com"]);
+    for pattern in &patterns[1..] {
+        assert!(matches(pattern, "v1.api.foo.com") == pattern.starts_with('*'), "{}", pattern);
+    }
+}
@@ -0,0 +1,265 @@
+use crate::retry_after::parse_upstream_hint;
+use crate::streaming::{payload_stream, PayloadStream};
+use crate::trace_context::XRAY_HEADER;
+use aws_sdk_lambda::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use aws_sdk_lambda::operation::invoke::InvokeError as SdkInvokeError;
+use aws_sdk_lambda::operation::invoke_with_response_stream::InvokeWithResponseStreamError;
+use aws_sdk_lambda::operation::RequestId;
+use aws_sdk_lambda::types::{InvocationType, ResponseStreamingInvocationType, State};
+use aws_sdk_lambda::Client;
+use aws_smithy_runtime_api::client::orchestrator::HttpRequest;
+use aws_smithy_types::Blob;
+use axum::body::Bytes;
+use futures::future::BoxFuture;
+use std::time::Duration;
+
+/// A single Lambda invocation as built by the gateway.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvokeRequest {
+    pub function_name: String,
+    /// Alias or version to invoke; `None` for `$LATEST` or the qualifier in `function_name`.
+    pub qualifier: Option<String>,
+    pub payload: String,
+    /// Base64 `ClientContext`, see [`crate::request::build_client_context`].
+    pub client_context: Option<String>,
+    /// `X-Amzn-Trace-Id` sent with the invoke, so that Lambda records the function in the
+    /// caller's X-Ray trace.
+    pub trace_header: Option<String>,
+}
+
+/// Result of a buffered invoke.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BufferedOutput {
+    pub payload: Bytes,
+    /// `FunctionError` reported by Lambda, e.g. `Unhandled`.
+    pub function_error: Option<String>,
+    /// `x-amzn-RequestId` of the invoke.
+    pub request_id: Option<String>,
+}
+
+/// Result of a response streaming invoke.
+pub struct StreamOutput {
+    pub payload: PayloadStream,
+    /// `x-amzn-RequestId` of the invoke.
+    pub request_id: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InvokeError {
+    /// The function is being updated or is not ready yet (`ResourceConflictException`,
+    /// `ResourceNotReadyException`). These clear up within seconds, e.g. during a deploy.
+    Conflict(String),
+    /// Lambda rejected the invoke for exceeding a concurrency or request rate limit
+    /// (`TooManyRequestsException`), with its hint of when to retry if it sent one.
+    Throttled(String, Option<Duration>),
+    /// The function or the qualifier does not exist (`ResourceNotFoundException`).
+    NotFound(String),
+    /// The gateway's credentials may not invoke the function (`AccessDeniedException`).
+    AccessDenied(String),
+    /// Lambda failed on its side (`ServiceException`); usually gone on the next attempt.
+    Service(String),
+    Other(String),
+}
+
+impl std::fmt::Display for InvokeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvokeError::Conflict(message) => write!(f, "function is not ready: {}", message),
+            InvokeError::Throttled(message, _) => write!(f, "invoke was throttled: {}", message),
+            InvokeError::NotFound(message)
+            | InvokeError::AccessDenied(message)
+            | InvokeError::Service(message)
+            | InvokeError::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for InvokeError {}
+
+/// Invokes Lambda functions. Implemented by [`LambdaInvoker`] and by mocks in tests.
+pub trait Invoker: Send + Sync + 'static {
+    fn invoke(&self, request: InvokeRequest) -> BoxFuture<'static, Result<BufferedOutput, InvokeError>>;
+
+    fn invoke_stream(&self, request: InvokeRequest) -> BoxFuture<'static, Result<StreamOutput, InvokeError>>;
+
+    /// Queues an asynchronous invoke with Lambda, returning the request ID Lambda assigned to it.
+    fn invoke_event(&self, request: InvokeRequest) -> BoxFuture<'static, Result<Option<String>, InvokeError>>;
+
+    /// Invokes with `DryRun`, which has Lambda check the parameters and the caller's permission to
+    /// invoke without running the function.
+    fn invoke_dry_run(&self, request: InvokeRequest) -> BoxFuture<'static, Result<(), InvokeError>>;
+
+    /// Checks that the function exists, is reachable with the gateway's credentials and is active,
+    /// without invoking it.
+    fn check_function(
+        &self,
+        function_name: &str,
+        qualifier: Option<&str>,
+    ) -> BoxFuture<'static, Result<(), InvokeError>>;
+}
+
+/// Sets the X-Ray header of an invoke, which the SDK otherwise only sets from `_X_AMZN_TRACE_ID`
+/// when the gateway itself runs in Lambda.
+fn with_trace_header(trace_header: Option<String>) -> impl Fn(&mut HttpRequest) + Send + Sync + 'static {
+    move |request| {
+        if let Some(trace_header) = &trace_header {
+            request.headers_mut().insert(XRAY_HEADER, trace_header.clone());
+        }
+    }
+}
+
+pub struct LambdaInvoker {
+    client: Client,
+}
+
+impl LambdaInvoker {
+    pub fn new(client: Client) -> Self {
+        Self { client }
+    }
+}
+
+impl Invoker for LambdaInvoker {
+    fn invoke(&self, request: InvokeRequest) -> BoxFuture<'static, Result<BufferedOutput, InvokeError>> {
+        let send = self
+            .client
+            .invoke()
+            .function_name(request.function_name)
+            .set_qualifier(request.qualifier)
+            .set_client_context(request.client_context)
+            .payload(Blob::new(request.payload))
+            .customize()
+            .mutate_request(with_trace_header(request.trace_header))
+            .send();
+        Box::pin(async move {
+            let resp = send.await.map_err(classify_invoke)?;
+            Ok(BufferedOutput {
+                payload: resp
+                    .payload()
+                    .map(|p| Bytes::copy_from_slice(p.as_ref()))
+                    .unwrap_or_default(),
+                function_error: resp.function_error().map(String::from),
+                request_id: resp.request_id().map(String::from),
+            })
+        })
+    }
+
+    fn invoke_stream(&self, request: InvokeRequest) -> BoxFuture<'static, Result<StreamOutput, InvokeError>> {
+        let send = self
+            .client
+            .invoke_with_response_stream()
+            .function_name(request.function_name)
+            .set_qualifier(request.qualifier)
+            .invocation_type(ResponseStreamingInvocationType::RequestResponse)
+            .set_client_context(request.client_context)
+            .payload(Blob::new(request.payload))
+            .customize()
+            .mutate_request(with_trace_header(request.trace_header))
+            .send();
+        Box::pin(async move {
+            let resp = send.await.map_err(|e| match e.as_service_error() {
+                Some(InvokeWithResponseStreamError::TooManyRequestsException(throttle)) => {
+                    let hint = throttle.retry_after_seconds().and_then(parse_upstream_hint);
+                    InvokeError::Throttled(DisplayErrorContext(e).to_string(), hint)
+                }
+                Some(
+                    InvokeWithResponseStreamError::ResourceConflictException(_)
+                    | InvokeWithResponseStreamError::ResourceNotReadyException(_),
+                ) => classify(true, e),
+                Some(InvokeWithResponseStreamError::ServiceException(_)) => {
+                    InvokeError::Service(DisplayErrorContext(e).to_string())
+                }
+                _ => classify(false, e),
+            })?;
+            Ok(StreamOutput {
+                request_id: resp.request_id().map(String::from),
+                payload: payload_stream(resp),
+            })
+        })
+    }
+
+    fn invoke_event(&self, request: InvokeRequest) -> BoxFuture<'static, Result<Option<String>, InvokeError>> {
+        let send = self
+            .client
+            .invoke()
+            .function_name(request.function_name)
+            .set_qualifier(request.qualifier)
+            .invocation_type(InvocationType::Event)
+            .set_client_context(request.client_context)
+            .payload(Blob::new(request.payload))
+            .customize()
+            .mutate_request(with_trace_header(request.trace_header))
+            .send();
+        Box::pin(async move {
+            let resp = send.await.map_err(classify_invoke)?;
+            Ok(resp.request_id().map(String::from))
+        })
+    }
+
+    fn invoke_dry_run(&self, request: InvokeRequest) -> BoxFuture<'static, Result<(), InvokeError>> {
+        let send = self
+            .client
+            .invoke()
+            .function_name(request.function_name)
+            .set_qualifier(request.qualifier)
+            .invocation_type(InvocationType::DryRun)
+            .set_client_context(request.client_context)
+            .payload(Blob::new(request.payload))
+            .send();
+        Box::pin(async move {
+            send.await.map_err(classify_invoke)?;
+            Ok(())
+        })
+    }
+
+    fn check_function(
+        &self,
+        function_name: &str,
+        qualifier: Option<&str>,
+    ) -> BoxFuture<'static, Result<(), InvokeError>> {
+        let send = self
+            .client
+            .get_function()
+            .function_name(function_name)
+            .set_qualifier(qualifier.map(String::from))
+            .send();
+        Box::pin(async move {
+            let resp = send.await.map_err(|e| classify(false, e))?;
+            match resp.configuration().and_then(|c| c.state()) {
+                Some(State::Active) | None => Ok(()),
+                Some(state) => Err(InvokeError::Conflict(format!("function state is {}", state.as_str()))),
+            }
+        })
+    }
+}
+
+fn classify_invoke<R: std::fmt::Debug>(e: SdkError<SdkInvokeError, R>) -> InvokeError {
+    match e.as_service_error() {
+        Some(SdkInvokeError::TooManyRequestsException(throttle)) => {
+            let hint = throttle.retry_after_seconds().and_then(parse_upstream_hint);
+            InvokeError::Throttled(DisplayErrorContext(e).to_string(), hint)
+        }
+        Some(SdkInvokeError::ResourceConflictException(_) | SdkInvokeError::ResourceNotReadyException(_)) => {
+            classify(true, e)
+        }
+        Some(SdkInvokeError::ResourceNotFoundException(_)) => InvokeError::NotFound(DisplayErrorContext(e).to_string()),
+        Some(SdkInvokeError::ServiceException(_)) => InvokeError::Service(DisplayErrorContext(e).to_string()),
+        // Not a modeled error of Invoke, so only known by its code
+        Some(service) if service.code() == Some("AccessDeniedException") => {
+            InvokeError::AccessDenied(DisplayErrorContext(e).to_string())
+        }
+        _ => classify(false, e),
+    }
+}
+
+fn classify<E, R>(conflict: bool, e: SdkError<E, R>) -> InvokeError
+where
+    E: std::error::Error + 'static,
+    R: std::fmt::Debug,
+{
+    let message = DisplayErrorContext(e).to_string();
+    if conflict {
+        InvokeError::Conflict(message)
+    } else {
+        InvokeError::Other(message)
+    }
+}
@@ -1,371 +1,2854 @@
+pub mod access_log;
+pub mod adaptive;
+pub mod auth_ban;
+pub mod balancer;
+pub mod body_store;
+pub mod breaker;
+pub mod buffer_pool;
+pub mod canonical;
+pub mod check;
+pub mod checkpoint;
+pub mod cidr;
+pub mod client_cache;
+pub mod clock;
+pub mod compact;
+pub mod compression;
 pub mod config;
+pub mod config_source;
+pub mod cookies;
+pub mod drain;
+pub mod error;
+pub mod error_pages;
+pub mod event_queue;
+pub mod ewma;
+pub mod expr;
+pub mod fair_queue;
+pub mod flags;
+pub mod function_errors;
+pub mod headers;
+pub mod health;
+pub mod hosts;
+pub mod invoker;
+pub mod lifecycle;
+pub mod limiter;
+pub mod log_dedup;
+pub mod memory;
+#[cfg(test)]
+mod mock;
+pub mod ndjson;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod prometheus;
+pub mod rate_limit;
+#[cfg(feature = "redis-state")]
+pub mod redis_store;
+pub mod request;
+pub mod response_diff;
+pub mod retry;
+pub mod retry_after;
+pub mod routes;
+pub mod schedule;
+pub mod server;
+pub mod shutdown;
+pub mod signed_url;
+pub mod slo;
+pub mod spool;
+pub mod state_store;
+pub mod stream_format;
+pub mod streaming;
+pub mod support;
+pub mod telemetry;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod trace_context;
+pub mod validate_service;
 
 #[cfg(test)]
 mod tests {
     include!("lib_tests.rs");
 }
 
-use crate::config::{Config, LambdaInvokeMode};
-use aws_config::BehaviorVersion;
-use aws_sdk_lambda::types::InvokeWithResponseStreamResponseEvent::{InvokeComplete, PayloadChunk};
-use aws_sdk_lambda::types::{InvokeResponseStreamUpdate, ResponseStreamingInvocationType};
+use crate::access_log::{AccessLog, AccessRecord};
+use crate::config::{
+    Config, LambdaInvokeMode, ListenerConfig, LogFormat, PathParams, PayloadMode, RouteRule, ShadowConfig,
+    SharedConfig, StoreKind, Target, UnmatchedRoute,
+};
+use auth_ban::AuthBans;
+use aws_config::{AppName, BehaviorVersion};
 use aws_sdk_lambda::Client;
-use aws_smithy_types::Blob;
 use axum::body::Body;
 use axum::{
     body::Bytes,
-    extract::{Path, Query, State},
-    http::{HeaderMap, Method, StatusCode},
+    extract::{ConnectInfo, Path, Query, State},
+    http::{
+        header::{ACCEPT, ALLOW, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE},
+        HeaderMap, HeaderName, HeaderValue, Method, StatusCode,
+    },
     response::{IntoResponse, Response},
     routing::any,
     routing::get,
+    routing::post,
+    routing::put,
     Router,
 };
+use balancer::Balancer;
 use base64::Engine;
-use futures_util::stream::StreamExt;
+use body_store::RecordFile;
+use breaker::CircuitBreakers;
+use buffer_pool::BufferPool;
+use checkpoint::Checkpointer;
+use clock::{Clock, TimeSource};
+use config_source::ConfigSourceState;
+use drain::TargetTracker;
+use error::{ErrorInfo, ErrorMessage, ErrorPhase, GatewayError};
+use event_queue::EventQueue;
+use flags::FeatureFlags;
+use futures::StreamExt;
+use headers::UpstreamHeaders;
+use health::HealthRegistry;
+use invoker::{BufferedOutput, InvokeError, InvokeRequest, Invoker, LambdaInvoker, StreamOutput};
+use lifecycle::{Lifecycle, LifecycleEvent};
+use limiter::ConcurrencyLimiter;
+use log_dedup::LogDedup;
+use memory::MemoryBudget;
+use rate_limit::RateLimiter;
+use request::{RequestContext, UpstreamTime};
+use retry_after::RetryAfterHints;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::collections::HashMap;
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::ReceiverStream;
-use tower_http::trace::TraceLayer;
+use shutdown::{InFlight, Shutdown};
+use slo::ErrorBudgets;
+use spool::Spool;
+use state_store::StateStore;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use stream_format::{DetectedStreamFormat, StreamFormatMonitor};
+use streaming::{handle_streaming_response, StreamStartError};
+use support::{FailedRequest, RecentLog, ReloadDiff, SupportBundle};
+use telemetry::Telemetry;
+use tower_http::trace::{DefaultOnResponse, OnResponse, TraceLayer};
+use tracing::Instrument;
 
 #[derive(Clone)]
 pub struct ApplicationState {
-    client: Client,
-    config: Config,
+    invoker: Arc<dyn Invoker>,
+    config: SharedConfig,
+    config_source: ConfigSourceState,
+    telemetry: Telemetry,
+    access_log: AccessLog,
+    health: HealthRegistry,
+    memory: MemoryBudget,
+    balancer: Balancer,
+    event_queue: EventQueue,
+    targets: TargetTracker,
+    limiter: ConcurrencyLimiter,
+    rate_limiter: RateLimiter,
+    breakers: CircuitBreakers,
+    auth_bans: AuthBans,
+    stream_formats: StreamFormatMonitor,
+    log_dedup: LogDedup,
+    checkpointer: Checkpointer,
+    /// Uses of signed URLs minted with `max_uses`.
+    signed_url_uses: Arc<dyn StateStore>,
+    spool: Spool,
+    payload_buffers: BufferPool,
+    in_flight: InFlight,
+    flags: FeatureFlags,
+    error_budgets: ErrorBudgets,
+    recent_errors: RecentLog<FailedRequest>,
+    flight_recorder: RecordFile,
+    reloads: RecentLog<ReloadDiff>,
+    shadow_mismatches: RecentLog<response_diff::ShadowMismatch>,
+    lifecycle: Lifecycle,
+    clock: Clock,
+    /// Reference for `clock`, measured against in the background; unset in tests.
+    clock_source: Option<Arc<dyn TimeSource>>,
+}
+
+impl ApplicationState {
+    pub async fn new(config: Config, telemetry: Telemetry) -> Self {
+        let aws_config = aws_config::defaults(BehaviorVersion::latest())
+            .app_name(app_name())
+            .load()
+            .await;
+        let invoker: Arc<dyn Invoker> = Arc::new(LambdaInvoker::new(Client::new(&aws_config)));
+        let access_log = AccessLog::new(&config.access_log);
+        let memory = MemoryBudget::new(config.state_memory_budget_bytes);
+        let event_queue = EventQueue::new(&config.event_retry, invoker.clone(), telemetry.clone());
+        let clock_source = clock::source(&config.clock, &aws_config);
+
+        ApplicationState {
+            clock_source: Some(clock_source),
+            ..ApplicationState::assemble(config, invoker, telemetry, access_log, memory, event_queue)
+        }
+    }
+
+    /// Wires the per-target state that reloads have to keep in step with the configuration.
+    pub(crate) fn assemble(
+        config: Config,
+        invoker: Arc<dyn Invoker>,
+        telemetry: Telemetry,
+        access_log: AccessLog,
+        memory: MemoryBudget,
+        event_queue: EventQueue,
+    ) -> Self {
+        let lifecycle = Lifecycle::new(&config.lifecycle);
+        let health = HealthRegistry::new(lifecycle.clone());
+        let balancer = Balancer::default();
+        let limiter = ConcurrencyLimiter::new(telemetry.clone());
+        let stream_formats = StreamFormatMonitor::default();
+        let log_dedup = LogDedup::new(&config.log_dedup);
+        let checkpointer = Checkpointer::new(&config.state_store);
+        let breaker_store = state_store::open(
+            "circuit_breakers",
+            config.circuit_breaker_store,
+            &config.state_store,
+            &memory,
+            &checkpointer,
+        );
+        let breakers = CircuitBreakers::new(telemetry.clone(), breaker_store);
+        let rate_limiter = RateLimiter::new(
+            state_store::open(
+                "rate_limits",
+                config.rate_limit_store,
+                &config.state_store,
+                &memory,
+                &checkpointer,
+            ),
+            telemetry.clone(),
+        );
+        let auth_ban_store = config.auth_bans.as_ref().map_or(StoreKind::Memory, |bans| bans.store);
+        let auth_bans = AuthBans::new(
+            state_store::open("auth_bans", auth_ban_store, &config.state_store, &memory, &checkpointer),
+            telemetry.clone(),
+        );
+        let signed_url_store = config
+            .signed_urls
+            .as_ref()
+            .map_or(StoreKind::Memory, |signed_urls| signed_urls.store);
+        let signed_url_uses = state_store::open(
+            "signed_urls",
+            signed_url_store,
+            &config.state_store,
+            &memory,
+            &checkpointer,
+        );
+        let spool = Spool::new(&config.spool, telemetry.clone());
+        let payload_buffers = BufferPool::new(&config.payload_buffers);
+        let in_flight = InFlight::new(telemetry.clone());
+        let flags = FeatureFlags::new(&config.feature_flags);
+        let error_budgets = ErrorBudgets::default();
+        let flight_recorder = RecordFile::flight_recorder(&config.flight_recorder, &config.body_storage);
+        let clock = Clock::new(&config.clock);
+        let config_source = ConfigSourceState::new(&config);
+        let shared = SharedConfig::new(config);
+        let on_retired: drain::RetiredHook = {
+            let (config, health, balancer, limiter, breakers, stream_formats, error_budgets) = (
+                shared.clone(),
+                health.clone(),
+                balancer.clone(),
+                limiter.clone(),
+                breakers.clone(),
+                stream_formats.clone(),
+                error_budgets.clone(),
+            );
+            Arc::new(move |pattern: &str, target: &Target| {
+                let current = config.current();
+                let replacement = current.targets.get(pattern);
+                if replacement.is_none() {
+                    health.remove(pattern);
+                    limiter.remove(pattern);
+                }
+                // A target that keeps its breaker keeps its state
+                if replacement.is_none_or(|target| target.circuit_breaker.is_none()) {
+                    breakers.remove(pattern);
+                }
+                // A target that keeps its SLO keeps its traffic history
+                if replacement.is_none_or(|target| target.slo.is_none()) {
+                    error_budgets.remove(pattern);
+                }
+                // A changed target may stream in its configured format again
+                stream_formats.remove(pattern);
+                if let Some(function) = &target.function {
+                    balancer.forget(function.members());
+                }
+            })
+        };
+        let targets = TargetTracker::new(&shared.current(), on_retired);
+        ApplicationState {
+            invoker,
+            config: shared,
+            config_source,
+            telemetry,
+            access_log,
+            health,
+            memory,
+            balancer,
+            event_queue,
+            targets,
+            limiter,
+            rate_limiter,
+            breakers,
+            auth_bans,
+            stream_formats,
+            log_dedup,
+            checkpointer,
+            signed_url_uses,
+            spool,
+            payload_buffers,
+            in_flight,
+            flags,
+            error_budgets,
+            recent_errors: RecentLog::new(support::RECENT_ERRORS),
+            flight_recorder,
+            reloads: RecentLog::new(support::RECENT_RELOADS),
+            shadow_mismatches: RecentLog::new(response_diff::RECENT_MISMATCHES),
+            lifecycle,
+            clock,
+            clock_source: None,
+        }
+    }
+
+    /// The configuration in effect; requests keep the snapshot they started with.
+    pub fn config(&self) -> Arc<Config> {
+        self.config.current()
+    }
+
+    /// Fingerprint of the configuration in effect, see [`Config::fingerprint`].
+    pub fn config_rev(&self) -> Arc<str> {
+        self.config.fingerprint()
+    }
+
+    /// Switches to `config`. Requests already running keep their snapshot; streams of targets
+    /// that `config` removes or changes are aborted after `reload_drain_timeout_ms`.
+    pub fn reload(&self, config: Config) {
+        let drain_timeout = Duration::from_millis(config.reload_drain_timeout_ms);
+        self.targets.reload(&config, drain_timeout);
+        self.flags.reload(&config.feature_flags);
+        let diff = ReloadDiff::new(&self.config(), &config);
+        let previous_rev = self.config_rev();
+        self.reloads.push(diff.clone());
+        self.config.replace(config);
+        tracing::info!(config_rev = %self.config_rev(), "Configuration reloaded");
+        self.lifecycle.publish(LifecycleEvent::ConfigReloaded {
+            config_rev: self.config_rev().to_string(),
+            previous_rev: previous_rev.to_string(),
+            targets_added: diff.targets_added,
+            targets_removed: diff.targets_removed,
+            targets_changed: diff.targets_changed,
+        });
+    }
+}
+
+/// Name appended to the SDK user agent as `app/lambda-web-gateway-<version>`, so invocations can
+/// be attributed to the gateway in CloudTrail. App names cannot contain `/`.
+fn app_name() -> AppName {
+    AppName::new(concat!("lambda-web-gateway-", env!("CARGO_PKG_VERSION"))).expect("valid app name")
 }
 
 pub async fn run_app() {
-    tracing_subscriber::fmt::init();
+    run_app_with_shutdown(Shutdown::new()).await
+}
+
+/// Runs the gateway until SIGTERM, Ctrl-C or `shutdown` is triggered, then shuts down in the
+/// phases of `shutdown` in the config.
+pub async fn run_app_with_shutdown(shutdown: Shutdown) {
+    // Warnings about the config itself are logged before its log_format is known
+    let config = tracing::subscriber::with_default(tracing_subscriber::fmt().finish(), || Config::load("config.yaml"));
+    // Before tracing, as the span exporter reports to it
+    let telemetry = Telemetry::new(&config.telemetry);
+    init_tracing(&config, &telemetry);
+    let app_state = ApplicationState::new(config, telemetry).await;
+    let config = app_state.config();
+    app_state
+        .lifecycle
+        .spawn_subscribers(&config.lifecycle, app_state.telemetry.clone());
+    memory::spawn_enforcer(app_state.memory.clone());
+    app_state.event_queue.spawn_drainer();
+    if let (true, Some(source)) = (config.clock.enabled, &app_state.clock_source) {
+        app_state.clock.spawn_checker(source.clone());
+    }
+    app_state.log_dedup.spawn_flusher();
+    if app_state.config().health.enabled {
+        health::spawn_prober(app_state.clone());
+    }
+    spawn_reloader(app_state.clone());
+    if let Some(source) = &config.config_source {
+        config_source::spawn(app_state.clone(), source.clone());
+    }
+    app_state.checkpointer.spawn_periodic();
+    app_state.spool.spawn_cleanup();
+
+    // The certificate was checked by validation; it can only have gone since
+    #[cfg(feature = "tls")]
+    let certificates = config
+        .tls
+        .as_ref()
+        .map(|tls| Arc::new(tls::Certificates::new(tls).unwrap()));
+    #[cfg(feature = "tls")]
+    if let Some(certificates) = &certificates {
+        tls::spawn_reloader(certificates.clone());
+    }
+    // Every listener is bound before any serves, so a taken port stops startup as a whole
+    let addrs = config.listener_addrs();
+    let mut listeners = Vec::with_capacity(addrs.len());
+    for (name, addr) in &addrs {
+        listeners.push(server::bind(addr, &config.server).await.unwrap());
+        let tls = config.listener_tls(name);
+        tracing::info!(config_rev = %app_state.config_rev(), listener = %name, tls, "Listening on {}", addr);
+    }
+    let metrics_listener = match config.metrics.addr.as_deref().filter(|_| config.metrics.enabled) {
+        Some(addr) => {
+            let listener = server::bind_tcp(addr, &config.server).await.unwrap();
+            tracing::info!("Serving /metrics on {}", addr);
+            Some(listener)
+        }
+        None => None,
+    };
+    app_state.lifecycle.publish(LifecycleEvent::StartupComplete {
+        config_rev: app_state.config_rev().to_string(),
+        addr: addrs
+            .iter()
+            .map(|(_, addr)| addr.to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+    });
+    let (refuse_tx, refuse_rx) = tokio::sync::watch::channel(false);
+    if let Some(listener) = metrics_listener {
+        let app = build_metrics_router(app_state.clone());
+        let server_config = config.server.clone();
+        // Scraped until the process exits, so the drain of a shutdown can be watched
+        tokio::spawn(async move {
+            server::serve(
+                listener,
+                app,
+                &server_config,
+                server::Transport::Plain,
+                std::future::pending(),
+            )
+            .await
+        });
+    }
+    for ((name, _), listener) in addrs.iter().zip(listeners) {
+        let app = build_listener_router(app_state.clone(), name);
+        let server_config = config.server.clone();
+        #[cfg(feature = "tls")]
+        let transport = match &certificates {
+            Some(certificates) if config.listener_tls(name) => server::Transport::Tls(certificates.clone()),
+            _ => server::Transport::Plain,
+        };
+        #[cfg(not(feature = "tls"))]
+        let transport = server::Transport::Plain;
+        let mut refuse_rx = refuse_rx.clone();
+        tokio::spawn(async move {
+            server::serve(listener, app, &server_config, transport, async move {
+                let _ = refuse_rx.wait_for(|refuse| *refuse).await;
+            })
+            .await
+        });
+    }
+    tokio::select! {
+        _ = shutdown_signal() => {}
+        _ = shutdown.triggered() => tracing::info!("Shutdown requested"),
+    }
+    // Phases read the configuration in effect now, which may have been reloaded since startup
+    let shutdown = app_state.config().shutdown_config();
+    shutdown::run(&app_state, &shutdown, move || {
+        let _ = refuse_tx.send(true);
+    })
+    .await;
+    #[cfg(feature = "otel")]
+    otel::shutdown().await;
+}
 
-    let config = Config::load("config.yaml");
-    let aws_config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let client = Client::new(&aws_config);
+/// Sets up the gateway's log lines on stdout, at info and above, and with `otel.endpoint` the
+/// export of its spans.
+fn init_tracing(config: &Config, #[cfg_attr(not(feature = "otel"), allow(unused_variables))] telemetry: &Telemetry) {
+    use tracing_subscriber::filter::LevelFilter;
+    use tracing_subscriber::prelude::*;
 
-    let app_state = ApplicationState { client, config };
+    let logs = match config.log_format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().flatten_event(true).boxed(),
+    };
+    let registry = tracing_subscriber::registry().with(logs.with_filter(LevelFilter::INFO));
+    #[cfg(feature = "otel")]
+    if config.otel.endpoint.is_some() {
+        match otel::tracer(&config.otel, telemetry) {
+            Ok(tracer) => registry.with(otel::layer(tracer)).init(),
+            Err(e) => {
+                registry.init();
+                tracing::warn!("Not exporting spans, the OTLP exporter cannot be set up: {}", e);
+            }
+        }
+        return;
+    }
+    registry.init();
+    if config.otel.endpoint.is_some() {
+        tracing::warn!("Not exporting spans, the gateway was built without the otel feature");
+    }
+}
 
-    let app = Router::new()
-        .route("/healthz", get(health))
+/// Resolves on SIGTERM or Ctrl-C.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::warn!("Shutdown on SIGTERM is unavailable: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+}
+
+/// Reloads `config.yaml` on SIGHUP, keeping the targets fetched from `config_source`. An invalid
+/// file is logged and the running configuration kept. Settings read at startup, such as `addr`,
+/// still need a restart.
+fn spawn_reloader(state: ApplicationState) {
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                tracing::warn!("Config reload on SIGHUP is unavailable: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            match Config::try_load("config.yaml") {
+                Ok(config) => state.reload(state.config_source.rebase(config)),
+                Err(e) => tracing::error!("Keeping the running configuration, reload failed: {}", e),
+            }
+        }
+    });
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+/// Builds the gateway router. The root path and every other path are registered separately;
+/// which target serves each is decided by [`Config::match_route`].
+pub fn build_router(app_state: ApplicationState) -> Router {
+    build_listener_router(app_state, config::DEFAULT_LISTENER)
+}
+
+/// The router for the listener `name`, whose `listeners` entry decides what it exposes. Without
+/// `listeners`, every name gets everything.
+pub fn build_listener_router(app_state: ApplicationState, name: &str) -> Router {
+    // Only proxied requests are checked against `allowed_hosts`; built-in routes answer any host
+    let proxy = Router::new()
         .route("/", any(handler))
         .route("/*path", any(handler))
+        .route_layer(axum::middleware::from_fn_with_state(app_state.clone(), check_host));
+    let admin = Router::new()
+        .route("/status", get(status))
+        .route("/support-bundle", get(support_bundle))
+        .route("/admin/events", get(list_lifecycle_events))
+        .route("/admin/shadow-mismatches", get(list_shadow_mismatches))
+        .route("/admin/flags", get(list_flags))
+        .route("/admin/flags/:name", put(set_flag).delete(clear_flag))
+        .route("/admin/dry-run", get(admin_dry_run))
+        .route("/admin/signed-urls", post(mint_signed_url))
+        .route("/admin/namespaces/:name/reload", post(reload_namespace))
+        .route_layer(axum::middleware::from_fn_with_state(app_state.clone(), check_admin));
+    let metrics = Router::new()
+        .route("/metrics", get(metrics))
+        .route_layer(axum::middleware::from_fn_with_state(app_state.clone(), check_metrics));
+    Router::new()
+        .route("/healthz", get(health))
+        .route("/readyz", get(readyz))
+        .merge(metrics)
+        .merge(admin)
+        .merge(proxy)
+        .layer(axum::middleware::from_fn_with_state(app_state.clone(), check_headers))
+        .layer(axum::middleware::from_fn_with_state(app_state.clone(), render_errors))
+        .layer(axum::Extension(ListenerName(name.into())))
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(request_span)
+                .on_response(record_response),
+        )
+        .layer(axum::middleware::from_fn_with_state(
+            app_state.clone(),
+            assign_request_id,
+        ))
+        .with_state(app_state)
+}
+
+/// The router of the listener of `metrics.addr`, which serves only `/metrics`.
+pub fn build_metrics_router(app_state: ApplicationState) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
         .layer(TraceLayer::new_for_http())
-        .with_state(app_state.clone());
+        .with_state(app_state)
+}
+
+/// Target of the events marking the progress of a streamed response on its invoke span, which
+/// are exported as span events whatever their level.
+pub const SPAN_EVENTS: &str = "lambda_web_gateway::span_events";
 
-    let addr = &app_state.config.addr;
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    tracing::info!("Listening on {}", addr);
-    axum::serve(listener, app).await.unwrap();
+/// The span of a request: the fields of `TraceLayer`'s default span, plus the request ID and,
+/// under `tracing.propagation`, the IDs of the trace, of the gateway's span and of its parent.
+/// The `otel.` and `http.` fields make it an OpenTelemetry server span, named after the route
+/// once one is matched.
+fn request_span(request: &axum::extract::Request) -> tracing::Span {
+    let request_id = request.extensions().get::<RequestId>().map(|id| id.0.as_str());
+    tracing::debug_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+        request_id,
+        trace_id = tracing::field::Empty,
+        span_id = tracing::field::Empty,
+        parent_id = tracing::field::Empty,
+        otel.name = %request.method(),
+        otel.kind = "server",
+        otel.status_code = tracing::field::Empty,
+        http.request.method = %request.method(),
+        http.route = tracing::field::Empty,
+        http.response.status_code = tracing::field::Empty,
+    )
 }
 
-async fn health() -> impl IntoResponse {
-    StatusCode::OK
+/// Records the status on the request's span, then logs the response as `TraceLayer` does.
+fn record_response(response: &Response, latency: Duration, span: &tracing::Span) {
+    span.record("http.response.status_code", response.status().as_u16());
+    if response.status().is_server_error() {
+        span.record("otel.status_code", "ERROR");
+    }
+    DefaultOnResponse::default().on_response(response, latency, span)
 }
 
-async fn handler(
-    path: Option<Path<String>>,
-    Query(query_string_parameters): Query<HashMap<String, String>>,
+/// ID correlating a request across the gateway, its function and its response, attached to the
+/// request extensions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+/// Gives every request its ID: the one the client sent in the `request_id` header when trusted,
+/// or a new UUIDv7. The ID replaces the header on the request, so functions see it among the event
+/// headers, and is set on every response not carrying one already, errors and streams included.
+async fn assign_request_id(
     State(state): State<ApplicationState>,
-    method: Method,
-    headers: HeaderMap,
-    body: Bytes,
+    mut request: axum::extract::Request,
+    next: axum::middleware::Next,
 ) -> Response {
-    let client = &state.client;
-    let config = &state.config;
-    let path = "/".to_string() + path.map(|p| p.0).unwrap_or_default().as_str();
+    let config = state.config();
+    let id = request::request_id(request.headers(), &config.request_id);
+    let header = HeaderName::try_from(config.request_id.header.as_str())
+        .ok()
+        .zip(HeaderValue::from_str(&id).ok());
+    if let Some((name, value)) = &header {
+        request.headers_mut().insert(name.clone(), value.clone());
+    }
+    request.extensions_mut().insert(RequestId(id));
+    let mut resp = next.run(request).await;
+    if let Some((name, value)) = header {
+        resp.headers_mut().entry(name).or_insert(value);
+    }
+    resp
+}
 
-    let http_method = method.to_string();
+/// Name of the listener a request arrived on, attached to the request extensions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListenerName(pub Arc<str>);
 
-    let content_type = headers
-        .get("content-type")
-        .and_then(|v| v.to_str().ok().map(Some).flatten())
-        .unwrap_or_default();
+/// Answers admin endpoints with 404 on listeners without `admin`, as if they did not exist.
+async fn check_admin(
+    State(state): State<ApplicationState>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let name = request.extensions().get::<ListenerName>().map(|name| name.0.clone());
+    if !state.config().listener(name.as_deref()).admin {
+        return no_route().into_response();
+    }
+    next.run(request).await
+}
 
-    let is_base64_encoded = match content_type {
-        "application/json" => false,
-        "application/xml" => false,
-        "application/javascript" => false,
-        _ if content_type.starts_with("text/") => false,
-        _ => true,
+/// Answers `/metrics` with 404 where it is not served: on listeners without `admin`, and on every
+/// listener when `metrics.addr` gives it one of its own.
+async fn check_metrics(
+    State(state): State<ApplicationState>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let config = state.config();
+    let name = request.extensions().get::<ListenerName>().map(|name| name.0.clone());
+    if !config.metrics.enabled || config.metrics.addr.is_some() || !config.listener(name.as_deref()).admin {
+        return no_route().into_response();
+    }
+    next.run(request).await
+}
+
+/// Renders the gateway's own error bodies as HTML or plain text for clients whose `Accept` prefers
+/// them, or as the matched target's `error_format`. Function responses are left alone.
+async fn render_errors(
+    State(state): State<ApplicationState>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let accept = request
+        .headers()
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    let request_id = request.extensions().get::<RequestId>().map(|id| id.0.clone());
+    let resp = next.run(request).await;
+    let (Some(info), Some(ErrorMessage(message))) = (resp.extensions().get::<ErrorInfo>(), resp.extensions().get())
+    else {
+        return resp;
+    };
+    let config = state.config();
+    let forced = resp
+        .extensions()
+        .get::<MatchedRoute>()
+        .and_then(|route| config.targets.get(&route.pattern))
+        .and_then(|target| target.error_format);
+    let format = forced.unwrap_or_else(|| error_pages::negotiate(accept.as_deref()));
+    // An ID set on the response, as for events, is the one the function saw
+    let response_id = resp
+        .headers()
+        .get(config.request_id.header.as_str())
+        .and_then(|v| v.to_str().ok());
+    let page = error_pages::ErrorPage {
+        status: resp.status(),
+        error_code: info.code,
+        phase: info.phase.as_str(),
+        message,
+        request_id: response_id.or(request_id.as_deref()).unwrap_or_default(),
+    };
+    let Some((content_type, body)) = error_pages::render(format, &config.error_pages, &page) else {
+        return resp;
     };
+    let (mut parts, _) = resp.into_parts();
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    parts.headers.remove(CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// Rejects requests whose headers were folded while `server.obs_fold` is `reject`, and requests
+/// with a header value longer than `server.max_header_value_bytes`.
+async fn check_headers(
+    State(state): State<ApplicationState>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let headers = request.headers();
+    if headers.contains_key(server::OBS_FOLD_HEADER) {
+        state
+            .telemetry
+            .increment("rejected_headers_total", vec![("reason", "obs_fold".to_string())]);
+        return GatewayError::new(
+            ErrorPhase::Ingress,
+            StatusCode::BAD_REQUEST,
+            "obs_fold_rejected",
+            "Header values continued on a new line (obs-fold) are not accepted",
+        )
+        .into_response();
+    }
+    let max = state.config().server.max_header_value_bytes;
+    if let Some((name, _)) = headers.iter().find(|(_, value)| value.len() > max) {
+        state.telemetry.increment(
+            "rejected_headers_total",
+            vec![("reason", "value_too_large".to_string())],
+        );
+        return GatewayError::new(
+            ErrorPhase::Ingress,
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            "header_value_too_large",
+            format!("The value of header {} is longer than {} bytes", name, max),
+        )
+        .into_response();
+    }
+    next.run(request).await
+}
 
-    let body = if is_base64_encoded {
-        base64::engine::general_purpose::STANDARD.encode(body)
+/// Rejects requests for hosts outside `allowed_hosts` with 421, before their body is read.
+async fn check_host(
+    State(state): State<ApplicationState>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let config = state.config();
+    if config.allowed_hosts.is_empty() {
+        return next.run(request).await;
+    }
+    let host = hosts::presented_host(request.uri(), request.headers());
+    if host.is_some_and(|host| hosts::is_allowed(&config.allowed_hosts, host)) {
+        return next.run(request).await;
+    }
+    let label = hosts::host_label(host);
+    if state.log_dedup.should_log(&label, "misdirected_request") {
+        tracing::warn!(
+            host = host.unwrap_or_default(),
+            "Rejected request for a host not in allowed_hosts"
+        );
+    }
+    state
+        .telemetry
+        .increment("misdirected_requests_total", vec![("host", label)]);
+    GatewayError::new(
+        ErrorPhase::Ingress,
+        StatusCode::MISDIRECTED_REQUEST,
+        "misdirected_request",
+        "This gateway does not serve the requested host",
+    )
+    .into_response()
+}
+
+/// Liveness: 503 from the start of a shutdown on, so load balancers stop sending requests while
+/// those in flight drain.
+async fn health(State(state): State<ApplicationState>) -> impl IntoResponse {
+    if state.in_flight.is_ready() {
+        StatusCode::OK
     } else {
-        String::from_utf8_lossy(&body).to_string()
-    };
-
-    match config.auth_mode {
-        config::AuthMode::Open => {}
-        config::AuthMode::ApiKey => {
-            let api_key = headers
-                .get("x-api-key")
-                .and_then(|v| v.to_str().ok())
-                .or_else(|| {
-                    headers
-                        .get("authorization")
-                        .and_then(|v| v.to_str().ok().and_then(|s| s.strip_prefix("Bearer ")))
-                })
-                .unwrap_or_default();
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
 
-            if !config.api_keys.contains(api_key) {
-                return Response::builder()
-                    .status(StatusCode::UNAUTHORIZED)
-                    .body(Body::empty())
-                    .unwrap();
-            }
-        }
+#[derive(Debug, Serialize)]
+struct Status {
+    #[serde(flatten)]
+    telemetry: telemetry::TelemetryStatus,
+    access_log_dropped_lines: u64,
+    flight_recorder_dropped_records: u64,
+    state_memory: memory::MemoryStatus,
+    draining_targets: Vec<drain::DrainingTarget>,
+    target_queues: BTreeMap<String, limiter::QueueStatus>,
+    /// The `concurrency` limit of the whole gateway, once a request met it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gateway_queue: Option<limiter::QueueStatus>,
+    namespaces: BTreeMap<String, NamespaceStatus>,
+    listeners: BTreeMap<String, ListenerStatus>,
+    schedules: BTreeMap<String, schedule::ScheduleState>,
+    clock: clock::ClockStatus,
+    error_budgets: BTreeMap<String, slo::BudgetStatus>,
+    circuit_breakers: BTreeMap<String, breaker::BreakerStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    config_source: Option<config_source::ConfigSourceStatus>,
+    request_body_bytes: Vec<BodySizeSummary>,
+    config_rev: String,
+}
+
+/// Percentiles of the request body sizes seen for one target, content family and encoding.
+#[derive(Debug, Serialize)]
+struct BodySizeSummary {
+    target: String,
+    content_family: String,
+    base64_encoded: bool,
+    count: u64,
+    p50: Option<f64>,
+    p90: Option<f64>,
+    p99: Option<f64>,
+}
+
+impl BodySizeSummary {
+    fn from_registry(registry: &telemetry::MetricsRegistry) -> Vec<Self> {
+        registry
+            .histograms
+            .iter()
+            .filter(|((name, _), _)| *name == "request_body_bytes")
+            .map(|((_, labels), histogram)| {
+                let label = |key| {
+                    labels
+                        .iter()
+                        .find(|(k, _)| *k == key)
+                        .map(|(_, v)| v.clone())
+                        .unwrap_or_default()
+                };
+                BodySizeSummary {
+                    target: label("target"),
+                    content_family: label("content_family"),
+                    base64_encoded: label("base64_encoded") == "true",
+                    count: histogram.count,
+                    p50: histogram.percentile(0.5),
+                    p90: histogram.percentile(0.9),
+                    p99: histogram.percentile(0.99),
+                }
+            })
+            .collect()
     }
+}
 
-    let lambda_request_body = json!({
-        "httpMethod": http_method,
-        "headers": to_string_map(&headers),
-        "path": path,
-        "queryStringParameters": query_string_parameters,
-        "isBase64Encoded": is_base64_encoded,
-        "body": body,
-        "requestContext": {
-            "elb": {
-                "targetGroupArn": "",
-            },
-        },
-    })
-    .to_string();
+#[derive(Debug, Default, Serialize)]
+struct NamespaceStatus {
+    prefix: String,
+    targets: Vec<String>,
+    requests: u64,
+    errors: u64,
+    /// Requests rejected by the namespace's `rate_limit`.
+    rate_limited: u64,
+}
 
-    let resp = match config.lambda_invoke_mode {
-        LambdaInvokeMode::Buffered => {
-            let resp = client
-                .invoke()
-                .function_name(config.lambda_function_name.as_str())
-                .payload(Blob::new(lambda_request_body))
-                .send()
-                .await
-                .unwrap();
-            handle_buffered_response(resp).await
-        }
-        LambdaInvokeMode::ResponseStream => {
-            let resp = client
-                .invoke_with_response_stream()
-                .function_name(config.lambda_function_name.as_str())
-                .invocation_type(ResponseStreamingInvocationType::RequestResponse)
-                .payload(Blob::new(lambda_request_body))
-                .send()
-                .await
-                .unwrap();
-            handle_streaming_response(resp).await
-        }
-    };
+#[derive(Debug, Default, Serialize)]
+struct ListenerStatus {
+    addr: String,
+    requests: u64,
+    errors: u64,
+}
 
-    resp
+/// `GET /status`, for holders of an `admin_api_keys` key, as `/support-bundle` is.
+async fn status(State(state): State<ApplicationState>, headers: HeaderMap) -> Response {
+    if let Err(e) = admin_key_id(&state.config(), &headers) {
+        return e.into_response();
+    }
+    axum::Json(gateway_status(&state)).into_response()
 }
 
-fn to_string_map(headers: &HeaderMap) -> HashMap<String, String> {
-    headers
+fn gateway_status(state: &ApplicationState) -> Status {
+    let config = state.config();
+    let mut namespaces: BTreeMap<String, NamespaceStatus> = config
+        .namespaces
         .iter()
-        .map(|(k, v)| {
-            (
-                k.as_str().to_owned(),
-                String::from_utf8_lossy(v.as_bytes()).into_owned(),
-            )
+        .map(|(name, namespace)| {
+            let status = NamespaceStatus {
+                prefix: namespace.prefix.clone(),
+                ..Default::default()
+            };
+            (name.clone(), status)
+        })
+        .collect();
+    for (pattern, target) in &config.targets {
+        if let Some(status) = target.namespace.as_ref().and_then(|name| namespaces.get_mut(name)) {
+            status.targets.push(pattern.clone());
+        }
+    }
+    let mut listeners: BTreeMap<String, ListenerStatus> = config
+        .listener_addrs()
+        .into_iter()
+        .map(|(name, addr)| {
+            let status = ListenerStatus {
+                addr: addr.to_string(),
+                ..Default::default()
+            };
+            (name, status)
         })
-        .collect()
+        .collect();
+    let registry = state.telemetry.snapshot();
+    for ((name, labels), count) in &registry.counters {
+        let label = |key| labels.iter().find(|(k, _)| *k == key).map(|(_, v)| v.as_str());
+        if *name == "namespace_rate_limited_total" {
+            if let Some(status) = label("namespace").and_then(|ns| namespaces.get_mut(ns)) {
+                status.rate_limited += count;
+            }
+            continue;
+        }
+        let status = match *name {
+            "requests_total" => label("namespace")
+                .and_then(|ns| namespaces.get_mut(ns))
+                .map(|status| (&mut status.requests, &mut status.errors)),
+            "listener_requests_total" => label("listener")
+                .and_then(|listener| listeners.get_mut(listener))
+                .map(|status| (&mut status.requests, &mut status.errors)),
+            _ => None,
+        };
+        if let Some((requests, errors)) = status {
+            *requests += count;
+            if label("status").is_some_and(|s| s.starts_with('5')) {
+                *errors += count;
+            }
+        }
+    }
+
+    let now = state.clock.now();
+    let schedules = config
+        .targets
+        .iter()
+        .filter_map(|(pattern, target)| {
+            let schedule = target.schedule.as_ref()?;
+            Some((pattern.clone(), schedule::evaluate(schedule, now)))
+        })
+        .collect();
+
+    Status {
+        telemetry: state.telemetry.status(),
+        access_log_dropped_lines: state.access_log.dropped_lines(),
+        flight_recorder_dropped_records: state.flight_recorder.dropped_records(),
+        state_memory: state.memory.status(),
+        draining_targets: state.targets.draining(),
+        target_queues: state.limiter.status(),
+        gateway_queue: state.limiter.global_status(),
+        namespaces,
+        listeners,
+        schedules,
+        clock: state.clock.status(),
+        error_budgets: state.error_budgets.status(&config.error_budget, Instant::now()),
+        circuit_breakers: state.breakers.status(
+            |pattern| config.targets.get(pattern)?.circuit_breaker.as_ref(),
+            Instant::now(),
+        ),
+        config_source: config.config_source.as_ref().map(|_| state.config_source.status()),
+        request_body_bytes: BodySizeSummary::from_registry(&registry),
+        config_rev: state.config_rev().to_string(),
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct LambdaResponse {
-    status_code: u16,
-    status_description: Option<String>,
-    is_base64_encoded: Option<bool>,
-    headers: Option<HashMap<String, String>>,
-    body: String,
+/// Diagnostics for bug reports: redacted config, routes, recent failures, counters, reloads and
+/// health. Protected by `admin_api_keys`, as the `/admin` endpoints, whatever the `auth_mode`.
+async fn support_bundle(State(state): State<ApplicationState>, headers: HeaderMap) -> Response {
+    let config = state.config();
+    if let Err(e) = admin_key_id(&config, &headers) {
+        return e.into_response();
+    }
+    let mut bundle = SupportBundle::new(&config).with_counters(&state.telemetry.snapshot());
+    bundle.recent_errors = state.recent_errors.entries();
+    bundle.reloads = state.reloads.entries();
+    bundle.health = state.health.report();
+    axum::Json(bundle).into_response()
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct MetadataPrelude {
-    #[serde(with = "http_serde::status_code")]
-    /// The HTTP status code.
-    pub status_code: StatusCode,
-    #[serde(with = "http_serde::header_map")]
-    /// The HTTP headers.
-    pub headers: HeaderMap,
-    /// The HTTP cookies.
-    pub cookies: Vec<String>,
-}
-
-async fn handle_buffered_response(resp: aws_sdk_lambda::operation::invoke::InvokeOutput) -> Response {
-    // Parse the InvokeOutput payload to extract the LambdaResponse
-    let payload = resp.payload().unwrap().as_ref().to_vec();
-    let lambda_response: LambdaResponse = serde_json::from_slice(&payload).unwrap();
+/// Authenticates a request to an `/admin` endpoint, returning the ID of its key for the audit log.
+fn admin_key_id(config: &Config, headers: &HeaderMap) -> Result<String, GatewayError> {
+    if config.admin_api_keys.is_empty() {
+        return Err(GatewayError::new(
+            ErrorPhase::Auth,
+            StatusCode::FORBIDDEN,
+            "admin_disabled",
+            "The admin endpoints are disabled, set admin_api_keys to enable them",
+        ));
+    }
+    let api_key = api_key_from(headers);
+    if !config.admin_api_keys.contains(api_key) {
+        return Err(unauthorized());
+    }
+    Ok(flags::key_id(api_key))
+}
 
-    // Build the response using the extracted information
-    let mut resp_builder = Response::builder().status(StatusCode::from_u16(lambda_response.status_code).unwrap());
+#[derive(Debug, Deserialize)]
+struct DryRunQuery {
+    target: String,
+}
 
-    if let Some(headers) = lambda_response.headers {
-        for (key, value) in headers {
-            resp_builder = resp_builder.header(key, value);
+/// Dry-runs every function of the target named by `?target=`, with its qualifier, to check the
+/// gateway's permissions from outside. Answers `204` when all of them pass, else the first failure.
+async fn admin_dry_run(
+    State(state): State<ApplicationState>,
+    Query(query): Query<DryRunQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let config = state.config();
+    if let Err(e) = admin_key_id(&config, &headers) {
+        return e.into_response();
+    }
+    let Some(target) = config.targets.get(&query.target) else {
+        return GatewayError::new(
+            ErrorPhase::Ingress,
+            StatusCode::NOT_FOUND,
+            "unknown_target",
+            format!("No target is keyed {:?}", query.target),
+        )
+        .into_response();
+    };
+    for function_name in config.function_members(target) {
+        let request = InvokeRequest {
+            function_name: function_name.clone(),
+            qualifier: target.qualifier.clone(),
+            payload: "{}".to_string(),
+            client_context: None,
+            trace_header: None,
+        };
+        if let Err(e) = dry_run(&state, request).await {
+            return e.into_response();
         }
     }
+    StatusCode::NO_CONTENT.into_response()
+}
 
-    let body = if lambda_response.is_base64_encoded.unwrap_or(false) {
-        base64::engine::general_purpose::STANDARD
-            .decode(lambda_response.body)
-            .unwrap()
-    } else {
-        lambda_response.body.into_bytes()
+/// The most recent lifecycle events, oldest first.
+async fn list_lifecycle_events(State(state): State<ApplicationState>, headers: HeaderMap) -> Response {
+    if let Err(e) = admin_key_id(&state.config(), &headers) {
+        return e.into_response();
+    }
+    axum::Json(state.lifecycle.recent()).into_response()
+}
+
+/// The most recent requests whose shadow answered differently, oldest first.
+async fn list_shadow_mismatches(State(state): State<ApplicationState>, headers: HeaderMap) -> Response {
+    if let Err(e) = admin_key_id(&state.config(), &headers) {
+        return e.into_response();
+    }
+    axum::Json(state.shadow_mismatches.entries()).into_response()
+}
+
+/// Every known feature flag as it is in effect, with its runtime override.
+async fn list_flags(State(state): State<ApplicationState>, headers: HeaderMap) -> Response {
+    if let Err(e) = admin_key_id(&state.config(), &headers) {
+        return e.into_response();
+    }
+    axum::Json(state.flags.report()).into_response()
+}
+
+/// Overrides a feature flag until the override is cleared; the body is a [`flags::FlagOverride`].
+async fn set_flag(
+    State(state): State<ApplicationState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let key_id = match admin_key_id(&state.config(), &headers) {
+        Ok(key_id) => key_id,
+        Err(e) => return e.into_response(),
+    };
+    let invalid = |message: String| {
+        GatewayError::new(
+            ErrorPhase::Ingress,
+            StatusCode::BAD_REQUEST,
+            "invalid_flag_override",
+            message,
+        )
+        .into_response()
+    };
+    let change: flags::FlagOverride = match serde_json::from_slice(&body) {
+        Ok(change) => change,
+        Err(e) => return invalid(e.to_string()),
     };
-    resp_builder.body(Body::from(body)).unwrap()
+    match state.flags.set_override(&name, change.clone()) {
+        Ok(effective) => {
+            tracing::info!(
+                target: "audit",
+                admin_key_id = %key_id,
+                flag = %name,
+                ?change,
+                ?effective,
+                "Feature flag overridden"
+            );
+            axum::Json(effective).into_response()
+        }
+        Err(e) => invalid(e),
+    }
 }
 
-async fn handle_streaming_response(
-    mut resp: aws_sdk_lambda::operation::invoke_with_response_stream::InvokeWithResponseStreamOutput,
+/// Reads the `file` of one namespace again and switches to it, leaving every other namespace as it
+/// is. An unreadable or invalid file keeps the running configuration.
+async fn reload_namespace(
+    State(state): State<ApplicationState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
 ) -> Response {
-    let (tx, rx) = mpsc::channel(1);
-    let mut metadata_buffer = Vec::new();
-    let mut metadata_prelude: Option<MetadataPrelude> = None;
-    let mut remaining_data = Vec::new();
+    let key_id = match admin_key_id(&state.config(), &headers) {
+        Ok(key_id) => key_id,
+        Err(e) => return e.into_response(),
+    };
+    let config = state.config();
+    if !config.namespaces.contains_key(&name) {
+        let message = format!("Namespace {} is not configured", name);
+        return GatewayError::new(ErrorPhase::Ingress, StatusCode::NOT_FOUND, "unknown_namespace", message)
+            .into_response();
+    }
+    match config.reload_namespace(&name) {
+        Ok(reloaded) => {
+            let targets: Vec<String> = reloaded
+                .targets
+                .iter()
+                .filter(|(_, target)| target.namespace.as_deref() == Some(name.as_str()))
+                .map(|(pattern, _)| pattern.clone())
+                .collect();
+            state.reload(reloaded);
+            tracing::info!(target: "audit", admin_key_id = %key_id, namespace = %name, "Namespace reloaded");
+            axum::Json(serde_json::json!({
+                "namespace": name,
+                "targets": targets,
+                "config_rev": &*state.config_rev(),
+            }))
+            .into_response()
+        }
+        Err(e) => {
+            tracing::error!(
+                "Keeping the running configuration, reload of namespace {} failed: {}",
+                name,
+                e
+            );
+            GatewayError::new(
+                ErrorPhase::Ingress,
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "namespace_reload_failed",
+                e,
+            )
+            .into_response()
+        }
+    }
+}
 
-    // Step 1: Detect if metadata exists and get the first chunk
-    let (has_metadata, first_chunk) = detect_metadata(&mut resp).await;
+/// Drops the runtime override of a feature flag, going back to its value from the config file.
+async fn clear_flag(State(state): State<ApplicationState>, Path(name): Path<String>, headers: HeaderMap) -> Response {
+    let key_id = match admin_key_id(&state.config(), &headers) {
+        Ok(key_id) => key_id,
+        Err(e) => return e.into_response(),
+    };
+    if state.flags.clear_override(&name) {
+        tracing::info!(target: "audit", admin_key_id = %key_id, flag = %name, "Feature flag override cleared");
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
 
-    // Step 2: Process the first chunk
-    if let Some(chunk) = first_chunk {
-        if has_metadata {
-            metadata_buffer.extend_from_slice(&chunk);
-            (metadata_prelude, remaining_data) = collect_metadata(&mut resp, &mut metadata_buffer).await;
-        } else {
-            // No metadata prelude, treat first chunk as payload
-            remaining_data = chunk;
+/// Signs a temporary URL for a target with `accept_signed_urls`; the body is a
+/// [`signed_url::MintRequest`].
+async fn mint_signed_url(State(state): State<ApplicationState>, headers: HeaderMap, body: Bytes) -> Response {
+    let config = state.config();
+    let key_id = match admin_key_id(&config, &headers) {
+        Ok(key_id) => key_id,
+        Err(e) => return e.into_response(),
+    };
+    let Some(signed_urls) = &config.signed_urls else {
+        return GatewayError::new(
+            ErrorPhase::Ingress,
+            StatusCode::NOT_FOUND,
+            "signed_urls_disabled",
+            "Signed URLs are disabled, add a signed_urls section to enable them",
+        )
+        .into_response();
+    };
+    let invalid = |message: String| {
+        GatewayError::new(
+            ErrorPhase::Ingress,
+            StatusCode::BAD_REQUEST,
+            "invalid_signed_url_request",
+            message,
+        )
+        .into_response()
+    };
+    let request: signed_url::MintRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => return invalid(e.to_string()),
+    };
+    let path = request.path.split('?').next().unwrap_or_default();
+    let accepted = config
+        .match_route(&request::decode_path(path))
+        .is_some_and(|(_, target, _)| target.accept_signed_urls);
+    if !accepted {
+        return invalid(format!("{:?} is not served by a target with accept_signed_urls", path));
+    }
+    match signed_url::mint(signed_urls, &request, state.clock.now()) {
+        Ok(signed) => {
+            tracing::info!(
+                target: "audit",
+                admin_key_id = %key_id,
+                path = %path,
+                expires_at = %signed.expires_at,
+                client_ip = ?request.client_ip,
+                max_uses = ?request.max_uses,
+                "Signed URL minted"
+            );
+            axum::Json(signed).into_response()
         }
+        Err(e) => invalid(e),
     }
+}
 
-    // Spawn task to handle remaining stream
-    tokio::spawn(async move {
-        // Send remaining data after metadata first
-        if !remaining_data.is_empty() {
-            let stream_update = InvokeResponseStreamUpdate::builder()
-                .payload(Blob::new(remaining_data))
-                .build();
-            let _ = tx.send(PayloadChunk(stream_update)).await;
-        }
-
-        while let Some(event) = resp.event_stream.recv().await.unwrap() {
-            match event {
-                PayloadChunk(chunk) => {
-                    if let Some(data) = chunk.payload() {
-                        let stream_update = InvokeResponseStreamUpdate::builder().payload(data.clone()).build();
-                        let _ = tx.send(PayloadChunk(stream_update)).await;
-                    }
+#[derive(Debug, Serialize)]
+struct Readiness {
+    ready: bool,
+    /// Shutdown has begun.
+    shutting_down: bool,
+    targets: BTreeMap<String, health::ProbeResult>,
+}
+
+/// Every metric of the gateway in the Prometheus text format. Like `/healthz`, it needs no API key.
+async fn metrics(State(state): State<ApplicationState>) -> Response {
+    let text = prometheus::render(&state.telemetry.snapshot());
+    ([(CONTENT_TYPE, prometheus::CONTENT_TYPE)], text).into_response()
+}
+
+/// Readiness report built from the latest health probes: 503 while any probed target is unhealthy,
+/// and from the start of a shutdown on.
+async fn readyz(State(state): State<ApplicationState>) -> impl IntoResponse {
+    let targets = state.health.report();
+    let shutting_down = !state.in_flight.is_ready();
+    let ready = !shutting_down && targets.values().all(|result| result.healthy);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        axum::Json(Readiness {
+            ready,
+            shutting_down,
+            targets,
+        }),
+    )
+}
+
+async fn handler(
+    path: Option<Path<String>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    listener_name: Option<axum::Extension<ListenerName>>,
+    State(state): State<ApplicationState>,
+    request: axum::extract::Request,
+) -> Response {
+    let started_at = Instant::now();
+    if state.in_flight.is_refusing() {
+        return shutting_down().into_response();
+    }
+    let (parts, body) = request.into_parts();
+    let (method, uri, headers) = (parts.method, parts.uri, parts.headers);
+    // Assigned by `assign_request_id`, which embedders serving `handler` on their own may skip
+    let request_id = match parts.extensions.get::<RequestId>() {
+        Some(RequestId(id)) => id.clone(),
+        None => request::request_id(&headers, &state.config().request_id),
+    };
+    // server::serve already reads IPv4-mapped peers as IPv4, but embedders may serve the router
+    let client_ip = connect_info.map(|ConnectInfo(addr)| addr.ip().to_canonical());
+    let path = "/".to_string() + path.map(|p| p.0).unwrap_or_default().as_str();
+    let default_target = Target::default();
+    let config = state.config();
+    // Routing works on the raw path so that captures are decoded segment by segment. Without a
+    // host, as from HTTP/1.0 clients, only targets without a `host` can match.
+    let host = hosts::presented_host(&uri, &headers);
+    let listener_name = listener_name.map(|axum::Extension(ListenerName(name))| name);
+    let listener = config.listener(listener_name.as_deref());
+    // A listener limited to some targets has no fallback to the top-level function
+    let (mut pattern, mut target, mut rule) = match config.match_route_on_listener(listener, host, uri.path()) {
+        Some(route) => route,
+        None if config.unmatched == UnmatchedRoute::NotFound || !listener.targets.is_empty() => {
+            return no_route().into_response()
+        }
+        None => ("", &default_target, RouteRule::Fallback),
+    };
+    let path_params = config::path_pattern(pattern)
+        .and_then(|pattern| config::match_pattern(pattern, uri.path()))
+        .unwrap_or_default();
+    // A target with `match` hands the request on, captures and all, to the target its conditions
+    // select or, when none holds, its body does
+    let body_json = match &target.body_match {
+        Some(body_match) => {
+            let attributes = expr::Attributes {
+                method: &method,
+                path: &path,
+                query: uri.query().unwrap_or_default(),
+                headers: &headers,
+                client_ip,
+                key_id: None,
+                record: None,
+            };
+            match request::match_conditions(body_match, &attributes) {
+                Some(name) => {
+                    let Some(selected) = match_target(&config, listener, name, (pattern, target)) else {
+                        return no_route().into_response();
+                    };
+                    (pattern, target) = selected;
+                    rule = RouteRule::Condition;
+                    None
                 }
-                InvokeComplete(_) => {
-                    let _ = tx.send(event).await;
+                None => Some(body_match),
+            }
+        }
+        None => None,
+    };
+    let upstream_time = UpstreamTime::default();
+    let mut request = IncomingRequest {
+        pattern,
+        method: &method,
+        path: &path,
+        raw_path: uri.path(),
+        raw_query_string: uri.query().unwrap_or_default(),
+        path_params: &path_params,
+        drain: None,
+        upstream_time: upstream_time.clone(),
+        client_ip,
+        listener,
+        request_id: &request_id,
+    };
+
+    // A target known before the body is read turns requests away, and limits the body, before any
+    // of it is read. Only targets picked by `body_json` rules wait for the body.
+    let known = match body_json {
+        Some(_) => None,
+        None => Some(admit(&state, target, &request, &headers).await),
+    };
+    let limit = match body_json {
+        Some(body_match) => config.body_match_limit(target, body_match),
+        None => config.request_body_limit(target),
+    };
+    let mut request_bytes = 0;
+    // Every target is invoked through the Lambda API, whose payload needs the whole body. Target
+    // kinds that could stream the body to their upstream would take `body` unbuffered here.
+    let admitted = match known {
+        Some(Err(e)) => Err(refusal(target, e)),
+        known => match request::read_body(&headers, body, limit).await {
+            Ok(body) => {
+                let body = request::ParsedBody::new(body);
+                request_bytes = body.bytes().len();
+                if let Some(body_match) = body_json {
+                    let (name, matched) = request::match_body(body_match, &headers, &body);
+                    let Some(selected) = match_target(&config, listener, name, (pattern, target)) else {
+                        return no_route().into_response();
+                    };
+                    (pattern, target) = selected;
+                    rule = if matched {
+                        RouteRule::BodyJson
+                    } else {
+                        RouteRule::BodyFallback
+                    };
+                    request.pattern = pattern;
                 }
-                _ => {}
+                let admitted = match known {
+                    Some(admitted) => admitted,
+                    None => admit(&state, target, &request, &headers).await,
+                };
+                admitted
+                    .map(|admission| (admission, body))
+                    .map_err(|e| refusal(target, e))
             }
+            Err(e) => Err(e.into_response()),
+        },
+    };
+    let active = state.targets.enter(pattern);
+    request.drain = active.as_ref().map(|active| active.drain.clone());
+    tracing::debug!(pattern, ?rule, ?path_params, "Matched route");
+
+    let encoding = compression::negotiate(&headers);
+    let mut in_flight = state.in_flight.enter();
+    let aborted = in_flight.aborted();
+    let forwarded = match admitted {
+        Ok((admission, body)) => tokio::select! {
+            resp = forward(&state, target, request, admission, headers, body) => Some(resp),
+            _ = aborted => None,
+        },
+        Err(resp) => Some(resp),
+    };
+    let mut resp = match forwarded {
+        Some(resp) => {
+            let streamed = resp.extensions().get::<StreamedResponse>().is_some();
+            if streamed {
+                in_flight.set_streaming();
+            }
+            // The digest trailer is of the bytes the client gets, which compressing would change
+            let digested = streamed && target.body_digest_trailer;
+            let resp = if config.compression.enabled && !digested {
+                compression::compress(resp, encoding, &config.compression).await
+            } else {
+                resp
+            };
+            // Counted in flight until the body has been sent
+            resp.map(|body| Body::new(shutdown::InFlightBody::new(body, in_flight)))
+        }
+        None => {
+            tracing::warn!(pattern, "Aborted a request still waiting on its function at shutdown");
+            drop(in_flight);
+            shutting_down().into_response()
         }
+    };
+    if let Some(slo) = target.slo {
+        let error = resp.status().is_server_error();
+        let alert = state
+            .error_budgets
+            .record(pattern, slo, &config.error_budget, error, Instant::now());
+        if let Some(alert) = alert.filter(|_| target.slo_warning_header) {
+            resp.headers_mut()
+                .insert(slo::SLO_AT_RISK_HEADER, HeaderValue::from_static(alert.as_str()));
+        }
+    }
+    if let Some(active) = active {
+        // The request counts against its target generation until the body has been sent
+        resp = resp.map(|body| Body::new(drain::TrackedBody::new(body, active)));
+    }
+    resp.extensions_mut().insert(MatchedRoute {
+        pattern: pattern.to_string(),
+        rule,
     });
 
-    let stream = ReceiverStream::new(rx).map(|event| {
-        match event {
-            PayloadChunk(chunk) => {
-                if let Some(data) = chunk.payload() {
-                    let bytes = data.clone().into_inner();
-                    Ok::<_, std::convert::Infallible>(Bytes::from(bytes))
-                } else {
-                    Ok(Bytes::default())
-                }
-            },
-            InvokeComplete(_) => Ok(Bytes::default()),
-            _ => Ok(Bytes::default()), // Handle other event types
+    let duration_ms = started_at.elapsed().as_secs_f64() * 1000.0;
+    let error = resp.extensions().get::<ErrorInfo>().copied();
+    let labels = vec![
+        ("method", method.to_string()),
+        ("namespace", target.namespace.clone().unwrap_or_default()),
+        ("target", pattern.to_string()),
+        ("status", resp.status().as_u16().to_string()),
+        ("phase", error.map(|e| e.phase.as_str()).unwrap_or_default().to_string()),
+    ];
+    state.telemetry.increment("requests_total", labels.clone());
+    if let Some(class) = error.and_then(|e| e.invoke_error_class()) {
+        state.telemetry.increment(
+            "invoke_errors_total",
+            vec![("target", pattern.to_string()), ("class", class.to_string())],
+        );
+    }
+    state.telemetry.increment(
+        "listener_requests_total",
+        vec![
+            (
+                "listener",
+                listener_name.as_deref().unwrap_or(config::DEFAULT_LISTENER).to_string(),
+            ),
+            ("status", resp.status().as_u16().to_string()),
+        ],
+    );
+    if resp.extensions().get::<StreamedResponse>().is_some() {
+        // A stream lasts until its last chunk has been sent
+        let timer = telemetry::DropTimer::new(state.telemetry.clone(), "request_duration_ms", labels, started_at);
+        resp = resp.map(|body| Body::new(drain::TrackedBody::new(body, timer)));
+    } else {
+        state.telemetry.observe("request_duration_ms", labels, duration_ms);
+    }
+    // Everything not spent waiting on Lambda was added by the gateway
+    let upstream_ms = upstream_time.get().map(|upstream| upstream.as_secs_f64() * 1000.0);
+    let overhead_ms = (duration_ms - upstream_ms.unwrap_or_default()).max(0.0);
+    let target_label = vec![("target", pattern.to_string())];
+    if let Some(upstream_ms) = upstream_ms {
+        state
+            .telemetry
+            .observe("upstream_duration_ms", target_label.clone(), upstream_ms);
+    }
+    state
+        .telemetry
+        .observe("gateway_overhead_ms", target_label, overhead_ms);
+    let upstream = resp.extensions().get::<Upstream>().map(|u| u.0.clone());
+    if error.is_some() || resp.status().is_server_error() {
+        let failed = FailedRequest {
+            timestamp_ms: access_log::now_ms(),
+            method: method.to_string(),
+            path: path.clone(),
+            target: pattern.to_string(),
+            status: resp.status().as_u16(),
+            phase: error.map(|e| e.phase),
+            error_code: error.map(|e| e.code),
+            upstream: upstream.clone(),
+        };
+        if let Ok(record) = serde_json::to_vec(&failed) {
+            state.flight_recorder.append(record);
+        }
+        state.recent_errors.push(failed);
+    }
+    let record = AccessRecord {
+        timestamp_ms: access_log::now_ms(),
+        client_ip,
+        method: method.to_string(),
+        path,
+        target: pattern.to_string(),
+        rule: resp.extensions().get::<MatchedRoute>().map(|route| route.rule),
+        status: resp.status().as_u16(),
+        duration_ms,
+        upstream_ms,
+        overhead_ms,
+        request_bytes: request_bytes as u64,
+        response_bytes: 0,
+        streamed: resp.extensions().get::<StreamedResponse>().is_some(),
+        client_disconnected: false,
+        upstream,
+        request_id,
+        lambda_request_id: resp.extensions().get::<LambdaRequestId>().map(|id| id.0.clone()),
+        phase: error.map(|e| e.phase),
+        error_code: error.map(|e| e.code),
+        config_rev: state.config_rev().to_string(),
+    };
+    // Logged once the body has been sent, with its size
+    let access_log = state.access_log.clone();
+    resp.map(|body| Body::new(access_log::LoggedBody::new(body, record, access_log, started_at)))
+}
+
+/// The target pattern and routing rule that served a request, attached to the response extensions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MatchedRoute {
+    pub pattern: String,
+    pub rule: RouteRule,
+}
+
+/// The target a `match` rule names, or `None` when the listener does not serve it. A name that is
+/// not a target, which validation rules out, leaves the request with the `match` target.
+fn match_target<'a>(
+    config: &'a Config,
+    listener: &ListenerConfig,
+    name: &str,
+    current: (&'a str, &'a Target),
+) -> Option<(&'a str, &'a Target)> {
+    match config.targets.get_key_value(name) {
+        Some((pattern, target)) if listener.serves(pattern) => Some((pattern.as_str(), target)),
+        Some(_) => None,
+        None => Some(current),
+    }
+}
+
+/// The parts of a client request that shape the Lambda payload, besides headers and body.
+struct IncomingRequest<'a> {
+    /// Pattern of the matched target; empty for the top-level function.
+    pattern: &'a str,
+    method: &'a Method,
+    /// Percent-decoded request path.
+    path: &'a str,
+    /// Request path as sent, still percent-encoded.
+    raw_path: &'a str,
+    raw_query_string: &'a str,
+    path_params: &'a PathParams,
+    /// Set when the target is tracked for draining on reload.
+    drain: Option<drain::DrainSignal>,
+    upstream_time: UpstreamTime,
+    /// Address of the connected client, when the server records it.
+    client_ip: Option<IpAddr>,
+    /// Profile of the listener the request arrived on.
+    listener: &'a ListenerConfig,
+    /// ID given to the request, sent to the function and returned to the client.
+    request_id: &'a str,
+}
+
+/// How a request was let in: with the API key it presented, or by a signed URL, leaving the query
+/// string without the signature parameters.
+struct Admission {
+    api_key: Option<String>,
+    unsigned_query: Option<String>,
+}
+
+/// Checks of `target` that need no body: its methods, its schedule, client bans and
+/// authentication. They run before the body is read, so a refused request is never read.
+/// Answer with [`refusal`].
+async fn admit(
+    state: &ApplicationState,
+    target: &Target,
+    request: &IncomingRequest<'_>,
+    headers: &HeaderMap,
+) -> Result<Admission, GatewayError> {
+    let config = state.config();
+
+    // Checked first, so a method the target never serves costs neither auth nor an invoke
+    if !target.accepts_method(request.method) {
+        return Err(method_not_allowed(request.method));
+    }
+
+    if let Some(schedule) = &target.schedule {
+        schedule::check(schedule, state.clock.now(), &config.retry_after)?;
+    }
+
+    let mut admission = Admission {
+        api_key: None,
+        unsigned_query: None,
+    };
+    if matches!(config.auth_mode_on(target, request.listener), config::AuthMode::Open) {
+        return Ok(admission);
+    }
+    // A banned address is turned away before its key is looked at
+    if let (Some(_), Some(ip)) = (&config.auth_bans, request.client_ip) {
+        let now = state.clock.now();
+        state.auth_bans.check(ip, &config.retry_after, now).await?;
+    }
+    let api_key = api_key_from(headers);
+    if config.api_keys(target).contains(api_key) {
+        admission.api_key = Some(api_key.to_string());
+    } else if target.accept_signed_urls && signed_url::is_signed(request.raw_query_string) {
+        let Some(signed_urls) = &config.signed_urls else {
+            return Err(failed_authentication(state, &config, request.client_ip).await);
+        };
+        let verified = signed_url::verify(
+            signed_urls,
+            state.signed_url_uses.as_ref(),
+            request.method,
+            request.raw_path,
+            request.raw_query_string,
+            request.client_ip,
+            state.clock.now(),
+        )
+        .await;
+        admission.unsigned_query = Some(verified?);
+    } else {
+        return Err(failed_authentication(state, &config, request.client_ip).await);
+    }
+    Ok(admission)
+}
+
+/// The answer to a request `target` refused, listing the methods it serves when refused for its
+/// method.
+fn refusal(target: &Target, e: GatewayError) -> Response {
+    let mut resp = e.into_response();
+    if resp.status() == StatusCode::METHOD_NOT_ALLOWED {
+        if let Ok(allow) = HeaderValue::from_str(&target.allow_header()) {
+            resp.headers_mut().insert(ALLOW, allow);
         }
+    }
+    resp
+}
+
+async fn forward(
+    state: &ApplicationState,
+    target: &Target,
+    request: IncomingRequest<'_>,
+    admission: Admission,
+    mut headers: HeaderMap,
+    mut body: request::ParsedBody,
+) -> Response {
+    let config = state.config();
+
+    // A target picked by its body may take less than was read for it
+    let limit = config.request_body_limit(target);
+    if body.bytes().len() > limit {
+        return request::body_too_large(limit).into_response();
+    }
+
+    let Admission {
+        api_key,
+        unsigned_query,
+    } = admission;
+    let signed = unsigned_query.is_some();
+    // The function never sees the gateway's signature parameters
+    let request = match &unsigned_query {
+        Some(query) => IncomingRequest {
+            raw_query_string: query,
+            ..request
+        },
+        None => request,
+    };
+
+    // Flag conditions see the request as the client sent it
+    let key_id = api_key.as_deref().map(flags::key_id);
+    let attributes = expr::Attributes {
+        method: request.method,
+        path: request.path,
+        query: request.raw_query_string,
+        headers: &headers,
+        client_ip: request.client_ip,
+        key_id: key_id.as_deref(),
+        record: None,
+    };
+    let flags = state.flags.context(&attributes);
+    let priority = target.priority_of(&attributes);
+    let records = target
+        .ndjson
+        .as_ref()
+        .map(|config| ndjson::RecordStream::new(config, ndjson::RequestAttributes::new(&attributes)));
+
+    if target.resumable_downloads {
+        if let Some(token) = headers.get(spool::DOWNLOAD_TOKEN_HEADER) {
+            return match state.spool.serve(request.pattern, token, &headers).await {
+                Ok(resp) => resp,
+                Err(e) => e.into_response(),
+            };
+        }
+    }
+
+    // A plain body keeps its parsed JSON; an inflated one is parsed afresh
+    if target.decompress_request && headers.contains_key(CONTENT_ENCODING) {
+        let max_bytes = target.max_decompressed_request_bytes;
+        body = match request::decompress_body(&mut headers, body.bytes().clone(), max_bytes) {
+            Ok(body) => request::ParsedBody::new(body),
+            Err(e) => return e.into_response(),
+        };
+    }
+
+    if target.validate_json_body {
+        if let Err(e) = request::validate_json_body(&headers, &body, target.max_json_depth) {
+            return e.into_response();
+        }
+    }
+    let body = body.bytes().clone();
+
+    if target.path_param_headers {
+        request::insert_path_param_headers(&mut headers, request.path_params);
+    }
+    // The function sees the rewritten path; the one the client sent travels in a header
+    let rewritten = request::rewrite_path(target, request.pattern, request.raw_path).map(|raw_path| {
+        request::insert_original_path_header(&mut headers, target, request.raw_path);
+        (request::decode_path(&raw_path), raw_path)
     });
+    let (path, raw_path) = match &rewritten {
+        Some((path, raw_path)) => (path.as_str(), raw_path.as_str()),
+        None => (request.path, request.raw_path),
+    };
+    if !target.forward_settings.is_empty() {
+        request::insert_setting_headers(&mut headers, target, request.pattern);
+    }
+    // API Gateway events carry the auth result in their authorizer, other payloads in headers
+    let auth = target
+        .auth_context
+        .then(|| request::AuthContext::new(api_key.as_deref(), signed));
+    match &auth {
+        Some(_) if request::AuthContext::in_authorizer(config.payload_mode(target)) => {
+            request::remove_auth_headers(&mut headers)
+        }
+        Some(auth) => request::insert_auth_headers(&mut headers, auth),
+        None => {}
+    }
 
-    let mut resp_builder = Response::builder();
+    let preencoded = match request::take_preencoded_body(
+        &mut headers,
+        &body,
+        api_key.as_deref(),
+        &config.trusted_body_encoding_keys,
+    ) {
+        Ok(preencoded) => preencoded,
+        Err(e) => return e.into_response(),
+    };
 
-    if let Some(metadata_prelude) = metadata_prelude {
-        resp_builder = resp_builder.status(metadata_prelude.status_code);
+    // The function sees the gateway's span as its parent
+    let trace = config
+        .tracing
+        .propagation
+        .map(|propagation| trace_context::TraceContext::continue_from(propagation, &headers));
+    let span = tracing::Span::current();
+    span.record("http.route", request.pattern);
+    span.record("otel.name", format!("{} {}", request.method, request.pattern).as_str());
+    if let Some(trace) = &trace {
+        trace.apply(&mut headers);
+        span.record("trace_id", trace.trace_id.as_str());
+        span.record("span_id", trace.span_id.as_str());
+        span.record("parent_id", trace.parent_id.as_deref());
+    }
 
-        for (k, v) in metadata_prelude.headers.iter() {
-            if k != "content-length" {
-                resp_builder = resp_builder.header(k, v);
+    let mut request_context = request::RequestContext::new(&headers);
+    request_context.trace_header = trace.and_then(|trace| trace.xray_header());
+    request_context.request_id = request.request_id.to_string();
+    request_context.pattern = request.pattern.to_string();
+    request_context.drain = request.drain;
+    request_context.upstream_time = request.upstream_time;
+    request_context.flags = flags;
+    request_context.records = records;
+    request_context.hide_debug_headers = !request.listener.debug_headers;
+    request_context.method = request.method.clone();
+    // A buffered response is complete when it is sent, so there is nothing to resume
+    let buffered = target.buffer_stream || (target.buffer_stream_on_request && request_context.buffer_stream);
+    if target.resumable_downloads && !buffered {
+        request_context.spool = Some(state.spool.clone());
+    }
+    let payload_mode = config.payload_mode(target);
+    // Lambda would refuse the invoke anyway; base64 makes a binary body a third larger
+    let payload_bytes = match &preencoded {
+        _ if payload_mode == PayloadMode::Raw => body.len(),
+        Some(encoded) => encoded.len(),
+        None => request::payload_body_bytes(&headers, body.len()),
+    };
+    if payload_bytes > request::MAX_INVOKE_PAYLOAD_BYTES {
+        return GatewayError::new(
+            ErrorPhase::Build,
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+            format!(
+                "Request body of {} bytes takes {} bytes in the invoke payload, over the Lambda limit of {} bytes",
+                body.len(),
+                payload_bytes,
+                request::MAX_INVOKE_PAYLOAD_BYTES
+            ),
+        )
+        .into_response();
+    }
+    let event_request = request::EventRequest {
+        method: request.method,
+        path,
+        raw_path,
+        raw_query_string: request.raw_query_string,
+        pattern: request.pattern,
+        host: target.host.as_deref(),
+        path_params: request.path_params,
+        source_ip: request.client_ip,
+        request_id: &request_context.request_id,
+        multi_value: target.multi_value,
+    };
+    let body_bytes = body.len();
+    let (lambda_request_body, is_base64_encoded) = if payload_mode == PayloadMode::Raw {
+        // Raw bodies skip the event and the pooled buffers, so the payload is byte for byte the body
+        let raw = raw_client_context(&config, &event_request, &headers)
+            .and_then(|context| Ok((context, request::raw_payload(body)?)));
+        match raw {
+            Ok((context, payload)) => {
+                request_context.client_context = Some(context);
+                (payload, false)
             }
+            Err(e) => return e.into_response(),
         }
+    } else {
+        // Trusted clients that already encoded the body are passed through as is
+        let (body, is_base64_encoded) = match preencoded {
+            Some(body) => (body, true),
+            None => request::encode_body(&headers, &body),
+        };
+        let mut event = request::build_event(payload_mode, &event_request, &headers, &body, is_base64_encoded);
+        if let Some(auth) = &auth {
+            request::insert_authorizer(payload_mode, &mut event, auth);
+        }
+        if target.compact_payload {
+            let saved = compact::compact(payload_mode, &mut event);
+            state.telemetry.add(
+                "payload_compaction_saved_bytes_total",
+                vec![("target", request.pattern.to_string())],
+                saved as u64,
+            );
+        }
+        (
+            state.payload_buffers.serialize(request.pattern, &event),
+            is_base64_encoded,
+        )
+    };
+    if body_bytes > 0 {
+        let content_type = headers.get("content-type").and_then(|v| v.to_str().ok());
+        let labels = vec![
+            ("target", request.pattern.to_string()),
+            (
+                "content_family",
+                request::content_family(content_type.unwrap_or_default()).to_string(),
+            ),
+            ("base64_encoded", is_base64_encoded.to_string()),
+        ];
+        state.telemetry.observe_in(
+            "request_body_bytes",
+            labels,
+            body_bytes as f64,
+            &telemetry::SIZE_BUCKETS_BYTES,
+        );
+    }
 
-        for cookie in &metadata_prelude.cookies {
-            resp_builder = resp_builder.header("set-cookie", cookie);
+    // A namespace over its rate answers before its targets' breakers and concurrency limits
+    let rate_limit = target
+        .namespace
+        .as_ref()
+        .and_then(|name| Some((name, config.namespaces.get(name)?.rate_limit.as_ref()?)));
+    if let Some((name, settings)) = rate_limit {
+        if let Err(e) = state
+            .rate_limiter
+            .admit(name, settings, &config.retry_after, state.clock.now())
+            .await
+        {
+            return e.into_response();
         }
+    }
+    // An open breaker answers before the request can take a concurrency slot
+    let breaker = match &target.circuit_breaker {
+        Some(settings) => match state.breakers.admit(request.pattern, settings, Instant::now()).await {
+            Ok(permit) => Some((permit, settings)),
+            Err(left) => return circuit_open(state, request.pattern, left, &config.retry_after).into_response(),
+        },
+        None => None,
+    };
+    // Listeners for trusted internal traffic may skip the concurrency limits. A request takes a
+    // slot of its target before one of the gateway, so it never holds a shared slot while queued.
+    let (permit, global_permit) = if request.listener.concurrency_limits {
+        let weights = &config.priority_weights;
+        let permit = match state
+            .limiter
+            .acquire(request.pattern, target, priority, weights, &config.retry_after)
+            .await
+        {
+            Ok(permit) => permit,
+            Err(e) => return e.into_response(),
+        };
+        let global_permit = match state
+            .limiter
+            .acquire_global(&config.concurrency, priority, weights, &config.retry_after)
+            .await
+        {
+            Ok(permit) => permit,
+            Err(e) => return e.into_response(),
+        };
+        (permit, global_permit)
     } else {
-        // Default response if no metadata
-        resp_builder = resp_builder.status(StatusCode::OK);
-        resp_builder = resp_builder.header("content-type", "application/octet-stream");
+        (None, None)
+    };
+    let result = invoke_target(state, target, lambda_request_body, &request_context).await;
+    if let (Some(permit), Some(latency)) = (&permit, request_context.upstream_time.get()) {
+        let throttled = result.as_ref().is_err_and(|e| e.code == "function_throttled");
+        permit.report(latency, throttled);
     }
+    if let Some((breaker, settings)) = breaker {
+        let failed = match &result {
+            Ok(resp) => resp.extensions().get::<FunctionError>().is_some(),
+            Err(e) => matches!(e.phase, ErrorPhase::Invoke | ErrorPhase::Upstream),
+        };
+        breaker.settle(settings, failed, Instant::now()).await;
+    }
+    match result {
+        // The concurrency slots are held until the body has been sent
+        Ok(resp) => match (permit, global_permit) {
+            (None, None) => resp,
+            permits => resp.map(|body| Body::new(drain::TrackedBody::new(body, permits))),
+        },
+        Err(e) => {
+            if state.log_dedup.should_log(request.pattern, e.code) {
+                tracing::error!("Request to {} failed: {}", config.function_name(target), e);
+            }
+            e.into_response()
+        }
+    }
+}
+
+/// The API key sent in `x-api-key` or as a Bearer token, or an empty string.
+fn api_key_from(headers: &HeaderMap) -> &str {
+    headers
+        .get("x-api-key")
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| {
+            headers
+                .get("authorization")
+                .and_then(|v| v.to_str().ok().and_then(|s| s.strip_prefix("Bearer ")))
+        })
+        .unwrap_or_default()
+}
+
+/// The target's circuit breaker is open for another `left`, or probing when `left` is zero.
+fn circuit_open(
+    state: &ApplicationState,
+    pattern: &str,
+    left: Duration,
+    retry_after: &config::RetryAfterConfig,
+) -> GatewayError {
+    state.telemetry.increment(
+        "circuit_breaker_rejections_total",
+        vec![("target", pattern.to_string())],
+    );
+    let hints = RetryAfterHints {
+        breaker: Some(left),
+        ..Default::default()
+    };
+    GatewayError::new(
+        ErrorPhase::Invoke,
+        StatusCode::SERVICE_UNAVAILABLE,
+        "circuit_open",
+        "The function is failing; requests are paused until it recovers",
+    )
+    .with_retry_after(hints.resolve(retry_after.default_secs, retry_after))
+}
+
+/// The function did not answer within the target's `invoke_timeout_ms`.
+fn invoke_timeout(ms: u64) -> GatewayError {
+    GatewayError::new(
+        ErrorPhase::Invoke,
+        StatusCode::GATEWAY_TIMEOUT,
+        "invoke_timeout",
+        format!("No response from the function within {} ms", ms),
+    )
+}
+
+fn no_route() -> GatewayError {
+    GatewayError::new(
+        ErrorPhase::Ingress,
+        StatusCode::NOT_FOUND,
+        "no_route",
+        "No target serves this path",
+    )
+}
 
-    resp_builder.body(Body::from_stream(stream)).unwrap()
+fn method_not_allowed(method: &Method) -> GatewayError {
+    GatewayError::new(
+        ErrorPhase::Ingress,
+        StatusCode::METHOD_NOT_ALLOWED,
+        "method_not_allowed",
+        format!("This path does not accept {}", method),
+    )
 }
 
-async fn detect_metadata(
-    resp: &mut aws_sdk_lambda::operation::invoke_with_response_stream::InvokeWithResponseStreamOutput,
-) -> (bool, Option<Vec<u8>>) {
-    if let Ok(Some(event)) = resp.event_stream.recv().await {
-        if let PayloadChunk(chunk) = event {
-            if let Some(data) = chunk.payload() {
-                let bytes = data.clone().into_inner();
-                let has_metadata = !bytes.is_empty() && bytes[0] == b'{';
-                return (has_metadata, Some(bytes));
+fn invalid_qualifier(qualifier: &str) -> GatewayError {
+    GatewayError::new(
+        ErrorPhase::Ingress,
+        StatusCode::BAD_REQUEST,
+        "invalid_qualifier",
+        format!(
+            "{} {:?} is not a valid alias or version",
+            request::QUALIFIER_HEADER,
+            qualifier
+        ),
+    )
+}
+
+fn shutting_down() -> GatewayError {
+    GatewayError::new(
+        ErrorPhase::Ingress,
+        StatusCode::SERVICE_UNAVAILABLE,
+        "shutting_down",
+        "The gateway is shutting down",
+    )
+}
+
+/// Answers a request whose API key was refused, counting the failure against its address when
+/// `auth_bans` is configured.
+async fn failed_authentication(state: &ApplicationState, config: &Config, client_ip: Option<IpAddr>) -> GatewayError {
+    if let (Some(bans), Some(ip)) = (&config.auth_bans, client_ip) {
+        state.auth_bans.record_failure(bans, ip, state.clock.now()).await;
+    }
+    unauthorized()
+}
+
+fn unauthorized() -> GatewayError {
+    GatewayError::new(
+        ErrorPhase::Auth,
+        StatusCode::UNAUTHORIZED,
+        "unauthorized",
+        "Missing or invalid API key",
+    )
+}
+
+/// Function that served a request, attached to the response extensions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Upstream(pub String);
+
+/// Request ID Lambda assigned to the invoke that served a request (`x-amzn-RequestId`), attached
+/// to the response extensions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LambdaRequestId(pub String);
+
+/// Marks a response whose body is streamed from the function as it arrives.
+#[derive(Clone, Copy, Debug)]
+pub struct StreamedResponse;
+
+/// Function error reported by Lambda, such as `Unhandled`, attached to the response extensions.
+#[derive(Clone, Debug)]
+pub struct FunctionError(pub String);
+
+/// Answers a request whose function failed with the error of `function_error_status`, logging the
+/// error object the function returned in `payload`.
+fn function_failed(
+    state: &ApplicationState,
+    request_context: &RequestContext,
+    function_name: &str,
+    kind: String,
+    payload: &[u8],
+) -> Response {
+    let error = function_errors::LambdaError::parse(payload);
+    if state.log_dedup.should_log(&request_context.pattern, "function_error") {
+        tracing::error!(
+            function = function_name,
+            kind = %kind,
+            error_type = %error.error_type,
+            error_message = %error.error_message,
+            "Function failed"
+        );
+    }
+    tracing::debug!(stack_trace = %error.stack_trace, "Stack trace of the failed function");
+    let mut resp = function_errors::gateway_error(&state.config(), &error).into_response();
+    resp.extensions_mut().insert(FunctionError(kind));
+    resp
+}
+
+/// Answers a request whose function responded with more than a buffered invoke carries, which only
+/// a response stream can, counting it in `response_payload_overflows_total` by target.
+fn response_too_large(
+    state: &ApplicationState,
+    request_context: &RequestContext,
+    function_name: &str,
+    kind: Option<String>,
+) -> Response {
+    state.telemetry.increment(
+        "response_payload_overflows_total",
+        vec![("target", request_context.pattern.clone())],
+    );
+    if state
+        .log_dedup
+        .should_log(&request_context.pattern, "response_payload_overflow")
+    {
+        tracing::error!(
+            function = function_name,
+            target = %request_context.pattern,
+            "Function response exceeded the Lambda payload limit of {} bytes; \
+             set `invoke: ResponseStream` on the target to stream it instead",
+            request::MAX_INVOKE_PAYLOAD_BYTES
+        );
+    }
+    let mut resp = function_errors::response_too_large_error().into_response();
+    if let Some(kind) = kind {
+        resp.extensions_mut().insert(FunctionError(kind));
+    }
+    resp
+}
+
+/// Invokes the target's function with an already-built payload and converts the result into the
+/// response sent to the client. Shared by the server and the `check` command.
+pub(crate) async fn invoke_target(
+    state: &ApplicationState,
+    target: &Target,
+    lambda_request_body: String,
+    request_context: &RequestContext,
+) -> Result<Response, GatewayError> {
+    let config = state.config();
+    let client_context = request_context.client_context.clone().or_else(|| {
+        config.client_context.then(|| {
+            let instance_id = config.instance_id.clone().unwrap_or_else(default_instance_id);
+            request::build_client_context(&instance_id, &request_context.request_id)
+        })
+    });
+    let (function_name, in_flight) = state
+        .balancer
+        .acquire(config.function_members(target), target.strategy, |_| true)
+        .ok_or_else(|| {
+            GatewayError::new(
+                ErrorPhase::Invoke,
+                StatusCode::SERVICE_UNAVAILABLE,
+                "no_available_function",
+                "No function of the target is available",
+            )
+        })?;
+    // The header only counts on targets that allow it; elsewhere it is ignored
+    let qualifier = match request_context.qualifier.as_deref() {
+        Some(qualifier) if target.qualifier_on_request => {
+            if !config::valid_qualifier(qualifier) {
+                return Err(invalid_qualifier(qualifier));
             }
+            Some(qualifier.to_string())
         }
+        _ => target.qualifier.clone(),
+    };
+    tracing::debug!(function = function_name, ?qualifier, "Selected upstream function");
+    let request = InvokeRequest {
+        function_name: function_name.to_string(),
+        qualifier,
+        payload: lambda_request_body,
+        client_context,
+        trace_header: request_context.trace_header.clone(),
+    };
+    if request_context.dry_run && target.dry_run_on_request {
+        return dry_run(state, request).await;
     }
-    (false, None)
-}
+    state
+        .telemetry
+        .increment("upstream_requests_total", vec![("function", function_name.to_string())]);
+    // Runs alongside the invoke; nothing the client gets waits on it
+    let mut shadow = target
+        .shadow
+        .as_ref()
+        .filter(|shadow| sampled(shadow.sample_percent))
+        .map(|shadow| start_shadow(state, shadow, &request, &request_context.pattern));
 
-async fn collect_metadata(
-    resp: &mut aws_sdk_lambda::operation::invoke_with_response_stream::InvokeWithResponseStreamOutput,
-    metadata_buffer: &mut Vec<u8>,
-) -> (Option<MetadataPrelude>, Vec<u8>) {
-    let mut metadata_prelude = None;
-    let mut remaining_data = Vec::new();
+    // As with the qualifier, the header only counts on targets that allow it
+    let invoke_mode = match &request_context.invoke_mode {
+        Some(mode) if target.allow_invoke_mode_override => mode.clone(),
+        _ => config.invoke_mode(target),
+    };
+    let invoke_span = tracing::debug_span!(
+        "invoke",
+        otel.name = %format!("invoke {}", function_name),
+        otel.kind = "client",
+        faas.invoked_name = function_name,
+        aws.lambda.invoke_mode = match invoke_mode {
+            LambdaInvokeMode::Buffered => "buffered",
+            LambdaInvokeMode::ResponseStream => "response_stream",
+            LambdaInvokeMode::Event => "event",
+        },
+    );
+    let mut response_mode = "buffered";
+    let mut lambda_request_id = None;
+    let mut resp = match invoke_mode {
+        LambdaInvokeMode::Buffered => {
+            let invoke = retry_transient(state, target, request_context, &request.function_name, || {
+                state.invoker.invoke(request.clone())
+            });
+            let invoke = invoke.instrument(invoke_span);
+            let output = match target.invoke_timeout_ms {
+                Some(ms) => tokio::time::timeout(Duration::from_millis(ms), invoke)
+                    .await
+                    .map_err(|_| invoke_timeout(ms))?,
+                None => invoke.await,
+            }
+            .map_err(|e| invoke_error(state, e))?;
+            lambda_request_id = output.request_id.clone();
+            if let Some(shadow) = shadow.take() {
+                compare_shadow(state, request_context, target, shadow, output.clone());
+            }
+            // A failed function returns an error object rather than an HTTP response
+            if function_errors::response_too_large(&output) {
+                response_too_large(state, request_context, function_name, output.function_error)
+            } else if let Some(kind) = output.function_error {
+                function_failed(state, request_context, function_name, kind, &output.payload)
+            } else {
+                buffered_response(&config, target, &output).await?
+            }
+        }
+        LambdaInvokeMode::ResponseStream => {
+            response_mode = "stream";
+            let dispatched_at = Instant::now();
+            let dispatch = async {
+                let upstream_time = &request_context.upstream_time;
+                // Nothing has reached the client before the stream is open, so it can be retried
+                let StreamOutput {
+                    mut payload,
+                    request_id,
+                } = retry_transient(state, target, request_context, &request.function_name, || {
+                    state.invoker.invoke_stream(request.clone())
+                })
+                .await
+                .map_err(|e| invoke_error(state, e))?;
+                // The first event counts towards the invoke budget; put it back in front of the rest
+                let first = {
+                    let _timer = upstream_time.start();
+                    payload.next().await
+                };
+                tracing::debug!(target: SPAN_EVENTS, "first_streamed_byte");
+                Ok::<_, GatewayError>((first, payload, request_id))
+            }
+            .instrument(invoke_span.clone());
+            let (first, payload, request_id) = match target.invoke_timeout_ms {
+                Some(ms) => tokio::time::timeout(Duration::from_millis(ms), dispatch)
+                    .await
+                    .map_err(|_| invoke_timeout(ms))??,
+                None => dispatch.await?,
+            };
+            lambda_request_id = request_id;
+            // The invoke span ends with the stream
+            let complete = futures::stream::poll_fn(move |_| {
+                tracing::debug!(target: SPAN_EVENTS, parent: &invoke_span, "stream_complete");
+                std::task::Poll::Ready(None)
+            });
+            let payload = futures::stream::iter(first).chain(payload).chain(complete).boxed();
+            // The request stays in flight until the stream ends
+            let payload = payload
+                .inspect(move |_| {
+                    let _ = &in_flight;
+                })
+                .boxed();
+            let pattern = &request_context.pattern;
+            let read_as = state.stream_formats.effective(pattern, target);
+            let started =
+                handle_streaming_response(payload, &read_as, &state.telemetry, request_context, dispatched_at).await;
+            match started {
+                Ok(resp) => {
+                    if let Some(DetectedStreamFormat(detected)) = resp.extensions().get().copied() {
+                        state.stream_formats.report(pattern, target, detected);
+                    }
+                    if target.buffer_stream || (target.buffer_stream_on_request && request_context.buffer_stream) {
+                        response_mode = "buffered_stream";
+                        let _timer = request_context.upstream_time.start();
+                        streaming::buffer_response(resp, target.max_response_body_bytes).await?
+                    } else {
+                        resp
+                    }
+                }
+                // A function failing before its prelude was complete is answered like a failed
+                // buffered invoke
+                Err(StreamStartError::Function { kind, details }) => {
+                    function_failed(state, request_context, function_name, kind, details.as_bytes())
+                }
+                Err(StreamStartError::Gateway(e)) => return Err(e),
+            }
+        }
+        LambdaInvokeMode::Event => {
+            response_mode = "event";
+            // Throttles and conflicts join the retry queue, which answers without a request ID
+            let request_id = {
+                let _timer = request_context.upstream_time.start();
+                state.event_queue.submit(request.clone()).instrument(invoke_span).await
+            }
+            .map_err(|e| invoke_error(state, e))?;
+            let mut resp = StatusCode::ACCEPTED.into_response();
+            let header = HeaderName::try_from(config.request_id.header.as_str()).ok();
+            if let Some((name, value)) = header.zip(request_id.and_then(|id| HeaderValue::from_str(&id).ok())) {
+                resp.headers_mut().insert(name, value);
+            }
+            resp
+        }
+    };
+    if shadow.is_some() && target.shadow.as_ref().is_some_and(|shadow| shadow.compare) {
+        state.telemetry.increment(
+            "shadow_comparisons_total",
+            vec![
+                ("target", request_context.pattern.clone()),
+                ("result", "skipped".to_string()),
+            ],
+        );
+    }
+    state
+        .telemetry
+        .increment("responses_by_mode_total", vec![("mode", response_mode.to_string())]);
+    if response_mode == "stream" {
+        resp.extensions_mut().insert(StreamedResponse);
+    }
+    if let Some(request_id) = lambda_request_id {
+        resp.extensions_mut().insert(LambdaRequestId(request_id));
+    }
 
-    // Process the metadata_buffer first
-    let (prelude, remaining) = process_buffer(metadata_buffer);
-    if let Some(p) = prelude {
-        return (Some(p), remaining);
+    headers::sanitize_response_headers(resp.headers_mut(), target);
+    if let Some(rewrite) = &target.cookie_rewrite {
+        cookies::rewrite_set_cookie_headers(resp.headers_mut(), rewrite);
     }
+    let debug_headers = config.debug_headers || flags::is_allowed(flags::DEBUG_HEADERS, &request_context.flags);
+    if debug_headers && !request_context.hide_debug_headers {
+        if let Ok(value) = HeaderValue::from_str(function_name) {
+            resp.headers_mut().insert("x-gateway-upstream", value);
+        }
+        if let Some(qualifier) = &request.qualifier {
+            if let Ok(value) = HeaderValue::from_str(qualifier) {
+                resp.headers_mut().insert("x-gateway-qualifier", value);
+            }
+        }
+        if let Ok(value) = HeaderValue::from_str(&state.config_rev()) {
+            resp.headers_mut().insert("x-gateway-config-rev", value);
+        }
+    }
+    // Added after sanitizing, so `allow_response_headers` cannot strip it
+    if let Some(spool::DownloadToken(token)) = resp.extensions().get() {
+        if let Ok(value) = HeaderValue::from_str(token) {
+            resp.headers_mut().insert(spool::DOWNLOAD_TOKEN_HEADER, value);
+        }
+    }
+    resp.extensions_mut().insert(Upstream(function_name.to_string()));
+    Ok(resp)
+}
+
+/// Draws whether a request is among the `percent` of requests sampled.
+fn sampled(percent: f64) -> bool {
+    if percent >= 100.0 {
+        return true;
+    }
+    let mut bytes = [0u8; 8];
+    if getrandom::getrandom(&mut bytes).is_err() {
+        return false;
+    }
+    let fraction = (u64::from_le_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64;
+    fraction * 100.0 < percent
+}
 
-    // If metadata is not complete, continue processing the stream
-    while let Ok(Some(event)) = resp.event_stream.recv().await {
-        if let PayloadChunk(chunk) = event {
-            if let Some(data) = chunk.payload() {
-                let bytes = data.clone().into_inner();
-                metadata_buffer.extend_from_slice(&bytes);
-                let (prelude, remaining) = process_buffer(metadata_buffer);
-                if let Some(p) = prelude {
-                    metadata_prelude = Some(p);
-                    remaining_data = remaining;
-                    break;
+/// Invokes the shadow function with a copy of `request` in the background. The shadow is always
+/// invoked buffered, whatever the target's invoke mode.
+fn start_shadow(
+    state: &ApplicationState,
+    shadow: &ShadowConfig,
+    request: &InvokeRequest,
+    pattern: &str,
+) -> tokio::task::JoinHandle<Result<BufferedOutput, InvokeError>> {
+    state
+        .telemetry
+        .increment("shadow_requests_total", vec![("target", pattern.to_string())]);
+    let request = InvokeRequest {
+        function_name: shadow.function.clone(),
+        qualifier: shadow.qualifier.clone(),
+        ..request.clone()
+    };
+    tokio::spawn(state.invoker.invoke(request))
+}
+
+/// Waits in the background, up to the shadow's `timeout_ms`, for the shadow started alongside a
+/// buffered invoke, then compares its response with the one `primary` gave the client. Counted
+/// in `shadow_comparisons_total` by result; mismatches are kept for
+/// `GET /admin/shadow-mismatches`.
+fn compare_shadow(
+    state: &ApplicationState,
+    request_context: &RequestContext,
+    target: &Target,
+    mut shadow: tokio::task::JoinHandle<Result<BufferedOutput, InvokeError>>,
+    primary: BufferedOutput,
+) {
+    let Some(settings) = target.shadow.clone().filter(|shadow| shadow.compare) else {
+        return;
+    };
+    let (state, config, target) = (state.clone(), state.config(), target.clone());
+    let (pattern, method, request_id) = (
+        request_context.pattern.clone(),
+        request_context.method.to_string(),
+        request_context.request_id.clone(),
+    );
+    tokio::spawn(async move {
+        let timeout = Duration::from_millis(settings.timeout_ms);
+        let result = match tokio::time::timeout(timeout, &mut shadow).await {
+            Err(_) => {
+                shadow.abort();
+                "timeout"
+            }
+            Ok(Err(_)) | Ok(Ok(Err(_))) => "error",
+            Ok(Ok(Ok(output))) => {
+                let primary = observe(&config, &target, primary).await;
+                let candidate = observe(&config, &target, output).await;
+                let differences = response_diff::compare(&primary, &candidate, &settings);
+                if differences.is_empty() {
+                    "match"
+                } else {
+                    state.shadow_mismatches.push(response_diff::ShadowMismatch {
+                        timestamp_ms: access_log::now_ms(),
+                        target: pattern.clone(),
+                        method,
+                        request_id,
+                        function: settings.function.clone(),
+                        differences,
+                    });
+                    "mismatch"
                 }
             }
+        };
+        state.telemetry.increment(
+            "shadow_comparisons_total",
+            vec![("target", pattern), ("result", result.to_string())],
+        );
+    });
+}
+
+/// The response a buffered invoke's `output` makes, as compared with its shadow's. A function
+/// error counts as a `502` with the error object as its body.
+async fn observe(config: &Config, target: &Target, output: BufferedOutput) -> response_diff::Observed {
+    if output.function_error.is_some() {
+        return response_diff::Observed {
+            status: StatusCode::BAD_GATEWAY.as_u16(),
+            headers: HeaderMap::new(),
+            body: output.payload,
+        };
+    }
+    match buffered_response(config, target, &output).await {
+        Ok(resp) => {
+            let (parts, body) = resp.into_parts();
+            response_diff::Observed {
+                status: parts.status.as_u16(),
+                headers: parts.headers,
+                body: axum::body::to_bytes(body, usize::MAX).await.unwrap_or_default(),
+            }
         }
+        Err(e) => response_diff::Observed {
+            status: e.status.as_u16(),
+            headers: HeaderMap::new(),
+            body: Bytes::new(),
+        },
     }
-    (metadata_prelude, remaining_data)
 }
 
-fn process_buffer(buffer: &[u8]) -> (Option<MetadataPrelude>, Vec<u8>) {
-    let mut null_count = 0;
-    for (i, &byte) in buffer.iter().enumerate() {
-        if byte == 0 {
-            null_count += 1;
-            if null_count == 8 {
-                let metadata_str = String::from_utf8_lossy(&buffer[..i]);
-                let metadata_prelude = serde_json::from_str(&metadata_str).unwrap_or_default();
-                tracing::debug!(metadata_prelude=?metadata_prelude);
-                // Save remaining data after metadata
-                let remaining_data = buffer[i + 1..].to_vec();
-                return (Some(metadata_prelude), remaining_data);
+/// Has Lambda check that the gateway may invoke `request`, answering `204` when it may. A missing
+/// function or qualifier is answered `502` and a denied permission `403`, with error codes
+/// `function_not_found` and `access_denied`.
+async fn dry_run(state: &ApplicationState, request: InvokeRequest) -> Result<Response, GatewayError> {
+    let function_name = request.function_name.clone();
+    match state.invoker.invoke_dry_run(request).await {
+        Ok(()) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Err(InvokeError::NotFound(e)) => Err(GatewayError::new(
+            ErrorPhase::Invoke,
+            StatusCode::BAD_GATEWAY,
+            "function_not_found",
+            format!("Function {} was not found: {}", function_name, e),
+        )),
+        Err(InvokeError::AccessDenied(e)) => Err(GatewayError::new(
+            ErrorPhase::Invoke,
+            StatusCode::FORBIDDEN,
+            "access_denied",
+            format!("The gateway may not invoke {}: {}", function_name, e),
+        )),
+        Err(e) => Err(invoke_error(state, e)),
+    }
+}
+
+fn invoke_error(state: &ApplicationState, e: InvokeError) -> GatewayError {
+    let config = state.config();
+    match e {
+        InvokeError::Conflict(e) => GatewayError::new(
+            ErrorPhase::Invoke,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "function_updating",
+            format!("The function is being updated, retry shortly: {}", e),
+        )
+        .with_retry_after(
+            RetryAfterHints::default().resolve(config.conflict_retry.retry_after_secs, &config.retry_after),
+        ),
+        InvokeError::Throttled(e, hint) => GatewayError::new(
+            ErrorPhase::Invoke,
+            StatusCode::TOO_MANY_REQUESTS,
+            "function_throttled",
+            format!("The function is throttled: {}", e),
+        )
+        .with_retry_after(
+            RetryAfterHints::upstream(hint).resolve(config.retry_after.default_secs, &config.retry_after),
+        ),
+        // Only dry runs tell the client which of these it was, see `dry_run`
+        InvokeError::NotFound(e) | InvokeError::AccessDenied(e) => GatewayError::new(
+            ErrorPhase::Invoke,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "invoke_failed",
+            e,
+        ),
+        // Failures of Lambda itself or of reaching it
+        InvokeError::Service(e) | InvokeError::Other(e) => {
+            GatewayError::new(ErrorPhase::Invoke, StatusCode::BAD_GATEWAY, "invoke_failed", e)
+        }
+    }
+}
+
+/// Retries invokes rejected while the function is updating, with exponential backoff. Every
+/// conflict is counted in `invoke_conflicts_total` so deploy blips stand apart from real failures.
+/// Each attempt counts towards `upstream_time`; the backoff between attempts does not.
+async fn retry_conflicts<T, F>(
+    state: &ApplicationState,
+    function_name: &str,
+    upstream_time: &UpstreamTime,
+    mut invoke: impl FnMut() -> F,
+) -> Result<T, InvokeError>
+where
+    F: std::future::Future<Output = Result<T, InvokeError>>,
+{
+    let config = state.config();
+    let retry = &config.conflict_retry;
+    let mut backoff = Duration::from_millis(retry.backoff_ms);
+    let mut attempt = 0;
+    loop {
+        let result = {
+            let _timer = upstream_time.start();
+            invoke().await
+        };
+        match result {
+            Err(InvokeError::Conflict(e)) => {
+                let outcome = if attempt < retry.retries {
+                    "retried"
+                } else {
+                    "exhausted"
+                };
+                state.telemetry.increment(
+                    "invoke_conflicts_total",
+                    vec![
+                        ("function", function_name.to_string()),
+                        ("outcome", outcome.to_string()),
+                    ],
+                );
+                if attempt >= retry.retries {
+                    return Err(InvokeError::Conflict(e));
+                }
+                tracing::info!("{} is not ready, retrying in {:?}: {}", function_name, backoff, e);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
             }
-        } else {
-            null_count = 0;
+            result => return result,
+        }
+    }
+}
+
+/// Retries invokes that failed in a way the target's `retries` allow, with jittered exponential
+/// backoff, around [`retry_conflicts`]. Requests with a method that is not idempotent are retried
+/// only with `retry_non_idempotent`. Every retryable failure is counted in `invoke_retries_total`.
+async fn retry_transient<T, F>(
+    state: &ApplicationState,
+    target: &Target,
+    request_context: &RequestContext,
+    function_name: &str,
+    mut invoke: impl FnMut() -> F,
+) -> Result<T, InvokeError>
+where
+    F: std::future::Future<Output = Result<T, InvokeError>>,
+{
+    let upstream_time = &request_context.upstream_time;
+    let Some(config) = target
+        .retries
+        .as_ref()
+        .filter(|config| retry::allows(config, &request_context.method))
+    else {
+        return retry_conflicts(state, function_name, upstream_time, invoke).await;
+    };
+    let mut attempt = 1;
+    loop {
+        let result = retry_conflicts(state, function_name, upstream_time, &mut invoke).await;
+        let reason = result.as_ref().err().and_then(retry::reason);
+        let Some(reason) = reason.filter(|reason| config.retry_on.contains(reason)) else {
+            return result;
+        };
+        let exhausted = attempt >= config.max_attempts;
+        state.telemetry.increment(
+            "invoke_retries_total",
+            vec![
+                ("function", function_name.to_string()),
+                ("reason", reason.as_str().to_string()),
+                ("outcome", if exhausted { "exhausted" } else { "retried" }.to_string()),
+            ],
+        );
+        if exhausted {
+            return result;
+        }
+        let delay = retry::backoff(config, attempt);
+        tracing::info!(
+            "Invoke of {} failed ({}), attempt {} of {}, retrying in {:?}",
+            function_name,
+            reason.as_str(),
+            attempt,
+            config.max_attempts,
+            delay
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// The `ClientContext` carrying a `payload: raw` request, with the configured or default instance ID.
+pub(crate) fn raw_client_context(
+    config: &Config,
+    request: &request::EventRequest,
+    headers: &HeaderMap,
+) -> Result<String, GatewayError> {
+    let instance_id = config.instance_id.clone().unwrap_or_else(default_instance_id);
+    request::build_raw_client_context(&instance_id, request, headers)
+}
+
+fn default_instance_id() -> String {
+    std::env::var("HOSTNAME").unwrap_or_default()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct LambdaResponse {
+    status_code: u16,
+    status_description: Option<String>,
+    is_base64_encoded: Option<bool>,
+    headers: Option<HashMap<String, String>>,
+    /// Every value of each header, in order; set by functions answering in multi-value mode.
+    multi_value_headers: Option<HashMap<String, Vec<String>>>,
+    body: String,
+}
+
+/// A buffered response in the API Gateway v1 shape, where every field is optional.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct LambdaResponseV1 {
+    status_code: Option<i64>,
+    is_base64_encoded: Option<bool>,
+    headers: Option<HashMap<String, String>>,
+    multi_value_headers: Option<HashMap<String, Vec<String>>>,
+    body: Option<String>,
+}
+
+/// A buffered response in the API Gateway v2 shape, where every field is optional.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct LambdaResponseV2 {
+    status_code: Option<u16>,
+    is_base64_encoded: Option<bool>,
+    headers: Option<HashMap<String, String>>,
+    /// `Set-Cookie` values, one per cookie.
+    cookies: Option<Vec<String>>,
+    body: Option<String>,
+}
+
+/// Reads the HTTP response in the output of a buffered invoke that did not fail, in the target's
+/// payload format.
+async fn buffered_response(
+    config: &Config,
+    target: &Target,
+    output: &BufferedOutput,
+) -> Result<Response, GatewayError> {
+    let strict = target.strict_upstream_headers;
+    match config.payload_mode(target) {
+        PayloadMode::Alb => handle_buffered_response(&output.payload, strict).await,
+        PayloadMode::ApiGatewayV1 => handle_buffered_v1_response(&output.payload, strict).await,
+        PayloadMode::ApiGatewayV2 | PayloadMode::FunctionUrl => {
+            handle_buffered_v2_response(&output.payload, strict).await
         }
+        PayloadMode::Raw => handle_buffered_raw_response(output, target),
     }
-    (None, Vec::new())
+}
+
+async fn handle_buffered_response(payload: &[u8], strict_headers: bool) -> Result<Response, GatewayError> {
+    // Parse the invoke payload to extract the LambdaResponse
+    let lambda_response: LambdaResponse = serde_json::from_slice(payload)
+        .map_err(|e| upstream_error(format!("Function response is not a valid HTTP response: {}", e)))?;
+    build_buffered_response(lambda_response, strict_headers)
+}
+
+/// Reads a buffered response of an API Gateway v1 target. A missing `statusCode` means 200.
+async fn handle_buffered_v1_response(payload: &[u8], strict_headers: bool) -> Result<Response, GatewayError> {
+    let v1: LambdaResponseV1 = serde_json::from_slice(payload)
+        .map_err(|e| upstream_error(format!("Function response is not a valid HTTP response: {}", e)))?;
+    let status_code = v1.status_code.unwrap_or(200);
+    let lambda_response = LambdaResponse {
+        status_code: u16::try_from(status_code)
+            .map_err(|_| upstream_error(format!("Invalid status code {}", status_code)))?,
+        status_description: None,
+        is_base64_encoded: v1.is_base64_encoded,
+        headers: v1.headers,
+        multi_value_headers: v1.multi_value_headers,
+        body: v1.body.unwrap_or_default(),
+    };
+    build_buffered_response(lambda_response, strict_headers)
+}
+
+/// Answers a buffered invoke of a `payload: raw` target with its payload as a 200 body.
+fn handle_buffered_raw_response(output: &BufferedOutput, target: &Target) -> Result<Response, GatewayError> {
+    let content_type = target.raw_content_type.as_deref().unwrap_or("application/json");
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", content_type)
+        .body(Body::from(output.payload.clone()))
+        .map_err(|e| upstream_error(format!("Invalid raw_content_type: {}", e)))
+}
+
+/// Reads a buffered response of an API Gateway v2 target. As with API Gateway, valid JSON without a
+/// `statusCode` is answered with 200 and itself as an `application/json` body.
+async fn handle_buffered_v2_response(payload: &[u8], strict_headers: bool) -> Result<Response, GatewayError> {
+    let value: serde_json::Value = serde_json::from_slice(payload)
+        .map_err(|e| upstream_error(format!("Function response is not valid JSON: {}", e)))?;
+    if value.get("statusCode").is_none() {
+        let lambda_response = LambdaResponse {
+            status_code: 200,
+            status_description: None,
+            is_base64_encoded: None,
+            headers: Some(HashMap::from([(
+                "content-type".to_string(),
+                "application/json".to_string(),
+            )])),
+            multi_value_headers: None,
+            body: String::from_utf8_lossy(payload).into_owned(),
+        };
+        return build_buffered_response(lambda_response, strict_headers);
+    }
+    let v2: LambdaResponseV2 = serde_json::from_value(value)
+        .map_err(|e| upstream_error(format!("Function response is not a valid HTTP response: {}", e)))?;
+    let mut headers = v2.headers.unwrap_or_default();
+    // Cookies are sent as one `Set-Cookie` each, after any the function put in `headers`
+    let mut multi_value_headers = HashMap::new();
+    if let Some(cookies) = v2.cookies.filter(|cookies| !cookies.is_empty()) {
+        let name = headers
+            .keys()
+            .find(|name| name.eq_ignore_ascii_case("set-cookie"))
+            .cloned();
+        let set_cookie = name
+            .and_then(|name| headers.remove(&name))
+            .into_iter()
+            .chain(cookies)
+            .collect();
+        multi_value_headers.insert("set-cookie".to_string(), set_cookie);
+    }
+    let lambda_response = LambdaResponse {
+        status_code: v2.status_code.unwrap_or(200),
+        status_description: None,
+        is_base64_encoded: v2.is_base64_encoded,
+        headers: Some(headers),
+        multi_value_headers: Some(multi_value_headers),
+        body: v2.body.unwrap_or_default(),
+    };
+    build_buffered_response(lambda_response, strict_headers)
+}
+
+fn upstream_error(message: String) -> GatewayError {
+    GatewayError::new(
+        ErrorPhase::Upstream,
+        StatusCode::BAD_GATEWAY,
+        "invalid_upstream_response",
+        message,
+    )
+}
+
+fn build_buffered_response(lambda_response: LambdaResponse, strict_headers: bool) -> Result<Response, GatewayError> {
+    let status = StatusCode::from_u16(lambda_response.status_code)
+        .map_err(|_| upstream_error(format!("Invalid status code {}", lambda_response.status_code)))?;
+
+    // Build the response using the extracted information
+    let mut resp_builder = Response::builder().status(status);
+
+    // A header listed in both maps is taken from `multiValueHeaders`, which keeps every value in
+    // the order the function gave them
+    let multi_value_headers = lambda_response.multi_value_headers.unwrap_or_default();
+    let single_value_headers = lambda_response
+        .headers
+        .iter()
+        .flatten()
+        .filter(|(name, _)| !multi_value_headers.keys().any(|multi| multi.eq_ignore_ascii_case(name)))
+        .map(|(name, value)| (name.as_str(), value.as_str()));
+    let headers: UpstreamHeaders = multi_value_headers
+        .iter()
+        .flat_map(|(name, values)| values.iter().map(move |value| (name.as_str(), value.as_str())))
+        .chain(single_value_headers)
+        .collect();
+    for (name, value) in headers.checked(strict_headers)?.iter() {
+        resp_builder = resp_builder.header(name, value);
+    }
+
+    let body = if lambda_response.is_base64_encoded.unwrap_or(false) {
+        base64::engine::general_purpose::STANDARD
+            .decode(lambda_response.body)
+            .map_err(|e| upstream_error(format!("Invalid base64 body: {}", e)))?
+    } else {
+        lambda_response.body.into_bytes()
+    };
+    resp_builder.body(Body::from(body)).map_err(|e| {
+        GatewayError::new(
+            ErrorPhase::Egress,
+            StatusCode::BAD_GATEWAY,
+            "invalid_response",
+            format!("Failed to build the client response: {}", e),
+        )
+    })
 }
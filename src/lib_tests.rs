@@ -1,31 +1,15 @@
 use super::*;
-// use axum::http::StatusCode;
-// use aws_smithy_types::Blob;
-// use std::collections::HashMap;
-// use aws_sdk_lambda::types::InvokeWithResponseStreamResponseEvent;
-// use aws_sdk_lambda::operation::invoke_with_response_stream::InvokeWithResponseStreamOutput;
-// use aws_sdk_lambda::primitives::event_stream::EventReceiver;
-// use aws_sdk_lambda::operation::invoke_with_response_stream::InvokeWithResponseStreamError;
+use crate::invoker::BufferedOutput;
+use crate::mock::{ok_output, test_state_with, MockInvoker};
+use std::collections::HashSet;
 
 #[tokio::test]
 async fn test_health() {
-    let response = health().await.into_response();
+    let state = test_state_with(Config::default(), MockInvoker::new(vec![]));
+    let response = health(State(state)).await.into_response();
     assert_eq!(response.status(), StatusCode::OK);
 }
 
-#[tokio::test]
-async fn test_to_string_map() {
-    let mut headers = HeaderMap::new();
-    headers.insert("Content-Type", "application/json".parse().unwrap());
-    headers.insert("X-Custom-Header", "test-value".parse().unwrap());
-
-    let result = to_string_map(&headers);
-
-    assert_eq!(result.len(), 2);
-    assert_eq!(result.get("content-type"), Some(&"application/json".to_string()));
-    assert_eq!(result.get("x-custom-header"), Some(&"test-value".to_string()));
-}
-
 #[tokio::test]
 async fn test_handle_buffered_response() {
     let lambda_response = LambdaResponse {
@@ -35,16 +19,13 @@ async fn test_handle_buffered_response() {
         headers: Some(HashMap::from([
             ("Content-Type".to_string(), "text/plain".to_string()),
         ])),
+        multi_value_headers: None,
         body: "Hello, World!".to_string(),
     };
 
     let payload = serde_json::to_vec(&lambda_response).unwrap();
-    let invoke_output = aws_sdk_lambda::operation::invoke::InvokeOutput::builder()
-        .payload(Blob::new(payload))
-        .status_code(200)
-        .build();
 
-    let response = handle_buffered_response(invoke_output).await;
+    let response = handle_buffered_response(&payload, false).await.unwrap();
 
     assert_eq!(response.status(), StatusCode::OK);
     assert_eq!(
@@ -55,84 +36,3613 @@ async fn test_handle_buffered_response() {
     assert_eq!(body, "Hello, World!");
 }
 
-// #[tokio::test]
-// async fn test_detect_metadata() {
-//     let payload = r#"{"statusCode": 200, "headers": {"Content-Type": "text/plain"}, "body": "Hello"}"#;
-//     let full_payload = payload.as_bytes().to_vec();
-//     let chunk = InvokeWithResponseStreamResponseEvent::PayloadChunk(
-//         aws_sdk_lambda::types::InvokeResponseStreamUpdate::builder()
-//             .payload(Blob::new(full_payload.clone()))
-//             .build(),
-//     );
-
-//     let event_receiver = EventReceiver {
-//         inner: vec![chunk],
-//     };
-
-//     let mut resp = InvokeWithResponseStreamOutput::builder()
-//         .event_stream(event_receiver)
-//         .build()
-//         .unwrap();
-
-//     // Type annotation to help the compiler
-//     let resp: InvokeWithResponseStreamOutput = resp;
-
-//     let (has_metadata, first_chunk) = detect_metadata(&mut resp).await;
-
-//     assert!(has_metadata);
-//     assert_eq!(first_chunk.unwrap(), full_payload);
-// }
-
-// #[tokio::test]
-// async fn test_collect_metadata() {
-//     let payload = r#"{"statusCode": 200, "headers": {"Content-Type": "text/plain"}, "body": "Hello"}"#;
-//     let null_padding = vec![0u8; 8];
-//     let remaining_data = b"Remaining data";
-
-//     let mut full_payload = payload.as_bytes().to_vec();
-//     full_payload.extend_from_slice(&null_padding);
-//     full_payload.extend_from_slice(remaining_data);
-
-//     let chunk = InvokeWithResponseStreamResponseEvent::PayloadChunk(
-//         aws_sdk_lambda::types::InvokeResponseStreamUpdate::builder()
-//             .payload(Blob::new(full_payload))
-//             .build(),
-//     );
-
-//     let event_receiver =EventReceiver {
-//         inner: vec![chunk],
-//     };
-
-//     let mut resp = InvokeWithResponseStreamOutput::builder()
-//         .event_stream(event_receiver)
-//         .build()
-//         .unwrap();
-
-//     let mut metadata_buffer = Vec::new();
-//     let (metadata_prelude, remaining) = collect_metadata(&mut resp, &mut metadata_buffer).await;
-
-//     assert!(metadata_prelude.is_some());
-//     let prelude = metadata_prelude.unwrap();
-//     assert_eq!(prelude.status_code, StatusCode::OK);
-//     assert_eq!(prelude.headers.get("content-type").unwrap(), "text/plain");
-//     assert_eq!(remaining, remaining_data);
-// }
-
-// #[tokio::test]
-// async fn test_process_buffer() {
-//     let payload = r#"{"statusCode": 200, "headers": {"Content-Type": "text/plain"}, "body": "Hello"}"#;
-//     let null_padding = vec![0u8; 8];
-//     let remaining_data = b"Remaining data";
-//
-//     let mut buffer = payload.as_bytes().to_vec();
-//     buffer.extend_from_slice(&null_padding);
-//     buffer.extend_from_slice(remaining_data);
-//
-//     let (metadata_prelude, remaining) = process_buffer(&buffer);
-//
-//     assert!(metadata_prelude.is_some());
-//     let prelude = metadata_prelude.unwrap();
-//     assert_eq!(prelude.status_code, StatusCode::OK);
-//     assert_eq!(prelude.headers.get("content-type").unwrap(), "text/plain");
-//     assert_eq!(remaining, remaining_data);
-// }
+fn test_state(config: Config) -> ApplicationState {
+    let client = Client::from_conf(
+        aws_sdk_lambda::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(aws_sdk_lambda::config::Region::new("us-east-1"))
+            .build(),
+    );
+    test_state_with(config, Arc::new(LambdaInvoker::new(client)))
+}
+
+#[tokio::test]
+async fn test_status_groups_by_namespace() {
+    let mut config = Config::default();
+    config.namespaces.insert(
+        "team-a".to_string(),
+        config::Namespace {
+            prefix: "/team-a".to_string(),
+            ..Default::default()
+        },
+    );
+    config.targets.insert(
+        "/team-a/*rest".to_string(),
+        Target {
+            namespace: Some("team-a".to_string()),
+            ..Default::default()
+        },
+    );
+    let state = test_state(config);
+    for status in ["200", "200", "502"] {
+        state.telemetry.increment(
+            "requests_total",
+            vec![
+                ("method", "GET".to_string()),
+                ("namespace", "team-a".to_string()),
+                ("status", status.to_string()),
+            ],
+        );
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    let response = axum::Json(gateway_status(&state)).into_response();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(status["observability_degraded"], false);
+    assert_eq!(status["access_log_dropped_lines"], 0);
+    assert_eq!(status["state_memory"]["used_bytes"], 0);
+    assert_eq!(status["namespaces"]["team-a"]["prefix"], "/team-a");
+    assert_eq!(status["namespaces"]["team-a"]["targets"][0], "/team-a/*rest");
+    assert_eq!(status["namespaces"]["team-a"]["requests"], 3);
+    assert_eq!(status["namespaces"]["team-a"]["errors"], 1);
+}
+
+#[tokio::test]
+async fn test_namespace_rate_limit() {
+    use tower::ServiceExt;
+
+    let mut config = Config::default();
+    for (name, prefix) in [("team-a", "/team-a"), ("team-b", "/team-b")] {
+        config.namespaces.insert(
+            name.to_string(),
+            config::Namespace {
+                prefix: prefix.to_string(),
+                rate_limit: Some(config::RateLimit {
+                    requests_per_second: 0.01,
+                    burst: Some(2),
+                }),
+                ..Default::default()
+            },
+        );
+    }
+    for (pattern, namespace) in [
+        ("/team-a/orders", "team-a"),
+        ("/team-a/users", "team-a"),
+        ("/team-b/orders", "team-b"),
+    ] {
+        config.targets.insert(
+            pattern.to_string(),
+            Target {
+                namespace: Some(namespace.to_string()),
+                ..Default::default()
+            },
+        );
+    }
+    let invoker = MockInvoker::new((0..3).map(|_| ok_output("ok")).collect());
+    let state = test_state_with(config, invoker.clone());
+
+    // The burst is shared by the namespace's targets
+    for path in ["/team-a/orders", "/team-a/users"] {
+        let response = get(build_router(state.clone()), path).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let response = build_router(state.clone())
+        .oneshot(get_request("/team-a/orders"))
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["retry-after"], "100");
+    let (status, phase, body) = error_of(state.clone(), get_request("/team-a/users")).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(phase, ErrorPhase::Ingress);
+    assert_eq!(body["error_code"], "namespace_rate_limited");
+    assert_eq!(invoker.calls(), 2);
+
+    // Other namespaces keep their own budget
+    let response = get(build_router(state.clone()), "/team-b/orders").await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = axum::Json(gateway_status(&state)).into_response();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(status["namespaces"]["team-a"]["rate_limited"], 2);
+    assert_eq!(status["namespaces"]["team-b"]["rate_limited"], 0);
+}
+
+#[tokio::test]
+async fn test_admin_namespace_reload() {
+    use tower::ServiceExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("team-a.yaml"), "targets:\n  /orders:\n    function: a-v1\n").unwrap();
+    std::fs::write(dir.path().join("team-b.yaml"), "targets:\n  /orders:\n    function: b-v1\n").unwrap();
+    let path = dir.path().join("config.yaml");
+    std::fs::write(
+        &path,
+        r#"
+lambda_function_name: default-function
+admin_api_keys: [admin-key]
+namespaces:
+  team-a:
+    prefix: /team-a
+    file: team-a.yaml
+  team-b:
+    prefix: /team-b
+    file: team-b.yaml
+"#,
+    )
+    .unwrap();
+    let state = test_state_with(Config::load_from_file(&path).unwrap(), MockInvoker::new(vec![]));
+    let reload = |name: &str, key: &str| keyed_request("POST", &format!("/admin/namespaces/{}/reload", name), key, "");
+
+    let (status, _, _) = error_of(state.clone(), reload("team-a", "app-key")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _, body) = error_of(state.clone(), reload("team-c", "admin-key")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["error_code"], "unknown_namespace");
+
+    // Team B's file is broken, which only matters to a reload of team B
+    std::fs::write(dir.path().join("team-b.yaml"), "targets: [").unwrap();
+    std::fs::write(
+        dir.path().join("team-a.yaml"),
+        "targets:\n  /orders:\n    function: a-v2\n  /users:\n    function: a-users\n",
+    )
+    .unwrap();
+    let response = build_router(state.clone())
+        .oneshot(reload("team-a", "admin-key"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["targets"], serde_json::json!(["/team-a/orders", "/team-a/users"]));
+    let config = state.config();
+    assert_eq!(config.function_name(&config.targets["/team-a/orders"]), "a-v2");
+    assert_eq!(config.function_name(&config.targets["/team-b/orders"]), "b-v1");
+
+    let (status, _, body) = error_of(state.clone(), reload("team-b", "admin-key")).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["error_code"], "namespace_reload_failed");
+    assert_eq!(state.config().function_name(&state.config().targets["/team-b/orders"]), "b-v1");
+}
+
+#[tokio::test]
+async fn test_burning_error_budget_flags_status_and_responses() {
+    let target = Target {
+        slo: Some(99.0),
+        slo_warning_header: true,
+        ..Default::default()
+    };
+    let config = Config {
+        targets: BTreeMap::from([("/api".to_string(), target)]),
+        ..Default::default()
+    };
+    let failures = (0..20).map(|_| output(r#"{"statusCode": 500, "body": ""}"#, None)).collect();
+    let state = test_state_with(config, MockInvoker::new(failures));
+    let app = build_router(state.clone());
+
+    // Alerts wait for `min_requests`
+    for _ in 0..19 {
+        let response = get(app.clone(), "/api").await;
+        assert!(!response.headers().contains_key(slo::SLO_AT_RISK_HEADER));
+    }
+    let response = get(app.clone(), "/api").await;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(response.headers()[slo::SLO_AT_RISK_HEADER], "fast_burn");
+
+    let response = axum::Json(gateway_status(&state)).into_response();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let budget = &status["error_budgets"]["/api"];
+    assert_eq!((budget["slo_at_risk"].clone(), budget["alert"].clone()), (true.into(), "fast_burn".into()));
+    assert_eq!(budget["long_window"]["errors"], 20);
+}
+
+#[tokio::test]
+async fn test_config_rev_reported_and_updated_on_reload() {
+    let config = Config {
+        debug_headers: true,
+        ..Default::default()
+    };
+    let state = test_state_with(config.clone(), MockInvoker::new(vec![]));
+    let rev = state.config_rev();
+    assert_eq!(*rev, config.fingerprint());
+
+    let response = get(build_router(state.clone()), "/x").await;
+    assert_eq!(response.headers()["x-gateway-config-rev"], *rev);
+    let response = axum::Json(gateway_status(&state)).into_response();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(status["config_rev"], *rev);
+
+    state.reload(Config {
+        debug_headers: false,
+        ..config
+    });
+    assert_ne!(state.config_rev(), rev);
+    let response = get(build_router(state.clone()), "/x").await;
+    assert!(!response.headers().contains_key("x-gateway-config-rev"));
+}
+
+async fn route_of(app: Router, uri: &str) -> MatchedRoute {
+    use tower::ServiceExt;
+
+    let request = axum::http::Request::builder().uri(uri).body(Body::empty()).unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    response.extensions().get::<MatchedRoute>().unwrap().clone()
+}
+
+fn routed_config(patterns: &[&str], root_target: Option<&str>) -> Config {
+    let mut config = Config {
+        auth_mode: config::AuthMode::ApiKey,
+        root_target: root_target.map(String::from),
+        ..Default::default()
+    };
+    for pattern in patterns {
+        config.targets.insert(pattern.to_string(), Target::default());
+    }
+    config
+}
+
+#[tokio::test]
+async fn test_router_root_target_keyed_slash() {
+    let app = build_router(test_state(routed_config(&["/", "/*rest"], None)));
+    assert_eq!(
+        route_of(app.clone(), "/").await,
+        MatchedRoute {
+            pattern: "/".to_string(),
+            rule: RouteRule::Exact
+        }
+    );
+    assert_eq!(route_of(app, "/a/b").await.pattern, "/*rest");
+}
+
+#[tokio::test]
+async fn test_router_root_target_field() {
+    let app = build_router(test_state(routed_config(&["/home", "/*rest"], Some("/home"))));
+    assert_eq!(
+        route_of(app.clone(), "/").await,
+        MatchedRoute {
+            pattern: "/home".to_string(),
+            rule: RouteRule::RootTarget
+        }
+    );
+    assert_eq!(route_of(app, "/other").await.rule, RouteRule::Wildcard);
+}
+
+#[tokio::test]
+async fn test_router_wildcard_only() {
+    let app = build_router(test_state(routed_config(&["/*rest"], None)));
+    assert_eq!(route_of(app.clone(), "/").await.rule, RouteRule::Fallback);
+    assert_eq!(route_of(app, "/x").await.rule, RouteRule::Wildcard);
+}
+
+fn conflict() -> Result<BufferedOutput, InvokeError> {
+    Err(InvokeError::Conflict("ResourceConflictException: function is updating".to_string()))
+}
+
+fn conflict_config(retries: u32) -> Config {
+    Config {
+        conflict_retry: config::ConflictRetryConfig {
+            retries,
+            backoff_ms: 1,
+            retry_after_secs: 3,
+        },
+        ..Default::default()
+    }
+}
+
+async fn get(app: Router, uri: &str) -> Response {
+    use tower::ServiceExt;
+
+    let request = axum::http::Request::builder().uri(uri).body(Body::empty()).unwrap();
+    app.oneshot(request).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_conflict_retried_until_success() {
+    let invoker = MockInvoker::new(vec![conflict(), conflict(), ok_output("deployed")]);
+    let state = test_state_with(conflict_config(2), invoker.clone());
+    let response = get(build_router(state.clone()), "/").await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, "deployed");
+    assert_eq!(invoker.calls(), 3);
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let key = (
+        "invoke_conflicts_total",
+        vec![("function", String::new()), ("outcome", "retried".to_string())],
+    );
+    assert_eq!(state.telemetry.snapshot().counters[&key], 2);
+}
+
+/// Sum of the durations `name` recorded for `target`, once telemetry has caught up.
+async fn recorded_ms(state: &ApplicationState, name: &'static str, target: &str) -> f64 {
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let key = (name, vec![("target", target.to_string())]);
+    state.telemetry.snapshot().histograms[&key].sum
+}
+
+#[tokio::test]
+async fn test_overhead_excludes_every_invoke_attempt() {
+    let invoker = MockInvoker::with_delayed_results(vec![conflict(), ok_output("")], Duration::from_millis(100));
+    let state = test_state_with(conflict_config(1), invoker.clone());
+    let started = Instant::now();
+    let response = get(build_router(state.clone()), "/").await;
+    let total_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let upstream_ms = recorded_ms(&state, "upstream_duration_ms", "").await;
+    let overhead_ms = recorded_ms(&state, "gateway_overhead_ms", "").await;
+    assert!((200.0..250.0).contains(&upstream_ms), "upstream {} ms", upstream_ms);
+    assert!(overhead_ms < 40.0, "overhead {} ms", overhead_ms);
+    assert!(upstream_ms + overhead_ms <= total_ms);
+}
+
+#[tokio::test]
+async fn test_conflict_exhausted_returns_503() {
+    let invoker = MockInvoker::new(vec![conflict(), conflict()]);
+    let state = test_state_with(conflict_config(1), invoker.clone());
+    let response = get(build_router(state.clone()), "/").await;
+
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()[axum::http::header::RETRY_AFTER], "3");
+    assert_eq!(invoker.calls(), 2);
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let key = (
+        "invoke_conflicts_total",
+        vec![("function", String::new()), ("outcome", "exhausted".to_string())],
+    );
+    assert_eq!(state.telemetry.snapshot().counters[&key], 1);
+}
+
+#[tokio::test]
+async fn test_other_invoke_errors_are_not_retried() {
+    let invoker = MockInvoker::new(vec![Err(InvokeError::AccessDenied("AccessDenied".to_string()))]);
+    let response = get(build_router(test_state_with(conflict_config(2), invoker.clone())), "/").await;
+
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(invoker.calls(), 1);
+}
+
+#[tokio::test]
+async fn test_throttle_retry_after_prefers_lambda_hint() {
+    let throttled = |hint: Option<u64>| {
+        Err(InvokeError::Throttled(
+            "Rate exceeded".to_string(),
+            hint.map(Duration::from_secs),
+        ))
+    };
+    let invoker = MockInvoker::new(vec![throttled(Some(5)), throttled(None), throttled(Some(600))]);
+    let config = Config {
+        retry_after: config::RetryAfterConfig {
+            default_secs: 2,
+            max_secs: 30,
+        },
+        ..Default::default()
+    };
+    let app = build_router(test_state_with(config, invoker));
+
+    for expected in ["5", "2", "30"] {
+        let response = get(app.clone(), "/").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[axum::http::header::RETRY_AFTER], expected);
+    }
+}
+
+fn output(payload: &str, function_error: Option<&str>) -> Result<BufferedOutput, InvokeError> {
+    Ok(BufferedOutput {
+        payload: Bytes::from(payload.to_string()),
+        function_error: function_error.map(String::from),
+        request_id: None,
+    })
+}
+
+/// Sends `request` and returns the status, the phase recorded on the response and the JSON body.
+async fn error_of(state: ApplicationState, request: axum::http::Request<Body>) -> (StatusCode, ErrorPhase, serde_json::Value) {
+    use tower::ServiceExt;
+
+    let response = build_router(state).oneshot(request).await.unwrap();
+    let status = response.status();
+    let info = *response.extensions().get::<ErrorInfo>().expect("error info");
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error_code"], info.code);
+    assert_eq!(body["phase"], info.phase.as_str());
+    (status, info.phase, body)
+}
+
+fn get_request(uri: &str) -> axum::http::Request<Body> {
+    axum::http::Request::builder().uri(uri).body(Body::empty()).unwrap()
+}
+
+fn allowed_hosts_config() -> Config {
+    Config {
+        allowed_hosts: vec!["api.example.com".to_string(), "*.internal.example.com".to_string()],
+        ..Default::default()
+    }
+}
+
+fn request_for_host(host: &str, body: Body) -> axum::http::Request<Body> {
+    axum::http::Request::builder()
+        .method("POST")
+        .uri("/orders")
+        .header("host", host)
+        .body(body)
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_unknown_host_is_misdirected_without_reading_the_body() {
+    let invoker = MockInvoker::new(vec![]);
+    let state = test_state_with(allowed_hosts_config(), invoker.clone());
+    // A body that never ends would hang the request if it were read
+    let body = Body::from_stream(futures::stream::pending::<Result<Bytes, std::io::Error>>());
+    let request = request_for_host("scanner.test", body);
+
+    let (status, phase, body) = tokio::time::timeout(Duration::from_secs(1), error_of(state.clone(), request))
+        .await
+        .expect("rejected without reading the body");
+    assert_eq!((status, phase), (StatusCode::MISDIRECTED_REQUEST, ErrorPhase::Ingress));
+    assert_eq!(body["error_code"], "misdirected_request");
+    assert_eq!(invoker.calls(), 0);
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let key = (
+        "misdirected_requests_total",
+        vec![("host", hosts::host_label(Some("scanner.test")))],
+    );
+    assert_eq!(state.telemetry.snapshot().counters[&key], 1);
+}
+
+#[tokio::test]
+async fn test_allowed_hosts_are_forwarded() {
+    use tower::ServiceExt;
+
+    let invoker = MockInvoker::new(vec![]);
+    let app = build_router(test_state_with(allowed_hosts_config(), invoker.clone()));
+    for host in ["API.example.com:8080", "a.internal.example.com"] {
+        let response = app.clone().oneshot(request_for_host(host, Body::empty())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{}", host);
+    }
+    assert_eq!(invoker.calls(), 2);
+}
+
+#[tokio::test]
+async fn test_builtin_routes_answer_any_host() {
+    use tower::ServiceExt;
+
+    let app = build_router(test_state_with(allowed_hosts_config(), MockInvoker::new(vec![])));
+    let request = axum::http::Request::builder()
+        .uri("/healthz")
+        .header("host", "10.0.0.12:8000")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(app.oneshot(request).await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_valid_json_body_forwarded_byte_for_byte() {
+    use tower::ServiceExt;
+
+    let mut config = Config::default();
+    config.targets.insert(
+        "/*rest".to_string(),
+        Target {
+            validate_json_body: true,
+            max_json_depth: Some(4),
+            ..Default::default()
+        },
+    );
+    let invoker = MockInvoker::new(vec![]);
+    let state = test_state_with(config, invoker.clone());
+    let json_request = |body: &'static str| {
+        axum::http::Request::builder()
+            .method("POST")
+            .uri("/orders")
+            .header("content-type", "application/json")
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    let (status, _, body) = error_of(state.clone(), json_request("{\"id\": ")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error_code"], "invalid_json");
+    let (status, _, body) = error_of(state.clone(), json_request("[[[[[1]]]]]")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error_code"], "json_too_deep");
+    assert_eq!(invoker.calls(), 0);
+
+    // Whitespace and key order survive, as nothing is re-serialized
+    let original = "{ \"b\" :1,\n\"a\":[ 1.50 ] }";
+    let response = build_router(state).oneshot(json_request(original)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let event: serde_json::Value = serde_json::from_str(&invoker.requests()[0].payload).unwrap();
+    assert_eq!(event["body"], original);
+}
+
+#[tokio::test]
+async fn test_closed_schedule_rejects_until_window_end() {
+    use chrono::{Datelike, Utc};
+
+    let now = Utc::now();
+    let deny_today = config::Schedule {
+        action: config::ScheduleAction::Deny,
+        timezone: chrono_tz::UTC,
+        windows: vec![config::ScheduleWindow {
+            days: vec![now.weekday()],
+            start: "00:00".parse().unwrap(),
+            end: "24:00".parse().unwrap(),
+            timezone: None,
+        }],
+    };
+    let mut config = Config::default();
+    config.retry_after.max_secs = 86400;
+    config.targets.insert(
+        "/closed/*rest".to_string(),
+        Target {
+            schedule: Some(deny_today),
+            ..Default::default()
+        },
+    );
+    let invoker = MockInvoker::new(vec![]);
+    let state = test_state_with(config, invoker.clone());
+
+    let response = get(build_router(state.clone()), "/closed/orders").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let retry_after: u64 = response.headers()["retry-after"].to_str().unwrap().parse().unwrap();
+    let tomorrow = now.date_naive() + chrono::Duration::days(1);
+    let midnight = tomorrow.and_hms_opt(0, 0, 0).unwrap().and_utc();
+    assert!(retry_after.abs_diff((midnight - now).num_seconds() as u64) <= 2);
+    let (_, phase, body) = error_of(state.clone(), get_request("/closed/orders")).await;
+    assert_eq!(phase, ErrorPhase::Ingress);
+    assert_eq!(body["error_code"], "outside_schedule");
+    assert_eq!(invoker.calls(), 0);
+
+    let response = axum::Json(gateway_status(&state)).into_response();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(status["schedules"]["/closed/*rest"]["open"], false);
+    let until = status["schedules"]["/closed/*rest"]["until"].as_str().unwrap();
+    assert_eq!(chrono::DateTime::parse_from_rfc3339(until).unwrap(), midnight);
+}
+
+#[tokio::test]
+async fn test_schedule_sees_corrected_clock() {
+    use chrono::{Datelike, Utc};
+
+    let now = Utc::now();
+    let open_today_and_tomorrow = config::Schedule {
+        action: config::ScheduleAction::Allow,
+        timezone: chrono_tz::UTC,
+        windows: vec![config::ScheduleWindow {
+            days: vec![now.weekday(), now.weekday().succ()],
+            start: "00:00".parse().unwrap(),
+            end: "24:00".parse().unwrap(),
+            timezone: None,
+        }],
+    };
+    let mut config = Config::default();
+    config.clock.correct = true;
+    config.targets.insert(
+        "/hours/*rest".to_string(),
+        Target {
+            schedule: Some(open_today_and_tomorrow),
+            ..Default::default()
+        },
+    );
+    let state = test_state_with(config, MockInvoker::new(vec![]));
+    assert_eq!(get(build_router(state.clone()), "/hours/x").await.status(), StatusCode::OK);
+
+    // The reference clock is three days ahead, past both days of the window
+    let ahead = now + chrono::Duration::days(3) - chrono::Duration::milliseconds(500);
+    state.clock.observe(now, now, ahead);
+    let response = get(build_router(state.clone()), "/hours/x").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let response = axum::Json(gateway_status(&state)).into_response();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(status["clock"]["offset_ms"], 3 * 86_400_000);
+    assert_eq!(status["clock"]["within_tolerance"], false);
+    assert_eq!(status["schedules"]["/hours/*rest"]["open"], false);
+}
+
+#[tokio::test]
+async fn test_error_phase_ingress() {
+    let mut config = Config::default();
+    config.targets.insert(
+        "/*rest".to_string(),
+        Target {
+            decompress_request: true,
+            ..Default::default()
+        },
+    );
+    let invoker = MockInvoker::new(vec![]);
+    let request = axum::http::Request::builder()
+        .method("POST")
+        .uri("/upload")
+        .header("content-encoding", "gzip")
+        .body(Body::from("not gzip"))
+        .unwrap();
+
+    let (status, phase, body) = error_of(test_state_with(config, invoker.clone()), request).await;
+    assert_eq!((status, phase), (StatusCode::BAD_REQUEST, ErrorPhase::Ingress));
+    assert_eq!(body["error_code"], "invalid_content_encoding");
+    assert_eq!(invoker.calls(), 0);
+}
+
+#[tokio::test]
+async fn test_error_phase_auth() {
+    let config = Config {
+        auth_mode: config::AuthMode::ApiKey,
+        ..Default::default()
+    };
+    let (status, phase, body) = error_of(test_state_with(config, MockInvoker::new(vec![])), get_request("/")).await;
+    assert_eq!((status, phase), (StatusCode::UNAUTHORIZED, ErrorPhase::Auth));
+    assert_eq!(body["error_code"], "unauthorized");
+}
+
+fn post_body(uri: &str, content_type: &str, body: Body) -> axum::http::Request<Body> {
+    axum::http::Request::post(uri)
+        .header("content-type", content_type)
+        .body(body)
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_request_body_limit() {
+    use tower::ServiceExt;
+
+    let config = Config {
+        max_request_body_bytes: 16,
+        targets: BTreeMap::from([(
+            "/uploads/*rest".to_string(),
+            Target {
+                max_request_body_bytes: Some(32),
+                ..Default::default()
+            },
+        )]),
+        ..Default::default()
+    };
+    let invoker = MockInvoker::new(vec![ok_output("ok"), ok_output("ok")]);
+    let state = test_state_with(config, invoker.clone());
+
+    // Exactly at the limit, with its length declared
+    let request = axum::http::Request::post("/orders")
+        .header("content-length", "16")
+        .body(Body::from("a".repeat(16)))
+        .unwrap();
+    let response = build_router(state.clone()).oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let request = axum::http::Request::post("/orders")
+        .header("content-length", "17")
+        .body(Body::from("a".repeat(17)))
+        .unwrap();
+    let (status, phase, body) = error_of(state.clone(), request).await;
+    assert_eq!((status, phase), (StatusCode::PAYLOAD_TOO_LARGE, ErrorPhase::Ingress));
+    assert_eq!(body["error_code"], "request_body_too_large");
+    assert_eq!(body["message"], "Request body exceeds 16 bytes");
+
+    // Chunked, without a length: rejected once the bytes read pass the limit
+    let chunks = futures::stream::iter(["aaaaaaaa", "aaaaaaaa", "a"]).map(Ok::<_, std::io::Error>);
+    let request = post_body("/orders", "text/plain", Body::from_stream(chunks));
+    let (status, _, body) = error_of(state.clone(), request).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(body["error_code"], "request_body_too_large");
+
+    // A target's own limit, over the top-level one
+    let request = post_body("/uploads/a", "text/plain", Body::from("a".repeat(32)));
+    let response = build_router(state.clone()).oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let request = post_body("/uploads/a", "text/plain", Body::from("a".repeat(33)));
+    assert_eq!(error_of(state, request).await.0, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(invoker.calls(), 2);
+}
+
+#[tokio::test]
+async fn test_refused_request_body_is_not_read() {
+    use tower::ServiceExt;
+
+    let config = Config {
+        auth_mode: config::AuthMode::ApiKey,
+        api_keys: HashSet::from(["key".to_string()]),
+        targets: BTreeMap::from([(
+            "/docs".to_string(),
+            Target {
+                methods: Some(vec!["get".to_string()]),
+                ..Default::default()
+            },
+        )]),
+        ..Default::default()
+    };
+    let invoker = MockInvoker::new(vec![]);
+    let app = build_router(test_state_with(config, invoker.clone()));
+    // A body that never ends, so reading it would never answer
+    let endless = || Body::from_stream(futures::stream::pending::<Result<Bytes, std::io::Error>>());
+    let send = |request: axum::http::Request<Body>| {
+        tokio::time::timeout(Duration::from_secs(5), app.clone().oneshot(request))
+    };
+
+    let response = send(post_body("/orders", "text/plain", endless())).await.unwrap().unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = send(keyed_request("POST", "/docs", "key", endless())).await.unwrap().unwrap();
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(response.headers()[ALLOW], "GET");
+    assert_eq!(invoker.calls(), 0);
+}
+
+#[tokio::test]
+async fn test_body_match_reads_up_to_the_limits_of_its_targets() {
+    use tower::ServiceExt;
+
+    let config = Config {
+        max_request_body_bytes: 16,
+        targets: BTreeMap::from([
+            (
+                "/events".to_string(),
+                Target {
+                    body_match: Some(config::BodyMatch {
+                        body_json: vec![config::BodyJsonRule {
+                            pointer: "/type".to_string(),
+                            equals: Some(serde_json::json!("small")),
+                            regex: None,
+                            target: "small".to_string(),
+                        }],
+                        fallback: "large".to_string(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            ),
+            ("small".to_string(), Target::default()),
+            (
+                "large".to_string(),
+                Target {
+                    max_request_body_bytes: Some(64),
+                    ..Default::default()
+                },
+            ),
+        ]),
+        ..Default::default()
+    };
+    let state = test_state_with(config, MockInvoker::new(vec![]));
+    let event = |kind: &str| format!(r#"{{"type": "{}", "pad": "{}"}}"#, kind, "x".repeat(20));
+
+    let request = post_body("/events", "application/json", Body::from(event("large")));
+    let response = build_router(state.clone()).oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    // The body is read for the largest target, then held to the limit of the one it picks
+    let request = post_body("/events", "application/json", Body::from(event("small")));
+    let (status, _, body) = error_of(state.clone(), request).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(body["message"], "Request body exceeds 16 bytes");
+    let request = post_body("/events", "application/json", Body::from("x".repeat(65)));
+    let (status, _, body) = error_of(state, request).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(body["message"], "Request body exceeds 64 bytes");
+}
+
+#[tokio::test]
+async fn test_request_body_over_the_invoke_payload_once_base64_encoded() {
+    use tower::ServiceExt;
+
+    let config = Config {
+        max_request_body_bytes: 10 * 1024 * 1024,
+        ..Default::default()
+    };
+    let invoker = MockInvoker::new(vec![ok_output("ok")]);
+    let state = test_state_with(config, invoker.clone());
+    // 5 MB fits as text, but is 6.67 MB once base64-encoded
+    let body = "a".repeat(5 * 1024 * 1024);
+
+    let request = post_body("/upload", "application/octet-stream", Body::from(body.clone()));
+    let (status, phase, error) = error_of(state.clone(), request).await;
+    assert_eq!((status, phase), (StatusCode::PAYLOAD_TOO_LARGE, ErrorPhase::Build));
+    assert_eq!(error["error_code"], "payload_too_large");
+    assert_eq!(invoker.calls(), 0);
+
+    let request = post_body("/upload", "text/plain", Body::from(body));
+    let response = build_router(state).oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(invoker.calls(), 1);
+}
+
+#[tokio::test]
+async fn test_response_over_the_invoke_payload_is_a_distinct_502() {
+    let config = Config {
+        function_error_status: 500,
+        targets: BTreeMap::from([("/*rest".to_string(), Target::default())]),
+        ..Default::default()
+    };
+    let oversized = format!(r#"{{"statusCode": 200, "body": "{}"}}"#, "a".repeat(6 * 1024 * 1024));
+    let overflow = r#"{"errorType": "Function.ResponseSizeTooLarge", "errorMessage": "Response size exceeded"}"#;
+    let invoker = MockInvoker::new(vec![output(&oversized, None), output(overflow, Some("Unhandled"))]);
+    let state = test_state_with(config, invoker);
+
+    for _ in 0..2 {
+        let (status, phase, body) = error_of(state.clone(), get_request("/report")).await;
+        assert_eq!((status, phase), (StatusCode::BAD_GATEWAY, ErrorPhase::Upstream));
+        assert_eq!(body["error_code"], "response_payload_too_large");
+    }
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let key = ("response_payload_overflows_total", vec![("target", "/*rest".to_string())]);
+    assert_eq!(state.telemetry.snapshot().counters[&key], 2);
+}
+
+fn retry_config(invoke: LambdaInvokeMode) -> Config {
+    Config {
+        lambda_function_name: "orders".to_string(),
+        targets: BTreeMap::from([(
+            "/*rest".to_string(),
+            Target {
+                invoke: Some(invoke),
+                retries: Some(config::InvokeRetryConfig {
+                    base_delay_ms: 5,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        )]),
+        ..Default::default()
+    }
+}
+
+fn throttled() -> Result<BufferedOutput, InvokeError> {
+    Err(InvokeError::Throttled("Rate exceeded".to_string(), None))
+}
+
+fn service_error() -> Result<BufferedOutput, InvokeError> {
+    Err(InvokeError::Service("ServiceException".to_string()))
+}
+
+#[tokio::test]
+async fn test_transient_invoke_failures_retried() {
+    use tower::ServiceExt;
+
+    let invoker = MockInvoker::new(vec![throttled(), service_error(), ok_output("done")]);
+    let state = test_state_with(retry_config(LambdaInvokeMode::Buffered), invoker.clone());
+    let response = get(build_router(state.clone()), "/orders").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(invoker.calls(), 3);
+
+    // The last failure is answered once attempts run out
+    let invoker = MockInvoker::new(vec![throttled(), throttled(), throttled(), ok_output("late")]);
+    let state = test_state_with(retry_config(LambdaInvokeMode::Buffered), invoker.clone());
+    let (status, _, body) = error_of(state.clone(), get_request("/orders")).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(body["error_code"], "function_throttled");
+    assert_eq!(invoker.calls(), 3);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let key = |outcome: &str| {
+        let labels = vec![
+            ("function", "orders".to_string()),
+            ("reason", "throttled".to_string()),
+            ("outcome", outcome.to_string()),
+        ];
+        ("invoke_retries_total", labels)
+    };
+    let counters = state.telemetry.snapshot().counters;
+    assert_eq!((counters[&key("retried")], counters[&key("exhausted")]), (2, 1));
+
+    // POST is not retried, and other failures are not retryable
+    let invoker = MockInvoker::new(vec![service_error(), Err(InvokeError::Other("dispatch failure".to_string()))]);
+    let state = test_state_with(retry_config(LambdaInvokeMode::Buffered), invoker.clone());
+    let (status, _, body) = error_of(state.clone(), keyed_request("POST", "/orders", "", "{}")).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert_eq!(body["error_code"], "invoke_failed");
+    let (status, _, _) = error_of(state, get_request("/orders")).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert_eq!(invoker.calls(), 2);
+
+    // A stream is retried while it has not opened
+    let streams = vec![
+        Err(InvokeError::Service("ServiceException".to_string())),
+        Ok(delayed_stream(vec![(0, STREAM_PRELUDE), (0, b"streamed")])),
+    ];
+    let invoker = MockInvoker::with_streams(streams, Duration::ZERO);
+    let app = build_router(test_state_with(retry_config(LambdaInvokeMode::ResponseStream), invoker.clone()));
+    let response = app.oneshot(get_request("/orders")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, "streamed");
+    assert_eq!(invoker.calls(), 2);
+}
+
+#[tokio::test]
+async fn test_circuit_breaker_fast_fails_failing_target() {
+    use tower::ServiceExt;
+
+    let mut config = Config::default();
+    config.admin_api_keys = HashSet::from(["admin-key".to_string()]);
+    config.targets.insert(
+        "/orders".to_string(),
+        Target {
+            circuit_breaker: Some(config::CircuitBreakerConfig {
+                failure_threshold: 2,
+                window_secs: 30,
+                open_secs: 15,
+            }),
+            ..Default::default()
+        },
+    );
+    let invoker = MockInvoker::new(vec![
+        // A function answering 500 is not failing in the breaker's sense
+        output(r#"{"statusCode": 500, "body": "oops"}"#, None),
+        output(r#"{"errorType": "Error", "errorMessage": "boom"}"#, Some("Unhandled")),
+        Err(InvokeError::AccessDenied("AccessDeniedException".to_string())),
+        ok_output("never"),
+    ]);
+    let state = test_state_with(config, invoker.clone());
+    let app = build_router(state.clone());
+    for expected in [StatusCode::INTERNAL_SERVER_ERROR, StatusCode::BAD_GATEWAY, StatusCode::INTERNAL_SERVER_ERROR] {
+        assert_eq!(app.clone().oneshot(get_request("/orders")).await.unwrap().status(), expected);
+    }
+
+    let response = app.clone().oneshot(get_request("/orders")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let retry_after: u64 = response.headers()["retry-after"].to_str().unwrap().parse().unwrap();
+    assert!((14..=15).contains(&retry_after), "{}", retry_after);
+    assert_eq!(response.extensions().get::<ErrorInfo>().unwrap().code, "circuit_open");
+    assert_eq!(invoker.calls(), 3);
+
+    let response = app.oneshot(keyed_request("GET", "/status", "admin-key", "")).await.unwrap();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(status["circuit_breakers"]["/orders"]["state"], "open");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let key = ("circuit_breaker_rejections_total", vec![("target", "/orders".to_string())]);
+    assert_eq!(state.telemetry.snapshot().counters[&key], 1);
+}
+
+#[tokio::test]
+async fn test_error_phase_invoke() {
+    let denied = Err(InvokeError::AccessDenied("AccessDeniedException".to_string()));
+    let invoker = MockInvoker::new(vec![denied, conflict()]);
+    let state = test_state_with(conflict_config(0), invoker);
+
+    let (status, phase, body) = error_of(state.clone(), get_request("/")).await;
+    assert_eq!((status, phase), (StatusCode::INTERNAL_SERVER_ERROR, ErrorPhase::Invoke));
+    assert_eq!(body["error_code"], "invoke_failed");
+
+    let (status, phase, body) = error_of(state, get_request("/")).await;
+    assert_eq!((status, phase), (StatusCode::SERVICE_UNAVAILABLE, ErrorPhase::Invoke));
+    assert_eq!(body["error_code"], "function_updating");
+}
+
+#[tokio::test]
+async fn test_error_phase_upstream() {
+    let invoker = MockInvoker::new(vec![
+        output("not json", None),
+        output(r#"{"statusCode": 42, "body": ""}"#, None),
+        output(r#"{"statusCode": 200, "isBase64Encoded": true, "body": "%%%"}"#, None),
+        output(r#"{"errorMessage": "boom", "errorType": "Error"}"#, Some("Unhandled")),
+    ]);
+    let state = test_state_with(Config::default(), invoker);
+
+    for expected_code in [
+        "invalid_upstream_response",
+        "invalid_upstream_response",
+        "invalid_upstream_response",
+        "function_error",
+    ] {
+        let (status, phase, body) = error_of(state.clone(), get_request("/")).await;
+        assert_eq!((status, phase), (StatusCode::BAD_GATEWAY, ErrorPhase::Upstream));
+        assert_eq!(body["error_code"], expected_code);
+    }
+}
+
+#[tokio::test]
+async fn test_invalid_upstream_header_phase() {
+    let payload = r#"{"statusCode": 200, "headers": {"x-bad": "a\nb", "x-good": "ok"}, "body": "hi"}"#;
+    let invoker = MockInvoker::new(vec![output(payload, None), output(payload, None)]);
+    let mut config = Config::default();
+    config.targets.insert(
+        "/strict".to_string(),
+        Target {
+            strict_upstream_headers: true,
+            ..Default::default()
+        },
+    );
+    let state = test_state_with(config, invoker);
+
+    // Dropped by default, the rest of the response is intact
+    let response = get(build_router(state.clone()), "/lenient").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["x-good"], "ok");
+    assert!(response.headers().get("x-bad").is_none());
+
+    let (status, phase, body) = error_of(state.clone(), get_request("/strict")).await;
+    assert_eq!((status, phase), (StatusCode::BAD_GATEWAY, ErrorPhase::Upstream));
+    assert_eq!(body["error_code"], "invalid_upstream_header");
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let key = (
+        "requests_total",
+        vec![
+            ("method", "GET".to_string()),
+            ("namespace", String::new()),
+            ("target", "/strict".to_string()),
+            ("status", "502".to_string()),
+            ("phase", "upstream".to_string()),
+        ],
+    );
+    assert_eq!(state.telemetry.snapshot().counters[&key], 1);
+}
+
+/// A streamed response whose chunks each arrive after their delay.
+fn delayed_stream(chunks: Vec<(u64, &'static [u8])>) -> streaming::PayloadStream {
+    futures::stream::iter(chunks)
+        .then(|(delay_ms, chunk)| async move {
+            tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+            Ok(Bytes::from_static(chunk))
+        })
+        .boxed()
+}
+
+const STREAM_PRELUDE: &[u8] = b"{\"statusCode\": 200, \"headers\": {\"content-type\": \"text/plain\"}}\0\0\0\0\0\0\0\0";
+
+fn timeout_state(invoker: Arc<MockInvoker>) -> ApplicationState {
+    let config = Config {
+        lambda_invoke_mode: LambdaInvokeMode::ResponseStream,
+        targets: BTreeMap::from([(
+            "/*rest".to_string(),
+            Target {
+                invoke_timeout_ms: Some(200),
+                prelude_timeout_ms: Some(200),
+                ..Default::default()
+            },
+        )]),
+        ..Default::default()
+    };
+    test_state_with(config, invoker)
+}
+
+#[tokio::test]
+async fn test_streaming_within_budgets() {
+    // Slow overall, but every phase stays within its own budget
+    let stream = delayed_stream(vec![(60, &STREAM_PRELUDE[..10]), (60, &STREAM_PRELUDE[10..]), (300, b"body")]);
+    let invoker = MockInvoker::with_streams(vec![Ok(stream)], Duration::from_millis(30));
+
+    let response = get(build_router(timeout_state(invoker)), "/x").await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, "body");
+}
+
+#[tokio::test]
+async fn test_streamed_response_is_gzipped_chunk_by_chunk() {
+    use http_body_util::BodyExt;
+    use std::io::Write;
+    use tower::ServiceExt;
+
+    let chunks: [&'static [u8]; 3] = [b"data: one\n\n", b"data: two\n\n", b"data: three\n\n"];
+    let stream = delayed_stream(vec![(0, STREAM_PRELUDE), (0, chunks[0]), (50, chunks[1]), (50, chunks[2])]);
+    let invoker = MockInvoker::with_streams(vec![Ok(stream)], Duration::ZERO);
+    let config = Config {
+        lambda_invoke_mode: LambdaInvokeMode::ResponseStream,
+        compression: config::CompressionConfig {
+            enabled: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let request = axum::http::Request::get("/events")
+        .header("accept-encoding", "gzip, deflate")
+        .body(Body::empty())
+        .unwrap();
+    let response = build_router(test_state_with(config, invoker)).oneshot(request).await.unwrap();
+
+    assert_eq!(response.headers()["content-encoding"], "gzip");
+    assert_eq!(response.headers()["vary"], "accept-encoding");
+    assert!(!response.headers().contains_key("content-length"));
+    // Every chunk decodes as soon as it arrives, before the stream has ended
+    let mut body = response.into_body();
+    let mut decoder = flate2::write::GzDecoder::new(Vec::new());
+    for sent in 1..=chunks.len() {
+        let frame = body.frame().await.unwrap().unwrap();
+        decoder.write_all(frame.data_ref().unwrap()).unwrap();
+        decoder.flush().unwrap();
+        assert_eq!(decoder.get_ref()[..], chunks[..sent].concat()[..]);
+    }
+    while let Some(frame) = body.frame().await {
+        decoder.write_all(frame.unwrap().data_ref().unwrap()).unwrap();
+    }
+    assert_eq!(decoder.finish().unwrap(), chunks.concat());
+}
+
+#[tokio::test]
+async fn test_digested_stream_is_not_compressed() {
+    use sha2::{Digest, Sha256};
+    use tower::ServiceExt;
+
+    let stream = delayed_stream(vec![(0, STREAM_PRELUDE), (0, b"data: one\n\n"), (0, b"data: two\n\n")]);
+    let invoker = MockInvoker::with_streams(vec![Ok(stream)], Duration::ZERO);
+    let target = Target {
+        body_digest_trailer: true,
+        ..Default::default()
+    };
+    let config = Config {
+        lambda_invoke_mode: LambdaInvokeMode::ResponseStream,
+        targets: BTreeMap::from([("/*rest".to_string(), target)]),
+        compression: config::CompressionConfig {
+            enabled: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let request = axum::http::Request::get("/events")
+        .header("accept-encoding", "gzip")
+        .header("te", "trailers")
+        .body(Body::empty())
+        .unwrap();
+    let response = build_router(test_state_with(config, invoker)).oneshot(request).await.unwrap();
+
+    assert!(!response.headers().contains_key("content-encoding"));
+    let collected = http_body_util::BodyExt::collect(response.into_body()).await.unwrap();
+    let trailer = collected.trailers().unwrap()[streaming::DIGEST_TRAILER].clone();
+    let received = collected.to_bytes();
+    assert_eq!(received, "data: one\n\ndata: two\n\n");
+    assert_eq!(trailer, format!("{:x}", Sha256::digest(&received)));
+}
+
+#[tokio::test]
+async fn test_streaming_overhead_excludes_time_to_first_byte() {
+    let stream = delayed_stream(vec![(60, &STREAM_PRELUDE[..10]), (60, &STREAM_PRELUDE[10..]), (300, b"body")]);
+    let invoker = MockInvoker::with_streams(vec![Ok(stream)], Duration::from_millis(30));
+    let state = timeout_state(invoker);
+
+    let response = get(build_router(state.clone()), "/x").await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    // Streaming the body happens after the response is handed over, and counts for neither
+    let upstream_ms = recorded_ms(&state, "upstream_duration_ms", "/*rest").await;
+    let overhead_ms = recorded_ms(&state, "gateway_overhead_ms", "/*rest").await;
+    assert!((150.0..200.0).contains(&upstream_ms), "upstream {} ms", upstream_ms);
+    assert!(overhead_ms < 40.0, "overhead {} ms", overhead_ms);
+}
+
+#[tokio::test]
+async fn test_streamed_request_duration_lasts_until_last_chunk() {
+    let stream = delayed_stream(vec![(0, STREAM_PRELUDE), (300, b"body")]);
+    let invoker = MockInvoker::with_streams(vec![Ok(stream)], Duration::ZERO);
+    let state = timeout_state(invoker);
+    let key = (
+        "request_duration_ms",
+        vec![
+            ("method", "GET".to_string()),
+            ("namespace", String::new()),
+            ("target", "/*rest".to_string()),
+            ("status", "200".to_string()),
+            ("phase", String::new()),
+        ],
+    );
+
+    let response = get(build_router(state.clone()), "/x").await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!state.telemetry.snapshot().histograms.contains_key(&key));
+    axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let duration_ms = state.telemetry.snapshot().histograms[&key].sum;
+    assert!(duration_ms >= 300.0, "{} ms", duration_ms);
+}
+
+/// Collects the lines a subscriber writes.
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl CapturedLogs {
+    /// Sets a subscriber writing JSON lines here as the default for the current thread, which
+    /// current-thread tests run every task on.
+    fn install(&self) -> tracing::subscriber::DefaultGuard {
+        let writer = self.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .flatten_event(true)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::set_default(subscriber)
+    }
+
+    fn access_events(&self) -> Vec<serde_json::Value> {
+        let lines = String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+        lines
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .filter(|event| event["target"] == "access")
+            .collect()
+    }
+}
+
+fn with_access_events(mut state: ApplicationState) -> ApplicationState {
+    state.access_log = AccessLog::new(&config::AccessLogConfig {
+        events: true,
+        ..Default::default()
+    });
+    state
+}
+
+#[tokio::test]
+async fn test_access_events_log_buffered_requests() {
+    use tower::ServiceExt;
+
+    let logs = CapturedLogs::default();
+    let _guard = logs.install();
+    let state = test_state_with(Config::default(), MockInvoker::new(vec![ok_output("hello")]));
+    let app = build_router(with_access_events(state));
+
+    let request = axum::http::Request::builder()
+        .method("POST")
+        .uri("/orders?id=1")
+        .body(Body::from("abc"))
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert!(logs.access_events().is_empty(), "logged before the body was sent");
+    axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+    let events = logs.access_events();
+    assert_eq!(events.len(), 1);
+    let event = &events[0];
+    assert_eq!(event["level"], "INFO");
+    assert_eq!(event["method"], "POST");
+    assert_eq!(event["path"], "/orders");
+    assert_eq!(event["pattern"], "");
+    assert_eq!(event["rule"], "fallback");
+    assert_eq!(event["status"], 200);
+    assert_eq!(event["request_bytes"], 3);
+    assert_eq!(event["response_bytes"], 5);
+    assert_eq!(event["lambda_request_id"], "mock-request-id");
+    assert_eq!(event["streamed"], false);
+    assert_eq!(event["client_disconnected"], false);
+    assert!(event["duration_ms"].is_f64());
+    assert!(event["upstream_ms"].is_f64());
+    assert!(event.get("client_ip").is_none());
+}
+
+#[tokio::test]
+async fn test_access_events_log_streams_when_they_end() {
+    let logs = CapturedLogs::default();
+    let _guard = logs.install();
+    let streams = vec![
+        Ok(delayed_stream(vec![(0, STREAM_PRELUDE), (300, b"body")])),
+        Ok(delayed_stream(vec![(0, STREAM_PRELUDE), (300, b"body")])),
+    ];
+    let invoker = MockInvoker::with_streams(streams, Duration::ZERO);
+    let app = build_router(with_access_events(timeout_state(invoker)));
+
+    let response = get(app.clone(), "/x").await;
+    axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    // The client leaves as soon as it has the head of the second response
+    drop(get(app, "/x").await);
+
+    let events = logs.access_events();
+    assert_eq!(events.len(), 2);
+    let (finished, left) = (&events[0], &events[1]);
+    assert_eq!(finished["pattern"], "/*rest");
+    assert_eq!(finished["streamed"], true);
+    assert_eq!(finished["response_bytes"], 4);
+    assert_eq!(finished["client_disconnected"], false);
+    assert_eq!(finished["lambda_request_id"], "mock-stream-request-id");
+    assert!(finished["duration_ms"].as_f64().unwrap() >= 300.0, "{}", finished);
+    assert_eq!(left["streamed"], true);
+    assert_eq!(left["response_bytes"], 0);
+    assert_eq!(left["client_disconnected"], true);
+    assert!(left["duration_ms"].as_f64().unwrap() < 300.0, "{}", left);
+}
+
+#[tokio::test]
+async fn test_stream_format_auto_corrected_after_first_mismatch() {
+    let prelude_response = || delayed_stream(vec![(0, STREAM_PRELUDE), (0, b"body")]);
+    let invoker = MockInvoker::with_streams(vec![Ok(prelude_response()), Ok(prelude_response())], Duration::ZERO);
+    let config = Config {
+        lambda_invoke_mode: LambdaInvokeMode::ResponseStream,
+        targets: BTreeMap::from([(
+            "/*rest".to_string(),
+            Target {
+                stream_format: config::StreamFormat::Raw,
+                auto_correct_stream_format: true,
+                ..Default::default()
+            },
+        )]),
+        ..Default::default()
+    };
+    let app = build_router(test_state_with(config, invoker));
+
+    // The first response is read as configured, the next one in the detected format
+    let first = get(app.clone(), "/x").await;
+    assert_eq!(first.headers()["content-type"], "application/octet-stream");
+    let second = get(app, "/x").await;
+    assert_eq!(second.headers()["content-type"], "text/plain");
+    let body = axum::body::to_bytes(second.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, "body");
+}
+
+#[tokio::test]
+async fn test_download_resumed_after_client_disconnect() {
+    use tower::ServiceExt;
+
+    let spool_dir = tempfile::tempdir().unwrap();
+    let stream = delayed_stream(vec![(0, STREAM_PRELUDE), (0, b"0123"), (100, b"456789")]);
+    let invoker = MockInvoker::with_streams(vec![Ok(stream)], Duration::ZERO);
+    let config = Config {
+        lambda_invoke_mode: LambdaInvokeMode::ResponseStream,
+        spool: config::SpoolConfig {
+            dir: Some(spool_dir.path().to_path_buf()),
+            ..Default::default()
+        },
+        targets: BTreeMap::from([(
+            "/*rest".to_string(),
+            Target {
+                resumable_downloads: true,
+                allow_response_headers: vec!["content-type".to_string()],
+                ..Default::default()
+            },
+        )]),
+        ..Default::default()
+    };
+    let app = build_router(test_state_with(config, invoker.clone()));
+
+    // The client goes away before the stream ends; the gateway reads it to the end anyway
+    let response = get(app.clone(), "/export").await;
+    let token = response.headers()[spool::DOWNLOAD_TOKEN_HEADER].clone();
+    drop(response);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let request = axum::http::Request::builder()
+        .uri("/export")
+        .header(spool::DOWNLOAD_TOKEN_HEADER, token)
+        .header("range", "bytes=4-")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.headers()["content-range"], "bytes 4-9/10");
+    assert_eq!(response.headers()["content-type"], "text/plain");
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, "456789");
+    assert_eq!(invoker.calls(), 1);
+}
+
+#[tokio::test]
+async fn test_streaming_dispatch_timeout() {
+    let stream = delayed_stream(vec![(0, STREAM_PRELUDE)]);
+    let invoker = MockInvoker::with_streams(vec![Ok(stream)], Duration::from_millis(500));
+
+    let (status, phase, body) = error_of(timeout_state(invoker), get_request("/x")).await;
+    assert_eq!((status, phase), (StatusCode::GATEWAY_TIMEOUT, ErrorPhase::Invoke));
+    assert_eq!(body["error_code"], "invoke_timeout");
+}
+
+#[tokio::test]
+async fn test_streaming_first_event_timeout() {
+    let stream = delayed_stream(vec![(500, STREAM_PRELUDE)]);
+    let invoker = MockInvoker::with_streams(vec![Ok(stream)], Duration::ZERO);
+
+    let (status, phase, body) = error_of(timeout_state(invoker), get_request("/x")).await;
+    assert_eq!((status, phase), (StatusCode::GATEWAY_TIMEOUT, ErrorPhase::Invoke));
+    assert_eq!(body["error_code"], "invoke_timeout");
+}
+
+#[tokio::test]
+async fn test_streaming_prelude_timeout() {
+    let stream = delayed_stream(vec![(0, &STREAM_PRELUDE[..10]), (500, &STREAM_PRELUDE[10..])]);
+    let invoker = MockInvoker::with_streams(vec![Ok(stream)], Duration::ZERO);
+
+    let (status, phase, body) = error_of(timeout_state(invoker), get_request("/x")).await;
+    assert_eq!((status, phase), (StatusCode::GATEWAY_TIMEOUT, ErrorPhase::Upstream));
+    assert_eq!(body["error_code"], "prelude_timeout");
+}
+
+#[tokio::test]
+async fn test_buffered_invoke_timeout() {
+    let config = |invoke_timeout_ms| Config {
+        targets: BTreeMap::from([(
+            "/*rest".to_string(),
+            Target {
+                invoke_timeout_ms,
+                ..Default::default()
+            },
+        )]),
+        ..Default::default()
+    };
+    let slow = || MockInvoker::with_delayed_results(vec![ok_output("late")], Duration::from_millis(300));
+
+    let started = Instant::now();
+    let (status, phase, body) = error_of(test_state_with(config(Some(100)), slow()), get_request("/x")).await;
+    assert_eq!((status, phase), (StatusCode::GATEWAY_TIMEOUT, ErrorPhase::Invoke));
+    assert_eq!(body["error_code"], "invoke_timeout");
+    assert!(started.elapsed() < Duration::from_millis(300), "{:?}", started.elapsed());
+
+    let response = get(build_router(test_state_with(config(None), slow())), "/x").await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_function_list_round_robin() {
+    let mut config = Config {
+        debug_headers: true,
+        ..Default::default()
+    };
+    config.targets.insert(
+        "/*rest".to_string(),
+        Target {
+            function: Some(config::FunctionRef::Many(vec!["fn-a".to_string(), "fn-b".to_string()])),
+            ..Default::default()
+        },
+    );
+    let invoker = MockInvoker::new(vec![]);
+    let state = test_state_with(config, invoker.clone());
+
+    let mut upstreams = Vec::new();
+    for _ in 0..4 {
+        let response = get(build_router(state.clone()), "/x").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        upstreams.push(response.headers()["x-gateway-upstream"].to_str().unwrap().to_string());
+    }
+
+    assert_eq!(upstreams, ["fn-a", "fn-b", "fn-a", "fn-b"]);
+    let invoked: Vec<String> = invoker.requests().into_iter().map(|r| r.function_name).collect();
+    assert_eq!(invoked, upstreams);
+    assert_eq!(state.balancer.in_flight("fn-a"), 0);
+}
+
+fn keyed_request(method: &str, uri: &str, api_key: &str, body: impl Into<Body>) -> axum::http::Request<Body> {
+    axum::http::Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("x-api-key", api_key)
+        .body(body.into())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_admin_flag_override_enables_debug_headers_per_key() {
+    use tower::ServiceExt;
+
+    let config = Config {
+        auth_mode: config::AuthMode::ApiKey,
+        api_keys: HashSet::from(["ops-key".to_string(), "app-key".to_string()]),
+        admin_api_keys: HashSet::from(["admin-key".to_string()]),
+        ..Default::default()
+    };
+    let app = build_router(test_state_with(config, MockInvoker::new(vec![])));
+    let send = |request| app.clone().oneshot(request);
+    let debug_header = |response: &Response| response.headers().contains_key("x-gateway-upstream");
+
+    let response = send(keyed_request("GET", "/admin/flags", "ops-key", "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = send(keyed_request("GET", "/admin/flags", "admin-key", "")).await.unwrap();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(report["debug_headers"]["enabled"], false);
+
+    let change = format!(r#"{{"enabled": true, "allowed_key_ids": ["{}"]}}"#, flags::key_id("ops-key"));
+    let response = send(keyed_request("PUT", "/admin/flags/debug_headers", "admin-key", change)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(debug_header(&send(keyed_request("GET", "/x", "ops-key", "")).await.unwrap()));
+    assert!(!debug_header(&send(keyed_request("GET", "/x", "app-key", "")).await.unwrap()));
+
+    let invalid = r#"{"allowed_cidrs": ["10.0.0.0/99"]}"#;
+    let response = send(keyed_request("PUT", "/admin/flags/debug_headers", "admin-key", invalid)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert!(debug_header(&send(keyed_request("GET", "/x", "ops-key", "")).await.unwrap()));
+
+    let response = send(keyed_request("DELETE", "/admin/flags/debug_headers", "admin-key", "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert!(!debug_header(&send(keyed_request("GET", "/x", "ops-key", "")).await.unwrap()));
+}
+
+#[tokio::test]
+async fn test_failed_authentication_bans_the_address() {
+    use tower::ServiceExt;
+
+    let config = Config {
+        auth_mode: config::AuthMode::ApiKey,
+        api_keys: HashSet::from(["app-key".to_string()]),
+        auth_bans: Some(config::AuthBanConfig {
+            max_failures: 2,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let invoker = MockInvoker::new(vec![]);
+    let app = build_router(test_state_with(config, invoker.clone()));
+    let send = |client: &str, api_key: &str| {
+        let mut request = keyed_request("GET", "/x", api_key, "");
+        let addr: SocketAddr = client.parse().unwrap();
+        request.extensions_mut().insert(ConnectInfo(addr));
+        app.clone().oneshot(request)
+    };
+
+    for _ in 0..2 {
+        assert_eq!(send("192.0.2.1:40000", "guess").await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    }
+    // Even the right key is turned away until the ban ends
+    let response = send("192.0.2.1:40000", "app-key").await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(response.headers()["retry-after"], "600");
+    assert_eq!(send("192.0.2.2:40000", "app-key").await.unwrap().status(), StatusCode::OK);
+    assert_eq!(invoker.calls(), 1);
+}
+
+#[tokio::test]
+async fn test_admin_endpoints_disabled_without_admin_keys() {
+    let app = build_router(test_state_with(Config::default(), MockInvoker::new(vec![])));
+    let response = get(app, "/admin/flags").await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_signed_urls_serve_requests_without_keys() {
+    use tower::ServiceExt;
+
+    let signed_target = Target {
+        accept_signed_urls: true,
+        auth_context: true,
+        ..Default::default()
+    };
+    let config = Config {
+        auth_mode: config::AuthMode::ApiKey,
+        api_keys: HashSet::from(["app-key".to_string()]),
+        admin_api_keys: HashSet::from(["admin-key".to_string()]),
+        signed_urls: Some(config::SignedUrlConfig {
+            signing_key: "0123456789abcdef0123456789abcdef".to_string(),
+            ..Default::default()
+        }),
+        targets: BTreeMap::from([
+            ("/reports/*path".to_string(), signed_target),
+            ("/private/*path".to_string(), Target::default()),
+        ]),
+        ..Default::default()
+    };
+    let invoker = MockInvoker::new(vec![]);
+    let app = build_router(test_state_with(config, invoker.clone()));
+    let send = |request| app.clone().oneshot(request);
+    let mint = |body: &str| send(keyed_request("POST", "/admin/signed-urls", "admin-key", body.to_string()));
+
+    let response = mint(r#"{"path": "/reports/42?format=pdf", "max_uses": 1}"#).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let signed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let url = signed["url"].as_str().unwrap().to_string();
+
+    // The function sees neither the gateway's parameters nor a key
+    let response = get(app.clone(), &url).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let event: serde_json::Value = serde_json::from_str(&invoker.requests()[0].payload).unwrap();
+    assert!(event.to_string().contains("pdf"));
+    assert!(!event.to_string().contains("x-gateway-signature"));
+    assert_eq!(event["headers"]["x-gateway-auth-method"], "signed_url");
+
+    // Used up, then tampered with
+    assert_eq!(get(app.clone(), &url).await.status(), StatusCode::FORBIDDEN);
+    let tampered = url.replace("/reports/42", "/reports/43");
+    assert_eq!(get(app.clone(), &tampered).await.status(), StatusCode::FORBIDDEN);
+    // Keys still work, and the URL alone is only a key for its target
+    let response = send(keyed_request("GET", &tampered, "app-key", "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(get(app.clone(), "/reports/42").await.status(), StatusCode::UNAUTHORIZED);
+
+    let response = mint(r#"{"path": "/private/42"}"#).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = send(keyed_request("POST", "/admin/signed-urls", "app-key", "{}")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_auth_context_fills_the_apigw_authorizer() {
+    use aws_lambda_events::apigw::{ApiGatewayProxyRequest, ApiGatewayV2httpRequest};
+    use tower::ServiceExt;
+
+    let target = |payload| Target {
+        auth_context: true,
+        payload: Some(payload),
+        ..Default::default()
+    };
+    let config = Config {
+        auth_mode: config::AuthMode::ApiKey,
+        api_keys: HashSet::from(["app-key".to_string()]),
+        targets: BTreeMap::from([
+            ("/v1".to_string(), target(PayloadMode::ApiGatewayV1)),
+            ("/v2".to_string(), target(PayloadMode::ApiGatewayV2)),
+        ]),
+        ..Default::default()
+    };
+    let invoker = MockInvoker::new(vec![]);
+    let app = build_router(test_state_with(config, invoker.clone()));
+    for path in ["/v1", "/v2"] {
+        let mut request = keyed_request("GET", path, "app-key", "");
+        request
+            .headers_mut()
+            .insert("x-gateway-auth-key-id", HeaderValue::from_static("0000000000000000"));
+        assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::OK);
+    }
+
+    // The authorizer replaces the headers, including the ones the client made up
+    let requests = invoker.requests();
+    for request in &requests {
+        assert!(!request.payload.contains("x-gateway-auth-"), "{}", request.payload);
+        assert!(!request.payload.contains("app-key"), "{}", request.payload);
+    }
+    let v1: ApiGatewayProxyRequest = serde_json::from_str(&requests[0].payload).unwrap();
+    let fields = &v1.request_context.authorizer.fields;
+    assert_eq!(fields["authMethod"], "api_key");
+    assert_eq!(fields["keyId"], flags::key_id("app-key"));
+    let v2: ApiGatewayV2httpRequest = serde_json::from_str(&requests[1].payload).unwrap();
+    let fields = &v2.request_context.authorizer.unwrap().fields;
+    assert_eq!(fields["authMethod"], "api_key");
+    assert_eq!(fields["keyId"], flags::key_id("app-key"));
+}
+
+#[tokio::test]
+async fn test_metrics_need_no_api_key() {
+    use tower::ServiceExt;
+
+    let config = Config {
+        auth_mode: config::AuthMode::ApiKey,
+        api_keys: HashSet::from(["key".to_string()]),
+        ..Default::default()
+    };
+    let invoker = MockInvoker::new(vec![throttled(), ok_output("ok")]);
+    let app = build_router(test_state_with(config, invoker));
+    for expected in [StatusCode::TOO_MANY_REQUESTS, StatusCode::OK] {
+        let response = app.clone().oneshot(keyed_request("GET", "/x", "key", "")).await.unwrap();
+        assert_eq!(response.status(), expected);
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let response = get(app, "/metrics").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[CONTENT_TYPE], prometheus::CONTENT_TYPE);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let text = String::from_utf8(body.to_vec()).unwrap();
+    assert!(text.contains("invoke_errors_total{target=\"\",class=\"throttle\"} 1\n"), "{}", text);
+    let ok = r#"requests_total{method="GET",namespace="",target="",status="200",phase=""} 1"#;
+    assert!(text.contains(ok), "{}", text);
+    assert!(text.contains("# TYPE request_duration_ms histogram\n"), "{}", text);
+}
+
+#[tokio::test]
+async fn test_metrics_on_their_own_listener() {
+    let mut config = listeners_config();
+    // Not on listeners without admin
+    let state = test_state_with(config.clone(), MockInvoker::new(vec![]));
+    let response = get(build_listener_router(state.clone(), "external"), "/metrics").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = get(build_listener_router(state, "internal"), "/metrics").await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    config.metrics.addr = Some("127.0.0.1:9100".to_string());
+    let state = test_state_with(config, MockInvoker::new(vec![]));
+    let response = get(build_listener_router(state.clone(), "internal"), "/metrics").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = get(build_metrics_router(state), "/metrics").await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_flag_allowed_by_client_network() {
+    use tower::ServiceExt;
+
+    let flag = config::FeatureFlag {
+        enabled: true,
+        allowed_cidrs: vec!["10.0.0.0/8".to_string()],
+        ..Default::default()
+    };
+    let config = Config {
+        feature_flags: BTreeMap::from([(flags::DEBUG_HEADERS.to_string(), flag)]),
+        ..Default::default()
+    };
+    let app = build_router(test_state_with(config, MockInvoker::new(vec![])));
+    for (client, expected) in [("10.1.2.3:40000", true), ("192.0.2.1:40000", false)] {
+        let mut request = get_request("/x");
+        let addr: SocketAddr = client.parse().unwrap();
+        request.extensions_mut().insert(ConnectInfo(addr));
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.headers().contains_key("x-gateway-upstream"), expected, "{}", client);
+    }
+}
+
+#[tokio::test]
+async fn test_upstream_header_is_debug_gated() {
+    let response = get(build_router(test_state_with(Config::default(), MockInvoker::new(vec![]))), "/").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("x-gateway-upstream").is_none());
+}
+
+fn preencoded_request(api_key: &str, body: &'static str) -> axum::http::Request<Body> {
+    axum::http::Request::builder()
+        .method("POST")
+        .uri("/batch")
+        .header("x-api-key", api_key)
+        .header("content-type", "application/octet-stream")
+        .header(request::BODY_ENCODED_HEADER, "base64")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn trusted_encoding_config() -> Config {
+    Config {
+        auth_mode: config::AuthMode::ApiKey,
+        api_keys: HashSet::from(["batch-key".to_string(), "other-key".to_string()]),
+        trusted_body_encoding_keys: HashSet::from(["batch-key".to_string()]),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_preencoded_body_passed_verbatim() {
+    use tower::ServiceExt;
+
+    let invoker = MockInvoker::new(vec![]);
+    let app = build_router(test_state_with(trusted_encoding_config(), invoker.clone()));
+    let response = app.oneshot(preencoded_request("batch-key", "/wA=")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let event: serde_json::Value = serde_json::from_str(&invoker.requests()[0].payload).unwrap();
+    assert_eq!(event["isBase64Encoded"], true);
+    assert_eq!(event["body"], "/wA=");
+    assert!(event["headers"].get(request::BODY_ENCODED_HEADER).is_none());
+}
+
+#[tokio::test]
+async fn test_preencoded_body_must_be_base64() {
+    let invoker = MockInvoker::new(vec![]);
+    let state = test_state_with(trusted_encoding_config(), invoker.clone());
+
+    let (status, phase, body) = error_of(state, preencoded_request("batch-key", "not base64!")).await;
+    assert_eq!((status, phase), (StatusCode::BAD_REQUEST, ErrorPhase::Ingress));
+    assert_eq!(body["error_code"], "invalid_base64_body");
+    assert_eq!(invoker.calls(), 0);
+}
+
+#[tokio::test]
+async fn test_preencoded_body_rejected_for_untrusted_key() {
+    let invoker = MockInvoker::new(vec![]);
+    let state = test_state_with(trusted_encoding_config(), invoker.clone());
+
+    let (status, phase, body) = error_of(state, preencoded_request("other-key", "/wA=")).await;
+    assert_eq!((status, phase), (StatusCode::FORBIDDEN, ErrorPhase::Auth));
+    assert_eq!(body["error_code"], "body_encoding_not_allowed");
+    assert_eq!(invoker.calls(), 0);
+}
+
+fn buffer_stream_state(target: Target, invoker: Arc<MockInvoker>) -> ApplicationState {
+    let config = Config {
+        lambda_invoke_mode: LambdaInvokeMode::ResponseStream,
+        targets: BTreeMap::from([("/*rest".to_string(), target)]),
+        ..Default::default()
+    };
+    test_state_with(config, invoker)
+}
+
+#[tokio::test]
+async fn test_buffer_stream_sets_content_length() {
+    let stream = delayed_stream(vec![(0, STREAM_PRELUDE), (0, b"hello "), (10, b"world")]);
+    let invoker = MockInvoker::with_streams(vec![Ok(stream)], Duration::ZERO);
+    let target = Target {
+        buffer_stream: true,
+        ..Default::default()
+    };
+    let state = buffer_stream_state(target, invoker);
+
+    let response = get(build_router(state.clone()), "/x").await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-length"], "11");
+    assert_eq!(response.headers()["content-type"], "text/plain");
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, "hello world");
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let key = ("responses_by_mode_total", vec![("mode", "buffered_stream".to_string())]);
+    assert_eq!(state.telemetry.snapshot().counters[&key], 1);
+}
+
+#[tokio::test]
+async fn test_buffer_stream_over_limit_is_502() {
+    let stream = delayed_stream(vec![(0, STREAM_PRELUDE), (0, b"0123456789"), (0, b"0123456789")]);
+    let invoker = MockInvoker::with_streams(vec![Ok(stream)], Duration::ZERO);
+    let target = Target {
+        buffer_stream: true,
+        max_response_body_bytes: 15,
+        ..Default::default()
+    };
+
+    let (status, phase, body) = error_of(buffer_stream_state(target, invoker), get_request("/x")).await;
+    assert_eq!((status, phase), (StatusCode::BAD_GATEWAY, ErrorPhase::Upstream));
+    assert_eq!(body["error_code"], "response_too_large");
+}
+
+#[tokio::test]
+async fn test_buffer_stream_on_request_is_gated() {
+    let buffered_request = || {
+        axum::http::Request::builder()
+            .uri("/x")
+            .header(request::BUFFER_STREAM_HEADER, "true")
+            .body(Body::empty())
+            .unwrap()
+    };
+    let streams = || vec![Ok(delayed_stream(vec![(0, STREAM_PRELUDE), (0, b"body")]))];
+
+    for (allowed, expect_length) in [(false, false), (true, true)] {
+        use tower::ServiceExt;
+
+        let target = Target {
+            buffer_stream_on_request: allowed,
+            ..Default::default()
+        };
+        let state = buffer_stream_state(target, MockInvoker::with_streams(streams(), Duration::ZERO));
+        let response = build_router(state).oneshot(buffered_request()).await.unwrap();
+        assert_eq!(response.headers().get("content-length").is_some(), expect_length);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "body");
+    }
+}
+
+#[tokio::test]
+async fn test_stream_survives_reload_until_drain_deadline() {
+    let stream = delayed_stream(vec![(0, STREAM_PRELUDE), (0, b"a"), (100, b"b"), (2000, b"c")]);
+    let invoker = MockInvoker::with_streams(vec![Ok(stream)], Duration::ZERO);
+    let target = Target {
+        function: Some("streamer".into()),
+        ..Default::default()
+    };
+    let state = buffer_stream_state(target, invoker);
+    let mut reloaded = (*state.config()).clone();
+    reloaded.targets.clear();
+    reloaded.reload_drain_timeout_ms = 300;
+
+    let response = get(build_router(state.clone()), "/x").await;
+    let mut body = response.into_body().into_data_stream();
+    assert_eq!(body.next().await.unwrap().unwrap(), "a");
+
+    state.reload(reloaded);
+    let draining = state.targets.draining();
+    assert_eq!((draining[0].pattern.as_str(), draining[0].active_requests), ("/*rest", 1));
+
+    // Chunks keep flowing after the reload, until the drain deadline aborts the stream
+    let started = Instant::now();
+    assert_eq!(body.next().await.unwrap().unwrap(), "b");
+    assert!(body.next().await.unwrap().is_err());
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(250) && elapsed < Duration::from_millis(1500), "{:?}", elapsed);
+
+    drop(body);
+    assert!(state.targets.draining().is_empty());
+}
+
+#[tokio::test]
+async fn test_support_bundle_requires_key_and_redacts_config() {
+    use tower::ServiceExt;
+
+    let config = Config {
+        auth_mode: config::AuthMode::ApiKey,
+        api_keys: ["app-key".to_string()].into_iter().collect(),
+        admin_api_keys: ["admin-key".to_string()].into_iter().collect(),
+        ..Default::default()
+    };
+    let state = test_state_with(config, MockInvoker::new(vec![]));
+    let app = build_router(state);
+
+    assert_eq!(get(app.clone(), "/orders").await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(get(app.clone(), "/support-bundle").await.status(), StatusCode::UNAUTHORIZED);
+    // A key of the proxied targets is not an admin key
+    let request = keyed_request("GET", "/support-bundle", "app-key", "");
+    assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+
+    let request = axum::http::Request::builder()
+        .uri("/support-bundle")
+        .header("x-api-key", "admin-key")
+        .body(Body::empty())
+        .unwrap();
+    let resp = app.oneshot(request).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    assert!(!String::from_utf8_lossy(&body).contains("admin-key"));
+    assert!(!String::from_utf8_lossy(&body).contains("app-key"));
+    let bundle: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(bundle["config"]["api_keys"], support::REDACTED);
+    assert_eq!(bundle["config"]["admin_api_keys"], support::REDACTED);
+    assert_eq!(bundle["build"]["version"], env!("CARGO_PKG_VERSION"));
+    assert!(bundle["routes"].as_array().unwrap().iter().any(|r| r["path"] == "/support-bundle"));
+    let errors = bundle["recent_errors"].as_array().unwrap();
+    // The rejected bundle request never reaches the handler, so only the proxied request is recorded
+    assert_eq!(errors.len(), 1);
+    assert_eq!((errors[0]["path"].as_str(), errors[0]["error_code"].as_str()), (Some("/orders"), Some("unauthorized")));
+}
+
+#[tokio::test]
+async fn test_support_bundle_needs_an_admin_key_under_open_auth() {
+    use tower::ServiceExt;
+
+    // Without admin keys, the bundle is not served at all
+    let state = test_state_with(Config::default(), MockInvoker::new(vec![]));
+    let (status, phase, body) = error_of(state, get_request("/support-bundle")).await;
+    assert_eq!((status, phase), (StatusCode::FORBIDDEN, ErrorPhase::Auth));
+    assert_eq!(body["error_code"], "admin_disabled");
+
+    let config = Config {
+        admin_api_keys: ["admin-key".to_string()].into_iter().collect(),
+        ..Default::default()
+    };
+    let app = build_router(test_state_with(config, MockInvoker::new(vec![])));
+    assert_eq!(get(app.clone(), "/support-bundle").await.status(), StatusCode::UNAUTHORIZED);
+    let request = keyed_request("GET", "/support-bundle", "admin-key", "");
+    assert_eq!(app.oneshot(request).await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_status_needs_an_admin_key_and_an_admin_listener() {
+    use tower::ServiceExt;
+
+    let state = test_state_with(Config::default(), MockInvoker::new(vec![]));
+    let (status, _, body) = error_of(state, get_request("/status")).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["error_code"], "admin_disabled");
+
+    let state = test_state_with(listeners_config(), MockInvoker::new(vec![]));
+    let internal = build_listener_router(state.clone(), "internal");
+    assert_eq!(get(internal.clone(), "/status").await.status(), StatusCode::UNAUTHORIZED);
+    let request = keyed_request("GET", "/status", "admin-key", "");
+    assert_eq!(internal.oneshot(request).await.unwrap().status(), StatusCode::OK);
+    // Not on listeners without admin, whatever the key
+    let request = keyed_request("GET", "/status", "admin-key", "");
+    let external = build_listener_router(state, "external");
+    assert_eq!(external.oneshot(request).await.unwrap().status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_flight_recorder_file_is_encrypted() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("failed.log");
+    let key = base64::engine::general_purpose::STANDARD.encode([7; 32]);
+    let config = Config {
+        auth_mode: config::AuthMode::ApiKey,
+        api_keys: ["key".to_string()].into_iter().collect(),
+        body_storage: config::BodyStorageConfig {
+            encrypt_with: Some("2026-10".to_string()),
+            keys: BTreeMap::from([(
+                "2026-10".to_string(),
+                config::StorageKey {
+                    key: Some(key.clone()),
+                    env: None,
+                },
+            )]),
+        },
+        flight_recorder: config::FlightRecorderConfig {
+            path: Some(path.clone()),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let state = test_state_with(config, MockInvoker::new(vec![]));
+    let app = build_router(state.clone());
+    assert_eq!(get(app.clone(), "/orders/secret-id").await.status(), StatusCode::UNAUTHORIZED);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let data = std::fs::read(&path).unwrap();
+    assert!(!String::from_utf8_lossy(&data).contains("secret-id"));
+    let keys = BTreeMap::from([("2026-10".to_string(), body_store::cipher(&key).unwrap())]);
+    let records: Vec<_> = body_store::RecordReader::new(keys).records(&data).collect();
+    assert_eq!(records.len(), 1);
+    let record: serde_json::Value = serde_json::from_slice(records[0].as_ref().unwrap()).unwrap();
+    assert_eq!((record["path"].as_str(), record["status"].as_u64()), (Some("/orders/secret-id"), Some(401)));
+    // The keys never show in the support bundle
+    let bundle = serde_json::to_string(&support::redacted_config(&state.config())).unwrap();
+    assert!(!bundle.contains(&key), "{}", bundle);
+}
+
+#[tokio::test]
+async fn test_saturated_target_keeps_latency_bounded() {
+    let mut config = Config::default();
+    config.targets.insert(
+        "/*rest".to_string(),
+        Target {
+            max_concurrency: Some(2),
+            max_queue_depth: 2,
+            ..Default::default()
+        },
+    );
+    let invoker = MockInvoker::with_delay(Duration::from_millis(200));
+    let app = build_router(test_state_with(config, invoker.clone()));
+
+    let requests = (0..20).map(|_| {
+        let app = app.clone();
+        tokio::spawn(async move {
+            let started = Instant::now();
+            let resp = get(app, "/x").await;
+            let retry_after = resp.headers().get("retry-after").cloned();
+            (resp.status(), retry_after, started.elapsed())
+        })
+    });
+    let results = futures::future::join_all(requests).await;
+
+    let (mut served, mut rejected) = (0, 0);
+    for (status, retry_after, elapsed) in results.into_iter().map(Result::unwrap) {
+        match status {
+            StatusCode::OK => {
+                served += 1;
+                // Two rounds of service at most: in flight, or behind a full queue of two
+                assert!(elapsed < Duration::from_millis(600), "served after {:?}", elapsed);
+            }
+            StatusCode::TOO_MANY_REQUESTS => {
+                rejected += 1;
+                assert!(retry_after.is_some());
+                assert!(elapsed < Duration::from_millis(100), "rejected after {:?}", elapsed);
+            }
+            status => panic!("unexpected status {}", status),
+        }
+    }
+    assert_eq!((served, rejected), (4, 16));
+    assert_eq!(invoker.max_in_flight(), 2);
+}
+
+#[tokio::test]
+async fn test_concurrency_slot_released_on_client_disconnect() {
+    let yaml = "targets:\n  /*rest:\n    max_in_flight: 1\n    overflow: reject\n";
+    let config: Config = serde_yaml::from_str(yaml).unwrap();
+    let invoker = MockInvoker::with_delay(Duration::from_millis(200));
+    let app = build_router(test_state_with(config, invoker.clone()));
+
+    let abandoned = tokio::spawn(get(app.clone(), "/x"));
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let response = get(app.clone(), "/x").await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(response.headers().contains_key("retry-after"));
+
+    // The client goes away while its invoke is running
+    abandoned.abort();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(get(app, "/x").await.status(), StatusCode::OK);
+    assert_eq!(invoker.calls(), 2);
+}
+
+#[tokio::test]
+async fn test_buffered_multi_value_headers_keep_their_order() {
+    let payload = serde_json::json!({
+        "statusCode": 401,
+        "headers": {"Set-Cookie": "ignored=1", "Content-Type": "text/plain"},
+        "multiValueHeaders": {
+            "Set-Cookie": ["c=3", "a=1", "b=2"],
+            "WWW-Authenticate": ["Negotiate", "Basic realm=\"gateway\""]
+        },
+        "body": ""
+    });
+    let response = handle_buffered_response(payload.to_string().as_bytes(), false).await.unwrap();
+
+    let values = |name| {
+        response
+            .headers()
+            .get_all(name)
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(values("set-cookie"), vec!["c=3", "a=1", "b=2"]);
+    assert_eq!(values("www-authenticate"), vec!["Negotiate", "Basic realm=\"gateway\""]);
+    assert_eq!(values("content-type"), vec!["text/plain"]);
+    // Header names are case-insensitive and always sent in lowercase
+    assert!(response.headers().keys().all(|name| name.as_str() == name.as_str().to_ascii_lowercase()));
+}
+
+/// Serves the gateway on a loopback port, for tests that write raw HTTP/1.1.
+async fn serve_locally(state: ApplicationState) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = state.config().server.clone();
+    let app = build_router(state);
+    tokio::spawn(async move {
+        server::serve(listener, app, &config, server::Transport::Plain, std::future::pending()).await
+    });
+    addr
+}
+
+/// Writes `request` as is and returns the status code and body of the response.
+async fn raw_exchange(addr: SocketAddr, request: &str) -> (u16, String) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    let response = String::from_utf8(response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head[9..12].parse().unwrap(), body.to_string())
+}
+
+const FOLDED_REQUEST: &str = "GET /folded HTTP/1.1\r\nHost: a\r\nX-Long: one\r\n two\r\nConnection: close\r\n\r\n";
+
+#[tokio::test]
+async fn test_folded_header_rejected_on_the_wire() {
+    let invoker = MockInvoker::new(vec![]);
+    let addr = serve_locally(test_state_with(Config::default(), invoker.clone())).await;
+
+    let (status, body) = raw_exchange(addr, FOLDED_REQUEST).await;
+    assert_eq!(status, 400);
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["error_code"], "obs_fold_rejected");
+    assert_eq!(body["phase"], "ingress");
+    assert_eq!(invoker.calls(), 0);
+}
+
+#[tokio::test]
+async fn test_folded_header_normalized_on_the_wire() {
+    let mut config = Config::default();
+    config.server.obs_fold = config::ObsFold::Normalize;
+    let invoker = MockInvoker::new(vec![]);
+    let addr = serve_locally(test_state_with(config, invoker.clone())).await;
+
+    let (status, _) = raw_exchange(addr, FOLDED_REQUEST).await;
+    assert_eq!(status, 200);
+    let event: serde_json::Value = serde_json::from_str(&invoker.requests()[0].payload).unwrap();
+    assert_eq!(event["headers"]["x-long"], "one two");
+    assert!(event["headers"].get(server::OBS_FOLD_HEADER).is_none());
+}
+
+#[tokio::test]
+async fn test_oversized_header_value_names_the_header() {
+    let invoker = MockInvoker::new(vec![]);
+    let addr = serve_locally(test_state_with(Config::default(), invoker.clone())).await;
+
+    let value = "v".repeat(20 * 1024);
+    let request = format!("GET / HTTP/1.1\r\nHost: a\r\nX-Big: {}\r\nConnection: close\r\n\r\n", value);
+    let (status, body) = raw_exchange(addr, &request).await;
+    assert_eq!(status, 431);
+    assert!(!body.contains(&value));
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["error_code"], "header_value_too_large");
+    assert_eq!(body["message"], "The value of header x-big is longer than 16384 bytes");
+    assert_eq!(invoker.calls(), 0);
+}
+
+#[tokio::test]
+async fn test_request_head_over_max_header_bytes() {
+    let mut config = Config::default();
+    config.server.max_header_bytes = 8192;
+    config.server.max_header_value_bytes = 64 * 1024;
+    let invoker = MockInvoker::new(vec![]);
+    let addr = serve_locally(test_state_with(config, invoker.clone())).await;
+
+    let request = format!("GET / HTTP/1.1\r\nHost: a\r\nX-Big: {}\r\n\r\n", "v".repeat(9000));
+    let (status, _) = raw_exchange(addr, &request).await;
+    assert_eq!(status, 431);
+    assert_eq!(invoker.calls(), 0);
+
+    let request = format!("GET / HTTP/1.1\r\nHost: a\r\nX-Big: {}\r\nConnection: close\r\n\r\n", "v".repeat(7000));
+    assert_eq!(raw_exchange(addr, &request).await.0, 200);
+}
+
+fn apigw_v2_state(results: Vec<Result<BufferedOutput, InvokeError>>) -> (ApplicationState, Arc<MockInvoker>) {
+    let mut config = Config::default();
+    config.targets.insert(
+        "/items/:id".to_string(),
+        Target {
+            payload: Some(config::PayloadMode::ApiGatewayV2),
+            ..Default::default()
+        },
+    );
+    let invoker = MockInvoker::new(results);
+    (test_state_with(config, invoker.clone()), invoker)
+}
+
+#[tokio::test]
+async fn test_apigw_v2_get_round_trip() {
+    use tower::ServiceExt;
+
+    let payload = r#"{"statusCode": 201, "headers": {"x-id": "42", "Set-Cookie": "first=0"},
+        "cookies": ["a=1; Path=/", "b=2"], "body": "created"}"#;
+    let (state, invoker) = apigw_v2_state(vec![output(payload, None)]);
+    let request = axum::http::Request::builder()
+        .uri("/items/42?sort=asc&tag=x&tag=y")
+        .header("cookie", "session=abc")
+        .body(Body::empty())
+        .unwrap();
+    let response = build_router(state).oneshot(request).await.unwrap();
+
+    let event: serde_json::Value = serde_json::from_str(&invoker.requests()[0].payload).unwrap();
+    assert_eq!(event["version"], "2.0");
+    assert_eq!(event["rawPath"], "/items/42");
+    assert_eq!(event["rawQueryString"], "sort=asc&tag=x&tag=y");
+    assert_eq!(event["queryStringParameters"]["tag"], "x,y");
+    assert_eq!(event["pathParameters"]["id"], "42");
+    assert_eq!(event["cookies"], serde_json::json!(["session=abc"]));
+    assert!(event.get("httpMethod").is_none());
+
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(response.headers()["x-id"], "42");
+    let cookies: Vec<_> = response.headers().get_all("set-cookie").iter().collect();
+    assert_eq!(cookies, ["first=0", "a=1; Path=/", "b=2"]);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, "created");
+}
+
+#[tokio::test]
+async fn test_apigw_v2_post_binary_round_trip() {
+    use base64::Engine;
+    use tower::ServiceExt;
+
+    let bytes: Vec<u8> = (0..=255).rev().collect();
+    let encoded = base64::engine::general_purpose::STANDARD.encode(&bytes);
+    // Without a statusCode the response is a 200
+    let payload = serde_json::json!({"isBase64Encoded": true, "statusCode": null, "body": encoded});
+    let (state, invoker) = apigw_v2_state(vec![output(&payload.to_string(), None)]);
+    let request = axum::http::Request::builder()
+        .method("POST")
+        .uri("/items/7")
+        .header("content-type", "application/octet-stream")
+        .body(Body::from(bytes.clone()))
+        .unwrap();
+    let response = build_router(state).oneshot(request).await.unwrap();
+
+    let event: serde_json::Value = serde_json::from_str(&invoker.requests()[0].payload).unwrap();
+    assert_eq!(event["requestContext"]["http"]["method"], "POST");
+    assert_eq!(event["isBase64Encoded"], true);
+    assert_eq!(event["body"], encoded);
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, bytes);
+}
+
+#[tokio::test]
+async fn test_apigw_v2_plain_json_response_is_the_body() {
+    let response = handle_buffered_v2_response(br#"{"items": [1, 2]}"#, false).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "application/json");
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, r#"{"items": [1, 2]}"#);
+
+    let response = handle_buffered_v2_response(br#""just a string""#, false).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(handle_buffered_v2_response(b"not json", false).await.is_err());
+}
+
+#[tokio::test]
+async fn test_apigw_v1_round_trip() {
+    use tower::ServiceExt;
+
+    let payload = r#"{"headers": {"x-id": "42"}, "multiValueHeaders": {"set-cookie": ["a=1", "b=2"]},
+        "body": "listed"}"#;
+    let mut config = Config::default();
+    config.payload = config::PayloadMode::ApiGatewayV1;
+    config.targets.insert("/items/:id".to_string(), Target::default());
+    let invoker = MockInvoker::new(vec![output(payload, None)]);
+    let state = test_state_with(config, invoker.clone());
+    let request = axum::http::Request::builder()
+        .uri("/items/42?tag=x&tag=y")
+        .body(Body::empty())
+        .unwrap();
+    let response = build_router(state).oneshot(request).await.unwrap();
+
+    let event: serde_json::Value = serde_json::from_str(&invoker.requests()[0].payload).unwrap();
+    assert_eq!(event["resource"], "/items/{id}");
+    assert_eq!(event["path"], "/items/42");
+    assert_eq!(event["httpMethod"], "GET");
+    assert_eq!(event["queryStringParameters"]["tag"], "y");
+    assert_eq!(event["multiValueQueryStringParameters"]["tag"], serde_json::json!(["x", "y"]));
+    assert_eq!(event["pathParameters"]["id"], "42");
+    assert_eq!(event["body"], serde_json::Value::Null);
+
+    // Without a statusCode the response is a 200
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["x-id"], "42");
+    let cookies: Vec<_> = response.headers().get_all("set-cookie").iter().collect();
+    assert_eq!(cookies, ["a=1", "b=2"]);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, "listed");
+}
+
+#[tokio::test]
+async fn test_function_url_streaming_round_trip() {
+    let stream = delayed_stream(vec![(0, STREAM_PRELUDE), (0, b"body")]);
+    let invoker = MockInvoker::with_streams(vec![Ok(stream)], Duration::ZERO);
+    let config = Config {
+        lambda_invoke_mode: LambdaInvokeMode::ResponseStream,
+        payload: config::PayloadMode::FunctionUrl,
+        ..Default::default()
+    };
+    let response = get(build_router(test_state_with(config, invoker.clone())), "/stream?a=1").await;
+
+    let event: serde_json::Value = serde_json::from_str(&invoker.requests()[0].payload).unwrap();
+    assert_eq!(event["rawPath"], "/stream");
+    assert_eq!(event["requestContext"]["accountId"], "anonymous");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "text/plain");
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, "body");
+}
+
+#[tokio::test]
+async fn test_request_body_sizes_in_status() {
+    use tower::ServiceExt;
+
+    let mut config = Config::default();
+    config.admin_api_keys = HashSet::from(["admin-key".to_string()]);
+    config.targets.insert("/upload".to_string(), Target::default());
+    let app = build_router(test_state_with(config, MockInvoker::new(vec![])));
+    let bodies = [
+        ("application/json; charset=utf-8", vec![b'1'; 100]),
+        ("application/json", vec![b'2'; 3000]),
+        ("image/png", vec![0; 2000]),
+    ];
+    for (content_type, body) in bodies {
+        let request = axum::http::Request::builder()
+            .method("POST")
+            .uri("/upload")
+            .header("content-type", content_type)
+            .body(Body::from(body))
+            .unwrap();
+        assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::OK);
+    }
+    // Requests without a body are not recorded
+    get(app.clone(), "/upload").await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let response = app.oneshot(keyed_request("GET", "/status", "admin-key", "")).await.unwrap();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let sizes = status["request_body_bytes"].as_array().unwrap();
+    assert_eq!(sizes.len(), 2);
+    let json = sizes.iter().find(|s| s["content_family"] == "json").unwrap();
+    assert_eq!(json["target"], "/upload");
+    assert_eq!(json["base64_encoded"], false);
+    assert_eq!(json["count"], 2);
+    assert_eq!(json["p50"], 256.0);
+    assert_eq!(json["p99"], 4096.0 - 3072.0 * 0.02);
+    let image = sizes.iter().find(|s| s["content_family"] == "image").unwrap();
+    assert_eq!(image["base64_encoded"], true);
+    assert_eq!(image["count"], 1);
+}
+
+#[tokio::test]
+async fn test_alb_multi_value_round_trip() {
+    use tower::ServiceExt;
+
+    let payload = r#"{"statusCode": 200, "multiValueHeaders": {"Set-Cookie": ["a=1", "b=2; Path=/", "c=3"],
+        "content-type": ["text/plain"]}, "body": "ok"}"#;
+    let mut config = Config::default();
+    config.targets.insert(
+        "/search".to_string(),
+        Target {
+            multi_value: true,
+            ..Default::default()
+        },
+    );
+    let invoker = MockInvoker::new(vec![output(payload, None)]);
+    let request = axum::http::Request::builder()
+        .uri("/search?tag=a&tag=b")
+        .header("x-forwarded-for", "192.0.2.1")
+        .header("x-forwarded-for", "198.51.100.2")
+        .body(Body::empty())
+        .unwrap();
+    let response = build_router(test_state_with(config, invoker.clone()))
+        .oneshot(request)
+        .await
+        .unwrap();
+
+    let event: serde_json::Value = serde_json::from_str(&invoker.requests()[0].payload).unwrap();
+    assert_eq!(event["multiValueQueryStringParameters"]["tag"], serde_json::json!(["a", "b"]));
+    assert_eq!(
+        event["multiValueHeaders"]["x-forwarded-for"],
+        serde_json::json!(["192.0.2.1", "198.51.100.2"])
+    );
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let cookies: Vec<_> = response.headers().get_all("set-cookie").iter().collect();
+    assert_eq!(cookies, ["a=1", "b=2; Path=/", "c=3"]);
+}
+
+#[tokio::test]
+async fn test_raw_payload_round_trip() {
+    use base64::Engine as _;
+    use tower::ServiceExt;
+
+    let mut config = Config::default();
+    config.targets.insert(
+        "/rpc".to_string(),
+        Target {
+            payload: Some(config::PayloadMode::Raw),
+            ..Default::default()
+        },
+    );
+    let invoker = MockInvoker::new(vec![output(r#"{"statusCode": "not an HTTP response"}"#, None)]);
+    let app = build_router(test_state_with(config, invoker.clone()));
+    let body = r#"{ "b": 1,  "a": [2, 3] }"#;
+    let request = axum::http::Request::builder()
+        .method("POST")
+        .uri("/rpc?v=2")
+        .header("content-type", "application/json")
+        .body(Body::from(body))
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+
+    // The body is the payload byte for byte, and the request line travels in the client context
+    let invoked = &invoker.requests()[0];
+    assert_eq!(invoked.payload, body);
+    let context = base64::engine::general_purpose::STANDARD
+        .decode(invoked.client_context.as_ref().unwrap())
+        .unwrap();
+    let context: serde_json::Value = serde_json::from_slice(&context).unwrap();
+    assert_eq!(context["custom"]["method"], "POST");
+    assert_eq!(context["custom"]["path"], "/rpc");
+    assert_eq!(context["custom"]["query"], "v=2");
+    let headers: serde_json::Value = serde_json::from_str(context["custom"]["headers"].as_str().unwrap()).unwrap();
+    assert_eq!(headers["content-type"], "application/json");
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "application/json");
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, r#"{"statusCode": "not an HTTP response"}"#);
+
+    // Lambda only takes JSON payloads
+    let request = axum::http::Request::builder()
+        .method("POST")
+        .uri("/rpc")
+        .body(Body::from(vec![0xff, 0xfe]))
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(invoker.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn test_raw_payload_function_error() {
+    let mut config = Config::default();
+    config.targets.insert(
+        "/rpc".to_string(),
+        Target {
+            payload: Some(config::PayloadMode::Raw),
+            ..Default::default()
+        },
+    );
+    let invoker = MockInvoker::new(vec![output(r#"{"errorMessage": "boom"}"#, Some("Unhandled"))]);
+    let response = get(build_router(test_state_with(config, invoker.clone())), "/rpc").await;
+
+    assert_eq!(invoker.requests()[0].payload, "");
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn test_function_errors_mapped_to_status() {
+    let payload = r#"{"errorType": "TypeError", "errorMessage": "x is undefined", "stackTrace": ["at handler"]}"#;
+    let invoker = MockInvoker::new(vec![output(payload, Some("Unhandled")), output(payload, Some("Handled"))]);
+    let state = test_state_with(Config::default(), invoker);
+
+    // Opaque by default, whichever kind of error the function reported
+    for _ in ["Unhandled", "Handled"] {
+        let (status, phase, body) = error_of(state.clone(), get_request("/")).await;
+        assert_eq!((status, phase), (StatusCode::BAD_GATEWAY, ErrorPhase::Upstream));
+        assert_eq!(body["error_code"], "function_error");
+        assert_eq!(body["message"], "The function failed");
+        assert!(body.get("error_type").is_none());
+    }
+
+    let config = Config {
+        function_error_status: 500,
+        expose_function_errors: true,
+        ..Default::default()
+    };
+    let invoker = MockInvoker::new(vec![output(payload, Some("Handled"))]);
+    let (status, _, body) = error_of(test_state_with(config.clone(), invoker), get_request("/")).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(body["error_type"], "TypeError");
+    assert_eq!(body["error_message"], "x is undefined");
+
+    // A stream completing with an error before any payload is answered the same way
+    let failed = streaming::StreamError::Function {
+        kind: "Unhandled".to_string(),
+        details: payload.to_string(),
+    };
+    let stream = futures::stream::iter(vec![Err(failed)]).boxed();
+    let invoker = MockInvoker::with_streams(vec![Ok(stream)], Duration::ZERO);
+    let config = Config {
+        lambda_invoke_mode: LambdaInvokeMode::ResponseStream,
+        ..config
+    };
+    let (status, _, body) = error_of(test_state_with(config.clone(), invoker), get_request("/")).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(body["message"], "The function failed with TypeError: x is undefined");
+
+    // As is one failing partway through its prelude
+    let failed = streaming::StreamError::Function {
+        kind: "Unhandled".to_string(),
+        details: payload.to_string(),
+    };
+    let stream = futures::stream::iter(vec![Ok(Bytes::from_static(b"{\"statusCode\": 2")), Err(failed)]).boxed();
+    let invoker = MockInvoker::with_streams(vec![Ok(stream)], Duration::ZERO);
+    let (status, _, body) = error_of(test_state_with(config, invoker), get_request("/")).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(body["error_type"], "TypeError");
+}
+
+#[tokio::test]
+async fn test_raw_payload_streaming_round_trip() {
+    // Without prelude detection a body that looks like a prelude is passed through
+    let stream = delayed_stream(vec![(0, b"{\"statusCode\": 201}"), (0, b"\n")]);
+    let invoker = MockInvoker::with_streams(vec![Ok(stream)], Duration::ZERO);
+    let mut config = Config {
+        lambda_invoke_mode: LambdaInvokeMode::ResponseStream,
+        ..Default::default()
+    };
+    config.targets.insert(
+        "/events".to_string(),
+        Target {
+            payload: Some(config::PayloadMode::Raw),
+            stream_format: config::StreamFormat::Raw,
+            raw_content_type: Some("application/x-ndjson".to_string()),
+            ..Default::default()
+        },
+    );
+    let response = get(build_router(test_state_with(config, invoker.clone())), "/events").await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, "{\"statusCode\": 201}\n");
+}
+
+#[tokio::test]
+async fn test_unmatched_not_found_routes_by_target() {
+    use tower::ServiceExt;
+
+    let target = |function: &str, key: &str| Target {
+        function: Some(function.into()),
+        auth: Some(config::AuthMode::ApiKey),
+        api_keys: Some(HashSet::from([key.to_string()])),
+        ..Default::default()
+    };
+    let config = Config {
+        unmatched: config::UnmatchedRoute::NotFound,
+        targets: BTreeMap::from([
+            ("/api/*rest".to_string(), target("api-function", "api-key")),
+            ("/api/v2/*rest".to_string(), target("api-v2-function", "api-key")),
+            ("/admin/*rest".to_string(), target("admin-function", "admin-key")),
+        ]),
+        ..Default::default()
+    };
+    let invoker = MockInvoker::new(vec![]);
+    let app = build_router(test_state_with(config, invoker.clone()));
+    let send = |uri: &str, key: &str| {
+        let request = axum::http::Request::builder()
+            .uri(uri)
+            .header("x-api-key", key)
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(request)
+    };
+
+    assert_eq!(send("/api/orders", "api-key").await.unwrap().status(), StatusCode::OK);
+    assert_eq!(send("/api/v2/orders", "api-key").await.unwrap().status(), StatusCode::OK);
+    assert_eq!(send("/admin/users", "api-key").await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(send("/admin/users", "admin-key").await.unwrap().status(), StatusCode::OK);
+    let functions: Vec<_> = invoker.requests().into_iter().map(|r| r.function_name).collect();
+    assert_eq!(functions, ["api-function", "api-v2-function", "admin-function"]);
+
+    for uri in ["/", "/other", "/apix"] {
+        let response = send(uri, "api-key").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error["error_code"], "no_route");
+    }
+    assert_eq!(invoker.requests().len(), 3);
+}
+
+#[tokio::test]
+async fn test_body_match_routes_by_json_body() {
+    use tower::ServiceExt;
+
+    let function = |name: &str| Target {
+        function: Some(name.into()),
+        ..Default::default()
+    };
+    let config = Config {
+        targets: BTreeMap::from([
+            (
+                "/events/:source".to_string(),
+                Target {
+                    body_match: Some(config::BodyMatch {
+                        body_json: vec![config::BodyJsonRule {
+                            pointer: "/type".to_string(),
+                            equals: Some(serde_json::json!("order.created")),
+                            regex: None,
+                            target: "orders".to_string(),
+                        }],
+                        fallback: "events".to_string(),
+                        max_body_bytes: 1024,
+                        conditions: vec![],
+                    }),
+                    ..Default::default()
+                },
+            ),
+            ("orders".to_string(), function("orders-function")),
+            ("events".to_string(), function("events-function")),
+        ]),
+        ..Default::default()
+    };
+    let invoker = MockInvoker::new(vec![]);
+    let app = build_router(test_state_with(config, invoker.clone()));
+    let send = |content_type: &str, body: String| {
+        let request = axum::http::Request::post("/events/shop")
+            .header("content-type", content_type)
+            .body(Body::from(body))
+            .unwrap();
+        app.clone().oneshot(request)
+    };
+
+    let order = r#"{"type": "order.created", "id": 1}"#.to_string();
+    let oversized = format!(r#"{{"type": "order.created", "pad": "{}"}}"#, "x".repeat(1024));
+    let cases = [
+        ("application/json", order.clone(), RouteRule::BodyJson),
+        ("application/json", r#"{"type": "order.paid"}"#.to_string(), RouteRule::BodyFallback),
+        ("text/plain", order.clone(), RouteRule::BodyFallback),
+        ("application/json", oversized, RouteRule::BodyFallback),
+    ];
+    for (content_type, body, rule) in cases {
+        let response = send(content_type, body).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.extensions().get::<MatchedRoute>().unwrap().rule, rule);
+    }
+    let requests = invoker.requests();
+    let functions: Vec<_> = requests.iter().map(|r| r.function_name.as_str()).collect();
+    assert_eq!(functions, ["orders-function", "events-function", "events-function", "events-function"]);
+    // The selected target gets the body as sent
+    let event: serde_json::Value = serde_json::from_str(&requests[0].payload).unwrap();
+    assert_eq!(event["body"], order);
+
+    // Targets keyed without any '/' are not reachable by path
+    let response = get(app, "/orders").await;
+    assert_eq!(response.extensions().get::<MatchedRoute>().unwrap().rule, RouteRule::Fallback);
+}
+
+#[tokio::test]
+async fn test_body_match_stays_within_the_listener_targets() {
+    use tower::ServiceExt;
+
+    let function = |name: &str| Target {
+        function: Some(name.into()),
+        ..Default::default()
+    };
+    let config = Config {
+        targets: BTreeMap::from([
+            (
+                "/events".to_string(),
+                Target {
+                    body_match: Some(config::BodyMatch {
+                        body_json: vec![config::BodyJsonRule {
+                            pointer: "/type".to_string(),
+                            equals: Some(serde_json::json!("order.created")),
+                            regex: None,
+                            target: "orders".to_string(),
+                        }],
+                        fallback: "events".to_string(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            ),
+            ("orders".to_string(), function("orders-function")),
+            ("events".to_string(), function("events-function")),
+        ]),
+        listeners: BTreeMap::from([(
+            "external".to_string(),
+            config::ListenerConfig {
+                targets: vec!["/events".to_string(), "events".to_string()],
+                ..Default::default()
+            },
+        )]),
+        ..Default::default()
+    };
+    let invoker = MockInvoker::new(vec![]);
+    let app = build_listener_router(test_state_with(config, invoker.clone()), "external");
+    let send = |body: &'static str| {
+        let request = axum::http::Request::post("/events")
+            .header("content-type", "application/json")
+            .body(Body::from(body))
+            .unwrap();
+        app.clone().oneshot(request)
+    };
+
+    // The body names a target this listener does not serve
+    let response = send(r#"{"type": "order.created"}"#).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = send(r#"{"type": "order.paid"}"#).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let requests = invoker.requests();
+    let functions: Vec<_> = requests.iter().map(|r| r.function_name.as_str()).collect();
+    assert_eq!(functions, ["events-function"]);
+}
+
+#[tokio::test]
+async fn test_match_conditions_route_before_body_rules() {
+    use tower::ServiceExt;
+
+    let function = |name: &str| Target {
+        function: Some(name.into()),
+        ..Default::default()
+    };
+    let config = Config {
+        targets: BTreeMap::from([
+            (
+                "/events/*rest".to_string(),
+                Target {
+                    body_match: Some(config::BodyMatch {
+                        conditions: vec![config::ConditionRule {
+                            when: config::Condition::new(
+                                r#"lower(header("x-tier")) == "gold" && starts_with(path, "/events/shop")"#,
+                            ),
+                            target: "priority".to_string(),
+                        }],
+                        body_json: vec![config::BodyJsonRule {
+                            pointer: "/type".to_string(),
+                            equals: Some(serde_json::json!("order.created")),
+                            regex: None,
+                            target: "orders".to_string(),
+                        }],
+                        fallback: "events".to_string(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            ),
+            ("priority".to_string(), function("priority-function")),
+            ("orders".to_string(), function("orders-function")),
+            ("events".to_string(), function("events-function")),
+        ]),
+        ..Default::default()
+    };
+    let invoker = MockInvoker::new(vec![]);
+    let app = build_router(test_state_with(config, invoker.clone()));
+    let order = r#"{"type": "order.created"}"#;
+    let cases = [
+        ("/events/shop%2Dde", Some("GOLD"), RouteRule::Condition, "priority-function"),
+        ("/events/shop", Some("silver"), RouteRule::BodyJson, "orders-function"),
+        ("/events/blog", Some("gold"), RouteRule::BodyJson, "orders-function"),
+        ("/events/shop", None, RouteRule::BodyJson, "orders-function"),
+    ];
+    for (i, (uri, tier, rule, function)) in cases.into_iter().enumerate() {
+        let mut request = axum::http::Request::post(uri).header("content-type", "application/json");
+        if let Some(tier) = tier {
+            request = request.header("x-tier", tier);
+        }
+        let response = app.clone().oneshot(request.body(Body::from(order)).unwrap()).await.unwrap();
+        assert_eq!(response.extensions().get::<MatchedRoute>().unwrap().rule, rule, "{}", uri);
+        assert_eq!(invoker.requests()[i].function_name, function, "{}", uri);
+    }
+}
+
+#[tokio::test]
+async fn test_host_routing_picks_target_by_host_header() {
+    use tower::ServiceExt;
+
+    let on_host = |host: &str, function: &str| Target {
+        host: Some(host.to_string()),
+        function: Some(function.into()),
+        payload: Some(config::PayloadMode::ApiGatewayV2),
+        ..Default::default()
+    };
+    let config = Config {
+        lambda_function_name: "default-function".to_string(),
+        targets: BTreeMap::from([
+            ("foo/*rest".to_string(), on_host("api.foo.com", "foo-function")),
+            ("bar/*rest".to_string(), on_host("*.bar.com", "bar-function")),
+            ("bar-eu/*rest".to_string(), on_host("*.eu.bar.com", "bar-eu-function")),
+        ]),
+        ..Default::default()
+    };
+    let invoker = MockInvoker::new(vec![]);
+    let app = build_router(test_state_with(config, invoker.clone()));
+    let send = |host: Option<&str>| {
+        let mut request = axum::http::Request::get("/orders/1");
+        if let Some(host) = host {
+            request = request.header("host", host);
+        }
+        app.clone().oneshot(request.body(Body::empty()).unwrap())
+    };
+
+    // Plain HTTP without SNI: only the Host header tells the virtual hosts apart. HTTP/1.0
+    // requests may carry no Host at all and go to the targets for any host
+    for host in [Some("api.foo.com"), Some("www.bar.com"), Some("api.eu.bar.com:8000"), Some("x.com"), None] {
+        assert_eq!(send(host).await.unwrap().status(), StatusCode::OK);
+    }
+    let requests = invoker.requests();
+    let functions: Vec<_> = requests.iter().map(|r| r.function_name.as_str()).collect();
+    assert_eq!(
+        functions,
+        ["foo-function", "bar-function", "bar-eu-function", "default-function", "default-function"]
+    );
+
+    let event: serde_json::Value = serde_json::from_str(&requests[2].payload).unwrap();
+    assert_eq!(event["requestContext"]["gatewayTarget"], "bar-eu/*rest");
+    assert_eq!(event["requestContext"]["gatewayHost"], "*.eu.bar.com");
+    assert_eq!(event["requestContext"]["domainName"], "api.eu.bar.com");
+    let event: serde_json::Value = serde_json::from_str(&requests[3].payload).unwrap();
+    assert!(event["requestContext"].get("gatewayHost").is_none());
+}
+
+#[tokio::test]
+async fn test_strip_prefix_forwards_the_rest_of_the_path() {
+    use tower::ServiceExt;
+
+    let config = Config {
+        lambda_function_name: "default-function".to_string(),
+        targets: BTreeMap::from([(
+            "/svc/orders/*rest".to_string(),
+            Target {
+                strip_prefix: true,
+                ..Default::default()
+            },
+        )]),
+        ..Default::default()
+    };
+    let invoker = MockInvoker::new(vec![]);
+    let app = build_router(test_state_with(config, invoker.clone()));
+    let request = axum::http::Request::get("/svc/orders/list%20all?page=2")
+        .header("x-forwarded-path", "/spoofed")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::OK);
+    assert_eq!(get(app, "/svc/orders/").await.status(), StatusCode::OK);
+
+    let requests = invoker.requests();
+    let event: serde_json::Value = serde_json::from_str(&requests[0].payload).unwrap();
+    assert_eq!(event["path"], "/list all");
+    assert_eq!(event["queryStringParameters"]["page"], "2");
+    assert_eq!(event["headers"]["x-forwarded-path"], "/svc/orders/list%20all");
+    let event: serde_json::Value = serde_json::from_str(&requests[1].payload).unwrap();
+    assert_eq!(event["path"], "/");
+}
+
+#[tokio::test]
+async fn test_method_not_allowed_before_auth_and_invoke() {
+    use tower::ServiceExt;
+
+    let config = Config {
+        lambda_function_name: "default-function".to_string(),
+        auth_mode: config::AuthMode::ApiKey,
+        api_keys: HashSet::from(["key".to_string()]),
+        targets: BTreeMap::from([(
+            "/docs/*rest".to_string(),
+            Target {
+                methods: Some(vec!["get".to_string(), "head".to_string()]),
+                ..Default::default()
+            },
+        )]),
+        ..Default::default()
+    };
+    let invoker = MockInvoker::new(vec![]);
+    let app = build_router(test_state_with(config, invoker.clone()));
+    let send = |method: Method| {
+        let request = axum::http::Request::builder()
+            .method(method)
+            .uri("/docs/index.html")
+            .header("x-api-key", "key")
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(request)
+    };
+
+    // Rejected without a key: the method is checked before auth
+    let request = axum::http::Request::post("/docs/index.html").body(Body::empty()).unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(response.headers()["allow"], "GET, HEAD");
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error_code"], "method_not_allowed");
+    assert_eq!(send(Method::DELETE).await.unwrap().status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert!(invoker.requests().is_empty());
+
+    assert_eq!(send(Method::GET).await.unwrap().status(), StatusCode::OK);
+    assert_eq!(send(Method::HEAD).await.unwrap().status(), StatusCode::OK);
+    assert_eq!(invoker.requests().len(), 2);
+}
+
+#[tokio::test]
+async fn test_qualifier_from_target_or_trusted_header() {
+    use tower::ServiceExt;
+
+    let target = |qualifier_on_request| Target {
+        qualifier: Some("live".to_string()),
+        qualifier_on_request,
+        ..Default::default()
+    };
+    let config = Config {
+        lambda_function_name: "default-function".to_string(),
+        debug_headers: true,
+        targets: BTreeMap::from([
+            ("/fixed".to_string(), target(false)),
+            ("/smoke".to_string(), target(true)),
+        ]),
+        ..Default::default()
+    };
+    let invoker = MockInvoker::new(vec![]);
+    let app = build_router(test_state_with(config, invoker.clone()));
+    let send = |uri: &str, qualifier: Option<&str>| {
+        let mut request = axum::http::Request::get(uri);
+        if let Some(qualifier) = qualifier {
+            request = request.header("x-lambda-qualifier", qualifier);
+        }
+        app.clone().oneshot(request.body(Body::empty()).unwrap())
+    };
+
+    let response = send("/fixed", Some("canary")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["x-gateway-qualifier"], "live");
+    send("/smoke", None).await.unwrap();
+    let response = send("/smoke", Some("canary")).await.unwrap();
+    assert_eq!(response.headers()["x-gateway-qualifier"], "canary");
+    send("/smoke", Some(" 7 ")).await.unwrap();
+    send("/other", Some("canary")).await.unwrap();
+    let qualifiers: Vec<_> = invoker.requests().into_iter().map(|r| r.qualifier).collect();
+    let expected = [Some("live"), Some("live"), Some("canary"), Some("7"), None];
+    assert_eq!(qualifiers, expected.map(|q| q.map(String::from)));
+
+    // Only targets that take the header reject a bad one, and without invoking
+    let response = send("/smoke", Some("live:canary")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error_code"], "invalid_qualifier");
+    assert_eq!(send("/fixed", Some("live:canary")).await.unwrap().status(), StatusCode::OK);
+    assert_eq!(invoker.requests().len(), 6);
+}
+
+#[tokio::test]
+async fn test_invoke_mode_override_header() {
+    use tower::ServiceExt;
+
+    let target = |allow_invoke_mode_override| Target {
+        allow_invoke_mode_override,
+        ..Default::default()
+    };
+    let config = Config {
+        lambda_function_name: "default-function".to_string(),
+        targets: BTreeMap::from([("/on".to_string(), target(true)), ("/off".to_string(), target(false))]),
+        ..Default::default()
+    };
+    let stream = delayed_stream(vec![(0, STREAM_PRELUDE), (0, b"streamed")]);
+    let invoker = MockInvoker::with_streams(vec![Ok(stream)], Duration::ZERO);
+    let app = build_router(test_state_with(config, invoker.clone()));
+    let send = |uri: &str, mode: &str| {
+        let request = axum::http::Request::get(uri).header("x-invoke-mode", mode);
+        app.clone().oneshot(request.body(Body::empty()).unwrap())
+    };
+    let body_of = |response: Response| axum::body::to_bytes(response.into_body(), usize::MAX);
+
+    let response = send("/on", "response_stream").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_of(response).await.unwrap(), "streamed");
+
+    // Ignored where not allowed, and a value that does not parse keeps the configured mode; the
+    // mock has no stream left, so either would fail if it streamed
+    let response = send("/off", "ResponseStream").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_of(response).await.unwrap(), "");
+    let response = send("/on", "sideways").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_of(response).await.unwrap(), "");
+    assert_eq!(invoker.requests().len(), 3);
+}
+
+fn dry_run_config() -> Config {
+    let target = |dry_run_on_request| Target {
+        function: Some(config::FunctionRef::One("orders".to_string())),
+        dry_run_on_request,
+        ..Default::default()
+    };
+    Config {
+        admin_api_keys: HashSet::from(["admin-key".to_string()]),
+        targets: BTreeMap::from([
+            ("/orders".to_string(), target(true)),
+            ("/fixed".to_string(), target(false)),
+        ]),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_dry_run_header_checks_permissions_without_invoking() {
+    use tower::ServiceExt;
+
+    let invoker = MockInvoker::with_dry_runs(vec![
+        Ok(()),
+        Err(InvokeError::NotFound("Function not found: orders".to_string())),
+        Err(InvokeError::AccessDenied("not authorized to perform lambda:InvokeFunction".to_string())),
+    ]);
+    let state = test_state_with(dry_run_config(), invoker.clone());
+    let dry_run = |uri: &str| {
+        axum::http::Request::get(uri)
+            .header("x-invoke-dry-run", "true")
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = build_router(state.clone()).oneshot(dry_run("/orders")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let (status, phase, body) = error_of(state.clone(), dry_run("/orders")).await;
+    assert_eq!((status, phase), (StatusCode::BAD_GATEWAY, ErrorPhase::Invoke));
+    assert_eq!(body["error_code"], "function_not_found");
+    let (status, _, body) = error_of(state.clone(), dry_run("/orders")).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["error_code"], "access_denied");
+    assert_eq!(invoker.requests()[0].function_name, "orders");
+
+    // Targets without dry_run_on_request invoke as usual
+    let response = build_router(state).oneshot(dry_run("/fixed")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(invoker.requests().len(), 4);
+}
+
+#[tokio::test]
+async fn test_admin_dry_run_endpoint() {
+    use tower::ServiceExt;
+
+    let invoker = MockInvoker::with_dry_runs(vec![Ok(())]);
+    let app = build_router(test_state_with(dry_run_config(), invoker.clone()));
+    let send = |uri: &str, api_key: &str| app.clone().oneshot(keyed_request("GET", uri, api_key, ""));
+
+    let response = send("/admin/dry-run?target=/fixed", "admin-key").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(invoker.requests()[0].function_name, "orders");
+    let response = send("/admin/dry-run?target=/missing", "admin-key").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = send("/admin/dry-run?target=/fixed", "other-key").await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(invoker.calls(), 1);
+}
+
+fn event_config(capacity: usize) -> Config {
+    Config {
+        lambda_function_name: "webhook".to_string(),
+        lambda_invoke_mode: LambdaInvokeMode::Event,
+        auth_mode: config::AuthMode::ApiKey,
+        api_keys: HashSet::from(["key".to_string()]),
+        event_retry: config::EventRetryConfig {
+            capacity,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_event_mode_answers_202_with_lambda_request_id() {
+    use tower::ServiceExt;
+
+    let invoker = MockInvoker::with_events(vec![Ok(Some("lambda-request-1".to_string())), Ok(None)]);
+    let app = build_router(test_state_with(event_config(10), invoker.clone()));
+
+    let response = app.clone().oneshot(keyed_request("POST", "/hook", "key", "{\"id\": 7}")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    assert_eq!(response.headers()["x-request-id"], "lambda-request-1");
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(body.is_empty());
+    let payload: serde_json::Value = serde_json::from_str(&invoker.requests()[0].payload).unwrap();
+    assert_eq!((payload["httpMethod"].as_str(), payload["path"].as_str()), (Some("POST"), Some("/hook")));
+
+    // Without an ID from Lambda, the response carries the gateway's own
+    let response = app.clone().oneshot(keyed_request("POST", "/hook", "key", "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    assert_eq!(response.headers()["x-request-id"].len(), 36);
+
+    // Auth runs before the invoke, as for the other modes
+    let response = app.oneshot(keyed_request("POST", "/hook", "wrong", "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(invoker.calls(), 2);
+}
+
+#[tokio::test]
+async fn test_event_mode_request_id_uses_the_configured_header() {
+    use tower::ServiceExt;
+
+    let invoker = MockInvoker::with_events(vec![Ok(Some("lambda-request-1".to_string()))]);
+    let config = Config {
+        request_id: config::RequestIdConfig {
+            header: "x-correlation-id".to_string(),
+            ..Default::default()
+        },
+        ..event_config(10)
+    };
+    let app = build_router(test_state_with(config, invoker));
+
+    let response = app.oneshot(keyed_request("POST", "/hook", "key", "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    assert_eq!(response.headers()["x-correlation-id"], "lambda-request-1");
+    assert!(!response.headers().contains_key("x-request-id"));
+}
+
+#[tokio::test]
+async fn test_event_mode_submit_errors() {
+    use tower::ServiceExt;
+
+    let invoker = MockInvoker::with_events(vec![
+        Err(InvokeError::Throttled("Rate exceeded".to_string(), Some(Duration::from_secs(3)))),
+        Err(InvokeError::AccessDenied("AccessDeniedException".to_string())),
+    ]);
+    // Without a retry queue a throttle reaches the client
+    let state = test_state_with(event_config(0), invoker);
+
+    let response = build_router(state.clone())
+        .oneshot(keyed_request("POST", "/hook", "key", ""))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()["retry-after"], "3");
+    let (status, phase, body) = error_of(state, keyed_request("POST", "/hook", "key", "")).await;
+    assert_eq!((status, phase), (StatusCode::INTERNAL_SERVER_ERROR, ErrorPhase::Invoke));
+    assert_eq!(body["error_code"], "invoke_failed");
+}
+
+#[tokio::test]
+async fn test_event_mode_queues_throttles_for_retry() {
+    use tower::ServiceExt;
+
+    let invoker = MockInvoker::with_events(vec![Err(InvokeError::Throttled("Rate exceeded".to_string(), None))]);
+    let app = build_router(test_state_with(event_config(10), invoker));
+    let response = app.oneshot(keyed_request("POST", "/hook", "key", "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    // The retry queue has no Lambda request ID to give, so the gateway's own is returned
+    assert_eq!(response.headers()["x-request-id"].len(), 36);
+}
+
+fn cookie_rewrite_target(invoke: LambdaInvokeMode) -> Config {
+    let mut config = Config::default();
+    config.targets.insert(
+        "/app/*rest".to_string(),
+        Target {
+            invoke: Some(invoke),
+            cookie_rewrite: Some(config::CookieRewrite {
+                path_prefix: Some("/app".to_string()),
+                strip_domain: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+    );
+    config
+}
+
+fn set_cookies(response: &Response) -> Vec<&str> {
+    response
+        .headers()
+        .get_all("set-cookie")
+        .iter()
+        .map(|v| v.to_str().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_cookie_rewrite_buffered_multi_value_headers() {
+    let payload = serde_json::json!({
+        "statusCode": 200,
+        "multiValueHeaders": {"Set-Cookie": ["sid=a=b; Path=/; Domain=fn.internal", "theme=dark; Path=/prefs"]},
+        "body": ""
+    });
+    let invoker = MockInvoker::new(vec![output(&payload.to_string(), None)]);
+    let state = test_state_with(cookie_rewrite_target(LambdaInvokeMode::Buffered), invoker);
+
+    let response = get(build_router(state), "/app/login").await;
+
+    assert_eq!(set_cookies(&response), ["sid=a=b; Path=/app", "theme=dark; Path=/app/prefs"]);
+}
+
+#[tokio::test]
+async fn test_cookie_rewrite_streaming_prelude_cookies() {
+    const PRELUDE: &[u8] =
+        b"{\"statusCode\": 200, \"cookies\": [\"sid=1; path=/; domain=fn.internal; HttpOnly\"]}\0\0\0\0\0\0\0\0";
+    let invoker = MockInvoker::with_streams(vec![Ok(delayed_stream(vec![(0, PRELUDE), (0, b"body")]))], Duration::ZERO);
+    let state = test_state_with(cookie_rewrite_target(LambdaInvokeMode::ResponseStream), invoker);
+
+    let response = get(build_router(state), "/app/login").await;
+
+    assert_eq!(set_cookies(&response), ["sid=1; path=/app; HttpOnly"]);
+}
+
+fn listeners_config() -> Config {
+    let target = |function: &str| Target {
+        function: Some(config::FunctionRef::One(function.to_string())),
+        ..Default::default()
+    };
+    Config {
+        lambda_function_name: "fallback".to_string(),
+        debug_headers: true,
+        api_keys: HashSet::from(["key".to_string()]),
+        admin_api_keys: HashSet::from(["admin-key".to_string()]),
+        targets: BTreeMap::from([
+            ("/public".to_string(), target("public")),
+            ("/internal".to_string(), target("internal")),
+        ]),
+        listeners: BTreeMap::from([
+            (
+                "external".to_string(),
+                config::ListenerConfig {
+                    addr: config::BindAddr::Tcp("0.0.0.0:8080".to_string()),
+                    auth_mode: Some(config::AuthMode::ApiKey),
+                    targets: vec!["/public".to_string()],
+                    ..Default::default()
+                },
+            ),
+            (
+                "internal".to_string(),
+                config::ListenerConfig {
+                    addr: config::BindAddr::Tcp("127.0.0.1:9090".to_string()),
+                    admin: true,
+                    debug_headers: true,
+                    ..Default::default()
+                },
+            ),
+        ]),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_listeners_expose_different_targets_and_middleware() {
+    use tower::ServiceExt;
+
+    let invoker = MockInvoker::new(vec![ok_output("public"), ok_output("internal"), ok_output("fallback")]);
+    let state = test_state_with(listeners_config(), invoker.clone());
+    let external = build_listener_router(state.clone(), "external");
+    let internal = build_listener_router(state.clone(), "internal");
+    let send = |app: &Router, uri: &str, api_key: &str| app.clone().oneshot(keyed_request("GET", uri, api_key, ""));
+
+    // The external listener serves only /public, with API keys and without debug headers or admin
+    let response = send(&external, "/internal", "key").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = send(&external, "/elsewhere", "key").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = send(&external, "/public", "").await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = send(&external, "/public", "key").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!response.headers().contains_key("x-gateway-upstream"));
+    let response = send(&external, "/admin/flags", "admin-key").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // The internal listener serves everything, open as the top-level auth_mode
+    let response = send(&internal, "/internal", "").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["x-gateway-upstream"], "internal");
+    let response = send(&internal, "/elsewhere", "").await.unwrap();
+    assert_eq!(response.headers()["x-gateway-upstream"], "fallback");
+    let response = send(&internal, "/admin/flags", "admin-key").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(invoker.calls(), 3);
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let response = axum::Json(gateway_status(&state)).into_response();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(status["listeners"]["external"]["addr"], "0.0.0.0:8080");
+    assert_eq!(status["listeners"]["external"]["requests"], 2);
+    assert_eq!(status["listeners"]["internal"]["requests"], 2);
+}
+
+#[tokio::test]
+async fn test_error_bodies_negotiated_from_accept() {
+    use tower::ServiceExt;
+
+    let config = Config {
+        unmatched: UnmatchedRoute::NotFound,
+        targets: BTreeMap::from([
+            ("/api".to_string(), Target::default()),
+            (
+                "/app".to_string(),
+                Target {
+                    auth: Some(config::AuthMode::ApiKey),
+                    error_format: Some(config::ErrorFormat::Html),
+                    ..Default::default()
+                },
+            ),
+        ]),
+        ..Default::default()
+    };
+    let invoker = MockInvoker::new(vec![ok_output("from the function")]);
+    let app = build_router(test_state_with(config, invoker));
+    let send = |uri: &str, accept: &str| {
+        let request = axum::http::Request::get(uri)
+            .header("accept", accept)
+            .header("x-request-id", "req-<1>")
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(request)
+    };
+    let body_of = |response: Response| axum::body::to_bytes(response.into_body(), usize::MAX);
+
+    let response = send("/missing", "text/html,application/xhtml+xml,*/*;q=0.8").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.headers()["content-type"], "text/html; charset=utf-8");
+    assert_eq!(response.extensions().get::<ErrorInfo>().unwrap().code, "no_route");
+    let body = String::from_utf8(body_of(response).await.unwrap().to_vec()).unwrap();
+    assert!(body.contains("<h1>404 Not Found</h1>"), "{}", body);
+    assert!(body.contains("Request ID: req-&lt;1&gt;"), "{}", body);
+
+    let response = send("/missing", "text/plain").await.unwrap();
+    assert_eq!(response.headers()["content-type"], "text/plain; charset=utf-8");
+    let body = body_of(response).await.unwrap();
+    assert!(body.starts_with(b"404 Not Found: No target serves this path\n"), "{:?}", body);
+
+    let response = send("/missing", "*/*").await.unwrap();
+    assert_eq!(response.headers()["content-type"], "application/json");
+
+    // The target forces HTML whatever the client accepts
+    let response = send("/app", "application/json").await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers()["content-type"], "text/html; charset=utf-8");
+
+    // Function responses are never rewritten
+    let response = send("/api", "text/html").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_of(response).await.unwrap(), "from the function");
+}
+
+fn request_id_config(request_id: config::RequestIdConfig) -> Config {
+    let mut config = Config {
+        lambda_function_name: "app".to_string(),
+        request_id,
+        ..Default::default()
+    };
+    config.targets.insert(
+        "/private".to_string(),
+        Target {
+            auth: Some(config::AuthMode::ApiKey),
+            ..Default::default()
+        },
+    );
+    config.targets.insert("/*rest".to_string(), Target::default());
+    config
+}
+
+fn with_header(uri: &str, name: &str, value: &str) -> axum::http::Request<Body> {
+    axum::http::Request::get(uri).header(name, value).body(Body::empty()).unwrap()
+}
+
+#[tokio::test]
+async fn test_request_id_reaches_function_and_response() {
+    use tower::ServiceExt;
+
+    let invoker = MockInvoker::new(vec![ok_output("ok"), ok_output("ok")]);
+    let app = build_router(test_state_with(request_id_config(Default::default()), invoker.clone()));
+
+    // An incoming ID is kept
+    let response = app.clone().oneshot(with_header("/a", "x-request-id", "trace-42")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["x-request-id"], "trace-42");
+    let payload: serde_json::Value = serde_json::from_str(&invoker.requests()[0].payload).unwrap();
+    assert_eq!(payload["headers"]["x-request-id"], "trace-42");
+
+    // A missing one is generated, and the function sees the same
+    let response = get(app, "/b").await;
+    let id = response.headers()["x-request-id"].to_str().unwrap().to_string();
+    assert_eq!((id.len(), &id[14..15]), (36, "7"), "{}", id);
+    let payload: serde_json::Value = serde_json::from_str(&invoker.requests()[1].payload).unwrap();
+    assert_eq!(payload["headers"]["x-request-id"], id.as_str());
+}
+
+#[tokio::test]
+async fn test_request_id_on_error_and_stream_responses() {
+    use tower::ServiceExt;
+
+    let invoker = MockInvoker::new(vec![Err(InvokeError::AccessDenied("AccessDenied".to_string()))]);
+    let app = build_router(test_state_with(request_id_config(Default::default()), invoker));
+
+    let response = app.clone().oneshot(with_header("/private", "x-request-id", "denied-1")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers()["x-request-id"], "denied-1");
+    let response = app.clone().oneshot(with_header("/a", "x-request-id", "failed-1")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(response.headers()["x-request-id"], "failed-1");
+    let response = get(app, "/private").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers()["x-request-id"].len(), 36);
+
+    let mut config = request_id_config(Default::default());
+    config.targets.get_mut("/*rest").unwrap().invoke = Some(LambdaInvokeMode::ResponseStream);
+    let stream = delayed_stream(vec![(0, STREAM_PRELUDE), (0, b"streamed")]);
+    let invoker = MockInvoker::with_streams(vec![Ok(stream)], Duration::ZERO);
+    let app = build_router(test_state_with(config, invoker));
+    let response = app.oneshot(with_header("/a", "x-request-id", "stream-1")).await.unwrap();
+    assert_eq!(response.headers()["x-request-id"], "stream-1");
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, "streamed");
+}
+
+#[tokio::test]
+async fn test_request_id_header_name_and_untrusted_ids() {
+    use tower::ServiceExt;
+
+    let settings = config::RequestIdConfig {
+        header: "x-correlation-id".to_string(),
+        trust_incoming: false,
+    };
+    let invoker = MockInvoker::new(vec![ok_output("ok")]);
+    let app = build_router(test_state_with(request_id_config(settings), invoker.clone()));
+
+    let response = app.oneshot(with_header("/a", "x-correlation-id", "spoofed")).await.unwrap();
+    let id = response.headers()["x-correlation-id"].to_str().unwrap();
+    assert_ne!(id, "spoofed");
+    assert!(!response.headers().contains_key("x-request-id"));
+    let payload: serde_json::Value = serde_json::from_str(&invoker.requests()[0].payload).unwrap();
+    assert_eq!(payload["headers"]["x-correlation-id"], id);
+}
+
+fn tracing_config(propagation: config::TracePropagation) -> Config {
+    Config {
+        lambda_function_name: "app".to_string(),
+        tracing: config::TracingConfig {
+            propagation: Some(propagation),
+        },
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_xray_trace_reaches_invoke_and_event() {
+    use tower::ServiceExt;
+
+    let invoker = MockInvoker::new(vec![ok_output("ok"), ok_output("ok")]);
+    let app = build_router(test_state_with(tracing_config(config::TracePropagation::Xray), invoker.clone()));
+    let root = "1-5759e988-bd862e3fe1be46a994272793";
+    let incoming = format!("Root={};Parent=53995c3f42cd8ad8;Sampled=1", root);
+    let response = app.clone().oneshot(with_header("/a", "x-amzn-trace-id", &incoming)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let request = &invoker.requests()[0];
+    let trace_header = request.trace_header.clone().unwrap();
+    assert!(trace_header.starts_with(&format!("Root={};Parent=", root)), "{}", trace_header);
+    assert!(trace_header.ends_with(";Sampled=1"), "{}", trace_header);
+    assert!(!trace_header.contains("53995c3f42cd8ad8"), "{}", trace_header);
+    let payload: serde_json::Value = serde_json::from_str(&request.payload).unwrap();
+    assert_eq!(payload["headers"]["x-amzn-trace-id"], trace_header.as_str());
+
+    // Without a trace from the client, one is started
+    get(app, "/b").await;
+    let trace_header = invoker.requests()[1].trace_header.clone().unwrap();
+    assert!(trace_header.starts_with("Root=1-"), "{}", trace_header);
+}
+
+#[tokio::test]
+async fn test_w3c_trace_reaches_event_headers_only() {
+    use tower::ServiceExt;
+
+    let invoker = MockInvoker::new(vec![ok_output("ok"), ok_output("ok")]);
+    let app = build_router(test_state_with(tracing_config(config::TracePropagation::W3c), invoker.clone()));
+    let request = axum::http::Request::get("/a")
+        .header("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+        .header("tracestate", "rojo=00f067aa0ba902b7")
+        .body(Body::empty())
+        .unwrap();
+    app.oneshot(request).await.unwrap();
+
+    let request = &invoker.requests()[0];
+    assert_eq!(request.trace_header, None);
+    let payload: serde_json::Value = serde_json::from_str(&request.payload).unwrap();
+    let traceparent = payload["headers"]["traceparent"].as_str().unwrap();
+    assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"), "{}", traceparent);
+    assert!(!traceparent.contains("00f067aa0ba902b7"), "{}", traceparent);
+    assert_eq!(payload["headers"]["tracestate"], "rojo=00f067aa0ba902b7");
+}
+
+#[tokio::test]
+async fn test_trace_headers_pass_unchanged_without_propagation() {
+    use tower::ServiceExt;
+
+    let invoker = MockInvoker::new(vec![ok_output("ok")]);
+    let app = build_router(test_state_with(request_id_config(Default::default()), invoker.clone()));
+    app.oneshot(with_header("/a", "x-amzn-trace-id", "Root=opaque")).await.unwrap();
+    let request = &invoker.requests()[0];
+    assert_eq!(request.trace_header, None);
+    let payload: serde_json::Value = serde_json::from_str(&request.payload).unwrap();
+    assert_eq!(payload["headers"]["x-amzn-trace-id"], "Root=opaque");
+}
+
+fn shadow_config(shadow: config::ShadowConfig) -> Config {
+    let target = Target {
+        shadow: Some(shadow),
+        ..Default::default()
+    };
+    Config {
+        lambda_function_name: "app".to_string(),
+        admin_api_keys: HashSet::from(["admin-key".to_string()]),
+        targets: BTreeMap::from([("/api/*rest".to_string(), target)]),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_shadow_responses_are_compared_and_mismatches_listed() {
+    use tower::ServiceExt;
+
+    let shadow = config::ShadowConfig {
+        function: "candidate".to_string(),
+        qualifier: Some("live".to_string()),
+        compare: true,
+        ..Default::default()
+    };
+    // The shadow is invoked first, so it takes the first result of each pair
+    let results = vec![ok_output("v1"), ok_output("v1"), ok_output("v2"), ok_output("v1")];
+    let invoker = MockInvoker::new(results);
+    let state = test_state_with(shadow_config(shadow), invoker.clone());
+    let app = build_router(state.clone());
+    for _ in 0..2 {
+        let response = get(app.clone(), "/api/orders").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "v1");
+    }
+
+    let requests = invoker.requests();
+    assert_eq!((requests[0].function_name.as_str(), requests[0].qualifier.as_deref()), ("candidate", Some("live")));
+    assert_eq!((requests[1].function_name.as_str(), requests[1].qualifier.as_deref()), ("app", None));
+    assert_eq!(requests[0].payload, requests[1].payload);
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let key = |result: &str| {
+        let labels = vec![("target", "/api/*rest".to_string()), ("result", result.to_string())];
+        ("shadow_comparisons_total", labels)
+    };
+    let counters = state.telemetry.snapshot().counters;
+    assert_eq!((counters[&key("match")], counters[&key("mismatch")]), (1, 1));
+    assert_eq!(counters[&("shadow_requests_total", vec![("target", "/api/*rest".to_string())])], 2);
+
+    let response = app.oneshot(keyed_request("GET", "/admin/shadow-mismatches", "admin-key", "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let mismatches: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(mismatches.as_array().unwrap().len(), 1);
+    assert_eq!(mismatches[0]["target"], "/api/*rest");
+    assert_eq!(mismatches[0]["function"], "candidate");
+    assert_eq!(mismatches[0]["differences"][0]["location"], "body");
+    // Bodies are described, never kept
+    assert!(!body.windows(2).any(|w| w == b"v2"));
+}
+
+#[tokio::test]
+async fn test_shadow_sampling_and_mirror_only() {
+    let unsampled = config::ShadowConfig {
+        function: "candidate".to_string(),
+        sample_percent: 0.0,
+        compare: true,
+        ..Default::default()
+    };
+    let invoker = MockInvoker::new(vec![]);
+    let state = test_state_with(shadow_config(unsampled), invoker.clone());
+    get(build_router(state), "/api/orders").await;
+    assert_eq!(invoker.calls(), 1);
+
+    // Without compare the shadow is only mirrored
+    let mirrored = config::ShadowConfig {
+        function: "candidate".to_string(),
+        ..Default::default()
+    };
+    let invoker = MockInvoker::new(vec![ok_output("v2"), ok_output("v1")]);
+    let state = test_state_with(shadow_config(mirrored), invoker.clone());
+    let response = get(build_router(state.clone()), "/api/orders").await;
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, "v1");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(invoker.calls(), 2);
+    assert!(state.shadow_mismatches.entries().is_empty());
+    let counters = state.telemetry.snapshot().counters;
+    assert!(!counters.keys().any(|(name, _)| *name == "shadow_comparisons_total"));
+}
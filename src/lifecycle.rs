@@ -0,0 +1,243 @@
+use crate::access_log::now_ms;
+use crate::config::{LifecycleConfig, ShutdownPhase, WebhookConfig};
+use crate::support::RecentLog;
+use crate::telemetry::Telemetry;
+use axum::body::Bytes;
+use axum::http::header::{CONTENT_TYPE, HOST};
+use axum::http::{Request, StatusCode};
+use http_body_util::Full;
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+
+/// Events held for subscribers that fall behind; slower ones skip the oldest.
+const BUS_CAPACITY: usize = 256;
+
+/// A gateway state transition. Unlike request logs these are rare, and meant to be read as a
+/// timeline of what the gateway went through, for example during incident review.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LifecycleEvent {
+    /// The listener is bound and requests are being served.
+    StartupComplete {
+        config_rev: String,
+        addr: String,
+    },
+    /// A reload switched to a new configuration, with the names of the targets it touched.
+    ConfigReloaded {
+        config_rev: String,
+        previous_rev: String,
+        targets_added: Vec<String>,
+        targets_removed: Vec<String>,
+        targets_changed: Vec<String>,
+    },
+    /// A target started failing its health probes.
+    TargetUnhealthy {
+        target: String,
+        error: Option<String>,
+    },
+    /// A target passes its health probes again, or for the first time.
+    TargetHealthy {
+        target: String,
+    },
+    ShutdownPhaseStarted {
+        phase: ShutdownPhase,
+    },
+    ShutdownPhaseFinished {
+        phase: ShutdownPhase,
+        elapsed_ms: u64,
+        completed: bool,
+    },
+}
+
+impl LifecycleEvent {
+    fn kind(&self) -> &'static str {
+        match self {
+            LifecycleEvent::StartupComplete { .. } => "startup_complete",
+            LifecycleEvent::ConfigReloaded { .. } => "config_reloaded",
+            LifecycleEvent::TargetUnhealthy { .. } => "target_unhealthy",
+            LifecycleEvent::TargetHealthy { .. } => "target_healthy",
+            LifecycleEvent::ShutdownPhaseStarted { .. } => "shutdown_phase_started",
+            LifecycleEvent::ShutdownPhaseFinished { .. } => "shutdown_phase_finished",
+        }
+    }
+}
+
+/// A published event with the time it was published.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LifecycleRecord {
+    pub timestamp_ms: u128,
+    #[serde(flatten)]
+    pub event: LifecycleEvent,
+}
+
+/// In-process bus the components of the gateway publish their lifecycle events to. Publishing
+/// never waits: subscribers each get their own copy of every event, see
+/// [`Lifecycle::spawn_subscribers`].
+#[derive(Clone)]
+pub struct Lifecycle {
+    bus: broadcast::Sender<LifecycleRecord>,
+    recent: RecentLog<LifecycleRecord>,
+}
+
+impl Default for Lifecycle {
+    fn default() -> Self {
+        Self::new(&LifecycleConfig::default())
+    }
+}
+
+impl Lifecycle {
+    pub fn new(config: &LifecycleConfig) -> Self {
+        Self {
+            bus: broadcast::channel(BUS_CAPACITY).0,
+            recent: RecentLog::new(config.recent_events),
+        }
+    }
+
+    pub fn publish(&self, event: LifecycleEvent) {
+        // No subscribers yet is not an error: early events are simply not delivered
+        let _ = self.bus.send(LifecycleRecord {
+            timestamp_ms: now_ms(),
+            event,
+        });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<LifecycleRecord> {
+        self.bus.subscribe()
+    }
+
+    /// Events kept for `/admin/events`, oldest first.
+    pub fn recent(&self) -> Vec<LifecycleRecord> {
+        self.recent.entries()
+    }
+
+    /// Starts the subscribers: the structured log under the `lifecycle` target, the recent events
+    /// of `/admin/events` and, when configured, the webhook.
+    pub fn spawn_subscribers(&self, config: &LifecycleConfig, telemetry: Telemetry) {
+        let recent = self.recent.clone();
+        subscribe(self.subscribe(), "recent", move |record| recent.push(record));
+        subscribe(self.subscribe(), "log", |record| {
+            let fields = serde_json::to_string(&record.event).unwrap_or_default();
+            tracing::info!(target: "lifecycle", event = record.event.kind(), %fields, "Lifecycle event");
+        });
+        if let Some(webhook) = &config.webhook {
+            let queue = Webhook::spawn(webhook.clone(), telemetry.clone());
+            subscribe(self.subscribe(), "webhook", move |record| {
+                if queue.try_send(record).is_err() {
+                    telemetry.increment("lifecycle_webhook_dropped_total", vec![]);
+                }
+            });
+        }
+    }
+}
+
+/// Feeds every event to `deliver` until the bus is gone.
+fn subscribe(
+    mut events: broadcast::Receiver<LifecycleRecord>,
+    name: &'static str,
+    mut deliver: impl FnMut(LifecycleRecord) + Send + 'static,
+) {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(record) => deliver(record),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!(
+                        subscriber = name,
+                        skipped,
+                        "Lifecycle subscriber fell behind, events skipped"
+                    )
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    });
+}
+
+/// Delivers events to the webhook one at a time, in order, from a bounded queue.
+struct Webhook {
+    config: WebhookConfig,
+    url: url::Url,
+    telemetry: Telemetry,
+}
+
+impl Webhook {
+    fn spawn(config: WebhookConfig, telemetry: Telemetry) -> mpsc::Sender<LifecycleRecord> {
+        let (tx, mut rx) = mpsc::channel(config.queue_capacity.max(1));
+        let Ok(url) = url::Url::parse(&config.url) else {
+            // Rejected by validation; nothing is queued for an unusable URL
+            return tx;
+        };
+        let webhook = Webhook { config, url, telemetry };
+        tokio::spawn(async move {
+            while let Some(record) = rx.recv().await {
+                webhook.deliver(&record).await;
+            }
+        });
+        tx
+    }
+
+    /// Posts `record`, retrying connection errors and 5xx answers with exponential backoff.
+    async fn deliver(&self, record: &LifecycleRecord) {
+        let body = Bytes::from(serde_json::to_vec(record).expect("lifecycle records serialize"));
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        let mut backoff = Duration::from_millis(self.config.backoff_ms);
+        for attempt in 0..=self.config.retries {
+            if attempt > 0 {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            let error = match tokio::time::timeout(timeout, self.post(body.clone())).await {
+                Ok(Ok(status)) if !status.is_server_error() => {
+                    if !status.is_success() {
+                        // A 4xx will not go away by retrying
+                        tracing::warn!(%status, event = record.event.kind(), "Lifecycle webhook rejected the event");
+                        self.telemetry.increment("lifecycle_webhook_failures_total", vec![]);
+                    }
+                    return;
+                }
+                Ok(Ok(status)) => status.to_string(),
+                Ok(Err(e)) => e,
+                Err(_) => format!("timed out after {:?}", timeout),
+            };
+            tracing::debug!(attempt, %error, "Lifecycle webhook attempt failed");
+            if attempt == self.config.retries {
+                tracing::warn!(%error, event = record.event.kind(), "Giving up on delivering a lifecycle event");
+                self.telemetry.increment("lifecycle_webhook_failures_total", vec![]);
+            }
+        }
+    }
+
+    async fn post(&self, body: Bytes) -> Result<StatusCode, String> {
+        let host = self.url.host_str().unwrap_or_default();
+        let port = self.url.port_or_known_default().unwrap_or(80);
+        let stream = tokio::net::TcpStream::connect((host, port))
+            .await
+            .map_err(|e| e.to_string())?;
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .map_err(|e| e.to_string())?;
+        tokio::spawn(connection);
+        let authority = match self.url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        let path = match self.url.query() {
+            Some(query) => format!("{}?{}", self.url.path(), query),
+            None => self.url.path().to_string(),
+        };
+        let request = Request::post(path)
+            .header(HOST, authority)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Full::new(body))
+            .map_err(|e| e.to_string())?;
+        let response = sender.send_request(request).await.map_err(|e| e.to_string())?;
+        Ok(response.status())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("lifecycle_tests.rs");
+}
@@ -0,0 +1,212 @@
+use super::*;
+use crate::config::{Config, HealthMode, ShutdownConfig, Target};
+use crate::health::{HealthRegistry, ProbeResult};
+use crate::mock::{test_state_with, MockInvoker};
+use axum::body::Body;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tower::ServiceExt;
+
+async fn next_event(events: &mut broadcast::Receiver<LifecycleRecord>) -> LifecycleEvent {
+    tokio::time::timeout(Duration::from_secs(1), events.recv())
+        .await
+        .expect("an event in time")
+        .unwrap()
+        .event
+}
+
+#[tokio::test]
+async fn test_reload_publishes_fingerprints_and_targets() {
+    let state = test_state_with(Config::default(), MockInvoker::new(vec![]));
+    let mut events = state.lifecycle.subscribe();
+    let previous_rev = state.config_rev().to_string();
+
+    state.reload(Config {
+        targets: BTreeMap::from([("/orders".to_string(), Target::default())]),
+        ..Default::default()
+    });
+    assert_eq!(
+        next_event(&mut events).await,
+        LifecycleEvent::ConfigReloaded {
+            config_rev: state.config_rev().to_string(),
+            previous_rev,
+            targets_added: vec!["/orders".to_string()],
+            targets_removed: vec![],
+            targets_changed: vec![],
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_health_transitions_are_published_once() {
+    let lifecycle = Lifecycle::default();
+    let mut events = lifecycle.subscribe();
+    let health = HealthRegistry::new(lifecycle);
+    let result = |healthy: bool| ProbeResult {
+        healthy,
+        mode: HealthMode::GetFunction,
+        checked_at_ms: 0,
+        latency_ms: 1.0,
+        error: (!healthy).then(|| "timed out".to_string()),
+    };
+
+    for healthy in [false, false, true, true] {
+        health.record("/orders", result(healthy));
+    }
+    assert_eq!(
+        next_event(&mut events).await,
+        LifecycleEvent::TargetUnhealthy {
+            target: "/orders".to_string(),
+            error: Some("timed out".to_string()),
+        }
+    );
+    assert_eq!(
+        next_event(&mut events).await,
+        LifecycleEvent::TargetHealthy {
+            target: "/orders".to_string()
+        }
+    );
+    assert!(events.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_shutdown_phases_are_published() {
+    let state = test_state_with(Config::default(), MockInvoker::new(vec![]));
+    let mut events = state.lifecycle.subscribe();
+    let config = ShutdownConfig {
+        phases: vec![ShutdownPhase::RefuseNew],
+        ..Default::default()
+    };
+    crate::shutdown::run(&state, &config, || {}).await;
+
+    let phase = ShutdownPhase::RefuseNew;
+    assert_eq!(next_event(&mut events).await, LifecycleEvent::ShutdownPhaseStarted { phase });
+    assert!(matches!(
+        next_event(&mut events).await,
+        LifecycleEvent::ShutdownPhaseFinished { completed: true, .. }
+    ));
+}
+
+#[tokio::test]
+async fn test_recent_events_on_admin_endpoint() {
+    let config = Config {
+        admin_api_keys: HashSet::from(["admin-key".to_string()]),
+        ..Default::default()
+    };
+    let state = test_state_with(config, MockInvoker::new(vec![]));
+    state
+        .lifecycle
+        .spawn_subscribers(&state.config().lifecycle, state.telemetry.clone());
+    state.reload(Config {
+        admin_api_keys: HashSet::from(["admin-key".to_string()]),
+        ..Default::default()
+    });
+    while state.lifecycle.recent().is_empty() {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    let request = |key: &str| {
+        Request::get("/admin/events")
+            .header("x-api-key", key)
+            .body(Body::empty())
+            .unwrap()
+    };
+    let app = crate::build_router(state);
+    let response = app.clone().oneshot(request("wrong-key")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = app.oneshot(request("admin-key")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let events: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(events[0]["type"], "config_reloaded");
+    assert!(events[0]["timestamp_ms"].as_u64().unwrap() > 0);
+    assert!(events[0]["config_rev"].is_string());
+}
+
+/// A webhook receiver answering the first `failures` posts 500, recording every body it gets.
+async fn receiver(failures: usize, delay: Duration) -> (String, Arc<Mutex<Vec<serde_json::Value>>>) {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let app = axum::Router::new().route(
+        "/hooks",
+        axum::routing::post({
+            let received = received.clone();
+            move |body: Bytes| async move {
+                tokio::time::sleep(delay).await;
+                received.lock().unwrap().push(serde_json::from_slice(&body).unwrap());
+                if attempts.fetch_add(1, Ordering::SeqCst) < failures {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                } else {
+                    StatusCode::NO_CONTENT
+                }
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hooks?source=gateway", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    (url, received)
+}
+
+fn webhook_config(url: String, queue_capacity: usize) -> LifecycleConfig {
+    LifecycleConfig {
+        webhook: Some(WebhookConfig {
+            url,
+            queue_capacity,
+            retries: 2,
+            backoff_ms: 10,
+            timeout_ms: 1000,
+        }),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_webhook_retries_server_errors() {
+    let (url, received) = receiver(2, Duration::ZERO).await;
+    let config = webhook_config(url, 10);
+    let lifecycle = Lifecycle::new(&config);
+    let telemetry = test_state_with(Config::default(), MockInvoker::new(vec![])).telemetry;
+    lifecycle.spawn_subscribers(&config, telemetry.clone());
+
+    lifecycle.publish(LifecycleEvent::StartupComplete {
+        config_rev: "abc".to_string(),
+        addr: "0.0.0.0:8000".to_string(),
+    });
+    while received.lock().unwrap().len() < 3 {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    let received = received.lock().unwrap().clone();
+    assert!(received.iter().all(|event| event == &received[0]));
+    assert_eq!(received[0]["type"], "startup_complete");
+    assert_eq!(received[0]["config_rev"], "abc");
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert!(telemetry.snapshot().counters.is_empty());
+}
+
+#[tokio::test]
+async fn test_webhook_queue_is_bounded() {
+    // A slow receiver holds the first event while the rest pile up in a queue of one
+    let (url, received) = receiver(0, Duration::from_millis(200)).await;
+    let config = webhook_config(url, 1);
+    let lifecycle = Lifecycle::new(&config);
+    let telemetry = test_state_with(Config::default(), MockInvoker::new(vec![])).telemetry;
+    lifecycle.spawn_subscribers(&config, telemetry.clone());
+
+    let started_at = std::time::Instant::now();
+    for phase in [ShutdownPhase::Unready, ShutdownPhase::RefuseNew, ShutdownPhase::DrainBuffered] {
+        lifecycle.publish(LifecycleEvent::ShutdownPhaseStarted { phase });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // Publishing never waited on the receiver
+    assert!(started_at.elapsed() < Duration::from_millis(200));
+    while received.lock().unwrap().len() < 2 {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let phases: Vec<_> = received.lock().unwrap().iter().map(|e| e["phase"].clone()).collect();
+    assert_eq!(phases, ["unready", "refuse_new"]);
+    let key = ("lifecycle_webhook_dropped_total", vec![]);
+    assert_eq!(telemetry.snapshot().counters[&key], 1);
+}
@@ -0,0 +1,528 @@
+use crate::adaptive::{AdaptiveStatus, AimdController, Sample};
+use crate::config::{
+    AdaptiveConcurrency, GlobalConcurrency, Overflow, PriorityClass, PriorityWeights, RetryAfterConfig, Target,
+};
+use crate::error::{ErrorPhase, GatewayError};
+use crate::ewma::{self, Ewma};
+use crate::fair_queue::{FairQueue, Pushed};
+use crate::retry_after::RetryAfterHints;
+use crate::telemetry::{Labels, Telemetry};
+use axum::http::StatusCode;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
+
+/// Limits on concurrent invokes, per target and for the gateway as a whole. Requests over
+/// `max_concurrency`, or the current limit of `adaptive_concurrency`, wait in a queue of at most
+/// `max_queue_depth`, for at most `queue_timeout_ms`; beyond that they are rejected with 429 and a
+/// `Retry-After` estimated from recent service times. Freed slots go to waiting requests by
+/// weighted fair queuing across their priority classes, see [`FairQueue`].
+#[derive(Clone)]
+pub struct ConcurrencyLimiter {
+    limits: Arc<Mutex<HashMap<String, Arc<TargetLimit>>>>,
+    global: Arc<Mutex<Option<Arc<TargetLimit>>>>,
+    telemetry: Telemetry,
+}
+
+/// Waits for a slot; a sender dropped without one means the request was shed.
+type Waiter = oneshot::Sender<OwnedSemaphorePermit>;
+
+struct TargetLimit {
+    max_concurrency: usize,
+    max_queue_depth: usize,
+    adaptive_settings: Option<AdaptiveConcurrency>,
+    slots: Arc<Semaphore>,
+    queue: Mutex<FairQueue<Waiter>>,
+    /// How long requests hold a slot, in milliseconds.
+    service_time: Mutex<Ewma>,
+    adaptive: Option<Mutex<Adaptive>>,
+}
+
+/// The controller of an adaptive target and the slots in existence, free or held. The semaphore
+/// gains slots as soon as the limit rises; when it falls, free slots are removed at once and held
+/// ones as they are released, so requests already running are never cut short.
+struct Adaptive {
+    controller: AimdController,
+    capacity: usize,
+}
+
+impl TargetLimit {
+    /// The limit in effect.
+    fn current(&self) -> usize {
+        match &self.adaptive {
+            Some(adaptive) => adaptive.lock().unwrap().controller.limit(),
+            None => self.max_concurrency,
+        }
+    }
+
+    /// Slots held by requests.
+    fn in_flight(&self) -> usize {
+        let capacity = match &self.adaptive {
+            Some(adaptive) => adaptive.lock().unwrap().capacity,
+            None => self.max_concurrency,
+        };
+        capacity.saturating_sub(self.slots.available_permits())
+    }
+
+    fn new(max_concurrency: usize, max_queue_depth: usize, adaptive: Option<&AdaptiveConcurrency>) -> Self {
+        let adaptive = adaptive.map(|settings| {
+            let controller = AimdController::new(settings);
+            let capacity = controller.limit();
+            (settings.clone(), Mutex::new(Adaptive { controller, capacity }))
+        });
+        let slots = match &adaptive {
+            Some((_, adaptive)) => adaptive.lock().unwrap().capacity,
+            None => max_concurrency,
+        };
+        let (adaptive_settings, adaptive) = adaptive.unzip();
+        Self {
+            max_concurrency,
+            max_queue_depth,
+            adaptive_settings,
+            slots: Arc::new(Semaphore::new(slots)),
+            queue: Mutex::new(FairQueue::new(max_queue_depth)),
+            service_time: Mutex::new(Ewma::new(ewma::SERVICE_TIME_ALPHA)),
+            adaptive,
+        }
+    }
+
+    /// Hands free slots to waiting requests, in the order of the queue. Called whenever a slot is
+    /// freed or added; the queue lock makes it atomic with a request's choice to queue.
+    fn dispatch(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        while !queue.is_empty() {
+            let Ok(permit) = self.slots.clone().try_acquire_owned() else {
+                return;
+            };
+            let (_, waiter) = queue.pop().expect("the queue is not empty");
+            // A waiter leaves the queue before it stops listening, so this only returns the slot
+            let _ = waiter.send(permit);
+        }
+    }
+}
+
+/// What a limit applies to, which names its metrics and errors.
+#[derive(Clone, Copy)]
+enum Scope<'a> {
+    Target(&'a str),
+    /// The `concurrency` limit shared by every target.
+    Gateway,
+}
+
+impl Scope<'_> {
+    fn labels(&self, priority: PriorityClass) -> Labels {
+        let priority = ("priority", priority.as_str().to_string());
+        match self {
+            Scope::Target(pattern) => vec![("target", pattern.to_string()), priority],
+            Scope::Gateway => vec![priority],
+        }
+    }
+
+    /// The name of the target's metric or error, or of the gateway's.
+    fn name(&self, target: &'static str, gateway: &'static str) -> &'static str {
+        match self {
+            Scope::Target(_) => target,
+            Scope::Gateway => gateway,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Scope::Target(pattern) => format!("Target {}", pattern),
+            Scope::Gateway => "The gateway".to_string(),
+        }
+    }
+}
+
+/// A concurrency slot held until dropped; the time it was held feeds the service time estimate.
+pub struct ConcurrencyPermit {
+    limit: Arc<TargetLimit>,
+    acquired_at: Instant,
+    /// Requests in flight when the slot was taken, this one included.
+    in_flight: usize,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ConcurrencyPermit {
+    /// Reports how long the invoke made with this slot waited on Lambda and whether it was
+    /// throttled, for targets with `adaptive_concurrency`. Only invokes that reached Lambda are
+    /// reported: requests turned away before that tell nothing about the function's capacity.
+    pub fn report(&self, latency: Duration, throttled: bool) {
+        let Some(adaptive) = &self.limit.adaptive else {
+            return;
+        };
+        let sample = Sample {
+            latency_ms: latency.as_secs_f64() * 1000.0,
+            throttled,
+            in_flight: self.in_flight,
+        };
+        let mut adaptive = adaptive.lock().unwrap();
+        let Some(limit) = adaptive.controller.observe(sample, crate::access_log::now_ms()) else {
+            return;
+        };
+        tracing::info!(limit, throttled, "Adjusted the adaptive concurrency limit");
+        if limit > adaptive.capacity {
+            self.limit.slots.add_permits(limit - adaptive.capacity);
+            adaptive.capacity = limit;
+            drop(adaptive);
+            self.limit.dispatch();
+        } else {
+            adaptive.capacity -= self.limit.slots.forget_permits(adaptive.capacity - limit);
+        }
+    }
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        let held_ms = self.acquired_at.elapsed().as_secs_f64() * 1000.0;
+        self.limit.service_time.lock().unwrap().observe(held_ms);
+        let Some(permit) = self.permit.take() else {
+            return;
+        };
+        if let Some(adaptive) = &self.limit.adaptive {
+            // A slot beyond a limit that has since been cut is retired instead of freed
+            let mut adaptive = adaptive.lock().unwrap();
+            if adaptive.capacity > adaptive.controller.limit() {
+                adaptive.capacity -= 1;
+                permit.forget();
+                return;
+            }
+        }
+        drop(permit);
+        self.limit.dispatch();
+    }
+}
+
+/// Concurrency and queue of one limited target, as shown on `/status`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct QueueStatus {
+    pub in_flight: usize,
+    pub queued: usize,
+    /// Requests queued per priority class.
+    pub queued_by_priority: BTreeMap<PriorityClass, usize>,
+    pub max_concurrency: usize,
+    pub max_queue_depth: usize,
+    pub service_time_ms: Option<f64>,
+    /// Set for targets with `adaptive_concurrency`, whose current limit is `max_concurrency`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adaptive: Option<AdaptiveStatus>,
+}
+
+impl QueueStatus {
+    fn new(limit: &TargetLimit) -> Self {
+        let queue = limit.queue.lock().unwrap();
+        Self {
+            in_flight: limit.in_flight(),
+            queued: queue.len(),
+            queued_by_priority: PriorityClass::ALL
+                .into_iter()
+                .map(|class| (class, queue.depth(class)))
+                .collect(),
+            max_concurrency: limit.current(),
+            max_queue_depth: limit.max_queue_depth,
+            service_time_ms: limit.service_time.lock().unwrap().value(),
+            adaptive: limit
+                .adaptive
+                .as_ref()
+                .map(|adaptive| adaptive.lock().unwrap().controller.status()),
+        }
+    }
+}
+
+/// A place in the queue, left when the request gets a slot, gives up waiting or is shed.
+struct Queued<'a> {
+    limit: &'a TargetLimit,
+    id: u64,
+    slot: oneshot::Receiver<OwnedSemaphorePermit>,
+    telemetry: &'a Telemetry,
+    depth_gauge: &'static str,
+    labels: &'a Labels,
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.limit.queue.lock().unwrap().remove(self.id);
+        // A slot sent just as the request gave up goes to the next one
+        self.slot.close();
+        if let Ok(permit) = self.slot.try_recv() {
+            drop(permit);
+            self.limit.dispatch();
+        }
+        self.telemetry.gauge(self.depth_gauge, self.labels.clone(), -1);
+    }
+}
+
+/// A 429 for a request that found no slot, with a `Retry-After` for `queued` requests ahead of it.
+fn rejection(
+    limit: &TargetLimit,
+    queued: usize,
+    code: &'static str,
+    message: String,
+    retry_after: &RetryAfterConfig,
+) -> GatewayError {
+    let service_ms = limit.service_time.lock().unwrap().value();
+    // Until a service time is measured, a second is a fair guess
+    let wait = service_ms.map(|ms| ewma::estimate_wait(queued, limit.current(), ms));
+    let retry_after = RetryAfterHints::limiter(wait).resolve(1, retry_after);
+    GatewayError::new(ErrorPhase::Invoke, StatusCode::TOO_MANY_REQUESTS, code, message).with_retry_after(retry_after)
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(telemetry: Telemetry) -> Self {
+        Self {
+            limits: Arc::default(),
+            global: Arc::default(),
+            telemetry,
+        }
+    }
+
+    /// Takes a slot for a request to `pattern`, waiting in the target's queue in class `priority`
+    /// if all slots are busy. Returns `None` for targets without `max_concurrency` or
+    /// `adaptive_concurrency`.
+    pub async fn acquire(
+        &self,
+        pattern: &str,
+        target: &Target,
+        priority: PriorityClass,
+        weights: &PriorityWeights,
+        retry_after: &RetryAfterConfig,
+    ) -> Result<Option<ConcurrencyPermit>, GatewayError> {
+        let max_queue_depth = match target.overflow {
+            Some(Overflow::Reject) => 0,
+            _ => target.max_queue_depth,
+        };
+        let limit = match (target.max_concurrency, &target.adaptive_concurrency) {
+            (_, Some(adaptive)) => self.limit(pattern, adaptive.initial_limit, max_queue_depth, Some(adaptive)),
+            (Some(max_concurrency), None) => self.limit(pattern, max_concurrency, max_queue_depth, None),
+            (None, None) => return Ok(None),
+        };
+        let admission = Admission {
+            priority,
+            weight: weights.weight(priority),
+            queue_timeout_ms: target.queue_timeout_ms,
+            retry_after,
+        };
+        self.acquire_slot(Scope::Target(pattern), limit, admission)
+            .await
+            .map(Some)
+    }
+
+    /// Takes a slot of the gateway's `concurrency` limit, as [`ConcurrencyLimiter::acquire`] does
+    /// for a target. Returns `None` without a limit.
+    pub async fn acquire_global(
+        &self,
+        settings: &GlobalConcurrency,
+        priority: PriorityClass,
+        weights: &PriorityWeights,
+        retry_after: &RetryAfterConfig,
+    ) -> Result<Option<ConcurrencyPermit>, GatewayError> {
+        let Some(max_concurrency) = settings.max_concurrency else {
+            return Ok(None);
+        };
+        let limit = {
+            let mut global = self.global.lock().unwrap();
+            match &*global {
+                Some(limit)
+                    if (limit.max_concurrency, limit.max_queue_depth)
+                        == (max_concurrency, settings.max_queue_depth) =>
+                {
+                    limit.clone()
+                }
+                // Started afresh when a reload changed the settings
+                _ => {
+                    let limit = Arc::new(TargetLimit::new(max_concurrency, settings.max_queue_depth, None));
+                    global.insert(limit).clone()
+                }
+            }
+        };
+        let admission = Admission {
+            priority,
+            weight: weights.weight(priority),
+            queue_timeout_ms: settings.queue_timeout_ms,
+            retry_after,
+        };
+        self.acquire_slot(Scope::Gateway, limit, admission).await.map(Some)
+    }
+
+    async fn acquire_slot(
+        &self,
+        scope: Scope<'_>,
+        limit: Arc<TargetLimit>,
+        admission: Admission<'_>,
+    ) -> Result<ConcurrencyPermit, GatewayError> {
+        let labels = scope.labels(admission.priority);
+        let depth_gauge = scope.name("target_queue_depth", "gateway_queue_depth");
+        let started_at = Instant::now();
+        let place = {
+            let mut queue = limit.queue.lock().unwrap();
+            // A free slot goes to requests already waiting first
+            let free = match queue.is_empty() {
+                true => limit.slots.clone().try_acquire_owned().ok(),
+                false => None,
+            };
+            match free {
+                Some(permit) => Place::Free(permit),
+                None => {
+                    let (tx, rx) = oneshot::channel();
+                    let ahead = queue.len();
+                    match queue.push(admission.priority, admission.weight, tx) {
+                        // The waiter shed sees its sender dropped and answers 429
+                        Ok(Pushed { id, shed }) => Place::Queued {
+                            id,
+                            shed: shed.map(|(class, _)| class),
+                            slot: rx,
+                            ahead,
+                        },
+                        Err(_) => {
+                            drop(queue);
+                            self.telemetry.increment(
+                                scope.name("target_queue_rejections_total", "gateway_queue_rejections_total"),
+                                labels,
+                            );
+                            let message = format!(
+                                "{} has {} requests in flight and {} queued",
+                                scope.describe(),
+                                limit.current(),
+                                ahead
+                            );
+                            let code = scope.name("target_queue_full", "gateway_queue_full");
+                            return Err(rejection(&limit, ahead, code, message, admission.retry_after));
+                        }
+                    }
+                }
+            }
+        };
+        let permit = match place {
+            Place::Free(permit) => permit,
+            Place::Queued { id, shed, slot, ahead } => {
+                if let Some(class) = shed {
+                    self.telemetry.increment(
+                        scope.name("target_queue_shed_total", "gateway_queue_shed_total"),
+                        scope.labels(class),
+                    );
+                }
+                self.telemetry.gauge(depth_gauge, labels.clone(), 1);
+                let mut queued = Queued {
+                    limit: &limit,
+                    id,
+                    slot,
+                    telemetry: &self.telemetry,
+                    depth_gauge,
+                    labels: &labels,
+                };
+                let received = match admission.queue_timeout_ms {
+                    Some(ms) => tokio::time::timeout(Duration::from_millis(ms), &mut queued.slot)
+                        .await
+                        .ok(),
+                    None => Some((&mut queued.slot).await),
+                };
+                match received {
+                    Some(Ok(permit)) => permit,
+                    Some(Err(_)) => {
+                        let message = format!("{} shed this request for one of a higher priority", scope.describe());
+                        let code = scope.name("target_queue_shed", "gateway_queue_shed");
+                        return Err(rejection(&limit, ahead, code, message, admission.retry_after));
+                    }
+                    None => {
+                        self.telemetry.increment(
+                            scope.name("target_queue_timeouts_total", "gateway_queue_timeouts_total"),
+                            labels.clone(),
+                        );
+                        let message = format!(
+                            "{} had no free slot within {} ms",
+                            scope.describe(),
+                            admission.queue_timeout_ms.unwrap_or_default()
+                        );
+                        let code = scope.name("target_queue_timeout", "gateway_queue_timeout");
+                        return Err(rejection(&limit, ahead, code, message, admission.retry_after));
+                    }
+                }
+            }
+        };
+        self.telemetry.observe(
+            scope.name("queue_wait_ms", "gateway_queue_wait_ms"),
+            labels,
+            started_at.elapsed().as_secs_f64() * 1000.0,
+        );
+        Ok(ConcurrencyPermit {
+            in_flight: limit.in_flight(),
+            limit,
+            acquired_at: Instant::now(),
+            permit: Some(permit),
+        })
+    }
+
+    /// Forgets the limit of a target removed by a reload. Requests holding its slots keep them.
+    pub fn remove(&self, pattern: &str) {
+        self.limits.lock().unwrap().remove(pattern);
+    }
+
+    pub fn status(&self) -> BTreeMap<String, QueueStatus> {
+        self.limits
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(pattern, limit)| (pattern.clone(), QueueStatus::new(limit)))
+            .collect()
+    }
+
+    /// Concurrency and queue of the gateway's `concurrency` limit, once a request met it.
+    pub fn global_status(&self) -> Option<QueueStatus> {
+        self.global.lock().unwrap().as_deref().map(QueueStatus::new)
+    }
+
+    /// The limit of `pattern`, started afresh when a reload changed its settings. An adaptive limit
+    /// starts from its `initial_limit`.
+    fn limit(
+        &self,
+        pattern: &str,
+        max_concurrency: usize,
+        max_queue_depth: usize,
+        adaptive: Option<&AdaptiveConcurrency>,
+    ) -> Arc<TargetLimit> {
+        let mut limits = self.limits.lock().unwrap();
+        match limits.get(pattern) {
+            Some(limit)
+                if (
+                    limit.max_concurrency,
+                    limit.max_queue_depth,
+                    limit.adaptive_settings.as_ref(),
+                ) == (max_concurrency, max_queue_depth, adaptive) =>
+            {
+                limit.clone()
+            }
+            _ => {
+                let limit = Arc::new(TargetLimit::new(max_concurrency, max_queue_depth, adaptive));
+                limits.insert(pattern.to_string(), limit.clone());
+                limit
+            }
+        }
+    }
+}
+
+/// Where a request lands: on a free slot, or in the queue behind `ahead` others, possibly after
+/// shedding a request of class `shed`.
+enum Place {
+    Free(OwnedSemaphorePermit),
+    Queued {
+        id: u64,
+        shed: Option<PriorityClass>,
+        slot: oneshot::Receiver<OwnedSemaphorePermit>,
+        ahead: usize,
+    },
+}
+
+/// How a request waits for a slot.
+struct Admission<'a> {
+    priority: PriorityClass,
+    weight: u32,
+    queue_timeout_ms: Option<u64>,
+    retry_after: &'a RetryAfterConfig,
+}
+
+#[cfg(test)]
+mod tests {
+    include!("limiter_tests.rs");
+}
@@ -0,0 +1,259 @@
+use super::*;
+use crate::config::{AdaptiveConcurrency, Overflow, TelemetryConfig};
+use std::time::Duration;
+
+const RETRY_AFTER: RetryAfterConfig = RetryAfterConfig {
+    default_secs: 1,
+    max_secs: 60,
+};
+
+fn limiter() -> ConcurrencyLimiter {
+    ConcurrencyLimiter::new(Telemetry::new(&TelemetryConfig::default()))
+}
+
+impl ConcurrencyLimiter {
+    /// Takes a slot for a request of the default class.
+    async fn acquire_normal(
+        &self,
+        pattern: &str,
+        target: &Target,
+        retry_after: &RetryAfterConfig,
+    ) -> Result<Option<ConcurrencyPermit>, GatewayError> {
+        let weights = PriorityWeights::default();
+        self.acquire(pattern, target, PriorityClass::Normal, &weights, retry_after).await
+    }
+}
+
+fn limited(max_concurrency: usize, max_queue_depth: usize) -> Target {
+    Target {
+        max_concurrency: Some(max_concurrency),
+        max_queue_depth,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_unlimited_target_gets_no_permit() {
+    assert!(limiter().acquire_normal("/a", &Target::default(), &RETRY_AFTER).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_queues_up_to_depth_then_rejects_with_retry_after() {
+    let limiter = limiter();
+    let target = limited(1, 1);
+    let held = limiter.acquire_normal("/a", &target, &RETRY_AFTER).await.unwrap().unwrap();
+
+    let waiter = {
+        let (limiter, target) = (limiter.clone(), target.clone());
+        tokio::spawn(async move { limiter.acquire_normal("/a", &target, &RETRY_AFTER).await.map(|p| p.is_some()) })
+    };
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(limiter.status()["/a"].queued, 1);
+
+    let rejected = limiter.acquire_normal("/a", &target, &RETRY_AFTER).await.err().unwrap();
+    assert_eq!(rejected.status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(rejected.code, "target_queue_full");
+    assert_eq!(rejected.retry_after_secs, Some(1));
+
+    drop(held);
+    assert!(waiter.await.unwrap().unwrap());
+    let status = &limiter.status()["/a"];
+    assert_eq!((status.in_flight, status.queued), (0, 0));
+    assert!(status.service_time_ms.is_some());
+}
+
+#[tokio::test]
+async fn test_abandoned_waiter_leaves_the_queue() {
+    let limiter = limiter();
+    let target = limited(1, 1);
+    let _held = limiter.acquire_normal("/a", &target, &RETRY_AFTER).await.unwrap();
+
+    let waiting = limiter.acquire_normal("/a", &target, &RETRY_AFTER);
+    let gave_up = tokio::time::timeout(Duration::from_millis(20), waiting).await;
+    assert!(gave_up.is_err());
+    assert_eq!(limiter.status()["/a"].queued, 0);
+}
+
+#[tokio::test]
+async fn test_overflow_reject_ignores_queue_depth() {
+    let limiter = limiter();
+    let target = Target {
+        overflow: Some(Overflow::Reject),
+        ..limited(1, 5)
+    };
+    let _held = limiter.acquire_normal("/a", &target, &RETRY_AFTER).await.unwrap();
+    let rejected = limiter.acquire_normal("/a", &target, &RETRY_AFTER).await.err().unwrap();
+    assert_eq!((rejected.status, rejected.code), (StatusCode::TOO_MANY_REQUESTS, "target_queue_full"));
+    assert_eq!(limiter.status()["/a"].max_queue_depth, 0);
+}
+
+#[tokio::test]
+async fn test_queue_timeout_rejects_and_leaves_the_queue() {
+    let limiter = limiter();
+    let target = Target {
+        overflow: Some(Overflow::Queue),
+        queue_timeout_ms: Some(30),
+        ..limited(1, 5)
+    };
+    let held = limiter.acquire_normal("/a", &target, &RETRY_AFTER).await.unwrap();
+    let started = Instant::now();
+    let rejected = limiter.acquire_normal("/a", &target, &RETRY_AFTER).await.err().unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(30));
+    assert_eq!((rejected.status, rejected.code), (StatusCode::TOO_MANY_REQUESTS, "target_queue_timeout"));
+    assert_eq!(rejected.retry_after_secs, Some(1));
+    assert_eq!(limiter.status()["/a"].queued, 0);
+
+    // A slot freed within the timeout goes to the waiter
+    let waiter = {
+        let (limiter, target) = (limiter.clone(), target.clone());
+        tokio::spawn(async move { limiter.acquire_normal("/a", &target, &RETRY_AFTER).await.map(|p| p.is_some()) })
+    };
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    drop(held);
+    assert!(waiter.await.unwrap().unwrap());
+}
+
+#[tokio::test]
+async fn test_retry_after_follows_service_time() {
+    let limiter = limiter();
+    let target = limited(1, 0);
+    let slow = limiter.acquire_normal("/a", &target, &RETRY_AFTER).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+    drop(slow);
+
+    let _held = limiter.acquire_normal("/a", &target, &RETRY_AFTER).await.unwrap();
+    let rejected = limiter.acquire_normal("/a", &target, &RETRY_AFTER).await.err().unwrap();
+    assert_eq!(rejected.retry_after_secs, Some(2));
+}
+
+#[tokio::test]
+async fn test_retry_after_capped_at_max_secs() {
+    let limiter = limiter();
+    let target = limited(1, 0);
+    let slow = limiter.acquire_normal("/a", &target, &RETRY_AFTER).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+    drop(slow);
+
+    let _held = limiter.acquire_normal("/a", &target, &RETRY_AFTER).await.unwrap();
+    let capped = RetryAfterConfig {
+        max_secs: 1,
+        ..RETRY_AFTER
+    };
+    let rejected = limiter.acquire_normal("/a", &target, &capped).await.err().unwrap();
+    assert_eq!(rejected.retry_after_secs, Some(1));
+}
+
+#[tokio::test]
+async fn test_adaptive_limit_retires_held_slots_when_cut() {
+    let limiter = limiter();
+    let target = Target {
+        adaptive_concurrency: Some(AdaptiveConcurrency {
+            initial_limit: 4,
+            window: 1,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let mut held = Vec::new();
+    for _ in 0..4 {
+        held.push(limiter.acquire_normal("/a", &target, &RETRY_AFTER).await.unwrap().unwrap());
+    }
+    assert!(limiter.acquire_normal("/a", &target, &RETRY_AFTER).await.is_err());
+
+    // A throttle cuts the limit while every slot is held
+    held[0].report(Duration::from_millis(5), true);
+    let status = &limiter.status()["/a"];
+    assert_eq!((status.in_flight, status.max_concurrency), (4, 3));
+    let adjustments = &status.adaptive.as_ref().unwrap().recent_adjustments;
+    assert_eq!((adjustments[0].from, adjustments[0].to), (4, 3));
+
+    // The first slot released is retired, the next one freed
+    held.truncate(2);
+    assert_eq!(limiter.status()["/a"].in_flight, 2);
+    let _third = limiter.acquire_normal("/a", &target, &RETRY_AFTER).await.unwrap().unwrap();
+    let rejected = limiter.acquire_normal("/a", &target, &RETRY_AFTER).await.err().unwrap();
+    assert_eq!(rejected.code, "target_queue_full");
+}
+
+/// Queues a request of `class` that records when it gets a slot and releases it at once.
+fn spawn_waiter(
+    limiter: &ConcurrencyLimiter,
+    target: &Target,
+    class: PriorityClass,
+    admitted: &Arc<Mutex<Vec<PriorityClass>>>,
+) -> tokio::task::JoinHandle<Result<(), &'static str>> {
+    let (limiter, target, admitted) = (limiter.clone(), target.clone(), admitted.clone());
+    tokio::spawn(async move {
+        let weights = PriorityWeights::default();
+        let permit = limiter.acquire("/a", &target, class, &weights, &RETRY_AFTER).await;
+        permit.map_err(|e| e.code)?;
+        admitted.lock().unwrap().push(class);
+        Ok(())
+    })
+}
+
+#[tokio::test]
+async fn test_freed_slots_go_by_priority() {
+    let limiter = limiter();
+    let target = limited(1, 10);
+    let held = limiter.acquire_normal("/a", &target, &RETRY_AFTER).await.unwrap();
+    let admitted = Arc::new(Mutex::new(Vec::new()));
+    let mut waiters = Vec::new();
+    for class in [PriorityClass::Batch, PriorityClass::Normal, PriorityClass::Critical] {
+        waiters.push(spawn_waiter(&limiter, &target, class, &admitted));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let queued = &limiter.status()["/a"].queued_by_priority;
+    assert_eq!(queued.values().copied().collect::<Vec<_>>(), [1, 1, 1]);
+
+    drop(held);
+    for waiter in waiters {
+        waiter.await.unwrap().unwrap();
+    }
+    let order = admitted.lock().unwrap().clone();
+    assert_eq!(order, [PriorityClass::Critical, PriorityClass::Normal, PriorityClass::Batch]);
+    assert_eq!(limiter.status()["/a"].in_flight, 0);
+}
+
+#[tokio::test]
+async fn test_full_queue_sheds_lower_priority() {
+    let limiter = limiter();
+    let target = limited(1, 1);
+    let held = limiter.acquire_normal("/a", &target, &RETRY_AFTER).await.unwrap();
+    let admitted = Arc::new(Mutex::new(Vec::new()));
+    let batch = spawn_waiter(&limiter, &target, PriorityClass::Batch, &admitted);
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    // A normal request takes the batch request's place; another one finds nothing lower to shed
+    let normal = spawn_waiter(&limiter, &target, PriorityClass::Normal, &admitted);
+    assert_eq!(batch.await.unwrap(), Err("target_queue_shed"));
+    let rejected = limiter.acquire_normal("/a", &target, &RETRY_AFTER).await.err().unwrap();
+    assert_eq!(rejected.code, "target_queue_full");
+
+    drop(held);
+    normal.await.unwrap().unwrap();
+    assert_eq!(*admitted.lock().unwrap(), [PriorityClass::Normal]);
+}
+
+#[tokio::test]
+async fn test_global_limit() {
+    let limiter = limiter();
+    let settings = GlobalConcurrency {
+        max_concurrency: Some(1),
+        ..Default::default()
+    };
+    let weights = PriorityWeights::default();
+    let acquire = || limiter.acquire_global(&settings, PriorityClass::Critical, &weights, &RETRY_AFTER);
+    assert!(limiter.global_status().is_none());
+    let held = acquire().await.unwrap();
+    assert!(held.is_some());
+    let rejected = acquire().await.err().unwrap();
+    assert_eq!((rejected.status, rejected.code), (StatusCode::TOO_MANY_REQUESTS, "gateway_queue_full"));
+    assert_eq!(limiter.global_status().unwrap().in_flight, 1);
+
+    drop(held);
+    assert!(acquire().await.unwrap().is_some());
+    let unlimited = GlobalConcurrency::default();
+    let acquired = limiter.acquire_global(&unlimited, PriorityClass::Batch, &weights, &RETRY_AFTER).await;
+    assert!(acquired.unwrap().is_none());
+}
@@ -0,0 +1,142 @@
+use crate::config::LogDedupConfig;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Key shared by all errors once `max_keys` distinct keys are tracked.
+const OVERFLOW_KEY: (&str, &str) = ("*", "*");
+
+/// Collapses repeated identical errors on hot paths, so a target that is down logs one line per
+/// window instead of one per request. Errors are identical when they share a target and error
+/// code; the first of each window is logged and the rest are counted and reported in a summary
+/// when the window ends. Memory is bounded by `max_keys`.
+#[derive(Clone)]
+pub struct LogDedup {
+    config: LogDedupConfig,
+    windows: Arc<Mutex<HashMap<(String, &'static str), Window>>>,
+}
+
+struct Window {
+    started_at: Instant,
+    suppressed: u64,
+}
+
+/// Errors suppressed for one key during a window that has ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Suppressed {
+    pub target: String,
+    pub error_code: &'static str,
+    pub count: u64,
+}
+
+impl LogDedup {
+    pub fn new(config: &LogDedupConfig) -> Self {
+        Self {
+            config: config.clone(),
+            windows: Arc::default(),
+        }
+    }
+
+    /// Whether an error of `target` with `error_code` should be logged now.
+    pub fn should_log(&self, target: &str, error_code: &'static str) -> bool {
+        self.should_log_at(target, error_code, Instant::now())
+    }
+
+    pub(crate) fn should_log_at(&self, target: &str, error_code: &'static str, now: Instant) -> bool {
+        if !self.config.enabled {
+            return true;
+        }
+        let window = self.window();
+        let mut windows = self.windows.lock().unwrap();
+        let mut key = (target.to_string(), error_code);
+        if !windows.contains_key(&key) && windows.len() >= self.config.max_keys {
+            windows.retain(|_, w| now.duration_since(w.started_at) < window || w.suppressed > 0);
+            if windows.len() >= self.config.max_keys {
+                key = (OVERFLOW_KEY.0.to_string(), OVERFLOW_KEY.1);
+            }
+        }
+        match windows.get_mut(&key) {
+            Some(w) if now.duration_since(w.started_at) < window => {
+                w.suppressed += 1;
+                false
+            }
+            _ => {
+                let suppressed = u64::from(!self.config.log_first);
+                let previous = windows.insert(
+                    key.clone(),
+                    Window {
+                        started_at: now,
+                        suppressed,
+                    },
+                );
+                // An elapsed window that `flush` has not reported yet
+                if let Some(previous) = previous.filter(|w| w.suppressed > 0) {
+                    report(&key.0, key.1, previous.suppressed, window);
+                }
+                self.config.log_first
+            }
+        }
+    }
+
+    /// Ends the windows that have elapsed, logging a summary for each that suppressed errors, and
+    /// returns those summaries.
+    pub fn flush(&self) -> Vec<Suppressed> {
+        self.flush_at(Instant::now())
+    }
+
+    pub(crate) fn flush_at(&self, now: Instant) -> Vec<Suppressed> {
+        let window = self.window();
+        let mut summaries = Vec::new();
+        self.windows.lock().unwrap().retain(|(target, error_code), w| {
+            if now.duration_since(w.started_at) < window {
+                return true;
+            }
+            if w.suppressed > 0 {
+                summaries.push(Suppressed {
+                    target: target.clone(),
+                    error_code,
+                    count: w.suppressed,
+                });
+            }
+            false
+        });
+        summaries.sort_by(|a, b| (&a.target, a.error_code).cmp(&(&b.target, b.error_code)));
+        for summary in &summaries {
+            report(&summary.target, summary.error_code, summary.count, window);
+        }
+        summaries
+    }
+
+    /// Reports suppressed errors as their windows end.
+    pub fn spawn_flusher(&self) -> tokio::task::JoinHandle<()> {
+        let dedup = self.clone();
+        tokio::spawn(async move {
+            let period = (dedup.window() / 4).max(Duration::from_secs(1));
+            let mut tick = tokio::time::interval(period);
+            loop {
+                tick.tick().await;
+                dedup.flush();
+            }
+        })
+    }
+
+    fn window(&self) -> Duration {
+        Duration::from_secs(self.config.window_secs)
+    }
+}
+
+fn report(target: &str, error_code: &str, count: u64, window: Duration) {
+    tracing::error!(
+        target,
+        error_code,
+        suppressed = count,
+        "Suppressed {} similar errors in the last {}s",
+        count,
+        window.as_secs()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    include!("log_dedup_tests.rs");
+}
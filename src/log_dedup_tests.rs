@@ -0,0 +1,82 @@
+use super::*;
+
+fn dedup(log_first: bool, max_keys: usize) -> LogDedup {
+    LogDedup::new(&LogDedupConfig {
+        enabled: true,
+        window_secs: 60,
+        log_first,
+        max_keys,
+    })
+}
+
+#[test]
+fn test_first_error_logged_and_rest_summarized() {
+    let dedup = dedup(true, 16);
+    let start = Instant::now();
+
+    assert!(dedup.should_log_at("/orders", "invoke_failed", start));
+    for i in 1..=4812 {
+        assert!(!dedup.should_log_at("/orders", "invoke_failed", start + Duration::from_millis(i)));
+    }
+    // A different error code of the same target is its own key
+    assert!(dedup.should_log_at("/orders", "function_error", start));
+
+    assert_eq!(dedup.flush_at(start + Duration::from_secs(30)), vec![]);
+    assert_eq!(
+        dedup.flush_at(start + Duration::from_secs(60)),
+        vec![Suppressed {
+            target: "/orders".to_string(),
+            error_code: "invoke_failed",
+            count: 4812,
+        }]
+    );
+    // The next window starts afresh
+    assert!(dedup.should_log_at("/orders", "invoke_failed", start + Duration::from_secs(61)));
+    assert_eq!(dedup.flush_at(start + Duration::from_secs(200)), vec![]);
+}
+
+#[test]
+fn test_without_log_first_only_summaries_are_logged() {
+    let dedup = dedup(false, 16);
+    let start = Instant::now();
+
+    assert!(!dedup.should_log_at("/a", "invoke_failed", start));
+    assert!(!dedup.should_log_at("/a", "invoke_failed", start));
+    let summaries = dedup.flush_at(start + Duration::from_secs(60));
+    assert_eq!(summaries[0].count, 2);
+}
+
+#[test]
+fn test_keys_beyond_the_limit_share_one_entry() {
+    let dedup = dedup(true, 2);
+    let start = Instant::now();
+
+    assert!(dedup.should_log_at("/a", "invoke_failed", start));
+    assert!(dedup.should_log_at("/b", "invoke_failed", start));
+    assert!(dedup.should_log_at("/c", "invoke_failed", start));
+    for target in ["/d", "/e", "/f"] {
+        assert!(!dedup.should_log_at(target, "invoke_failed", start));
+    }
+    assert!(dedup.windows.lock().unwrap().len() <= 3);
+
+    let summaries = dedup.flush_at(start + Duration::from_secs(60));
+    assert_eq!(
+        summaries,
+        vec![Suppressed {
+            target: "*".to_string(),
+            error_code: "*",
+            count: 3,
+        }]
+    );
+}
+
+#[test]
+fn test_disabled_logs_everything() {
+    let dedup = LogDedup::new(&LogDedupConfig {
+        enabled: false,
+        ..Default::default()
+    });
+    for _ in 0..3 {
+        assert!(dedup.should_log("/a", "invoke_failed"));
+    }
+}
@@ -1,6 +1,61 @@
+use clap::{Parser, Subcommand};
+use lambda_web_gateway::body_store::{run_decrypt_capture, DecryptCaptureArgs};
+use lambda_web_gateway::check::{run_check, CheckArgs};
+use lambda_web_gateway::config::Config;
+use lambda_web_gateway::routes::RouteRegistry;
 use lambda_web_gateway::run_app;
+use lambda_web_gateway::support::SupportBundle;
+use lambda_web_gateway::validate_service::{run_serve_validate, ServeValidateArgs};
+use std::process::ExitCode;
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Print every route with what serves it, and any conflicts, then exit
+    #[arg(long)]
+    print_routes: bool,
+    /// Print a support bundle with the redacted config, version and route table as JSON, then exit
+    #[arg(long)]
+    dump_support_bundle: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Invoke a target end to end and print the response
+    Check(CheckArgs),
+    /// Print the records of a flight recorder file, decrypting them with the given keys
+    DecryptCapture(DecryptCaptureArgs),
+    /// Serve an endpoint that validates posted config documents, without calling AWS
+    ServeValidate(ServeValidateArgs),
+}
 
 #[tokio::main]
-async fn main() {
-    run_app().await;
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    if cli.print_routes {
+        let registry = RouteRegistry::new(&Config::load_unvalidated("config.yaml"));
+        print!("{}", registry);
+        return match registry.errors().next() {
+            Some(_) => ExitCode::FAILURE,
+            None => ExitCode::SUCCESS,
+        };
+    }
+    if cli.dump_support_bundle {
+        let bundle = SupportBundle::new(&Config::load_unvalidated("config.yaml"));
+        println!("{}", serde_json::to_string_pretty(&bundle).expect("bundle serializes"));
+        return ExitCode::SUCCESS;
+    }
+    match cli.command {
+        None => {
+            run_app().await;
+            ExitCode::SUCCESS
+        }
+        Some(Command::Check(args)) => run_check(Config::load("config.yaml"), args).await,
+        Some(Command::DecryptCapture(args)) => {
+            run_decrypt_capture(&Config::load_unvalidated("config.yaml").body_storage, args)
+        }
+        Some(Command::ServeValidate(args)) => run_serve_validate(args).await,
+    }
 }
@@ -0,0 +1,111 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A piece of in-memory gateway state, such as a cache or a rate limiter, whose size counts against
+/// the shared `state_memory_budget_bytes`.
+pub trait StatefulStore: Send + Sync + 'static {
+    fn name(&self) -> &str;
+
+    /// Approximate bytes held by the store.
+    fn usage_bytes(&self) -> usize;
+
+    /// Drops entries worth about `bytes`, least valuable first, and returns the bytes freed.
+    fn evict(&self, bytes: usize) -> usize;
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct MemoryStatus {
+    pub budget_bytes: Option<usize>,
+    pub used_bytes: usize,
+    pub stores: BTreeMap<String, usize>,
+}
+
+/// Keeps the sum of all registered stores under one budget. When the total exceeds it, every store
+/// is asked to give back its share of the excess in proportion to its usage, so no store is
+/// starved to make room for another.
+#[derive(Clone, Default)]
+pub struct MemoryBudget {
+    budget_bytes: Option<usize>,
+    stores: Arc<Mutex<Vec<Arc<dyn StatefulStore>>>>,
+}
+
+/// Eviction passes per enforcement, for stores that free less than asked.
+const MAX_EVICTION_PASSES: usize = 3;
+
+impl MemoryBudget {
+    pub fn new(budget_bytes: Option<usize>) -> Self {
+        Self {
+            budget_bytes,
+            stores: Default::default(),
+        }
+    }
+
+    pub fn register(&self, store: Arc<dyn StatefulStore>) {
+        self.stores.lock().unwrap().push(store);
+    }
+
+    pub fn status(&self) -> MemoryStatus {
+        let stores: BTreeMap<String, usize> = self
+            .stores()
+            .iter()
+            .map(|store| (store.name().to_string(), store.usage_bytes()))
+            .collect();
+        MemoryStatus {
+            budget_bytes: self.budget_bytes,
+            used_bytes: stores.values().sum(),
+            stores,
+        }
+    }
+
+    /// Evicts from every store in proportion to its usage until the total fits the budget.
+    /// Returns the bytes freed per store.
+    pub fn enforce(&self) -> BTreeMap<String, usize> {
+        let mut freed = BTreeMap::new();
+        let Some(budget) = self.budget_bytes else {
+            return freed;
+        };
+        let stores = self.stores();
+        for _ in 0..MAX_EVICTION_PASSES {
+            let usage: Vec<usize> = stores.iter().map(|store| store.usage_bytes()).collect();
+            let total: usize = usage.iter().sum();
+            if total <= budget {
+                break;
+            }
+            let excess = total - budget;
+            for (store, used) in stores.iter().zip(usage) {
+                if used == 0 {
+                    continue;
+                }
+                // Round up so the shares always cover the excess
+                let share = (excess as u128 * used as u128).div_ceil(total as u128) as usize;
+                *freed.entry(store.name().to_string()).or_default() += store.evict(share.min(used));
+            }
+        }
+        if !freed.is_empty() {
+            tracing::info!(?freed, budget, "Evicted state to stay within the memory budget");
+        }
+        freed
+    }
+
+    fn stores(&self) -> Vec<Arc<dyn StatefulStore>> {
+        self.stores.lock().unwrap().clone()
+    }
+}
+
+/// Enforces the budget once a second.
+pub fn spawn_enforcer(budget: MemoryBudget) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tick.tick().await;
+            budget.enforce();
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    include!("memory_tests.rs");
+}
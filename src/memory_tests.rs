@@ -0,0 +1,110 @@
+use super::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A store of fixed-size entries that evicts whole entries.
+struct SyntheticStore {
+    name: String,
+    entry_bytes: usize,
+    entries: AtomicUsize,
+}
+
+impl SyntheticStore {
+    fn new(name: &str, entry_bytes: usize, entries: usize) -> Arc<Self> {
+        Arc::new(Self {
+            name: name.to_string(),
+            entry_bytes,
+            entries: AtomicUsize::new(entries),
+        })
+    }
+}
+
+impl StatefulStore for SyntheticStore {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn usage_bytes(&self) -> usize {
+        self.entries.load(Ordering::SeqCst) * self.entry_bytes
+    }
+
+    fn evict(&self, bytes: usize) -> usize {
+        let entries = self.entries.load(Ordering::SeqCst);
+        let evicted = bytes.div_ceil(self.entry_bytes).min(entries);
+        self.entries.store(entries - evicted, Ordering::SeqCst);
+        evicted * self.entry_bytes
+    }
+}
+
+#[test]
+fn test_status_sums_stores() {
+    let budget = MemoryBudget::new(Some(1000));
+    budget.register(SyntheticStore::new("cache", 10, 30));
+    budget.register(SyntheticStore::new("limiter", 1, 50));
+
+    let status = budget.status();
+    assert_eq!(status.budget_bytes, Some(1000));
+    assert_eq!(status.used_bytes, 350);
+    assert_eq!(status.stores["cache"], 300);
+    assert_eq!(status.stores["limiter"], 50);
+    assert!(budget.enforce().is_empty());
+}
+
+#[test]
+fn test_eviction_is_proportional() {
+    let budget = MemoryBudget::new(Some(400));
+    let cache = SyntheticStore::new("cache", 1, 600);
+    let dedup = SyntheticStore::new("dedup", 1, 200);
+    budget.register(cache.clone());
+    budget.register(dedup.clone());
+
+    let freed = budget.enforce();
+
+    // 400 bytes over budget, split 3:1 like the usage
+    assert_eq!(freed["cache"], 300);
+    assert_eq!(freed["dedup"], 100);
+    assert_eq!(cache.usage_bytes(), 300);
+    assert_eq!(dedup.usage_bytes(), 100);
+}
+
+#[test]
+fn test_eviction_rounds_up_to_cover_excess() {
+    let budget = MemoryBudget::new(Some(100));
+    for name in ["a", "b", "c"] {
+        budget.register(SyntheticStore::new(name, 1, 41));
+    }
+
+    budget.enforce();
+
+    let status = budget.status();
+    assert!(status.used_bytes <= 100);
+    // Each store keeps the same share
+    assert_eq!(status.stores["a"], status.stores["b"]);
+    assert_eq!(status.stores["b"], status.stores["c"]);
+}
+
+#[test]
+fn test_coarse_stores_do_not_starve_others() {
+    let budget = MemoryBudget::new(Some(1000));
+    // Evicts 500 bytes at a time
+    let blobs = SyntheticStore::new("blobs", 500, 2);
+    let small = SyntheticStore::new("small", 1, 100);
+    budget.register(blobs.clone());
+    budget.register(small.clone());
+
+    budget.enforce();
+
+    assert!(budget.status().used_bytes <= 1000);
+    assert_eq!(blobs.usage_bytes(), 500);
+    // The small store only gives back its own share of the 100 byte excess
+    assert_eq!(small.usage_bytes(), 90);
+}
+
+#[test]
+fn test_no_budget_never_evicts() {
+    let budget = MemoryBudget::new(None);
+    let cache = SyntheticStore::new("cache", 1, 1_000_000);
+    budget.register(cache.clone());
+
+    assert!(budget.enforce().is_empty());
+    assert_eq!(cache.usage_bytes(), 1_000_000);
+}
@@ -0,0 +1,218 @@
+//! Test doubles shared by the unit tests of several modules.
+
+use crate::access_log::AccessLog;
+use crate::config::Config;
+use crate::event_queue::EventQueue;
+use crate::invoker::{BufferedOutput, InvokeError, InvokeRequest, Invoker, StreamOutput};
+use crate::memory::MemoryBudget;
+use crate::state_store::{StateStore, StoreError, StoreResult};
+use crate::streaming::PayloadStream;
+use crate::telemetry::Telemetry;
+use crate::ApplicationState;
+use axum::body::Bytes;
+use futures::future::BoxFuture;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Builds application state around `invoker`, with observability that goes nowhere.
+pub(crate) fn test_state_with(config: Config, invoker: Arc<dyn Invoker>) -> ApplicationState {
+    let telemetry = Telemetry::new(&config.telemetry);
+    let memory = MemoryBudget::new(config.state_memory_budget_bytes);
+    let event_queue = EventQueue::new(&config.event_retry, invoker.clone(), telemetry.clone());
+    ApplicationState::assemble(config, invoker, telemetry, AccessLog::disabled(), memory, event_queue)
+}
+
+/// A buffered Lambda response with status 200 and `body`.
+pub(crate) fn ok_output(body: &str) -> Result<BufferedOutput, InvokeError> {
+    let payload = serde_json::json!({"statusCode": 200, "body": body}).to_string();
+    Ok(BufferedOutput {
+        payload: Bytes::from(payload),
+        function_error: None,
+        request_id: Some("mock-request-id".to_string()),
+    })
+}
+
+/// Replays scripted invoke results in order, answering buffered invokes with 200 once the script
+/// runs out, and records every request it receives.
+#[derive(Default)]
+pub(crate) struct MockInvoker {
+    results: Mutex<VecDeque<Result<BufferedOutput, InvokeError>>>,
+    streams: Mutex<VecDeque<Result<PayloadStream, InvokeError>>>,
+    events: Mutex<VecDeque<Result<Option<String>, InvokeError>>>,
+    dry_runs: Mutex<VecDeque<Result<(), InvokeError>>>,
+    function_state: Mutex<Option<InvokeError>>,
+    delay: Duration,
+    requests: Mutex<Vec<InvokeRequest>>,
+    calls: AtomicUsize,
+    in_flight: Arc<AtomicUsize>,
+    max_in_flight: AtomicUsize,
+}
+
+impl MockInvoker {
+    pub(crate) fn new(results: Vec<Result<BufferedOutput, InvokeError>>) -> Arc<Self> {
+        Arc::new(Self {
+            results: Mutex::new(results.into()),
+            ..Default::default()
+        })
+    }
+
+    /// Replays scripted invoke results in order, each call delayed by `delay`.
+    pub(crate) fn with_delayed_results(
+        results: Vec<Result<BufferedOutput, InvokeError>>,
+        delay: Duration,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            results: Mutex::new(results.into()),
+            delay,
+            ..Default::default()
+        })
+    }
+
+    /// Replays scripted streaming invoke results in order, each call delayed by `delay`.
+    pub(crate) fn with_streams(streams: Vec<Result<PayloadStream, InvokeError>>, delay: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            streams: Mutex::new(streams.into()),
+            delay,
+            ..Default::default()
+        })
+    }
+
+    /// Replays scripted asynchronous invoke results in order, accepting every invoke once the
+    /// script runs out.
+    pub(crate) fn with_events(events: Vec<Result<Option<String>, InvokeError>>) -> Arc<Self> {
+        Arc::new(Self {
+            events: Mutex::new(events.into()),
+            ..Default::default()
+        })
+    }
+
+    /// Replays scripted dry run results in order, passing every dry run once the script runs out.
+    pub(crate) fn with_dry_runs(dry_runs: Vec<Result<(), InvokeError>>) -> Arc<Self> {
+        Arc::new(Self {
+            dry_runs: Mutex::new(dry_runs.into()),
+            ..Default::default()
+        })
+    }
+
+    /// Delays every call, to observe how many run at once.
+    pub(crate) fn with_delay(delay: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            delay,
+            ..Default::default()
+        })
+    }
+
+    /// Makes `check_function` fail with `error`.
+    pub(crate) fn set_function_error(&self, error: InvokeError) {
+        *self.function_state.lock().unwrap() = Some(error);
+    }
+
+    pub(crate) fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn max_in_flight(&self) -> usize {
+        self.max_in_flight.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn requests(&self) -> Vec<InvokeRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    fn call<T: Send + 'static>(&self, result: Result<T, InvokeError>) -> BoxFuture<'static, Result<T, InvokeError>> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+        let (delay, in_flight) = (self.delay, self.in_flight.clone());
+        Box::pin(async move {
+            tokio::time::sleep(delay).await;
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            result
+        })
+    }
+}
+
+impl Invoker for MockInvoker {
+    fn invoke(&self, request: InvokeRequest) -> BoxFuture<'static, Result<BufferedOutput, InvokeError>> {
+        self.requests.lock().unwrap().push(request);
+        let result = self
+            .results
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| ok_output(""));
+        self.call(result)
+    }
+
+    fn invoke_stream(&self, request: InvokeRequest) -> BoxFuture<'static, Result<StreamOutput, InvokeError>> {
+        self.requests.lock().unwrap().push(request);
+        let result = self
+            .streams
+            .lock()
+            .unwrap()
+            .pop_front()
+            .expect("unexpected streaming invoke");
+        self.call(result.map(|payload| StreamOutput {
+            payload,
+            request_id: Some("mock-stream-request-id".to_string()),
+        }))
+    }
+
+    fn invoke_event(&self, request: InvokeRequest) -> BoxFuture<'static, Result<Option<String>, InvokeError>> {
+        self.requests.lock().unwrap().push(request);
+        let result = self
+            .events
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| Ok(Some("mock-request-id".to_string())));
+        self.call(result)
+    }
+
+    fn invoke_dry_run(&self, request: InvokeRequest) -> BoxFuture<'static, Result<(), InvokeError>> {
+        self.requests.lock().unwrap().push(request);
+        let result = self.dry_runs.lock().unwrap().pop_front().unwrap_or(Ok(()));
+        self.call(result)
+    }
+
+    fn check_function(
+        &self,
+        _function_name: &str,
+        _qualifier: Option<&str>,
+    ) -> BoxFuture<'static, Result<(), InvokeError>> {
+        let result = match self.function_state.lock().unwrap().clone() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        };
+        self.call(result)
+    }
+}
+
+/// A shared store that is down.
+pub(crate) struct UnreachableStore;
+
+impl StateStore for UnreachableStore {
+    fn get(&self, _key: &str) -> BoxFuture<'static, StoreResult<Option<Vec<u8>>>> {
+        Box::pin(async { Err(StoreError("connection refused".to_string())) })
+    }
+
+    fn set(&self, _key: &str, _value: Vec<u8>, _ttl: Option<Duration>) -> BoxFuture<'static, StoreResult<()>> {
+        Box::pin(async { Err(StoreError("connection refused".to_string())) })
+    }
+
+    fn increment(&self, _key: &str, _delta: i64, _ttl: Option<Duration>) -> BoxFuture<'static, StoreResult<i64>> {
+        Box::pin(std::future::pending())
+    }
+
+    fn compare_and_swap(
+        &self,
+        _key: &str,
+        _expected: Option<Vec<u8>>,
+        _new: Vec<u8>,
+        _ttl: Option<Duration>,
+    ) -> BoxFuture<'static, StoreResult<bool>> {
+        Box::pin(async { Err(StoreError("connection refused".to_string())) })
+    }
+}
@@ -0,0 +1,220 @@
+use crate::config::{NdjsonConfig, OnInvalidRecord};
+use crate::expr::Attributes;
+use crate::telemetry::Telemetry;
+use axum::http::{HeaderMap, Method};
+use serde_json::Value;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// Error ending a stream at an invalid record, with `on_invalid: abort`.
+#[derive(Debug)]
+pub struct InvalidRecord {
+    /// Position of the record in the stream, counted from 1; blank lines are not counted.
+    pub record: u64,
+    pub reason: String,
+}
+
+impl std::fmt::Display for InvalidRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "stream aborted: record {} {}", self.record, self.reason)
+    }
+}
+
+impl std::error::Error for InvalidRecord {}
+
+/// The request as a record filter sees it. Records are filtered after the handler has returned,
+/// so it is copied.
+#[derive(Clone, Debug, Default)]
+pub struct RequestAttributes {
+    method: Method,
+    path: String,
+    query: String,
+    headers: HeaderMap,
+    client_ip: Option<IpAddr>,
+    key_id: Option<String>,
+}
+
+impl RequestAttributes {
+    pub fn new(request: &Attributes) -> Self {
+        Self {
+            method: request.method.clone(),
+            path: request.path.to_string(),
+            query: request.query.to_string(),
+            headers: request.headers.clone(),
+            client_ip: request.client_ip,
+            key_id: request.key_id.map(String::from),
+        }
+    }
+
+    fn with_record<'a>(&'a self, record: &'a Value) -> Attributes<'a> {
+        Attributes {
+            method: &self.method,
+            path: &self.path,
+            query: &self.query,
+            headers: &self.headers,
+            client_ip: self.client_ip,
+            key_id: self.key_id.as_deref(),
+            record: Some(record),
+        }
+    }
+}
+
+/// Records of one stream by what became of them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RecordCounts {
+    pub forwarded: u64,
+    /// Not valid JSON.
+    pub invalid: u64,
+    /// Longer than `max_record_bytes`.
+    pub too_large: u64,
+    /// Did not meet the filter.
+    pub filtered: u64,
+}
+
+/// Splits a streamed body into newline-delimited records, whatever chunks it arrives in, and
+/// keeps the records that are valid JSON and meet the target's filter. A record split across
+/// chunks waits in a buffer for the rest of its line.
+#[derive(Clone, Debug)]
+pub struct RecordStream {
+    config: NdjsonConfig,
+    request: Arc<RequestAttributes>,
+    /// Start of a record whose end of line has not arrived yet.
+    partial: Vec<u8>,
+    /// Set while skipping the rest of a record that outgrew `max_record_bytes`.
+    skipping: bool,
+    counts: RecordCounts,
+}
+
+impl RecordStream {
+    pub fn new(config: &NdjsonConfig, request: RequestAttributes) -> Self {
+        Self {
+            config: config.clone(),
+            request: Arc::new(request),
+            partial: Vec::new(),
+            skipping: false,
+            counts: RecordCounts::default(),
+        }
+    }
+
+    pub fn counts(&self) -> RecordCounts {
+        self.counts
+    }
+
+    /// Appends the complete records of `chunk` that survive, each ending with `\n`, to `out`.
+    /// With `on_invalid: abort`, stops at the first invalid record; the records before it are in
+    /// `out` all the same.
+    pub fn push(&mut self, mut chunk: &[u8], out: &mut Vec<u8>) -> Result<(), InvalidRecord> {
+        while let Some(end) = chunk.iter().position(|&b| b == b'\n') {
+            let (line, rest) = (&chunk[..end], &chunk[end + 1..]);
+            chunk = rest;
+            if std::mem::take(&mut self.skipping) {
+                continue;
+            }
+            if self.partial.is_empty() {
+                self.complete(line, out)?;
+            } else {
+                let mut record = std::mem::take(&mut self.partial);
+                record.extend_from_slice(line);
+                let completed = self.complete(&record, out);
+                // The buffer is kept for the next partial record
+                record.clear();
+                self.partial = record;
+                completed?;
+            }
+        }
+        if !self.skipping && !chunk.is_empty() {
+            if self.partial.len() + chunk.len() > self.config.max_record_bytes {
+                self.partial.clear();
+                self.skipping = true;
+                return self.invalid(InvalidKind::TooLarge, "is longer than max_record_bytes".to_string());
+            }
+            self.partial.extend_from_slice(chunk);
+        }
+        Ok(())
+    }
+
+    /// Handles the last record of a stream that ended without a final `\n`.
+    pub fn finish(&mut self, out: &mut Vec<u8>) -> Result<(), InvalidRecord> {
+        let partial = std::mem::take(&mut self.partial);
+        self.skipping = false;
+        self.record(&partial, out)
+    }
+
+    /// Handles a record whose end of line has arrived, which may make it too long at last.
+    fn complete(&mut self, line: &[u8], out: &mut Vec<u8>) -> Result<(), InvalidRecord> {
+        if line.len() > self.config.max_record_bytes {
+            return self.invalid(InvalidKind::TooLarge, "is longer than max_record_bytes".to_string());
+        }
+        self.record(line, out)
+    }
+
+    fn record(&mut self, line: &[u8], out: &mut Vec<u8>) -> Result<(), InvalidRecord> {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.iter().all(u8::is_ascii_whitespace) {
+            return Ok(());
+        }
+        let record: Value = match serde_json::from_slice(line) {
+            Ok(record) => record,
+            Err(e) => return self.invalid(InvalidKind::Json, format!("is not valid JSON: {}", e)),
+        };
+        if let Some(filter) = &self.config.filter {
+            if !filter.holds(&self.request.with_record(&record)) {
+                self.counts.filtered += 1;
+                return Ok(());
+            }
+        }
+        self.counts.forwarded += 1;
+        out.extend_from_slice(line);
+        out.push(b'\n');
+        Ok(())
+    }
+
+    /// Counts an invalid record, failing when the stream is to abort at it.
+    fn invalid(&mut self, kind: InvalidKind, reason: String) -> Result<(), InvalidRecord> {
+        match kind {
+            InvalidKind::Json => self.counts.invalid += 1,
+            InvalidKind::TooLarge => self.counts.too_large += 1,
+        }
+        match self.config.on_invalid {
+            OnInvalidRecord::Drop => Ok(()),
+            OnInvalidRecord::Abort => Err(InvalidRecord {
+                record: self.position(),
+                reason,
+            }),
+        }
+    }
+
+    fn position(&self) -> u64 {
+        let counts = self.counts;
+        counts.forwarded + counts.invalid + counts.too_large + counts.filtered
+    }
+
+    /// Counts the records of the stream as `ndjson_records_total{target, outcome}`.
+    pub fn report(&self, telemetry: &Telemetry, pattern: &str) {
+        let counts = self.counts;
+        for (outcome, n) in [
+            ("forwarded", counts.forwarded),
+            ("invalid", counts.invalid),
+            ("too_large", counts.too_large),
+            ("filtered", counts.filtered),
+        ] {
+            if n > 0 {
+                telemetry.add(
+                    "ndjson_records_total",
+                    vec![("target", pattern.to_string()), ("outcome", outcome.to_string())],
+                    n,
+                );
+            }
+        }
+    }
+}
+
+enum InvalidKind {
+    Json,
+    TooLarge,
+}
+
+#[cfg(test)]
+mod tests {
+    include!("ndjson_tests.rs");
+}
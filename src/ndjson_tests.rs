@@ -0,0 +1,193 @@
+use super::*;
+use crate::config::Condition;
+
+const BODY: &str = concat!(
+    "{\"tenant\":\"a\",\"n\":1}\n",
+    "\n",
+    "{\"tenant\":\"b\",\"msg\":\"x\\ny\"}\r\n",
+    "not json\n",
+    "[1,2]\n",
+    "{\"tenant\":\"a\",\"text\":\"caf\u{e9} \u{1f600}\"}",
+);
+
+fn request(key_id: Option<&str>) -> RequestAttributes {
+    let headers = HeaderMap::new();
+    RequestAttributes::new(&Attributes {
+        method: &Method::GET,
+        path: "/events",
+        query: "",
+        headers: &headers,
+        client_ip: None,
+        key_id,
+        record: None,
+    })
+}
+
+fn ndjson(on_invalid: OnInvalidRecord, filter: Option<&str>) -> NdjsonConfig {
+    NdjsonConfig {
+        on_invalid,
+        filter: filter.map(Condition::new),
+        ..Default::default()
+    }
+}
+
+/// Runs `chunks` through a fresh record stream, returning what it forwarded, the error it ended
+/// with, if any, and its counts.
+fn run(config: &NdjsonConfig, chunks: &[&[u8]]) -> (String, Option<InvalidRecord>, RecordCounts) {
+    let mut records = RecordStream::new(config, request(Some("a")));
+    let mut out = Vec::new();
+    let mut error = None;
+    for chunk in chunks {
+        if let Err(e) = records.push(chunk, &mut out) {
+            error = Some(e);
+            break;
+        }
+    }
+    if error.is_none() {
+        error = records.finish(&mut out).err();
+    }
+    (String::from_utf8(out).unwrap(), error, records.counts())
+}
+
+const KEPT: &str = concat!(
+    "{\"tenant\":\"a\",\"n\":1}\n",
+    "{\"tenant\":\"b\",\"msg\":\"x\\ny\"}\n",
+    "[1,2]\n",
+    "{\"tenant\":\"a\",\"text\":\"caf\u{e9} \u{1f600}\"}\n",
+);
+
+#[test]
+fn test_drops_invalid_records() {
+    let (out, error, counts) = run(&ndjson(OnInvalidRecord::Drop, None), &[BODY.as_bytes()]);
+    assert_eq!(out, KEPT);
+    assert!(error.is_none());
+    assert_eq!(
+        counts,
+        RecordCounts {
+            forwarded: 4,
+            invalid: 1,
+            ..Default::default()
+        }
+    );
+}
+
+#[test]
+fn test_split_at_every_position() {
+    let config = ndjson(OnInvalidRecord::Drop, None);
+    let body = BODY.as_bytes();
+    for at in 0..=body.len() {
+        let (out, _, counts) = run(&config, &[&body[..at], &body[at..]]);
+        assert_eq!(out, KEPT, "split at {}", at);
+        assert_eq!((counts.forwarded, counts.invalid), (4, 1), "split at {}", at);
+    }
+}
+
+#[test]
+fn test_split_at_every_pair_of_positions() {
+    let config = ndjson(OnInvalidRecord::Drop, Some(r#"record("tenant") == key_id"#));
+    let body = BODY.as_bytes();
+    let kept = concat!(
+        "{\"tenant\":\"a\",\"n\":1}\n",
+        "{\"tenant\":\"a\",\"text\":\"caf\u{e9} \u{1f600}\"}\n",
+    );
+    for first in 0..=body.len() {
+        for second in first..=body.len() {
+            let chunks = [&body[..first], &body[first..second], &body[second..]];
+            let (out, _, counts) = run(&config, &chunks);
+            assert_eq!(out, kept, "split at {} and {}", first, second);
+            assert_eq!(counts.filtered, 2, "split at {} and {}", first, second);
+        }
+    }
+}
+
+#[test]
+fn test_byte_at_a_time() {
+    let chunks: Vec<&[u8]> = BODY.as_bytes().chunks(1).collect();
+    let (out, _, counts) = run(&ndjson(OnInvalidRecord::Drop, None), &chunks);
+    assert_eq!(out, KEPT);
+    assert_eq!(counts.forwarded, 4);
+}
+
+#[test]
+fn test_filter_by_key_id() {
+    let config = ndjson(OnInvalidRecord::Drop, Some(r#"record("tenant") == key_id"#));
+    let mut records = RecordStream::new(&config, request(Some("b")));
+    let mut out = Vec::new();
+    records.push(BODY.as_bytes(), &mut out).unwrap();
+    records.finish(&mut out).unwrap();
+    assert_eq!(out, b"{\"tenant\":\"b\",\"msg\":\"x\\ny\"}\n");
+    // Records without the field match nothing
+    let (out, _, counts) = run(&config, &[&b"[1]\n{}\n"[..]]);
+    assert_eq!((out.as_str(), counts.filtered), ("", 2));
+}
+
+#[test]
+fn test_abort_at_invalid_record() {
+    let config = ndjson(OnInvalidRecord::Abort, None);
+    let body = BODY.as_bytes();
+    for at in 0..=body.len() {
+        let (out, error, counts) = run(&config, &[&body[..at], &body[at..]]);
+        // Everything before the bad record is forwarded, nothing after it
+        assert_eq!(
+            out,
+            "{\"tenant\":\"a\",\"n\":1}\n{\"tenant\":\"b\",\"msg\":\"x\\ny\"}\n",
+            "split at {}",
+            at
+        );
+        let error = error.unwrap();
+        assert_eq!(error.record, 3);
+        assert!(error.to_string().starts_with("stream aborted: record 3 is not valid JSON"), "{}", error);
+        assert_eq!((counts.forwarded, counts.invalid), (2, 1));
+    }
+}
+
+#[test]
+fn test_last_record_without_newline() {
+    let config = ndjson(OnInvalidRecord::Abort, None);
+    let (out, error, _) = run(&config, &[&b"{\"a\":1}\n{\"a\":"[..], &b"2}"[..]]);
+    assert_eq!(out, "{\"a\":1}\n{\"a\":2}\n");
+    assert!(error.is_none());
+    // A record cut short by the end of the stream is invalid
+    let (out, error, _) = run(&config, &[&b"{\"a\":1}\n{\"a\":"[..]]);
+    assert_eq!(out, "{\"a\":1}\n");
+    assert_eq!(error.unwrap().record, 2);
+}
+
+#[test]
+fn test_records_over_max_length() {
+    let config = NdjsonConfig {
+        max_record_bytes: 8,
+        ..ndjson(OnInvalidRecord::Drop, None)
+    };
+    let body = b"[1,2,3]\n[1,2,3,4,5,6]\n\"short\"\n[1,2,3,4,5,6,7,8,9,10]\n[4]";
+    for at in 0..=body.len() {
+        let (out, _, counts) = run(&config, &[&body[..at], &body[at..]]);
+        assert_eq!(out, "[1,2,3]\n\"short\"\n[4]\n", "split at {}", at);
+        assert_eq!((counts.forwarded, counts.too_large), (3, 2), "split at {}", at);
+    }
+
+    let config = NdjsonConfig {
+        on_invalid: OnInvalidRecord::Abort,
+        ..config
+    };
+    let (out, error, _) = run(&config, &[&b"[1]\n[1,2,3,"[..], &b"4,5]\n[2]\n"[..]]);
+    assert_eq!(out, "[1]\n");
+    let error = error.unwrap();
+    assert_eq!((error.record, error.to_string().ends_with("max_record_bytes")), (2, true));
+}
+
+#[tokio::test]
+async fn test_report_counts_outcomes() {
+    let telemetry = Telemetry::new(&Default::default());
+    let mut records = RecordStream::new(&ndjson(OnInvalidRecord::Drop, None), request(None));
+    records.push(BODY.as_bytes(), &mut Vec::new()).unwrap();
+    records.finish(&mut Vec::new()).unwrap();
+    records.report(&telemetry, "/events");
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    let registry = telemetry.snapshot();
+    let count = |outcome: &str| {
+        let labels = vec![("target", "/events".to_string()), ("outcome", outcome.to_string())];
+        registry.counters.get(&("ndjson_records_total", labels)).copied()
+    };
+    assert_eq!((count("forwarded"), count("invalid"), count("filtered")), (Some(4), Some(1), None));
+}
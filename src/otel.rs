@@ -0,0 +1,178 @@
+use crate::config::{OtelConfig, OtlpProtocol};
+use crate::telemetry::Telemetry;
+use futures::future::BoxFuture;
+use opentelemetry::trace::{TraceError, TracerProvider as _};
+use opentelemetry::{global, ExportError, KeyValue};
+use opentelemetry_otlp::{SpanExporterBuilder, WithExportConfig};
+use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use opentelemetry_sdk::trace::{BatchSpanProcessor, Tracer, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use std::fmt;
+use std::time::Duration;
+use tracing::{Level, Subscriber};
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Name of the tracer the gateway's spans are exported with.
+const TRACER_NAME: &str = "lambda-web-gateway";
+
+/// A tracer exporting to the collector of `config` in batches, from the background. Installed as
+/// the global provider, so that [`shutdown`] flushes it. Failed exports and spans dropped from a
+/// full batch queue are reported to `telemetry`.
+pub fn tracer(config: &OtelConfig, telemetry: &Telemetry) -> Result<Tracer, String> {
+    let endpoint = config.endpoint.clone().unwrap_or_default();
+    let timeout = Duration::from_millis(config.timeout_ms);
+    let exporter: SpanExporterBuilder = match config.protocol {
+        OtlpProtocol::Grpc => opentelemetry_otlp::new_exporter()
+            .tonic()
+            .with_endpoint(endpoint)
+            .with_timeout(timeout)
+            .into(),
+        OtlpProtocol::HttpProtobuf => opentelemetry_otlp::new_exporter()
+            .http()
+            .with_endpoint(traces_url(&endpoint))
+            .with_timeout(timeout)
+            .into(),
+    };
+    let exporter = ReportingExporter {
+        inner: exporter.build_span_exporter().map_err(|e| e.to_string())?,
+        telemetry: telemetry.clone(),
+    };
+    let resource = Resource::new([KeyValue::new("service.name", config.service_name.clone())]);
+    let provider = TracerProvider::builder()
+        .with_span_processor(BatchSpanProcessor::builder(exporter, runtime::Tokio).build())
+        .with_config(opentelemetry_sdk::trace::Config::default().with_resource(resource))
+        .build();
+    let tracer = provider.tracer(TRACER_NAME);
+    global::set_tracer_provider(provider);
+
+    let telemetry = telemetry.clone();
+    let _ = global::set_error_handler(move |error| match error {
+        // Counted by the exporter already
+        global::Error::Trace(TraceError::ExportFailed(_) | TraceError::ExportTimedOut(_)) => {}
+        // Mostly a span the batch processor's full queue had no room for
+        error => telemetry.export_failed(1, &format!("span not exported: {}", error)),
+    });
+    Ok(tracer)
+}
+
+/// Exporter reporting how each batch of `inner` went to the telemetry facade. Its errors are
+/// handed back as [`Reported`], so the global error handler does not count them twice.
+struct ReportingExporter<E> {
+    inner: E,
+    telemetry: Telemetry,
+}
+
+impl<E: fmt::Debug> fmt::Debug for ReportingExporter<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReportingExporter").field("inner", &self.inner).finish()
+    }
+}
+
+impl<E: SpanExporter> SpanExporter for ReportingExporter<E> {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        // An export the batch processor times out on is dropped, and counted as failed then
+        let mut pending = PendingExport {
+            telemetry: self.telemetry.clone(),
+            spans: batch.len() as u64,
+            settled: false,
+        };
+        let export = self.inner.export(batch);
+        Box::pin(async move {
+            let result = export.await;
+            pending.settled = true;
+            match result {
+                Ok(()) => {
+                    pending.telemetry.export_succeeded();
+                    Ok(())
+                }
+                Err(e) => {
+                    let reason = format!("span export failed: {}", e);
+                    pending.telemetry.export_failed(pending.spans, &reason);
+                    Err(TraceError::ExportFailed(Box::new(Reported(e))))
+                }
+            }
+        })
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    fn force_flush(&mut self) -> BoxFuture<'static, ExportResult> {
+        self.inner.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource)
+    }
+}
+
+/// Counts the spans of an export dropped before it finished as failed.
+struct PendingExport {
+    telemetry: Telemetry,
+    spans: u64,
+    settled: bool,
+}
+
+impl Drop for PendingExport {
+    fn drop(&mut self) {
+        if !self.settled {
+            self.telemetry.export_failed(self.spans, "span export timed out");
+        }
+    }
+}
+
+/// An export error the telemetry facade has been told about.
+#[derive(Debug)]
+struct Reported(TraceError);
+
+impl fmt::Display for Reported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for Reported {}
+
+impl ExportError for Reported {
+    fn exporter_name(&self) -> &'static str {
+        "otlp"
+    }
+}
+
+/// The URL HTTP exports are posted to: the endpoint's `/v1/traces`, unless it names it already.
+fn traces_url(endpoint: &str) -> String {
+    let endpoint = endpoint.trim_end_matches('/');
+    if endpoint.ends_with("/v1/traces") {
+        endpoint.to_string()
+    } else {
+        format!("{}/v1/traces", endpoint)
+    }
+}
+
+/// Turns the gateway's tracing spans into spans of `tracer`. Fields named `otel.*` set the name,
+/// kind and status of a span, and the others become its attributes. Events logged at info and
+/// above, and those of [`crate::SPAN_EVENTS`], become span events.
+pub fn layer<S>(tracer: Tracer) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(filter_fn(|metadata| {
+            metadata.is_span() || metadata.target() == crate::SPAN_EVENTS || *metadata.level() <= Level::INFO
+        }))
+}
+
+/// Exports the spans still batched, for shutdown.
+pub async fn shutdown() {
+    // The flush blocks until the batch processor, itself on the runtime, is done
+    let _ = tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await;
+}
+
+#[cfg(test)]
+mod tests {
+    include!("otel_tests.rs");
+}
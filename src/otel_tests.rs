@@ -0,0 +1,147 @@
+use super::*;
+use crate::config::{Config, LambdaInvokeMode, Target};
+use crate::mock::{ok_output, test_state_with, MockInvoker};
+use axum::body::{Body, Bytes};
+use futures::StreamExt;
+use opentelemetry::trace::SpanKind;
+use opentelemetry::Value;
+use opentelemetry_sdk::export::trace::SpanData;
+use opentelemetry_sdk::testing::trace::InMemorySpanExporter;
+use opentelemetry_sdk::trace::TracerProvider;
+use std::collections::BTreeMap;
+use tower::ServiceExt;
+use tracing_subscriber::prelude::*;
+
+const STREAM_PRELUDE: &[u8] = b"{\"statusCode\": 200, \"headers\": {\"content-type\": \"text/plain\"}}\0\0\0\0\0\0\0\0";
+
+/// Serves one `GET` of `uri` with every span exported to memory, returning the finished spans.
+async fn exported_spans(config: Config, invoker: std::sync::Arc<MockInvoker>, uri: &str) -> Vec<SpanData> {
+    let exporter = InMemorySpanExporter::default();
+    let provider = TracerProvider::builder().with_simple_exporter(exporter.clone()).build();
+    let subscriber = tracing_subscriber::registry().with(layer(provider.tracer("test")));
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let app = crate::build_router(test_state_with(config, invoker));
+    let request = axum::http::Request::get(uri).body(Body::empty()).unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), 200);
+    // The spans end with the body
+    axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    exporter.get_finished_spans().unwrap()
+}
+
+fn attribute<'a>(span: &'a SpanData, key: &str) -> Option<&'a Value> {
+    span.attributes.iter().find(|kv| kv.key.as_str() == key).map(|kv| &kv.value)
+}
+
+fn orders_config(mode: LambdaInvokeMode) -> Config {
+    Config {
+        lambda_function_name: "orders".to_string(),
+        lambda_invoke_mode: mode,
+        targets: BTreeMap::from([("/orders/*rest".to_string(), Target::default())]),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_invoke_span_is_a_child_of_the_request_span() {
+    let invoker = MockInvoker::new(vec![ok_output("ok")]);
+    let spans = exported_spans(orders_config(LambdaInvokeMode::Buffered), invoker, "/orders/42").await;
+    let server = spans.iter().find(|span| span.span_kind == SpanKind::Server).unwrap();
+    let client = spans.iter().find(|span| span.span_kind == SpanKind::Client).unwrap();
+
+    assert_eq!(server.name, "GET /orders/*rest");
+    assert_eq!(client.span_context.trace_id(), server.span_context.trace_id());
+    assert_eq!(client.parent_span_id, server.span_context.span_id());
+    assert_eq!(attribute(server, "http.request.method"), Some(&Value::from("GET")));
+    assert_eq!(attribute(server, "http.route"), Some(&Value::from("/orders/*rest")));
+    assert_eq!(attribute(server, "http.response.status_code"), Some(&Value::I64(200)));
+    assert_eq!(client.name, "invoke orders");
+    assert_eq!(attribute(client, "faas.invoked_name"), Some(&Value::from("orders")));
+    assert_eq!(attribute(client, "aws.lambda.invoke_mode"), Some(&Value::from("buffered")));
+    // Debug logs stay out of the spans
+    assert!(client.events.events.is_empty() && server.events.events.is_empty());
+}
+
+#[tokio::test]
+async fn test_streamed_invoke_span_marks_first_byte_and_completion() {
+    let stream = futures::stream::iter([STREAM_PRELUDE, b"streamed".as_slice()])
+        .map(|chunk| Ok(Bytes::from_static(chunk)))
+        .boxed();
+    let invoker = MockInvoker::with_streams(vec![Ok(stream)], std::time::Duration::ZERO);
+    let spans = exported_spans(orders_config(LambdaInvokeMode::ResponseStream), invoker, "/orders/42").await;
+    let server = spans.iter().find(|span| span.span_kind == SpanKind::Server).unwrap();
+    let client = spans.iter().find(|span| span.span_kind == SpanKind::Client).unwrap();
+
+    assert_eq!(client.parent_span_id, server.span_context.span_id());
+    assert_eq!(attribute(client, "aws.lambda.invoke_mode"), Some(&Value::from("response_stream")));
+    let events: Vec<&str> = client.events.events.iter().map(|event| event.name.as_ref()).collect();
+    assert_eq!(events, ["first_streamed_byte", "stream_complete"]);
+}
+
+#[test]
+fn test_http_exports_go_to_the_traces_path() {
+    assert_eq!(traces_url("http://collector:4318"), "http://collector:4318/v1/traces");
+    assert_eq!(traces_url("http://collector:4318/"), "http://collector:4318/v1/traces");
+    assert_eq!(traces_url("http://collector:4318/v1/traces"), "http://collector:4318/v1/traces");
+}
+
+/// Exporter whose exports fail while `failing` is set.
+#[derive(Debug)]
+struct FlakyExporter {
+    failing: bool,
+}
+
+impl SpanExporter for FlakyExporter {
+    fn export(&mut self, _batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        let result = if self.failing {
+            Err(TraceError::from("collector unavailable"))
+        } else {
+            Ok(())
+        };
+        Box::pin(std::future::ready(result))
+    }
+}
+
+/// A batch of `len` spans, as the batch processor hands them over.
+fn batch(len: usize) -> Vec<SpanData> {
+    let exporter = InMemorySpanExporter::default();
+    let provider = TracerProvider::builder().with_simple_exporter(exporter.clone()).build();
+    let tracer = provider.tracer("test");
+    for _ in 0..len {
+        opentelemetry::trace::Tracer::start(&tracer, "span");
+    }
+    exporter.get_finished_spans().unwrap()
+}
+
+#[tokio::test]
+async fn test_failed_exports_degrade_the_telemetry_facade() {
+    let telemetry = Telemetry::new(&Default::default());
+    let mut exporter = ReportingExporter {
+        inner: FlakyExporter { failing: true },
+        telemetry: telemetry.clone(),
+    };
+    let err = exporter.export(batch(3)).await.unwrap_err();
+    assert!(matches!(err, TraceError::ExportFailed(_)));
+    let status = telemetry.status();
+    assert!(status.observability_degraded);
+    assert_eq!(status.dropped_events, 3);
+
+    exporter.inner.failing = false;
+    exporter.export(batch(1)).await.unwrap();
+    assert!(!telemetry.is_degraded());
+    assert_eq!(telemetry.status().dropped_events, 3);
+}
+
+#[tokio::test]
+async fn test_abandoned_export_counts_as_failed() {
+    let telemetry = Telemetry::new(&Default::default());
+    let mut exporter = ReportingExporter {
+        inner: FlakyExporter { failing: false },
+        telemetry: telemetry.clone(),
+    };
+    // As the batch processor does with an export it times out on
+    drop(exporter.export(batch(2)));
+    assert!(telemetry.is_degraded());
+    assert_eq!(telemetry.status().dropped_events, 2);
+}
@@ -0,0 +1,77 @@
+use crate::telemetry::{Histogram, Labels, MetricKey, MetricsRegistry};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+
+/// Content type of the Prometheus text exposition format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Renders every metric of `registry` in the Prometheus text exposition format, under the names it
+/// is recorded with: `requests_total` is scraped as `requests_total`, and histograms keep their
+/// unit in their name, as `request_duration_ms` does.
+pub fn render(registry: &MetricsRegistry) -> String {
+    let mut out = String::new();
+    write_samples(&mut out, "counter", &registry.counters);
+    write_samples(&mut out, "gauge", &registry.gauges);
+    let mut last = None;
+    for ((name, labels), histogram) in &registry.histograms {
+        if last != Some(*name) {
+            let _ = writeln!(out, "# TYPE {} histogram", name);
+            last = Some(*name);
+        }
+        write_histogram(&mut out, name, labels, histogram);
+    }
+    out
+}
+
+/// Writes one sample per key, with a `# TYPE` line ahead of each metric. Keys are sorted, so the
+/// samples of a metric follow each other.
+fn write_samples<V: Display>(out: &mut String, kind: &str, samples: &BTreeMap<MetricKey, V>) {
+    let mut last = None;
+    for ((name, labels), value) in samples {
+        if last != Some(*name) {
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            last = Some(*name);
+        }
+        let _ = writeln!(out, "{}{} {}", name, label_set(labels, None), value);
+    }
+}
+
+fn write_histogram(out: &mut String, name: &str, labels: &Labels, histogram: &Histogram) {
+    for (bound, count) in histogram.bounds.iter().zip(&histogram.buckets) {
+        let le = bound.to_string();
+        let _ = writeln!(out, "{}_bucket{} {}", name, label_set(labels, Some(&le)), count);
+    }
+    let _ = writeln!(
+        out,
+        "{}_bucket{} {}",
+        name,
+        label_set(labels, Some("+Inf")),
+        histogram.count
+    );
+    let _ = writeln!(out, "{}_sum{} {}", name, label_set(labels, None), histogram.sum);
+    let _ = writeln!(out, "{}_count{} {}", name, label_set(labels, None), histogram.count);
+}
+
+/// `{name="value",...}` with the bucket bound `le` last, or nothing without labels.
+fn label_set(labels: &Labels, le: Option<&str>) -> String {
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .chain(le.map(|le| ("le", le)))
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    include!("prometheus_tests.rs");
+}
@@ -0,0 +1,63 @@
+use super::*;
+use crate::telemetry::LATENCY_BUCKETS_MS;
+
+#[test]
+fn test_renders_counters_and_gauges() {
+    let mut registry = MetricsRegistry::default();
+    let requests = |status: &str| {
+        let labels = vec![("target", "/orders".to_string()), ("status", status.to_string())];
+        ("requests_total", labels)
+    };
+    registry.counters.insert(requests("200"), 3);
+    registry.counters.insert(requests("502"), 1);
+    registry.counters.insert(("invoke_errors_total", vec![]), 2);
+    registry
+        .gauges
+        .insert(("requests_in_flight", vec![("kind", "stream".to_string())]), 4);
+
+    assert_eq!(
+        render(&registry),
+        concat!(
+            "# TYPE invoke_errors_total counter\n",
+            "invoke_errors_total 2\n",
+            "# TYPE requests_total counter\n",
+            "requests_total{target=\"/orders\",status=\"200\"} 3\n",
+            "requests_total{target=\"/orders\",status=\"502\"} 1\n",
+            "# TYPE requests_in_flight gauge\n",
+            "requests_in_flight{kind=\"stream\"} 4\n",
+        )
+    );
+}
+
+#[test]
+fn test_renders_cumulative_histogram_buckets() {
+    let mut histogram = Histogram {
+        bounds: &LATENCY_BUCKETS_MS,
+        buckets: vec![0; LATENCY_BUCKETS_MS.len()],
+        ..Default::default()
+    };
+    for (bucket, bound) in histogram.buckets.iter_mut().zip(&LATENCY_BUCKETS_MS) {
+        *bucket = u64::from(*bound >= 10.0) + u64::from(*bound >= 250.0);
+    }
+    histogram.sum = 207.5;
+    histogram.count = 3;
+    let mut registry = MetricsRegistry::default();
+    let labels = vec![("target", "/orders".to_string())];
+    registry.histograms.insert(("request_duration_ms", labels), histogram);
+
+    let text = render(&registry);
+    assert!(text.starts_with("# TYPE request_duration_ms histogram\n"), "{}", text);
+    assert!(text.contains("request_duration_ms_bucket{target=\"/orders\",le=\"5\"} 0\n"), "{}", text);
+    assert!(text.contains("request_duration_ms_bucket{target=\"/orders\",le=\"250\"} 2\n"), "{}", text);
+    assert!(text.contains("request_duration_ms_bucket{target=\"/orders\",le=\"+Inf\"} 3\n"), "{}", text);
+    assert!(text.contains("request_duration_ms_sum{target=\"/orders\"} 207.5\n"), "{}", text);
+    assert!(text.ends_with("request_duration_ms_count{target=\"/orders\"} 3\n"), "{}", text);
+}
+
+#[test]
+fn test_escapes_label_values() {
+    let mut registry = MetricsRegistry::default();
+    let labels = vec![("target", "/a\"b\\c\nd".to_string())];
+    registry.counters.insert(("requests_total", labels), 1);
+    assert!(render(&registry).contains(r#"requests_total{target="/a\"b\\c\nd"} 1"#));
+}
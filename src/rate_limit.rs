@@ -0,0 +1,155 @@
+use crate::config::{RateLimit, RetryAfterConfig};
+use crate::error::{ErrorPhase, GatewayError};
+use crate::retry_after::RetryAfterHints;
+use crate::state_store::StateStore;
+use crate::telemetry::Telemetry;
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Attempts at taking a token from a bucket that other requests keep changing, before the request
+/// is rejected as if the bucket were empty.
+const MAX_ATTEMPTS: usize = 8;
+
+/// Request rate limits of namespaces, each a token bucket shared by the namespace's targets.
+/// Requests finding the bucket empty are rejected with 429 and a `Retry-After` of when the next
+/// token is due. Buckets are kept in the state store, so replicas sharing it share the limit and
+/// a restart does not refill them. A bucket that cannot be read or stored lets the request through.
+#[derive(Clone)]
+pub struct RateLimiter {
+    store: Arc<dyn StateStore>,
+    telemetry: Telemetry,
+}
+
+fn bucket_key(namespace: &str) -> String {
+    format!("rate_limit:{}", namespace)
+}
+
+/// A bucket as stored: its tokens when last refilled, and the settings it was filled under.
+struct Bucket {
+    tokens: f64,
+    refilled_at_ms: i64,
+    requests_per_second: f64,
+    capacity: f64,
+}
+
+impl Bucket {
+    fn full(settings: &RateLimit, now_ms: i64) -> Self {
+        Self {
+            tokens: settings.capacity(),
+            refilled_at_ms: now_ms,
+            requests_per_second: settings.requests_per_second,
+            capacity: settings.capacity(),
+        }
+    }
+
+    /// The bucket stored as `value`, unless it is malformed or was filled under other settings.
+    fn parse(value: &[u8], settings: &RateLimit) -> Option<Self> {
+        let mut fields = std::str::from_utf8(value).ok()?.split(' ');
+        let bucket = Self {
+            tokens: fields.next()?.parse().ok()?,
+            refilled_at_ms: fields.next()?.parse().ok()?,
+            requests_per_second: fields.next()?.parse().ok()?,
+            capacity: fields.next()?.parse().ok()?,
+        };
+        (bucket.requests_per_second == settings.requests_per_second && bucket.capacity == settings.capacity())
+            .then_some(bucket)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        format!(
+            "{} {} {} {}",
+            self.tokens, self.refilled_at_ms, self.requests_per_second, self.capacity
+        )
+        .into_bytes()
+    }
+
+    fn refill(&mut self, now_ms: i64) {
+        // Replicas' clocks may disagree a little; a bucket never refills backwards
+        let elapsed = now_ms.saturating_sub(self.refilled_at_ms).max(0) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * self.requests_per_second).min(self.capacity);
+        self.refilled_at_ms = self.refilled_at_ms.max(now_ms);
+    }
+}
+
+impl RateLimiter {
+    pub fn new(store: Arc<dyn StateStore>, telemetry: Telemetry) -> Self {
+        Self { store, telemetry }
+    }
+
+    /// Takes a token from the bucket of `namespace`, which starts full, and afresh when a reload
+    /// changed `settings`. Taking it is a compare-and-swap on the stored bucket, so requests on
+    /// several replicas never take the same token.
+    pub async fn admit(
+        &self,
+        namespace: &str,
+        settings: &RateLimit,
+        retry_after: &RetryAfterConfig,
+        now: DateTime<Utc>,
+    ) -> Result<(), GatewayError> {
+        let key = bucket_key(namespace);
+        let now_ms = now.timestamp_millis();
+        // A bucket left alone this long is full again, the same as one that expired
+        let ttl = Duration::try_from_secs_f64(settings.capacity() / settings.requests_per_second)
+            .ok()
+            .map(|ttl| ttl.max(Duration::from_secs(1)));
+        let mut wait = None;
+        for _ in 0..MAX_ATTEMPTS {
+            let current = match self.store.get(&key).await {
+                Ok(current) => current,
+                Err(e) => {
+                    tracing::warn!(
+                        namespace,
+                        "Letting the request through, its rate limit cannot be read: {}",
+                        e
+                    );
+                    return Ok(());
+                }
+            };
+            let mut bucket = current
+                .as_deref()
+                .and_then(|value| Bucket::parse(value, settings))
+                .unwrap_or_else(|| Bucket::full(settings, now_ms));
+            bucket.refill(now_ms);
+            if bucket.tokens < 1.0 {
+                wait = Duration::try_from_secs_f64((1.0 - bucket.tokens) / settings.requests_per_second).ok();
+                break;
+            }
+            bucket.tokens -= 1.0;
+            match self.store.compare_and_swap(&key, current, bucket.encode(), ttl).await {
+                Ok(true) => return Ok(()),
+                // Another request changed the bucket in the meantime
+                Ok(false) => {}
+                Err(e) => {
+                    tracing::warn!(
+                        namespace,
+                        "Letting the request through, its rate limit cannot be stored: {}",
+                        e
+                    );
+                    return Ok(());
+                }
+            }
+        }
+        self.telemetry.increment(
+            "namespace_rate_limited_total",
+            vec![("namespace", namespace.to_string())],
+        );
+        let message = format!(
+            "Namespace {} accepts {} requests per second",
+            namespace, settings.requests_per_second
+        );
+        Err(GatewayError::new(
+            ErrorPhase::Ingress,
+            StatusCode::TOO_MANY_REQUESTS,
+            "namespace_rate_limited",
+            message,
+        )
+        .with_retry_after(RetryAfterHints::limiter(wait).resolve(1, retry_after)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("rate_limit_tests.rs");
+}
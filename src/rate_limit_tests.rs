@@ -0,0 +1,137 @@
+use super::*;
+use crate::config::TelemetryConfig;
+use crate::mock::UnreachableStore;
+use crate::state_store::{FallbackStore, MemoryStore};
+
+fn limiter_on(store: Arc<dyn StateStore>) -> RateLimiter {
+    RateLimiter::new(store, Telemetry::new(&TelemetryConfig::default()))
+}
+
+fn limiter() -> RateLimiter {
+    limiter_on(Arc::new(MemoryStore::default()))
+}
+
+fn retry_after() -> RetryAfterConfig {
+    RetryAfterConfig::default()
+}
+
+fn rate(requests_per_second: f64, burst: Option<u32>) -> RateLimit {
+    RateLimit {
+        requests_per_second,
+        burst,
+    }
+}
+
+/// Rate limit behaviour that must hold whatever store the buckets are kept in. Limiters sharing
+/// `store`, as replicas or a replica and its restart do, share the bucket.
+async fn rate_limit_contract(store: Arc<dyn StateStore>, namespace: &str) {
+    let (first, second) = (limiter_on(store.clone()), limiter_on(store));
+    let settings = rate(2.0, Some(3));
+    let start = Utc::now();
+    for limiter in [&first, &second, &first] {
+        assert!(limiter.admit(namespace, &settings, &retry_after(), start).await.is_ok());
+    }
+    let err = second.admit(namespace, &settings, &retry_after(), start).await.unwrap_err();
+    assert_eq!(err.status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(err.code, "namespace_rate_limited");
+    assert_eq!(err.retry_after_secs, Some(1));
+
+    // Half a second brings one token back at two per second
+    let later = start + chrono::Duration::milliseconds(500);
+    assert!(second.admit(namespace, &settings, &retry_after(), later).await.is_ok());
+    assert!(first.admit(namespace, &settings, &retry_after(), later).await.is_err());
+}
+
+#[tokio::test]
+async fn test_rate_limit_contract_on_memory_store() {
+    rate_limit_contract(Arc::new(MemoryStore::default()), "team-a").await;
+}
+
+#[tokio::test]
+async fn test_rate_limit_contract_while_store_is_down() {
+    let fallback = FallbackStore::new(Arc::new(UnreachableStore), Arc::new(MemoryStore::default()), 50);
+    rate_limit_contract(Arc::new(fallback), "team-a").await;
+}
+
+#[tokio::test]
+async fn test_unreadable_bucket_lets_the_request_through() {
+    let limiter = limiter_on(Arc::new(UnreachableStore));
+    let settings = rate(1.0, None);
+    for _ in 0..3 {
+        assert!(limiter.admit("team-a", &settings, &retry_after(), Utc::now()).await.is_ok());
+    }
+}
+
+#[tokio::test]
+async fn test_racing_requests_take_separate_tokens() {
+    let store: Arc<dyn StateStore> = Arc::new(MemoryStore::default());
+    let replicas: Vec<_> = (0..4).map(|_| limiter_on(store.clone())).collect();
+    let settings = rate(1.0, Some(2));
+    let now = Utc::now();
+    let admitted = futures::future::join_all(
+        replicas
+            .iter()
+            .map(|replica| replica.admit("team-a", &settings, &retry_after(), now)),
+    )
+    .await;
+    assert_eq!(admitted.iter().filter(|admitted| admitted.is_ok()).count(), 2);
+}
+
+#[tokio::test]
+async fn test_namespaces_have_separate_buckets() {
+    let limiter = limiter();
+    let settings = rate(1.0, None);
+    let now = Utc::now();
+    assert!(limiter.admit("team-a", &settings, &retry_after(), now).await.is_ok());
+    assert!(limiter.admit("team-a", &settings, &retry_after(), now).await.is_err());
+    assert!(limiter.admit("team-b", &settings, &retry_after(), now).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_slow_rate_retry_after() {
+    let limiter = limiter();
+    let settings = rate(0.1, None);
+    let now = Utc::now();
+    // A bucket holds at least one request
+    assert!(limiter.admit("team-a", &settings, &retry_after(), now).await.is_ok());
+    let err = limiter.admit("team-a", &settings, &retry_after(), now).await.unwrap_err();
+    assert_eq!(err.retry_after_secs, Some(10));
+}
+
+#[tokio::test]
+async fn test_retry_after_is_capped() {
+    let limiter = limiter();
+    let settings = rate(0.01, None);
+    let now = Utc::now();
+    let capped = RetryAfterConfig {
+        max_secs: 30,
+        ..Default::default()
+    };
+    assert!(limiter.admit("team-a", &settings, &capped, now).await.is_ok());
+    let err = limiter.admit("team-a", &settings, &capped, now).await.unwrap_err();
+    assert_eq!(err.retry_after_secs, Some(30));
+}
+
+#[tokio::test]
+async fn test_changed_settings_start_a_full_bucket() {
+    let limiter = limiter();
+    let now = Utc::now();
+    assert!(limiter.admit("team-a", &rate(1.0, None), &retry_after(), now).await.is_ok());
+    assert!(limiter.admit("team-a", &rate(1.0, None), &retry_after(), now).await.is_err());
+    assert!(limiter.admit("team-a", &rate(1.0, Some(2)), &retry_after(), now).await.is_ok());
+    assert!(limiter.admit("team-a", &rate(1.0, Some(2)), &retry_after(), now).await.is_ok());
+    assert!(limiter.admit("team-a", &rate(1.0, Some(2)), &retry_after(), now).await.is_err());
+}
+
+/// Runs the contract against the server in `REDIS_URL`, when set.
+#[cfg(feature = "redis-state")]
+#[tokio::test]
+async fn test_rate_limit_contract_on_redis_store() {
+    let Ok(url) = std::env::var("REDIS_URL") else {
+        eprintln!("REDIS_URL is not set, skipping the Redis rate limit contract test");
+        return;
+    };
+    let store = crate::redis_store::RedisStore::new(&url).unwrap();
+    let namespace = format!("gateway-contract-{}", Utc::now().timestamp_millis());
+    rate_limit_contract(Arc::new(store), &namespace).await;
+}
@@ -0,0 +1,136 @@
+use crate::client_cache::{ClientCache, DEFAULT_FAILURE_TTL};
+use crate::state_store::{StateStore, StoreError, StoreResult};
+use futures::future::BoxFuture;
+use redis::aio::ConnectionManager;
+use redis::{Client, Script};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Increments a counter, giving it an expiry (ARGV[2] milliseconds, 0 for none) only when it has
+/// none yet.
+const INCREMENT_SCRIPT: &str = r"
+local value = redis.call('INCRBY', KEYS[1], ARGV[1])
+local ttl = tonumber(ARGV[2])
+if ttl > 0 and redis.call('PTTL', KEYS[1]) == -1 then
+  redis.call('PEXPIRE', KEYS[1], ttl)
+end
+return value
+";
+
+/// Stores ARGV[3] if the key is absent (ARGV[1] = '0') or holds ARGV[2] (ARGV[1] = '1'), with an
+/// expiry of ARGV[4] milliseconds, 0 for none.
+const COMPARE_AND_SWAP_SCRIPT: &str = r"
+local current = redis.call('GET', KEYS[1])
+local matches = (ARGV[1] == '0' and current == false) or (ARGV[1] == '1' and current == ARGV[2])
+if not matches then
+  return 0
+end
+local ttl = tonumber(ARGV[4])
+if ttl > 0 then
+  redis.call('SET', KEYS[1], ARGV[3], 'PX', ttl)
+else
+  redis.call('SET', KEYS[1], ARGV[3])
+end
+return 1
+";
+
+/// State shared by all gateway replicas through Redis. The connection is made on first use and
+/// re-established after failures, so the gateway starts while Redis is down.
+#[derive(Clone)]
+pub struct RedisStore {
+    client: Client,
+    connection: Arc<ClientCache<(), ConnectionManager, StoreError>>,
+}
+
+impl RedisStore {
+    pub fn new(url: &str) -> StoreResult<Self> {
+        Ok(Self {
+            client: Client::open(url).map_err(store_error)?,
+            connection: Arc::new(ClientCache::new(DEFAULT_FAILURE_TTL)),
+        })
+    }
+
+    async fn connection(&self) -> StoreResult<ConnectionManager> {
+        let client = self.client.clone();
+        self.connection
+            .get_or_try_init(&(), || async move {
+                ConnectionManager::new(client).await.map_err(store_error)
+            })
+            .await
+    }
+}
+
+fn store_error(e: redis::RedisError) -> StoreError {
+    StoreError(format!("redis: {}", e))
+}
+
+fn millis(ttl: Option<Duration>) -> u64 {
+    ttl.map_or(0, |ttl| (ttl.as_millis() as u64).max(1))
+}
+
+impl StateStore for RedisStore {
+    fn get(&self, key: &str) -> BoxFuture<'static, StoreResult<Option<Vec<u8>>>> {
+        let (store, key) = (self.clone(), key.to_string());
+        Box::pin(async move {
+            let mut connection = store.connection().await?;
+            let value: Option<Vec<u8>> = redis::cmd("GET")
+                .arg(&key)
+                .query_async(&mut connection)
+                .await
+                .map_err(store_error)?;
+            Ok(value)
+        })
+    }
+
+    fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> BoxFuture<'static, StoreResult<()>> {
+        let (store, key) = (self.clone(), key.to_string());
+        Box::pin(async move {
+            let mut connection = store.connection().await?;
+            let mut cmd = redis::cmd("SET");
+            cmd.arg(&key).arg(value);
+            if ttl.is_some() {
+                cmd.arg("PX").arg(millis(ttl));
+            }
+            let () = cmd.query_async(&mut connection).await.map_err(store_error)?;
+            Ok(())
+        })
+    }
+
+    fn increment(&self, key: &str, delta: i64, ttl: Option<Duration>) -> BoxFuture<'static, StoreResult<i64>> {
+        let (store, key) = (self.clone(), key.to_string());
+        Box::pin(async move {
+            let mut connection = store.connection().await?;
+            let value: i64 = Script::new(INCREMENT_SCRIPT)
+                .key(&key)
+                .arg(delta)
+                .arg(millis(ttl))
+                .invoke_async(&mut connection)
+                .await
+                .map_err(store_error)?;
+            Ok(value)
+        })
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'static, StoreResult<bool>> {
+        let (store, key) = (self.clone(), key.to_string());
+        Box::pin(async move {
+            let mut connection = store.connection().await?;
+            let swapped: i64 = Script::new(COMPARE_AND_SWAP_SCRIPT)
+                .key(&key)
+                .arg(if expected.is_some() { "1" } else { "0" })
+                .arg(expected.unwrap_or_default())
+                .arg(new)
+                .arg(millis(ttl))
+                .invoke_async(&mut connection)
+                .await
+                .map_err(store_error)?;
+            Ok(swapped == 1)
+        })
+    }
+}
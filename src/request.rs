@@ -0,0 +1,1255 @@
+use crate::config::{
+    literal_prefix_segments, path_pattern, BodyMatch, ForwardedSetting, LambdaInvokeMode, PathParams, PayloadMode,
+    RequestIdConfig, Target,
+};
+use crate::drain::DrainSignal;
+use crate::error::{ErrorPhase, GatewayError};
+use crate::expr::Attributes;
+use crate::flags::{self, FlagContext};
+use crate::ndjson::RecordStream;
+use crate::spool::Spool;
+use axum::body::Bytes;
+use axum::http::header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, TE};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use base64::Engine;
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Largest `ClientContext` Lambda accepts, measured after base64 encoding.
+pub const MAX_CLIENT_CONTEXT_BYTES: usize = 3583;
+
+/// The media type of a `Content-Type` value, lowercased and without parameters such as `charset`.
+pub fn media_type(content_type: &str) -> String {
+    let essence = content_type.split(';').next().unwrap_or_default();
+    essence.trim().to_ascii_lowercase()
+}
+
+/// Whether a request body with this content type is sent to Lambda base64-encoded.
+pub fn is_base64_encoded(content_type: &str) -> bool {
+    let media_type = media_type(content_type);
+    match media_type.as_str() {
+        "application/json" => false,
+        "application/xml" => false,
+        "application/javascript" => false,
+        _ if media_type.starts_with("text/") => false,
+        _ => true,
+    }
+}
+
+/// Coarse family of a content type, one of a fixed set so it can label metrics: `json`, `text`,
+/// `xml`, `form`, `multipart`, `image`, `audio`, `video`, `binary`, `other` or `none`.
+pub fn content_family(content_type: &str) -> &'static str {
+    let media_type = media_type(content_type);
+    let (kind, subtype) = media_type.split_once('/').unwrap_or((&media_type, ""));
+    match (kind, subtype) {
+        ("", _) => "none",
+        (_, "json") => "json",
+        (_, subtype) if subtype.ends_with("+json") => "json",
+        (_, "xml") => "xml",
+        (_, subtype) if subtype.ends_with("+xml") => "xml",
+        ("text", _) | ("application", "javascript") => "text",
+        ("application", "x-www-form-urlencoded") => "form",
+        ("multipart", _) => "multipart",
+        ("image", _) => "image",
+        ("audio", _) => "audio",
+        ("video", _) => "video",
+        ("application", _) => "binary",
+        _ => "other",
+    }
+}
+
+/// Largest payload of a synchronous invoke, request or response.
+pub const MAX_INVOKE_PAYLOAD_BYTES: usize = 6 * 1024 * 1024;
+
+/// Reads a request body of at most `limit` bytes. A `Content-Length` over the limit is rejected
+/// before anything is read, and a body without one, as a chunked body, once it passes the limit.
+pub async fn read_body(headers: &HeaderMap, body: axum::body::Body, limit: usize) -> Result<Bytes, GatewayError> {
+    let declared = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+    if declared.is_some_and(|length| length > limit as u64) {
+        return Err(body_too_large(limit));
+    }
+    axum::body::to_bytes(body, limit).await.map_err(|e| {
+        let source = std::error::Error::source(&e);
+        if source.is_some_and(|source| source.is::<http_body_util::LengthLimitError>()) {
+            body_too_large(limit)
+        } else {
+            GatewayError::new(
+                ErrorPhase::Ingress,
+                StatusCode::BAD_REQUEST,
+                "invalid_request_body",
+                format!("Failed to read request body: {}", e),
+            )
+        }
+    })
+}
+
+/// The answer to a request body over `limit` bytes.
+pub fn body_too_large(limit: usize) -> GatewayError {
+    GatewayError::new(
+        ErrorPhase::Ingress,
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "request_body_too_large",
+        format!("Request body exceeds {} bytes", limit),
+    )
+}
+
+/// Bytes a body of `length` bytes takes in the invoke payload: its base64 encoding for the
+/// content types sent encoded, its own length otherwise. The rest of the event is not counted.
+pub fn payload_body_bytes(headers: &HeaderMap, length: usize) -> usize {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if is_base64_encoded(content_type) {
+        length.div_ceil(3) * 4
+    } else {
+        length
+    }
+}
+
+/// A request body in its payload form: base64 unless the content type is textual.
+pub fn encode_body(headers: &HeaderMap, body: &[u8]) -> (String, bool) {
+    let content_type = headers
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+
+    if is_base64_encoded(content_type) {
+        (base64::engine::general_purpose::STANDARD.encode(body), true)
+    } else {
+        (String::from_utf8_lossy(body).to_string(), false)
+    }
+}
+
+/// Serializes an HTTP request into the ALB target group event shape.
+pub fn build_alb_request_body(
+    method: &Method,
+    path: &str,
+    query_string_parameters: &HashMap<String, String>,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> String {
+    let (body, is_base64_encoded) = encode_body(headers, body);
+    build_alb_event(method, path, query_string_parameters, headers, &body, is_base64_encoded).to_string()
+}
+
+/// Builds the event of an HTTP request whose body is already in its payload form, base64 or text.
+pub fn build_alb_event(
+    method: &Method,
+    path: &str,
+    query_string_parameters: &HashMap<String, String>,
+    headers: &HeaderMap,
+    body: &str,
+    is_base64_encoded: bool,
+) -> serde_json::Value {
+    json!({
+        "httpMethod": method.as_str(),
+        "headers": to_string_map(headers),
+        "path": path,
+        "queryStringParameters": query_string_parameters,
+        "isBase64Encoded": is_base64_encoded,
+        "body": body,
+        "requestContext": {
+            "elb": {
+                "targetGroupArn": "",
+            },
+        },
+    })
+}
+
+/// Builds the event of an HTTP request whose body is already in its payload form as an ALB target
+/// group with multi-value headers enabled sends it, listing every value of repeated headers and
+/// query parameters instead of only the last.
+pub fn build_alb_multi_value_event(
+    method: &Method,
+    path: &str,
+    raw_query_string: &str,
+    headers: &HeaderMap,
+    body: &str,
+    is_base64_encoded: bool,
+) -> serde_json::Value {
+    json!({
+        "httpMethod": method.as_str(),
+        "multiValueHeaders": multi_value_headers(headers),
+        "path": path,
+        "multiValueQueryStringParameters": multi_value_query(raw_query_string),
+        "isBase64Encoded": is_base64_encoded,
+        "body": body,
+        "requestContext": {
+            "elb": {
+                "targetGroupArn": "",
+            },
+        },
+    })
+}
+
+/// Every value of each header, in the order received.
+fn multi_value_headers(headers: &HeaderMap) -> HashMap<String, Vec<String>> {
+    let mut multi_value_headers: HashMap<String, Vec<String>> = HashMap::new();
+    for (name, value) in headers {
+        multi_value_headers
+            .entry(name.as_str().to_string())
+            .or_default()
+            .push(String::from_utf8_lossy(value.as_bytes()).into_owned());
+    }
+    multi_value_headers
+}
+
+/// Every value of each query parameter, percent-decoded, in the order received.
+fn multi_value_query(raw_query_string: &str) -> HashMap<String, Vec<String>> {
+    let mut multi_value_query: HashMap<String, Vec<String>> = HashMap::new();
+    for (name, value) in url::form_urlencoded::parse(raw_query_string.as_bytes()) {
+        multi_value_query
+            .entry(name.into_owned())
+            .or_default()
+            .push(value.into_owned());
+    }
+    multi_value_query
+}
+
+/// What a Lambda event says about a request besides its headers and body.
+pub struct EventRequest<'a> {
+    pub method: &'a Method,
+    /// Percent-decoded request path.
+    pub path: &'a str,
+    /// Path as sent by the client, still percent-encoded.
+    pub raw_path: &'a str,
+    pub raw_query_string: &'a str,
+    /// Pattern of the matched target; empty for the top-level function.
+    pub pattern: &'a str,
+    /// `host` of the matched target, for targets serving a single host.
+    pub host: Option<&'a str>,
+    pub path_params: &'a PathParams,
+    /// Address of the connected client.
+    pub source_ip: Option<IpAddr>,
+    pub request_id: &'a str,
+    /// Whether an ALB event lists every value of repeated headers and query parameters.
+    pub multi_value: bool,
+}
+
+/// Serializes an HTTP request into the event shape of `mode`. A raw payload is the body itself.
+pub fn build_request_body(mode: PayloadMode, request: &EventRequest, headers: &HeaderMap, body: &[u8]) -> String {
+    if mode == PayloadMode::Raw {
+        return String::from_utf8_lossy(body).into_owned();
+    }
+    let (body, is_base64_encoded) = encode_body(headers, body);
+    build_event(mode, request, headers, &body, is_base64_encoded).to_string()
+}
+
+/// Builds the event of an HTTP request whose body is already in its payload form, base64 or text,
+/// in the event shape of `mode`. Raw payloads are sent as they are by [`raw_payload`]; here they
+/// are the parsed body, or `null` when it is empty or not JSON.
+pub fn build_event(
+    mode: PayloadMode,
+    request: &EventRequest,
+    headers: &HeaderMap,
+    body: &str,
+    is_base64_encoded: bool,
+) -> serde_json::Value {
+    let mut event = match mode {
+        PayloadMode::Alb if request.multi_value => build_alb_multi_value_event(
+            request.method,
+            request.path,
+            request.raw_query_string,
+            headers,
+            body,
+            is_base64_encoded,
+        ),
+        PayloadMode::Alb => {
+            // The last value of a repeated parameter wins
+            let query_string_parameters: HashMap<String, String> =
+                url::form_urlencoded::parse(request.raw_query_string.as_bytes())
+                    .into_owned()
+                    .collect();
+            build_alb_event(
+                request.method,
+                request.path,
+                &query_string_parameters,
+                headers,
+                body,
+                is_base64_encoded,
+            )
+        }
+        PayloadMode::ApiGatewayV1 => build_apigw_v1_event(request, headers, body, is_base64_encoded),
+        PayloadMode::ApiGatewayV2 => build_apigw_v2_event(request, headers, body, is_base64_encoded),
+        PayloadMode::FunctionUrl => build_function_url_event(request, headers, body, is_base64_encoded),
+        PayloadMode::Raw => return serde_json::from_str(body).unwrap_or_default(),
+    };
+    // Tells functions serving several virtual hosts which one the request was routed by
+    if let Some(host) = request.host {
+        let context = &mut event["requestContext"];
+        context["gatewayTarget"] = json!(request.pattern);
+        context["gatewayHost"] = json!(host);
+    }
+    event
+}
+
+/// The invoke payload of a `payload: raw` request: its body, unchanged. Lambda only accepts JSON
+/// payloads, so any other body, including one that is not UTF-8, is answered 415 without invoking.
+/// An empty body is sent as an empty payload, which the function receives as `{}`.
+pub fn raw_payload(body: Bytes) -> Result<String, GatewayError> {
+    let not_json = || {
+        GatewayError::new(
+            ErrorPhase::Ingress,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "raw_body_not_json",
+            "Raw payload targets only accept JSON request bodies",
+        )
+    };
+    let payload = String::from_utf8(Vec::from(body)).map_err(|_| not_json())?;
+    if !payload.is_empty() {
+        serde_json::from_str::<serde::de::IgnoredAny>(&payload).map_err(|_| not_json())?;
+    }
+    Ok(payload)
+}
+
+/// Builds the event of an HTTP request whose body is already in its payload form in the API
+/// Gateway REST API proxy shape, payload format 1.0. The `multiValue` maps hold every value of
+/// repeated headers and query parameters, the single-value maps the last one. Empty maps and bodies
+/// are `null`, as API Gateway sends them.
+pub fn build_apigw_v1_event(
+    request: &EventRequest,
+    headers: &HeaderMap,
+    body: &str,
+    is_base64_encoded: bool,
+) -> serde_json::Value {
+    let mut multi_value_headers: HashMap<String, Vec<String>> = HashMap::new();
+    for (name, value) in headers {
+        multi_value_headers
+            .entry(name.as_str().to_string())
+            .or_default()
+            .push(String::from_utf8_lossy(value.as_bytes()).into_owned());
+    }
+    let mut multi_value_query: HashMap<String, Vec<String>> = HashMap::new();
+    for (name, value) in url::form_urlencoded::parse(request.raw_query_string.as_bytes()) {
+        multi_value_query
+            .entry(name.into_owned())
+            .or_default()
+            .push(value.into_owned());
+    }
+    let last = |map: &HashMap<String, Vec<String>>| -> HashMap<String, String> {
+        map.iter()
+            .filter_map(|(name, values)| Some((name.clone(), values.last()?.clone())))
+            .collect()
+    };
+    let or_null = |value: serde_json::Value, empty: bool| if empty { serde_json::Value::Null } else { value };
+    let user_agent = headers.get("user-agent").and_then(|v| v.to_str().ok());
+    let resource = resource_path(path_pattern(request.pattern).unwrap_or_default());
+    let now = chrono::Utc::now();
+
+    json!({
+        "resource": resource,
+        "path": request.path,
+        "httpMethod": request.method.as_str(),
+        "headers": or_null(json!(last(&multi_value_headers)), headers.is_empty()),
+        "multiValueHeaders": or_null(json!(multi_value_headers), headers.is_empty()),
+        "queryStringParameters": or_null(json!(last(&multi_value_query)), multi_value_query.is_empty()),
+        "multiValueQueryStringParameters": or_null(json!(multi_value_query), multi_value_query.is_empty()),
+        "pathParameters": or_null(json!(request.path_params), request.path_params.is_empty()),
+        "stageVariables": null,
+        "requestContext": {
+            "accountId": "",
+            "apiId": "",
+            "resourceId": "",
+            "resourcePath": resource,
+            "httpMethod": request.method.as_str(),
+            "path": request.path,
+            "protocol": "HTTP/1.1",
+            "stage": "$default",
+            "requestId": request.request_id,
+            "requestTime": now.format("%d/%b/%Y:%H:%M:%S %z").to_string(),
+            "requestTimeEpoch": now.timestamp_millis(),
+            "identity": {
+                "sourceIp": request.source_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+                "userAgent": user_agent,
+            },
+        },
+        "body": or_null(json!(body), body.is_empty()),
+        "isBase64Encoded": is_base64_encoded && !body.is_empty(),
+    })
+}
+
+/// API Gateway resource of a target pattern: `/items/:id` becomes `/items/{id}` and `/files/*rest`
+/// becomes `/files/{rest+}`. The top-level function, which serves every path, is `/{proxy+}`.
+pub fn resource_path(pattern: &str) -> String {
+    if pattern.is_empty() {
+        return "/{proxy+}".to_string();
+    }
+    pattern
+        .split('/')
+        .map(|segment| {
+            if let Some(name) = segment.strip_prefix(':') {
+                format!("{{{}}}", name)
+            } else if let Some(name) = segment.strip_prefix('*') {
+                format!("{{{}+}}", name)
+            } else {
+                segment.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Builds the event of an HTTP request whose body is already in its payload form in the API
+/// Gateway v2 shape. As API Gateway does, cookies move from the `Cookie` header to `cookies`,
+/// repeated headers and query parameters are joined with commas, and empty fields are left out.
+pub fn build_apigw_v2_event(
+    request: &EventRequest,
+    headers: &HeaderMap,
+    body: &str,
+    is_base64_encoded: bool,
+) -> serde_json::Value {
+    let source_ip = request.source_ip.map(|ip| ip.to_string()).unwrap_or_default();
+    let mut event = http_api_event(request, headers, &source_ip, body, is_base64_encoded);
+    if !request.path_params.is_empty() {
+        event["pathParameters"] = json!(request.path_params);
+    }
+    event
+}
+
+/// Builds the event of an HTTP request whose body is already in its payload form in the Lambda
+/// Function URL shape: the 2.0 shape of an unauthenticated URL, which has no routes and so no path
+/// parameters. The source IP is the first `X-Forwarded-For` address when a proxy in front of the
+/// gateway set one.
+pub fn build_function_url_event(
+    request: &EventRequest,
+    headers: &HeaderMap,
+    body: &str,
+    is_base64_encoded: bool,
+) -> serde_json::Value {
+    let source_ip = forwarded_for(headers)
+        .or(request.source_ip)
+        .map(|ip| ip.to_string())
+        .unwrap_or_default();
+    let mut event = http_api_event(request, headers, &source_ip, body, is_base64_encoded);
+    let context = &mut event["requestContext"];
+    context["accountId"] = json!("anonymous");
+    context["apiId"] = context["domainPrefix"].clone();
+    event
+}
+
+/// First address of the `X-Forwarded-For` header, the client as seen by the outermost proxy.
+fn forwarded_for(headers: &HeaderMap) -> Option<IpAddr> {
+    let value = headers.get("x-forwarded-for")?.to_str().ok()?;
+    parse_forwarded_ip(value.split(',').next()?.trim())
+}
+
+/// An address as proxies write it in `X-Forwarded-For`: bare, as `2001:db8::1`, or with a port,
+/// IPv6 then in brackets as `[2001:db8::1]:443`. Some also bracket IPv6 without a port.
+/// IPv4-mapped addresses are read as IPv4.
+fn parse_forwarded_ip(value: &str) -> Option<IpAddr> {
+    let ip = value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| value.strip_prefix('[')?.strip_suffix(']')?.parse().ok())?;
+    Some(ip.to_canonical())
+}
+
+/// Fields shared by the API Gateway v2 and Function URL events.
+fn http_api_event(
+    request: &EventRequest,
+    headers: &HeaderMap,
+    source_ip: &str,
+    body: &str,
+    is_base64_encoded: bool,
+) -> serde_json::Value {
+    let mut event_headers: HashMap<String, String> = HashMap::new();
+    for (name, value) in headers.iter().filter(|(name, _)| *name != COOKIE) {
+        join_value(
+            &mut event_headers,
+            name.as_str(),
+            &String::from_utf8_lossy(value.as_bytes()),
+        );
+    }
+    let cookies: Vec<&str> = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .map(str::trim)
+        .filter(|cookie| !cookie.is_empty())
+        .collect();
+    let mut query_string_parameters = HashMap::new();
+    for (name, value) in url::form_urlencoded::parse(request.raw_query_string.as_bytes()) {
+        join_value(&mut query_string_parameters, &name, &value);
+    }
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok()).unwrap_or_default();
+    let domain_name = header("host").split(':').next().unwrap_or_default();
+    let now = chrono::Utc::now();
+
+    let mut event = json!({
+        "version": "2.0",
+        "routeKey": "$default",
+        "rawPath": request.raw_path,
+        "rawQueryString": request.raw_query_string,
+        "headers": event_headers,
+        "requestContext": {
+            "accountId": "",
+            "apiId": "",
+            "domainName": domain_name,
+            "domainPrefix": domain_name.split('.').next().unwrap_or_default(),
+            "http": {
+                "method": request.method.as_str(),
+                "path": request.raw_path,
+                "protocol": "HTTP/1.1",
+                "sourceIp": source_ip,
+                "userAgent": header("user-agent"),
+            },
+            "requestId": request.request_id,
+            "routeKey": "$default",
+            "stage": "$default",
+            "time": now.format("%d/%b/%Y:%H:%M:%S %z").to_string(),
+            "timeEpoch": now.timestamp_millis(),
+        },
+        "isBase64Encoded": is_base64_encoded && !body.is_empty(),
+    });
+    if !cookies.is_empty() {
+        event["cookies"] = json!(cookies);
+    }
+    if !query_string_parameters.is_empty() {
+        event["queryStringParameters"] = json!(query_string_parameters);
+    }
+    if !body.is_empty() {
+        event["body"] = json!(body);
+    }
+    event
+}
+
+fn join_value(map: &mut HashMap<String, String>, name: &str, value: &str) {
+    map.entry(name.to_string())
+        .and_modify(|joined| {
+            joined.push(',');
+            joined.push_str(value);
+        })
+        .or_insert_with(|| value.to_string());
+}
+
+/// Header a trusted client sets to `base64` when the body it sends is already base64-encoded.
+pub const BODY_ENCODED_HEADER: &str = "x-gateway-body-encoded";
+
+/// Takes a body the client marked as already base64-encoded with [`BODY_ENCODED_HEADER`],
+/// returning it as text for the payload. Returns `None` for bodies without the header. Only
+/// callers whose API key is listed in `trusted_body_encoding_keys` may use the header.
+pub fn take_preencoded_body(
+    headers: &mut HeaderMap,
+    body: &Bytes,
+    api_key: Option<&str>,
+    trusted_keys: &HashSet<String>,
+) -> Result<Option<String>, GatewayError> {
+    let Some(encoding) = headers.remove(BODY_ENCODED_HEADER) else {
+        return Ok(None);
+    };
+    if !api_key.is_some_and(|key| trusted_keys.contains(key)) {
+        return Err(GatewayError::new(
+            ErrorPhase::Auth,
+            StatusCode::FORBIDDEN,
+            "body_encoding_not_allowed",
+            format!("This API key may not send {}", BODY_ENCODED_HEADER),
+        ));
+    }
+    if !encoding.as_bytes().eq_ignore_ascii_case(b"base64") {
+        return Err(GatewayError::new(
+            ErrorPhase::Ingress,
+            StatusCode::BAD_REQUEST,
+            "unsupported_body_encoding",
+            format!("{} must be base64", BODY_ENCODED_HEADER),
+        ));
+    }
+    let invalid = || {
+        GatewayError::new(
+            ErrorPhase::Ingress,
+            StatusCode::BAD_REQUEST,
+            "invalid_base64_body",
+            "The request body is not valid base64",
+        )
+    };
+    let text = std::str::from_utf8(body).map_err(|_| invalid())?;
+    base64::engine::general_purpose::STANDARD
+        .decode(text)
+        .map_err(|_| invalid())?;
+    Ok(Some(text.to_string()))
+}
+
+/// Inflates a gzip or deflate encoded request body, dropping `Content-Encoding` and correcting
+/// `Content-Length` so the function sees a plain body. Other encodings are left untouched.
+pub fn decompress_body(
+    headers: &mut HeaderMap,
+    body: Bytes,
+    max_decompressed_bytes: usize,
+) -> Result<Bytes, GatewayError> {
+    let encoding = match headers.get(CONTENT_ENCODING).and_then(|v| v.to_str().ok()) {
+        Some(encoding) => encoding.trim().to_ascii_lowercase(),
+        None => return Ok(body),
+    };
+
+    let decompressed = match encoding.as_str() {
+        "gzip" | "x-gzip" => inflate(GzDecoder::new(&body[..]), max_decompressed_bytes)?,
+        // "deflate" is meant to be zlib-wrapped, but raw deflate streams are common in the wild. Only
+        // the header tells them apart, so that a zlib stream that fails later is not read again.
+        "deflate" if is_zlib_header(&body) => inflate(ZlibDecoder::new(&body[..]), max_decompressed_bytes)?,
+        "deflate" => inflate(DeflateDecoder::new(&body[..]), max_decompressed_bytes)?,
+        _ => return Ok(body),
+    };
+
+    headers.remove(CONTENT_ENCODING);
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(decompressed.len()));
+    Ok(Bytes::from(decompressed))
+}
+
+/// Whether `content_type` is `application/json` or a `+json` type such as
+/// `application/problem+json`, with any parameters.
+pub fn is_json_content_type(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence == "application/json" || (essence.starts_with("application/") && essence.ends_with("+json"))
+}
+
+/// A request body whose JSON is parsed on first use and then shared, so that routing by body and
+/// `validate_json_body` parse a body at most once between them.
+#[derive(Clone, Debug, Default)]
+pub struct ParsedBody {
+    bytes: Bytes,
+    json: Arc<OnceLock<Result<serde_json::Value, String>>>,
+}
+
+impl ParsedBody {
+    pub fn new(bytes: Bytes) -> Self {
+        Self {
+            bytes,
+            json: Arc::default(),
+        }
+    }
+
+    pub fn bytes(&self) -> &Bytes {
+        &self.bytes
+    }
+
+    /// The body parsed as JSON, whatever its content type, or the parse error.
+    pub fn json(&self) -> Result<&serde_json::Value, &str> {
+        self.json
+            .get_or_init(|| serde_json::from_slice(&self.bytes).map_err(|e| e.to_string()))
+            .as_ref()
+            .map_err(String::as_str)
+    }
+}
+
+/// Whether a body with these headers is JSON the gateway can read: a JSON content type, no
+/// remaining `Content-Encoding` and not empty.
+fn is_readable_json(headers: &HeaderMap, body: &[u8]) -> bool {
+    let is_json = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(is_json_content_type);
+    is_json && !headers.contains_key(CONTENT_ENCODING) && !body.is_empty()
+}
+
+/// The target of the first of `conditions` the request meets, if any.
+pub fn match_conditions<'a>(body_match: &'a BodyMatch, request: &Attributes) -> Option<&'a str> {
+    body_match
+        .conditions
+        .iter()
+        .find(|rule| rule.when.holds(request))
+        .map(|rule| rule.target.as_str())
+}
+
+/// Picks the target of a request to a target with `match`: that of the first `body_json` rule
+/// whose pointer holds an equal value, or a string the regex matches, and `fallback` when none
+/// does or the body is not readable JSON within `max_body_bytes`. Also returns whether a rule
+/// matched.
+pub fn match_body<'a>(body_match: &'a BodyMatch, headers: &HeaderMap, body: &ParsedBody) -> (&'a str, bool) {
+    let fallback = (body_match.fallback.as_str(), false);
+    if body.bytes().len() > body_match.max_body_bytes || !is_readable_json(headers, body.bytes()) {
+        return fallback;
+    }
+    let Ok(json) = body.json() else {
+        return fallback;
+    };
+    let matched = body_match.body_json.iter().find(|rule| {
+        let Some(value) = json.pointer(&rule.pointer) else {
+            return false;
+        };
+        match (&rule.equals, &rule.regex) {
+            (Some(equals), _) => value == equals,
+            (None, Some(regex)) => match (value.as_str(), regex.compiled()) {
+                (Some(value), Ok(regex)) => regex.is_match(value),
+                _ => false,
+            },
+            (None, None) => false,
+        }
+    });
+    matched.map_or(fallback, |rule| (rule.target.as_str(), true))
+}
+
+/// Rejects a JSON request body that does not parse, or nests deeper than `max_depth`, with 400.
+/// Bodies of other content types, still encoded bodies and empty bodies are not checked. The body
+/// is only read, never re-serialized.
+pub fn validate_json_body(
+    headers: &HeaderMap,
+    body: &ParsedBody,
+    max_depth: Option<usize>,
+) -> Result<(), GatewayError> {
+    if !is_readable_json(headers, body.bytes()) {
+        return Ok(());
+    }
+    // Checked before parsing so that hostile nesting never reaches the parser's recursion
+    if let Some(max_depth) = max_depth {
+        if json_depth(body.bytes()) > max_depth {
+            return Err(GatewayError::new(
+                ErrorPhase::Ingress,
+                StatusCode::BAD_REQUEST,
+                "json_too_deep",
+                format!("JSON body nests deeper than {} levels", max_depth),
+            ));
+        }
+    }
+    body.json().map_err(|e| {
+        GatewayError::new(
+            ErrorPhase::Ingress,
+            StatusCode::BAD_REQUEST,
+            "invalid_json",
+            format!("Invalid JSON body: {}", e),
+        )
+    })?;
+    Ok(())
+}
+
+/// Deepest nesting of arrays and objects in `body`, ignoring brackets inside strings. Does not
+/// check that the JSON is well-formed.
+fn json_depth(body: &[u8]) -> usize {
+    let (mut depth, mut max_depth) = (0usize, 0);
+    let (mut in_string, mut escaped) = (false, false);
+    for &byte in body {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                max_depth = max_depth.max(depth);
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    max_depth
+}
+
+/// Whether `body` starts with a zlib header: deflate with a window of at most 32 KiB, and a check
+/// value making the two bytes a multiple of 31.
+fn is_zlib_header(body: &[u8]) -> bool {
+    match body {
+        [cmf, flg, ..] => cmf & 0x0f == 8 && cmf >> 4 <= 7 && ((u16::from(*cmf) << 8) | u16::from(*flg)) % 31 == 0,
+        _ => false,
+    }
+}
+
+fn inflate(decoder: impl Read, max_decompressed_bytes: usize) -> Result<Vec<u8>, GatewayError> {
+    let mut decompressed = Vec::new();
+    decoder
+        .take(max_decompressed_bytes as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| {
+            GatewayError::new(
+                ErrorPhase::Ingress,
+                StatusCode::BAD_REQUEST,
+                "invalid_content_encoding",
+                format!("Failed to decompress request body: {}", e),
+            )
+        })?;
+    if decompressed.len() > max_decompressed_bytes {
+        return Err(GatewayError::new(
+            ErrorPhase::Ingress,
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "decompressed_body_too_large",
+            format!("Decompressed request body exceeds {} bytes", max_decompressed_bytes),
+        ));
+    }
+    Ok(decompressed)
+}
+
+/// The path a target with `strip_prefix` or `rewrite` forwards in place of `raw_path`, still
+/// percent-encoded, or `None` when the target forwards paths as they are. `pattern` is the key of
+/// the target. The result always starts with a single `/`: an empty path becomes `/`, and runs of
+/// slashes, as a rewrite can leave behind, are collapsed.
+pub fn rewrite_path(target: &Target, pattern: &str, raw_path: &str) -> Option<String> {
+    if !target.strip_prefix && target.rewrite.is_none() {
+        return None;
+    }
+    let mut path = raw_path.to_string();
+    if target.strip_prefix {
+        // The route matched, so the path starts with the pattern's literal segments
+        let skip = 1 + literal_prefix_segments(pattern);
+        path = format!("/{}", raw_path.split('/').skip(skip).collect::<Vec<_>>().join("/"));
+    }
+    if let Some(rewrite) = &target.rewrite {
+        path = if rewrite.is_regex() {
+            match rewrite.from.compiled() {
+                Ok(regex) => regex.replace(&path, rewrite.to.as_str()).into_owned(),
+                Err(_) => path,
+            }
+        } else {
+            match path.strip_prefix(rewrite.from.as_str()) {
+                Some(rest) => format!("{}{}", rewrite.to, rest),
+                None => path,
+            }
+        };
+    }
+    let mut normalized = String::with_capacity(path.len() + 1);
+    for c in std::iter::once('/').chain(path.chars()) {
+        if !(c == '/' && normalized.ends_with('/')) {
+            normalized.push(c);
+        }
+    }
+    Some(normalized)
+}
+
+/// Percent-decodes a rewritten path, as the client's path is decoded for events carrying `path`.
+pub fn decode_path(raw_path: &str) -> String {
+    percent_encoding::percent_decode_str(raw_path)
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+/// Records the path the client sent in the target's `original_path_header`, replacing any value
+/// the client sent under that name.
+pub fn insert_original_path_header(headers: &mut HeaderMap, target: &Target, raw_path: &str) {
+    let name = HeaderName::try_from(target.original_path_header.as_str());
+    if let (Ok(name), Ok(value)) = (name, HeaderValue::from_str(raw_path)) {
+        headers.insert(name, value);
+    }
+}
+
+/// Adds an `x-path-param-<name>` header per captured path parameter, replacing any the client
+/// sent. Parameters whose name or value cannot be carried in a header are skipped.
+pub fn insert_path_param_headers(headers: &mut HeaderMap, params: &PathParams) {
+    for (name, value) in params {
+        let header = HeaderName::try_from(format!("x-path-param-{}", name.to_ascii_lowercase()));
+        match (header, HeaderValue::from_str(value)) {
+            (Ok(header), Ok(value)) => {
+                headers.insert(header, value);
+            }
+            _ => tracing::debug!("Skipping path parameter {} that is not a valid header", name),
+        }
+    }
+}
+
+/// Prefix of the headers carrying a target's `forward_settings`.
+pub const SETTING_HEADER_PREFIX: &str = "x-gateway-setting-";
+
+/// Adds a header per setting in the target's `forward_settings`, in the order listed. Setting
+/// headers sent by the client are removed first so functions cannot be misled. Settings without a
+/// value, such as the namespace of a top-level target, are left out.
+pub fn insert_setting_headers(headers: &mut HeaderMap, target: &Target, pattern: &str) {
+    let spoofed: Vec<HeaderName> = headers
+        .keys()
+        .filter(|name| name.as_str().starts_with(SETTING_HEADER_PREFIX))
+        .cloned()
+        .collect();
+    for name in spoofed {
+        headers.remove(name);
+    }
+    for setting in &target.forward_settings {
+        let value = match setting {
+            ForwardedSetting::TimeoutMs => target.invoke_timeout_ms.map(|ms| ms.to_string()),
+            ForwardedSetting::Namespace => target.namespace.clone(),
+            ForwardedSetting::TargetName => Some(pattern.to_string()),
+        };
+        match value.map(HeaderValue::try_from) {
+            Some(Ok(value)) => {
+                headers.insert(setting.header_name(), value);
+            }
+            Some(Err(_)) => tracing::debug!(?setting, "Skipping setting that is not a valid header value"),
+            None => {}
+        }
+    }
+}
+
+/// Prefix of the headers carrying the result of gateway authentication.
+pub const AUTH_HEADER_PREFIX: &str = "x-gateway-auth-";
+
+/// How a request authenticated, as passed to the functions of targets with `auth_context`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuthContext {
+    /// `api_key`, `signed_url` or `none`.
+    pub method: &'static str,
+    /// ID of the API key, never the key itself.
+    pub key_id: Option<String>,
+}
+
+impl AuthContext {
+    pub fn new(api_key: Option<&str>, signed_url: bool) -> Self {
+        let method = match (api_key, signed_url) {
+            (Some(_), _) => "api_key",
+            (None, true) => "signed_url",
+            (None, false) => "none",
+        };
+        Self {
+            method,
+            key_id: api_key.map(flags::key_id),
+        }
+    }
+
+    /// Whether events of `mode` carry the context in `requestContext.authorizer` rather than in
+    /// request headers. ALB and Function URL events have no authorizer to fill, and raw payloads no
+    /// event at all.
+    pub fn in_authorizer(mode: PayloadMode) -> bool {
+        matches!(mode, PayloadMode::ApiGatewayV1 | PayloadMode::ApiGatewayV2)
+    }
+
+    /// The context as a Lambda authorizer returns it: a flat map of strings, with the key ID as
+    /// the principal.
+    fn authorizer_fields(&self) -> serde_json::Value {
+        let mut fields = json!({ "authMethod": self.method });
+        if let Some(key_id) = &self.key_id {
+            fields["principalId"] = json!(key_id);
+            fields["keyId"] = json!(key_id);
+        }
+        fields
+    }
+}
+
+/// Removes the auth headers sent by the client, so functions can trust the gateway's.
+pub fn remove_auth_headers(headers: &mut HeaderMap) {
+    let spoofed: Vec<HeaderName> = headers
+        .keys()
+        .filter(|name| name.as_str().starts_with(AUTH_HEADER_PREFIX))
+        .cloned()
+        .collect();
+    for name in spoofed {
+        headers.remove(name);
+    }
+}
+
+/// Adds `x-gateway-auth-method` and, for API keys, `x-gateway-auth-key-id`, after removing the
+/// auth headers sent by the client.
+pub fn insert_auth_headers(headers: &mut HeaderMap, auth: &AuthContext) {
+    remove_auth_headers(headers);
+    headers.insert("x-gateway-auth-method", HeaderValue::from_static(auth.method));
+    if let Some(key_id) = &auth.key_id {
+        let key_id = HeaderValue::try_from(key_id.as_str()).expect("hex digits are a valid header value");
+        headers.insert("x-gateway-auth-key-id", key_id);
+    }
+}
+
+/// Sets `requestContext.authorizer` of an API Gateway event as a Lambda authorizer would: the
+/// fields themselves in the 1.0 shape, and under `lambda` in the 2.0 shape. Events of other modes
+/// are left alone.
+pub fn insert_authorizer(mode: PayloadMode, event: &mut serde_json::Value, auth: &AuthContext) {
+    let authorizer = match mode {
+        PayloadMode::ApiGatewayV1 => auth.authorizer_fields(),
+        PayloadMode::ApiGatewayV2 => json!({ "lambda": auth.authorizer_fields() }),
+        _ => return,
+    };
+    event["requestContext"]["authorizer"] = authorizer;
+}
+
+/// Builds the base64 `ClientContext` carrying a `payload: raw` request, whose payload is only its
+/// body. Besides the fields of [`build_client_context`], `custom` has the `method`, `path`, `query`
+/// and `source_ip` of the request and its `headers` as a JSON object string, since some runtimes
+/// only read string values. When the context would exceed [`MAX_CLIENT_CONTEXT_BYTES`], headers are
+/// dropped largest first and `headers_truncated` is set; a request line too long even without
+/// headers is answered 414.
+pub fn build_raw_client_context(
+    instance_id: &str,
+    request: &EventRequest,
+    headers: &HeaderMap,
+) -> Result<String, GatewayError> {
+    let mut header_map = HashMap::new();
+    for (name, value) in headers {
+        join_value(
+            &mut header_map,
+            name.as_str(),
+            &String::from_utf8_lossy(value.as_bytes()),
+        );
+    }
+    let mut truncated = false;
+    loop {
+        let context = json!({
+            "custom": {
+                "gateway": "lambda-web-gateway",
+                "gateway_version": env!("CARGO_PKG_VERSION"),
+                "instance_id": instance_id,
+                "request_id": request.request_id,
+                "method": request.method.as_str(),
+                "path": request.raw_path,
+                "query": request.raw_query_string,
+                "source_ip": request.source_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+                "headers": serde_json::to_string(&header_map).expect("string maps always serialize"),
+                "headers_truncated": truncated.to_string(),
+            },
+        });
+        let encoded = base64::engine::general_purpose::STANDARD.encode(context.to_string());
+        if encoded.len() <= MAX_CLIENT_CONTEXT_BYTES {
+            return Ok(encoded);
+        }
+        let largest = header_map
+            .iter()
+            .max_by_key(|(name, value)| name.len() + value.len())
+            .map(|(name, _)| name.clone());
+        match largest {
+            Some(name) => {
+                header_map.remove(&name);
+                truncated = true;
+            }
+            None => {
+                return Err(GatewayError::new(
+                    ErrorPhase::Ingress,
+                    StatusCode::URI_TOO_LONG,
+                    "raw_request_too_long",
+                    "The request line does not fit in the client context of a raw payload invoke",
+                ))
+            }
+        }
+    }
+}
+
+/// Header asking for a streamed response to be buffered, honoured on targets with
+/// `buffer_stream_on_request`.
+pub const BUFFER_STREAM_HEADER: &str = "x-gateway-buffer-stream";
+
+/// Request header choosing the alias or version to invoke, on targets with `qualifier_on_request`.
+pub const QUALIFIER_HEADER: &str = "x-lambda-qualifier";
+
+/// Header asking for a `DryRun` invoke answered `204`, honoured on targets with
+/// `dry_run_on_request`.
+pub const DRY_RUN_HEADER: &str = "x-invoke-dry-run";
+
+/// Request header choosing the invoke mode, such as `response_stream`, on targets with
+/// `allow_invoke_mode_override`.
+pub const INVOKE_MODE_HEADER: &str = "x-invoke-mode";
+
+/// What the invoke path needs to know about the incoming request besides its payload.
+#[derive(Clone, Debug, Default)]
+pub struct RequestContext {
+    pub request_id: String,
+    /// Pattern of the matched target; empty for the top-level function.
+    pub pattern: String,
+    /// The client sent `TE: trailers`, so the response may end with trailer fields.
+    pub accepts_trailers: bool,
+    /// The client asked for a streamed response to be buffered with [`BUFFER_STREAM_HEADER`].
+    pub buffer_stream: bool,
+    /// Alias or version the client asked for with [`QUALIFIER_HEADER`], not yet validated.
+    pub qualifier: Option<String>,
+    /// Invoke mode the client asked for with [`INVOKE_MODE_HEADER`]; unset when the value does not
+    /// parse.
+    pub invoke_mode: Option<LambdaInvokeMode>,
+    /// The client asked for a dry run with [`DRY_RUN_HEADER`].
+    pub dry_run: bool,
+    /// Fires when a reload has retired the request's target and the drain deadline has passed.
+    pub drain: Option<DrainSignal>,
+    /// Time spent waiting on the function, to tell it apart from the gateway's own overhead.
+    pub upstream_time: UpstreamTime,
+    /// Set for targets with `resumable_downloads`, so a streamed body is spooled for resuming.
+    pub spool: Option<Spool>,
+    /// Feature flags for the request's key and client.
+    pub flags: FlagContext,
+    /// Set for targets with `ndjson`, so a streamed body is forwarded record by record.
+    pub records: Option<RecordStream>,
+    /// `ClientContext` to invoke with instead of the one from the `client_context` setting; carries
+    /// the request line and headers of `payload: raw` targets.
+    pub client_context: Option<String>,
+    /// The request arrived on a listener without `debug_headers`, so no debug headers are added
+    /// whatever the config or flags say.
+    pub hide_debug_headers: bool,
+    /// Method of the request, deciding whether a failed invoke may be retried.
+    pub method: Method,
+    /// `X-Amzn-Trace-Id` to invoke with under `tracing.propagation: xray`.
+    pub trace_header: Option<String>,
+}
+
+impl RequestContext {
+    pub fn new(headers: &HeaderMap) -> Self {
+        let accepts_trailers = headers
+            .get_all(TE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|t| {
+                t.split(';')
+                    .next()
+                    .unwrap_or_default()
+                    .trim()
+                    .eq_ignore_ascii_case("trailers")
+            });
+        let flag = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| v.trim().eq_ignore_ascii_case("true") || v.trim() == "1")
+        };
+        Self {
+            request_id: request_id(headers, &RequestIdConfig::default()),
+            pattern: String::new(),
+            accepts_trailers,
+            buffer_stream: flag(BUFFER_STREAM_HEADER),
+            qualifier: headers
+                .get(QUALIFIER_HEADER)
+                .map(|v| String::from_utf8_lossy(v.as_bytes()).trim().to_string()),
+            invoke_mode: headers
+                .get(INVOKE_MODE_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse().ok()),
+            dry_run: flag(DRY_RUN_HEADER),
+            drain: None,
+            upstream_time: UpstreamTime::default(),
+            spool: None,
+            flags: FlagContext::default(),
+            records: None,
+            client_context: None,
+            hide_debug_headers: false,
+            method: Method::GET,
+            trace_header: None,
+        }
+    }
+}
+
+/// Time a request spent waiting on Lambda, summed over every attempt. Shared between the copies of
+/// a request's [`RequestContext`].
+#[derive(Clone, Debug, Default)]
+pub struct UpstreamTime(Arc<Mutex<Option<Duration>>>);
+
+impl UpstreamTime {
+    /// Counts the time until the returned guard is dropped, so an attempt cut short by a timeout
+    /// still counts.
+    pub fn start(&self) -> UpstreamTimer {
+        UpstreamTimer {
+            total: self.clone(),
+            started_at: Instant::now(),
+        }
+    }
+
+    /// The time counted so far, or `None` if the request never reached Lambda.
+    pub fn get(&self) -> Option<Duration> {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Adds the time since it was started to its [`UpstreamTime`] when dropped.
+pub struct UpstreamTimer {
+    total: UpstreamTime,
+    started_at: Instant,
+}
+
+impl Drop for UpstreamTimer {
+    fn drop(&mut self) {
+        let mut total = self.total.0.lock().unwrap();
+        *total = Some(total.unwrap_or_default() + self.started_at.elapsed());
+    }
+}
+
+/// Longest request ID kept from a client; longer ones are replaced.
+pub const MAX_REQUEST_ID_BYTES: usize = 128;
+
+/// Returns the ID the client sent in the `request_id` header when it is trusted and made of at
+/// most [`MAX_REQUEST_ID_BYTES`] visible ASCII characters, or a new UUIDv7.
+pub fn request_id(headers: &HeaderMap, settings: &RequestIdConfig) -> String {
+    let incoming = headers
+        .get(settings.header.as_str())
+        .filter(|_| settings.trust_incoming)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_BYTES && id.bytes().all(|b| b.is_ascii_graphic()));
+    match incoming {
+        Some(id) => id.to_string(),
+        None => uuid_v7(),
+    }
+}
+
+/// A UUIDv7: the Unix time in milliseconds followed by random bits, so IDs sort by creation time.
+/// Without randomness, a process-wide counter keeps the IDs unique.
+fn uuid_v7() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let mut random = [0u8; 10];
+    if getrandom::getrandom(&mut random).is_err() {
+        random[2..].copy_from_slice(&COUNTER.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    }
+    let mut bytes = [0u8; 16];
+    bytes[..6].copy_from_slice(&millis.to_be_bytes()[2..]);
+    bytes[6..].copy_from_slice(&random);
+    bytes[6] = 0x70 | (bytes[6] & 0x0f);
+    bytes[8] = 0x80 | (bytes[8] & 0x3f);
+    let hex = format!("{:032x}", u128::from_be_bytes(bytes));
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Builds the base64 `ClientContext` for an invoke, readable by the function as
+/// `context.client_context.custom`. The instance and request IDs are truncated, longest first,
+/// until the encoded context fits in [`MAX_CLIENT_CONTEXT_BYTES`].
+pub fn build_client_context(instance_id: &str, request_id: &str) -> String {
+    let mut instance_id = instance_id.to_string();
+    let mut request_id = request_id.to_string();
+    loop {
+        let context = json!({
+            "custom": {
+                "gateway": "lambda-web-gateway",
+                "gateway_version": env!("CARGO_PKG_VERSION"),
+                "instance_id": instance_id,
+                "request_id": request_id,
+            },
+        });
+        let encoded = base64::engine::general_purpose::STANDARD.encode(context.to_string());
+        if encoded.len() <= MAX_CLIENT_CONTEXT_BYTES {
+            return encoded;
+        }
+        // Every 4 encoded bytes carry 3 raw ones; always drop at least one character
+        let excess = ((encoded.len() - MAX_CLIENT_CONTEXT_BYTES) * 3 / 4).max(1);
+        let longest = if request_id.len() >= instance_id.len() {
+            &mut request_id
+        } else {
+            &mut instance_id
+        };
+        let mut len = longest.len().saturating_sub(excess);
+        while !longest.is_char_boundary(len) {
+            len -= 1;
+        }
+        longest.truncate(len);
+    }
+}
+
+pub(crate) fn to_string_map(headers: &HeaderMap) -> HashMap<String, String> {
+    headers
+        .iter()
+        .map(|(k, v)| {
+            (
+                k.as_str().to_owned(),
+                String::from_utf8_lossy(v.as_bytes()).into_owned(),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    include!("request_tests.rs");
+}
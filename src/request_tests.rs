@@ -0,0 +1,970 @@
+use super::*;
+use serde_json::Value;
+use crate::config::{BodyJsonRule, Condition, ConditionRule, LazyRegex, PathRewrite, RequestIdConfig};
+
+#[tokio::test]
+async fn test_to_string_map() {
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", "application/json".parse().unwrap());
+    headers.insert("X-Custom-Header", "test-value".parse().unwrap());
+
+    let result = to_string_map(&headers);
+
+    assert_eq!(result.len(), 2);
+    assert_eq!(result.get("content-type"), Some(&"application/json".to_string()));
+    assert_eq!(result.get("x-custom-header"), Some(&"test-value".to_string()));
+}
+
+#[test]
+fn test_is_base64_encoded() {
+    assert!(!is_base64_encoded("application/json"));
+    assert!(!is_base64_encoded("text/plain"));
+    assert!(is_base64_encoded("application/octet-stream"));
+    assert!(is_base64_encoded(""));
+    assert!(!is_base64_encoded("Application/JSON; charset=utf-8"));
+    assert!(!is_base64_encoded("text/html;charset=UTF-8"));
+}
+
+#[test]
+fn test_content_family() {
+    let cases = [
+        ("", "none"),
+        ("application/json", "json"),
+        ("application/json; charset=utf-8", "json"),
+        ("application/vnd.api+json", "json"),
+        ("application/xml", "xml"),
+        ("text/xml", "xml"),
+        ("image/svg+xml", "xml"),
+        ("text/plain", "text"),
+        ("TEXT/HTML; charset=UTF-8", "text"),
+        ("application/javascript", "text"),
+        ("application/x-www-form-urlencoded", "form"),
+        ("multipart/form-data; boundary=x", "multipart"),
+        ("image/png", "image"),
+        ("audio/mpeg", "audio"),
+        ("video/mp4", "video"),
+        ("application/octet-stream", "binary"),
+        ("application/pdf", "binary"),
+        ("font/woff2", "other"),
+        ("nonsense", "other"),
+    ];
+    for (content_type, family) in cases {
+        assert_eq!(content_family(content_type), family, "{}", content_type);
+    }
+}
+
+#[test]
+fn test_build_alb_request_body() {
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", "application/json".parse().unwrap());
+    let query = HashMap::from([("a".to_string(), "1".to_string())]);
+
+    let body = build_alb_request_body(&Method::POST, "/orders", &query, &headers, br#"{"id":1}"#);
+    let event: Value = serde_json::from_str(&body).unwrap();
+
+    assert_eq!(event["httpMethod"], "POST");
+    assert_eq!(event["path"], "/orders");
+    assert_eq!(event["queryStringParameters"]["a"], "1");
+    assert_eq!(event["isBase64Encoded"], false);
+    assert_eq!(event["body"], r#"{"id":1}"#);
+    assert_eq!(event["requestContext"]["elb"]["targetGroupArn"], "");
+}
+
+#[test]
+fn test_build_alb_request_body_with_path_param_headers() {
+    let mut headers = HeaderMap::new();
+    headers.insert("x-path-param-id", "spoofed".parse().unwrap());
+    let params = PathParams::from([
+        ("id".to_string(), "a/b c".to_string()),
+        ("Key".to_string(), "docs/report.pdf".to_string()),
+        ("bad".to_string(), "line\nbreak".to_string()),
+    ]);
+    insert_path_param_headers(&mut headers, &params);
+
+    let body = build_alb_request_body(&Method::GET, "/users/a/b c", &HashMap::new(), &headers, b"");
+    let event: Value = serde_json::from_str(&body).unwrap();
+
+    assert_eq!(event["headers"]["x-path-param-id"], "a/b c");
+    assert_eq!(event["headers"]["x-path-param-key"], "docs/report.pdf");
+    assert!(event["headers"].get("x-path-param-bad").is_none());
+}
+
+#[test]
+fn test_build_alb_request_body_binary() {
+    let body = build_alb_request_body(&Method::PUT, "/", &HashMap::new(), &HeaderMap::new(), &[0xff, 0x00]);
+    let event: Value = serde_json::from_str(&body).unwrap();
+
+    assert_eq!(event["isBase64Encoded"], true);
+    assert_eq!(event["body"], "/wA=");
+}
+
+/// `{"hello":"world"}` compressed with the gzip command line tool.
+const GZIP_FIXTURE: &[u8] = &[
+    31, 139, 8, 0, 0, 0, 0, 0, 2, 3, 171, 86, 202, 72, 205, 201, 201, 87, 178, 82, 42, 207, 47, 202, 73, 81, 170, 5, 0,
+    209, 65, 9, 216, 17, 0, 0, 0,
+];
+
+fn encoded_headers(encoding: &str, length: usize) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", "application/json".parse().unwrap());
+    headers.insert("content-encoding", encoding.parse().unwrap());
+    headers.insert("content-length", length.into());
+    headers
+}
+
+#[test]
+fn test_decompress_gzip_fixture() {
+    let mut headers = encoded_headers("gzip", GZIP_FIXTURE.len());
+    let body = decompress_body(&mut headers, Bytes::from_static(GZIP_FIXTURE), 1024).unwrap();
+
+    assert_eq!(body, r#"{"hello":"world"}"#);
+    assert!(headers.get("content-encoding").is_none());
+    assert_eq!(headers["content-length"], "17");
+
+    // The base64 decision runs on the inflated body
+    let event: Value = serde_json::from_str(&build_alb_request_body(
+        &Method::POST,
+        "/",
+        &HashMap::new(),
+        &headers,
+        &body,
+    ))
+    .unwrap();
+    assert_eq!(event["isBase64Encoded"], false);
+    assert_eq!(event["body"], r#"{"hello":"world"}"#);
+}
+
+#[test]
+fn test_decompress_deflate() {
+    use flate2::write::{DeflateEncoder, ZlibEncoder};
+    use std::io::Write;
+
+    let mut zlib = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    zlib.write_all(b"zlib body").unwrap();
+    let mut raw = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+    raw.write_all(b"raw body").unwrap();
+
+    for (compressed, expected) in [(zlib.finish().unwrap(), "zlib body"), (raw.finish().unwrap(), "raw body")] {
+        let mut headers = encoded_headers("deflate", compressed.len());
+        let body = decompress_body(&mut headers, Bytes::from(compressed), 1024).unwrap();
+        assert_eq!(body, expected);
+    }
+}
+
+#[test]
+fn test_decompress_rejects_oversized_body() {
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    // A small payload that inflates to 1MB
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(&vec![0u8; 1024 * 1024]).unwrap();
+    let compressed = encoder.finish().unwrap();
+    assert!(compressed.len() < 4096);
+
+    let mut headers = encoded_headers("gzip", compressed.len());
+    let err = decompress_body(&mut headers, Bytes::from(compressed), 64 * 1024).unwrap_err();
+    assert_eq!(err.status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(headers["content-encoding"], "gzip");
+}
+
+#[test]
+fn test_decompress_rejects_zlib_bomb() {
+    // 8 MiB of zeros compressed with zlib at level 9
+    const ZLIB_BOMB: &[u8] = include_bytes!("testdata/zlib-bomb.zz");
+
+    let mut headers = encoded_headers("deflate", ZLIB_BOMB.len());
+    let err = decompress_body(&mut headers, Bytes::from_static(ZLIB_BOMB), 64 * 1024).unwrap_err();
+    assert_eq!(err.status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(err.code, "decompressed_body_too_large");
+    assert_eq!(headers["content-encoding"], "deflate");
+
+    // A corrupt zlib stream is not retried as raw deflate
+    let mut corrupt = ZLIB_BOMB[..64].to_vec();
+    corrupt[10] ^= 0xff;
+    let err = decompress_body(&mut encoded_headers("deflate", 64), Bytes::from(corrupt), 1024).unwrap_err();
+    assert_eq!(err.status, StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn test_decompress_rejects_corrupt_stream() {
+    let mut corrupt = GZIP_FIXTURE.to_vec();
+    corrupt.truncate(20);
+    let mut headers = encoded_headers("gzip", corrupt.len());
+    let err = decompress_body(&mut headers, Bytes::from(corrupt), 1024).unwrap_err();
+    assert_eq!(err.status, StatusCode::BAD_REQUEST);
+    assert_eq!(err.code, "invalid_content_encoding");
+}
+
+#[test]
+fn test_decompress_ignores_other_encodings() {
+    let mut headers = encoded_headers("br", 3);
+    let body = decompress_body(&mut headers, Bytes::from_static(b"abc"), 1024).unwrap();
+    assert_eq!(body, "abc");
+    assert_eq!(headers["content-encoding"], "br");
+
+    let mut headers = HeaderMap::new();
+    let body = decompress_body(&mut headers, Bytes::from_static(b"abc"), 1024).unwrap();
+    assert_eq!(body, "abc");
+}
+
+fn decode_client_context(encoded: &str) -> serde_json::Value {
+    let raw = base64::engine::general_purpose::STANDARD.decode(encoded).unwrap();
+    serde_json::from_slice(&raw).unwrap()
+}
+
+#[test]
+fn test_build_client_context() {
+    let encoded = build_client_context("gw-1", "req-42");
+    let context = decode_client_context(&encoded);
+    assert_eq!(context["custom"]["gateway"], "lambda-web-gateway");
+    assert_eq!(context["custom"]["gateway_version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(context["custom"]["instance_id"], "gw-1");
+    assert_eq!(context["custom"]["request_id"], "req-42");
+}
+
+#[test]
+fn test_build_client_context_truncates_to_limit() {
+    let request_id = "r".repeat(5000);
+    let instance_id = "é".repeat(1000);
+    let encoded = build_client_context(&instance_id, &request_id);
+    assert!(encoded.len() <= MAX_CLIENT_CONTEXT_BYTES);
+
+    let context = decode_client_context(&encoded);
+    let request_id = context["custom"]["request_id"].as_str().unwrap();
+    let instance_id = context["custom"]["instance_id"].as_str().unwrap();
+    assert!(request_id.len() < 5000);
+    assert!(request_id.chars().all(|c| c == 'r'));
+    assert!(instance_id.chars().all(|c| c == 'é'));
+    assert_eq!(context["custom"]["gateway_version"], env!("CARGO_PKG_VERSION"));
+}
+
+#[test]
+fn test_raw_payload_passes_json_through() {
+    let body = " {\"a\": [1, 2]} ";
+    assert_eq!(raw_payload(Bytes::from(body)).unwrap(), body);
+    assert_eq!(raw_payload(Bytes::new()).unwrap(), "");
+    for body in [&b"a=1&b=2"[..], b"{\"a\": 1", b"\"\xff\""] {
+        let e = raw_payload(Bytes::copy_from_slice(body)).unwrap_err();
+        assert_eq!(e.status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(e.code, "raw_body_not_json");
+    }
+}
+
+#[test]
+fn test_build_raw_client_context() {
+    let params = PathParams::new();
+    let request = event_request(&Method::PUT, "/items/1", "a=1&a=2", "/items/:id", &params);
+    let mut headers = HeaderMap::new();
+    headers.append("accept", HeaderValue::from_static("text/plain"));
+    headers.append("accept", HeaderValue::from_static("text/html"));
+    let context = decode_client_context(&build_raw_client_context("gw-1", &request, &headers).unwrap());
+    let custom = &context["custom"];
+    assert_eq!(custom["instance_id"], "gw-1");
+    assert_eq!(custom["request_id"], "req-1");
+    assert_eq!(custom["method"], "PUT");
+    assert_eq!(custom["path"], "/items/1");
+    assert_eq!(custom["query"], "a=1&a=2");
+    assert_eq!(custom["source_ip"], "192.0.2.7");
+    assert_eq!(custom["headers"], r#"{"accept":"text/plain,text/html"}"#);
+    assert_eq!(custom["headers_truncated"], "false");
+}
+
+#[test]
+fn test_build_raw_client_context_drops_largest_headers() {
+    let params = PathParams::new();
+    let request = event_request(&Method::GET, "/", "", "", &params);
+    let mut headers = HeaderMap::new();
+    headers.insert("x-small", HeaderValue::from_static("1"));
+    headers.insert("x-large", HeaderValue::from_str(&"l".repeat(3000)).unwrap());
+    let encoded = build_raw_client_context("gw-1", &request, &headers).unwrap();
+    assert!(encoded.len() <= MAX_CLIENT_CONTEXT_BYTES);
+    let context = decode_client_context(&encoded);
+    assert_eq!(context["custom"]["headers"], r#"{"x-small":"1"}"#);
+    assert_eq!(context["custom"]["headers_truncated"], "true");
+
+    let query = "q".repeat(3000);
+    let request = event_request(&Method::GET, "/", &query, "", &params);
+    let e = build_raw_client_context("gw-1", &request, &headers).unwrap_err();
+    assert_eq!(e.status, StatusCode::URI_TOO_LONG);
+}
+
+#[test]
+fn test_request_id_prefers_header() {
+    let settings = RequestIdConfig::default();
+    let mut headers = HeaderMap::new();
+    assert_ne!(request_id(&headers, &settings), request_id(&headers, &settings));
+    headers.insert("x-request-id", HeaderValue::from_static("abc"));
+    assert_eq!(request_id(&headers, &settings), "abc");
+
+    let untrusted = RequestIdConfig {
+        trust_incoming: false,
+        ..Default::default()
+    };
+    assert_ne!(request_id(&headers, &untrusted), "abc");
+    let renamed = RequestIdConfig {
+        header: "x-correlation-id".to_string(),
+        ..Default::default()
+    };
+    assert_ne!(request_id(&headers, &renamed), "abc");
+    headers.insert("x-correlation-id", HeaderValue::from_static("def"));
+    assert_eq!(request_id(&headers, &renamed), "def");
+}
+
+#[test]
+fn test_request_id_replaces_unusable_values() {
+    let settings = RequestIdConfig::default();
+    for value in ["", "a b", &"x".repeat(MAX_REQUEST_ID_BYTES + 1)] {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-request-id", HeaderValue::from_str(value).unwrap());
+        assert_ne!(request_id(&headers, &settings), value);
+    }
+}
+
+#[test]
+fn test_generated_request_ids_are_uuid_v7() {
+    let id = request_id(&HeaderMap::new(), &RequestIdConfig::default());
+    let groups: Vec<&str> = id.split('-').collect();
+    assert_eq!(groups.iter().map(|g| g.len()).collect::<Vec<_>>(), [8, 4, 4, 4, 12], "{}", id);
+    assert!(groups[2].starts_with('7'), "{}", id);
+    assert!(matches!(groups[3].as_bytes()[0], b'8' | b'9' | b'a' | b'b'), "{}", id);
+    // Time ordered: the millisecond timestamp leads
+    let later = request_id(&HeaderMap::new(), &RequestIdConfig::default());
+    assert!(later[..8] >= id[..8], "{} then {}", id, later);
+}
+
+#[test]
+fn test_request_context_detects_te_trailers() {
+    let mut headers = HeaderMap::new();
+    assert!(!RequestContext::new(&headers).accepts_trailers);
+    headers.insert("te", HeaderValue::from_static("gzip;q=0.5, Trailers"));
+    assert!(RequestContext::new(&headers).accepts_trailers);
+    headers.insert("te", HeaderValue::from_static("trailersx"));
+    assert!(!RequestContext::new(&headers).accepts_trailers);
+}
+
+#[test]
+fn test_build_alb_request_body_with_setting_headers() {
+    let mut headers = HeaderMap::new();
+    headers.insert("x-gateway-setting-namespace", "spoofed".parse().unwrap());
+    headers.insert("x-gateway-setting-anything", "spoofed".parse().unwrap());
+    let target = Target {
+        forward_settings: vec![
+            ForwardedSetting::TargetName,
+            ForwardedSetting::TimeoutMs,
+            ForwardedSetting::Namespace,
+        ],
+        invoke_timeout_ms: Some(3000),
+        namespace: Some("team-a".to_string()),
+        ..Default::default()
+    };
+    insert_setting_headers(&mut headers, &target, "/team-a/orders/*rest");
+
+    let body = build_alb_request_body(&Method::GET, "/team-a/orders/1", &HashMap::new(), &headers, b"");
+    let event: Value = serde_json::from_str(&body).unwrap();
+
+    assert_eq!(event["headers"]["x-gateway-setting-target-name"], "/team-a/orders/*rest");
+    assert_eq!(event["headers"]["x-gateway-setting-timeout-ms"], "3000");
+    assert_eq!(event["headers"]["x-gateway-setting-namespace"], "team-a");
+    assert!(event["headers"].get("x-gateway-setting-anything").is_none());
+}
+
+#[test]
+fn test_unset_settings_are_left_out() {
+    let mut headers = HeaderMap::new();
+    headers.insert("x-gateway-setting-namespace", "spoofed".parse().unwrap());
+    let target = Target {
+        forward_settings: vec![ForwardedSetting::TimeoutMs, ForwardedSetting::Namespace],
+        ..Default::default()
+    };
+    insert_setting_headers(&mut headers, &target, "/orders");
+
+    assert!(headers.is_empty());
+}
+
+#[test]
+fn test_unknown_forward_setting_is_rejected() {
+    let parsed: Result<Target, _> = serde_yaml::from_str("forward_settings: [target_name, api_keys]");
+    assert!(parsed.unwrap_err().to_string().contains("unknown variant `api_keys`"));
+}
+
+#[test]
+fn test_auth_headers_replace_spoofed_ones() {
+    let mut headers = HeaderMap::new();
+    headers.insert("x-gateway-auth-method", "api_key".parse().unwrap());
+    headers.insert("x-gateway-auth-key-id", "0000000000000000".parse().unwrap());
+    headers.insert("x-gateway-auth-scopes", "admin".parse().unwrap());
+    insert_auth_headers(&mut headers, &AuthContext::new(None, false));
+    assert_eq!(headers.len(), 1);
+    assert_eq!(headers["x-gateway-auth-method"], "none");
+
+    insert_auth_headers(&mut headers, &AuthContext::new(None, true));
+    assert_eq!(headers["x-gateway-auth-method"], "signed_url");
+
+    insert_auth_headers(&mut headers, &AuthContext::new(Some("secret-key"), false));
+    assert_eq!(headers.len(), 2);
+    assert_eq!(headers["x-gateway-auth-method"], "api_key");
+    assert_eq!(headers["x-gateway-auth-key-id"], "85dbe15d75ef9308");
+}
+
+#[test]
+fn test_authorizer_in_apigw_events() {
+    use aws_lambda_events::apigw::{ApiGatewayProxyRequest, ApiGatewayV2httpRequest};
+
+    let (method, params) = (Method::GET, PathParams::new());
+    let request = event_request(&method, "/items", "", "/items", &params);
+    let auth = AuthContext::new(Some("secret-key"), false);
+    assert!(AuthContext::in_authorizer(PayloadMode::ApiGatewayV1));
+    assert!(!AuthContext::in_authorizer(PayloadMode::Alb) && !AuthContext::in_authorizer(PayloadMode::FunctionUrl));
+
+    let mut event = build_event(PayloadMode::ApiGatewayV1, &request, &HeaderMap::new(), "", false);
+    insert_authorizer(PayloadMode::ApiGatewayV1, &mut event, &auth);
+    assert!(!event.to_string().contains("secret-key"));
+    let parsed: ApiGatewayProxyRequest = serde_json::from_value(event).unwrap();
+    let fields = &parsed.request_context.authorizer.fields;
+    assert_eq!(fields["authMethod"], "api_key");
+    assert_eq!(fields["keyId"], "85dbe15d75ef9308");
+    assert_eq!(fields["principalId"], "85dbe15d75ef9308");
+
+    let mut event = build_event(PayloadMode::ApiGatewayV2, &request, &HeaderMap::new(), "", false);
+    insert_authorizer(PayloadMode::ApiGatewayV2, &mut event, &AuthContext::new(None, true));
+    let parsed: ApiGatewayV2httpRequest = serde_json::from_value(event).unwrap();
+    let fields = &parsed.request_context.authorizer.unwrap().fields;
+    assert_eq!(fields["authMethod"], "signed_url");
+    assert!(!fields.contains_key("keyId"));
+
+    // Events without an authorizer are left alone
+    let mut event = build_event(PayloadMode::Alb, &request, &HeaderMap::new(), "", false);
+    insert_authorizer(PayloadMode::Alb, &mut event, &auth);
+    assert!(event["requestContext"].get("authorizer").is_none());
+}
+
+fn json_headers(content_type: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", content_type.parse().unwrap());
+    headers
+}
+
+#[test]
+fn test_is_json_content_type() {
+    for content_type in ["application/json", "Application/JSON; charset=utf-8", "application/problem+json"] {
+        assert!(is_json_content_type(content_type), "{}", content_type);
+    }
+    for content_type in ["text/json-ish", "application/jsonl", "text/plain+json", ""] {
+        assert!(!is_json_content_type(content_type), "{}", content_type);
+    }
+}
+
+fn parsed(body: &[u8]) -> ParsedBody {
+    ParsedBody::new(Bytes::copy_from_slice(body))
+}
+
+#[test]
+fn test_invalid_json_body_reports_location() {
+    let headers = json_headers("application/json");
+    assert_eq!(validate_json_body(&headers, &parsed(br#"{"a": [1, 2], "b": "x"}"#), None), Ok(()));
+
+    let err = validate_json_body(&headers, &parsed(b"{\n  \"a\": 1,\n  \"b\": }"), None).unwrap_err();
+    assert_eq!((err.status, err.phase, err.code), (StatusCode::BAD_REQUEST, ErrorPhase::Ingress, "invalid_json"));
+    assert!(err.message.contains("line 3 column 8"), "{}", err.message);
+    assert!(validate_json_body(&headers, &parsed(br#"{"a": 1} trailing"#), None).is_err());
+}
+
+#[test]
+fn test_json_nesting_limit() {
+    let headers = json_headers("application/json");
+    let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+    assert_eq!(validate_json_body(&headers, &parsed(nested(8).as_bytes()), Some(8)), Ok(()));
+    let err = validate_json_body(&headers, &parsed(nested(9).as_bytes()), Some(8)).unwrap_err();
+    assert_eq!(err.code, "json_too_deep");
+    // Brackets inside strings do not count
+    let quoted = br#"{"a": "[[[[[[[[[[\"]]"}"#;
+    assert_eq!(validate_json_body(&headers, &parsed(quoted), Some(1)), Ok(()));
+    // Far beyond the parser's own recursion limit, still rejected without overflowing
+    let err = validate_json_body(&headers, &parsed(nested(100_000).as_bytes()), Some(64)).unwrap_err();
+    assert_eq!(err.code, "json_too_deep");
+}
+
+#[test]
+fn test_json_validation_skips_what_it_cannot_check() {
+    let mut headers = json_headers("application/json");
+    assert_eq!(validate_json_body(&headers, &parsed(b""), Some(1)), Ok(()));
+    assert_eq!(validate_json_body(&json_headers("text/plain"), &parsed(b"{oops"), None), Ok(()));
+    headers.insert("content-encoding", "gzip".parse().unwrap());
+    assert_eq!(validate_json_body(&headers, &parsed(b"\x1f\x8b"), None), Ok(()));
+}
+
+fn order_match() -> BodyMatch {
+    BodyMatch {
+        body_json: vec![
+            BodyJsonRule {
+                pointer: "/type".to_string(),
+                equals: Some(json!("order.created")),
+                regex: None,
+                target: "orders".to_string(),
+            },
+            BodyJsonRule {
+                pointer: "/customer/id".to_string(),
+                equals: None,
+                regex: Some(LazyRegex::new("^vip-")),
+                target: "vip".to_string(),
+            },
+        ],
+        fallback: "default".to_string(),
+        max_body_bytes: 64,
+        conditions: vec![],
+    }
+}
+
+#[test]
+fn test_match_body_picks_first_matching_rule() {
+    let body_match = order_match();
+    let headers = json_headers("application/json; charset=utf-8");
+    let select = |body: &[u8]| match_body(&body_match, &headers, &parsed(body));
+    assert_eq!(select(br#"{"type": "order.created"}"#), ("orders", true));
+    assert_eq!(select(br#"{"type": "order.created", "customer": {"id": "vip-1"}}"#), ("orders", true));
+    assert_eq!(select(br#"{"type": "x", "customer": {"id": "vip-1"}}"#), ("vip", true));
+    // Equality is on JSON values and regexes only match strings
+    assert_eq!(select(br#"{"type": ["order.created"]}"#), ("default", false));
+    assert_eq!(select(br#"{"customer": {"id": 7}}"#), ("default", false));
+    assert_eq!(select(br#"{"other": 1}"#), ("default", false));
+}
+
+#[test]
+fn test_match_body_falls_back_for_unreadable_bodies() {
+    let body_match = order_match();
+    let order = br#"{"type": "order.created"}"#;
+    let select = |headers: &HeaderMap, body: &[u8]| match_body(&body_match, headers, &parsed(body));
+    assert_eq!(select(&json_headers("text/plain"), order), ("default", false));
+    assert_eq!(select(&HeaderMap::new(), order), ("default", false));
+    assert_eq!(select(&json_headers("application/json"), b"{oops"), ("default", false));
+    assert_eq!(select(&json_headers("application/json"), b""), ("default", false));
+    let mut encoded = json_headers("application/json");
+    encoded.insert("content-encoding", "gzip".parse().unwrap());
+    assert_eq!(select(&encoded, order), ("default", false));
+
+    // Over max_body_bytes, the body is not even parsed
+    let large = format!(r#"{{"type": "order.created", "padding": "{}"}}"#, "x".repeat(64));
+    let body = parsed(large.as_bytes());
+    assert_eq!(match_body(&body_match, &json_headers("application/json"), &body), ("default", false));
+    assert!(body.json.get().is_none());
+}
+
+#[test]
+fn test_match_conditions_picks_first_that_holds() {
+    let body_match = BodyMatch {
+        conditions: vec![
+            ConditionRule {
+                when: Condition::new(r#"header("x-tier") == "gold""#),
+                target: "gold".to_string(),
+            },
+            ConditionRule {
+                when: Condition::new(r#"method == "POST" || query("debug") != null"#),
+                target: "posts".to_string(),
+            },
+        ],
+        ..order_match()
+    };
+    let mut headers = HeaderMap::new();
+    let select = |method: &Method, query: &str, headers: &HeaderMap| {
+        let request = Attributes {
+            method,
+            path: "/events",
+            query,
+            headers,
+            client_ip: None,
+            key_id: None,
+            record: None,
+        };
+        match_conditions(&body_match, &request)
+    };
+    assert_eq!(select(&Method::GET, "", &headers), None);
+    assert_eq!(select(&Method::POST, "", &headers), Some("posts"));
+    assert_eq!(select(&Method::GET, "debug", &headers), Some("posts"));
+    headers.insert("x-tier", "gold".parse().unwrap());
+    assert_eq!(select(&Method::POST, "", &headers), Some("gold"));
+    // Conditions that do not compile never hold
+    let broken = BodyMatch {
+        conditions: vec![ConditionRule {
+            when: Condition::new("method =="),
+            target: "gold".to_string(),
+        }],
+        ..order_match()
+    };
+    let request = Attributes {
+        method: &Method::GET,
+        path: "/",
+        query: "",
+        headers: &headers,
+        client_ip: None,
+        key_id: None,
+        record: None,
+    };
+    assert_eq!(match_conditions(&broken, &request), None);
+}
+
+#[test]
+fn test_parsed_body_is_parsed_once() {
+    let body = parsed(br#"{"type": "order.created"}"#);
+    let shared = body.clone();
+    let headers = json_headers("application/json");
+    assert_eq!(match_body(&order_match(), &headers, &body), ("orders", true));
+    assert_eq!(validate_json_body(&headers, &shared, Some(8)), Ok(()));
+    assert!(std::ptr::eq(body.json().unwrap(), shared.json().unwrap()));
+}
+
+fn event_request<'a>(
+    method: &'a Method,
+    raw_path: &'a str,
+    raw_query_string: &'a str,
+    pattern: &'a str,
+    params: &'a PathParams,
+) -> EventRequest<'a> {
+    EventRequest {
+        method,
+        path: raw_path,
+        raw_path,
+        raw_query_string,
+        pattern,
+        host: None,
+        path_params: params,
+        source_ip: Some("192.0.2.7".parse().unwrap()),
+        request_id: "req-1",
+        multi_value: false,
+    }
+}
+
+#[test]
+fn test_build_apigw_v2_get_with_query_and_cookies() {
+    let mut headers = HeaderMap::new();
+    headers.insert("host", "api.example.com:8000".parse().unwrap());
+    headers.insert("user-agent", "curl/8".parse().unwrap());
+    headers.append("accept", "text/html".parse().unwrap());
+    headers.append("accept", "application/json".parse().unwrap());
+    headers.append("cookie", "a=1; b=2".parse().unwrap());
+    headers.append("cookie", "c=3".parse().unwrap());
+    let params = PathParams::from([("id".to_string(), "a b".to_string())]);
+    let method = Method::GET;
+    let request = event_request(&method, "/items/a%20b", "tag=x&tag=y&q=caf%C3%A9", "/items/:id", &params);
+
+    let event: Value =
+        serde_json::from_str(&build_request_body(PayloadMode::ApiGatewayV2, &request, &headers, b"")).unwrap();
+
+    assert_eq!(event["version"], "2.0");
+    assert_eq!(event["routeKey"], "$default");
+    assert_eq!(event["rawPath"], "/items/a%20b");
+    assert_eq!(event["rawQueryString"], "tag=x&tag=y&q=caf%C3%A9");
+    assert_eq!(event["queryStringParameters"], serde_json::json!({"tag": "x,y", "q": "café"}));
+    assert_eq!(event["pathParameters"], serde_json::json!({"id": "a b"}));
+    assert_eq!(event["cookies"], serde_json::json!(["a=1", "b=2", "c=3"]));
+    assert!(event["headers"].get("cookie").is_none());
+    assert_eq!(event["headers"]["accept"], "text/html,application/json");
+    let http = &event["requestContext"]["http"];
+    assert_eq!(http["method"], "GET");
+    assert_eq!(http["path"], "/items/a%20b");
+    assert_eq!(http["sourceIp"], "192.0.2.7");
+    assert_eq!(http["userAgent"], "curl/8");
+    assert_eq!(event["requestContext"]["domainName"], "api.example.com");
+    assert_eq!(event["requestContext"]["domainPrefix"], "api");
+    assert_eq!(event["requestContext"]["requestId"], "req-1");
+    assert!(event["requestContext"]["timeEpoch"].as_i64().unwrap() > 0);
+    assert_eq!(event["isBase64Encoded"], false);
+    assert!(event.get("body").is_none());
+}
+
+#[test]
+fn test_build_apigw_v2_post_with_binary_body() {
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", "application/octet-stream".parse().unwrap());
+    let body: Vec<u8> = (0..=255).collect();
+    let params = PathParams::new();
+    let method = Method::POST;
+    let request = event_request(&method, "/upload", "", "/upload", &params);
+
+    let event: Value =
+        serde_json::from_str(&build_request_body(PayloadMode::ApiGatewayV2, &request, &headers, &body)).unwrap();
+
+    assert_eq!(event["isBase64Encoded"], true);
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(event["body"].as_str().unwrap())
+        .unwrap();
+    assert_eq!(decoded, body);
+    assert_eq!(event["rawQueryString"], "");
+    for absent in ["queryStringParameters", "pathParameters", "cookies"] {
+        assert!(event.get(absent).is_none(), "{}", absent);
+    }
+}
+
+#[test]
+fn test_build_apigw_v1_get_with_repeated_values() {
+    let mut headers = HeaderMap::new();
+    headers.insert("user-agent", "curl/8".parse().unwrap());
+    headers.append("accept", "text/html".parse().unwrap());
+    headers.append("accept", "application/json".parse().unwrap());
+    let params = PathParams::from([("id".to_string(), "42".to_string())]);
+    let method = Method::GET;
+    let request = event_request(&method, "/items/42", "tag=x&tag=y&q=caf%C3%A9", "/items/:id", &params);
+
+    let event: Value =
+        serde_json::from_str(&build_request_body(PayloadMode::ApiGatewayV1, &request, &headers, b"")).unwrap();
+
+    assert_eq!(event["resource"], "/items/{id}");
+    assert_eq!(event["path"], "/items/42");
+    assert_eq!(event["httpMethod"], "GET");
+    assert_eq!(event["headers"]["accept"], "application/json");
+    assert_eq!(event["multiValueHeaders"]["accept"], serde_json::json!(["text/html", "application/json"]));
+    assert_eq!(event["queryStringParameters"], serde_json::json!({"tag": "y", "q": "café"}));
+    assert_eq!(
+        event["multiValueQueryStringParameters"],
+        serde_json::json!({"tag": ["x", "y"], "q": ["café"]})
+    );
+    assert_eq!(event["pathParameters"], serde_json::json!({"id": "42"}));
+    assert_eq!(event["stageVariables"], Value::Null);
+    let context = &event["requestContext"];
+    assert_eq!(context["resourcePath"], "/items/{id}");
+    assert_eq!(context["httpMethod"], "GET");
+    assert_eq!(context["requestId"], "req-1");
+    assert_eq!(context["identity"]["sourceIp"], "192.0.2.7");
+    assert_eq!(context["identity"]["userAgent"], "curl/8");
+    assert!(context["requestTimeEpoch"].as_i64().unwrap() > 0);
+    assert_eq!(event["body"], Value::Null);
+    assert_eq!(event["isBase64Encoded"], false);
+}
+
+#[test]
+fn test_build_apigw_v1_post_with_binary_body() {
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", "application/octet-stream".parse().unwrap());
+    let body: Vec<u8> = (0..=255).collect();
+    let params = PathParams::new();
+    let method = Method::POST;
+    let request = event_request(&method, "/upload", "", "/upload", &params);
+
+    let event: Value =
+        serde_json::from_str(&build_request_body(PayloadMode::ApiGatewayV1, &request, &headers, &body)).unwrap();
+
+    assert_eq!(event["isBase64Encoded"], true);
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(event["body"].as_str().unwrap())
+        .unwrap();
+    assert_eq!(decoded, body);
+    for empty in ["queryStringParameters", "multiValueQueryStringParameters", "pathParameters"] {
+        assert_eq!(event[empty], Value::Null, "{}", empty);
+    }
+}
+
+#[test]
+fn test_resource_path() {
+    assert_eq!(resource_path("/items/:id"), "/items/{id}");
+    assert_eq!(resource_path("/files/*rest"), "/files/{rest+}");
+    assert_eq!(resource_path("/users/:user/posts/:post"), "/users/{user}/posts/{post}");
+    assert_eq!(resource_path("/static"), "/static");
+    assert_eq!(resource_path(""), "/{proxy+}");
+}
+
+/// Replaces the per-invocation fields of a captured event with those of `event`.
+fn align_invocation_fields(event: &Value, captured: &str) -> Value {
+    let mut captured: Value = serde_json::from_str(captured).unwrap();
+    for field in ["requestId", "time", "timeEpoch"] {
+        captured["requestContext"][field] = event["requestContext"][field].clone();
+    }
+    captured
+}
+
+/// A GET to a Function URL with `AuthType: NONE`, as logged by the function.
+const CAPTURED_FUNCTION_URL_GET: &str = r#"{
+    "version": "2.0",
+    "routeKey": "$default",
+    "rawPath": "/items/a%20b",
+    "rawQueryString": "tag=x&tag=y&q=caf%C3%A9",
+    "cookies": ["session=abc", "theme=dark"],
+    "headers": {
+        "host": "abcdefghij.lambda-url.us-east-1.on.aws",
+        "x-forwarded-for": "198.51.100.23",
+        "accept": "text/html,application/json",
+        "user-agent": "curl/8.4.0"
+    },
+    "queryStringParameters": {"tag": "x,y", "q": "café"},
+    "requestContext": {
+        "accountId": "anonymous",
+        "apiId": "abcdefghij",
+        "domainName": "abcdefghij.lambda-url.us-east-1.on.aws",
+        "domainPrefix": "abcdefghij",
+        "http": {
+            "method": "GET",
+            "path": "/items/a%20b",
+            "protocol": "HTTP/1.1",
+            "sourceIp": "198.51.100.23",
+            "userAgent": "curl/8.4.0"
+        },
+        "requestId": "6f2d3c4e-0d1a-4a47-9a53-3a1f0c1b2d3e",
+        "routeKey": "$default",
+        "stage": "$default",
+        "time": "16/Oct/2026:09:30:12 +0000",
+        "timeEpoch": 1792143012345
+    },
+    "isBase64Encoded": false
+}"#;
+
+/// A binary POST to the same Function URL.
+const CAPTURED_FUNCTION_URL_POST: &str = r#"{
+    "version": "2.0",
+    "routeKey": "$default",
+    "rawPath": "/upload",
+    "rawQueryString": "",
+    "headers": {
+        "host": "abcdefghij.lambda-url.us-east-1.on.aws",
+        "content-type": "application/octet-stream",
+        "user-agent": "curl/8.4.0"
+    },
+    "requestContext": {
+        "accountId": "anonymous",
+        "apiId": "abcdefghij",
+        "domainName": "abcdefghij.lambda-url.us-east-1.on.aws",
+        "domainPrefix": "abcdefghij",
+        "http": {
+            "method": "POST",
+            "path": "/upload",
+            "protocol": "HTTP/1.1",
+            "sourceIp": "192.0.2.7",
+            "userAgent": "curl/8.4.0"
+        },
+        "requestId": "0b8e7a6c-5d4f-4e3a-8b2c-1d0e9f8a7b6c",
+        "routeKey": "$default",
+        "stage": "$default",
+        "time": "16/Oct/2026:09:31:40 +0000",
+        "timeEpoch": 1792143100987
+    },
+    "body": "AAECAw==",
+    "isBase64Encoded": true
+}"#;
+
+#[test]
+fn test_build_function_url_get_matches_captured_event() {
+    let mut headers = HeaderMap::new();
+    headers.insert("host", "abcdefghij.lambda-url.us-east-1.on.aws".parse().unwrap());
+    headers.insert("x-forwarded-for", "198.51.100.23".parse().unwrap());
+    headers.append("accept", "text/html".parse().unwrap());
+    headers.append("accept", "application/json".parse().unwrap());
+    headers.insert("user-agent", "curl/8.4.0".parse().unwrap());
+    headers.append("cookie", "session=abc; theme=dark".parse().unwrap());
+    // Function URLs have no routes, so captures are not passed on
+    let params = PathParams::from([("id".to_string(), "a b".to_string())]);
+    let method = Method::GET;
+    let request = event_request(&method, "/items/a%20b", "tag=x&tag=y&q=caf%C3%A9", "/items/:id", &params);
+
+    let event: Value =
+        serde_json::from_str(&build_request_body(PayloadMode::FunctionUrl, &request, &headers, b"")).unwrap();
+
+    assert_eq!(event, align_invocation_fields(&event, CAPTURED_FUNCTION_URL_GET));
+    assert!(event["requestContext"]["timeEpoch"].as_i64().unwrap() > 0);
+}
+
+#[test]
+fn test_build_function_url_binary_post_matches_captured_event() {
+    let mut headers = HeaderMap::new();
+    headers.insert("host", "abcdefghij.lambda-url.us-east-1.on.aws".parse().unwrap());
+    headers.insert("content-type", "application/octet-stream".parse().unwrap());
+    headers.insert("user-agent", "curl/8.4.0".parse().unwrap());
+    let params = PathParams::new();
+    let method = Method::POST;
+    let request = event_request(&method, "/upload", "", "/upload", &params);
+
+    let event: Value = serde_json::from_str(&build_request_body(
+        PayloadMode::FunctionUrl,
+        &request,
+        &headers,
+        &[0, 1, 2, 3],
+    ))
+    .unwrap();
+
+    // Without X-Forwarded-For the peer address is the source
+    assert_eq!(event, align_invocation_fields(&event, CAPTURED_FUNCTION_URL_POST));
+}
+
+#[test]
+fn test_build_alb_multi_value_event() {
+    let mut headers = HeaderMap::new();
+    headers.append("accept", "text/html".parse().unwrap());
+    headers.append("accept", "application/json".parse().unwrap());
+    headers.insert("user-agent", "curl/8".parse().unwrap());
+    let params = PathParams::new();
+    let method = Method::GET;
+    let mut request = event_request(&method, "/search", "tag=a&tag=b&q=caf%C3%A9", "/search", &params);
+    request.multi_value = true;
+
+    let event: Value = serde_json::from_str(&build_request_body(PayloadMode::Alb, &request, &headers, b"")).unwrap();
+
+    assert_eq!(event["multiValueHeaders"]["accept"], serde_json::json!(["text/html", "application/json"]));
+    assert_eq!(event["multiValueHeaders"]["user-agent"], serde_json::json!(["curl/8"]));
+    assert_eq!(
+        event["multiValueQueryStringParameters"],
+        serde_json::json!({"tag": ["a", "b"], "q": ["café"]})
+    );
+    // As with ALB, the single-value maps are replaced rather than sent alongside
+    assert!(event.get("headers").is_none());
+    assert!(event.get("queryStringParameters").is_none());
+
+    request.multi_value = false;
+    let event: Value = serde_json::from_str(&build_request_body(PayloadMode::Alb, &request, &headers, b"")).unwrap();
+    assert_eq!(event["queryStringParameters"]["tag"], "b");
+    assert!(event.get("multiValueQueryStringParameters").is_none());
+}
+
+fn rewriting_target(strip_prefix: bool, rewrite: Option<(&str, &str)>) -> Target {
+    Target {
+        strip_prefix,
+        rewrite: rewrite.map(|(from, to)| PathRewrite {
+            from: LazyRegex::new(from),
+            to: to.to_string(),
+        }),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_rewrite_path_strips_literal_prefix() {
+    let target = rewriting_target(true, None);
+    assert_eq!(rewrite_path(&target, "/svc/orders/*rest", "/svc/orders/list"), Some("/list".to_string()));
+    assert_eq!(rewrite_path(&target, "/svc/orders/*rest", "/svc/orders"), Some("/".to_string()));
+    assert_eq!(rewrite_path(&target, "/svc/orders/*rest", "/svc/orders/"), Some("/".to_string()));
+    // Only the literal segments ahead of parameters go, and the rest stays encoded
+    assert_eq!(rewrite_path(&target, "shop/svc/:id", "/svc/a%2Fb"), Some("/a%2Fb".to_string()));
+    assert_eq!(rewrite_path(&Target::default(), "/svc/*rest", "/svc/list"), None);
+}
+
+#[test]
+fn test_rewrite_path_replaces_prefix_or_regex() {
+    let literal = rewriting_target(false, Some(("/v1/", "/api/")));
+    assert_eq!(rewrite_path(&literal, "/v1/*rest", "/v1/users"), Some("/api/users".to_string()));
+    assert_eq!(rewrite_path(&literal, "/*rest", "/v2/users"), Some("/v2/users".to_string()));
+
+    let regex = rewriting_target(false, Some(("^/users/([^/]+)/orders", "/orders/by-user/$1")));
+    assert_eq!(
+        rewrite_path(&regex, "/users/*rest", "/users/42/orders/7"),
+        Some("/orders/by-user/42/7".to_string())
+    );
+
+    // A rewrite after stripping, whose result would have a double slash
+    let both = rewriting_target(true, Some(("/", "/internal/")));
+    assert_eq!(rewrite_path(&both, "/svc/*rest", "/svc//list"), Some("/internal/list".to_string()));
+    assert_eq!(decode_path("/caf%C3%A9"), "/café");
+}
+
+#[test]
+fn test_parse_forwarded_ip() {
+    for (value, expected) in [
+        ("198.51.100.23", "198.51.100.23"),
+        ("198.51.100.23:443", "198.51.100.23"),
+        ("2001:db8::1", "2001:db8::1"),
+        ("[2001:db8::1]", "2001:db8::1"),
+        ("[2001:db8::1]:443", "2001:db8::1"),
+        ("::ffff:198.51.100.23", "198.51.100.23"),
+        ("[::ffff:198.51.100.23]:443", "198.51.100.23"),
+    ] {
+        assert_eq!(parse_forwarded_ip(value), Some(expected.parse().unwrap()), "{}", value);
+    }
+    for invalid in ["", "unknown", "[2001:db8::1", "2001:db8::1]:443", "_hidden"] {
+        assert_eq!(parse_forwarded_ip(invalid), None, "{}", invalid);
+    }
+}
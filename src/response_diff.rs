@@ -0,0 +1,201 @@
+use crate::config::{BodyComparison, ShadowConfig};
+use axum::body::Bytes;
+use axum::http::HeaderMap;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+/// Differences kept per comparison; the rest are dropped.
+pub const MAX_DIFFERENCES: usize = 20;
+/// Mismatches kept for `GET /admin/shadow-mismatches`.
+pub const RECENT_MISMATCHES: usize = 50;
+
+/// A response as compared: the status, headers and whole body the client gets or would get.
+#[derive(Clone, Debug, Default)]
+pub struct Observed {
+    pub status: u16,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+/// Where the responses of a target's function and of its shadow differ. Header and body values
+/// are described by their kind and size only, so that no response data is kept.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Difference {
+    /// `status`, `header content-type`, `body`, or `body` followed by the JSON pointer of a
+    /// field, as in `body /items/0/price`.
+    pub location: String,
+    pub primary: String,
+    pub shadow: String,
+}
+
+/// A request whose shadow answered differently, as listed by `GET /admin/shadow-mismatches`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ShadowMismatch {
+    pub timestamp_ms: u128,
+    pub target: String,
+    pub method: String,
+    /// The gateway's request ID, to find the request in the access log.
+    pub request_id: String,
+    /// The shadow function.
+    pub function: String,
+    pub differences: Vec<Difference>,
+}
+
+/// Compares the status, the `headers` and the body of two responses as `settings` say, returning
+/// at most [`MAX_DIFFERENCES`] differences.
+pub fn compare(primary: &Observed, shadow: &Observed, settings: &ShadowConfig) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    if primary.status != shadow.status {
+        differences.push(Difference {
+            location: "status".to_string(),
+            primary: primary.status.to_string(),
+            shadow: shadow.status.to_string(),
+        });
+    }
+    for name in &settings.headers {
+        let (a, b) = (
+            header_value(&primary.headers, name),
+            header_value(&shadow.headers, name),
+        );
+        if a != b {
+            differences.push(Difference {
+                location: format!("header {}", name.to_ascii_lowercase()),
+                primary: describe_bytes(a.as_deref().map(str::as_bytes)),
+                shadow: describe_bytes(b.as_deref().map(str::as_bytes)),
+            });
+        }
+    }
+    let json = match settings.body {
+        BodyComparison::Json => serde_json::from_slice::<Value>(&primary.body)
+            .ok()
+            .zip(serde_json::from_slice::<Value>(&shadow.body).ok()),
+        BodyComparison::Exact => None,
+    };
+    match json {
+        Some((a, b)) => {
+            let ignored: Vec<Vec<String>> = settings
+                .ignore_pointers
+                .iter()
+                .map(String::as_str)
+                .map(parse_pointer)
+                .collect();
+            for (pointer, a, b) in json_differences(&a, &b, &ignored, MAX_DIFFERENCES) {
+                differences.push(Difference {
+                    location: format!("body {}", pointer).trim_end().to_string(),
+                    primary: describe_json(a),
+                    shadow: describe_json(b),
+                });
+            }
+        }
+        None if primary.body != shadow.body => differences.push(Difference {
+            location: "body".to_string(),
+            primary: describe_bytes(Some(&primary.body[..])),
+            shadow: describe_bytes(Some(&shadow.body[..])),
+        }),
+        None => {}
+    }
+    differences.truncate(MAX_DIFFERENCES);
+    differences
+}
+
+/// Every value of `name`, joined as one header line; `None` when absent.
+fn header_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    let values: Vec<String> = headers
+        .get_all(name)
+        .iter()
+        .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+        .collect();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+/// The fields where `a` and `b` differ, as JSON pointers with the value on each side, skipping
+/// the subtrees `ignored` names and stopping after `limit`. Objects are compared key by key
+/// whatever their order; arrays element by element.
+pub fn json_differences<'a>(
+    a: &'a Value,
+    b: &'a Value,
+    ignored: &[Vec<String>],
+    limit: usize,
+) -> Vec<(String, Option<&'a Value>, Option<&'a Value>)> {
+    let mut out = Vec::new();
+    walk(Some(a), Some(b), &mut Vec::new(), ignored, limit, &mut out);
+    out
+}
+
+fn walk<'a>(
+    a: Option<&'a Value>,
+    b: Option<&'a Value>,
+    path: &mut Vec<String>,
+    ignored: &[Vec<String>],
+    limit: usize,
+    out: &mut Vec<(String, Option<&'a Value>, Option<&'a Value>)>,
+) {
+    if out.len() >= limit || ignored.iter().any(|pointer| matches_path(pointer, path)) {
+        return;
+    }
+    match (a, b) {
+        (Some(Value::Object(a)), Some(Value::Object(b))) => {
+            let keys: BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+            for key in keys {
+                path.push(key.clone());
+                walk(a.get(key), b.get(key), path, ignored, limit, out);
+                path.pop();
+            }
+        }
+        (Some(Value::Array(a)), Some(Value::Array(b))) => {
+            for i in 0..a.len().max(b.len()) {
+                path.push(i.to_string());
+                walk(a.get(i), b.get(i), path, ignored, limit, out);
+                path.pop();
+            }
+        }
+        (a, b) if a != b => out.push((to_pointer(path), a, b)),
+        _ => {}
+    }
+}
+
+/// Splits a JSON pointer into its unescaped segments.
+pub fn parse_pointer(pointer: &str) -> Vec<String> {
+    pointer
+        .split('/')
+        .skip(1)
+        .map(|segment| segment.replace("~1", "/").replace("~0", "~"))
+        .collect()
+}
+
+fn matches_path(pointer: &[String], path: &[String]) -> bool {
+    pointer.len() == path.len() && pointer.iter().zip(path).all(|(p, s)| p == "*" || p == s)
+}
+
+fn to_pointer(path: &[String]) -> String {
+    path.iter().fold(String::new(), |mut pointer, segment| {
+        pointer.push('/');
+        pointer.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+        pointer
+    })
+}
+
+fn describe_bytes(value: Option<&[u8]>) -> String {
+    match value {
+        Some(value) => format!("{} bytes", value.len()),
+        None => "missing".to_string(),
+    }
+}
+
+fn describe_json(value: Option<&Value>) -> String {
+    match value {
+        None => "missing".to_string(),
+        Some(Value::Null) => "null".to_string(),
+        Some(Value::Bool(_)) => "boolean".to_string(),
+        Some(Value::Number(_)) => "number".to_string(),
+        Some(Value::String(s)) => format!("string of {} bytes", s.len()),
+        Some(Value::Array(a)) => format!("array of {} items", a.len()),
+        Some(Value::Object(o)) => format!("object of {} keys", o.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("response_diff_tests.rs");
+}
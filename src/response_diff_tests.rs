@@ -0,0 +1,141 @@
+use super::*;
+use axum::http::HeaderValue;
+use serde_json::json;
+
+fn observed(status: u16, content_type: &'static str, body: &'static str) -> Observed {
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static(content_type));
+    Observed {
+        status,
+        headers,
+        body: Bytes::from_static(body.as_bytes()),
+    }
+}
+
+fn settings(body: BodyComparison, ignore_pointers: &[&str]) -> ShadowConfig {
+    ShadowConfig {
+        function: "candidate".to_string(),
+        body,
+        ignore_pointers: ignore_pointers.iter().map(|p| p.to_string()).collect(),
+        ..Default::default()
+    }
+}
+
+fn locations(differences: &[Difference]) -> Vec<&str> {
+    differences.iter().map(|d| d.location.as_str()).collect()
+}
+
+#[test]
+fn test_identical_responses_match() {
+    let a = observed(200, "application/json", r#"{"id": 1, "tags": ["a", "b"]}"#);
+    assert!(compare(&a, &a.clone(), &settings(BodyComparison::Json, &[])).is_empty());
+    assert!(compare(&a, &a.clone(), &settings(BodyComparison::Exact, &[])).is_empty());
+}
+
+#[test]
+fn test_status_and_selected_headers() {
+    let a = observed(200, "application/json", "{}");
+    let mut b = observed(503, "text/plain", "{}");
+    b.headers.insert("x-debug", HeaderValue::from_static("on"));
+    let differences = compare(&a, &b, &settings(BodyComparison::Json, &[]));
+    // Only the configured headers count
+    assert_eq!(locations(&differences), ["status", "header content-type"]);
+    assert_eq!((differences[0].primary.as_str(), differences[0].shadow.as_str()), ("200", "503"));
+    // Header values are redacted to their size
+    assert_eq!(
+        (differences[1].primary.as_str(), differences[1].shadow.as_str()),
+        ("16 bytes", "10 bytes")
+    );
+
+    b.headers.remove("content-type");
+    let differences = compare(&a, &b, &settings(BodyComparison::Json, &[]));
+    assert_eq!(differences[1].shadow, "missing");
+}
+
+#[test]
+fn test_json_bodies_compare_structurally() {
+    let a = observed(200, "application/json", r#"{"id": 1, "name": "x", "items": [{"id": 7, "price": 3}]}"#);
+    let reordered = observed(200, "application/json", r#"{"items":[{"price":3,"id":7}],"name":"x","id":1}"#);
+    assert!(compare(&a, &reordered, &settings(BodyComparison::Json, &[])).is_empty());
+    // Byte for byte, the order and spacing count
+    let differences = compare(&a, &reordered, &settings(BodyComparison::Exact, &[]));
+    assert_eq!(locations(&differences), ["body"]);
+
+    let changed = r#"{"id": 1, "items": [{"id": 7, "price": "3"}, {}], "extra": null}"#;
+    let changed = observed(200, "application/json", changed);
+    let differences = compare(&a, &changed, &settings(BodyComparison::Json, &[]));
+    assert_eq!(
+        locations(&differences),
+        ["body /extra", "body /items/0/price", "body /items/1", "body /name"]
+    );
+    let described: Vec<(&str, &str)> = differences
+        .iter()
+        .map(|d| (d.primary.as_str(), d.shadow.as_str()))
+        .collect();
+    assert_eq!(
+        described,
+        [
+            ("missing", "null"),
+            ("number", "string of 1 bytes"),
+            ("missing", "object of 0 keys"),
+            ("string of 1 bytes", "missing"),
+        ]
+    );
+}
+
+#[test]
+fn test_ignored_pointers_with_wildcards() {
+    let a = observed(200, "application/json", r#"{"at": 1, "items": [{"id": 1, "ts": 5}, {"id": 2, "ts": 6}]}"#);
+    let b = observed(200, "application/json", r#"{"at": 2, "items": [{"id": 1, "ts": 9}, {"id": 3, "ts": 8}]}"#);
+    let differences = compare(&a, &b, &settings(BodyComparison::Json, &["/at", "/items/*/ts"]));
+    assert_eq!(locations(&differences), ["body /items/1/id"]);
+    // A whole subtree can be left out
+    assert!(compare(&a, &b, &settings(BodyComparison::Json, &["/at", "/items"])).is_empty());
+}
+
+#[test]
+fn test_pointer_escapes() {
+    assert_eq!(parse_pointer("/a~1b/c~0d/*"), ["a/b", "c~d", "*"]);
+    let a = json!({"a/b": 1, "c~d": 1});
+    let b = json!({"a/b": 2, "c~d": 2});
+    let found: Vec<String> = json_differences(&a, &b, &[], 10).into_iter().map(|(p, _, _)| p).collect();
+    assert_eq!(found, ["/a~1b", "/c~0d"]);
+    let ignored = vec![parse_pointer("/a~1b")];
+    let found: Vec<String> = json_differences(&a, &b, &ignored, 10).into_iter().map(|(p, _, _)| p).collect();
+    assert_eq!(found, ["/c~0d"]);
+}
+
+#[test]
+fn test_non_json_bodies_fall_back_to_bytes() {
+    let a = observed(200, "text/plain", "hello");
+    let b = observed(200, "text/plain", "hello!");
+    let differences = compare(&a, &b, &settings(BodyComparison::Json, &[]));
+    assert_eq!(locations(&differences), ["body"]);
+    assert_eq!((differences[0].primary.as_str(), differences[0].shadow.as_str()), ("5 bytes", "6 bytes"));
+}
+
+#[test]
+fn test_differences_are_capped() {
+    let a = json!((0..50).collect::<Vec<u32>>());
+    let b = json!((1..51).collect::<Vec<u32>>());
+    assert_eq!(json_differences(&a, &b, &[], MAX_DIFFERENCES).len(), MAX_DIFFERENCES);
+    let a = Observed {
+        body: Bytes::from(a.to_string()),
+        ..observed(200, "application/json", "")
+    };
+    let b = Observed {
+        status: 500,
+        body: Bytes::from(b.to_string()),
+        ..observed(200, "application/json", "")
+    };
+    assert_eq!(compare(&a, &b, &settings(BodyComparison::Json, &[])).len(), MAX_DIFFERENCES);
+}
+
+#[test]
+fn test_root_values_that_differ() {
+    let a = observed(200, "application/json", "1");
+    let b = observed(200, "application/json", "[1]");
+    let differences = compare(&a, &b, &settings(BodyComparison::Json, &[]));
+    assert_eq!(locations(&differences), ["body"]);
+    assert_eq!((differences[0].primary.as_str(), differences[0].shadow.as_str()), ("number", "array of 1 items"));
+}
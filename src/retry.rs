@@ -0,0 +1,43 @@
+use crate::config::{InvokeRetryConfig, RetryOn};
+use crate::invoker::InvokeError;
+use axum::http::Method;
+use std::time::Duration;
+
+/// Which of the retryable failures `e` is, if any.
+pub fn reason(e: &InvokeError) -> Option<RetryOn> {
+    match e {
+        InvokeError::Throttled(..) => Some(RetryOn::Throttled),
+        InvokeError::Service(_) => Some(RetryOn::ServiceError),
+        _ => None,
+    }
+}
+
+/// Whether a request with `method` may be retried: idempotent methods always, others only with
+/// `retry_non_idempotent`.
+pub fn allows(config: &InvokeRetryConfig, method: &Method) -> bool {
+    method.is_idempotent() || config.retry_non_idempotent
+}
+
+/// Cap of the delay before retry `retry`, counted from 1: `base_delay_ms` doubled per retry, up
+/// to `max_delay_ms`.
+pub fn delay_cap(config: &InvokeRetryConfig, retry: u32) -> Duration {
+    let factor = 1u64.checked_shl(retry.saturating_sub(1)).unwrap_or(u64::MAX);
+    let ms = config.base_delay_ms.saturating_mul(factor).min(config.max_delay_ms);
+    Duration::from_millis(ms)
+}
+
+/// The delay before retry `retry`, drawn uniformly below [`delay_cap`] ("full jitter").
+pub fn backoff(config: &InvokeRetryConfig, retry: u32) -> Duration {
+    let cap = delay_cap(config, retry);
+    let mut bytes = [0u8; 8];
+    if getrandom::getrandom(&mut bytes).is_err() {
+        return cap;
+    }
+    let fraction = (u64::from_le_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64;
+    cap.mul_f64(fraction)
+}
+
+#[cfg(test)]
+mod tests {
+    include!("retry_tests.rs");
+}
@@ -0,0 +1,108 @@
+use crate::config::RetryAfterConfig;
+use serde::Serialize;
+use std::time::Duration;
+
+/// Where a `Retry-After` value came from, in order of precedence.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryAfterSource {
+    /// Lambda's own hint on a throttling error.
+    Upstream,
+    /// The gateway's limiter for the target.
+    Limiter,
+    /// The circuit breaker of the target.
+    Breaker,
+    /// The end of the target's closed schedule window.
+    Schedule,
+    /// The end of the client address's authentication ban.
+    Ban,
+    /// The configured value for the error.
+    Default,
+}
+
+/// What is known about when a rejected request may succeed. Every error answered with
+/// `Retry-After` computes the header from these, so the best-informed source always wins.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RetryAfterHints {
+    /// Hint sent by Lambda with a throttling error.
+    pub upstream: Option<Duration>,
+    /// Time until the target's limiter is expected to admit requests again.
+    pub limiter: Option<Duration>,
+    /// Time left in the cooldown of the target's circuit breaker.
+    pub breaker: Option<Duration>,
+    /// Time until the target's schedule opens again.
+    pub schedule: Option<Duration>,
+    /// Time left in the authentication ban of the client's address.
+    pub ban: Option<Duration>,
+}
+
+impl RetryAfterHints {
+    pub fn upstream(upstream: Option<Duration>) -> Self {
+        Self {
+            upstream,
+            ..Default::default()
+        }
+    }
+
+    pub fn limiter(limiter: Option<Duration>) -> Self {
+        Self {
+            limiter,
+            ..Default::default()
+        }
+    }
+
+    pub fn schedule(schedule: Option<Duration>) -> Self {
+        Self {
+            schedule,
+            ..Default::default()
+        }
+    }
+
+    pub fn ban(ban: Option<Duration>) -> Self {
+        Self {
+            ban,
+            ..Default::default()
+        }
+    }
+
+    /// Seconds to send and their source: the first hint available, else `default_secs`. Hints are
+    /// rounded up to whole seconds, and the result is kept between one second and `max_secs`.
+    pub fn compute(&self, default_secs: u64, max_secs: u64) -> (u64, RetryAfterSource) {
+        let (secs, source) = [
+            (self.upstream, RetryAfterSource::Upstream),
+            (self.limiter, RetryAfterSource::Limiter),
+            (self.breaker, RetryAfterSource::Breaker),
+            (self.schedule, RetryAfterSource::Schedule),
+            (self.ban, RetryAfterSource::Ban),
+        ]
+        .into_iter()
+        .find_map(|(hint, source)| hint.map(|hint| (ceil_secs(hint), source)))
+        .unwrap_or((default_secs, RetryAfterSource::Default));
+        (secs.clamp(1, max_secs.max(1)), source)
+    }
+
+    /// Like [`compute`](Self::compute) with the configured maximum, logging the source.
+    pub fn resolve(&self, default_secs: u64, config: &RetryAfterConfig) -> u64 {
+        let (secs, source) = self.compute(default_secs, config.max_secs);
+        tracing::debug!(secs, ?source, "Computed Retry-After");
+        secs
+    }
+}
+
+/// Parses Lambda's `retryAfterSeconds`, a decimal number of seconds.
+pub fn parse_upstream_hint(value: &str) -> Option<Duration> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+}
+
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    include!("retry_after_tests.rs");
+}
@@ -0,0 +1,50 @@
+use super::*;
+use crate::ewma::estimate_wait;
+
+const ALL: RetryAfterHints = RetryAfterHints {
+    upstream: Some(Duration::from_secs(7)),
+    limiter: Some(Duration::from_secs(5)),
+    breaker: Some(Duration::from_secs(3)),
+    schedule: Some(Duration::from_secs(2)),
+    ban: Some(Duration::from_secs(4)),
+};
+
+#[test]
+fn test_precedence() {
+    assert_eq!(ALL.compute(2, 60), (7, RetryAfterSource::Upstream));
+    let hints = RetryAfterHints { upstream: None, ..ALL };
+    assert_eq!(hints.compute(2, 60), (5, RetryAfterSource::Limiter));
+    let hints = RetryAfterHints { limiter: None, ..hints };
+    assert_eq!(hints.compute(2, 60), (3, RetryAfterSource::Breaker));
+    let hints = RetryAfterHints { breaker: None, ..hints };
+    assert_eq!(hints.compute(9, 60), (2, RetryAfterSource::Schedule));
+    let hints = RetryAfterHints { schedule: None, ..hints };
+    assert_eq!(hints.compute(9, 60), (4, RetryAfterSource::Ban));
+    assert_eq!(RetryAfterHints::default().compute(2, 60), (2, RetryAfterSource::Default));
+}
+
+#[test]
+fn test_clamped_to_maximum() {
+    assert_eq!(ALL.compute(2, 4), (4, RetryAfterSource::Upstream));
+    assert_eq!(RetryAfterHints::default().compute(90, 60), (60, RetryAfterSource::Default));
+    // At least one second, whatever the hint or settings
+    let hints = RetryAfterHints::upstream(Some(Duration::ZERO));
+    assert_eq!(hints.compute(2, 60), (1, RetryAfterSource::Upstream));
+    assert_eq!(RetryAfterHints::default().compute(0, 0), (1, RetryAfterSource::Default));
+}
+
+#[test]
+fn test_rounds_up_to_whole_seconds() {
+    let limiter = |wait| RetryAfterHints::limiter(Some(wait)).compute(1, 60).0;
+    assert_eq!(limiter(estimate_wait(0, 4, 20.0)), 1);
+    assert_eq!(limiter(estimate_wait(8, 4, 700.0)), 3);
+    assert_eq!(limiter(estimate_wait(10, 1, 1000.0)), 11);
+}
+
+#[test]
+fn test_parse_upstream_hint() {
+    assert_eq!(parse_upstream_hint("3"), Some(Duration::from_secs(3)));
+    assert_eq!(parse_upstream_hint(" 1.5 "), Some(Duration::from_millis(1500)));
+    assert_eq!(parse_upstream_hint("-1"), None);
+    assert_eq!(parse_upstream_hint("soon"), None);
+}
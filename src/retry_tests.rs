@@ -0,0 +1,42 @@
+use super::*;
+
+#[test]
+fn test_reason() {
+    assert_eq!(reason(&InvokeError::Throttled("slow down".to_string(), None)), Some(RetryOn::Throttled));
+    assert_eq!(reason(&InvokeError::Service("ServiceException".to_string())), Some(RetryOn::ServiceError));
+    assert_eq!(reason(&InvokeError::Conflict("updating".to_string())), None);
+    assert_eq!(reason(&InvokeError::AccessDenied("denied".to_string())), None);
+    assert_eq!(reason(&InvokeError::Other("timed out".to_string())), None);
+}
+
+#[test]
+fn test_non_idempotent_methods_need_opt_in() {
+    let config = InvokeRetryConfig::default();
+    for method in [Method::GET, Method::HEAD, Method::PUT, Method::DELETE, Method::OPTIONS] {
+        assert!(allows(&config, &method), "{}", method);
+    }
+    assert!(!allows(&config, &Method::POST));
+    assert!(!allows(&config, &Method::PATCH));
+    let config = InvokeRetryConfig {
+        retry_non_idempotent: true,
+        ..Default::default()
+    };
+    assert!(allows(&config, &Method::POST) && allows(&config, &Method::PATCH));
+}
+
+#[test]
+fn test_backoff_doubles_up_to_max_with_jitter() {
+    let config = InvokeRetryConfig {
+        base_delay_ms: 50,
+        max_delay_ms: 300,
+        ..Default::default()
+    };
+    let caps: Vec<u128> = (1..=5).map(|retry| delay_cap(&config, retry).as_millis()).collect();
+    assert_eq!(caps, [50, 100, 200, 300, 300]);
+    assert_eq!(delay_cap(&config, 200).as_millis(), 300);
+
+    let delays: Vec<Duration> = (0..100).map(|_| backoff(&config, 2)).collect();
+    assert!(delays.iter().all(|delay| *delay <= Duration::from_millis(100)));
+    // Drawn at random rather than always the cap
+    assert!(delays.iter().any(|delay| *delay < Duration::from_millis(90)));
+}
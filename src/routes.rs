@@ -0,0 +1,216 @@
+use crate::config::{match_pattern, path_pattern, pattern_rank, Config, UnmatchedRoute};
+use crate::hosts;
+use serde::Serialize;
+use std::fmt;
+
+/// Routes answered by the gateway itself. They are registered ahead of the catch-all route, so
+/// they win over any target matching the same path, whatever the method.
+pub const BUILTIN_ROUTES: &[(&str, &str)] = &[
+    ("/healthz", "liveness check"),
+    ("/readyz", "readiness report"),
+    ("/status", "gateway status"),
+    ("/metrics", "Prometheus metrics"),
+    ("/support-bundle", "support bundle"),
+    ("/admin/events", "lifecycle event list"),
+    ("/admin/flags", "feature flag list"),
+    ("/admin/flags/:name", "feature flag override"),
+    ("/admin/dry-run", "target permission check"),
+    ("/admin/signed-urls", "signed URL minting"),
+    ("/admin/namespaces/:name/reload", "namespace reload"),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// Part of a definition is shadowed; the gateway still starts.
+    Warning,
+    /// A definition can never be reached; the config is rejected.
+    Error,
+}
+
+/// Two route definitions competing for the same paths.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RouteConflict {
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// One line of the route table, in the order definitions are tried.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RouteEntry {
+    pub path: String,
+    pub served_by: String,
+}
+
+/// Every route the gateway serves, built-in and configured, with the conflicts between them.
+/// Backs config validation and `--print-routes`.
+#[derive(Clone, Debug, Default)]
+pub struct RouteRegistry {
+    pub routes: Vec<RouteEntry>,
+    pub conflicts: Vec<RouteConflict>,
+}
+
+impl RouteRegistry {
+    pub fn new(config: &Config) -> Self {
+        let mut registry = Self::default();
+        for (path, description) in BUILTIN_ROUTES {
+            registry.route(path, format!("built-in {}", description));
+        }
+
+        // Targets for the most specific hosts first, as `match_route_on_host` tries them
+        let mut hosts: Vec<Option<&str>> = config.targets.values().map(|target| target.host.as_deref()).collect();
+        hosts.sort_by_key(|host| std::cmp::Reverse((host.is_some(), host.map(hosts::specificity), *host)));
+        hosts.dedup();
+        for host in hosts {
+            let on_host = |served_by: String| match host {
+                Some(host) => format!("host {}: {}", host, served_by),
+                None => served_by,
+            };
+            if let Some(root_target) = &config.root_target {
+                if let Some(target) = config.targets.get(root_target).filter(|t| t.host.as_deref() == host) {
+                    let served_by = format!("target {} -> {}", root_target, config.function_name(target));
+                    registry.route("/", on_host(served_by));
+                }
+            }
+
+            // Exact patterns first, then patterns in the order `match_route` prefers them. Targets
+            // keyed without a '/' are only reached through a `match`
+            let (exact, mut patterns): (Vec<_>, Vec<_>) = config
+                .targets
+                .iter()
+                .filter(|(_, target)| target.host.as_deref() == host)
+                .filter_map(|(key, target)| Some((key, path_pattern(key)?, target)))
+                .partition(|(_, pattern, _)| !pattern.contains("/:") && !pattern.contains("/*"));
+            patterns.sort_by_key(|(_, pattern, _)| std::cmp::Reverse(pattern_rank(pattern)));
+            for (key, _, target) in exact.iter().chain(&patterns) {
+                let served_by = match &target.body_match {
+                    Some(body_match) => {
+                        let conditions = body_match.conditions.iter().map(|rule| rule.target.as_str());
+                        let rules = body_match.body_json.iter().map(|rule| rule.target.as_str());
+                        let mut names: Vec<_> = conditions.chain(rules).collect();
+                        names.dedup();
+                        let on = if body_match.conditions.is_empty() {
+                            "body"
+                        } else {
+                            "request"
+                        };
+                        format!("match {} -> {}, else {}", on, names.join(", "), body_match.fallback)
+                    }
+                    None => format!("target -> {}", config.function_name(target)),
+                };
+                registry.route(key, on_host(served_by));
+            }
+        }
+        match config.unmatched {
+            UnmatchedRoute::Fallback => {
+                registry.route("/*", format!("top-level function -> {}", config.lambda_function_name))
+            }
+            UnmatchedRoute::NotFound => registry.route("/*", "404 no_route".to_string()),
+        }
+
+        // Built-in routes answer every host
+        for (key, pattern) in config.targets.keys().filter_map(|key| Some((key, path_pattern(key)?))) {
+            for (path, description) in BUILTIN_ROUTES {
+                if pattern == *path {
+                    registry.conflict(
+                        Severity::Error,
+                        format!(
+                            "target {} is unreachable: the built-in {} answers {}",
+                            key, description, path
+                        ),
+                    );
+                } else if match_pattern(pattern, path).is_some() {
+                    registry.conflict(
+                        Severity::Warning,
+                        format!(
+                            "target {} does not receive {}: the built-in {} answers it",
+                            key, path, description
+                        ),
+                    );
+                }
+            }
+        }
+
+        // BTreeMap order puts `a` before `b`, so on a full tie `match_route` picks `b`, except for
+        // equal exact patterns under different keys, where it picks the first
+        let keyed: Vec<_> = config
+            .targets
+            .iter()
+            .filter_map(|(key, target)| Some((key, path_pattern(key)?, target.host.as_deref())))
+            .collect();
+        for (i, (a, a_pattern, a_host)) in keyed.iter().enumerate() {
+            for (b, b_pattern, b_host) in keyed.iter().skip(i + 1) {
+                if a_host != b_host || pattern_shape(a_pattern) != pattern_shape(b_pattern) {
+                    continue;
+                }
+                let exact = !a_pattern.contains("/:") && !a_pattern.contains("/*");
+                let (winner, loser) = if exact || pattern_rank(a_pattern) > pattern_rank(b_pattern) {
+                    (a, b)
+                } else {
+                    (b, a)
+                };
+                registry.conflict(
+                    Severity::Error,
+                    format!(
+                        "target {} is unreachable: target {} matches the same paths and wins",
+                        loser, winner
+                    ),
+                );
+            }
+        }
+        registry
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &RouteConflict> {
+        self.conflicts.iter().filter(|c| c.severity == Severity::Error)
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &RouteConflict> {
+        self.conflicts.iter().filter(|c| c.severity == Severity::Warning)
+    }
+
+    fn route(&mut self, path: &str, served_by: String) {
+        self.routes.push(RouteEntry {
+            path: path.to_string(),
+            served_by,
+        });
+    }
+
+    fn conflict(&mut self, severity: Severity, message: String) {
+        self.conflicts.push(RouteConflict { severity, message });
+    }
+}
+
+impl fmt::Display for RouteRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self.routes.iter().map(|r| r.path.len()).max().unwrap_or(0);
+        for route in &self.routes {
+            writeln!(f, "{:width$}  {}", route.path, route.served_by, width = width)?;
+        }
+        for conflict in &self.conflicts {
+            let label = match conflict.severity {
+                Severity::Warning => "warning",
+                Severity::Error => "error",
+            };
+            writeln!(f, "{}: {}", label, conflict.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// A pattern with its capture names erased; patterns of the same shape match the same paths.
+fn pattern_shape(pattern: &str) -> Vec<&str> {
+    pattern
+        .split('/')
+        .map(|part| match part.chars().next() {
+            Some(':') => ":",
+            Some('*') => "*",
+            _ => part,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    include!("routes_tests.rs");
+}
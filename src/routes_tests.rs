@@ -0,0 +1,149 @@
+use super::*;
+use crate::config::Target;
+use crate::config::{BodyJsonRule, BodyMatch, Condition, ConditionRule};
+
+fn config_with(patterns: &[&str]) -> Config {
+    let mut config = Config {
+        lambda_function_name: "default-function".to_string(),
+        ..Default::default()
+    };
+    for pattern in patterns {
+        config.targets.insert(pattern.to_string(), Target::default());
+    }
+    config
+}
+
+#[test]
+fn test_target_on_builtin_path_is_rejected() {
+    let config = config_with(&["/status"]);
+    let registry = RouteRegistry::new(&config);
+
+    let errors: Vec<_> = registry.errors().map(|e| e.message.as_str()).collect();
+    assert_eq!(
+        errors,
+        vec!["target /status is unreachable: the built-in gateway status answers /status"]
+    );
+    assert!(config.validate().unwrap_err().contains("target /status is unreachable"));
+}
+
+#[test]
+fn test_wildcard_target_shadowed_by_status_path_warns() {
+    let config = config_with(&["/*rest", "/users/:id"]);
+    let registry = RouteRegistry::new(&config);
+
+    assert_eq!(registry.errors().count(), 0);
+    let warnings: Vec<_> = registry.warnings().map(|w| w.message.as_str()).collect();
+    assert_eq!(warnings.len(), BUILTIN_ROUTES.len());
+    assert!(warnings.contains(&"target /*rest does not receive /status: the built-in gateway status answers it"));
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn test_patterns_of_same_shape_are_rejected() {
+    let registry = RouteRegistry::new(&config_with(&["/users/:id", "/users/:name", "/orders/:a", "/orders/:b"]));
+
+    let errors: Vec<_> = registry.errors().map(|e| e.message.as_str()).collect();
+    assert_eq!(
+        errors,
+        vec![
+            "target /orders/:a is unreachable: target /orders/:b matches the same paths and wins",
+            "target /users/:id is unreachable: target /users/:name matches the same paths and wins",
+        ]
+    );
+}
+
+#[test]
+fn test_route_table_in_precedence_order() {
+    let mut config = config_with(&["/*rest", "/home", "/users/:id"]);
+    config.root_target = Some("/home".to_string());
+    let table = RouteRegistry::new(&config).to_string();
+
+    let paths: Vec<_> = table
+        .lines()
+        .filter(|line| line.starts_with('/'))
+        .map(|line| line.split_whitespace().next().unwrap())
+        .collect();
+    assert_eq!(
+        paths,
+        vec![
+            "/healthz",
+            "/readyz",
+            "/status",
+            "/metrics",
+            "/support-bundle",
+            "/admin/events",
+            "/admin/flags",
+            "/admin/flags/:name",
+            "/admin/dry-run",
+            "/admin/signed-urls",
+            "/admin/namespaces/:name/reload",
+            "/",
+            "/home",
+            "/users/:id",
+            "/*rest",
+            "/*"
+        ]
+    );
+    assert!(table.contains("/*                  top-level function -> default-function"), "{}", table);
+    assert!(table.contains("warning: target /*rest does not receive /healthz"), "{}", table);
+}
+
+#[test]
+fn test_route_table_body_match() {
+    let mut config = config_with(&["/events", "orders", "events"]);
+    config.targets.get_mut("/events").unwrap().body_match = Some(BodyMatch {
+        body_json: vec![BodyJsonRule {
+            pointer: "/type".to_string(),
+            equals: Some(serde_json::json!("order.created")),
+            regex: None,
+            target: "orders".to_string(),
+        }],
+        fallback: "events".to_string(),
+        ..Default::default()
+    });
+    let registry = RouteRegistry::new(&config);
+    // Targets keyed without any '/' are only reached through the match
+    let paths: Vec<_> = registry.routes.iter().map(|r| r.path.as_str()).collect();
+    assert!(!paths.contains(&"orders") && !paths.contains(&"events"), "{:?}", paths);
+    let table = registry.to_string();
+    assert!(table.contains("/events             match body -> orders, else events"), "{}", table);
+
+    let body_match = config.targets.get_mut("/events").unwrap().body_match.as_mut().unwrap();
+    body_match.conditions.push(ConditionRule {
+        when: Condition::new(r#"header("x-tier") == "gold""#),
+        target: "priority".to_string(),
+    });
+    let table = RouteRegistry::new(&config).to_string();
+    assert!(table.contains("/events             match request -> priority, orders, else events"), "{}", table);
+}
+
+#[test]
+fn test_route_table_groups_targets_by_host() {
+    let mut config = config_with(&["/*rest"]);
+    let on_host = |host: &str| Target {
+        host: Some(host.to_string()),
+        ..Default::default()
+    };
+    config.targets.insert("foo/*rest".to_string(), on_host("api.foo.com"));
+    config.targets.insert("bar/*rest".to_string(), on_host("*.bar.com"));
+    config.targets.insert("bar/*path".to_string(), on_host("*.bar.com"));
+    let registry = RouteRegistry::new(&config);
+
+    let paths: Vec<_> = registry.routes.iter().map(|r| r.path.as_str()).skip(BUILTIN_ROUTES.len()).collect();
+    assert_eq!(paths, ["foo/*rest", "bar/*path", "bar/*rest", "/*rest", "/*"]);
+    assert!(registry.to_string().contains("host api.foo.com: target -> default-function"));
+    // The same pattern on different hosts is fine, on the same host it is not
+    let errors: Vec<_> = registry.errors().map(|e| e.message.as_str()).collect();
+    assert_eq!(
+        errors,
+        ["target bar/*path is unreachable: target bar/*rest matches the same paths and wins"]
+    );
+}
+
+#[test]
+fn test_route_table_unmatched_not_found() {
+    let mut config = config_with(&["/api/*rest"]);
+    config.unmatched = UnmatchedRoute::NotFound;
+    let table = RouteRegistry::new(&config).to_string();
+    assert!(table.contains("/*                  404 no_route"), "{}", table);
+}
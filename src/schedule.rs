@@ -0,0 +1,120 @@
+use crate::config::{RetryAfterConfig, Schedule, ScheduleAction, ScheduleWindow};
+use crate::error::{ErrorPhase, GatewayError};
+use crate::retry_after::RetryAfterHints;
+use axum::http::StatusCode;
+use chrono::{DateTime, Datelike, Duration, LocalResult, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::Serialize;
+
+/// Days of window occurrences considered around now. A week ahead is enough to find the next
+/// change of any weekly schedule; the day before covers windows crossing midnight into today.
+const HORIZON_DAYS: i64 = 8;
+
+/// Whether a target's schedule lets requests through at some instant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct ScheduleState {
+    pub open: bool,
+    /// When `open` next changes, or `None` if it does not within a week.
+    pub until: Option<DateTime<Utc>>,
+}
+
+/// Evaluates `schedule` at `now`. Windows are taken in their own time zone, so they follow daylight
+/// saving time: a window starting at a local time skipped by a transition starts at the end of the
+/// gap, and one at a repeated local time starts at its first occurrence.
+pub fn evaluate(schedule: &Schedule, now: DateTime<Utc>) -> ScheduleState {
+    let intervals = merge(
+        schedule
+            .windows
+            .iter()
+            .flat_map(|window| occurrences(window, window.timezone.unwrap_or(schedule.timezone), now))
+            .collect(),
+    );
+    let current = intervals.iter().find(|(start, end)| *start <= now && now < *end);
+    let in_window = current.is_some();
+    let change = match current {
+        Some((_, end)) => Some(*end),
+        None => intervals.iter().map(|(start, _)| *start).find(|start| *start > now),
+    };
+    ScheduleState {
+        open: match schedule.action {
+            ScheduleAction::Allow => in_window,
+            ScheduleAction::Deny => !in_window,
+        },
+        until: change.filter(|change| *change <= now + Duration::days(HORIZON_DAYS - 1)),
+    }
+}
+
+/// Rejects a request with 503 while `schedule` is closed. `Retry-After` points at the reopening,
+/// within the configured maximum.
+pub fn check(schedule: &Schedule, now: DateTime<Utc>, retry_after: &RetryAfterConfig) -> Result<(), GatewayError> {
+    let state = evaluate(schedule, now);
+    if state.open {
+        return Ok(());
+    }
+    let reopens_in = state.until.and_then(|until| (until - now).to_std().ok());
+    let message = match state.until {
+        Some(until) => format!("The target is closed by its schedule until {}", until.to_rfc3339()),
+        None => "The target is closed by its schedule".to_string(),
+    };
+    Err(GatewayError::new(
+        ErrorPhase::Ingress,
+        StatusCode::SERVICE_UNAVAILABLE,
+        "outside_schedule",
+        message,
+    )
+    .with_retry_after(RetryAfterHints::schedule(reopens_in).resolve(retry_after.default_secs, retry_after)))
+}
+
+/// Instants covered by `window` on the days around `now`, as half-open intervals.
+fn occurrences(window: &ScheduleWindow, tz: Tz, now: DateTime<Utc>) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let today = now.with_timezone(&tz).date_naive();
+    (-1..=HORIZON_DAYS)
+        .filter_map(|offset| today.checked_add_signed(Duration::days(offset)))
+        .filter(|date| window.days.contains(&date.weekday()))
+        .map(|date| {
+            let end_date = if window.end > window.start {
+                date
+            } else {
+                date + Duration::days(1)
+            };
+            (
+                local_instant(tz, date, window.start.minutes),
+                local_instant(tz, end_date, window.end.minutes),
+            )
+        })
+        .collect()
+}
+
+/// The instant `minutes` past midnight of `date` in `tz`.
+fn local_instant(tz: Tz, date: NaiveDate, minutes: u32) -> DateTime<Utc> {
+    let mut local = date.and_hms_opt(0, 0, 0).unwrap() + Duration::minutes(minutes.into());
+    // Gaps are at most a few hours; the first minute after one exists
+    for _ in 0..24 * 60 {
+        match tz.from_local_datetime(&local) {
+            LocalResult::Single(instant) | LocalResult::Ambiguous(instant, _) => {
+                return instant.with_timezone(&Utc);
+            }
+            LocalResult::None => local += Duration::minutes(1),
+        }
+    }
+    Utc.from_utc_datetime(&local)
+}
+
+/// Sorts intervals and joins overlapping or adjacent ones.
+fn merge(mut intervals: Vec<(DateTime<Utc>, DateTime<Utc>)>) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    intervals.retain(|(start, end)| start < end);
+    intervals.sort();
+    let mut merged: Vec<(DateTime<Utc>, DateTime<Utc>)> = Vec::with_capacity(intervals.len());
+    for (start, end) in intervals {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    include!("schedule_tests.rs");
+}
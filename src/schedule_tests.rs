@@ -0,0 +1,164 @@
+use super::*;
+use chrono::Weekday::*;
+
+fn at(s: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+}
+
+fn window(days: &[chrono::Weekday], start: &str, end: &str) -> ScheduleWindow {
+    ScheduleWindow {
+        days: days.to_vec(),
+        start: start.parse().unwrap(),
+        end: end.parse().unwrap(),
+        timezone: None,
+    }
+}
+
+fn schedule(action: ScheduleAction, timezone: Tz, windows: Vec<ScheduleWindow>) -> Schedule {
+    Schedule {
+        action,
+        timezone,
+        windows,
+    }
+}
+
+fn schedule_with(timezone: Tz, window: ScheduleWindow) -> Schedule {
+    schedule(ScheduleAction::Allow, timezone, vec![window])
+}
+
+fn state(open: bool, until: &str) -> ScheduleState {
+    ScheduleState {
+        open,
+        until: Some(at(until)),
+    }
+}
+
+fn business_hours() -> Schedule {
+    let weekdays = [Mon, Tue, Wed, Thu, Fri];
+    schedule(ScheduleAction::Allow, chrono_tz::UTC, vec![window(&weekdays, "09:00", "17:00")])
+}
+
+#[test]
+fn test_allow_window() {
+    let schedule = business_hours();
+    // 2026-10-14 is a Wednesday
+    assert_eq!(evaluate(&schedule, at("2026-10-14T10:00:00Z")), state(true, "2026-10-14T17:00:00Z"));
+    assert_eq!(evaluate(&schedule, at("2026-10-14T09:00:00Z")), state(true, "2026-10-14T17:00:00Z"));
+    assert_eq!(evaluate(&schedule, at("2026-10-14T17:00:00Z")), state(false, "2026-10-15T09:00:00Z"));
+    assert_eq!(evaluate(&schedule, at("2026-10-14T06:00:00Z")), state(false, "2026-10-14T09:00:00Z"));
+    // Closed over the weekend
+    assert_eq!(evaluate(&schedule, at("2026-10-16T18:00:00Z")), state(false, "2026-10-19T09:00:00Z"));
+}
+
+#[test]
+fn test_deny_window() {
+    let schedule = schedule(ScheduleAction::Deny, chrono_tz::UTC, vec![window(&[Sun], "02:00", "04:00")]);
+    assert_eq!(evaluate(&schedule, at("2026-10-18T03:00:00Z")), state(false, "2026-10-18T04:00:00Z"));
+    assert_eq!(evaluate(&schedule, at("2026-10-18T04:00:00Z")), state(true, "2026-10-25T02:00:00Z"));
+    assert_eq!(evaluate(&schedule, at("2026-10-14T12:00:00Z")), state(true, "2026-10-18T02:00:00Z"));
+}
+
+#[test]
+fn test_window_crossing_midnight() {
+    let schedule = schedule(ScheduleAction::Allow, chrono_tz::UTC, vec![window(&[Fri], "22:00", "06:00")]);
+    assert_eq!(evaluate(&schedule, at("2026-10-16T23:00:00Z")), state(true, "2026-10-17T06:00:00Z"));
+    // Still open after midnight, although Saturday is not one of the days
+    assert_eq!(evaluate(&schedule, at("2026-10-17T03:00:00Z")), state(true, "2026-10-17T06:00:00Z"));
+    assert_eq!(evaluate(&schedule, at("2026-10-17T06:00:00Z")), state(false, "2026-10-23T22:00:00Z"));
+}
+
+#[test]
+fn test_adjacent_and_overlapping_windows_merge() {
+    let windows = vec![
+        window(&[Wed], "09:00", "12:00"),
+        window(&[Wed], "12:00", "15:00"),
+        window(&[Wed], "14:00", "18:00"),
+    ];
+    let schedule = schedule(ScheduleAction::Allow, chrono_tz::UTC, windows);
+    assert_eq!(evaluate(&schedule, at("2026-10-14T10:00:00Z")), state(true, "2026-10-14T18:00:00Z"));
+}
+
+#[test]
+fn test_no_change_within_a_week() {
+    let every_day = [Mon, Tue, Wed, Thu, Fri, Sat, Sun];
+    let schedule = schedule(ScheduleAction::Allow, chrono_tz::UTC, vec![window(&every_day, "00:00", "24:00")]);
+    let expected = ScheduleState { open: true, until: None };
+    assert_eq!(evaluate(&schedule, at("2026-10-14T10:00:00Z")), expected);
+}
+
+#[test]
+fn test_window_timezones() {
+    let mut schedule = business_hours();
+    schedule.timezone = chrono_tz::America::New_York;
+    // 09:00 EDT is 13:00 UTC
+    assert_eq!(evaluate(&schedule, at("2026-10-14T12:00:00Z")), state(false, "2026-10-14T13:00:00Z"));
+    schedule.windows[0].timezone = Some(chrono_tz::Asia::Tokyo);
+    // 09:00 JST on Thursday is 00:00 UTC
+    assert_eq!(evaluate(&schedule, at("2026-10-14T12:00:00Z")), state(false, "2026-10-15T00:00:00Z"));
+}
+
+#[test]
+fn test_spring_forward() {
+    // Berlin skips from 02:00 CET to 03:00 CEST on 2026-03-29
+    let berlin = chrono_tz::Europe::Berlin;
+    let schedule = schedule(ScheduleAction::Deny, berlin, vec![window(&[Sun], "02:30", "05:00")]);
+    // 02:30 does not exist, so the window starts when the clocks go forward
+    assert_eq!(evaluate(&schedule, at("2026-03-29T00:30:00Z")), state(true, "2026-03-29T01:00:00Z"));
+    assert_eq!(evaluate(&schedule, at("2026-03-29T01:00:00Z")), state(false, "2026-03-29T03:00:00Z"));
+
+    // Business hours move an hour earlier in UTC across the weekend
+    let mut hours = business_hours();
+    hours.timezone = berlin;
+    assert_eq!(evaluate(&hours, at("2026-03-27T08:30:00Z")), state(true, "2026-03-27T16:00:00Z"));
+    assert_eq!(evaluate(&hours, at("2026-03-28T12:00:00Z")), state(false, "2026-03-30T07:00:00Z"));
+}
+
+#[test]
+fn test_window_inside_the_gap_never_opens() {
+    let berlin = chrono_tz::Europe::Berlin;
+    let schedule = schedule(ScheduleAction::Deny, berlin, vec![window(&[Sun], "02:15", "02:45")]);
+    assert_eq!(evaluate(&schedule, at("2026-03-29T01:00:00Z")), state(true, "2026-04-05T00:15:00Z"));
+}
+
+#[test]
+fn test_fall_back() {
+    // Berlin repeats 02:00 to 03:00 on 2026-10-25, first in CEST, then in CET
+    let berlin = chrono_tz::Europe::Berlin;
+    let schedule = schedule(ScheduleAction::Deny, berlin, vec![window(&[Sun], "02:30", "04:00")]);
+    // The window starts at the first 02:30 and ends at 04:00 CET, two and a half hours later
+    assert_eq!(evaluate(&schedule, at("2026-10-25T00:15:00Z")), state(true, "2026-10-25T00:30:00Z"));
+    assert_eq!(evaluate(&schedule, at("2026-10-25T01:45:00Z")), state(false, "2026-10-25T03:00:00Z"));
+
+    // An overnight window lasts an hour longer
+    let schedule = schedule_with(berlin, window(&[Sat], "22:00", "06:00"));
+    assert_eq!(evaluate(&schedule, at("2026-10-24T20:00:00Z")), state(true, "2026-10-25T05:00:00Z"));
+}
+
+#[test]
+fn test_time_of_day() {
+    let time = |s: &str| s.parse::<crate::config::TimeOfDay>().map(|t| t.minutes);
+    assert_eq!(time("00:00"), Ok(0));
+    assert_eq!(time("9:05"), Ok(545));
+    assert_eq!(time("24:00"), Ok(1440));
+    for invalid in ["24:01", "12:60", "12", "noon", "-1:00", ""] {
+        assert!(time(invalid).is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn test_schedule_from_yaml() {
+    let yaml = "
+action: deny
+timezone: Europe/Berlin
+windows:
+  - days: [sat, Sunday]
+    start: '22:00'
+    end: '06:00'
+    timezone: UTC
+";
+    let parsed: Schedule = serde_yaml::from_str(yaml).unwrap();
+    let mut expected = schedule_with(chrono_tz::Europe::Berlin, window(&[Sat, Sun], "22:00", "06:00"));
+    expected.action = ScheduleAction::Deny;
+    expected.windows[0].timezone = Some(chrono_tz::UTC);
+    assert_eq!(parsed, expected);
+}
@@ -0,0 +1,614 @@
+use crate::config::{BindAddr, ObsFold, ServerConfig};
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::Router;
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::watch;
+use tower::ServiceExt;
+
+/// Added by the listener to a request whose headers were folded while `obs_fold` is `reject`, so
+/// the router can answer it with a JSON error. Copies sent by clients are removed.
+pub const OBS_FOLD_HEADER: &str = "x-gateway-obs-fold";
+
+/// Longest chunk size or trailer line followed in a chunked body before giving up on tracking
+/// the connection.
+const MAX_CHUNK_LINE: usize = 8 * 1024;
+
+/// A bound listener: a TCP socket, or a Unix domain socket.
+pub enum Listener {
+    Tcp(TcpListener),
+    /// Its socket file is removed when [`serve`] stops.
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+/// An accepted connection, with the client's address over TCP. Unix socket clients have none.
+enum Accepted {
+    Tcp(tokio::net::TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+impl Listener {
+    async fn accept(&self) -> io::Result<Accepted> {
+        match self {
+            Listener::Tcp(listener) => listener
+                .accept()
+                .await
+                .map(|(stream, remote)| Accepted::Tcp(stream, remote)),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => listener.accept().await.map(|(stream, _)| Accepted::Unix(stream)),
+        }
+    }
+}
+
+/// Binds a listener to `addr`: a TCP one as [`bind_tcp`] does, or a Unix socket as
+/// [`bind_unix`] does.
+pub async fn bind(addr: &BindAddr, config: &ServerConfig) -> io::Result<Listener> {
+    match addr {
+        BindAddr::Tcp(addr) => bind_tcp(addr, config).await.map(Listener::Tcp),
+        #[cfg(unix)]
+        BindAddr::Unix(path) => {
+            bind_unix(path, config.socket_mode.0).map(|listener| Listener::Unix(listener, path.clone()))
+        }
+        #[cfg(not(unix))]
+        BindAddr::Unix(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} needs Unix sockets", addr),
+        )),
+    }
+}
+
+/// Binds a listener to `addr`, a `host:port` with IPv6 addresses in brackets such as `[::]:8000`.
+/// IPv6 sockets accept IPv4 clients with `dual_stack` only, whatever the platform's default.
+pub async fn bind_tcp(addr: &str, config: &ServerConfig) -> io::Result<TcpListener> {
+    let mut last_error = None;
+    for addr in tokio::net::lookup_host(addr).await? {
+        match bind_socket(addr, config.dual_stack) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} has no address", addr))))
+}
+
+fn bind_socket(addr: SocketAddr, dual_stack: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    // As TcpListener::bind does, so a restart can bind while old connections linger
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Binds a Unix domain socket at `path` with the permissions `mode`. A socket file left behind by
+/// a process that did not stop gracefully is replaced; one still accepting connections is not.
+#[cfg(unix)]
+pub fn bind_unix(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        Ok(_) => match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is in use by another process", path.display()),
+                ));
+            }
+            Err(_) => {
+                tracing::info!("Removing the stale socket {}", path.display());
+                std::fs::remove_file(path)?;
+            }
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// How the connections of a listener are read.
+#[derive(Clone)]
+pub enum Transport {
+    Plain,
+    /// Behind a TLS handshake with the current certificate.
+    #[cfg(feature = "tls")]
+    Tls(Arc<crate::tls::Certificates>),
+}
+
+/// Errors ending a connection, from hyper or from setting it up.
+type ConnectionError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Serves HTTP/1.1, and with `http2` h2c, on `listener` until `shutdown` resolves, then waits for
+/// open connections to finish their requests. Connections are served by hyper directly, so the
+/// request head limit applies and folded headers are handled before hyper parses them.
+pub async fn serve(
+    listener: impl Into<Listener>,
+    app: Router,
+    config: &ServerConfig,
+    transport: Transport,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) {
+    let listener = listener.into();
+    let builder = connection_builder(config);
+    let keepalive = (config.keepalive_secs > 0)
+        .then(|| socket2::TcpKeepalive::new().with_time(std::time::Duration::from_secs(config.keepalive_secs)));
+    let (stop_tx, stop_rx) = watch::channel(());
+    let (open_tx, open_rx) = watch::channel(());
+    tokio::pin!(shutdown);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Usually out of file descriptors; give connections time to close
+                    tracing::warn!("Failed to accept a connection: {}", e);
+                    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        let mut connection = Connection {
+            remote: None,
+            app: app.clone(),
+            scanner: HeadScanner::new(config.obs_fold, config.max_header_bytes),
+            builder: builder.clone(),
+            stop: stop_rx.clone(),
+        };
+        if let (Accepted::Tcp(stream, remote), Some(keepalive)) = (&accepted, &keepalive) {
+            if let Err(e) = socket2::SockRef::from(stream).set_tcp_keepalive(keepalive) {
+                tracing::debug!("Failed to set TCP keep-alive for {}: {}", remote, e);
+            }
+        }
+        let (transport, open) = (transport.clone(), open_rx.clone());
+        tokio::spawn(async move {
+            let result = match accepted {
+                Accepted::Tcp(stream, remote) => {
+                    // IPv4 clients of a dual-stack socket arrive as ::ffff:a.b.c.d; everything past
+                    // here, from logs to network allowlists, sees them as the IPv4 clients they are
+                    connection.remote = Some(SocketAddr::new(remote.ip().to_canonical(), remote.port()));
+                    connection.run(stream, transport).await
+                }
+                #[cfg(unix)]
+                Accepted::Unix(stream) => connection.run(stream, transport).await,
+            };
+            if let Err((peer, e)) = result {
+                tracing::debug!("Connection from {} failed: {}", peer, e);
+            }
+            drop(open);
+        });
+    }
+    #[cfg(unix)]
+    if let Listener::Unix(_, path) = &listener {
+        if let Err(e) = std::fs::remove_file(path) {
+            tracing::warn!("Failed to remove the socket {}: {}", path.display(), e);
+        }
+    }
+    drop(listener);
+    drop(open_rx);
+    let _ = stop_tx.send(());
+    open_tx.closed().await;
+}
+
+/// The hyper connection settings of `config`: HTTP/1.1 only unless `http2` is set, in which case
+/// a connection starting with the HTTP/2 preface is served as HTTP/2.
+fn connection_builder(config: &ServerConfig) -> auto::Builder<TokioExecutor> {
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder.http1().max_buf_size(config.max_header_bytes);
+    builder
+        .http2()
+        .max_concurrent_streams(config.max_concurrent_streams)
+        .max_header_list_size(u32::try_from(config.max_header_bytes).unwrap_or(u32::MAX));
+    if config.http2 {
+        builder
+    } else {
+        builder.http1_only()
+    }
+}
+
+/// An accepted connection and what serving it needs.
+struct Connection {
+    /// The client's address, given to requests as [`ConnectInfo`]; `None` over Unix sockets.
+    remote: Option<SocketAddr>,
+    app: Router,
+    scanner: HeadScanner,
+    builder: auto::Builder<TokioExecutor>,
+    stop: watch::Receiver<()>,
+}
+
+impl Connection {
+    /// Sets up `transport` on `stream`, then serves its requests. Errors name the client.
+    async fn run<S>(self, stream: S, transport: Transport) -> Result<(), (String, ConnectionError)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let peer = self
+            .remote
+            .map_or_else(|| "a Unix socket client".to_string(), |remote| remote.to_string());
+        let result = match transport {
+            Transport::Plain => self.serve(stream).await,
+            #[cfg(feature = "tls")]
+            Transport::Tls(certificates) => match certificates.accept(stream).await {
+                Ok(stream) => self.serve(stream).await,
+                Err(e) => {
+                    tracing::debug!("TLS handshake with {} failed: {}", peer, e);
+                    Ok(())
+                }
+            },
+        };
+        result.map_err(|e| (peer, e))
+    }
+
+    /// Serves the requests of `stream` until the client closes it, or the listener stops and the
+    /// request in progress is answered.
+    async fn serve<S>(self, stream: S) -> Result<(), ConnectionError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let Self {
+            remote,
+            app,
+            scanner,
+            builder,
+            mut stop,
+        } = self;
+        let io = TokioIo::new(ScannedStream::new(stream, scanner));
+        let service = hyper::service::service_fn(move |mut request: hyper::Request<Incoming>| {
+            if let Some(remote) = remote {
+                request.extensions_mut().insert(ConnectInfo(remote));
+            }
+            app.clone().oneshot(request.map(Body::new))
+        });
+        // Response bodies are written as they are produced, so streams flush each chunk over either
+        // protocol
+        let connection = builder.serve_connection_with_upgrades(io, service);
+        tokio::pin!(connection);
+        tokio::select! {
+            result = connection.as_mut() => result,
+            _ = stop.changed() => {
+                connection.as_mut().graceful_shutdown();
+                connection.await
+            }
+        }
+    }
+}
+
+/// Passes the bytes of a connection through a [`HeadScanner`] on their way to hyper.
+struct ScannedStream<S> {
+    inner: S,
+    scanner: HeadScanner,
+    scanned: Vec<u8>,
+    position: usize,
+    read_buf: Box<[u8]>,
+}
+
+impl<S> ScannedStream<S> {
+    fn new(inner: S, scanner: HeadScanner) -> Self {
+        Self {
+            inner,
+            scanner,
+            scanned: Vec::new(),
+            position: 0,
+            read_buf: vec![0; 8 * 1024].into_boxed_slice(),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ScannedStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.position < this.scanned.len() {
+                let n = buf.remaining().min(this.scanned.len() - this.position);
+                buf.put_slice(&this.scanned[this.position..this.position + n]);
+                this.position += n;
+                return Poll::Ready(Ok(()));
+            }
+            let mut read = ReadBuf::new(&mut this.read_buf);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            this.scanned.clear();
+            this.position = 0;
+            this.scanner.push(read.filled(), &mut this.scanned);
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ScannedStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+/// Follows the HTTP/1.1 requests of a connection and rewrites request heads with folded header
+/// lines (obs-fold), which hyper would otherwise reject without a body. Bodies are passed on
+/// untouched; their framing is tracked only to find where the next head starts. Anything it cannot
+/// follow, such as upgraded connections or an oversized head, is passed on as is from then on.
+pub(crate) struct HeadScanner {
+    obs_fold: ObsFold,
+    max_head: usize,
+    phase: Phase,
+    head: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Phase {
+    Head,
+    /// Body bytes left of a request with a `Content-Length`.
+    Length(u64),
+    Chunked(Chunk),
+    Passthrough,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Chunk {
+    Size(Vec<u8>),
+    /// Data bytes left in the chunk, including the CRLF after them.
+    Data(u64),
+    Trailer(Vec<u8>),
+    /// The empty line after the trailers was read.
+    End,
+    /// The framing could not be followed.
+    Lost,
+}
+
+impl HeadScanner {
+    pub(crate) fn new(obs_fold: ObsFold, max_head: usize) -> Self {
+        Self {
+            obs_fold,
+            max_head,
+            phase: Phase::Head,
+            head: Vec::new(),
+        }
+    }
+
+    /// Passes `input` on to `out`, rewriting request heads as needed. Bytes of an incomplete head
+    /// are held back until the rest of it arrives.
+    pub(crate) fn push(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        let mut data = input.to_vec();
+        let mut position = 0;
+        while position < data.len() {
+            let input = &data[position..];
+            match &mut self.phase {
+                Phase::Passthrough => {
+                    out.extend_from_slice(input);
+                    return;
+                }
+                Phase::Length(remaining) => {
+                    let n = input.len().min(usize::try_from(*remaining).unwrap_or(usize::MAX));
+                    out.extend_from_slice(&input[..n]);
+                    *remaining -= n as u64;
+                    if *remaining == 0 {
+                        self.phase = Phase::Head;
+                    }
+                    position += n;
+                }
+                Phase::Chunked(chunk) => {
+                    let n = chunk_bytes(chunk, input);
+                    out.extend_from_slice(&input[..n]);
+                    position += n;
+                    match chunk {
+                        Chunk::End => self.phase = Phase::Head,
+                        Chunk::Lost => self.phase = Phase::Passthrough,
+                        _ => {}
+                    }
+                }
+                Phase::Head => {
+                    let searched = self.head.len().saturating_sub(2);
+                    self.head.extend_from_slice(input);
+                    let Some(end) = head_end(&self.head, searched) else {
+                        if self.head.len() > self.max_head {
+                            // Too large for hyper, which answers 431 itself
+                            out.append(&mut self.head);
+                            self.phase = Phase::Passthrough;
+                        }
+                        return;
+                    };
+                    data = self.head.split_off(end);
+                    position = 0;
+                    let head = std::mem::take(&mut self.head);
+                    self.phase = self.finish_head(&head, out);
+                }
+            }
+        }
+    }
+
+    /// Writes a complete request head to `out` and returns how its body is framed.
+    fn finish_head(&self, head: &[u8], out: &mut Vec<u8>) -> Phase {
+        if head.iter().all(u8::is_ascii_whitespace) {
+            // Empty lines ahead of a request line
+            out.extend_from_slice(head);
+            return Phase::Head;
+        }
+        if head.starts_with(b"PRI * HTTP/2.0") {
+            out.extend_from_slice(head);
+            return Phase::Passthrough;
+        }
+        let mut lines = head
+            .split(|b| *b == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+            .skip_while(|line| line.is_empty());
+        let request_line = lines.next().unwrap_or_default();
+        let mut fields: Vec<Vec<u8>> = Vec::new();
+        let mut folded = false;
+        let mut rewritten = false;
+        for line in lines.filter(|line| !line.is_empty()) {
+            match fields.last_mut() {
+                Some(field) if line[0] == b' ' || line[0] == b'\t' => {
+                    while field.last().is_some_and(|b| *b == b' ' || *b == b'\t') {
+                        field.pop();
+                    }
+                    field.push(b' ');
+                    field.extend_from_slice(line.trim_ascii_start());
+                    folded = true;
+                }
+                _ if field_is(line, OBS_FOLD_HEADER) => rewritten = true,
+                _ => fields.push(line.to_vec()),
+            }
+        }
+
+        if folded || rewritten {
+            out.extend_from_slice(request_line);
+            out.extend_from_slice(b"\r\n");
+            for field in &fields {
+                out.extend_from_slice(field);
+                out.extend_from_slice(b"\r\n");
+            }
+            if folded && self.obs_fold == ObsFold::Reject {
+                out.extend_from_slice(OBS_FOLD_HEADER.as_bytes());
+                out.extend_from_slice(b": 1\r\n");
+            }
+            out.extend_from_slice(b"\r\n");
+        } else {
+            out.extend_from_slice(head);
+        }
+
+        let value = |name: &'static str| fields.iter().filter(|f| field_is(f, name)).map(|f| field_value(f));
+        let upgrade = request_line.starts_with(b"CONNECT ") || value("upgrade").next().is_some();
+        let chunked = value("transfer-encoding").any(|v| v.to_ascii_lowercase().contains("chunked"));
+        let length = value("content-length")
+            .next()
+            .and_then(|v| v.trim().parse::<u64>().ok());
+        if upgrade {
+            Phase::Passthrough
+        } else if chunked {
+            Phase::Chunked(Chunk::Size(Vec::new()))
+        } else {
+            match length {
+                Some(length) if length > 0 => Phase::Length(length),
+                _ => Phase::Head,
+            }
+        }
+    }
+}
+
+/// End of the request head in `head`, looking from `from` on: just past the empty line.
+fn head_end(head: &[u8], from: usize) -> Option<usize> {
+    (from..head.len()).find_map(|i| {
+        if head[i] != b'\n' {
+            return None;
+        }
+        match head.get(i + 1..) {
+            Some([b'\n', ..]) => Some(i + 2),
+            Some([b'\r', b'\n', ..]) => Some(i + 3),
+            _ => None,
+        }
+    })
+}
+
+/// Advances through a chunked body, returning how many bytes of `input` belong to it.
+fn chunk_bytes(chunk: &mut Chunk, input: &[u8]) -> usize {
+    let mut position = 0;
+    while position < input.len() {
+        match chunk {
+            Chunk::Data(remaining) => {
+                let n = (input.len() - position).min(usize::try_from(*remaining).unwrap_or(usize::MAX));
+                *remaining -= n as u64;
+                position += n;
+                if *remaining == 0 {
+                    *chunk = Chunk::Size(Vec::new());
+                }
+            }
+            Chunk::Size(line) | Chunk::Trailer(line) => {
+                let byte = input[position];
+                line.push(byte);
+                position += 1;
+                if line.len() > MAX_CHUNK_LINE {
+                    *chunk = Chunk::Lost;
+                } else if byte == b'\n' {
+                    *chunk = match chunk {
+                        Chunk::Size(line) => chunk_size(line),
+                        Chunk::Trailer(line) if line.trim_ascii().is_empty() => Chunk::End,
+                        _ => Chunk::Trailer(Vec::new()),
+                    };
+                }
+            }
+            Chunk::End | Chunk::Lost => break,
+        }
+    }
+    position
+}
+
+/// What follows a chunk size line.
+fn chunk_size(line: &[u8]) -> Chunk {
+    let line = String::from_utf8_lossy(line);
+    let size = line.split(';').next().unwrap_or_default().trim();
+    match u64::from_str_radix(size, 16) {
+        Ok(0) => Chunk::Trailer(Vec::new()),
+        // Data is followed by a CRLF
+        Ok(size) => Chunk::Data(size.saturating_add(2)),
+        // Malformed; hyper fails the request
+        Err(_) => Chunk::Lost,
+    }
+}
+
+fn field_is(field: &[u8], name: &str) -> bool {
+    field.len() > name.len() && field[name.len()] == b':' && field[..name.len()].eq_ignore_ascii_case(name.as_bytes())
+}
+
+fn field_value(field: &[u8]) -> String {
+    let value = field.splitn(2, |b| *b == b':').nth(1).unwrap_or_default();
+    String::from_utf8_lossy(value).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    include!("server_tests.rs");
+}
@@ -0,0 +1,239 @@
+use super::*;
+
+fn scan(obs_fold: ObsFold, reads: &[&[u8]]) -> String {
+    let mut scanner = HeadScanner::new(obs_fold, 1024);
+    let mut out = Vec::new();
+    for read in reads {
+        scanner.push(read, &mut out);
+    }
+    String::from_utf8(out).unwrap()
+}
+
+const FOLDED: &str = "GET / HTTP/1.1\r\nHost: a\r\nX-Long: one \r\n  two\r\n\tthree\r\n\r\n";
+
+#[test]
+fn test_unfolded_requests_pass_through_unchanged() {
+    let requests = "GET / HTTP/1.1\r\nHost: a\r\n\r\nPOST /b HTTP/1.1\nContent-Length: 3\n\nabcGET /c HTTP/1.1\r\n\r\n";
+    assert_eq!(scan(ObsFold::Reject, &[requests.as_bytes()]), requests);
+}
+
+#[test]
+fn test_folded_headers_are_marked_or_joined() {
+    assert_eq!(
+        scan(ObsFold::Reject, &[FOLDED.as_bytes()]),
+        "GET / HTTP/1.1\r\nHost: a\r\nX-Long: one two three\r\nx-gateway-obs-fold: 1\r\n\r\n"
+    );
+    assert_eq!(
+        scan(ObsFold::Normalize, &[FOLDED.as_bytes()]),
+        "GET / HTTP/1.1\r\nHost: a\r\nX-Long: one two three\r\n\r\n"
+    );
+}
+
+#[test]
+fn test_client_sent_marker_is_removed() {
+    let request = "GET / HTTP/1.1\r\nX-Gateway-Obs-Fold: 1\r\nHost: a\r\n\r\n";
+    assert_eq!(scan(ObsFold::Reject, &[request.as_bytes()]), "GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+}
+
+#[test]
+fn test_heads_split_across_reads() {
+    let reads: Vec<&[u8]> = FOLDED.as_bytes().chunks(1).collect();
+    assert_eq!(scan(ObsFold::Normalize, &reads), scan(ObsFold::Normalize, &[FOLDED.as_bytes()]));
+    // Nothing is passed on before the head is complete
+    assert_eq!(scan(ObsFold::Normalize, &reads[..reads.len() - 1]), "");
+}
+
+#[test]
+fn test_bodies_are_not_mistaken_for_heads() {
+    let body = "X: a\r\n b\r\n\r\n";
+    let length = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+    let chunked = format!(
+        "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{:x};ext=1\r\n{}\r\n0\r\nTrailer: x\r\n\r\n",
+        body.len(),
+        body
+    );
+    for request in [length, chunked] {
+        let pipelined = format!("{}{}", request, FOLDED);
+        let reads: Vec<&[u8]> = pipelined.as_bytes().chunks(5).collect();
+        assert_eq!(
+            scan(ObsFold::Normalize, &reads),
+            format!("{}GET / HTTP/1.1\r\nHost: a\r\nX-Long: one two three\r\n\r\n", request)
+        );
+    }
+}
+
+#[test]
+fn test_untracked_connections_pass_through() {
+    let upgrade = format!("GET / HTTP/1.1\r\nUpgrade: websocket\r\n\r\n{}", FOLDED);
+    assert_eq!(scan(ObsFold::Reject, &[upgrade.as_bytes()]), upgrade);
+    let bad_chunk = format!("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n{}", FOLDED);
+    assert_eq!(scan(ObsFold::Reject, &[bad_chunk.as_bytes()]), bad_chunk);
+
+    // A head over the limit is left to hyper, which answers 431
+    let oversized = format!("GET / HTTP/1.1\r\nX-Big: {}\r\n", "a".repeat(2000));
+    assert_eq!(scan(ObsFold::Reject, &[oversized.as_bytes(), b" folded\r\n\r\n"]), oversized + " folded\r\n\r\n");
+}
+
+/// Serves the client address each request came from on `listener`.
+fn serve_client_addresses(listener: TcpListener, config: ServerConfig) {
+    let app = Router::new().route(
+        "/",
+        axum::routing::get(|ConnectInfo(addr): ConnectInfo<SocketAddr>| async move { addr.ip().to_string() }),
+    );
+    tokio::spawn(async move { serve(listener, app, &config, Transport::Plain, std::future::pending()).await });
+}
+
+async fn client_address(addr: SocketAddr) -> io::Result<String> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut stream = tokio::net::TcpStream::connect(addr).await?;
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")
+        .await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    Ok(response.rsplit("\r\n\r\n").next().unwrap_or_default().to_string())
+}
+
+#[tokio::test]
+async fn test_dual_stack_listener_sees_ipv4_clients_as_ipv4() {
+    let config = ServerConfig {
+        dual_stack: true,
+        ..Default::default()
+    };
+    let listener = bind_tcp("[::]:0", &config).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    serve_client_addresses(listener, config);
+
+    let ipv4 = client_address(SocketAddr::from(([127, 0, 0, 1], port))).await.unwrap();
+    assert_eq!(ipv4, "127.0.0.1");
+    let ipv6 = client_address(SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, port))).await.unwrap();
+    assert_eq!(ipv6, "::1");
+}
+
+#[tokio::test]
+async fn test_ipv6_listener_is_ipv6_only_by_default() {
+    let config = ServerConfig::default();
+    let listener = bind_tcp("[::]:0", &config).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    serve_client_addresses(listener, config);
+
+    assert!(client_address(SocketAddr::from(([127, 0, 0, 1], port))).await.is_err());
+    let ipv6 = client_address(SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, port))).await.unwrap();
+    assert_eq!(ipv6, "::1");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unix_socket_listener() {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("gateway.sock");
+    let addr = BindAddr::Unix(path.clone());
+    // Left behind by a process that did not stop gracefully
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    let config = ServerConfig {
+        socket_mode: crate::config::FileMode(0o600),
+        ..Default::default()
+    };
+    let listener = bind(&addr, &config).await.unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    // A socket still accepting connections is left alone
+    let in_use = bind(&addr, &config).await.err().unwrap();
+    assert_eq!(in_use.kind(), io::ErrorKind::AddrInUse);
+
+    let app = Router::new().route(
+        "/",
+        axum::routing::get(|connect_info: Option<ConnectInfo<SocketAddr>>| async move {
+            connect_info.map_or_else(|| "no peer".to_string(), |ConnectInfo(addr)| addr.to_string())
+        }),
+    );
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(async move {
+        let stop = async {
+            let _ = stop_rx.await;
+        };
+        serve(listener, app, &config, Transport::Plain, stop).await
+    });
+
+    let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nno peer"), "{}", response);
+
+    // A graceful shutdown removes the socket file
+    stop_tx.send(()).unwrap();
+    server.await.unwrap();
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn test_h2c_streams_each_chunk_as_it_is_produced() {
+    use axum::body::Bytes;
+    use http_body_util::BodyExt;
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let config = ServerConfig {
+        http2: true,
+        keepalive_secs: 30,
+        ..Default::default()
+    };
+    let (chunk_tx, chunk_rx) = tokio::sync::mpsc::channel::<&'static str>(1);
+    let chunk_rx = std::sync::Arc::new(Mutex::new(Some(chunk_rx)));
+    let app = Router::new().route(
+        "/events",
+        axum::routing::get(move || async move {
+            let chunks = chunk_rx.lock().unwrap().take().unwrap();
+            Body::from_stream(futures::stream::unfold(chunks, |mut chunks| async move {
+                let chunk = chunks.recv().await?;
+                Some((Ok::<_, io::Error>(Bytes::from_static(chunk.as_bytes())), chunks))
+            }))
+        }),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { serve(listener, app, &config, Transport::Plain, std::future::pending()).await });
+
+    let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let (mut sender, connection) = hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(tcp))
+        .await
+        .unwrap();
+    tokio::spawn(connection);
+    let request = hyper::Request::get("http://localhost/events")
+        .body(http_body_util::Empty::<Bytes>::new())
+        .unwrap();
+    let response = sender.send_request(request).await.unwrap();
+    assert_eq!((response.status(), response.version()), (200, hyper::Version::HTTP_2));
+
+    // Each chunk reaches the client before the next one is produced
+    let mut body = response.into_body();
+    for chunk in ["data: one\n\n", "data: two\n\n"] {
+        chunk_tx.send(chunk).await.unwrap();
+        let frame = tokio::time::timeout(std::time::Duration::from_secs(5), body.frame())
+            .await
+            .expect("chunk not flushed")
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame.into_data().unwrap(), chunk);
+    }
+    drop(chunk_tx);
+    assert!(body.frame().await.is_none());
+
+    // HTTP/1.1 clients are still served on the same listener
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"GET /missing HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+}
@@ -0,0 +1,386 @@
+use crate::config::{ShutdownConfig, ShutdownPhase};
+use crate::lifecycle::LifecycleEvent;
+use crate::telemetry::Telemetry;
+use crate::ApplicationState;
+use axum::body::{Body, Bytes};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use http_body::Frame;
+use serde::Serialize;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// How often a drain phase logs what it is still waiting on.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether a request in flight is still waiting on its function or sending a buffered response,
+/// or streaming its response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InFlightKind {
+    Buffered,
+    Stream,
+}
+
+impl InFlightKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            InFlightKind::Buffered => "buffered",
+            InFlightKind::Stream => "stream",
+        }
+    }
+}
+
+/// Requests in flight by kind.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct InFlightCounts {
+    pub buffered: usize,
+    pub streams: usize,
+}
+
+impl InFlightCounts {
+    fn of(&self, kind: InFlightKind) -> usize {
+        match kind {
+            InFlightKind::Buffered => self.buffered,
+            InFlightKind::Stream => self.streams,
+        }
+    }
+
+    fn of_mut(&mut self, kind: InFlightKind) -> &mut usize {
+        match kind {
+            InFlightKind::Buffered => &mut self.buffered,
+            InFlightKind::Stream => &mut self.streams,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct State {
+    counts: InFlightCounts,
+    unready: bool,
+    refusing: bool,
+    abort_buffered: bool,
+    abort_streams: bool,
+}
+
+impl State {
+    fn aborts(&self, kind: InFlightKind) -> bool {
+        match kind {
+            InFlightKind::Buffered => self.abort_buffered,
+            InFlightKind::Stream => self.abort_streams,
+        }
+    }
+}
+
+/// Counts proxied requests in flight for the `requests_in_flight` gauge and drives the phases of
+/// a shutdown from the same counts: readiness, refusing new requests and aborting the requests
+/// still in flight at a drain deadline.
+#[derive(Clone)]
+pub struct InFlight {
+    state: Arc<watch::Sender<State>>,
+    telemetry: Telemetry,
+}
+
+impl InFlight {
+    pub fn new(telemetry: Telemetry) -> Self {
+        Self {
+            state: Arc::new(watch::channel(State::default()).0),
+            telemetry,
+        }
+    }
+
+    /// Counts a new request as buffered until [`InFlightGuard::set_streaming`].
+    pub fn enter(&self) -> InFlightGuard {
+        self.adjust(InFlightKind::Buffered, 1);
+        InFlightGuard {
+            in_flight: self.clone(),
+            kind: InFlightKind::Buffered,
+        }
+    }
+
+    pub fn counts(&self) -> InFlightCounts {
+        self.state.borrow().counts
+    }
+
+    /// False once shutdown has begun, so load balancers stop sending requests.
+    pub fn is_ready(&self) -> bool {
+        !self.state.borrow().unready
+    }
+
+    /// True once shutdown refuses new requests.
+    pub fn is_refusing(&self) -> bool {
+        self.state.borrow().refusing
+    }
+
+    fn adjust(&self, kind: InFlightKind, delta: i64) {
+        self.state.send_modify(|state| {
+            let count = state.counts.of_mut(kind);
+            *count = count.saturating_add_signed(delta as isize);
+        });
+        self.telemetry
+            .gauge("requests_in_flight", vec![("kind", kind.as_str().to_string())], delta);
+    }
+
+    /// Waits up to `timeout` for the requests of `kind` to finish, then aborts the rest and
+    /// returns how many were aborted.
+    async fn drain(&self, phase: ShutdownPhase, kind: InFlightKind, timeout: Duration) -> usize {
+        let deadline = tokio::time::sleep(timeout);
+        let mut progress = tokio::time::interval(PROGRESS_INTERVAL);
+        let mut state = self.state.subscribe();
+        tokio::pin!(deadline);
+        loop {
+            let counts = state.borrow_and_update().counts;
+            if counts.of(kind) == 0 {
+                return 0;
+            }
+            tokio::select! {
+                _ = progress.tick() => tracing::info!(
+                    phase = phase.as_str(),
+                    buffered_requests = counts.buffered,
+                    active_streams = counts.streams,
+                    "Waiting for requests in flight"
+                ),
+                _ = state.changed() => {}
+                _ = &mut deadline => {
+                    let mut aborted = 0;
+                    self.state.send_modify(|state| {
+                        aborted = state.counts.of(kind);
+                        match kind {
+                            InFlightKind::Buffered => state.abort_buffered = true,
+                            InFlightKind::Stream => state.abort_streams = true,
+                        }
+                    });
+                    return aborted;
+                }
+            }
+        }
+    }
+}
+
+/// A request counted in [`InFlight`] until dropped.
+pub struct InFlightGuard {
+    in_flight: InFlight,
+    kind: InFlightKind,
+}
+
+impl InFlightGuard {
+    /// Counts the request as a stream from now on.
+    pub fn set_streaming(&mut self) {
+        if self.kind == InFlightKind::Stream {
+            return;
+        }
+        self.in_flight.adjust(self.kind, -1);
+        self.kind = InFlightKind::Stream;
+        self.in_flight.adjust(self.kind, 1);
+    }
+
+    /// Resolves once shutdown aborts requests of this request's kind.
+    pub fn aborted(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut state = self.in_flight.state.subscribe();
+        let kind = self.kind;
+        async move {
+            // The sender lives as long as this guard's `InFlight`
+            let _ = state.wait_for(|state| state.aborts(kind)).await;
+        }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.in_flight.adjust(self.kind, -1);
+    }
+}
+
+/// A response body counted in flight until sent or dropped, and cut short with an error when
+/// shutdown aborts it.
+pub struct InFlightBody {
+    inner: Body,
+    aborted: Option<BoxFuture<'static, ()>>,
+    _guard: InFlightGuard,
+}
+
+impl InFlightBody {
+    pub fn new(inner: Body, guard: InFlightGuard) -> Self {
+        Self {
+            inner,
+            aborted: Some(guard.aborted().boxed()),
+            _guard: guard,
+        }
+    }
+}
+
+impl http_body::Body for InFlightBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let Some(aborted) = &mut self.aborted else {
+            return Poll::Ready(None);
+        };
+        if aborted.poll_unpin(cx).is_ready() {
+            self.aborted = None;
+            return Poll::Ready(Some(Err(axum::Error::new(ShutdownAborted))));
+        }
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.aborted.is_none() || self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Error ending a response that was still in flight at the deadline of its shutdown phase.
+#[derive(Debug)]
+pub struct ShutdownAborted;
+
+impl std::fmt::Display for ShutdownAborted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("response aborted: the gateway is shutting down")
+    }
+}
+
+impl std::error::Error for ShutdownAborted {}
+
+/// Starts a shutdown from code, as SIGTERM does, for programs embedding the gateway with
+/// [`crate::run_app_with_shutdown`]. Clones share the same shutdown.
+#[derive(Clone, Debug)]
+pub struct Shutdown(Arc<watch::Sender<bool>>);
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self(Arc::new(watch::channel(false).0))
+    }
+
+    /// Starts the shutdown; later calls do nothing.
+    pub fn trigger(&self) {
+        self.0.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once [`Shutdown::trigger`] has been called.
+    pub async fn triggered(&self) {
+        let mut triggered = self.0.subscribe();
+        // The sender lives as long as `self`
+        let _ = triggered.wait_for(|triggered| *triggered).await;
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How one shutdown phase went.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PhaseOutcome {
+    pub phase: ShutdownPhase,
+    pub elapsed_ms: u64,
+    /// False when the phase ran into its deadline.
+    pub completed: bool,
+}
+
+/// Outcome of a shutdown: its phases in the order they ran and the requests force-aborted.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ShutdownReport {
+    pub phases: Vec<PhaseOutcome>,
+    pub aborted: InFlightCounts,
+}
+
+/// Runs the phases of `config` in order. `refuse_new` stops the listener.
+pub async fn run(state: &ApplicationState, config: &ShutdownConfig, refuse_new: impl FnOnce()) -> ShutdownReport {
+    let in_flight = &state.in_flight;
+    let mut refuse_new = Some(refuse_new);
+    let mut report = ShutdownReport::default();
+    for &phase in &config.phases {
+        let started_at = Instant::now();
+        let counts = in_flight.counts();
+        tracing::info!(
+            phase = phase.as_str(),
+            buffered_requests = counts.buffered,
+            active_streams = counts.streams,
+            "Shutdown phase started"
+        );
+        state.lifecycle.publish(LifecycleEvent::ShutdownPhaseStarted { phase });
+        let completed = match phase {
+            ShutdownPhase::Unready => {
+                in_flight.state.send_modify(|state| state.unready = true);
+                tokio::time::sleep(Duration::from_millis(config.unready_ms)).await;
+                true
+            }
+            ShutdownPhase::RefuseNew => {
+                in_flight.state.send_modify(|state| state.refusing = true);
+                if let Some(refuse_new) = refuse_new.take() {
+                    refuse_new();
+                }
+                true
+            }
+            ShutdownPhase::DrainBuffered => {
+                let timeout = Duration::from_millis(config.drain_buffered_ms);
+                let aborted = in_flight.drain(phase, InFlightKind::Buffered, timeout).await;
+                report.aborted.buffered += aborted;
+                aborted == 0
+            }
+            ShutdownPhase::DrainStreams => {
+                let timeout = Duration::from_millis(config.drain_streams_ms);
+                let aborted = in_flight.drain(phase, InFlightKind::Stream, timeout).await;
+                report.aborted.streams += aborted;
+                aborted == 0
+            }
+            ShutdownPhase::FlushState => {
+                tracing::info!(
+                    phase = phase.as_str(),
+                    checkpoint = state.checkpointer.is_enabled(),
+                    pending_event_retries = state.event_queue.depth(),
+                    "Flushing state"
+                );
+                state
+                    .checkpointer
+                    .flush(Duration::from_millis(config.flush_state_ms))
+                    .await
+            }
+        };
+        let outcome = PhaseOutcome {
+            phase,
+            elapsed_ms: started_at.elapsed().as_millis() as u64,
+            completed,
+        };
+        tracing::info!(
+            phase = phase.as_str(),
+            elapsed_ms = outcome.elapsed_ms,
+            completed,
+            "Shutdown phase finished"
+        );
+        state.lifecycle.publish(LifecycleEvent::ShutdownPhaseFinished {
+            phase,
+            elapsed_ms: outcome.elapsed_ms,
+            completed,
+        });
+        report.phases.push(outcome);
+    }
+    if report.aborted == InFlightCounts::default() {
+        tracing::info!("Shutdown complete, nothing was aborted");
+    } else {
+        tracing::warn!(
+            aborted_buffered_requests = report.aborted.buffered,
+            aborted_streams = report.aborted.streams,
+            "Shutdown complete, requests still in flight at their deadline were aborted"
+        );
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    include!("shutdown_tests.rs");
+}
@@ -0,0 +1,168 @@
+use super::*;
+use crate::config::{Config, LambdaInvokeMode, Target};
+use crate::mock::{test_state_with, MockInvoker};
+use crate::streaming::PayloadStream;
+use axum::http::{Request, StatusCode};
+use futures::StreamExt;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tower::ServiceExt;
+
+const PRELUDE: &[u8] = b"{\"statusCode\": 200}\0\0\0\0\0\0\0\0";
+
+/// A streamed response with a prelude, then `body` after `delay_ms`.
+fn stream(delay_ms: u64, body: &'static [u8]) -> PayloadStream {
+    futures::stream::iter([(0, PRELUDE), (delay_ms, body)])
+        .then(|(delay_ms, chunk)| async move {
+            tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+            Ok(Bytes::from_static(chunk))
+        })
+        .boxed()
+}
+
+fn phases_config(phases: Vec<ShutdownPhase>) -> ShutdownConfig {
+    ShutdownConfig {
+        phases,
+        unready_ms: 100,
+        drain_buffered_ms: 100,
+        drain_streams_ms: 200,
+        flush_state_ms: 100,
+    }
+}
+
+async fn get(app: axum::Router, uri: &str) -> axum::response::Response {
+    app.oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_phases_drain_then_abort_what_is_left() {
+    let invoker = MockInvoker::with_streams(
+        vec![
+            Ok(stream(50, b"done")),
+            Ok(stream(10_000, b"too late")),
+            Ok(stream(10_000, b"too late")),
+        ],
+        Duration::ZERO,
+    );
+    let config = Config {
+        lambda_invoke_mode: LambdaInvokeMode::ResponseStream,
+        targets: BTreeMap::from([
+            ("/stream/:id".to_string(), Target::default()),
+            (
+                // Waits for the whole stream, so it counts as buffered until then
+                "/buffered".to_string(),
+                Target {
+                    buffer_stream: true,
+                    ..Default::default()
+                },
+            ),
+        ]),
+        ..Default::default()
+    };
+    let state = test_state_with(config, invoker);
+    let app = crate::build_router(state.clone());
+
+    let mut bodies = Vec::new();
+    for uri in ["/stream/short", "/stream/long"] {
+        let response = get(app.clone(), uri).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        bodies.push(tokio::spawn(axum::body::to_bytes(response.into_body(), usize::MAX)));
+    }
+    let buffered = tokio::spawn(get(app.clone(), "/buffered"));
+    while state.in_flight.counts().buffered == 0 {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    assert_eq!(state.in_flight.counts(), InFlightCounts { buffered: 1, streams: 2 });
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let key = ("requests_in_flight", vec![("kind", "stream".to_string())]);
+    assert_eq!(state.telemetry.snapshot().gauges[&key], 2);
+
+    let refused = Arc::new(AtomicBool::new(false));
+    let config = phases_config(ShutdownConfig::default().phases);
+    let shutdown = tokio::spawn({
+        let (state, refused) = (state.clone(), refused.clone());
+        async move { run(&state, &config, move || refused.store(true, Ordering::SeqCst)).await }
+    });
+
+    // Requests are still served while unready, but readiness fails
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert!(!refused.load(Ordering::SeqCst));
+    let response = get(app.clone(), "/readyz").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let readiness: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(readiness["shutting_down"], true);
+    // Liveness fails too, so load balancers checking /healthz stop sending requests
+    assert_eq!(get(app.clone(), "/healthz").await.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let report = shutdown.await.unwrap();
+    assert!(refused.load(Ordering::SeqCst));
+    let phases: Vec<_> = report.phases.iter().map(|p| (p.phase, p.completed)).collect();
+    assert_eq!(
+        phases,
+        [
+            (ShutdownPhase::Unready, true),
+            (ShutdownPhase::RefuseNew, true),
+            (ShutdownPhase::DrainBuffered, false),
+            (ShutdownPhase::DrainStreams, false),
+            (ShutdownPhase::FlushState, true),
+        ]
+    );
+    assert!(report.phases[0].elapsed_ms >= 100);
+    assert!(report.phases[3].elapsed_ms >= 200);
+    assert_eq!(report.aborted, InFlightCounts { buffered: 1, streams: 1 });
+
+    // The short stream finished while draining, the long one was cut off
+    let mut bodies = bodies.into_iter();
+    assert_eq!(bodies.next().unwrap().await.unwrap().unwrap(), "done");
+    assert!(bodies.next().unwrap().await.unwrap().is_err());
+    let response = buffered.await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(state.in_flight.counts(), InFlightCounts::default());
+
+    let response = get(app, "/stream/late").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn test_phases_run_in_configured_order() {
+    let state = test_state_with(Config::default(), MockInvoker::new(vec![]));
+    let config = phases_config(vec![
+        ShutdownPhase::FlushState,
+        ShutdownPhase::DrainStreams,
+        ShutdownPhase::Unready,
+    ]);
+    let report = run(&state, &config, || panic!("refuse_new is not a configured phase")).await;
+
+    let phases: Vec<_> = report.phases.iter().map(|p| (p.phase, p.completed)).collect();
+    assert_eq!(
+        phases,
+        [
+            (ShutdownPhase::FlushState, true),
+            (ShutdownPhase::DrainStreams, true),
+            (ShutdownPhase::Unready, true),
+        ]
+    );
+    // Nothing was in flight, so draining did not wait for its deadline
+    assert!(report.phases[1].elapsed_ms < 200);
+    assert_eq!(report.aborted, InFlightCounts::default());
+    assert!(!state.in_flight.is_ready());
+    assert!(!state.in_flight.is_refusing());
+}
+
+#[tokio::test]
+async fn test_shutdown_handle_is_shared_by_clones() {
+    let shutdown = Shutdown::new();
+    let waiter = tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move { shutdown.triggered().await }
+    });
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert!(!waiter.is_finished() && !shutdown.is_triggered());
+
+    shutdown.clone().trigger();
+    tokio::time::timeout(Duration::from_millis(100), waiter).await.unwrap().unwrap();
+    assert!(shutdown.is_triggered());
+    // Waiting after the fact resolves at once
+    tokio::time::timeout(Duration::from_millis(100), shutdown.triggered()).await.unwrap();
+}
@@ -0,0 +1,251 @@
+use crate::canonical::CanonicalRequest;
+use crate::config::{CanonicalRequestConfig, SignedUrlConfig};
+use crate::error::{ErrorPhase, GatewayError};
+use crate::state_store::StateStore;
+use axum::http::{HeaderMap, Method, StatusCode, Uri};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// Prefix of the query parameters the gateway adds to the URLs it signs. They are removed before
+/// the request is forwarded.
+pub const PARAM_PREFIX: &str = "x-gateway-";
+/// Unix time in seconds the URL stops being valid at.
+pub const EXPIRES_PARAM: &str = "x-gateway-expires";
+/// Address of the only client the URL is valid for.
+pub const CLIENT_PARAM: &str = "x-gateway-client";
+/// Number of requests the URL is valid for.
+pub const USES_PARAM: &str = "x-gateway-uses";
+/// Base64url HMAC-SHA256 over the canonical form of the rest of the URL.
+pub const SIGNATURE_PARAM: &str = "x-gateway-signature";
+
+/// What `POST /admin/signed-urls` is asked to sign.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct MintRequest {
+    /// Path to grant access to, with the query string the URL must carry, as in
+    /// `/reports/42?format=pdf`.
+    pub path: String,
+    /// Method the URL is valid for; `GET` when unset.
+    pub method: Option<String>,
+    /// Lifetime of the URL; `default_ttl_secs` when unset.
+    pub ttl_secs: Option<u64>,
+    /// Binds the URL to requests from this address.
+    pub client_ip: Option<IpAddr>,
+    /// Limits the URL to this many requests.
+    pub max_uses: Option<u64>,
+}
+
+/// A URL minted by [`mint`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SignedUrl {
+    /// Path and query string, to be appended to the gateway's address.
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Signs `request` with the key of `config` at `now`. Errors describe a request that cannot be
+/// signed, for a `400` answer.
+pub fn mint(config: &SignedUrlConfig, request: &MintRequest, now: DateTime<Utc>) -> Result<SignedUrl, String> {
+    let method: Method = match &request.method {
+        Some(method) => method.parse().map_err(|_| format!("{:?} is not a method", method))?,
+        None => Method::GET,
+    };
+    let ttl_secs = request.ttl_secs.unwrap_or(config.default_ttl_secs);
+    if ttl_secs == 0 || ttl_secs > config.max_ttl_secs {
+        return Err(format!("ttl_secs must be between 1 and {}", config.max_ttl_secs));
+    }
+    if request.max_uses == Some(0) {
+        return Err("max_uses must be at least 1".to_string());
+    }
+    let uri: Uri = match request.path.parse() {
+        Ok(uri) if request.path.starts_with('/') => uri,
+        _ => {
+            return Err(format!(
+                "{:?} is not a path with an optional query string",
+                request.path
+            ))
+        }
+    };
+    if has_gateway_params(uri.query().unwrap_or_default()) {
+        return Err(format!("the query string must not carry {}* parameters", PARAM_PREFIX));
+    }
+
+    let expires_at = now + chrono::Duration::seconds(ttl_secs as i64);
+    let mut query = uri.query().map(String::from).unwrap_or_default();
+    let mut params = url::form_urlencoded::Serializer::for_suffix(&mut query, 0);
+    params.append_pair(EXPIRES_PARAM, &expires_at.timestamp().to_string());
+    if let Some(client_ip) = request.client_ip {
+        params.append_pair(CLIENT_PARAM, &client_ip.to_string());
+    }
+    if let Some(max_uses) = request.max_uses {
+        params.append_pair(USES_PARAM, &max_uses.to_string());
+    }
+    drop(params);
+    let signature = sign(&config.signing_key, &method, uri.path(), &query);
+    url::form_urlencoded::Serializer::for_suffix(&mut query, 0).append_pair(SIGNATURE_PARAM, &signature);
+    Ok(SignedUrl {
+        url: format!("{}?{}", uri.path(), query),
+        expires_at,
+    })
+}
+
+/// Whether a raw query string carries a signature, so the request is meant to be checked with
+/// [`verify`].
+pub fn is_signed(raw_query: &str) -> bool {
+    url::form_urlencoded::parse(raw_query.as_bytes()).any(|(key, _)| key == SIGNATURE_PARAM)
+}
+
+/// Checks a signed request: its signature, then its expiry, client binding and uses, counted in
+/// `store`. Returns the query string to forward, without the gateway's parameters.
+pub async fn verify(
+    config: &SignedUrlConfig,
+    store: &dyn StateStore,
+    method: &Method,
+    raw_path: &str,
+    raw_query: &str,
+    client_ip: Option<IpAddr>,
+    now: DateTime<Utc>,
+) -> Result<String, GatewayError> {
+    let mut signature = None;
+    let mut expires = None;
+    let mut client = None;
+    let mut uses = None;
+    for (key, value) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+        let slot = match key.as_ref() {
+            SIGNATURE_PARAM => &mut signature,
+            EXPIRES_PARAM => &mut expires,
+            CLIENT_PARAM => &mut client,
+            USES_PARAM => &mut uses,
+            _ => continue,
+        };
+        // A parameter repeated could be read differently by whoever checks it next
+        if slot.replace(value.into_owned()).is_some() {
+            return Err(rejected("invalid_signature", format!("{} is repeated", key)));
+        }
+    }
+    let signature = signature.unwrap_or_default();
+    let input = canonical_input(
+        method,
+        raw_path,
+        &without_params(raw_query, |name| name == SIGNATURE_PARAM),
+    );
+    // The MAC compares in constant time
+    let valid = URL_SAFE_NO_PAD
+        .decode(&signature)
+        .is_ok_and(|signature| mac(&config.signing_key, &input).verify_slice(&signature).is_ok());
+    if !valid {
+        return Err(rejected("invalid_signature", "The URL signature is not valid"));
+    }
+    // Dot segments would let the signature of one path serve a path the router sees differently
+    let dot_segment = raw_path
+        .split('/')
+        .any(|segment| matches!(&percent_decode_str(segment).collect::<Vec<u8>>()[..], b"." | b".."));
+    if dot_segment {
+        return Err(rejected(
+            "invalid_signature",
+            "Signed URLs cannot have . or .. path segments",
+        ));
+    }
+
+    let expires_at = expires
+        .and_then(|expires| expires.parse::<i64>().ok())
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .ok_or_else(|| rejected("invalid_signature", format!("{} is missing or invalid", EXPIRES_PARAM)))?;
+    if expires_at <= now {
+        return Err(rejected(
+            "signed_url_expired",
+            format!("The URL expired at {}", expires_at.to_rfc3339()),
+        ));
+    }
+    // Minted with a lifetime no longer allowed, e.g. before max_ttl_secs was lowered
+    if expires_at > now + chrono::Duration::seconds(config.max_ttl_secs as i64) {
+        return Err(rejected("signed_url_expired", "The URL outlives max_ttl_secs"));
+    }
+    if let Some(client) = client {
+        if client.parse::<IpAddr>().ok() != client_ip {
+            return Err(rejected(
+                "signed_url_client_mismatch",
+                "The URL was signed for another client",
+            ));
+        }
+    }
+    if let Some(uses) = uses {
+        let max_uses: i64 = uses
+            .parse()
+            .map_err(|_| rejected("invalid_signature", format!("{} is invalid", USES_PARAM)))?;
+        let ttl = (expires_at - now).to_std().unwrap_or(Duration::ZERO);
+        let used = store
+            .increment(&format!("signed_url:{}", signature), 1, Some(ttl))
+            .await
+            .map_err(|e| {
+                GatewayError::new(
+                    ErrorPhase::Auth,
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "signed_url_store_unavailable",
+                    format!("The uses of the URL cannot be counted: {}", e),
+                )
+            })?;
+        if used > max_uses {
+            return Err(rejected(
+                "signed_url_used",
+                format!("The URL was valid for {} requests", max_uses),
+            ));
+        }
+    }
+
+    Ok(without_params(raw_query, |name| name.starts_with(PARAM_PREFIX)))
+}
+
+/// The signature of a request for `raw_path` with `raw_query`, which holds every signed parameter
+/// but the signature: a MAC over the [`CanonicalRequest`] without headers or body, so parameter
+/// order and encoding variants do not matter.
+fn sign(key: &str, method: &Method, raw_path: &str, raw_query: &str) -> String {
+    let input = canonical_input(method, raw_path, raw_query);
+    URL_SAFE_NO_PAD.encode(mac(key, &input).finalize().into_bytes())
+}
+
+fn canonical_input(method: &Method, raw_path: &str, raw_query: &str) -> String {
+    let config = CanonicalRequestConfig { headers: Vec::new() };
+    let uri = format!("{}?{}", raw_path, raw_query).parse().unwrap_or_default();
+    CanonicalRequest::new(&config, method, &uri, &HeaderMap::new(), b"").to_string()
+}
+
+fn mac(key: &str, input: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(input.as_bytes());
+    mac
+}
+
+/// A raw query string without the pairs whose decoded name is `dropped`, the others unchanged.
+fn without_params(raw_query: &str, dropped: impl Fn(&str) -> bool) -> String {
+    let kept: Vec<&str> = raw_query
+        .split('&')
+        .filter(|pair| {
+            let name = url::form_urlencoded::parse(pair.as_bytes())
+                .next()
+                .map(|(name, _)| name);
+            !name.is_some_and(|name| dropped(&name))
+        })
+        .collect();
+    kept.join("&")
+}
+
+fn has_gateway_params(raw_query: &str) -> bool {
+    url::form_urlencoded::parse(raw_query.as_bytes()).any(|(key, _)| key.starts_with(PARAM_PREFIX))
+}
+
+fn rejected(code: &'static str, message: impl Into<String>) -> GatewayError {
+    GatewayError::new(ErrorPhase::Auth, StatusCode::FORBIDDEN, code, message)
+}
+
+#[cfg(test)]
+mod tests {
+    include!("signed_url_tests.rs");
+}
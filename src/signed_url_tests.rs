@@ -0,0 +1,203 @@
+use super::*;
+use crate::state_store::MemoryStore;
+
+fn config() -> SignedUrlConfig {
+    SignedUrlConfig {
+        signing_key: "0123456789abcdef0123456789abcdef".to_string(),
+        ..Default::default()
+    }
+}
+
+fn now() -> DateTime<Utc> {
+    DateTime::from_timestamp(1_700_000_000, 0).unwrap()
+}
+
+fn minted(request: MintRequest) -> (String, String) {
+    let url = mint(&config(), &request, now()).unwrap().url;
+    let (path, query) = url.split_once('?').unwrap();
+    (path.to_string(), query.to_string())
+}
+
+fn request(path: &str) -> MintRequest {
+    MintRequest {
+        path: path.to_string(),
+        ..Default::default()
+    }
+}
+
+async fn check(method: Method, path: &str, query: &str, client_ip: Option<IpAddr>) -> Result<String, &'static str> {
+    check_at(&MemoryStore::default(), method, path, query, client_ip, now()).await
+}
+
+async fn check_at(
+    store: &MemoryStore,
+    method: Method,
+    path: &str,
+    query: &str,
+    client_ip: Option<IpAddr>,
+    at: DateTime<Utc>,
+) -> Result<String, &'static str> {
+    verify(&config(), store, &method, path, query, client_ip, at)
+        .await
+        .map_err(|e| e.code)
+}
+
+#[tokio::test]
+async fn test_roundtrip_strips_gateway_params() {
+    let signed = mint(&config(), &request("/reports/42?format=pdf"), now()).unwrap();
+    assert_eq!(signed.expires_at, now() + chrono::Duration::seconds(3600));
+    assert!(signed.url.starts_with("/reports/42?format=pdf&x-gateway-expires=1700003600&"));
+
+    let (path, query) = signed.url.split_once('?').unwrap();
+    assert!(is_signed(query));
+    assert!(!is_signed("format=pdf"));
+    assert_eq!(check(Method::GET, path, query, None).await.as_deref(), Ok("format=pdf"));
+}
+
+#[tokio::test]
+async fn test_expiry() {
+    let (path, query) = minted(MintRequest {
+        ttl_secs: Some(60),
+        ..request("/reports/42")
+    });
+    let store = MemoryStore::default();
+    let at = |secs: i64| now() + chrono::Duration::seconds(secs);
+    assert!(check_at(&store, Method::GET, &path, &query, None, at(59)).await.is_ok());
+    let expired = check_at(&store, Method::GET, &path, &query, None, at(60)).await;
+    assert_eq!(expired, Err("signed_url_expired"));
+}
+
+#[tokio::test]
+async fn test_tampering_is_rejected() {
+    let client_ip: IpAddr = "192.0.2.1".parse().unwrap();
+    let (path, query) = minted(MintRequest {
+        client_ip: Some(client_ip),
+        max_uses: Some(5),
+        ..request("/reports/42?format=pdf")
+    });
+    let ip = Some(client_ip);
+    assert!(check(Method::GET, &path, &query, ip).await.is_ok());
+
+    let tampered = [
+        (Method::GET, "/reports/43".to_string(), query.clone()),
+        (Method::GET, path.clone(), query.replace("format=pdf", "format=csv")),
+        (Method::GET, path.clone(), format!("{}&extra=1", query)),
+        (Method::GET, path.clone(), query.replace("expires=17000036", "expires=17000037")),
+        (Method::GET, path.clone(), query.replace("client=192.0.2.1", "client=192.0.2.2")),
+        (Method::GET, path.clone(), query.replace("uses=5", "uses=50")),
+        (Method::POST, path.clone(), query.clone()),
+        (Method::GET, path.clone(), query.replace("signature=", "signature=A")),
+    ];
+    for (method, path, query) in tampered {
+        let result = check(method.clone(), &path, &query, ip).await;
+        assert_eq!(result, Err("invalid_signature"), "{} {}?{}", method, path, query);
+    }
+
+    let other_key = SignedUrlConfig {
+        signing_key: "fedcba9876543210fedcba9876543210".to_string(),
+        ..config()
+    };
+    let result = verify(&other_key, &MemoryStore::default(), &Method::GET, &path, &query, ip, now()).await;
+    assert_eq!(result.map_err(|e| e.code), Err("invalid_signature"));
+}
+
+#[tokio::test]
+async fn test_repeated_params_are_rejected() {
+    let (path, query) = minted(request("/reports/42"));
+    let repeated = format!("{}&x-gateway-expires=1800000000", query);
+    assert_eq!(check(Method::GET, &path, &repeated, None).await, Err("invalid_signature"));
+}
+
+#[tokio::test]
+async fn test_equivalent_encodings_verify() {
+    let (path, query) = minted(request("/files/caf%C3%A9?b=2&a=x%20y"));
+    // Parameter order and percent-encoding variants are the same request
+    let mut pairs: Vec<&str> = query.split('&').collect();
+    pairs.reverse();
+    let reordered = pairs.join("&").replace("x%20y", "x+y");
+    let result = check(Method::GET, &path.replace("%C3%A9", "%c3%a9"), &reordered, None).await;
+    assert_eq!(result.as_deref(), Ok("a=x+y&b=2"));
+}
+
+#[tokio::test]
+async fn test_client_binding() {
+    let (path, query) = minted(MintRequest {
+        client_ip: Some("2001:db8::1".parse().unwrap()),
+        ..request("/reports/42")
+    });
+    assert!(check(Method::GET, &path, &query, Some("2001:db8::1".parse().unwrap())).await.is_ok());
+    let other = check(Method::GET, &path, &query, Some("2001:db8::2".parse().unwrap())).await;
+    assert_eq!(other, Err("signed_url_client_mismatch"));
+    assert_eq!(check(Method::GET, &path, &query, None).await, Err("signed_url_client_mismatch"));
+}
+
+#[tokio::test]
+async fn test_max_uses() {
+    let (path, query) = minted(MintRequest {
+        max_uses: Some(2),
+        ..request("/reports/42")
+    });
+    let store = MemoryStore::default();
+    for _ in 0..2 {
+        assert!(check_at(&store, Method::GET, &path, &query, None, now()).await.is_ok());
+    }
+    let replayed = check_at(&store, Method::GET, &path, &query, None, now()).await;
+    assert_eq!(replayed, Err("signed_url_used"));
+
+    // Without max_uses, a URL serves any number of requests
+    let (path, query) = minted(request("/reports/42"));
+    for _ in 0..3 {
+        assert!(check_at(&store, Method::GET, &path, &query, None, now()).await.is_ok());
+    }
+}
+
+#[tokio::test]
+async fn test_dot_segments_are_rejected() {
+    // Signed for /public/x, whose canonical form a path through /private/.. shares
+    let (_, query) = minted(request("/public/x"));
+    let result = check(Method::GET, "/private/../public/x", &query, None).await;
+    assert_eq!(result, Err("invalid_signature"));
+    let result = check(Method::GET, "/public/%2e/x", &query, None).await;
+    assert_eq!(result, Err("invalid_signature"));
+}
+
+#[tokio::test]
+async fn test_lowered_max_ttl_rejects_older_urls() {
+    let (path, query) = minted(MintRequest {
+        ttl_secs: Some(7200),
+        ..request("/reports/42")
+    });
+    let lowered = SignedUrlConfig {
+        max_ttl_secs: 3600,
+        ..config()
+    };
+    let result = verify(&lowered, &MemoryStore::default(), &Method::GET, &path, &query, None, now()).await;
+    assert_eq!(result.map_err(|e| e.code), Err("signed_url_expired"));
+}
+
+#[test]
+fn test_mint_rejects_invalid_requests() {
+    let invalid = [
+        MintRequest {
+            ttl_secs: Some(0),
+            ..request("/a")
+        },
+        MintRequest {
+            ttl_secs: Some(86401),
+            ..request("/a")
+        },
+        MintRequest {
+            max_uses: Some(0),
+            ..request("/a")
+        },
+        MintRequest {
+            method: Some("NOT A METHOD".to_string()),
+            ..request("/a")
+        },
+        request("reports/42"),
+        request("/a?x-gateway-expires=1"),
+    ];
+    for request in invalid {
+        assert!(mint(&config(), &request, now()).is_err(), "{:?}", request);
+    }
+}
@@ -0,0 +1,239 @@
+use crate::config::ErrorBudgetConfig;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Response header naming the burn rate alert of a target with `slo_warning_header`.
+pub const SLO_AT_RISK_HEADER: &str = "x-gateway-slo-at-risk";
+
+/// Buckets per window. Recording touches one bucket; reading a window sums all of them.
+const BUCKETS: u64 = 60;
+
+/// A burn rate alert, from the more to the less urgent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BurnAlert {
+    FastBurn,
+    SlowBurn,
+}
+
+impl BurnAlert {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BurnAlert::FastBurn => "fast_burn",
+            BurnAlert::SlowBurn => "slow_burn",
+        }
+    }
+}
+
+/// Traffic of one window, as shown on `/status`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct WindowStatus {
+    pub requests: u64,
+    pub errors: u64,
+    /// How fast the window spends the error budget: its error ratio over the ratio the SLO allows.
+    /// 1 spends exactly the budget over the SLO period.
+    pub burn_rate: f64,
+}
+
+/// Error budget of one target, as shown on `/status`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BudgetStatus {
+    pub slo: f64,
+    pub short_window: WindowStatus,
+    pub long_window: WindowStatus,
+    pub slo_at_risk: bool,
+    pub alert: Option<BurnAlert>,
+}
+
+/// Request and error counts over a sliding window, kept in a ring of fixed-width buckets. Old
+/// traffic falls out one bucket at a time.
+#[derive(Clone, Debug)]
+struct RollingWindow {
+    bucket_width: Duration,
+    buckets: Vec<Bucket>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Bucket {
+    /// Number of the bucket-width interval since the origin, plus one; zero marks an unused slot.
+    id: u64,
+    requests: u64,
+    errors: u64,
+}
+
+impl RollingWindow {
+    fn new(window: Duration) -> Self {
+        Self {
+            bucket_width: (window / BUCKETS as u32).max(Duration::from_millis(1)),
+            buckets: vec![Bucket::default(); BUCKETS as usize],
+        }
+    }
+
+    fn bucket_id(&self, elapsed: Duration) -> u64 {
+        (elapsed.as_nanos() / self.bucket_width.as_nanos()) as u64 + 1
+    }
+
+    fn record(&mut self, elapsed: Duration, error: bool) {
+        let id = self.bucket_id(elapsed);
+        let bucket = &mut self.buckets[(id % BUCKETS) as usize];
+        if bucket.id != id {
+            *bucket = Bucket {
+                id,
+                ..Default::default()
+            };
+        }
+        bucket.requests += 1;
+        bucket.errors += u64::from(error);
+    }
+
+    fn status(&self, elapsed: Duration, slo: f64) -> WindowStatus {
+        let current = self.bucket_id(elapsed);
+        let (requests, errors) = self
+            .buckets
+            .iter()
+            .filter(|bucket| bucket.id != 0 && bucket.id + BUCKETS > current)
+            .fold((0, 0), |(requests, errors), bucket| {
+                (requests + bucket.requests, errors + bucket.errors)
+            });
+        let burn_rate = match requests {
+            0 => 0.0,
+            _ => errors as f64 / requests as f64 / (1.0 - slo / 100.0),
+        };
+        WindowStatus {
+            requests,
+            errors,
+            burn_rate,
+        }
+    }
+}
+
+struct Budget {
+    slo: f64,
+    windows: (u64, u64),
+    short: RollingWindow,
+    long: RollingWindow,
+    alert: Option<BurnAlert>,
+}
+
+impl Budget {
+    fn new(slo: f64, config: &ErrorBudgetConfig) -> Self {
+        Self {
+            slo,
+            windows: (config.short_window_secs, config.long_window_secs),
+            short: RollingWindow::new(Duration::from_secs(config.short_window_secs)),
+            long: RollingWindow::new(Duration::from_secs(config.long_window_secs)),
+            alert: None,
+        }
+    }
+
+    fn status(&self, config: &ErrorBudgetConfig, elapsed: Duration) -> BudgetStatus {
+        let short_window = self.short.status(elapsed, self.slo);
+        let long_window = self.long.status(elapsed, self.slo);
+        let burning = |rate| short_window.burn_rate >= rate && long_window.burn_rate >= rate;
+        let alert = if long_window.requests < config.min_requests {
+            None
+        } else if burning(config.fast_burn_rate) {
+            Some(BurnAlert::FastBurn)
+        } else if burning(config.slow_burn_rate) {
+            Some(BurnAlert::SlowBurn)
+        } else {
+            None
+        };
+        BudgetStatus {
+            slo: self.slo,
+            short_window,
+            long_window,
+            slo_at_risk: alert.is_some(),
+            alert,
+        }
+    }
+}
+
+/// Rolling success ratios of the targets with an `slo`, and the burn rate alerts they raise.
+/// Alerts are logged when they fire, escalate or clear.
+#[derive(Clone)]
+pub struct ErrorBudgets {
+    origin: Instant,
+    budgets: Arc<Mutex<HashMap<String, Budget>>>,
+}
+
+impl Default for ErrorBudgets {
+    fn default() -> Self {
+        Self {
+            origin: Instant::now(),
+            budgets: Arc::default(),
+        }
+    }
+}
+
+impl ErrorBudgets {
+    /// Counts a request to `pattern` at `now` and returns the target's alert, if any.
+    pub fn record(
+        &self,
+        pattern: &str,
+        slo: f64,
+        config: &ErrorBudgetConfig,
+        error: bool,
+        now: Instant,
+    ) -> Option<BurnAlert> {
+        let elapsed = now.saturating_duration_since(self.origin);
+        let mut budgets = self.budgets.lock().unwrap();
+        let budget = budgets
+            .entry(pattern.to_string())
+            .or_insert_with(|| Budget::new(slo, config));
+        if budget.windows != (config.short_window_secs, config.long_window_secs) {
+            // Buckets cannot be resized; the windows start over
+            *budget = Budget::new(slo, config);
+        }
+        budget.slo = slo;
+        budget.short.record(elapsed, error);
+        budget.long.record(elapsed, error);
+
+        let status = budget.status(config, elapsed);
+        if status.alert != budget.alert {
+            let (short, long) = (status.short_window.burn_rate, status.long_window.burn_rate);
+            match status.alert {
+                Some(alert) => tracing::warn!(
+                    pattern,
+                    slo,
+                    alert = alert.as_str(),
+                    short_burn_rate = short,
+                    long_burn_rate = long,
+                    "Error budget burning too fast"
+                ),
+                None => tracing::info!(
+                    pattern,
+                    slo,
+                    short_burn_rate = short,
+                    long_burn_rate = long,
+                    "Error budget burn rate back below alert thresholds"
+                ),
+            }
+            budget.alert = status.alert;
+        }
+        status.alert
+    }
+
+    /// Every tracked target's error budget as of `now`.
+    pub fn status(&self, config: &ErrorBudgetConfig, now: Instant) -> BTreeMap<String, BudgetStatus> {
+        let elapsed = now.saturating_duration_since(self.origin);
+        self.budgets
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(pattern, budget)| (pattern.clone(), budget.status(config, elapsed)))
+            .collect()
+    }
+
+    /// Forgets a target removed by a reload, or one that no longer has an `slo`.
+    pub fn remove(&self, pattern: &str) {
+        self.budgets.lock().unwrap().remove(pattern);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("slo_tests.rs");
+}
@@ -0,0 +1,101 @@
+use super::*;
+
+const SLO: f64 = 99.5;
+
+/// One request a second from `from` to `to` seconds after the origin, every `error_every`th failing.
+/// Returns the alert after the last request.
+fn traffic(budgets: &ErrorBudgets, from: u64, to: u64, error_every: Option<u64>) -> Option<BurnAlert> {
+    let config = ErrorBudgetConfig::default();
+    let mut alert = None;
+    for second in from..to {
+        let now = budgets.origin + Duration::from_secs(second);
+        let error = error_every.is_some_and(|every| second % every == 0);
+        alert = budgets.record("/api", SLO, &config, error, now);
+    }
+    alert
+}
+
+fn status_at(budgets: &ErrorBudgets, second: u64) -> BudgetStatus {
+    let now = budgets.origin + Duration::from_secs(second);
+    budgets.status(&ErrorBudgetConfig::default(), now).remove("/api").unwrap()
+}
+
+#[test]
+fn test_healthy_traffic_raises_no_alert() {
+    let budgets = ErrorBudgets::default();
+    // 0.25% errors burn the 0.5% budget at half the sustainable rate
+    assert_eq!(traffic(&budgets, 0, 3600, Some(400)), None);
+    let status = status_at(&budgets, 3599);
+    assert_eq!((status.long_window.requests, status.long_window.errors), (3600, 9));
+    assert!((status.long_window.burn_rate - 0.5).abs() < 1e-9, "{}", status.long_window.burn_rate);
+    assert!(!status.slo_at_risk);
+}
+
+#[test]
+fn test_fast_burn() {
+    let budgets = ErrorBudgets::default();
+    assert_eq!(traffic(&budgets, 0, 3600, None), None);
+    // 10% errors burn the budget 20 times too fast. The long window passes the slow burn rate
+    // after 18 minutes and the fast one after about 43
+    assert_eq!(traffic(&budgets, 3600, 3600 + 15 * 60, Some(10)), None);
+    assert_eq!(traffic(&budgets, 3600 + 15 * 60, 3600 + 30 * 60, Some(10)), Some(BurnAlert::SlowBurn));
+    assert_eq!(traffic(&budgets, 3600 + 30 * 60, 3600 + 45 * 60, Some(10)), Some(BurnAlert::FastBurn));
+
+    let status = status_at(&budgets, 3600 + 45 * 60 - 1);
+    assert!(status.slo_at_risk);
+    assert!((status.short_window.burn_rate - 20.0).abs() < 1e-9);
+    assert!(status.long_window.burn_rate >= 14.4);
+}
+
+#[test]
+fn test_slow_burn() {
+    let budgets = ErrorBudgets::default();
+    // 4% errors burn the budget 8 times too fast: under the fast threshold, over the slow one
+    assert_eq!(traffic(&budgets, 0, 3600, Some(25)), Some(BurnAlert::SlowBurn));
+    let status = status_at(&budgets, 3599);
+    assert_eq!(status.alert, Some(BurnAlert::SlowBurn));
+    assert!((status.long_window.burn_rate - 8.0).abs() < 1e-9);
+}
+
+#[test]
+fn test_short_blip_does_not_alert() {
+    let budgets = ErrorBudgets::default();
+    traffic(&budgets, 0, 3540, None);
+    // A minute of 50% errors: the short window burns at 20, the long one stays below 2
+    assert_eq!(traffic(&budgets, 3540, 3600, Some(2)), None);
+    let status = status_at(&budgets, 3599);
+    assert!(status.short_window.burn_rate >= 14.4);
+    assert!(status.long_window.burn_rate < 6.0);
+}
+
+#[test]
+fn test_alert_clears_soon_after_recovery() {
+    let budgets = ErrorBudgets::default();
+    assert_eq!(traffic(&budgets, 0, 1800, Some(5)), Some(BurnAlert::FastBurn));
+    // The long window still remembers the outage, but the short one is clean again
+    assert_eq!(traffic(&budgets, 1800, 1800 + 310, None), None);
+    assert!(status_at(&budgets, 1800 + 309).long_window.burn_rate >= 14.4);
+}
+
+#[test]
+fn test_quiet_targets_need_min_requests() {
+    let budgets = ErrorBudgets::default();
+    assert_eq!(traffic(&budgets, 0, 19, Some(1)), None);
+    assert_eq!(traffic(&budgets, 19, 20, Some(1)), Some(BurnAlert::FastBurn));
+}
+
+#[test]
+fn test_old_traffic_falls_out_of_the_windows() {
+    let budgets = ErrorBudgets::default();
+    traffic(&budgets, 0, 60, Some(1));
+    let status = status_at(&budgets, 120);
+    assert_eq!((status.short_window.requests, status.long_window.requests), (60, 60));
+    let status = status_at(&budgets, 400);
+    assert_eq!((status.short_window.requests, status.long_window.requests), (0, 60));
+    let status = status_at(&budgets, 3700);
+    assert_eq!((status.long_window.requests, status.long_window.burn_rate), (0, 0.0));
+    assert_eq!(status.alert, None);
+
+    budgets.remove("/api");
+    assert!(budgets.status(&ErrorBudgetConfig::default(), Instant::now()).is_empty());
+}
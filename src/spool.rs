@@ -0,0 +1,498 @@
+use crate::config::SpoolConfig;
+use crate::error::{ErrorPhase, GatewayError};
+use crate::telemetry::Telemetry;
+use axum::body::{Body, Bytes};
+use axum::http::header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, RANGE};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::Response;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// Header carrying the token a spooled download is resumed with.
+pub const DOWNLOAD_TOKEN_HEADER: &str = "x-download-token";
+
+/// Response headers kept with a spooled download and sent again when it is resumed.
+const KEPT_HEADERS: [&str; 6] = [
+    "content-type",
+    "content-disposition",
+    "content-encoding",
+    "content-language",
+    "etag",
+    "last-modified",
+];
+
+/// Hex digits of a download token, 128 random bits.
+const TOKEN_LEN: usize = 32;
+
+const SPOOL_EXTENSION: &str = "spool";
+
+const READ_CHUNK_BYTES: u64 = 64 * 1024;
+
+/// Token of a spooled download, attached to the response extensions for the token header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DownloadToken(pub String);
+
+/// Keeps completed streams on disk for a while, so clients can fetch the rest of an interrupted
+/// download with `Range` instead of invoking the function again. A stream is read to the end even
+/// after its client disconnects; only downloads that completed can be resumed.
+#[derive(Clone)]
+pub struct Spool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    dir: PathBuf,
+    max_file_bytes: u64,
+    max_total_bytes: u64,
+    ttl: Duration,
+    telemetry: Telemetry,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    downloads: HashMap<String, Download>,
+    /// Bytes of every spool file, complete or not.
+    total_bytes: u64,
+}
+
+struct Download {
+    pattern: String,
+    headers: HeaderMap,
+    len: u64,
+    /// Set once the stream completed; until then the download cannot be resumed.
+    expires_at: Option<Instant>,
+}
+
+impl fmt::Debug for Spool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Spool").field("dir", &self.inner.dir).finish()
+    }
+}
+
+impl Spool {
+    pub fn new(config: &SpoolConfig, telemetry: Telemetry) -> Self {
+        let dir = config
+            .dir
+            .clone()
+            .unwrap_or_else(|| std::env::temp_dir().join("lambda-web-gateway-spool"));
+        Self {
+            inner: Arc::new(Inner {
+                dir,
+                max_file_bytes: config.max_file_bytes,
+                max_total_bytes: config.max_total_bytes,
+                ttl: Duration::from_secs(config.ttl_secs),
+                telemetry,
+                state: Mutex::default(),
+            }),
+        }
+    }
+
+    /// Starts spooling a stream of the target at `pattern`, keeping the headers a resumed download
+    /// needs. `None` when the spool file cannot be created.
+    pub async fn begin(&self, pattern: &str, headers: &HeaderMap) -> Option<SpoolWriter> {
+        let token = match new_token() {
+            Ok(token) => token,
+            Err(e) => {
+                tracing::warn!("Cannot generate a download token: {}", e);
+                return None;
+            }
+        };
+        let path = self.path(&token);
+        let file = async {
+            tokio::fs::create_dir_all(&self.inner.dir).await?;
+            tokio::fs::File::create(&path).await
+        };
+        let file = match file.await {
+            Ok(file) => file,
+            Err(e) => {
+                tracing::warn!("Cannot create spool file {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        let kept = KEPT_HEADERS
+            .iter()
+            .flat_map(|name| headers.get_all(*name).iter().map(move |value| (*name, value.clone())))
+            .fold(HeaderMap::new(), |mut kept, (name, value)| {
+                kept.append(name, value);
+                kept
+            });
+        let download = Download {
+            pattern: pattern.to_string(),
+            headers: kept,
+            len: 0,
+            expires_at: None,
+        };
+        self.inner
+            .state
+            .lock()
+            .unwrap()
+            .downloads
+            .insert(token.clone(), download);
+        Some(SpoolWriter {
+            spool: self.clone(),
+            token,
+            file: Some(file),
+        })
+    }
+
+    /// Answers a request carrying a download token from the spool: `206` for a satisfiable `Range`,
+    /// `416` for one past the end, and the whole body otherwise.
+    pub async fn serve(
+        &self,
+        pattern: &str,
+        token: &HeaderValue,
+        headers: &HeaderMap,
+    ) -> Result<Response, GatewayError> {
+        let token = token
+            .to_str()
+            .ok()
+            .filter(|token| is_valid_token(token))
+            .ok_or_else(|| {
+                GatewayError::new(
+                    ErrorPhase::Ingress,
+                    StatusCode::BAD_REQUEST,
+                    "invalid_download_token",
+                    format!("{} is not a valid download token", DOWNLOAD_TOKEN_HEADER),
+                )
+            })?;
+        let (len, kept) = self.lookup(pattern, token)?;
+        let unavailable = |e: io::Error| {
+            tracing::warn!("Cannot read spooled download {}: {}", token, e);
+            expired()
+        };
+        let mut file = tokio::fs::File::open(self.path(token)).await.map_err(unavailable)?;
+
+        let mut response = Response::builder()
+            .header(ACCEPT_RANGES, "bytes")
+            .header(DOWNLOAD_TOKEN_HEADER, token);
+        for (name, value) in &kept {
+            response = response.header(name, value);
+        }
+        let (start, end) = match parse_range(headers.get(RANGE), len) {
+            ByteRange::Full => {
+                response = response.status(StatusCode::OK);
+                (0, len)
+            }
+            ByteRange::Partial(start, end) => {
+                response = response
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end - 1, len));
+                (start, end)
+            }
+            ByteRange::Unsatisfiable => {
+                return Ok(response
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(CONTENT_RANGE, format!("bytes */{}", len))
+                    .body(Body::empty())
+                    .unwrap());
+            }
+        };
+        file.seek(io::SeekFrom::Start(start)).await.map_err(unavailable)?;
+        self.inner
+            .telemetry
+            .increment("downloads_resumed_total", vec![("target", pattern.to_string())]);
+        // Every header came from a valid header map, so building the response cannot fail
+        Ok(response
+            .header(CONTENT_LENGTH, end - start)
+            .body(Body::from_stream(read_stream(file, end - start)))
+            .unwrap())
+    }
+
+    /// Removes spool files left by an earlier run, then expired downloads every minute or `ttl_secs`,
+    /// whichever is shorter.
+    pub fn spawn_cleanup(&self) -> tokio::task::JoinHandle<()> {
+        let spool = self.clone();
+        tokio::spawn(async move {
+            let dir = spool.inner.dir.clone();
+            if let Err(e) = tokio::task::spawn_blocking(move || remove_spool_files(&dir)).await {
+                tracing::warn!("Spool cleanup task failed: {}", e);
+            }
+            let period = spool.inner.ttl.clamp(Duration::from_secs(1), Duration::from_secs(60));
+            let mut tick = tokio::time::interval(period);
+            loop {
+                tick.tick().await;
+                spool.remove_expired();
+            }
+        })
+    }
+
+    /// Deletes completed downloads whose `ttl_secs` has passed.
+    pub fn remove_expired(&self) {
+        let now = Instant::now();
+        let expired: Vec<String> = {
+            let state = self.inner.state.lock().unwrap();
+            state
+                .downloads
+                .iter()
+                .filter(|(_, download)| download.expires_at.is_some_and(|at| at <= now))
+                .map(|(token, _)| token.clone())
+                .collect()
+        };
+        for token in expired {
+            self.discard(&token);
+        }
+    }
+
+    fn lookup(&self, pattern: &str, token: &str) -> Result<(u64, HeaderMap), GatewayError> {
+        let state = self.inner.state.lock().unwrap();
+        // A token only resumes downloads of the target that issued it
+        let download = match state.downloads.get(token) {
+            Some(download) if download.pattern == pattern => download,
+            _ => return Err(expired()),
+        };
+        match download.expires_at {
+            None => Err(GatewayError::new(
+                ErrorPhase::Ingress,
+                StatusCode::CONFLICT,
+                "download_incomplete",
+                "The download is still in progress and can be resumed once it completed",
+            )),
+            Some(at) if at <= Instant::now() => {
+                drop(state);
+                self.discard(token);
+                Err(expired())
+            }
+            Some(_) => Ok((download.len, download.headers.clone())),
+        }
+    }
+
+    /// Accounts for `bytes` more of the download, evicting the oldest completed downloads if the
+    /// disk cap requires it. False when the download has to stop spooling.
+    fn reserve(&self, token: &str, bytes: u64) -> bool {
+        let mut evicted = Vec::new();
+        let reserved = {
+            let mut state = self.inner.state.lock().unwrap();
+            let Some(len) = state.downloads.get(token).map(|download| download.len) else {
+                return false;
+            };
+            if len + bytes > self.inner.max_file_bytes {
+                tracing::info!(
+                    "Download {} exceeds max_file_bytes of {}, it cannot be resumed",
+                    token,
+                    self.inner.max_file_bytes
+                );
+                false
+            } else {
+                while state.total_bytes + bytes > self.inner.max_total_bytes {
+                    let oldest = state
+                        .downloads
+                        .iter()
+                        .filter_map(|(token, download)| download.expires_at.map(|at| (at, token)))
+                        .min()
+                        .map(|(_, token)| token.clone());
+                    let Some(oldest) = oldest else {
+                        break;
+                    };
+                    let download = state.downloads.remove(&oldest).unwrap();
+                    state.total_bytes -= download.len;
+                    evicted.push(oldest);
+                }
+                let fits = state.total_bytes + bytes <= self.inner.max_total_bytes;
+                if fits {
+                    state.total_bytes += bytes;
+                    state.downloads.get_mut(token).unwrap().len += bytes;
+                } else {
+                    tracing::info!("Spool is full, download {} cannot be resumed", token);
+                }
+                fits
+            }
+        };
+        for token in evicted {
+            self.inner.telemetry.increment("spool_evictions_total", vec![]);
+            remove_file(&self.path(&token));
+        }
+        reserved
+    }
+
+    fn complete(&self, token: &str) {
+        if let Some(download) = self.inner.state.lock().unwrap().downloads.get_mut(token) {
+            download.expires_at = Some(Instant::now() + self.inner.ttl);
+        }
+    }
+
+    fn discard(&self, token: &str) {
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            let Some(download) = state.downloads.remove(token) else {
+                return;
+            };
+            state.total_bytes -= download.len;
+        }
+        remove_file(&self.path(token));
+    }
+
+    fn path(&self, token: &str) -> PathBuf {
+        self.inner.dir.join(format!("{}.{}", token, SPOOL_EXTENSION))
+    }
+}
+
+/// Writes one stream to its spool file. Dropping it before [`finish`](Self::finish), e.g. when
+/// the stream fails, discards the file.
+pub struct SpoolWriter {
+    spool: Spool,
+    token: String,
+    file: Option<tokio::fs::File>,
+}
+
+impl SpoolWriter {
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Appends `data` to the spool file. False once the stream is no longer spooled, because it
+    /// outgrew the limits or the file could not be written.
+    pub async fn write(&mut self, data: &[u8]) -> bool {
+        let Some(file) = self.file.as_mut() else {
+            return false;
+        };
+        if !self.spool.reserve(&self.token, data.len() as u64) {
+            self.abandon();
+            return false;
+        }
+        if let Err(e) = file.write_all(data).await {
+            tracing::warn!("Cannot write spool file for download {}: {}", self.token, e);
+            self.abandon();
+            return false;
+        }
+        true
+    }
+
+    /// Makes the download resumable for `ttl_secs`.
+    pub async fn finish(mut self) {
+        let Some(mut file) = self.file.take() else {
+            return;
+        };
+        match file.flush().await {
+            Ok(()) => self.spool.complete(&self.token),
+            Err(e) => {
+                tracing::warn!("Cannot write spool file for download {}: {}", self.token, e);
+                self.spool.discard(&self.token);
+            }
+        }
+    }
+
+    fn abandon(&mut self) {
+        self.file = None;
+        self.spool.discard(&self.token);
+    }
+}
+
+impl Drop for SpoolWriter {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            self.spool.discard(&self.token);
+        }
+    }
+}
+
+/// The part of a spooled body a request asks for, as a half-open byte range.
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    Full,
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Reads a single `bytes=` range. Other units, several ranges and malformed values are ignored as
+/// HTTP allows, answering with the whole body.
+fn parse_range(header: Option<&HeaderValue>, len: u64) -> ByteRange {
+    let Some(spec) = header
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().strip_prefix("bytes="))
+    else {
+        return ByteRange::Full;
+    };
+    let Some((first, last)) = spec.split_once('-').filter(|_| !spec.contains(',')) else {
+        return ByteRange::Full;
+    };
+    let (first, last) = (first.trim(), last.trim());
+    if first.is_empty() {
+        // A suffix range: the last N bytes
+        return match last.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(suffix) => ByteRange::Partial(len.saturating_sub(suffix), len),
+            Err(_) => ByteRange::Full,
+        };
+    }
+    let Ok(start) = first.parse::<u64>() else {
+        return ByteRange::Full;
+    };
+    let end = match last {
+        "" => len,
+        last => match last.parse::<u64>() {
+            Ok(last) if last >= start => last.saturating_add(1).min(len),
+            _ => return ByteRange::Full,
+        },
+    };
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start, end)
+}
+
+fn read_stream(file: tokio::fs::File, len: u64) -> impl futures::Stream<Item = io::Result<Bytes>> {
+    futures::stream::try_unfold((file, len), |(mut file, remaining)| async move {
+        if remaining == 0 {
+            return Ok(None);
+        }
+        let mut buffer = vec![0; remaining.min(READ_CHUNK_BYTES) as usize];
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "spool file is truncated"));
+        }
+        buffer.truncate(read);
+        Ok(Some((Bytes::from(buffer), (file, remaining - read as u64))))
+    })
+}
+
+fn expired() -> GatewayError {
+    GatewayError::new(
+        ErrorPhase::Ingress,
+        StatusCode::GONE,
+        "download_expired",
+        "The download token is unknown or has expired",
+    )
+}
+
+fn new_token() -> Result<String, getrandom::Error> {
+    let mut bytes = [0u8; TOKEN_LEN / 2];
+    getrandom::getrandom(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Tokens are exactly what [`new_token`] generates, so they are safe to use as file names.
+fn is_valid_token(token: &str) -> bool {
+    token.len() == TOKEN_LEN && token.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+fn remove_spool_files(dir: &Path) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == SPOOL_EXTENSION) {
+            remove_file(&path);
+        }
+    }
+}
+
+fn remove_file(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            tracing::warn!("Cannot remove spool file {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("spool_tests.rs");
+}
@@ -0,0 +1,203 @@
+use super::*;
+use crate::config::TelemetryConfig;
+
+fn spool(dir: &Path, config: SpoolConfig) -> Spool {
+    let config = SpoolConfig {
+        dir: Some(dir.to_path_buf()),
+        ..config
+    };
+    Spool::new(&config, Telemetry::new(&TelemetryConfig::default()))
+}
+
+async fn spooled(spool: &Spool, pattern: &str, body: &[u8]) -> String {
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static("text/csv"));
+    headers.insert("x-internal", HeaderValue::from_static("secret"));
+    let mut writer = spool.begin(pattern, &headers).await.unwrap();
+    let token = writer.token().to_string();
+    for chunk in body.chunks(4) {
+        assert!(writer.write(chunk).await);
+    }
+    writer.finish().await;
+    token
+}
+
+async fn resume(spool: &Spool, pattern: &str, token: &str, range: Option<&str>) -> Result<Response, GatewayError> {
+    let mut headers = HeaderMap::new();
+    if let Some(range) = range {
+        headers.insert(RANGE, HeaderValue::from_str(range).unwrap());
+    }
+    spool.serve(pattern, &HeaderValue::from_str(token).unwrap(), &headers).await
+}
+
+async fn body_of(response: Response) -> Bytes {
+    axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_resumed_download() {
+    let dir = tempfile::tempdir().unwrap();
+    let spool = spool(dir.path(), SpoolConfig::default());
+    let token = spooled(&spool, "/export", b"id,name\n1,alice\n").await;
+
+    let response = resume(&spool, "/export", &token, Some("bytes=8-")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.headers()[CONTENT_RANGE], "bytes 8-15/16");
+    assert_eq!(response.headers()[CONTENT_LENGTH], "8");
+    assert_eq!(response.headers()["content-type"], "text/csv");
+    assert!(!response.headers().contains_key("x-internal"));
+    assert_eq!(body_of(response).await, "1,alice\n");
+
+    let response = resume(&spool, "/export", &token, None).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_of(response).await, "id,name\n1,alice\n");
+
+    let response = resume(&spool, "/export", &token, Some("bytes=16-")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(response.headers()[CONTENT_RANGE], "bytes */16");
+}
+
+#[tokio::test]
+async fn test_expired_token() {
+    let dir = tempfile::tempdir().unwrap();
+    let spool = spool(
+        dir.path(),
+        SpoolConfig {
+            ttl_secs: 0,
+            ..Default::default()
+        },
+    );
+    let token = spooled(&spool, "/export", b"data").await;
+
+    let error = resume(&spool, "/export", &token, Some("bytes=2-")).await.unwrap_err();
+    assert_eq!((error.status, error.code), (StatusCode::GONE, "download_expired"));
+    assert!(!spool.path(&token).exists());
+}
+
+#[tokio::test]
+async fn test_cleanup_removes_expired_downloads() {
+    let dir = tempfile::tempdir().unwrap();
+    let spool = spool(
+        dir.path(),
+        SpoolConfig {
+            ttl_secs: 0,
+            ..Default::default()
+        },
+    );
+    let token = spooled(&spool, "/export", b"data").await;
+    let pending = spool.begin("/export", &HeaderMap::new()).await.unwrap();
+
+    spool.remove_expired();
+    assert!(!spool.path(&token).exists());
+    // Downloads still streaming are kept
+    assert!(spool.path(pending.token()).exists());
+}
+
+#[tokio::test]
+async fn test_disk_cap_evicts_oldest_completed_download() {
+    let dir = tempfile::tempdir().unwrap();
+    let spool = spool(
+        dir.path(),
+        SpoolConfig {
+            max_total_bytes: 20,
+            ..Default::default()
+        },
+    );
+    let oldest = spooled(&spool, "/export", b"0123456789").await;
+    let newer = spooled(&spool, "/export", b"abcdefgh").await;
+    let latest = spooled(&spool, "/export", b"ABCDEFGH").await;
+
+    assert!(!spool.path(&oldest).exists());
+    let error = resume(&spool, "/export", &oldest, None).await.unwrap_err();
+    assert_eq!(error.status, StatusCode::GONE);
+    for token in [newer, latest] {
+        assert_eq!(resume(&spool, "/export", &token, None).await.unwrap().status(), StatusCode::OK);
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let counters = spool.inner.telemetry.snapshot().counters;
+    assert_eq!(counters[&("spool_evictions_total", vec![])], 1);
+}
+
+#[tokio::test]
+async fn test_streams_over_the_limits_are_not_spooled() {
+    let dir = tempfile::tempdir().unwrap();
+    let spool = spool(
+        dir.path(),
+        SpoolConfig {
+            max_file_bytes: 6,
+            max_total_bytes: 10,
+            ..Default::default()
+        },
+    );
+    let mut writer = spool.begin("/export", &HeaderMap::new()).await.unwrap();
+    assert!(writer.write(b"0123").await);
+    assert!(!writer.write(b"4567").await);
+    assert!(!spool.path(writer.token()).exists());
+
+    // Nothing completed can be evicted to make room for streams still in progress
+    let mut first = spool.begin("/export", &HeaderMap::new()).await.unwrap();
+    let mut second = spool.begin("/export", &HeaderMap::new()).await.unwrap();
+    assert!(first.write(b"012345").await);
+    assert!(!second.write(b"012345").await);
+    assert!(spool.path(first.token()).exists());
+}
+
+#[tokio::test]
+async fn test_unfinished_downloads_cannot_be_resumed() {
+    let dir = tempfile::tempdir().unwrap();
+    let spool = spool(dir.path(), SpoolConfig::default());
+    let mut writer = spool.begin("/export", &HeaderMap::new()).await.unwrap();
+    let token = writer.token().to_string();
+    assert!(writer.write(b"partial").await);
+
+    let error = resume(&spool, "/export", &token, None).await.unwrap_err();
+    assert_eq!((error.status, error.code), (StatusCode::CONFLICT, "download_incomplete"));
+    // A stream that fails drops its writer, which discards the file
+    drop(writer);
+    assert!(!spool.path(&token).exists());
+    let error = resume(&spool, "/export", &token, None).await.unwrap_err();
+    assert_eq!(error.status, StatusCode::GONE);
+}
+
+#[tokio::test]
+async fn test_token_validation() {
+    let dir = tempfile::tempdir().unwrap();
+    let spool = spool(dir.path(), SpoolConfig::default());
+    let token = spooled(&spool, "/export", b"data").await;
+
+    for invalid in ["../../etc/passwd", "", &token.to_uppercase(), &token[1..], &format!("{}0", token)] {
+        let error = resume(&spool, "/export", invalid, None).await.unwrap_err();
+        assert_eq!((error.status, error.code), (StatusCode::BAD_REQUEST, "invalid_download_token"));
+    }
+    // Tokens only resume downloads of the target that issued them
+    let error = resume(&spool, "/other", &token, None).await.unwrap_err();
+    assert_eq!(error.status, StatusCode::GONE);
+    let unknown = "0".repeat(TOKEN_LEN);
+    assert_eq!(resume(&spool, "/export", &unknown, None).await.unwrap_err().status, StatusCode::GONE);
+}
+
+#[test]
+fn test_parse_range() {
+    let range = |v: &str| parse_range(Some(&HeaderValue::from_str(v).unwrap()), 100);
+    assert_eq!(parse_range(None, 100), ByteRange::Full);
+    assert_eq!(range("bytes=10-"), ByteRange::Partial(10, 100));
+    assert_eq!(range("bytes=10-19"), ByteRange::Partial(10, 20));
+    assert_eq!(range("bytes=90-200"), ByteRange::Partial(90, 100));
+    assert_eq!(range("bytes=-30"), ByteRange::Partial(70, 100));
+    assert_eq!(range("bytes=-300"), ByteRange::Partial(0, 100));
+    assert_eq!(range("bytes=100-"), ByteRange::Unsatisfiable);
+    assert_eq!(range("bytes=-0"), ByteRange::Unsatisfiable);
+    for ignored in ["items=0-5", "bytes=5", "bytes=x-", "bytes=20-10", "bytes=0-5,10-15"] {
+        assert_eq!(range(ignored), ByteRange::Full, "{}", ignored);
+    }
+}
+
+#[test]
+fn test_leftover_spool_files_removed() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join(format!("{}.spool", "a".repeat(TOKEN_LEN))), b"old").unwrap();
+    std::fs::write(dir.path().join("notes.txt"), b"keep").unwrap();
+    remove_spool_files(dir.path());
+    let names: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+    assert_eq!(names, vec!["notes.txt"]);
+}
@@ -0,0 +1,370 @@
+use crate::access_log::now_ms;
+use crate::checkpoint::Checkpointer;
+use crate::config::{StateStoreConfig, StoreKind};
+use crate::memory::{MemoryBudget, StatefulStore};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A failed state store operation, e.g. an unreachable server or a value that is not an integer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoreError(pub String);
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+/// Key-value state that stateful features such as rate limits and quotas keep, either in this
+/// process ([`MemoryStore`]) or shared between replicas (`RedisStore`, with the `redis-state`
+/// feature). Expiry is per key; a `ttl` of `None` keeps the key until it is overwritten.
+pub trait StateStore: Send + Sync + 'static {
+    fn get(&self, key: &str) -> BoxFuture<'static, StoreResult<Option<Vec<u8>>>>;
+
+    fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> BoxFuture<'static, StoreResult<()>>;
+
+    /// Adds `delta` to the integer stored at `key`, starting from 0, and returns the new value.
+    /// `ttl` is applied when the key has no expiry yet, so a counter expires a fixed time after it
+    /// was created rather than after its last increment.
+    fn increment(&self, key: &str, delta: i64, ttl: Option<Duration>) -> BoxFuture<'static, StoreResult<i64>>;
+
+    /// Stores `new` only if the current value is `expected`, where `None` means the key is absent.
+    /// Returns whether the value was stored.
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'static, StoreResult<bool>>;
+}
+
+/// Opens the store `feature` selected with `store: memory` or `store: redis`. A Redis store falls
+/// back to local state while the server is unreachable. Local state is checkpointed under the
+/// feature's name, and restored from the last checkpoint.
+pub fn open(
+    feature: &str,
+    kind: StoreKind,
+    config: &StateStoreConfig,
+    memory: &MemoryBudget,
+    checkpointer: &Checkpointer,
+) -> Arc<dyn StateStore> {
+    let local = Arc::new(MemoryStore::default());
+    memory.register(local.clone());
+    checkpointer.register(feature, local.clone());
+    match kind {
+        StoreKind::Memory => local,
+        #[cfg(feature = "redis-state")]
+        StoreKind::Redis => {
+            let url = config.redis_url.as_deref().unwrap_or_default();
+            match crate::redis_store::RedisStore::new(url) {
+                Ok(redis) => Arc::new(FallbackStore::new(Arc::new(redis), local, config.timeout_ms)),
+                Err(e) => {
+                    tracing::warn!("Using local state, the Redis state store cannot be opened: {}", e);
+                    local
+                }
+            }
+        }
+        #[cfg(not(feature = "redis-state"))]
+        StoreKind::Redis => {
+            let _ = config;
+            tracing::warn!("Using local state, the gateway was built without the redis-state feature");
+            local
+        }
+    }
+}
+
+struct MemoryEntry {
+    value: Vec<u8>,
+    expires_at: Option<Instant>,
+}
+
+impl MemoryEntry {
+    fn is_live(&self, now: Instant) -> bool {
+        self.expires_at.is_none_or(|at| at > now)
+    }
+}
+
+/// State kept in this process. Counts against the shared memory budget as `state_store`.
+#[derive(Default)]
+pub struct MemoryStore {
+    entries: Mutex<HashMap<String, MemoryEntry>>,
+}
+
+/// A stored entry as written to a checkpoint, with its expiry in wall-clock time so it survives a
+/// restart.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    pub key: String,
+    /// Base64 of the value.
+    pub value: String,
+    pub expires_at_ms: Option<u128>,
+}
+
+impl MemoryStore {
+    /// The live entries, for a checkpoint.
+    pub fn snapshot(&self) -> Vec<SnapshotEntry> {
+        let entries = self.entries.lock().unwrap();
+        let (now, now_ms) = (Instant::now(), now_ms());
+        let mut snapshot: Vec<SnapshotEntry> = entries
+            .iter()
+            .filter(|(_, entry)| entry.is_live(now))
+            .map(|(key, entry)| SnapshotEntry {
+                key: key.clone(),
+                value: BASE64.encode(&entry.value),
+                expires_at_ms: entry
+                    .expires_at
+                    .map(|at| now_ms + at.saturating_duration_since(now).as_millis()),
+            })
+            .collect();
+        snapshot.sort_by(|a, b| a.key.cmp(&b.key));
+        snapshot
+    }
+
+    /// Puts back entries from a checkpoint, skipping those that expired meanwhile or cannot be
+    /// decoded. Returns the number restored.
+    pub fn restore(&self, snapshot: Vec<SnapshotEntry>) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let (now, now_ms) = (Instant::now(), now_ms());
+        let mut restored = 0;
+        for entry in snapshot {
+            let expires_at = match entry.expires_at_ms {
+                Some(at) if at <= now_ms => continue,
+                Some(at) => Some(now + Duration::from_millis((at - now_ms) as u64)),
+                None => None,
+            };
+            let Ok(value) = BASE64.decode(&entry.value) else {
+                tracing::warn!(key = entry.key, "Skipping checkpointed state that is not valid base64");
+                continue;
+            };
+            entries.insert(entry.key, MemoryEntry { value, expires_at });
+            restored += 1;
+        }
+        restored
+    }
+}
+
+fn expiry(now: Instant, ttl: Option<Duration>) -> Option<Instant> {
+    ttl.map(|ttl| now + ttl)
+}
+
+impl StateStore for MemoryStore {
+    fn get(&self, key: &str) -> BoxFuture<'static, StoreResult<Option<Vec<u8>>>> {
+        let entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        let value = entries
+            .get(key)
+            .filter(|entry| entry.is_live(now))
+            .map(|e| e.value.clone());
+        Box::pin(async move { Ok(value) })
+    }
+
+    fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> BoxFuture<'static, StoreResult<()>> {
+        let entry = MemoryEntry {
+            value,
+            expires_at: expiry(Instant::now(), ttl),
+        };
+        self.entries.lock().unwrap().insert(key.to_string(), entry);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn increment(&self, key: &str, delta: i64, ttl: Option<Duration>) -> BoxFuture<'static, StoreResult<i64>> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        let entry = entries
+            .entry(key.to_string())
+            .and_modify(|entry| {
+                if !entry.is_live(now) {
+                    *entry = MemoryEntry {
+                        value: b"0".to_vec(),
+                        expires_at: None,
+                    };
+                }
+            })
+            .or_insert_with(|| MemoryEntry {
+                value: b"0".to_vec(),
+                expires_at: None,
+            });
+        let result = std::str::from_utf8(&entry.value)
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .ok_or_else(|| StoreError(format!("value of {} is not an integer", key)))
+            .and_then(|value| {
+                value
+                    .checked_add(delta)
+                    .ok_or_else(|| StoreError(format!("incrementing {} overflows", key)))
+            })
+            .inspect(|value| {
+                entry.value = value.to_string().into_bytes();
+                if entry.expires_at.is_none() {
+                    entry.expires_at = expiry(now, ttl);
+                }
+            });
+        Box::pin(async move { result })
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'static, StoreResult<bool>> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        let current = entries.get(key).filter(|entry| entry.is_live(now)).map(|e| &e.value);
+        let swapped = current == expected.as_ref();
+        if swapped {
+            let entry = MemoryEntry {
+                value: new,
+                expires_at: expiry(now, ttl),
+            };
+            entries.insert(key.to_string(), entry);
+        }
+        Box::pin(async move { Ok(swapped) })
+    }
+}
+
+impl StatefulStore for MemoryStore {
+    fn name(&self) -> &str {
+        "state_store"
+    }
+
+    fn usage_bytes(&self) -> usize {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().map(|(key, entry)| key.len() + entry.value.len()).sum()
+    }
+
+    /// Drops expired entries first, then the entries closest to expiring.
+    fn evict(&self, bytes: usize) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        let mut candidates: Vec<(bool, Option<Instant>, String)> = entries
+            .iter()
+            .map(|(key, entry)| (entry.is_live(now), entry.expires_at, key.clone()))
+            .collect();
+        // Expired first, then by expiry with keys that never expire last
+        candidates.sort_by_key(|(live, expires_at, _)| (*live, expires_at.is_none(), *expires_at));
+        let mut freed = 0;
+        for (_, _, key) in candidates {
+            if freed >= bytes {
+                break;
+            }
+            if let Some(entry) = entries.remove(&key) {
+                freed += key.len() + entry.value.len();
+            }
+        }
+        freed
+    }
+}
+
+/// How long a degraded [`FallbackStore`] uses local state before trying the shared store again.
+const PRIMARY_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A shared store that falls back to local state while it is unreachable or slower than
+/// `timeout_ms`, logging a warning when it degrades and again when it recovers. Features keep
+/// working during an outage, with limits enforced per replica instead of across them.
+pub struct FallbackStore {
+    primary: Arc<dyn StateStore>,
+    local: Arc<dyn StateStore>,
+    timeout: Duration,
+    degraded: Arc<AtomicBool>,
+    /// While degraded, when the shared store is tried next.
+    retry_at: Arc<Mutex<Option<Instant>>>,
+}
+
+impl FallbackStore {
+    pub fn new(primary: Arc<dyn StateStore>, local: Arc<dyn StateStore>, timeout_ms: u64) -> Self {
+        Self {
+            primary,
+            local,
+            timeout: Duration::from_millis(timeout_ms),
+            degraded: Arc::default(),
+            retry_at: Arc::default(),
+        }
+    }
+
+    /// Whether the last operation had to use local state.
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Relaxed)
+    }
+
+    fn run<T: Send + 'static>(
+        &self,
+        primary: BoxFuture<'static, StoreResult<T>>,
+        local: impl FnOnce() -> BoxFuture<'static, StoreResult<T>> + Send + 'static,
+    ) -> BoxFuture<'static, StoreResult<T>> {
+        let (timeout, degraded, retry_at) = (self.timeout, self.degraded.clone(), self.retry_at.clone());
+        Box::pin(async move {
+            if retry_at.lock().unwrap().is_some_and(|at| Instant::now() < at) {
+                return local().await;
+            }
+            let error = match tokio::time::timeout(timeout, primary).await {
+                Ok(Ok(value)) => {
+                    *retry_at.lock().unwrap() = None;
+                    if degraded.swap(false, Ordering::Relaxed) {
+                        tracing::info!("State store recovered, using shared state again");
+                    }
+                    return Ok(value);
+                }
+                Ok(Err(e)) => e,
+                Err(_) => StoreError(format!("no answer within {} ms", timeout.as_millis())),
+            };
+            *retry_at.lock().unwrap() = Some(Instant::now() + PRIMARY_RETRY_INTERVAL);
+            if !degraded.swap(true, Ordering::Relaxed) {
+                tracing::warn!("State store unavailable, falling back to local state: {}", error);
+            }
+            local().await
+        })
+    }
+}
+
+impl StateStore for FallbackStore {
+    fn get(&self, key: &str) -> BoxFuture<'static, StoreResult<Option<Vec<u8>>>> {
+        let (local, key_owned) = (self.local.clone(), key.to_string());
+        self.run(self.primary.get(key), move || local.get(&key_owned))
+    }
+
+    fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> BoxFuture<'static, StoreResult<()>> {
+        let (local, key_owned, value_owned) = (self.local.clone(), key.to_string(), value.clone());
+        self.run(self.primary.set(key, value, ttl), move || {
+            local.set(&key_owned, value_owned, ttl)
+        })
+    }
+
+    fn increment(&self, key: &str, delta: i64, ttl: Option<Duration>) -> BoxFuture<'static, StoreResult<i64>> {
+        let (local, key_owned) = (self.local.clone(), key.to_string());
+        self.run(self.primary.increment(key, delta, ttl), move || {
+            local.increment(&key_owned, delta, ttl)
+        })
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'static, StoreResult<bool>> {
+        let (local, key_owned) = (self.local.clone(), key.to_string());
+        let (expected_owned, new_owned) = (expected.clone(), new.clone());
+        self.run(self.primary.compare_and_swap(key, expected, new, ttl), move || {
+            local.compare_and_swap(&key_owned, expected_owned, new_owned, ttl)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("state_store_tests.rs");
+}
@@ -0,0 +1,104 @@
+use super::*;
+use crate::mock::UnreachableStore;
+
+/// Behaviour every [`StateStore`] must provide. Keys are prefixed so that runs against a shared
+/// server do not collide.
+async fn contract(store: &dyn StateStore, prefix: &str) {
+    let key = |name: &str| format!("{}:{}", prefix, name);
+
+    assert_eq!(store.get(&key("missing")).await, Ok(None));
+    store.set(&key("plain"), b"value".to_vec(), None).await.unwrap();
+    assert_eq!(store.get(&key("plain")).await, Ok(Some(b"value".to_vec())));
+    store.set(&key("plain"), b"other".to_vec(), None).await.unwrap();
+    assert_eq!(store.get(&key("plain")).await, Ok(Some(b"other".to_vec())));
+
+    store
+        .set(&key("short"), b"value".to_vec(), Some(Duration::from_millis(100)))
+        .await
+        .unwrap();
+    assert_eq!(store.get(&key("short")).await, Ok(Some(b"value".to_vec())));
+
+    let counter = key("counter");
+    let ttl = Some(Duration::from_millis(300));
+    assert_eq!(store.increment(&counter, 1, ttl).await, Ok(1));
+    assert_eq!(store.increment(&counter, 5, ttl).await, Ok(6));
+    assert_eq!(store.increment(&counter, -2, ttl).await, Ok(4));
+    assert_eq!(store.get(&counter).await, Ok(Some(b"4".to_vec())));
+    assert!(store.increment(&key("plain"), 1, None).await.is_err());
+
+    let cas = key("cas");
+    assert_eq!(store.compare_and_swap(&cas, None, b"v1".to_vec(), None).await, Ok(true));
+    assert_eq!(store.compare_and_swap(&cas, None, b"v2".to_vec(), None).await, Ok(false));
+    assert_eq!(
+        store.compare_and_swap(&cas, Some(b"stale".to_vec()), b"v2".to_vec(), None).await,
+        Ok(false)
+    );
+    assert_eq!(
+        store
+            .compare_and_swap(&cas, Some(b"v1".to_vec()), b"v2".to_vec(), Some(Duration::from_millis(100)))
+            .await,
+        Ok(true)
+    );
+    assert_eq!(store.get(&cas).await, Ok(Some(b"v2".to_vec())));
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(store.get(&key("short")).await, Ok(None));
+    // An expired key counts as absent
+    assert_eq!(store.compare_and_swap(&cas, None, b"v3".to_vec(), None).await, Ok(true));
+    // The counter expires from its creation, not its last increment
+    assert_eq!(store.increment(&counter, 1, ttl).await, Ok(5));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(store.get(&counter).await, Ok(None));
+    assert_eq!(store.increment(&counter, 1, ttl).await, Ok(1));
+}
+
+#[tokio::test]
+async fn test_memory_store_contract() {
+    contract(&MemoryStore::default(), "memory").await;
+}
+
+#[tokio::test]
+async fn test_fallback_store_contract_while_primary_is_down() {
+    let store = FallbackStore::new(Arc::new(UnreachableStore), Arc::new(MemoryStore::default()), 50);
+    contract(&store, "fallback").await;
+    assert!(store.is_degraded());
+}
+
+#[tokio::test]
+async fn test_fallback_store_uses_primary_while_it_is_up() {
+    let (primary, local) = (Arc::new(MemoryStore::default()), Arc::new(MemoryStore::default()));
+    let store = FallbackStore::new(primary.clone(), local.clone(), 50);
+
+    assert_eq!(store.increment("hits", 3, None).await, Ok(3));
+    assert_eq!(primary.get("hits").await, Ok(Some(b"3".to_vec())));
+    assert_eq!(local.get("hits").await, Ok(None));
+    assert!(!store.is_degraded());
+}
+
+#[tokio::test]
+async fn test_memory_store_evicts_expired_entries_first() {
+    let store = MemoryStore::default();
+    store.set("keep", b"1234".to_vec(), None).await.unwrap();
+    store
+        .set("gone", b"1234".to_vec(), Some(Duration::from_millis(1)))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    assert_eq!(store.usage_bytes(), 16);
+
+    assert_eq!(store.evict(1), 8);
+    assert_eq!(store.get("keep").await, Ok(Some(b"1234".to_vec())));
+}
+
+/// Runs the contract against the server in `REDIS_URL`, when set.
+#[cfg(feature = "redis-state")]
+#[tokio::test]
+async fn test_redis_store_contract() {
+    let Ok(url) = std::env::var("REDIS_URL") else {
+        eprintln!("REDIS_URL is not set, skipping the Redis contract test");
+        return;
+    };
+    let store = crate::redis_store::RedisStore::new(&url).unwrap();
+    let prefix = format!("gateway-contract-{}", crate::access_log::now_ms());
+    contract(&store, &prefix).await;
+}
@@ -0,0 +1,76 @@
+use crate::config::{StreamFormat, Target};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// The format a streamed response actually came in, attached to the response extensions when it
+/// differs from the target's `stream_format`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DetectedStreamFormat(pub StreamFormat);
+
+/// Remembers targets whose function streams in another format than configured. Each is warned
+/// about once, and with `auto_correct_stream_format` read in the detected format from then on.
+#[derive(Clone, Default)]
+pub struct StreamFormatMonitor {
+    detected: Arc<Mutex<HashMap<String, StreamFormat>>>,
+}
+
+impl StreamFormatMonitor {
+    /// `target` as its responses should be read, with the detected format if it auto-corrects.
+    pub fn effective<'a>(&self, pattern: &str, target: &'a Target) -> Cow<'a, Target> {
+        if !target.auto_correct_stream_format {
+            return Cow::Borrowed(target);
+        }
+        match self.detected.lock().unwrap().get(pattern) {
+            Some(&stream_format) if stream_format != target.stream_format => Cow::Owned(Target {
+                stream_format,
+                ..target.clone()
+            }),
+            _ => Cow::Borrowed(target),
+        }
+    }
+
+    /// Records that a response of `pattern` came in `detected` format, warning the first time it
+    /// differs from the configured one. Returns whether it warned.
+    pub fn report(&self, pattern: &str, target: &Target, detected: StreamFormat) -> bool {
+        let mut formats = self.detected.lock().unwrap();
+        if detected == target.stream_format {
+            // The function went back to the configured format
+            formats.remove(pattern);
+            return false;
+        }
+        if formats.insert(pattern.to_string(), detected) == Some(detected) {
+            return false;
+        }
+        let setting = match detected {
+            StreamFormat::Auto => "auto",
+            StreamFormat::Prelude => "prelude",
+            StreamFormat::Raw => "raw",
+        };
+        if target.auto_correct_stream_format {
+            tracing::warn!(
+                pattern,
+                "Target streams in {} format, reading its responses that way from now on",
+                setting
+            );
+        } else {
+            tracing::warn!(
+                pattern,
+                "Target streams in {} format unlike configured; set stream_format: {}",
+                setting,
+                setting
+            );
+        }
+        true
+    }
+
+    /// Forgets what was detected for a target removed or changed by a reload.
+    pub fn remove(&self, pattern: &str) {
+        self.detected.lock().unwrap().remove(pattern);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("stream_format_tests.rs");
+}
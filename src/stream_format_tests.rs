@@ -0,0 +1,45 @@
+use super::*;
+
+fn raw_target(auto_correct_stream_format: bool) -> Target {
+    Target {
+        stream_format: StreamFormat::Raw,
+        auto_correct_stream_format,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_warns_once_per_target() {
+    let monitor = StreamFormatMonitor::default();
+    let target = raw_target(false);
+    assert!(monitor.report("/a", &target, StreamFormat::Prelude));
+    assert!(!monitor.report("/a", &target, StreamFormat::Prelude));
+    assert!(monitor.report("/b", &target, StreamFormat::Prelude));
+
+    let prelude_target = Target {
+        stream_format: StreamFormat::Prelude,
+        ..Default::default()
+    };
+    assert!(monitor.report("/c", &prelude_target, StreamFormat::Raw));
+    assert!(!monitor.report("/c", &prelude_target, StreamFormat::Raw));
+
+    // A reload starts over
+    monitor.remove("/a");
+    assert!(monitor.report("/a", &target, StreamFormat::Prelude));
+}
+
+#[test]
+fn test_corrects_only_when_enabled() {
+    let monitor = StreamFormatMonitor::default();
+    let (plain, correcting) = (raw_target(false), raw_target(true));
+    assert_eq!(monitor.effective("/a", &correcting).stream_format, StreamFormat::Raw);
+
+    monitor.report("/a", &plain, StreamFormat::Prelude);
+    assert_eq!(monitor.effective("/a", &plain).stream_format, StreamFormat::Raw);
+    assert_eq!(monitor.effective("/a", &correcting).stream_format, StreamFormat::Prelude);
+    assert_eq!(monitor.effective("/b", &correcting).stream_format, StreamFormat::Raw);
+
+    // The function went back to the configured format
+    assert!(!monitor.report("/a", &correcting, StreamFormat::Raw));
+    assert_eq!(monitor.effective("/a", &correcting).stream_format, StreamFormat::Raw);
+}
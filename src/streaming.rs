@@ -0,0 +1,776 @@
+use crate::config::{StreamFormat, Target};
+use crate::drain::Drained;
+use crate::error::{ErrorPhase, GatewayError};
+use crate::function_errors::LambdaError;
+use crate::headers::UpstreamHeaders;
+use crate::ndjson::{InvalidRecord, RecordStream};
+use crate::request::RequestContext;
+use crate::spool::{DownloadToken, SpoolWriter};
+use crate::stream_format::DetectedStreamFormat;
+use crate::telemetry::Telemetry;
+use aws_sdk_lambda::operation::invoke_with_response_stream::InvokeWithResponseStreamOutput;
+use aws_sdk_lambda::types::InvokeWithResponseStreamResponseEvent::{InvokeComplete, PayloadChunk};
+use axum::body::{Body, Bytes};
+use axum::http::header::CONTENT_LENGTH;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::Response;
+use futures::stream::{BoxStream, Stream};
+use futures_util::stream::StreamExt;
+use http_body::Frame;
+use http_body_util::StreamBody;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Trailer carrying the hex SHA-256 of the streamed body, sent when `body_digest_trailer` is set.
+pub const DIGEST_TRAILER: &str = "x-content-sha256";
+
+/// Payload bytes of a streaming invoke, ending when the function completes.
+pub type PayloadStream = BoxStream<'static, Result<Bytes, StreamError>>;
+
+/// Why a [`PayloadStream`] failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StreamError {
+    /// The function failed: Lambda completed the invoke with an error code such as `Unhandled`,
+    /// and the function's error object as details.
+    Function { kind: String, details: String },
+    /// Receiving the stream failed.
+    Transport(String),
+}
+
+impl std::fmt::Display for StreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamError::Function { kind, .. } => write!(f, "function failed ({})", kind),
+            StreamError::Transport(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+/// Why a stream could not be answered with a response head.
+#[derive(Debug)]
+pub(crate) enum StreamStartError {
+    /// The function failed before its prelude was complete, to be answered like a failed buffered
+    /// invoke.
+    Function {
+        kind: String,
+        details: String,
+    },
+    Gateway(GatewayError),
+}
+
+impl From<GatewayError> for StreamStartError {
+    fn from(error: GatewayError) -> Self {
+        StreamStartError::Gateway(error)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct MetadataPrelude {
+    #[serde(with = "http_serde::status_code")]
+    /// The HTTP status code.
+    pub status_code: StatusCode,
+    /// The HTTP headers.
+    #[serde(default)]
+    pub headers: UpstreamHeaders,
+    /// The HTTP cookies.
+    #[serde(default)]
+    pub cookies: Vec<String>,
+}
+
+/// Adapts the SDK event receiver into a [`PayloadStream`].
+pub fn payload_stream(resp: InvokeWithResponseStreamOutput) -> PayloadStream {
+    futures::stream::unfold(Some(resp), |resp| async move {
+        let mut resp = resp?;
+        loop {
+            match resp.event_stream.recv().await {
+                Ok(Some(PayloadChunk(chunk))) => {
+                    if let Some(data) = chunk.payload() {
+                        return Some((Ok(Bytes::from(data.clone().into_inner())), Some(resp)));
+                    }
+                }
+                Ok(Some(InvokeComplete(complete))) => {
+                    let kind = complete.error_code()?.to_string();
+                    let details = complete.error_details().unwrap_or_default().to_string();
+                    return Some((Err(StreamError::Function { kind, details }), None));
+                }
+                Ok(None) => return None,
+                Ok(Some(_)) => {}
+                Err(e) => return Some((Err(StreamError::Transport(e.to_string())), None)),
+            }
+        }
+    })
+    .boxed()
+}
+
+/// A chunk on its way to the client, stamped with the time it was received from Lambda.
+type ForwardedChunk = (Instant, Result<Bytes, std::io::Error>);
+
+/// Why a streamed response ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TerminationReason {
+    Completed,
+    UpstreamError,
+    ClientDisconnected,
+    ClientLagExceeded,
+    /// The target was removed or changed by a reload and the stream outlived its drain deadline.
+    Drained,
+    /// The stream ran longer than its target's `max_stream_duration_ms`.
+    MaxDurationExceeded,
+    /// The function sent nothing for its target's `idle_timeout_ms`.
+    IdleTimeout,
+    /// The function sent an invalid NDJSON record to a target that aborts at them.
+    InvalidRecord,
+}
+
+/// Error ending a stream that ran into its target's `max_stream_duration_ms`.
+#[derive(Debug)]
+pub struct MaxDurationExceeded(pub u64);
+
+impl std::fmt::Display for MaxDurationExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "stream aborted: it ran longer than {} ms", self.0)
+    }
+}
+
+impl std::error::Error for MaxDurationExceeded {}
+
+/// Error ending a stream whose function sent nothing for its target's `idle_timeout_ms`.
+#[derive(Debug)]
+pub struct IdleTimeout(pub u64);
+
+impl std::fmt::Display for IdleTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "stream aborted: the function sent nothing for {} ms", self.0)
+    }
+}
+
+impl std::error::Error for IdleTimeout {}
+
+pub(crate) async fn handle_streaming_response(
+    mut payload: PayloadStream,
+    target: &Target,
+    telemetry: &Telemetry,
+    request_context: &RequestContext,
+    dispatched_at: Instant,
+) -> Result<Response, StreamStartError> {
+    let (tx, rx) = mpsc::channel::<ForwardedChunk>(1);
+    let mut metadata_prelude: Option<MetadataPrelude> = None;
+    let mut remaining_data = Vec::new();
+    // Set when the response came in the other format than the target's `stream_format`
+    let mut mismatch = None;
+
+    let read_prelude = async {
+        // Waiting for the prelude is time spent on the function
+        let _timer = request_context.upstream_time.start();
+        // Step 1: Detect if metadata exists and get the first chunk
+        let (has_metadata, first_chunk) = detect_metadata(&mut payload).await?;
+
+        // Step 2: Process the first chunk
+        let Some(chunk) = first_chunk else {
+            return Ok(());
+        };
+        match target.stream_format {
+            StreamFormat::Auto | StreamFormat::Prelude if has_metadata => {
+                let mut metadata_buffer = chunk;
+                (metadata_prelude, remaining_data) = collect_metadata(&mut payload, &mut metadata_buffer).await?;
+                if metadata_prelude.is_none() && target.stream_format == StreamFormat::Prelude {
+                    // The stream ended without a prelude separator, so it was all body
+                    mismatch = Some(StreamFormat::Raw);
+                }
+            }
+            StreamFormat::Prelude => {
+                // A prelude starts with `{`, so this can only be body
+                mismatch = Some(StreamFormat::Raw);
+                remaining_data = chunk;
+            }
+            StreamFormat::Raw if looks_like_prelude(&chunk) => {
+                // Sent as configured; reading it as a prelude is left to `auto_correct_stream_format`
+                mismatch = Some(StreamFormat::Prelude);
+                remaining_data = chunk;
+            }
+            StreamFormat::Auto | StreamFormat::Raw => {
+                // No metadata prelude, treat first chunk as payload
+                remaining_data = chunk;
+            }
+        }
+        Ok::<_, StreamError>(())
+    };
+    let read = match target.prelude_timeout_ms {
+        Some(ms) => tokio::time::timeout(Duration::from_millis(ms), read_prelude)
+            .await
+            .map_err(|_| {
+                GatewayError::new(
+                    ErrorPhase::Upstream,
+                    StatusCode::GATEWAY_TIMEOUT,
+                    "prelude_timeout",
+                    format!("Function did not send its response prelude within {} ms", ms),
+                )
+            })?,
+        None => read_prelude.await,
+    };
+    // Nothing has been sent yet, so a failure is answered as a failed invoke
+    read.map_err(|e| match e {
+        StreamError::Function { kind, details } => StreamStartError::Function { kind, details },
+        StreamError::Transport(message) => StreamStartError::Gateway(GatewayError::new(
+            ErrorPhase::Invoke,
+            StatusCode::BAD_GATEWAY,
+            "invoke_failed",
+            format!("Lambda response stream failed: {}", message),
+        )),
+    })?;
+
+    let padding = metadata_prelude
+        .as_ref()
+        .and_then(|prelude| initial_flush_padding(prelude, target.initial_flush_padding));
+
+    let mut resp_builder = Response::builder();
+    let status = metadata_prelude
+        .as_ref()
+        .map_or(StatusCode::OK, |prelude| prelude.status_code);
+
+    if let Some(metadata_prelude) = metadata_prelude {
+        resp_builder = resp_builder.status(metadata_prelude.status_code);
+
+        let mut headers = metadata_prelude.headers;
+        for cookie in &metadata_prelude.cookies {
+            headers.append("set-cookie", cookie);
+        }
+        for (k, v) in headers.checked(target.strict_upstream_headers)?.iter() {
+            if k != "content-length" {
+                resp_builder = resp_builder.header(k, v);
+            }
+        }
+    } else {
+        // Default response if no metadata
+        resp_builder = resp_builder.status(StatusCode::OK);
+        let content_type = target.raw_content_type.as_deref().unwrap_or("application/octet-stream");
+        resp_builder = resp_builder.header("content-type", content_type);
+    }
+
+    // Only successful bodies are worth resuming
+    let mut spool = match &request_context.spool {
+        Some(spool) if status == StatusCode::OK => {
+            let headers = resp_builder.headers_ref().cloned().unwrap_or_default();
+            spool.begin(&request_context.pattern, &headers).await
+        }
+        _ => None,
+    };
+    if let Some(writer) = &spool {
+        resp_builder = resp_builder.extension(DownloadToken(writer.token().to_string()));
+    }
+
+    let drain = request_context.drain.clone();
+    let max_duration = target.max_stream_duration_ms;
+    let idle_timeout = target.idle_timeout_ms;
+    let mut records = request_context.records.clone();
+    let (stream_telemetry, pattern) = (telemetry.clone(), request_context.pattern.clone());
+    let body = LagTrackingStream::new(ReceiverStream::new(rx), target, telemetry.clone());
+    let watch = body.stall_watch();
+    // Spawn task to handle remaining stream. The response head is returned below without waiting
+    // for any chunk beyond the prelude, so clients receive headers as soon as the prelude is parsed.
+    tokio::spawn(async move {
+        // A spooled stream is read to the end even after the client left, so it can be resumed
+        let mut client_gone = false;
+        if let Some(padding) = padding {
+            forward_chunk(&tx, &mut spool, &mut client_gone, padding, &watch).await;
+        }
+
+        // Data that came with the prelude goes first
+        let mut first = (!remaining_data.is_empty()).then(|| Bytes::from(remaining_data));
+
+        let drained = async move {
+            match drain {
+                Some(drain) => drain.expired().await,
+                None => std::future::pending().await,
+            }
+        };
+        let capped = async move {
+            match max_duration {
+                Some(ms) => {
+                    let deadline = dispatched_at + Duration::from_millis(ms);
+                    tokio::time::sleep_until(deadline.into()).await;
+                }
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(drained, capped);
+        loop {
+            // Restarted for every chunk, so only a gap between chunks runs into it
+            let idle = async {
+                match idle_timeout {
+                    Some(ms) => tokio::time::sleep(Duration::from_millis(ms)).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let chunk = if let Some(data) = first.take() {
+                Some(Ok(data))
+            } else {
+                tokio::select! {
+                    chunk = payload.next() => chunk,
+                    _ = &mut drained => {
+                        tracing::warn!("Aborting response stream at the drain deadline of its retired target");
+                        let _ = tx.send((Instant::now(), Err(std::io::Error::other(Drained)))).await;
+                        break;
+                    }
+                    _ = &mut capped => {
+                        let ms = max_duration.unwrap_or_default();
+                        tracing::warn!("Aborting response stream after its max_stream_duration_ms of {} ms", ms);
+                        let error = std::io::Error::other(MaxDurationExceeded(ms));
+                        let _ = tx.send((Instant::now(), Err(error))).await;
+                        break;
+                    }
+                    _ = idle => {
+                        let ms = idle_timeout.unwrap_or_default();
+                        tracing::warn!("Aborting response stream after {} ms without a chunk from the function", ms);
+                        let _ = tx.send((Instant::now(), Err(std::io::Error::other(IdleTimeout(ms))))).await;
+                        break;
+                    }
+                }
+            };
+            let Some(chunk) = chunk else {
+                // A last record without its end of line
+                let (data, invalid) = keep_records(&mut records, None);
+                if !data.is_empty() {
+                    forward_chunk(&tx, &mut spool, &mut client_gone, data, &watch).await;
+                }
+                if let Some(invalid) = invalid {
+                    abort_at_record(&tx, invalid).await;
+                } else if let Some(spool) = spool {
+                    spool.finish().await;
+                }
+                break;
+            };
+            match chunk {
+                Ok(data) => {
+                    let (data, invalid) = keep_records(&mut records, Some(data));
+                    if !data.is_empty() && !forward_chunk(&tx, &mut spool, &mut client_gone, data, &watch).await {
+                        break;
+                    }
+                    if let Some(invalid) = invalid {
+                        abort_at_record(&tx, invalid).await;
+                        break;
+                    }
+                }
+                Err(StreamError::Function { kind, details }) => {
+                    let error = LambdaError::parse(details.as_bytes());
+                    tracing::error!(
+                        kind = %kind,
+                        error_type = %error.error_type,
+                        error_message = %error.error_message,
+                        "Function failed while streaming its response"
+                    );
+                    let e = StreamError::Function { kind, details };
+                    let _ = tx.send((Instant::now(), Err(std::io::Error::other(e)))).await;
+                    break;
+                }
+                Err(e) => {
+                    tracing::warn!("Lambda response stream failed: {}", e);
+                    let _ = tx.send((Instant::now(), Err(std::io::Error::other(e)))).await;
+                    break;
+                }
+            }
+        }
+        if let Some(records) = &records {
+            records.report(&stream_telemetry, &pattern);
+        }
+    });
+
+    let body = if target.body_digest_trailer {
+        // HTTP/1.1 only sends trailers announced in the response head
+        if request_context.accepts_trailers {
+            resp_builder = resp_builder.header("trailer", DIGEST_TRAILER);
+        }
+        Body::new(StreamBody::new(DigestStream::new(body, request_context)))
+    } else {
+        Body::from_stream(body)
+    };
+    // Every header was validated above, so building the response cannot fail
+    let mut resp = resp_builder.body(body).unwrap();
+    if let Some(detected) = mismatch {
+        resp.extensions_mut().insert(DetectedStreamFormat(detected));
+    }
+    Ok(resp)
+}
+
+/// The records of `data` a target reading NDJSON keeps, or the records still buffered when `data`
+/// is `None` at the end of the stream; everything for other targets. Returns the invalid record
+/// the stream is to abort at, if any, besides the records before it.
+fn keep_records(records: &mut Option<RecordStream>, data: Option<Bytes>) -> (Bytes, Option<InvalidRecord>) {
+    let Some(records) = records else {
+        return (data.unwrap_or_default(), None);
+    };
+    let mut kept = Vec::new();
+    let result = match &data {
+        Some(data) => records.push(data, &mut kept),
+        None => records.finish(&mut kept),
+    };
+    (Bytes::from(kept), result.err())
+}
+
+async fn abort_at_record(tx: &mpsc::Sender<ForwardedChunk>, invalid: InvalidRecord) {
+    tracing::warn!("Aborting NDJSON response stream: {}", invalid);
+    let _ = tx.send((Instant::now(), Err(std::io::Error::other(invalid)))).await;
+}
+
+/// Hands `data` to the client and to the spool, if any. False once neither takes more data. A
+/// client that takes nothing for as long as `watch` allows counts as gone.
+async fn forward_chunk(
+    tx: &mpsc::Sender<ForwardedChunk>,
+    spool: &mut Option<SpoolWriter>,
+    client_gone: &mut bool,
+    data: Bytes,
+    watch: &StallWatch,
+) -> bool {
+    if let Some(writer) = spool {
+        if !writer.write(&data).await {
+            *spool = None;
+        }
+    }
+    if !*client_gone {
+        let send = tx.send((Instant::now(), Ok(data)));
+        let sent = match watch.limit {
+            Some(limit) => match tokio::time::timeout(limit, send).await {
+                Ok(sent) => sent.is_ok(),
+                Err(_) => {
+                    tracing::warn!(
+                        "Aborting response stream, the client took no chunk for {} ms",
+                        limit.as_millis()
+                    );
+                    watch.stalled.store(true, Ordering::Relaxed);
+                    false
+                }
+            },
+            None => send.await.is_ok(),
+        };
+        *client_gone = !sent;
+    }
+    !*client_gone || spool.is_some()
+}
+
+/// Shared between a [`LagTrackingStream`] and the task feeding it. Lag is measured when the client
+/// takes a chunk, which a client that stopped reading altogether never does, so the feeding task
+/// gives up on it once a chunk has waited for the lag limit and its duration together.
+#[derive(Clone, Debug)]
+pub(crate) struct StallWatch {
+    limit: Option<Duration>,
+    stalled: Arc<AtomicBool>,
+}
+
+impl StallWatch {
+    fn is_stalled(&self) -> bool {
+        self.stalled.load(Ordering::Relaxed)
+    }
+}
+
+/// Measures how long each chunk waits between receipt from Lambda and being handed to the client
+/// connection, logs a summary when the stream ends, and aborts the stream when the client falls
+/// behind for too long.
+pub(crate) struct LagTrackingStream<S> {
+    inner: S,
+    telemetry: Telemetry,
+    max_lag: Option<Duration>,
+    max_lag_duration: Duration,
+    lagging_since: Option<Instant>,
+    chunks: u64,
+    total_lag: Duration,
+    max_seen_lag: Duration,
+    termination: Option<TerminationReason>,
+    watch: StallWatch,
+}
+
+impl<S> LagTrackingStream<S> {
+    pub(crate) fn new(inner: S, target: &Target, telemetry: Telemetry) -> Self {
+        Self {
+            inner,
+            telemetry,
+            max_lag: target.max_client_lag_ms.map(Duration::from_millis),
+            max_lag_duration: Duration::from_millis(target.max_client_lag_duration_ms),
+            lagging_since: None,
+            chunks: 0,
+            total_lag: Duration::ZERO,
+            max_seen_lag: Duration::ZERO,
+            termination: None,
+            watch: StallWatch {
+                limit: target
+                    .max_client_lag_ms
+                    .map(|ms| Duration::from_millis(ms.saturating_add(target.max_client_lag_duration_ms))),
+                stalled: Arc::default(),
+            },
+        }
+    }
+
+    pub(crate) fn stall_watch(&self) -> StallWatch {
+        self.watch.clone()
+    }
+
+    fn record(&mut self, received_at: Instant) -> Option<TerminationReason> {
+        let now = Instant::now();
+        let lag = now.saturating_duration_since(received_at);
+        self.chunks += 1;
+        self.total_lag += lag;
+        self.max_seen_lag = self.max_seen_lag.max(lag);
+        self.telemetry
+            .observe("stream_chunk_lag_ms", vec![], lag.as_secs_f64() * 1000.0);
+
+        match self.max_lag {
+            Some(max_lag) if lag > max_lag => {
+                let since = *self.lagging_since.get_or_insert(now);
+                (now - since >= self.max_lag_duration).then_some(TerminationReason::ClientLagExceeded)
+            }
+            _ => {
+                self.lagging_since = None;
+                None
+            }
+        }
+    }
+}
+
+impl<S: Stream<Item = ForwardedChunk> + Unpin> Stream for LagTrackingStream<S> {
+    type Item = Result<Bytes, std::io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.termination.is_some() {
+            return Poll::Ready(None);
+        }
+        if self.watch.is_stalled() {
+            self.termination = Some(TerminationReason::ClientLagExceeded);
+            return Poll::Ready(Some(Err(client_lag_exceeded())));
+        }
+        match self.inner.poll_next_unpin(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(None) => {
+                self.termination = Some(TerminationReason::Completed);
+                Poll::Ready(None)
+            }
+            Poll::Ready(Some((_, Err(e)))) => {
+                let cause = e.get_ref();
+                self.termination = Some(if cause.is_some_and(|e| e.is::<Drained>()) {
+                    TerminationReason::Drained
+                } else if cause.is_some_and(|e| e.is::<MaxDurationExceeded>()) {
+                    TerminationReason::MaxDurationExceeded
+                } else if cause.is_some_and(|e| e.is::<IdleTimeout>()) {
+                    TerminationReason::IdleTimeout
+                } else if cause.is_some_and(|e| e.is::<InvalidRecord>()) {
+                    TerminationReason::InvalidRecord
+                } else {
+                    TerminationReason::UpstreamError
+                });
+                Poll::Ready(Some(Err(e)))
+            }
+            Poll::Ready(Some((received_at, Ok(data)))) => match self.record(received_at) {
+                Some(reason) => {
+                    self.termination = Some(reason);
+                    Poll::Ready(Some(Err(client_lag_exceeded())))
+                }
+                None => Poll::Ready(Some(Ok(data))),
+            },
+        }
+    }
+}
+
+fn client_lag_exceeded() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::TimedOut, "client lag exceeded the configured limit")
+}
+
+impl<S> Drop for LagTrackingStream<S> {
+    fn drop(&mut self) {
+        let termination = match self.termination {
+            Some(termination) => termination,
+            None if self.watch.is_stalled() => TerminationReason::ClientLagExceeded,
+            None => TerminationReason::ClientDisconnected,
+        };
+        let avg_lag_ms = match self.chunks {
+            0 => 0.0,
+            n => self.total_lag.as_secs_f64() * 1000.0 / n as f64,
+        };
+        tracing::info!(
+            ?termination,
+            chunks = self.chunks,
+            avg_lag_ms,
+            max_lag_ms = self.max_seen_lag.as_secs_f64() * 1000.0,
+            "Response stream finished"
+        );
+    }
+}
+
+/// Reads a streamed response to the end and returns it as a buffered response with a
+/// `Content-Length`. Bodies over `max_bytes` and streams that fail midway are answered with 502.
+pub(crate) async fn buffer_response(response: Response, max_bytes: usize) -> Result<Response, GatewayError> {
+    let (mut parts, body) = response.into_parts();
+    let mut data_stream = body.into_data_stream();
+    let mut buffered = Vec::new();
+    while let Some(chunk) = data_stream.next().await {
+        let chunk = chunk.map_err(|e| {
+            GatewayError::new(
+                ErrorPhase::Upstream,
+                StatusCode::BAD_GATEWAY,
+                "stream_failed",
+                format!("Response stream failed while buffering: {}", e),
+            )
+        })?;
+        if buffered.len() + chunk.len() > max_bytes {
+            return Err(GatewayError::new(
+                ErrorPhase::Upstream,
+                StatusCode::BAD_GATEWAY,
+                "response_too_large",
+                format!("Streamed response exceeds the {} byte buffering limit", max_bytes),
+            ));
+        }
+        buffered.extend_from_slice(&chunk);
+    }
+    // Trailers cannot follow a body sent with a Content-Length
+    parts.headers.remove("trailer");
+    parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(buffered.len()));
+    Ok(Response::from_parts(parts, Body::from(buffered)))
+}
+
+/// Hashes every byte handed to the client, padding included, and finishes a stream that ends
+/// cleanly with the digest: as a trailer when the client accepts trailers, otherwise in the log
+/// next to the request ID. Streams that fail get no digest, since the client saw a partial body.
+pub(crate) struct DigestStream<S> {
+    inner: S,
+    hasher: Option<Sha256>,
+    send_trailer: bool,
+    request_id: String,
+}
+
+impl<S> DigestStream<S> {
+    pub(crate) fn new(inner: S, request_context: &RequestContext) -> Self {
+        Self {
+            inner,
+            hasher: Some(Sha256::new()),
+            send_trailer: request_context.accepts_trailers,
+            request_id: request_context.request_id.clone(),
+        }
+    }
+}
+
+impl<S: Stream<Item = Result<Bytes, std::io::Error>> + Unpin> Stream for DigestStream<S> {
+    type Item = Result<Frame<Bytes>, std::io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let Some(hasher) = this.hasher.as_mut() else {
+            return Poll::Ready(None);
+        };
+        match this.inner.poll_next_unpin(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Some(Ok(data))) => {
+                hasher.update(&data);
+                Poll::Ready(Some(Ok(Frame::data(data))))
+            }
+            Poll::Ready(Some(Err(e))) => {
+                this.hasher = None;
+                Poll::Ready(Some(Err(e)))
+            }
+            Poll::Ready(None) => {
+                let digest = format!("{:x}", this.hasher.take().unwrap().finalize());
+                if !this.send_trailer {
+                    tracing::info!(request_id = %this.request_id, sha256 = %digest, "Response body digest");
+                    return Poll::Ready(None);
+                }
+                let mut trailers = HeaderMap::new();
+                // A hex string is always a valid header value
+                trailers.insert(DIGEST_TRAILER, HeaderValue::from_str(&digest).unwrap());
+                Poll::Ready(Some(Ok(Frame::trailers(trailers))))
+            }
+        }
+    }
+}
+
+/// Builds the `initial_flush_padding` preamble: an HTML comment of the configured size, so it is
+/// only emitted for `text/html` responses.
+fn initial_flush_padding(prelude: &MetadataPrelude, size: usize) -> Option<Bytes> {
+    const OPEN: &str = "<!--";
+    const CLOSE: &str = "-->\n";
+    let is_html = prelude
+        .headers
+        .valid
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim_start().to_ascii_lowercase().starts_with("text/html"));
+    if size == 0 || !is_html {
+        return None;
+    }
+    let spaces = size.saturating_sub(OPEN.len() + CLOSE.len());
+    Some(Bytes::from(format!("{}{}{}", OPEN, " ".repeat(spaces), CLOSE)))
+}
+
+async fn detect_metadata(payload: &mut PayloadStream) -> Result<(bool, Option<Vec<u8>>), StreamError> {
+    if let Some(bytes) = payload.next().await.transpose()? {
+        let has_metadata = !bytes.is_empty() && bytes[0] == b'{';
+        return Ok((has_metadata, Some(bytes.to_vec())));
+    }
+    Ok((false, None))
+}
+
+async fn collect_metadata(
+    payload: &mut PayloadStream,
+    metadata_buffer: &mut Vec<u8>,
+) -> Result<(Option<MetadataPrelude>, Vec<u8>), StreamError> {
+    // Process the metadata_buffer first
+    let (prelude, remaining) = process_buffer(metadata_buffer);
+    if let Some(p) = prelude {
+        return Ok((Some(p), remaining));
+    }
+
+    // If metadata is not complete, continue processing the stream
+    while let Some(bytes) = payload.next().await.transpose()? {
+        metadata_buffer.extend_from_slice(&bytes);
+        let (prelude, remaining) = process_buffer(metadata_buffer);
+        if let Some(p) = prelude {
+            return Ok((Some(p), remaining));
+        }
+    }
+    // No separator before the stream ended: what looked like a prelude was the body
+    Ok((None, std::mem::take(metadata_buffer)))
+}
+
+/// Whether `chunk` starts with a complete, well-formed response prelude. Only the first chunk is
+/// checked, which is where functions write their prelude.
+pub(crate) fn looks_like_prelude(chunk: &[u8]) -> bool {
+    if chunk.first() != Some(&b'{') {
+        return false;
+    }
+    let Some(end) = chunk.windows(8).position(|w| w == [0; 8]) else {
+        return false;
+    };
+    serde_json::from_slice::<MetadataPrelude>(&chunk[..end]).is_ok()
+}
+
+fn process_buffer(buffer: &[u8]) -> (Option<MetadataPrelude>, Vec<u8>) {
+    let mut null_count = 0;
+    for (i, &byte) in buffer.iter().enumerate() {
+        if byte == 0 {
+            null_count += 1;
+            if null_count == 8 {
+                // The prelude JSON ends where the run of eight NUL separator bytes starts
+                let metadata_str = String::from_utf8_lossy(&buffer[..i + 1 - 8]);
+                let metadata_prelude = serde_json::from_str(&metadata_str).unwrap_or_default();
+                tracing::debug!(metadata_prelude=?metadata_prelude);
+                // Save remaining data after metadata
+                let remaining_data = buffer[i + 1..].to_vec();
+                return (Some(metadata_prelude), remaining_data);
+            }
+        } else {
+            null_count = 0;
+        }
+    }
+    (None, Vec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    include!("streaming_tests.rs");
+}
@@ -0,0 +1,680 @@
+use super::*;
+use std::time::Duration;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+const PRELUDE: &str = r#"{"statusCode": 200, "headers": {"content-type": "text/html"}, "cookies": ["a=1"]}"#;
+
+fn telemetry() -> Telemetry {
+    Telemetry::new(&Default::default())
+}
+
+fn channel_stream() -> (mpsc::UnboundedSender<Result<Bytes, StreamError>>, PayloadStream) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    (tx, UnboundedReceiverStream::new(rx).boxed())
+}
+
+fn prelude_chunk(prelude: &str) -> Bytes {
+    let mut chunk = prelude.as_bytes().to_vec();
+    chunk.extend_from_slice(&[0u8; 8]);
+    Bytes::from(chunk)
+}
+
+#[test]
+fn test_process_buffer() {
+    let payload = r#"{"statusCode": 200, "headers": {"Content-Type": "text/plain"}, "cookies": []}"#;
+    let null_padding = vec![0u8; 8];
+    let remaining_data = b"Remaining data";
+
+    let mut buffer = payload.as_bytes().to_vec();
+    buffer.extend_from_slice(&null_padding);
+    buffer.extend_from_slice(remaining_data);
+
+    let (metadata_prelude, remaining) = process_buffer(&buffer);
+
+    assert!(metadata_prelude.is_some());
+    let prelude = metadata_prelude.unwrap();
+    assert_eq!(prelude.status_code, StatusCode::OK);
+    assert_eq!(prelude.headers.valid.get("content-type").unwrap(), "text/plain");
+    assert_eq!(remaining, remaining_data);
+}
+
+#[test]
+fn test_process_buffer_incomplete() {
+    let (metadata_prelude, remaining) = process_buffer(br#"{"statusCode": 200"#);
+    assert!(metadata_prelude.is_none());
+    assert!(remaining.is_empty());
+}
+
+#[tokio::test]
+async fn test_collect_metadata_across_chunks() {
+    let (tx, mut payload) = channel_stream();
+    let chunk = prelude_chunk(PRELUDE);
+    let (head, tail) = chunk.split_at(10);
+    tx.send(Ok(Bytes::copy_from_slice(tail))).unwrap();
+    tx.send(Ok(Bytes::from_static(b"body"))).unwrap();
+
+    let mut buffer = head.to_vec();
+    let (prelude, remaining) = collect_metadata(&mut payload, &mut buffer).await.unwrap();
+
+    assert_eq!(prelude.unwrap().cookies, vec!["a=1"]);
+    assert!(remaining.is_empty());
+}
+
+#[tokio::test]
+async fn test_function_error_within_prelude_fails_the_response() {
+    let (tx, payload) = channel_stream();
+    tx.send(Ok(Bytes::from_static(br#"{"statusCode": 2"#))).unwrap();
+    let failure = StreamError::Function {
+        kind: "Unhandled".to_string(),
+        details: r#"{"errorMessage": "boom"}"#.to_string(),
+    };
+    tx.send(Err(failure)).unwrap();
+
+    let target = Target::default();
+    let err = handle_streaming_response(payload, &target, &telemetry(), &RequestContext::default(), Instant::now())
+        .await
+        .unwrap_err();
+
+    let StreamStartError::Function { kind, details } = err else {
+        panic!("expected a function error, got {:?}", err);
+    };
+    assert_eq!(kind, "Unhandled");
+    assert_eq!(details, r#"{"errorMessage": "boom"}"#);
+}
+
+#[tokio::test]
+async fn test_transport_error_before_any_chunk_fails_the_response() {
+    let (tx, payload) = channel_stream();
+    tx.send(Err(StreamError::Transport("connection reset".to_string()))).unwrap();
+
+    let target = Target::default();
+    let err = handle_streaming_response(payload, &target, &telemetry(), &RequestContext::default(), Instant::now())
+        .await
+        .unwrap_err();
+
+    let StreamStartError::Gateway(err) = err else {
+        panic!("expected a gateway error, got {:?}", err);
+    };
+    assert_eq!((err.status, err.code), (StatusCode::BAD_GATEWAY, "invoke_failed"));
+    assert!(err.message.contains("connection reset"));
+}
+
+#[tokio::test]
+async fn test_headers_flushed_before_first_body_chunk() {
+    let (tx, payload) = channel_stream();
+    tx.send(Ok(prelude_chunk(PRELUDE))).unwrap();
+
+    // No body chunk has been produced yet, but the response head must already be available.
+    let response = tokio::time::timeout(
+        Duration::from_secs(1),
+        handle_streaming_response(
+            payload,
+            &Target::default(),
+            &telemetry(),
+            &RequestContext::default(),
+            Instant::now(),
+        ),
+    )
+    .await
+    .expect("response head should not wait for the first body chunk")
+    .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get("content-type").unwrap(), "text/html");
+    assert_eq!(response.headers().get("set-cookie").unwrap(), "a=1");
+
+    tx.send(Ok(Bytes::from_static(b"<html>"))).unwrap();
+    drop(tx);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, "<html>");
+}
+
+#[tokio::test]
+async fn test_initial_flush_padding_for_html() {
+    let (tx, payload) = channel_stream();
+    tx.send(Ok(prelude_chunk(PRELUDE))).unwrap();
+    tx.send(Ok(Bytes::from_static(b"<html>"))).unwrap();
+    drop(tx);
+
+    let target = Target {
+        initial_flush_padding: 2048,
+        ..Default::default()
+    };
+    let response = handle_streaming_response(payload, &target, &telemetry(), &RequestContext::default(), Instant::now())
+        .await
+        .unwrap();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+    assert_eq!(body.len(), 2048 + "<html>".len());
+    assert!(body.starts_with(b"<!--"));
+    assert!(body.ends_with(b"-->\n<html>"));
+}
+
+#[tokio::test]
+async fn test_initial_flush_padding_skipped_for_non_html() {
+    let (tx, payload) = channel_stream();
+    tx.send(Ok(prelude_chunk(
+        r#"{"statusCode": 200, "headers": {"content-type": "application/json"}}"#,
+    )))
+    .unwrap();
+    tx.send(Ok(Bytes::from_static(b"{}"))).unwrap();
+    drop(tx);
+
+    let target = Target {
+        initial_flush_padding: 2048,
+        ..Default::default()
+    };
+    let response = handle_streaming_response(payload, &target, &telemetry(), &RequestContext::default(), Instant::now())
+        .await
+        .unwrap();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, "{}");
+}
+
+#[tokio::test]
+async fn test_stream_without_metadata() {
+    let (tx, payload) = channel_stream();
+    tx.send(Ok(Bytes::from_static(b"raw bytes"))).unwrap();
+    drop(tx);
+
+    let response = handle_streaming_response(
+        payload,
+        &Target::default(),
+        &telemetry(),
+        &RequestContext::default(),
+        Instant::now(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "application/octet-stream"
+    );
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, "raw bytes");
+}
+
+#[tokio::test]
+async fn test_lag_tracking_passes_through_timely_chunks() {
+    let target = Target {
+        max_client_lag_ms: Some(50),
+        max_client_lag_duration_ms: 0,
+        ..Default::default()
+    };
+    let chunks = vec![
+        (Instant::now(), Ok(Bytes::from_static(b"a"))),
+        (Instant::now(), Ok(Bytes::from_static(b"b"))),
+    ];
+    let mut stream = LagTrackingStream::new(futures::stream::iter(chunks), &target, telemetry());
+
+    assert_eq!(stream.next().await.unwrap().unwrap(), "a");
+    assert_eq!(stream.next().await.unwrap().unwrap(), "b");
+    assert!(stream.next().await.is_none());
+    assert_eq!(stream.termination, Some(TerminationReason::Completed));
+    assert_eq!(stream.chunks, 2);
+}
+
+#[tokio::test]
+async fn test_lag_tracking_aborts_after_sustained_lag() {
+    let target = Target {
+        max_client_lag_ms: Some(10),
+        max_client_lag_duration_ms: 30,
+        ..Default::default()
+    };
+    let stale = || (Instant::now() - Duration::from_millis(100), Ok(Bytes::from_static(b"x")));
+    let chunks = vec![stale(), stale(), stale()];
+    let mut stream = LagTrackingStream::new(futures::stream::iter(chunks), &target, telemetry());
+
+    // The first lagging chunk only starts the clock.
+    assert!(stream.next().await.unwrap().is_ok());
+    tokio::time::sleep(Duration::from_millis(40)).await;
+    assert!(stream.next().await.unwrap().is_err());
+    assert!(stream.next().await.is_none());
+    assert_eq!(stream.termination, Some(TerminationReason::ClientLagExceeded));
+    assert!(stream.max_seen_lag >= Duration::from_millis(100));
+}
+
+#[tokio::test]
+async fn test_lag_recovery_resets_abort_clock() {
+    let target = Target {
+        max_client_lag_ms: Some(10),
+        max_client_lag_duration_ms: 30,
+        ..Default::default()
+    };
+    let stale = || (Instant::now() - Duration::from_millis(100), Ok(Bytes::from_static(b"x")));
+    let chunks = vec![stale(), (Instant::now() + Duration::from_secs(1), Ok(Bytes::from_static(b"y"))), stale()];
+    let mut stream = LagTrackingStream::new(futures::stream::iter(chunks), &target, telemetry());
+
+    assert!(stream.next().await.unwrap().is_ok());
+    tokio::time::sleep(Duration::from_millis(40)).await;
+    assert!(stream.next().await.unwrap().is_ok());
+    assert!(stream.next().await.unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn test_slow_consumer_is_aborted() {
+    let (tx, payload) = channel_stream();
+    for _ in 0..10 {
+        tx.send(Ok(Bytes::from_static(b"chunk"))).unwrap();
+    }
+    drop(tx);
+
+    let target = Target {
+        max_client_lag_ms: Some(5),
+        max_client_lag_duration_ms: 20,
+        ..Default::default()
+    };
+    let response = handle_streaming_response(payload, &target, &telemetry(), &RequestContext::default(), Instant::now())
+        .await
+        .unwrap();
+    let mut body = response.into_body().into_data_stream();
+
+    let mut saw_error = false;
+    while let Some(chunk) = body.next().await {
+        if chunk.is_err() {
+            saw_error = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(15)).await;
+    }
+    assert!(saw_error);
+}
+
+#[tokio::test]
+async fn test_stalled_consumer_is_aborted() {
+    let (tx, payload) = channel_stream();
+    let target = Target {
+        max_client_lag_ms: Some(20),
+        max_client_lag_duration_ms: 30,
+        ..Default::default()
+    };
+    let response = handle_streaming_response(payload, &target, &telemetry(), &RequestContext::default(), Instant::now())
+        .await
+        .unwrap();
+    let mut body = response.into_body().into_data_stream();
+    tx.send(Ok(Bytes::from_static(b"first"))).unwrap();
+    assert_eq!(body.next().await.unwrap().unwrap(), "first");
+
+    // The client stops reading altogether while the function keeps sending
+    let started = Instant::now();
+    while tx.send(Ok(Bytes::from_static(b"chunk"))).is_ok() {
+        assert!(started.elapsed() < Duration::from_secs(2), "the stream was never aborted");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // Nothing stopped before the lag limit and its duration had passed
+    assert!(started.elapsed() >= Duration::from_millis(50));
+
+    // The client learns the stream failed once it reads again
+    let mut saw_error = false;
+    while let Some(chunk) = body.next().await {
+        saw_error |= chunk.is_err();
+    }
+    assert!(saw_error);
+}
+
+#[tokio::test]
+async fn test_max_stream_duration_aborts_trickling_stream() {
+    let (tx, payload) = channel_stream();
+    let trickle = tokio::spawn(async move {
+        // Stops once the gateway has dropped the payload, which releases the invoke
+        while tx.send(Ok(Bytes::from_static(b"."))).is_ok() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    });
+    let target = Target {
+        max_stream_duration_ms: Some(200),
+        ..Default::default()
+    };
+    // The cap counts from dispatch, so time spent before the response started is included
+    let dispatched_at = Instant::now() - Duration::from_millis(50);
+    let response = handle_streaming_response(payload, &target, &telemetry(), &RequestContext::default(), dispatched_at)
+        .await
+        .unwrap();
+    let mut body = response.into_body().into_data_stream();
+
+    let mut chunks = 0;
+    let error = loop {
+        match body.next().await.unwrap() {
+            Ok(_) => chunks += 1,
+            Err(e) => break e,
+        }
+    };
+    let elapsed = dispatched_at.elapsed();
+    assert!(elapsed >= Duration::from_millis(200) && elapsed < Duration::from_millis(1000), "{:?}", elapsed);
+    assert!(chunks >= 3, "{}", chunks);
+    assert!(error.to_string().contains("ran longer than 200 ms"), "{}", error);
+    tokio::time::timeout(Duration::from_secs(1), trickle).await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_idle_timeout_aborts_stalled_stream() {
+    let (tx, payload) = channel_stream();
+    tx.send(Ok(prelude_chunk(PRELUDE))).unwrap();
+    let target = Target {
+        idle_timeout_ms: Some(150),
+        ..Default::default()
+    };
+    let started = Instant::now();
+    let response = handle_streaming_response(payload, &target, &telemetry(), &RequestContext::default(), started)
+        .await
+        .unwrap();
+    let mut body = response.into_body().into_data_stream();
+
+    // Chunks closer together than the timeout keep the stream going past it
+    for chunk in ["a", "b", "c"] {
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        tx.send(Ok(Bytes::from(chunk))).unwrap();
+        assert_eq!(body.next().await.unwrap().unwrap(), chunk);
+    }
+    let stalled_at = Instant::now();
+    let error = body.next().await.unwrap().unwrap_err();
+    let stalled = stalled_at.elapsed();
+    assert!(stalled >= Duration::from_millis(100) && stalled < Duration::from_millis(1000), "{:?}", stalled);
+    assert!(started.elapsed() >= Duration::from_millis(240));
+    assert!(error.to_string().contains("sent nothing for 150 ms"), "{}", error);
+    assert!(body.next().await.is_none());
+    // The payload is dropped, which releases the invoke
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(tx.send(Ok(Bytes::from_static(b"late"))).is_err());
+}
+
+#[tokio::test]
+async fn test_lag_tracking_records_idle_termination() {
+    let chunks = vec![(Instant::now(), Err(std::io::Error::other(IdleTimeout(100))))];
+    let mut stream = LagTrackingStream::new(futures::stream::iter(chunks), &Target::default(), telemetry());
+
+    assert!(stream.next().await.unwrap().is_err());
+    assert_eq!(stream.termination, Some(TerminationReason::IdleTimeout));
+}
+
+#[tokio::test]
+async fn test_lag_tracking_records_max_duration_termination() {
+    let error = std::io::Error::other(MaxDurationExceeded(100));
+    let chunks = vec![(Instant::now(), Ok(Bytes::from_static(b"a"))), (Instant::now(), Err(error))];
+    let mut stream = LagTrackingStream::new(futures::stream::iter(chunks), &Target::default(), telemetry());
+
+    assert!(stream.next().await.unwrap().is_ok());
+    assert!(stream.next().await.unwrap().is_err());
+    assert!(stream.next().await.is_none());
+    assert_eq!(stream.termination, Some(TerminationReason::MaxDurationExceeded));
+}
+
+#[tokio::test]
+async fn test_invalid_prelude_headers_dropped() {
+    let (tx, payload) = channel_stream();
+    tx.send(Ok(prelude_chunk(
+        r#"{"statusCode": 200, "headers": {"x-bad": "a\r\nInjected: 1", "x-good": "ok"}, "cookies": ["b=\n"]}"#,
+    )))
+    .unwrap();
+    drop(tx);
+
+    let response = handle_streaming_response(
+        payload,
+        &Target::default(),
+        &telemetry(),
+        &RequestContext::default(),
+        Instant::now(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["x-good"], "ok");
+    assert!(response.headers().get("x-bad").is_none());
+    assert!(response.headers().get("injected").is_none());
+    assert!(response.headers().get("set-cookie").is_none());
+}
+
+#[tokio::test]
+async fn test_invalid_prelude_headers_strict() {
+    let (tx, payload) = channel_stream();
+    tx.send(Ok(prelude_chunk(r#"{"statusCode": 200, "headers": {"x-bad": "a\nb"}}"#)))
+        .unwrap();
+    let target = Target {
+        strict_upstream_headers: true,
+        ..Default::default()
+    };
+
+    let err = handle_streaming_response(payload, &target, &telemetry(), &RequestContext::default(), Instant::now())
+        .await
+        .unwrap_err();
+
+    let StreamStartError::Gateway(err) = err else {
+        panic!("expected a gateway error, got {:?}", err);
+    };
+    assert_eq!(err.status, StatusCode::BAD_GATEWAY);
+    assert_eq!(err.code, "invalid_upstream_header");
+    assert!(err.message.contains("x-bad"));
+}
+
+fn digest_target() -> Target {
+    Target {
+        body_digest_trailer: true,
+        ..Default::default()
+    }
+}
+
+fn trailer_context() -> RequestContext {
+    RequestContext {
+        request_id: "req-1".to_string(),
+        accepts_trailers: true,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_digest_trailer_matches_known_hash() {
+    let (tx, payload) = channel_stream();
+    tx.send(Ok(Bytes::from_static(b"a"))).unwrap();
+    tx.send(Ok(Bytes::from_static(b"bc"))).unwrap();
+    drop(tx);
+
+    let response = handle_streaming_response(
+        payload,
+        &digest_target(),
+        &telemetry(),
+        &trailer_context(),
+        Instant::now(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(response.headers()["trailer"], DIGEST_TRAILER);
+
+    let collected = http_body_util::BodyExt::collect(response.into_body()).await.unwrap();
+    assert_eq!(
+        collected.trailers().unwrap()[DIGEST_TRAILER],
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    assert_eq!(collected.to_bytes(), "abc");
+}
+
+#[tokio::test]
+async fn test_digest_trailer_covers_padding() {
+    let (tx, payload) = channel_stream();
+    tx.send(Ok(prelude_chunk(PRELUDE))).unwrap();
+    tx.send(Ok(Bytes::from_static(b"<html>"))).unwrap();
+    tx.send(Ok(Bytes::from_static(b"</html>"))).unwrap();
+    drop(tx);
+
+    let target = Target {
+        initial_flush_padding: 512,
+        ..digest_target()
+    };
+    let response = handle_streaming_response(payload, &target, &telemetry(), &trailer_context(), Instant::now())
+        .await
+        .unwrap();
+
+    let collected = http_body_util::BodyExt::collect(response.into_body()).await.unwrap();
+    let trailer = collected.trailers().unwrap()[DIGEST_TRAILER].clone();
+    let body = collected.to_bytes();
+    assert_eq!(body.len(), 512 + "<html></html>".len());
+    assert_eq!(trailer, format!("{:x}", Sha256::digest(&body)));
+}
+
+#[tokio::test]
+async fn test_digest_without_te_trailers_is_logged_only() {
+    let (tx, payload) = channel_stream();
+    tx.send(Ok(Bytes::from_static(b"abc"))).unwrap();
+    drop(tx);
+
+    let context = RequestContext {
+        accepts_trailers: false,
+        ..trailer_context()
+    };
+    let response = handle_streaming_response(payload, &digest_target(), &telemetry(), &context, Instant::now())
+        .await
+        .unwrap();
+    assert!(response.headers().get("trailer").is_none());
+
+    let collected = http_body_util::BodyExt::collect(response.into_body()).await.unwrap();
+    assert!(collected.trailers().is_none());
+    assert_eq!(collected.to_bytes(), "abc");
+}
+
+#[tokio::test]
+async fn test_digest_trailer_omitted_after_upstream_error() {
+    let (tx, payload) = channel_stream();
+    tx.send(Ok(Bytes::from_static(b"abc"))).unwrap();
+    tx.send(Err(StreamError::Transport("boom".to_string()))).unwrap();
+
+    let response = handle_streaming_response(
+        payload,
+        &digest_target(),
+        &telemetry(),
+        &trailer_context(),
+        Instant::now(),
+    )
+    .await
+    .unwrap();
+
+    let mut body = response.into_body();
+    let mut trailers = None;
+    while let Some(frame) = http_body_util::BodyExt::frame(&mut body).await {
+        match frame {
+            Ok(frame) => trailers = trailers.or(frame.into_trailers().ok()),
+            Err(_) => break,
+        }
+    }
+    assert!(trailers.is_none());
+}
+
+#[test]
+fn test_looks_like_prelude() {
+    assert!(looks_like_prelude(&prelude_chunk(PRELUDE)));
+    assert!(looks_like_prelude(&[&prelude_chunk(PRELUDE)[..], b"body"].concat()));
+    // A JSON body has no separator, and a separator after other JSON is not a prelude
+    assert!(!looks_like_prelude(br#"{"statusCode": 200}"#));
+    assert!(!looks_like_prelude(&prelude_chunk(r#"{"items": []}"#)));
+    assert!(!looks_like_prelude(b"<html></html>"));
+    assert!(!looks_like_prelude(b""));
+}
+
+async fn read_as(stream_format: StreamFormat, chunks: Vec<Bytes>) -> (Response, Bytes) {
+    let (tx, payload) = channel_stream();
+    for chunk in chunks {
+        tx.send(Ok(chunk)).unwrap();
+    }
+    drop(tx);
+    let target = Target {
+        stream_format,
+        ..Default::default()
+    };
+    let mut response = handle_streaming_response(
+        payload,
+        &target,
+        &telemetry(),
+        &RequestContext::default(),
+        Instant::now(),
+    )
+    .await
+    .unwrap();
+    let body = std::mem::take(response.body_mut());
+    (response, axum::body::to_bytes(body, usize::MAX).await.unwrap())
+}
+
+fn detected(response: &Response) -> Option<StreamFormat> {
+    response.extensions().get::<DetectedStreamFormat>().map(|d| d.0)
+}
+
+#[tokio::test]
+async fn test_prelude_sent_to_raw_target_is_detected() {
+    let chunks = vec![prelude_chunk(PRELUDE), Bytes::from_static(b"body")];
+    let (response, body) = read_as(StreamFormat::Raw, chunks.clone()).await;
+    // Read as configured, but flagged
+    assert_eq!(detected(&response), Some(StreamFormat::Prelude));
+    assert_eq!(body, [&chunks[0][..], b"body"].concat());
+
+    let (response, body) = read_as(StreamFormat::Prelude, chunks).await;
+    assert_eq!(detected(&response), None);
+    assert_eq!(response.headers()["content-type"], "text/html");
+    assert_eq!(body, "body");
+}
+
+#[tokio::test]
+async fn test_raw_body_sent_to_prelude_target_is_served_as_body() {
+    for body in ["<html></html>", r#"{"items": []}"#] {
+        let chunks = vec![Bytes::from(body), Bytes::from_static(b"!")];
+        let (response, received) = read_as(StreamFormat::Prelude, chunks.clone()).await;
+        assert_eq!(detected(&response), Some(StreamFormat::Raw), "{}", body);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(received, format!("{}!", body));
+
+        let (response, _) = read_as(StreamFormat::Raw, chunks).await;
+        assert_eq!(detected(&response), None, "{}", body);
+    }
+}
+
+#[tokio::test]
+async fn test_ndjson_records_forwarded_whole() {
+    let (tx, payload) = channel_stream();
+    tx.send(Ok(prelude_chunk(r#"{"statusCode": 200}"#))).unwrap();
+    for chunk in ["{\"a\":", "1}\n{\"a\"", ":2}\nbroken\n{\"a\":3}"] {
+        tx.send(Ok(Bytes::from(chunk))).unwrap();
+    }
+    drop(tx);
+
+    let ndjson = crate::config::NdjsonConfig::default();
+    let context = RequestContext {
+        records: Some(RecordStream::new(&ndjson, Default::default())),
+        ..Default::default()
+    };
+    let response = handle_streaming_response(payload, &Target::default(), &telemetry(), &context, Instant::now())
+        .await
+        .unwrap();
+    let mut body = response.into_body().into_data_stream();
+    // Records split across chunks arrive whole, each in one chunk
+    let mut chunks = Vec::new();
+    while let Some(chunk) = body.next().await {
+        chunks.push(chunk.unwrap());
+    }
+    assert_eq!(chunks, ["{\"a\":1}\n", "{\"a\":2}\n", "{\"a\":3}\n"]);
+}
+
+#[tokio::test]
+async fn test_ndjson_invalid_record_aborts_stream() {
+    let (tx, payload) = channel_stream();
+    tx.send(Ok(prelude_chunk(r#"{"statusCode": 200}"#))).unwrap();
+    tx.send(Ok(Bytes::from_static(b"{\"a\":1}\n{\"a\":"))).unwrap();
+    tx.send(Ok(Bytes::from_static(b"2}\n{oops}\n{\"a\":3}\n"))).unwrap();
+
+    let ndjson = crate::config::NdjsonConfig {
+        on_invalid: crate::config::OnInvalidRecord::Abort,
+        ..Default::default()
+    };
+    let context = RequestContext {
+        records: Some(RecordStream::new(&ndjson, Default::default())),
+        ..Default::default()
+    };
+    let response = handle_streaming_response(payload, &Target::default(), &telemetry(), &context, Instant::now())
+        .await
+        .unwrap();
+    let mut body = response.into_body().into_data_stream();
+    assert_eq!(body.next().await.unwrap().unwrap(), "{\"a\":1}\n");
+    assert_eq!(body.next().await.unwrap().unwrap(), "{\"a\":2}\n");
+    let error = body.next().await.unwrap().unwrap_err();
+    assert!(error.to_string().starts_with("stream aborted: record 3 is not valid JSON"), "{}", error);
+    assert!(body.next().await.is_none());
+    // The payload is dropped, which releases the invoke
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(tx.send(Ok(Bytes::from_static(b"late"))).is_err());
+}
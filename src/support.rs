@@ -0,0 +1,239 @@
+use crate::access_log::now_ms;
+use crate::config::Config;
+use crate::error::ErrorPhase;
+use crate::health::ProbeResult;
+use crate::routes::{RouteConflict, RouteEntry, RouteRegistry};
+use crate::telemetry::MetricsRegistry;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// Replacement for every value redacted from the support bundle.
+pub const REDACTED: &str = "[redacted]";
+
+/// Field name fragments that mark a config value as secret, whatever section it lives in.
+const SECRET_FIELD_MARKERS: &[&str] = &["key", "secret", "token", "password", "passwd", "credential", "private"];
+
+/// Failed requests kept for the support bundle.
+pub const RECENT_ERRORS: usize = 200;
+/// Reloads kept for the support bundle.
+pub const RECENT_RELOADS: usize = 10;
+
+/// A bounded log of the most recent entries, oldest first.
+#[derive(Clone)]
+pub struct RecentLog<T> {
+    entries: Arc<Mutex<VecDeque<T>>>,
+    capacity: usize,
+}
+
+impl<T: Clone> RecentLog<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    pub fn push(&self, entry: T) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        if self.capacity > 0 {
+            entries.push_back(entry);
+        }
+    }
+
+    pub fn entries(&self) -> Vec<T> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// A request the gateway failed, as kept by the flight recorder.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FailedRequest {
+    pub timestamp_ms: u128,
+    pub method: String,
+    pub path: String,
+    pub target: String,
+    pub status: u16,
+    pub phase: Option<ErrorPhase>,
+    pub error_code: Option<&'static str>,
+    pub upstream: Option<String>,
+}
+
+/// What a reload changed, by name only so that no secret values are kept.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ReloadDiff {
+    pub timestamp_ms: u128,
+    pub targets_added: Vec<String>,
+    pub targets_removed: Vec<String>,
+    pub targets_changed: Vec<String>,
+    /// Top-level settings other than `targets` whose value changed.
+    pub settings_changed: Vec<String>,
+}
+
+impl ReloadDiff {
+    pub fn new(old: &Config, new: &Config) -> Self {
+        let (old_targets, new_targets) = (&old.targets, &new.targets);
+        let mut diff = ReloadDiff {
+            timestamp_ms: now_ms(),
+            ..Default::default()
+        };
+        for (pattern, target) in new_targets {
+            match old_targets.get(pattern) {
+                None => diff.targets_added.push(pattern.clone()),
+                Some(old) if old != target => diff.targets_changed.push(pattern.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.targets_removed = old_targets
+            .keys()
+            .filter(|pattern| !new_targets.contains_key(*pattern))
+            .cloned()
+            .collect();
+
+        let (old, new) = (config_fields(old), config_fields(new));
+        let names: std::collections::BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+        diff.settings_changed = names
+            .into_iter()
+            .filter(|name| *name != "targets" && old.get(*name) != new.get(*name))
+            .cloned()
+            .collect();
+        diff
+    }
+}
+
+fn config_fields(config: &Config) -> serde_json::Map<String, Value> {
+    match serde_json::to_value(config) {
+        Ok(Value::Object(fields)) => fields,
+        _ => Default::default(),
+    }
+}
+
+/// The config as the support bundle shows it, with every secret field redacted.
+pub fn redacted_config(config: &Config) -> Value {
+    let mut value = serde_json::to_value(config).unwrap_or(Value::Null);
+    redact(&mut value);
+    value
+}
+
+/// Replaces the value of every field whose name marks it as secret, at any depth. Matching is by
+/// field name rather than by a list of known fields, so a section added later is covered without
+/// changes here. Target patterns are map keys, not field names, and are never treated as secret.
+/// Credentials embedded in URLs, such as `redis://:password@host`, are redacted wherever they are.
+pub fn redact(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, value) in fields.iter_mut() {
+                if is_secret_field(name) {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        Value::String(text) => {
+            if let Some(url) = redact_url_credentials(text) {
+                *text = url;
+            }
+        }
+        _ => {}
+    }
+}
+
+fn redact_url_credentials(text: &str) -> Option<String> {
+    let mut url = url::Url::parse(text).ok()?;
+    if url.password().is_none() && url.username().is_empty() {
+        return None;
+    }
+    url.set_username(REDACTED).ok()?;
+    url.set_password(None).ok()?;
+    Some(url.to_string())
+}
+
+fn is_secret_field(name: &str) -> bool {
+    if name.starts_with('/') {
+        return false;
+    }
+    let name = name.to_ascii_lowercase();
+    SECRET_FIELD_MARKERS.iter().any(|marker| name.contains(marker))
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub os: &'static str,
+    pub arch: &'static str,
+    pub debug: bool,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            os: std::env::consts::OS,
+            arch: std::env::consts::ARCH,
+            debug: cfg!(debug_assertions),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CounterSnapshot {
+    pub name: &'static str,
+    pub labels: BTreeMap<&'static str, String>,
+    pub value: u64,
+}
+
+/// Everything usually asked for when a problem is reported, in one JSON document.
+#[derive(Clone, Debug, Serialize)]
+pub struct SupportBundle {
+    pub generated_at_ms: u128,
+    pub build: BuildInfo,
+    pub config: Value,
+    pub routes: Vec<RouteEntry>,
+    pub route_conflicts: Vec<RouteConflict>,
+    pub recent_errors: Vec<FailedRequest>,
+    pub counters: Vec<CounterSnapshot>,
+    pub reloads: Vec<ReloadDiff>,
+    pub health: BTreeMap<String, ProbeResult>,
+}
+
+impl SupportBundle {
+    /// A bundle with the parts that only need the config; runtime sections start empty.
+    pub fn new(config: &Config) -> Self {
+        let registry = RouteRegistry::new(config);
+        Self {
+            generated_at_ms: now_ms(),
+            build: BuildInfo::current(),
+            config: redacted_config(config),
+            routes: registry.routes,
+            route_conflicts: registry.conflicts,
+            recent_errors: Vec::new(),
+            counters: Vec::new(),
+            reloads: Vec::new(),
+            health: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_counters(mut self, metrics: &MetricsRegistry) -> Self {
+        self.counters = metrics
+            .counters
+            .iter()
+            .map(|((name, labels), value)| CounterSnapshot {
+                name,
+                labels: labels.iter().cloned().collect(),
+                value: *value,
+            })
+            .collect();
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("support_tests.rs");
+}
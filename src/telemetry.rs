@@ -0,0 +1,286 @@
+use crate::config::TelemetryConfig;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+/// Upper bounds (milliseconds) of the histogram buckets kept by the registry.
+pub const LATENCY_BUCKETS_MS: [f64; 11] = [
+    5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0,
+];
+
+/// Upper bounds (bytes) of the buckets of size histograms, up to Lambda's 6 MB payload limit.
+pub const SIZE_BUCKETS_BYTES: [f64; 11] = [
+    256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 524288.0, 1048576.0, 2097152.0, 4194304.0, 6291456.0,
+];
+
+const WARN_INTERVAL_SECS: u64 = 10;
+
+pub type Labels = Vec<(&'static str, String)>;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Histogram {
+    /// Upper bounds of the buckets, [`LATENCY_BUCKETS_MS`] unless recorded with other bounds.
+    pub bounds: &'static [f64],
+    /// Cumulative count of observations up to each bound.
+    pub buckets: Vec<u64>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64, bounds: &'static [f64]) {
+        if self.buckets.is_empty() {
+            self.bounds = bounds;
+            self.buckets = vec![0; bounds.len()];
+        }
+        for (bucket, bound) in self.buckets.iter_mut().zip(self.bounds) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// Estimates the `q` quantile (0 to 1) by interpolating within the bucket it falls in, as
+    /// Prometheus' `histogram_quantile` does. Values past the last bound are reported as it.
+    pub fn percentile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = q.clamp(0.0, 1.0) * self.count as f64;
+        let mut lower = (0.0, 0);
+        for (bound, cumulative) in self.bounds.iter().zip(&self.buckets) {
+            if *cumulative as f64 >= rank {
+                let (lower_bound, lower_count) = lower;
+                let in_bucket = (*cumulative - lower_count) as f64;
+                let fraction = if in_bucket == 0.0 {
+                    1.0
+                } else {
+                    (rank - lower_count as f64) / in_bucket
+                };
+                return Some(lower_bound + (bound - lower_bound) * fraction);
+            }
+            lower = (*bound, *cumulative);
+        }
+        self.bounds.last().copied()
+    }
+}
+
+pub type MetricKey = (&'static str, Labels);
+
+#[derive(Clone, Debug, Default)]
+pub struct MetricsRegistry {
+    pub counters: BTreeMap<MetricKey, u64>,
+    pub gauges: BTreeMap<MetricKey, i64>,
+    pub histograms: BTreeMap<MetricKey, Histogram>,
+}
+
+enum Event {
+    Counter(&'static str, Labels, u64),
+    Histogram(&'static str, Labels, f64, &'static [f64]),
+}
+
+struct Inner {
+    tx: mpsc::Sender<Event>,
+    registry: Mutex<MetricsRegistry>,
+    degraded: AtomicBool,
+    /// Set while the trace exporter's last export failed.
+    exports_failing: AtomicBool,
+    dropped: AtomicU64,
+    last_warn: AtomicU64,
+}
+
+/// Non-blocking facade in front of metric recording.
+///
+/// Every recording call is a `try_send` into a bounded queue drained by a background worker. When
+/// the queue is full, data is dropped and the facade reports itself as degraded until the worker
+/// has caught up; the request path never waits on observability. Gauges are the exception: they
+/// are adjusted in place, as a single lost delta would skew them for good. Spans the trace exporter
+/// fails to deliver degrade the facade too, see [`Telemetry::export_failed`].
+#[derive(Clone)]
+pub struct Telemetry {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TelemetryStatus {
+    pub observability_degraded: bool,
+    pub dropped_events: u64,
+}
+
+impl Telemetry {
+    pub fn new(config: &TelemetryConfig) -> Self {
+        let (tx, rx) = mpsc::channel(config.queue_capacity.max(1));
+        let telemetry = Telemetry {
+            inner: Arc::new(Inner {
+                tx,
+                registry: Mutex::new(MetricsRegistry::default()),
+                degraded: AtomicBool::new(false),
+                exports_failing: AtomicBool::new(false),
+                dropped: AtomicU64::new(0),
+                last_warn: AtomicU64::new(0),
+            }),
+        };
+        let worker = Worker {
+            telemetry: telemetry.clone(),
+        };
+        tokio::spawn(worker.run(rx));
+        telemetry
+    }
+
+    pub fn increment(&self, name: &'static str, labels: Labels) {
+        self.add(name, labels, 1);
+    }
+
+    pub fn add(&self, name: &'static str, labels: Labels, value: u64) {
+        self.send(Event::Counter(name, labels, value));
+    }
+
+    /// Adjusts a gauge by `delta`, bypassing the queue so that the delta is never dropped.
+    pub fn gauge(&self, name: &'static str, labels: Labels, delta: i64) {
+        let mut registry = self.inner.registry.lock().unwrap();
+        *registry.gauges.entry((name, labels)).or_default() += delta;
+    }
+
+    pub fn observe(&self, name: &'static str, labels: Labels, value: f64) {
+        self.observe_in(name, labels, value, &LATENCY_BUCKETS_MS);
+    }
+
+    /// Records `value` in a histogram with the given bucket bounds, such as [`SIZE_BUCKETS_BYTES`].
+    pub fn observe_in(&self, name: &'static str, labels: Labels, value: f64, bounds: &'static [f64]) {
+        self.send(Event::Histogram(name, labels, value, bounds));
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.inner.degraded.load(Ordering::Relaxed) || self.inner.exports_failing.load(Ordering::Relaxed)
+    }
+
+    /// Counts `dropped` spans the trace exporter could not deliver, degrading the facade until an
+    /// export succeeds again.
+    pub fn export_failed(&self, dropped: u64, reason: &str) {
+        self.inner.dropped.fetch_add(dropped, Ordering::Relaxed);
+        self.inner.exports_failing.store(true, Ordering::Relaxed);
+        self.warn_degraded(reason);
+    }
+
+    pub fn export_succeeded(&self) {
+        if self.inner.exports_failing.swap(false, Ordering::Relaxed) {
+            tracing::info!("Span export recovered");
+        }
+    }
+
+    pub fn status(&self) -> TelemetryStatus {
+        TelemetryStatus {
+            observability_degraded: self.is_degraded(),
+            dropped_events: self.inner.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsRegistry {
+        self.inner.registry.lock().unwrap().clone()
+    }
+
+    fn send(&self, event: Event) {
+        if self.inner.tx.try_send(event).is_err() {
+            self.inner.dropped.fetch_add(1, Ordering::Relaxed);
+            self.degrade("telemetry queue is full");
+        }
+    }
+
+    fn degrade(&self, reason: &str) {
+        self.inner.degraded.store(true, Ordering::Relaxed);
+        self.warn_degraded(reason);
+    }
+
+    /// Logs `reason`, at most once every [`WARN_INTERVAL_SECS`].
+    fn warn_degraded(&self, reason: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let last = self.inner.last_warn.load(Ordering::Relaxed);
+        if now.saturating_sub(last) >= WARN_INTERVAL_SECS
+            && self
+                .inner
+                .last_warn
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            tracing::warn!(
+                dropped_events = self.inner.dropped.load(Ordering::Relaxed),
+                "Observability degraded: {}. Dropping telemetry until the backend recovers.",
+                reason
+            );
+        }
+    }
+
+    fn recover(&self) {
+        if self.inner.degraded.swap(false, Ordering::Relaxed) {
+            tracing::info!("Observability recovered");
+        }
+    }
+}
+
+/// Records the milliseconds from its start to its drop in a histogram, for work that outlives the
+/// code starting it, such as sending a streamed response body.
+pub struct DropTimer {
+    telemetry: Telemetry,
+    name: &'static str,
+    labels: Labels,
+    started_at: Instant,
+}
+
+impl DropTimer {
+    pub fn new(telemetry: Telemetry, name: &'static str, labels: Labels, started_at: Instant) -> Self {
+        Self {
+            telemetry,
+            name,
+            labels,
+            started_at,
+        }
+    }
+}
+
+impl Drop for DropTimer {
+    fn drop(&mut self) {
+        let elapsed_ms = self.started_at.elapsed().as_secs_f64() * 1000.0;
+        self.telemetry
+            .observe(self.name, std::mem::take(&mut self.labels), elapsed_ms);
+    }
+}
+
+struct Worker {
+    telemetry: Telemetry,
+}
+
+impl Worker {
+    async fn run(self, mut rx: mpsc::Receiver<Event>) {
+        while let Some(event) = rx.recv().await {
+            self.apply(event);
+            if rx.len() < rx.max_capacity() / 2 {
+                self.telemetry.recover();
+            }
+        }
+    }
+
+    fn apply(&self, event: Event) {
+        let mut registry = self.telemetry.inner.registry.lock().unwrap();
+        match event {
+            Event::Counter(name, labels, value) => *registry.counters.entry((name, labels)).or_default() += value,
+            Event::Histogram(name, labels, value, bounds) => registry
+                .histograms
+                .entry((name, labels))
+                .or_default()
+                .observe(value, bounds),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("telemetry_tests.rs");
+}
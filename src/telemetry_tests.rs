@@ -0,0 +1,90 @@
+use super::*;
+use std::time::Duration;
+
+fn test_config() -> TelemetryConfig {
+    TelemetryConfig { queue_capacity: 16 }
+}
+
+#[tokio::test]
+async fn test_records_metrics() {
+    let telemetry = Telemetry::new(&TelemetryConfig::default());
+    telemetry.increment("requests_total", vec![("status", "200".to_string())]);
+    telemetry.increment("requests_total", vec![("status", "200".to_string())]);
+    telemetry.observe("request_duration_ms", vec![], 7.0);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let registry = telemetry.snapshot();
+    assert_eq!(
+        registry.counters[&("requests_total", vec![("status", "200".to_string())])],
+        2
+    );
+    let histogram = &registry.histograms[&("request_duration_ms", vec![])];
+    assert_eq!(histogram.count, 1);
+    assert_eq!(histogram.buckets[0], 0);
+    assert_eq!(histogram.buckets[1], 1);
+    assert!(!telemetry.is_degraded());
+}
+
+#[tokio::test]
+async fn test_histograms_keep_their_bounds() {
+    let telemetry = Telemetry::new(&TelemetryConfig::default());
+    telemetry.observe_in("request_body_bytes", vec![], 300.0, &SIZE_BUCKETS_BYTES);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let registry = telemetry.snapshot();
+    let histogram = &registry.histograms[&("request_body_bytes", vec![])];
+    assert_eq!(histogram.bounds, SIZE_BUCKETS_BYTES);
+    assert_eq!(histogram.buckets[..3], [0, 1, 1]);
+}
+
+#[test]
+fn test_histogram_percentiles() {
+    let mut histogram = Histogram::default();
+    assert_eq!(histogram.percentile(0.5), None);
+    for value in [1.0, 2.0, 3.0, 4.0, 20.0, 20.0, 20.0, 20.0, 20.0, 20000.0] {
+        histogram.observe(value, &LATENCY_BUCKETS_MS);
+    }
+
+    // Four values in 0..5, five in 10..25 and one past the last bound
+    assert_eq!(histogram.percentile(0.2), Some(2.5));
+    assert_eq!(histogram.percentile(0.5), Some(13.0));
+    assert_eq!(histogram.percentile(0.9), Some(25.0));
+    assert_eq!(histogram.percentile(0.99), Some(10000.0));
+}
+
+#[tokio::test]
+async fn test_full_queue_does_not_block_recording() {
+    let telemetry = Telemetry::new(&test_config());
+
+    // The worker cannot run before this task yields, so the queue fills up
+    let started = Instant::now();
+    for _ in 0..10_000 {
+        telemetry.increment("requests_total", vec![]);
+    }
+    assert!(started.elapsed() < Duration::from_millis(500));
+
+    let status = telemetry.status();
+    assert!(status.observability_degraded);
+    assert!(status.dropped_events > 0);
+
+    // Recovers once the worker has caught up
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    telemetry.increment("requests_total", vec![]);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!telemetry.is_degraded());
+}
+
+#[tokio::test]
+async fn test_gauges_survive_a_full_queue() {
+    let telemetry = Telemetry::new(&test_config());
+    for _ in 0..1000 {
+        telemetry.increment("requests_total", vec![]);
+        telemetry.gauge("requests_in_flight", vec![], 1);
+    }
+    for _ in 0..1000 {
+        telemetry.gauge("requests_in_flight", vec![], -1);
+    }
+    telemetry.gauge("requests_in_flight", vec![], 1);
+    assert!(telemetry.status().dropped_events > 0);
+    assert_eq!(telemetry.snapshot().gauges[&("requests_in_flight", vec![])], 1);
+}
@@ -0,0 +1,147 @@
+use crate::config::TlsConfig;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// Time a client has to complete the handshake, so idle connections do not hold a task forever.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reads the certificate chain and private key of `config` into a rustls server config,
+/// failing when either cannot be read or they do not belong together.
+pub fn load(config: &TlsConfig) -> Result<Arc<ServerConfig>, String> {
+    let certs = read_certs(&config.cert)?;
+    let key = read_key(&config.key)?;
+    // Named rather than the process default, which is ambiguous once several providers are built
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let mut server = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| {
+            format!(
+                "key {} does not fit certificate {}: {}",
+                config.key.display(),
+                config.cert.display(),
+                e
+            )
+        })?;
+    server.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(server))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let pem = std::fs::read(path).map_err(|e| format!("cannot read certificate {}: {}", path.display(), e))?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("certificate {} is not valid PEM: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("certificate {} holds no certificate", path.display()));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    let pem = std::fs::read(path).map_err(|e| format!("cannot read key {}: {}", path.display(), e))?;
+    rustls_pemfile::private_key(&mut pem.as_slice())
+        .map_err(|e| format!("key {} is not valid PEM: {}", path.display(), e))?
+        .ok_or_else(|| format!("key {} holds no private key", path.display()))
+}
+
+/// The certificate served by TLS listeners, replaced on [`Certificates::reload`] while
+/// connections already open keep the one they started with.
+pub struct Certificates {
+    config: TlsConfig,
+    current: RwLock<Arc<ServerConfig>>,
+    /// Modification times of the certificate and key files when they were last read.
+    modified: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl Certificates {
+    pub fn new(config: &TlsConfig) -> Result<Self, String> {
+        let modified = modified(config);
+        Ok(Self {
+            current: RwLock::new(load(config)?),
+            config: config.clone(),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Reads the files again. A certificate that fails to load is logged, and the previous one
+    /// kept serving.
+    pub fn reload(&self) {
+        *self.modified.lock().unwrap() = modified(&self.config);
+        match load(&self.config) {
+            Ok(server) => {
+                *self.current.write().unwrap() = server;
+                tracing::info!("Reloaded TLS certificate {}", self.config.cert.display());
+            }
+            Err(e) => tracing::error!("Keeping the current TLS certificate, reload failed: {}", e),
+        }
+    }
+
+    /// Reloads when the certificate or key file changed since they were last read.
+    pub fn reload_if_changed(&self) {
+        let changed = *self.modified.lock().unwrap() != modified(&self.config);
+        if changed {
+            self.reload();
+        }
+    }
+
+    /// Completes the handshake of an accepted connection with the current certificate.
+    pub async fn accept<S>(&self, stream: S) -> io::Result<TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let acceptor = TlsAcceptor::from(self.current.read().unwrap().clone());
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))?
+    }
+}
+
+fn modified(config: &TlsConfig) -> (Option<SystemTime>, Option<SystemTime>) {
+    let at = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    (at(&config.cert), at(&config.key))
+}
+
+/// Reloads `certificates` on SIGHUP and, every `watch_interval_secs`, when their files changed,
+/// so renewed certificates are served without a restart.
+pub fn spawn_reloader(certificates: Arc<Certificates>) {
+    let interval = certificates.config.watch_interval_secs;
+    if interval > 0 {
+        let certificates = certificates.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(Duration::from_secs(interval));
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticks.tick().await;
+                certificates.reload_if_changed();
+            }
+        });
+    }
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+            return;
+        };
+        while hangup.recv().await.is_some() {
+            certificates.reload();
+        }
+    });
+    #[cfg(not(unix))]
+    let _ = certificates;
+}
+
+#[cfg(test)]
+mod tests {
+    include!("tls_tests.rs");
+}
@@ -0,0 +1,116 @@
+use super::*;
+use crate::config::ServerConfig as ListenerServerConfig;
+use crate::server::{serve, Transport};
+use rustls::pki_types::ServerName;
+use std::path::PathBuf;
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// A self-signed certificate for `localhost`, as PEM, and the DER of the certificate.
+fn self_signed() -> (String, String, CertificateDer<'static>) {
+    let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let rcgen::CertifiedKey { cert, key_pair } = generated;
+    (cert.pem(), key_pair.serialize_pem(), cert.der().clone())
+}
+
+fn write_pair(dir: &TempDir, cert: &str, key: &str) -> TlsConfig {
+    let (cert_path, key_path) = (dir.path().join("fullchain.pem"), dir.path().join("privkey.pem"));
+    std::fs::write(&cert_path, cert).unwrap();
+    std::fs::write(&key_path, key).unwrap();
+    TlsConfig {
+        cert: cert_path,
+        key: key_path,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_certificate_and_key_must_load_and_match() {
+    let dir = TempDir::new().unwrap();
+    let (cert, key, _) = self_signed();
+    assert!(load(&write_pair(&dir, &cert, &key)).is_ok());
+
+    let (_, other_key, _) = self_signed();
+    let err = load(&write_pair(&dir, &cert, &other_key)).unwrap_err();
+    assert!(err.contains("does not fit certificate"), "{}", err);
+
+    let err = load(&write_pair(&dir, "not a certificate", &key)).unwrap_err();
+    assert!(err.contains("holds no certificate"), "{}", err);
+    let err = load(&write_pair(&dir, &cert, &cert)).unwrap_err();
+    assert!(err.contains("holds no private key"), "{}", err);
+    let missing = TlsConfig {
+        key: PathBuf::from("/nonexistent/privkey.pem"),
+        ..write_pair(&dir, &cert, &key)
+    };
+    let err = load(&missing).unwrap_err();
+    assert!(err.contains("cannot read key /nonexistent/privkey.pem"), "{}", err);
+}
+
+#[test]
+fn test_malformed_certificate_keeps_the_previous_one() {
+    let dir = TempDir::new().unwrap();
+    let (cert, key, _) = self_signed();
+    let config = write_pair(&dir, &cert, &key);
+    let certificates = Certificates::new(&config).unwrap();
+    let first = certificates.current.read().unwrap().clone();
+
+    std::fs::write(&config.cert, "-----BEGIN CERTIFICATE-----\ntruncated").unwrap();
+    certificates.reload();
+    assert!(Arc::ptr_eq(&first, &certificates.current.read().unwrap()));
+
+    let (cert, key, _) = self_signed();
+    write_pair(&dir, &cert, &key);
+    certificates.reload();
+    assert!(!Arc::ptr_eq(&first, &certificates.current.read().unwrap()));
+    // Nothing changed since
+    let second = certificates.current.read().unwrap().clone();
+    certificates.reload_if_changed();
+    assert!(Arc::ptr_eq(&second, &certificates.current.read().unwrap()));
+}
+
+/// Sends one request over TLS, trusting only `root`, and returns the response.
+async fn https_get(addr: std::net::SocketAddr, root: CertificateDer<'static>) -> io::Result<String> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(root).unwrap();
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let client = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(client));
+    let tcp = tokio::net::TcpStream::connect(addr).await?;
+    let mut stream = connector.connect(ServerName::try_from("localhost").unwrap(), tcp).await?;
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    Ok(response)
+}
+
+#[tokio::test]
+async fn test_https_is_served_with_the_current_certificate() {
+    let dir = TempDir::new().unwrap();
+    let (cert, key, der) = self_signed();
+    let config = write_pair(&dir, &cert, &key);
+    let certificates = Arc::new(Certificates::new(&config).unwrap());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = axum::Router::new().route("/", axum::routing::get(|| async { "secure" }));
+    let transport = Transport::Tls(certificates.clone());
+    tokio::spawn(async move {
+        serve(listener, app, &ListenerServerConfig::default(), transport, std::future::pending()).await
+    });
+
+    let response = https_get(addr, der.clone()).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.ends_with("secure"), "{}", response);
+
+    // A renewed certificate is served to new connections
+    let (cert, key, renewed) = self_signed();
+    write_pair(&dir, &cert, &key);
+    certificates.reload();
+    assert!(https_get(addr, der).await.is_err());
+    assert!(https_get(addr, renewed).await.unwrap().ends_with("secure"));
+}
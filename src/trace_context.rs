@@ -0,0 +1,201 @@
+use crate::config::TracePropagation;
+use axum::http::{HeaderMap, HeaderValue};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// AWS X-Ray trace header, as in
+/// `Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1`.
+pub const XRAY_HEADER: &str = "x-amzn-trace-id";
+/// W3C trace header, as in `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+/// Vendor data of W3C traces, passed on unchanged with the `traceparent` it came with.
+pub const TRACESTATE_HEADER: &str = "tracestate";
+
+/// The trace a request belongs to, as continued by the gateway. The gateway takes part in the
+/// trace as a span of its own, `span_id`, which the function sees as its parent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    pub propagation: TracePropagation,
+    /// The X-Ray root, as in `1-5759e988-bd862e3fe1be46a994272793`, or 32 hex digits for W3C.
+    pub trace_id: String,
+    /// The client's span, when it sent a trace to continue.
+    pub parent_id: Option<String>,
+    /// The gateway's span: 16 hex digits.
+    pub span_id: String,
+    /// Whether the trace is recorded; `None` leaves the decision to the function, as X-Ray does
+    /// without `Sampled`.
+    pub sampled: Option<bool>,
+    /// `tracestate` of the client's W3C trace.
+    pub state: Option<String>,
+}
+
+impl TraceContext {
+    /// Continues the trace in the client's headers, or starts a new one when they carry none or
+    /// one that does not parse.
+    pub fn continue_from(propagation: TracePropagation, headers: &HeaderMap) -> Self {
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        let continued = match propagation {
+            TracePropagation::Xray => header(XRAY_HEADER).and_then(parse_xray),
+            TracePropagation::W3c => header(TRACEPARENT_HEADER).and_then(parse_traceparent).map(|mut trace| {
+                trace.state = header(TRACESTATE_HEADER).map(String::from);
+                trace
+            }),
+        };
+        continued.unwrap_or_else(|| Self::start(propagation))
+    }
+
+    /// A new trace with the gateway's span at its root. New X-Ray traces leave sampling to the
+    /// function; new W3C traces are sampled, as there is no one downstream to decide.
+    fn start(propagation: TracePropagation) -> Self {
+        let (trace_id, sampled) = match propagation {
+            TracePropagation::Xray => {
+                let secs = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                (format!("1-{:08x}-{}", secs, random_hex(12)), None)
+            }
+            TracePropagation::W3c => (random_hex(16), Some(true)),
+        };
+        Self {
+            propagation,
+            trace_id,
+            parent_id: None,
+            span_id: random_hex(8),
+            sampled,
+            state: None,
+        }
+    }
+
+    /// The X-Ray header naming the gateway's span as the parent, for the invoke itself so that
+    /// Lambda records the function in the same trace.
+    pub fn xray_header(&self) -> Option<String> {
+        if self.propagation != TracePropagation::Xray {
+            return None;
+        }
+        let mut header = format!("Root={};Parent={}", self.trace_id, self.span_id);
+        if let Some(sampled) = self.sampled {
+            header.push_str(if sampled { ";Sampled=1" } else { ";Sampled=0" });
+        }
+        Some(header)
+    }
+
+    /// `traceparent` naming the gateway's span as the parent.
+    pub fn traceparent(&self) -> Option<String> {
+        if self.propagation != TracePropagation::W3c {
+            return None;
+        }
+        let flags = u8::from(self.sampled.unwrap_or_default());
+        Some(format!("00-{}-{}-{:02x}", self.trace_id, self.span_id, flags))
+    }
+
+    /// Replaces the client's trace headers with the gateway's, for the headers sent to the function.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        match self.propagation {
+            TracePropagation::Xray => {
+                insert(headers, XRAY_HEADER, self.xray_header());
+            }
+            TracePropagation::W3c => {
+                insert(headers, TRACEPARENT_HEADER, self.traceparent());
+                headers.remove(TRACESTATE_HEADER);
+                insert(headers, TRACESTATE_HEADER, self.state.clone());
+            }
+        }
+    }
+}
+
+fn insert(headers: &mut HeaderMap, name: &'static str, value: Option<String>) {
+    if let Some(value) = value.and_then(|value| HeaderValue::from_str(&value).ok()) {
+        headers.insert(name, value);
+    }
+}
+
+/// Reads `Root`, `Parent` and `Sampled` from an X-Ray header; other fields, such as the `Self`
+/// added by load balancers, are dropped.
+fn parse_xray(header: &str) -> Option<TraceContext> {
+    let (mut root, mut parent, mut sampled) = (None, None, None);
+    for field in header.split(';') {
+        let Some((key, value)) = field.split_once('=') else {
+            continue;
+        };
+        match (key.trim(), value.trim()) {
+            ("Root", value) => root = Some(value),
+            ("Parent", value) if is_hex(value, 16) => parent = Some(value.to_string()),
+            ("Sampled", "1") => sampled = Some(true),
+            ("Sampled", "0") => sampled = Some(false),
+            _ => {}
+        }
+    }
+    let root = root?;
+    let mut parts = root.split('-');
+    let valid = parts.next() == Some("1")
+        && parts.next().is_some_and(|time| is_hex(time, 8))
+        && parts.next().is_some_and(|id| is_hex(id, 24))
+        && parts.next().is_none();
+    valid.then(|| TraceContext {
+        propagation: TracePropagation::Xray,
+        trace_id: root.to_string(),
+        parent_id: parent,
+        span_id: random_hex(8),
+        sampled,
+        state: None,
+    })
+}
+
+/// Reads a `traceparent`. Versions after `00` may add fields, which are ignored.
+fn parse_traceparent(header: &str) -> Option<TraceContext> {
+    let mut parts = header.trim().split('-');
+    let version = parts.next().filter(|version| is_hex(version, 2) && *version != "ff")?;
+    let trace_id = parts.next().filter(|id| is_hex(id, 32) && !is_zero(id))?;
+    let parent_id = parts.next().filter(|id| is_hex(id, 16) && !is_zero(id))?;
+    let flags = parts.next().filter(|flags| is_hex(flags, 2))?;
+    if version == "00" && parts.next().is_some() {
+        return None;
+    }
+    Some(TraceContext {
+        propagation: TracePropagation::W3c,
+        trace_id: trace_id.to_string(),
+        parent_id: Some(parent_id.to_string()),
+        span_id: random_hex(8),
+        sampled: Some(u8::from_str_radix(flags, 16).ok()? & 1 == 1),
+        state: None,
+    })
+}
+
+/// Lowercase hex of exactly `len` digits, as both formats require.
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len && value.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn is_zero(value: &str) -> bool {
+    value.bytes().all(|b| b == b'0')
+}
+
+/// `bytes` random bytes in hex, never all zeros, which both formats treat as invalid. Without
+/// randomness, the time and a process-wide counter keep IDs unique.
+fn random_hex(bytes: usize) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut random = vec![0u8; bytes];
+    if getrandom::getrandom(&mut random).is_err() {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let seed = nanos ^ COUNTER.fetch_add(1, Ordering::Relaxed).rotate_left(32);
+        for (byte, source) in random.iter_mut().rev().zip(seed.to_le_bytes().into_iter().cycle()) {
+            *byte = source;
+        }
+    }
+    if random.iter().all(|b| *b == 0) {
+        random[bytes - 1] = 1;
+    }
+    random.iter().fold(String::with_capacity(bytes * 2), |mut hex, b| {
+        hex.push_str(&format!("{:02x}", b));
+        hex
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    include!("trace_context_tests.rs");
+}
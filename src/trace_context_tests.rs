@@ -0,0 +1,102 @@
+use super::*;
+
+fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+    pairs
+        .iter()
+        .map(|&(name, value)| (axum::http::HeaderName::from_static(name), HeaderValue::from_static(value)))
+        .collect()
+}
+
+#[test]
+fn test_xray_trace_is_continued_with_the_gateway_as_parent() {
+    let incoming = headers(&[(
+        XRAY_HEADER,
+        "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1;Self=1-67891234-abc",
+    )]);
+    let trace = TraceContext::continue_from(TracePropagation::Xray, &incoming);
+    assert_eq!(trace.trace_id, "1-5759e988-bd862e3fe1be46a994272793");
+    assert_eq!(trace.parent_id.as_deref(), Some("53995c3f42cd8ad8"));
+    assert_eq!(trace.sampled, Some(true));
+    assert!(is_hex(&trace.span_id, 16), "{}", trace.span_id);
+    assert_ne!(trace.span_id, "53995c3f42cd8ad8");
+
+    let header = trace.xray_header().unwrap();
+    assert_eq!(
+        header,
+        format!("Root=1-5759e988-bd862e3fe1be46a994272793;Parent={};Sampled=1", trace.span_id)
+    );
+    let mut forwarded = incoming.clone();
+    trace.apply(&mut forwarded);
+    assert_eq!(forwarded[XRAY_HEADER], header.as_str());
+    assert_eq!(trace.traceparent(), None);
+}
+
+#[test]
+fn test_xray_trace_is_started_without_a_valid_header() {
+    for incoming in [
+        headers(&[]),
+        headers(&[(XRAY_HEADER, "Parent=53995c3f42cd8ad8")]),
+        headers(&[(XRAY_HEADER, "Root=1-5759e988-short")]),
+        headers(&[(XRAY_HEADER, "Root=2-5759e988-bd862e3fe1be46a994272793")]),
+    ] {
+        let trace = TraceContext::continue_from(TracePropagation::Xray, &incoming);
+        let parts: Vec<&str> = trace.trace_id.split('-').collect();
+        assert_eq!(parts.len(), 3, "{}", trace.trace_id);
+        assert!(parts[0] == "1" && is_hex(parts[1], 8) && is_hex(parts[2], 24), "{}", trace.trace_id);
+        assert_eq!((trace.parent_id.as_deref(), trace.sampled), (None, None));
+        // Sampling is left to Lambda
+        assert!(!trace.xray_header().unwrap().contains("Sampled"));
+    }
+}
+
+#[test]
+fn test_w3c_trace_is_continued_with_its_state() {
+    let incoming = headers(&[
+        (TRACEPARENT_HEADER, "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+        (TRACESTATE_HEADER, "rojo=00f067aa0ba902b7,congo=t61rcWkgMzE"),
+    ]);
+    let trace = TraceContext::continue_from(TracePropagation::W3c, &incoming);
+    assert_eq!(trace.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_eq!(trace.parent_id.as_deref(), Some("00f067aa0ba902b7"));
+    assert_eq!(trace.sampled, Some(true));
+
+    let mut forwarded = incoming.clone();
+    trace.apply(&mut forwarded);
+    assert_eq!(
+        forwarded[TRACEPARENT_HEADER],
+        format!("00-4bf92f3577b34da6a3ce929d0e0e4736-{}-01", trace.span_id).as_str()
+    );
+    assert_eq!(forwarded[TRACESTATE_HEADER], "rojo=00f067aa0ba902b7,congo=t61rcWkgMzE");
+    assert_eq!(trace.xray_header(), None);
+
+    let unsampled = headers(&[(TRACEPARENT_HEADER, "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00")]);
+    let trace = TraceContext::continue_from(TracePropagation::W3c, &unsampled);
+    assert!(trace.traceparent().unwrap().ends_with("-00"));
+}
+
+#[test]
+fn test_w3c_trace_is_restarted_for_invalid_traceparents() {
+    for value in [
+        "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+        "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+        "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+    ] {
+        let mut incoming = headers(&[(TRACESTATE_HEADER, "rojo=00f067aa0ba902b7")]);
+        incoming.insert(TRACEPARENT_HEADER, HeaderValue::from_static(value));
+        let trace = TraceContext::continue_from(TracePropagation::W3c, &incoming);
+        assert_ne!(trace.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736", "{}", value);
+        assert!(is_hex(&trace.trace_id, 32) && trace.parent_id.is_none(), "{}", value);
+
+        // The state belonged to the trace that was dropped
+        trace.apply(&mut incoming);
+        assert!(!incoming.contains_key(TRACESTATE_HEADER), "{}", value);
+        assert!(incoming[TRACEPARENT_HEADER].to_str().unwrap().ends_with("-01"));
+    }
+
+    // Later versions may carry more fields
+    let incoming = headers(&[(TRACEPARENT_HEADER, "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra")]);
+    let trace = TraceContext::continue_from(TracePropagation::W3c, &incoming);
+    assert_eq!(trace.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+}
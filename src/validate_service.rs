@@ -0,0 +1,242 @@
+use crate::config::Config;
+use crate::error::{ErrorPhase, GatewayError};
+use crate::routes::{RouteEntry, RouteRegistry};
+use axum::body::Bytes;
+use axum::extract::{Query, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+/// Serves `POST /validate`, checking config documents as the gateway does at startup, for CI
+/// pipelines with many configs to check. No AWS clients are created.
+#[derive(Args, Debug)]
+pub struct ServeValidateArgs {
+    /// Address to listen on.
+    #[arg(long, default_value = "127.0.0.1:8090")]
+    pub addr: String,
+    /// Config documents can be merged onto with `?merge_with=NAME`, as `NAME=PATH`; may be repeated.
+    #[arg(long = "base")]
+    pub bases: Vec<String>,
+}
+
+/// Format of a config document.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DocumentFormat {
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl DocumentFormat {
+    /// The format a `Content-Type` names; YAML unless it is JSON or TOML.
+    pub fn from_content_type(content_type: Option<&str>) -> Self {
+        let media = content_type
+            .and_then(|value| value.split(';').next())
+            .unwrap_or_default();
+        match media.trim().to_ascii_lowercase().as_str() {
+            "application/json" => DocumentFormat::Json,
+            "application/toml" | "text/toml" => DocumentFormat::Toml,
+            _ => DocumentFormat::Yaml,
+        }
+    }
+
+    /// The format a file extension names; YAML unless it is `.json` or `.toml`.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => DocumentFormat::Json,
+            Some("toml") => DocumentFormat::Toml,
+            _ => DocumentFormat::Yaml,
+        }
+    }
+}
+
+/// Parses a config document into its JSON form, so documents of every format can be merged. An
+/// empty document is an empty map.
+pub fn parse_document(contents: &str, format: DocumentFormat) -> Result<Value, String> {
+    let value: Value = match format {
+        DocumentFormat::Yaml => serde_yaml::from_str(contents).map_err(|e| e.to_string())?,
+        DocumentFormat::Json => serde_json::from_str(contents).map_err(|e| e.to_string())?,
+        DocumentFormat::Toml => toml::from_str(contents).map_err(|e| e.to_string())?,
+    };
+    match value {
+        Value::Null => Ok(Value::Object(Default::default())),
+        Value::Object(_) => Ok(value),
+        _ => Err("a config document must be a map".to_string()),
+    }
+}
+
+/// Merges `overlay` onto `base`: maps key by key, so a fragment can add targets to a base config,
+/// while any other value in `overlay` replaces the one in `base`.
+pub fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// What `POST /validate` answers for a document that parses as a config.
+#[derive(Clone, Debug, Serialize)]
+pub struct ValidationReport {
+    pub valid: bool,
+    /// Problems that would stop the gateway from starting, as [`Config::validation_errors`].
+    pub errors: Vec<String>,
+    /// Problems it would start with, as [`Config::lint`].
+    pub warnings: Vec<String>,
+    /// The route table, as `--print-routes` shows it.
+    pub routes: Vec<RouteEntry>,
+    /// [`Config::fingerprint`] of the config.
+    pub fingerprint: String,
+}
+
+impl ValidationReport {
+    /// Checks a config document without reading the files it names, which are reported instead.
+    pub fn new(document: Value) -> Result<Self, String> {
+        let mut config: Config = serde_json::from_value(document).map_err(|e| e.to_string())?;
+        let mut unread: Vec<String> = config
+            .namespaces
+            .iter()
+            .filter_map(|(name, namespace)| Some(format!("namespace {}: {}", name, namespace.file.as_ref()?.display())))
+            .collect();
+        let templates = [&config.error_pages.html_template, &config.error_pages.text_template];
+        unread.extend(
+            templates
+                .into_iter()
+                .flatten()
+                .map(|file| format!("error_pages: {}", file.display())),
+        );
+        config.mount_namespaces();
+
+        let errors = config.validation_errors();
+        let mut warnings = config.lint();
+        if config.lambda_function_name.is_empty() && config.needs_top_level_function() {
+            warnings.push("lambda_function_name is unset, so LAMBDA_FUNCTION_NAME must provide it".to_string());
+        }
+        warnings.extend(unread.into_iter().map(|file| format!("{} was not read", file)));
+        Ok(Self {
+            valid: errors.is_empty(),
+            errors,
+            warnings,
+            routes: RouteRegistry::new(&config).routes,
+            fingerprint: config.fingerprint(),
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ValidateQuery {
+    merge_with: Option<String>,
+}
+
+/// The `serve-validate` endpoint, with the base configs documents may be merged onto by name.
+pub fn validate_router(bases: BTreeMap<String, Value>) -> Router {
+    Router::new()
+        .route("/validate", post(validate))
+        .with_state(Arc::new(bases))
+}
+
+/// Answers `200` with the [`ValidationReport`] of a valid config and `422` with that of an
+/// invalid one. A document that does not parse as a config gets `400 invalid_document`, and an
+/// unknown `merge_with` base `404 unknown_base`.
+async fn validate(
+    State(bases): State<Arc<BTreeMap<String, Value>>>,
+    Query(query): Query<ValidateQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let invalid = |message: String| {
+        GatewayError::new(
+            ErrorPhase::Ingress,
+            StatusCode::BAD_REQUEST,
+            "invalid_document",
+            message,
+        )
+        .into_response()
+    };
+    let format = DocumentFormat::from_content_type(headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()));
+    let Ok(contents) = std::str::from_utf8(&body) else {
+        return invalid("the document is not UTF-8".to_string());
+    };
+    let mut document = match parse_document(contents, format) {
+        Ok(document) => document,
+        Err(e) => return invalid(e),
+    };
+    if let Some(name) = &query.merge_with {
+        let Some(base) = bases.get(name) else {
+            let message = format!("No base config named {}", name);
+            return GatewayError::new(ErrorPhase::Ingress, StatusCode::NOT_FOUND, "unknown_base", message)
+                .into_response();
+        };
+        let mut merged = base.clone();
+        merge(&mut merged, document);
+        document = merged;
+    }
+    match ValidationReport::new(document) {
+        Ok(report) if report.valid => Json(report).into_response(),
+        Ok(report) => (StatusCode::UNPROCESSABLE_ENTITY, Json(report)).into_response(),
+        Err(e) => invalid(e),
+    }
+}
+
+/// Reads the `--base NAME=PATH` configs.
+fn load_bases(args: &[String]) -> Result<BTreeMap<String, Value>, String> {
+    args.iter()
+        .map(|arg| {
+            let (name, path) = arg
+                .split_once('=')
+                .ok_or_else(|| format!("--base {:?} is not NAME=PATH", arg))?;
+            let path = Path::new(path);
+            let contents = std::fs::read_to_string(path).map_err(|e| format!("base {}: {}", name, e))?;
+            let document = parse_document(&contents, DocumentFormat::from_path(path))
+                .map_err(|e| format!("base {}: {}", name, e))?;
+            Ok((name.to_string(), document))
+        })
+        .collect()
+}
+
+pub async fn run_serve_validate(args: ServeValidateArgs) -> ExitCode {
+    let bases = match load_bases(&args.bases) {
+        Ok(bases) => bases,
+        Err(e) => {
+            eprintln!("FAIL: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let listener = match tokio::net::TcpListener::bind(&args.addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("FAIL: cannot listen on {}: {}", args.addr, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    println!("Validating configs at http://{}/validate", args.addr);
+    match axum::serve(listener, validate_router(bases)).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("FAIL: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    include!("validate_service_tests.rs");
+}
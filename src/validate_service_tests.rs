@@ -0,0 +1,104 @@
+use super::*;
+use axum::body::Body;
+use tower::ServiceExt;
+
+const BASE: &str = "
+lambda_function_name: gateway-default
+addr: 127.0.0.1:8000
+targets:
+  /orders/:id:
+    function: orders
+";
+
+async fn post(router: Router, uri: &str, content_type: &str, document: &str) -> (StatusCode, Value) {
+    let request = axum::http::Request::post(uri)
+        .header("content-type", content_type)
+        .body(Body::from(document.to_string()))
+        .unwrap();
+    let response = router.oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+fn router() -> Router {
+    let base = parse_document(BASE, DocumentFormat::Yaml).unwrap();
+    validate_router(BTreeMap::from([("base".to_string(), base)]))
+}
+
+#[tokio::test]
+async fn test_valid_document_reports_routes_and_fingerprint() {
+    let (status, report) = post(router(), "/validate", "application/yaml", BASE).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(report["valid"], true);
+    assert_eq!(report["errors"], serde_json::json!([]));
+    let routes = report["routes"].as_array().unwrap();
+    assert!(routes.iter().any(|route| route["path"] == "/orders/:id" && route["served_by"] == "target -> orders"));
+    let expected = serde_yaml::from_str::<Config>(BASE).unwrap().fingerprint();
+    assert_eq!(report["fingerprint"], expected);
+}
+
+#[tokio::test]
+async fn test_invalid_documents() {
+    let json = r#"{"lambda_function_name": "f", "targets": {"/orders": {"max_concurrency": 0}}}"#;
+    let (status, report) = post(router(), "/validate", "application/json", json).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(report["valid"], false);
+    assert_eq!(report["errors"][0], "target /orders: max_concurrency must be at least 1");
+
+    // Documents that are not a config at all are not reported on
+    let (status, body) = post(router(), "/validate", "application/json", "[1, 2]").await;
+    assert_eq!((status, &body["error_code"]), (StatusCode::BAD_REQUEST, &Value::from("invalid_document")));
+    let (status, _) = post(router(), "/validate", "application/yaml", "targets: 42").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_conflicting_routes() {
+    let toml = r#"
+lambda_function_name = "f"
+addr = "127.0.0.1:8000"
+
+[targets."/items/:id"]
+function = "a"
+
+[targets."/items/:key"]
+function = "b"
+
+[targets."/*rest"]
+function = "c"
+"#;
+    let (status, report) = post(router(), "/validate", "application/toml", toml).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let errors = report["errors"].as_array().unwrap();
+    let unreachable = "target /items/:id is unreachable: target /items/:key matches the same paths and wins";
+    assert!(errors.contains(&Value::from(unreachable)));
+    let warnings = report["warnings"].as_array().unwrap();
+    assert!(warnings.iter().any(|w| w.as_str().unwrap().starts_with("target /*rest does not receive /healthz")));
+}
+
+#[tokio::test]
+async fn test_fragments_merged_onto_base() {
+    let fragment = "targets:\n  /invoices:\n    function: invoices\n";
+    let (status, report) = post(router(), "/validate?merge_with=base", "application/yaml", fragment).await;
+    assert_eq!(status, StatusCode::OK);
+    let paths: Vec<&str> = report["routes"].as_array().unwrap().iter().filter_map(|r| r["path"].as_str()).collect();
+    assert!(paths.contains(&"/orders/:id") && paths.contains(&"/invoices"));
+
+    // A fragment clashing with a route of the base
+    let fragment = "targets:\n  /orders/:key:\n    function: other\n";
+    let (status, report) = post(router(), "/validate?merge_with=base", "application/yaml", fragment).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(report["errors"][0].as_str().unwrap().contains("/orders/:id"));
+
+    let (status, body) = post(router(), "/validate?merge_with=missing", "application/yaml", fragment).await;
+    assert_eq!((status, &body["error_code"]), (StatusCode::NOT_FOUND, &Value::from("unknown_base")));
+}
+
+#[test]
+fn test_merge_replaces_non_map_values() {
+    let mut base = serde_json::json!({"addr": "a", "targets": {"/a": {"function": "a"}}, "api_keys": ["k1"]});
+    merge(&mut base, serde_json::json!({"addr": "b", "targets": {"/b": {"function": "b"}}, "api_keys": ["k2"]}));
+    let targets = serde_json::json!({"/a": {"function": "a"}, "/b": {"function": "b"}});
+    assert_eq!(base, serde_json::json!({"addr": "b", "targets": targets, "api_keys": ["k2"]}));
+}